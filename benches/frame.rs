@@ -0,0 +1,43 @@
+//! Renders of tiny frames of the built-in scenes, measuring everything a sample goes through.
+//! Run with `cargo bench --bench frame`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_ray_tracing::distributed::Tile;
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::RenderSettings;
+use std::hint::black_box;
+
+const WIDTH: u16 = 64;
+const HEIGHT: u16 = 36;
+
+fn frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    group.sample_size(10);
+    for scene in [
+        BuiltinScene::RandomSpheres,
+        BuiltinScene::CornellBox,
+        BuiltinScene::FinalNextWeek,
+    ] {
+        let settings = RenderSettings {
+            scene,
+            image_width: WIDTH,
+            image_height: HEIGHT,
+            samples_per_pixel: 4,
+            ..RenderSettings::default()
+        };
+        let scene = settings.scene.build(&settings);
+        let tile = Tile {
+            x: 0,
+            y: 0,
+            width: WIDTH,
+            height: HEIGHT,
+        };
+        group.bench_function(settings.scene.to_string(), |b| {
+            b.iter(|| scene.render_tile(&settings, black_box(tile)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frame);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+//! Micro-benchmarks of the innermost loops: vector math, ray-sphere intersections and BVH
+//! traversal. Run with `cargo bench --bench intersection`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_ray_tracing::bvh::{Bvh, BvhSplit};
+use rust_ray_tracing::float::Float;
+use rust_ray_tracing::material::MaterialId;
+use rust_ray_tracing::object::{HitRecord, Hittable};
+use rust_ray_tracing::ray::Ray;
+use rust_ray_tracing::sphere::Sphere;
+use rust_ray_tracing::vec3::{unit_vector, Point3, Vec3};
+use std::hint::black_box;
+
+const RAY_COUNT: usize = 1024;
+
+/// Rays from around `origin` towards random points of the cube of half size `extent` around
+/// the origin.
+fn rays(rng: &mut StdRng, origin: Point3, extent: Float) -> Vec<Ray> {
+    (0..RAY_COUNT)
+        .map(|_| {
+            let origin = origin + Vec3::random_range(rng, -0.5, 0.5);
+            let target = Vec3::random_range(rng, -extent, extent);
+            Ray::new(origin, target - origin)
+        })
+        .collect()
+}
+
+/// Number of the rays hitting `object`, for the compiler not to skip the intersections.
+fn trace(object: &dyn Hittable, rays: &[Ray]) -> usize {
+    let mut hit_record = HitRecord::empty();
+    rays.iter()
+        .filter(|ray| object.hit(ray, 0.001, Float::INFINITY, &mut hit_record))
+        .count()
+}
+
+fn vec3(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let vectors: Vec<Vec3> = (0..RAY_COUNT)
+        .map(|_| Vec3::random_range(&mut rng, -1.0, 1.0))
+        .collect();
+
+    c.bench_function("vec3 dot", |b| {
+        b.iter(|| {
+            vectors
+                .windows(2)
+                .map(|pair| pair[0].dot(&pair[1]))
+                .sum::<Float>()
+        })
+    });
+    c.bench_function("vec3 cross", |b| {
+        b.iter(|| {
+            vectors
+                .windows(2)
+                .fold(Vec3::zero(), |sum, pair| sum + pair[0].cross(&pair[1]))
+        })
+    });
+    c.bench_function("vec3 unit_vector", |b| {
+        b.iter(|| {
+            vectors
+                .iter()
+                .fold(Vec3::zero(), |sum, v| sum + unit_vector(black_box(*v)))
+        })
+    });
+}
+
+fn sphere_hit(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let sphere = Sphere::new(Point3::zero(), 1.0, MaterialId::default());
+    // About half of the rays hit the sphere
+    let rays = rays(&mut rng, Point3::new(0.0, 0.0, 5.0), 1.5);
+
+    c.bench_function("sphere hit", |b| {
+        b.iter(|| trace(&sphere, black_box(&rays)))
+    });
+}
+
+fn bvh_traversal(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let spheres: Vec<Sphere> = (0..10_000)
+        .map(|_| {
+            let center = Vec3::random_range(&mut rng, -10.0, 10.0);
+            Sphere::new(center, rng.gen_range(0.05..0.3), MaterialId::default())
+        })
+        .collect();
+    let rays = rays(&mut rng, Point3::new(0.0, 5.0, 30.0), 10.0);
+
+    let mut group = c.benchmark_group("bvh traversal");
+    for (name, split) in [("median", BvhSplit::Median), ("sah", BvhSplit::Sah)] {
+        let spheres = spheres
+            .iter()
+            .map(|sphere| Box::new(sphere.clone()) as Box<dyn Hittable>)
+            .collect();
+        let bvh = Bvh::new(spheres, split);
+        group.bench_function(name, |b| b.iter(|| trace(&bvh, black_box(&rays))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vec3, sphere_hit, bvh_traversal);
+criterion_main!(benches);
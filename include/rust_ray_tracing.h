@@ -0,0 +1,63 @@
+/*
+ * C bindings of the ray tracer, built as a shared library with
+ *
+ *     cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Functions returning an int return 0 on success and -1 on failure, those returning a pointer
+ * return NULL on failure, rt_last_error() then describing the error. Scenes can be used from
+ * any thread, but not from several at once.
+ */
+
+#ifndef RUST_RAY_TRACING_H
+#define RUST_RAY_TRACING_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Scene to render, with the settings of its image. */
+typedef struct RtScene RtScene;
+
+/* Built-in scene of the given name, e.g. "cornell-box", with its camera. */
+RtScene *rt_scene_builtin(const char *name);
+
+/* Scene of a scene file. */
+RtScene *rt_scene_load(const char *path);
+
+void rt_scene_free(RtScene *scene);
+
+/*
+ * Replaces the camera of the scene by a camera at look_from looking at look_at, both of 3
+ * floats, the lens of diameter aperture focusing at focus_dist.
+ */
+int rt_scene_set_camera(RtScene *scene, const float *look_from, const float *look_at,
+                        float vertical_fov_deg, float aperture, float focus_dist);
+
+int rt_scene_set_image_size(RtScene *scene, uint16_t width, uint16_t height);
+
+int rt_scene_set_samples(RtScene *scene, uint16_t samples_per_pixel);
+
+/* Seed of the random numbers of the samples, the same seed giving the same image. */
+int rt_scene_set_seed(RtScene *scene, uint64_t seed);
+
+/*
+ * Renders the scene on threads threads, 0 for one per core, into pixels: len floats, at least
+ * 3 per pixel, filled with the linear RGB radiance of the pixels in row-major order from the
+ * top row.
+ */
+int rt_render(RtScene *scene, uint32_t threads, float *pixels, size_t len);
+
+/*
+ * Message of the last error on the calling thread, NULL if none. It stays valid until the
+ * next call failing on the thread.
+ */
+const char *rt_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
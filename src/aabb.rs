@@ -0,0 +1,187 @@
+use crate::float::Float;
+use crate::ray::Ray;
+use crate::vec3::{Point3, Vec3};
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    min: Point3,
+    max: Point3,
+}
+
+impl Aabb {
+    pub fn new(a: Point3, b: Point3) -> Aabb {
+        Aabb {
+            min: a.min(&b),
+            max: a.max(&b),
+        }
+    }
+
+    /// Box containing nothing, the identity of `union`.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point3::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+            max: Point3::new(-Float::INFINITY, -Float::INFINITY, -Float::INFINITY),
+        }
+    }
+
+    /// Box of the points within `radius` of `center`.
+    pub fn around(center: Point3, radius: Float) -> Aabb {
+        let r = Vec3::new(radius.abs(), radius.abs(), radius.abs());
+        Aabb {
+            min: center - r,
+            max: center + r,
+        }
+    }
+
+    /// Box of a disk of `radius` around `center`, in the plane orthogonal to the unit vector
+    /// `normal`.
+    pub fn disk(center: Point3, normal: Vec3, radius: Float) -> Aabb {
+        let extent = |n: Float| radius * (1.0 - n * n).max(0.0).sqrt();
+        let r = Vec3::new(extent(normal.x()), extent(normal.y()), extent(normal.z()));
+        Aabb {
+            min: center - r,
+            max: center + r,
+        }
+    }
+
+    pub fn min(&self) -> Point3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Point3 {
+        self.max
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    /// Box of the points in both boxes, empty if they don't overlap.
+    pub fn intersection(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.max(&other.min),
+            max: self.max.min(&other.max),
+        }
+    }
+
+    pub fn grow(&self, point: &Point3) -> Aabb {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    /// Box grown by `margin` on all sides.
+    pub fn padded(&self, margin: Float) -> Aabb {
+        let m = Vec3::new(margin, margin, margin);
+        Aabb {
+            min: self.min - m,
+            max: self.max + m,
+        }
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> Float {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.extent();
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    /// Axis along which the box is the largest: 0 for X, 1 for Y and 2 for Z.
+    pub fn longest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x() >= d.y() && d.x() >= d.z() {
+            0
+        } else if d.y() >= d.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Slab test, with `inverse_direction` the component-wise inverse of the direction of the
+    /// ray, computed once per ray.
+    pub fn hit(&self, ray: &Ray, inverse_direction: &Vec3, t_min: Float, t_max: Float) -> bool {
+        self.clip(ray, inverse_direction, t_min, t_max).is_some()
+    }
+
+    /// Part of the [`t_min`, `t_max`] range of the ray within the box, if any.
+    pub fn clip(
+        &self,
+        ray: &Ray,
+        inverse_direction: &Vec3,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<(Float, Float)> {
+        let origin = ray.origin();
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let mut t0 = (self.min[axis] - origin[axis]) * inverse_direction[axis];
+            let mut t1 = (self.max[axis] - origin[axis]) * inverse_direction[axis];
+            if inverse_direction[axis] < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // Rounded up, for the rays grazing the box at the vertices of watertight meshes
+            t1 *= 1.0 + 3.0 * Float::EPSILON;
+            // Written so that NaNs, from rays in the plane of a slab, don't reject the hit
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit() {
+        let aabb = Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let hit = |origin: Point3, direction: Vec3| {
+            let inverse = Vec3::new(
+                1.0 / direction.x(),
+                1.0 / direction.y(),
+                1.0 / direction.z(),
+            );
+            aabb.hit(&Ray::new(origin, direction), &inverse, 0.001, Float::MAX)
+        };
+        assert!(hit(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0)));
+        assert!(hit(Point3::new(-5.0, -5.0, -5.0), Vec3::new(1.0, 1.0, 1.0)));
+        assert!(!hit(Point3::new(0.0, 2.0, -5.0), Vec3::new(0.0, 0.0, 1.0)));
+        // Behind the origin
+        assert!(!hit(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn test_union_and_area() {
+        let a = Aabb::new(Point3::zero(), Point3::new(1.0, 1.0, 1.0));
+        let b = Aabb::around(Point3::new(2.0, 0.5, 0.5), 0.5);
+        assert_eq!(Aabb::empty().union(&a), a);
+        assert_eq!(a.union(&b).extent(), Vec3::new(2.5, 1.0, 1.0));
+        assert_eq!(a.union(&b).longest_axis(), 0);
+        assert_eq!(a.surface_area(), 6.0);
+        assert_eq!(Aabb::empty().surface_area(), 0.0);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::bvh::Bvh;
+use crate::kdtree::KdTree;
+use crate::object::Hittable;
+use crate::settings::RenderSettings;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Spatial structure over a collection of objects, finding the closest hit of a ray without
+/// testing all of them.
+pub trait Accelerator: Hittable {
+    /// Number of objects in the structure.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All the objects, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = &dyn Hittable> + '_>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcceleratorType {
+    /// Bounding volume hierarchy, the best all-rounder.
+    Bvh,
+    /// kd-tree, sometimes faster for scenes of many axis-aligned objects.
+    KdTree,
+}
+
+impl AcceleratorType {
+    /// Builds the structure over `objects`, as configured by the `settings`.
+    pub fn build(
+        &self,
+        objects: Vec<Box<dyn Hittable>>,
+        settings: &RenderSettings,
+    ) -> Box<dyn Accelerator> {
+        match *self {
+            AcceleratorType::Bvh => Box::new(Bvh::new(objects, settings.bvh_split)),
+            AcceleratorType::KdTree => Box::new(KdTree::new(objects)),
+        }
+    }
+}
+
+impl fmt::Display for AcceleratorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            AcceleratorType::Bvh => "bvh",
+            AcceleratorType::KdTree => "kd-tree",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AcceleratorType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<AcceleratorType> {
+        match s {
+            "bvh" => Ok(AcceleratorType::Bvh),
+            "kd-tree" => Ok(AcceleratorType::KdTree),
+            _ => bail!("Unknown accelerator '{}', expected one of: bvh, kd-tree", s),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::camera::Camera;
+use crate::float::Float;
+use crate::settings::RenderSettings;
+use crate::vec3::{Point3, Vec3};
+use anyhow::{bail, Result};
+use std::ops::{Add, Mul};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe<T> {
+    /// Seconds from the start of the animation.
+    pub time: Float,
+    pub value: T,
+}
+
+/// Value animated by linear interpolation between keyframes, constant before the first and
+/// after the last one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track<T> {
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T> Track<T>
+where
+    T: Copy + Add<Output = T> + Mul<Float, Output = T>,
+{
+    /// Track of `keyframes`, in any order. Those at NaN times are sorted last.
+    pub fn new(mut keyframes: Vec<Keyframe<T>>) -> Track<T> {
+        assert!(!keyframes.is_empty(), "A track needs at least one keyframe");
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Track { keyframes }
+    }
+
+    pub fn constant(value: T) -> Track<T> {
+        Track::new(vec![Keyframe { time: 0.0, value }])
+    }
+
+    pub fn duration(&self) -> Float {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+
+    pub fn sample(&self, time: Float) -> T {
+        let next = self.keyframes.iter().position(|k| k.time > time);
+        match next {
+            None => self.keyframes[self.keyframes.len() - 1].value,
+            Some(0) => self.keyframes[0].value,
+            Some(i) => {
+                let k0 = self.keyframes[i - 1];
+                let k1 = self.keyframes[i];
+                let t = (time - k0.time) / (k1.time - k0.time);
+                k0.value * (1.0 - t) + k1.value * t
+            }
+        }
+    }
+}
+
+/// Keyframed camera, focused on the point it looks at.
+#[derive(Clone, Debug)]
+pub struct CameraPath {
+    pub look_from: Track<Point3>,
+    pub look_at: Track<Point3>,
+    pub vertical_fov_deg: Track<Float>,
+    pub v_up: Vec3,
+    pub aperture: Float,
+}
+
+impl CameraPath {
+    pub fn duration(&self) -> Float {
+        self.look_from
+            .duration()
+            .max(self.look_at.duration())
+            .max(self.vertical_fov_deg.duration())
+    }
+
+    pub fn camera_at(&self, time: Float, aspect_ratio: Float) -> Camera {
+        let look_from = self.look_from.sample(time);
+        let look_at = self.look_at.sample(time);
+        Camera::new(
+            look_from,
+            look_at,
+            self.v_up,
+            self.vertical_fov_deg.sample(time),
+            aspect_ratio,
+            self.aperture,
+            (look_from - look_at).length(),
+        )
+    }
+}
+
+/// Fraction of the samples of a frame which the next one renews with `FrameSeed::Correlated`.
+const CORRELATED_RENEWAL: u32 = 4;
+
+/// How the random numbers of the samples change from one frame of an animation to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameSeed {
+    /// The same numbers for every frame: the noise stays still on the screen while the scene
+    /// moves behind it.
+    Fixed,
+    /// Other numbers for every frame: the noise of each frame is independent, and flickers.
+    Varying,
+    /// Each frame keeping most of the samples of the previous one and renewing the others, a
+    /// quarter of them: the noise changes gradually, neither flickering nor staying still.
+    Correlated,
+}
+
+impl FrameSeed {
+    /// Settings of the `frame`-th frame, counting from zero, of an animation rendered with
+    /// `settings`.
+    pub fn frame_settings(&self, settings: &RenderSettings, frame: u32) -> RenderSettings {
+        match *self {
+            FrameSeed::Fixed => *settings,
+            FrameSeed::Varying => RenderSettings {
+                // Golden ratio increments, spreading the seeds of the frames apart
+                seed: settings.seed ^ (frame as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+                ..*settings
+            },
+            FrameSeed::Correlated => {
+                // Sliding the window of the sample indices of the pixels
+                let stride = (settings.samples_per_pixel as u32).div_ceil(CORRELATED_RENEWAL);
+                RenderSettings {
+                    first_sample: settings.first_sample + frame * stride,
+                    ..*settings
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for FrameSeed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<FrameSeed> {
+        match s {
+            "fixed" => Ok(FrameSeed::Fixed),
+            "varying" => Ok(FrameSeed::Varying),
+            "correlated" => Ok(FrameSeed::Correlated),
+            _ => bail!(
+                "Unknown frame seed '{}', expected one of: fixed, varying, correlated",
+                s
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_sample() {
+        let track = Track::new(vec![
+            Keyframe {
+                time: 2.0,
+                value: 10.0,
+            },
+            Keyframe {
+                time: 1.0,
+                value: 0.0,
+            },
+        ]);
+        assert_eq!(track.duration(), 2.0);
+        assert_eq!(track.sample(0.0), 0.0);
+        assert_eq!(track.sample(1.5), 5.0);
+        assert_eq!(track.sample(3.0), 10.0);
+    }
+
+    #[test]
+    fn test_track_nan_time() {
+        let track = Track::new(vec![
+            Keyframe {
+                time: Float::NAN,
+                value: 1.0,
+            },
+            Keyframe {
+                time: 0.0,
+                value: 0.0,
+            },
+        ]);
+        assert_eq!(track.keyframes[0].time, 0.0);
+        assert!(track.keyframes[1].time.is_nan());
+    }
+
+    #[test]
+    fn test_track_sample_vec3() {
+        let track = Track::new(vec![
+            Keyframe {
+                time: 0.0,
+                value: Point3::zero(),
+            },
+            Keyframe {
+                time: 4.0,
+                value: Point3::new(4.0, 8.0, 0.0),
+            },
+        ]);
+        assert_eq!(track.sample(1.0), Point3::new(1.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn test_frame_seed() {
+        let settings = RenderSettings {
+            samples_per_pixel: 10,
+            seed: 7,
+            ..RenderSettings::default()
+        };
+        for frame in 0..3 {
+            assert_eq!(FrameSeed::Fixed.frame_settings(&settings, frame), settings);
+        }
+
+        let varying = |frame| FrameSeed::Varying.frame_settings(&settings, frame);
+        assert_eq!(varying(0), settings);
+        assert_ne!(varying(1).seed, settings.seed);
+        assert_ne!(varying(2).seed, varying(1).seed);
+        assert_eq!(varying(1).first_sample, 0);
+
+        // 3 of the samples renewed by each frame, the frames 4 apart sharing none
+        let correlated = |frame| FrameSeed::Correlated.frame_settings(&settings, frame);
+        assert_eq!(correlated(0), settings);
+        assert_eq!(correlated(1).first_sample, 3);
+        assert_eq!(correlated(4).first_sample, 12);
+        assert_eq!(correlated(4).seed, settings.seed);
+
+        assert!("still".parse::<FrameSeed>().is_err());
+        assert_eq!(
+            "correlated".parse::<FrameSeed>().unwrap(),
+            FrameSeed::Correlated
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use crate::background::Background;
+use crate::camera::Camera;
+use crate::float::Float;
+use crate::material::{Material, MaterialList};
+use crate::object::{HitRecord, Hittable, ObjectId};
+use crate::ray::Ray;
+use crate::tonemap::luminance;
+use crate::util::clamp;
+use crate::vec3::{Color, Point3, Vec3};
+
+/// Arbitrary output variables of one camera ray, taken at its first hit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AovSample {
+    pub albedo: Color,
+    pub normal: Vec3,
+    /// Distance from the ray origin, infinite if nothing was hit.
+    pub depth: Float,
+    /// Object hit, none for the background.
+    pub object: Option<ObjectId>,
+    /// Point hit, none for the background.
+    pub position: Option<Point3>,
+}
+
+impl AovSample {
+    /// Sample where the camera sees nothing, not even the background.
+    pub fn empty() -> AovSample {
+        AovSample {
+            albedo: Color::zero(),
+            normal: Vec3::zero(),
+            depth: Float::INFINITY,
+            object: None,
+            position: None,
+        }
+    }
+
+    pub fn trace<H: Hittable + ?Sized, B: Background + ?Sized>(
+        ray: &Ray,
+        world: &H,
+        materials: &MaterialList,
+        background: &B,
+    ) -> AovSample {
+        let mut hit_record = HitRecord::empty();
+        if !world.hit(ray, 0.0, Float::MAX, &mut hit_record) {
+            return AovSample {
+                albedo: background.color(ray.direction()),
+                normal: Vec3::zero(),
+                depth: Float::INFINITY,
+                object: None,
+                position: None,
+            };
+        }
+
+        let material = &materials[hit_record.material];
+        let albedo = match material {
+            Material::DiffuseLight(_) => {
+                let emit = material.albedo();
+                Color::new(
+                    clamp(emit.x(), 0.0, 1.0),
+                    clamp(emit.y(), 0.0, 1.0),
+                    clamp(emit.z(), 0.0, 1.0),
+                )
+            }
+            _ => material.albedo(),
+        };
+
+        AovSample {
+            albedo,
+            normal: hit_record.normal,
+            depth: hit_record.t * ray.direction().length(),
+            object: Some(hit_record.object),
+            position: Some(hit_record.point),
+        }
+    }
+}
+
+/// Per-pixel albedo, shading normal, depth and position, averaged over the pixel samples,
+/// object id and variance.
+pub struct AovBuffers {
+    width: usize,
+    height: usize,
+    albedo: Vec<Color>,
+    normal: Vec<Vec3>,
+    depth: Vec<Float>,
+    /// Object seen by most of the samples of the pixel, ids not being averageable.
+    object_ids: Vec<Option<ObjectId>>,
+    /// First hit of the samples that hit something.
+    positions: Vec<Option<Point3>>,
+    /// Estimated variance of the luminance of the pixel, averaging the colors of its samples.
+    variance: Vec<Float>,
+}
+
+impl AovBuffers {
+    pub fn new(width: usize, height: usize) -> AovBuffers {
+        AovBuffers {
+            width,
+            height,
+            albedo: Vec::with_capacity(width * height),
+            normal: Vec::with_capacity(width * height),
+            depth: Vec::with_capacity(width * height),
+            object_ids: Vec::with_capacity(width * height),
+            positions: Vec::with_capacity(width * height),
+            variance: Vec::with_capacity(width * height),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Appends the next pixel, in output order, with the colors of the samples rendered for it,
+    /// fewer than `samples` when resuming.
+    pub fn push(&mut self, samples: &[AovSample], colors: &[Color]) {
+        let count = samples.len() as Float;
+        let mut albedo = Color::zero();
+        let mut normal = Vec3::zero();
+        let mut depth = 0.0;
+        let mut position = Point3::zero();
+        let mut hits = 0;
+        let mut object_counts: Vec<(Option<ObjectId>, usize)> = Vec::new();
+
+        for sample in samples {
+            albedo += sample.albedo;
+            normal += sample.normal;
+            if let Some(p) = sample.position {
+                depth += sample.depth;
+                position += p;
+                hits += 1;
+            }
+            match object_counts
+                .iter_mut()
+                .find(|(id, _)| *id == sample.object)
+            {
+                Some((_, count)) => *count += 1,
+                None => object_counts.push((sample.object, 1)),
+            }
+        }
+
+        self.albedo.push(albedo / count);
+        self.normal.push(normal / count);
+        if hits > 0 {
+            self.depth.push(depth / hits as Float);
+            self.positions.push(Some(position / hits as Float));
+        } else {
+            self.depth.push(Float::INFINITY);
+            self.positions.push(None);
+        }
+        // Ties go to the first object seen
+        let object = object_counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .and_then(|(id, _)| *id);
+        self.object_ids.push(object);
+        self.variance.push(variance_of_mean(colors));
+    }
+
+    pub fn albedo(&self) -> &[Color] {
+        &self.albedo
+    }
+
+    pub fn normal(&self) -> &[Vec3] {
+        &self.normal
+    }
+
+    pub fn depth(&self) -> &[Float] {
+        &self.depth
+    }
+
+    pub fn object_ids(&self) -> &[Option<ObjectId>] {
+        &self.object_ids
+    }
+
+    pub fn positions(&self) -> &[Option<Point3>] {
+        &self.positions
+    }
+
+    pub fn variance(&self) -> &[Float] {
+        &self.variance
+    }
+
+    /// Variance as gray levels, for floating point images.
+    pub fn variance_image(&self) -> Vec<Color> {
+        self.variance.iter().map(|&v| Color::new(v, v, v)).collect()
+    }
+
+    /// Motion of each pixel since the previous frame, in pixels towards the right and the
+    /// bottom of the image: the position seen through `camera` minus the one seen through
+    /// `previous_camera`. Zero for the background and for the points the previous camera
+    /// didn't see, the motion of the scene itself not being tracked.
+    pub fn motion_vectors(&self, camera: &Camera, previous_camera: &Camera) -> Vec<(Float, Float)> {
+        // Image coordinates span (width - 1) by (height - 1) pixels, as in `Scene::camera_ray`
+        let scale = (
+            (self.width.max(2) - 1) as Float,
+            (self.height.max(2) - 1) as Float,
+        );
+        self.positions
+            .iter()
+            .map(|position| {
+                let projections = position
+                    .and_then(|p| Some((camera.project(&p)?, previous_camera.project(&p)?)));
+                match projections {
+                    Some(((s, t), (previous_s, previous_t))) => {
+                        ((s - previous_s) * scale.0, (previous_t - t) * scale.1)
+                    }
+                    None => (0.0, 0.0),
+                }
+            })
+            .collect()
+    }
+
+    /// Motion vectors as colors, x and y in the red and green channels, for floating point
+    /// images.
+    pub fn motion_image(&self, camera: &Camera, previous_camera: &Camera) -> Vec<Color> {
+        self.motion_vectors(camera, previous_camera)
+            .into_iter()
+            .map(|(x, y)| Color::new(x, y, 0.0))
+            .collect()
+    }
+
+    /// Normals remapped from [-1, 1] to [0, 1] for display.
+    pub fn normal_image(&self) -> Vec<Color> {
+        self.normal
+            .iter()
+            .map(|n| 0.5 * (*n + Vec3::new(1.0, 1.0, 1.0)))
+            .collect()
+    }
+
+    /// Depth normalized by the farthest hit, white being the farthest and the background.
+    pub fn depth_image(&self) -> Vec<Color> {
+        let max_depth = self
+            .depth
+            .iter()
+            .filter(|d| d.is_finite())
+            .fold(0.0 as Float, |max, &d| max.max(d));
+
+        self.depth
+            .iter()
+            .map(|&d| {
+                let v = if d.is_finite() && max_depth > 0.0 {
+                    d / max_depth
+                } else {
+                    1.0
+                };
+                Color::new(v, v, v)
+            })
+            .collect()
+    }
+
+    /// A color per object, picked by hashing its id so that neighbouring objects are told
+    /// apart, and black for the background.
+    pub fn object_id_image(&self) -> Vec<Color> {
+        self.object_ids
+            .iter()
+            .map(|id| id.map_or(Color::zero(), object_id_color))
+            .collect()
+    }
+}
+
+/// Variance of the average luminance of `colors`, from their sample variance. Zero for fewer
+/// than two colors, there being nothing to estimate it from.
+fn variance_of_mean(colors: &[Color]) -> Float {
+    if colors.len() < 2 {
+        return 0.0;
+    }
+    let count = colors.len() as Float;
+    let mean = colors.iter().map(|c| luminance(*c)).sum::<Float>() / count;
+    let squares: Float = colors.iter().map(|c| (luminance(*c) - mean).powi(2)).sum();
+    squares / (count - 1.0) / count
+}
+
+/// Colors of a ramp from dark blue through cyan, green and yellow to red, for `values` relative
+/// to the largest of them but a percent, for the few outliers not to wash the others out.
+/// Values that aren't finite are red, as the largest.
+pub fn heatmap(values: &[Float]) -> Vec<Color> {
+    let mut sorted: Vec<Float> = values.iter().copied().filter(|v| v.is_finite()).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let scale = sorted
+        .get((sorted.len() as Float * HEATMAP_PERCENTILE) as usize)
+        .or_else(|| sorted.last())
+        .copied()
+        .unwrap_or(0.0);
+    values
+        .iter()
+        .map(|&v| {
+            let t = if !v.is_finite() {
+                1.0
+            } else if scale > 0.0 {
+                (v / scale).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            heatmap_color(t)
+        })
+        .collect()
+}
+
+/// Share of the values of a heatmap below its top color.
+const HEATMAP_PERCENTILE: Float = 0.99;
+
+fn heatmap_color(t: Float) -> Color {
+    const STOPS: [[Float; 3]; 5] = [
+        [0.0, 0.0, 0.3],
+        [0.0, 0.6, 1.0],
+        [0.0, 0.8, 0.2],
+        [1.0, 0.9, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+    let position = t * (STOPS.len() - 1) as Float;
+    let i = (position as usize).min(STOPS.len() - 2);
+    let f = position - i as Float;
+    let [a, b] = [STOPS[i], STOPS[i + 1]];
+    Color::new(
+        a[0] + f * (b[0] - a[0]),
+        a[1] + f * (b[1] - a[1]),
+        a[2] + f * (b[2] - a[2]),
+    )
+}
+
+fn object_id_color(id: ObjectId) -> Color {
+    // Finalizer of MurmurHash3, mixing all the bits of the id
+    let mut h = id.index() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+
+    // Kept away from black to stay distinct from the background
+    let channel = |shift: u32| 0.2 + 0.8 * ((h >> shift) & 0xff) as Float / 255.0;
+    Color::new(channel(0), channel(8), channel(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_averages_hits() {
+        let hit = AovSample {
+            albedo: Color::new(1.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            depth: 4.0,
+            object: Some(ObjectId::new(3)),
+            position: Some(Point3::new(0.0, 4.0, 0.0)),
+        };
+        let miss = AovSample {
+            albedo: Color::new(0.0, 0.0, 1.0),
+            normal: Vec3::zero(),
+            depth: Float::INFINITY,
+            object: None,
+            position: None,
+        };
+
+        let mut buffers = AovBuffers::new(2, 1);
+        buffers.push(&[hit, miss], &[]);
+        buffers.push(&[miss], &[]);
+
+        assert_eq!(buffers.albedo()[0], Color::new(0.5, 0.0, 0.5));
+        assert_eq!(buffers.normal()[0], Vec3::new(0.0, 0.5, 0.0));
+        assert_eq!(buffers.depth()[0], 4.0);
+        assert_eq!(buffers.depth()[1], Float::INFINITY);
+        assert_eq!(
+            buffers.depth_image(),
+            vec![Color::new(1.0, 1.0, 1.0), Color::new(1.0, 1.0, 1.0)]
+        );
+        assert_eq!(buffers.object_ids(), &[Some(ObjectId::new(3)), None]);
+        assert_eq!(
+            buffers.positions(),
+            &[Some(Point3::new(0.0, 4.0, 0.0)), None]
+        );
+    }
+
+    #[test]
+    fn test_object_id_is_most_frequent() {
+        let sample = |object: Option<usize>| AovSample {
+            albedo: Color::zero(),
+            normal: Vec3::zero(),
+            depth: 1.0,
+            object: object.map(ObjectId::new),
+            position: Some(Point3::zero()),
+        };
+
+        let mut buffers = AovBuffers::new(2, 1);
+        buffers.push(
+            &[
+                sample(Some(1)),
+                sample(None),
+                sample(Some(2)),
+                sample(Some(2)),
+            ],
+            &[],
+        );
+        buffers.push(&[sample(None), sample(None), sample(Some(0))], &[]);
+        assert_eq!(buffers.object_ids(), &[Some(ObjectId::new(2)), None]);
+
+        let colors = buffers.object_id_image();
+        assert_ne!(colors[0], Color::zero());
+        assert_eq!(colors[1], Color::zero());
+        assert_ne!(
+            object_id_color(ObjectId::new(0)),
+            object_id_color(ObjectId::new(1))
+        );
+    }
+
+    #[test]
+    fn test_motion_vectors() {
+        let sample = |position| AovSample {
+            albedo: Color::zero(),
+            normal: Vec3::zero(),
+            depth: 1.0,
+            object: None,
+            position,
+        };
+        let camera_at = |x| {
+            Camera::new(
+                Point3::new(x, 0.0, 10.0),
+                Point3::new(x, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                90.0,
+                1.0,
+                0.0,
+                10.0,
+            )
+        };
+
+        // 21 pixels across the 20 units of the viewport at the plane of focus
+        let mut buffers = AovBuffers::new(21, 21);
+        buffers.push(&[sample(Some(Point3::new(2.0, 3.0, 0.0)))], &[]);
+        buffers.push(&[sample(None)], &[]);
+
+        // The camera moved 1 unit to the right, the point a pixel to the left
+        let motion = buffers.motion_vectors(&camera_at(1.0), &camera_at(0.0));
+        assert!((motion[0].0 + 1.0).abs() < 1e-4 && motion[0].1.abs() < 1e-4);
+        assert_eq!(motion[1], (0.0, 0.0));
+
+        // The camera moved down, the point up, towards the top row
+        let lowered = Camera::new(
+            Point3::new(0.0, -1.0, 10.0),
+            Point3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            1.0,
+            0.0,
+            10.0,
+        );
+        let motion = buffers.motion_vectors(&lowered, &camera_at(0.0));
+        assert!(motion[0].0.abs() < 1e-4 && (motion[0].1 + 1.0).abs() < 1e-4);
+
+        assert_eq!(
+            buffers.motion_vectors(&camera_at(0.0), &camera_at(0.0)),
+            vec![(0.0, 0.0); 2]
+        );
+    }
+
+    #[test]
+    fn test_variance() {
+        let gray = |v| Color::new(v, v, v);
+        let mut buffers = AovBuffers::new(3, 1);
+        buffers.push(&[], &[gray(1.0), gray(3.0), gray(1.0), gray(3.0)]);
+        buffers.push(&[], &[gray(2.0), gray(2.0)]);
+        buffers.push(&[], &[gray(5.0)]);
+        // Sample variance 4/3, over 4 samples
+        assert!((buffers.variance()[0] - 1.0 / 3.0).abs() < 1e-5);
+        assert_eq!(&buffers.variance()[1..], &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let mut values: Vec<Float> = (0..=100).map(|v| v as Float).collect();
+        values.push(Float::INFINITY);
+        let colors = heatmap(&values);
+        assert_eq!(colors[0], Color::new(0.0, 0.0, 0.3));
+        // The largest values are past the percentile
+        assert_eq!(colors[100], Color::new(1.0, 0.0, 0.0));
+        assert_eq!(colors[101], Color::new(1.0, 0.0, 0.0));
+        assert!(colors[50].y() > colors[50].x() && colors[50].y() > colors[50].z());
+        assert_eq!(heatmap(&[0.0, 0.0]), vec![Color::new(0.0, 0.0, 0.3); 2]);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::material::MaterialId;
+use crate::mesh::Mesh;
+use crate::texture::ImageTexture;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Files loaded while setting up a scene, so that textures and meshes referenced several times
+/// are read once and shared. Files are only loaded when first asked for.
+///
+/// Shared meshes are placed in the scene with instances. Meshes hold their material, so the
+/// same file with another material is a separate mesh.
+#[derive(Default)]
+pub struct Assets {
+    textures: HashMap<PathBuf, Arc<ImageTexture>>,
+    opacity_maps: HashMap<PathBuf, Arc<ImageTexture>>,
+    meshes: HashMap<(PathBuf, MaterialId), Arc<Mesh>>,
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets::default()
+    }
+
+    pub fn texture<P: AsRef<Path>>(&mut self, path: P) -> Result<Arc<ImageTexture>> {
+        let key = cache_key(path.as_ref());
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let texture = Arc::new(ImageTexture::load(path)?);
+        self.textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+
+    /// Opacity of an image for cutouts, see `ImageTexture::load_opacity`.
+    pub fn opacity_map<P: AsRef<Path>>(&mut self, path: P) -> Result<Arc<ImageTexture>> {
+        let key = cache_key(path.as_ref());
+        if let Some(opacity) = self.opacity_maps.get(&key) {
+            return Ok(opacity.clone());
+        }
+        let opacity = Arc::new(ImageTexture::load_opacity(path)?);
+        self.opacity_maps.insert(key, opacity.clone());
+        Ok(opacity)
+    }
+
+    pub fn mesh<P: AsRef<Path>>(&mut self, path: P, material: MaterialId) -> Result<Arc<Mesh>> {
+        let key = (cache_key(path.as_ref()), material);
+        if let Some(mesh) = self.meshes.get(&key) {
+            return Ok(mesh.clone());
+        }
+        let mesh = Arc::new(Mesh::load(path, material)?);
+        self.meshes.insert(key, mesh.clone());
+        Ok(mesh)
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let textures = || self.textures.values().chain(self.opacity_maps.values());
+        MemoryUsage {
+            texture_count: textures().count(),
+            texture_bytes: textures().map(|t| t.memory_size()).sum(),
+            mesh_count: self.meshes.len(),
+            mesh_bytes: self.meshes.values().map(|m| m.memory_size()).sum(),
+        }
+    }
+}
+
+/// Same key for all the paths of a file, when it exists.
+fn cache_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Memory taken by the loaded assets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryUsage {
+    pub texture_count: usize,
+    pub texture_bytes: usize,
+    pub mesh_count: usize,
+    pub mesh_bytes: usize,
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let megabytes = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        write!(
+            f,
+            "{} textures ({:.1} MiB), {} meshes ({:.1} MiB)",
+            self.texture_count,
+            megabytes(self.texture_bytes),
+            self.mesh_count,
+            megabytes(self.mesh_bytes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loaded_once() {
+        let dir = std::env::temp_dir();
+        let texture_path = dir.join("rust-ray-tracing-test-texture.png");
+        image::RgbImage::new(4, 2).save(&texture_path).unwrap();
+        let mesh_path = dir.join("rust-ray-tracing-test-mesh.obj");
+        fs::write(&mesh_path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+
+        let mut assets = Assets::new();
+        let texture = assets.texture(&texture_path).unwrap();
+        let same_texture = assets.texture(dir.join(".").join(texture_path.file_name().unwrap()));
+        assert!(Arc::ptr_eq(&texture, &same_texture.unwrap()));
+        let opacity = assets.opacity_map(&texture_path).unwrap();
+        assert!(!Arc::ptr_eq(&texture, &opacity));
+        assert!(Arc::ptr_eq(
+            &opacity,
+            &assets.opacity_map(&texture_path).unwrap()
+        ));
+
+        let material = MaterialId::default();
+        let mesh = assets.mesh(&mesh_path, material).unwrap();
+        assert!(Arc::ptr_eq(
+            &mesh,
+            &assets.mesh(&mesh_path, material).unwrap()
+        ));
+        assert!(assets
+            .texture(dir.join("rust-ray-tracing-missing.png"))
+            .is_err());
+
+        fs::remove_file(&texture_path).unwrap();
+        fs::remove_file(&mesh_path).unwrap();
+
+        let usage = assets.memory_usage();
+        assert_eq!(usage.texture_count, 2);
+        assert_eq!(
+            usage.texture_bytes,
+            texture.memory_size() + opacity.memory_size()
+        );
+        assert_eq!(usage.mesh_count, 1);
+        assert_eq!(usage.mesh_bytes, mesh.memory_size());
+    }
+}
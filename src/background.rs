@@ -0,0 +1,368 @@
+use crate::float::{Float, PI};
+use crate::onb::Onb;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Color, Vec3};
+use anyhow::{Context, Result};
+use rand::Rng;
+use std::any::Any;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Radiance returned for rays escaping the scene.
+pub trait Background: Any {
+    fn color(&self, direction: Vec3) -> Color;
+
+    /// Density, with respect to solid angle, of sampling `direction` with `random`. Backgrounds
+    /// without bright features worth sampling as lights leave it to 0.
+    fn pdf_value(&self, _direction: &Vec3) -> Float {
+        0.0
+    }
+
+    /// Random direction towards the bright features of the background.
+    fn random(&self, _rng: &mut SampleRng) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+}
+
+// -------------
+//  SOLID COLOR
+// -------------
+
+#[derive(Clone)]
+pub struct SolidColor {
+    pub(crate) color: Color,
+}
+
+impl SolidColor {
+    pub fn new(color: Color) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Background for SolidColor {
+    fn color(&self, _direction: Vec3) -> Color {
+        self.color
+    }
+}
+
+// ----------
+//  GRADIENT
+// ----------
+
+/// Vertical blend from `bottom` (looking down) to `top` (looking up).
+#[derive(Clone)]
+pub struct Gradient {
+    pub(crate) bottom: Color,
+    pub(crate) top: Color,
+}
+
+impl Gradient {
+    pub fn new(bottom: Color, top: Color) -> Gradient {
+        Gradient { bottom, top }
+    }
+}
+
+impl Background for Gradient {
+    fn color(&self, direction: Vec3) -> Color {
+        let unit_direction = unit_vector(direction);
+        let t = 0.5 * (unit_direction.y() + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+// ----------------------
+//  EQUIRECTANGULAR HDR
+// ----------------------
+
+/// Latitude-longitude environment map, +y being the top row of the image. Clones share the
+/// pixels.
+#[derive(Clone)]
+pub struct EquirectangularHdr {
+    width: usize,
+    height: usize,
+    pixels: Arc<[Color]>,
+    intensity: Float,
+}
+
+impl EquirectangularHdr {
+    pub fn new(
+        width: usize,
+        height: usize,
+        pixels: Vec<Color>,
+        intensity: Float,
+    ) -> EquirectangularHdr {
+        assert_eq!(width * height, pixels.len());
+        EquirectangularHdr {
+            width,
+            height,
+            pixels: pixels.into(),
+            intensity,
+        }
+    }
+
+    /// Loads a Radiance `.hdr` file.
+    pub fn load<P: AsRef<Path>>(path: P, intensity: Float) -> Result<EquirectangularHdr> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load environment map {}", path.display()))?
+            .into_rgb32f();
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .pixels()
+            .map(|p| Color::new(p[0] as Float, p[1] as Float, p[2] as Float))
+            .collect();
+
+        Ok(EquirectangularHdr::new(width, height, pixels, intensity))
+    }
+}
+
+impl Background for EquirectangularHdr {
+    fn color(&self, direction: Vec3) -> Color {
+        let unit_direction = unit_vector(direction);
+        let theta = (-unit_direction.y()).acos();
+        let phi = (-unit_direction.z()).atan2(unit_direction.x()) + PI;
+
+        let u = phi / (2.0 * PI);
+        let v = theta / PI;
+
+        let col = ((u * self.width as Float) as usize).min(self.width - 1);
+        let row = (((1.0 - v) * self.height as Float) as usize).min(self.height - 1);
+        self.intensity * self.pixels[row * self.width + col]
+    }
+}
+
+// -----------
+//  SUN & SKY
+// -----------
+
+/// Angular radius of the sun seen from the ground.
+const SUN_ANGULAR_RADIUS: Float = 0.004_65;
+/// Luminance of the sun outside of the atmosphere, in kcd/m^2 like the sky.
+const SUN_LUMINANCE: Float = 1.6e6;
+
+/// Preetham's analytic daylight model ("A Practical Analytic Model for Daylight", 1999): a clear
+/// sky lit by a sun disk, both colored by the atmosphere according to its `turbidity` (2 for a
+/// very clear sky, 10 for a hazy one).
+///
+/// Luminances are in kcd/m^2, scaled by `intensity`. The default intensity brings a white
+/// diffuse surface under a high sun to about 1.
+#[derive(Clone)]
+pub struct SunSky {
+    sun_direction: Vec3,
+    sun_frame: Onb,
+    sun_radiance: Color,
+    /// Squared distance between unit vectors at the angular radius of the sun, which unlike
+    /// its cosine keeps its precision for such a small angle.
+    sun_chord_squared: Float,
+    /// Perez coefficients A to E of the luminance Y and the chromaticities x and y.
+    perez: [[Float; 5]; 3],
+    /// Zenith luminance and chromaticities divided by the Perez function at the zenith.
+    zenith: [Float; 3],
+    intensity: Float,
+}
+
+impl SunSky {
+    /// Sun `elevation_deg` above the horizon, and `azimuth_deg` from +x towards +z.
+    pub fn new(elevation_deg: Float, azimuth_deg: Float, turbidity: Float) -> SunSky {
+        let elevation = elevation_deg.to_radians();
+        let azimuth = azimuth_deg.to_radians();
+        let sun_direction = Vec3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.sin(),
+            elevation.cos() * azimuth.sin(),
+        );
+        let t = turbidity;
+        let theta_s = PI / 2.0 - elevation;
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let cubic = |c: [Float; 4]| {
+            c[0] * theta_s * theta_s * theta_s + c[1] * theta_s * theta_s + c[2] * theta_s + c[3]
+        };
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let zenith_values = [zenith_luminance, zenith_x, zenith_y];
+        let mut zenith = [0.0; 3];
+        for i in 0..3 {
+            zenith[i] = zenith_values[i] / perez_function(&perez[i], 1.0, theta_s.cos());
+        }
+
+        SunSky {
+            sun_direction,
+            sun_frame: Onb::build_from_w(&sun_direction),
+            sun_radiance: SUN_LUMINANCE * sun_transmittance(theta_s, turbidity),
+            sun_chord_squared: (2.0 * (SUN_ANGULAR_RADIUS / 2.0).sin()).powi(2),
+            perez,
+            zenith,
+            intensity: 1.0 / 40.0,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: Float) -> SunSky {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+
+    fn is_in_sun(&self, unit_direction: &Vec3) -> bool {
+        (*unit_direction - self.sun_direction).length_squared() <= self.sun_chord_squared
+    }
+
+    fn sky_color(&self, direction: &Vec3) -> Color {
+        // The model is only defined above the horizon, the sky right above it is extended below
+        let cos_theta = direction.y().max(0.001);
+        let cos_gamma = direction.dot(&self.sun_direction);
+
+        let mut values = [0.0; 3];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = self.zenith[i] * perez_function(&self.perez[i], cos_theta, cos_gamma);
+        }
+        xyy_to_rgb(values[1], values[2], values[0])
+    }
+}
+
+impl Background for SunSky {
+    fn color(&self, direction: Vec3) -> Color {
+        let direction = unit_vector(direction);
+        let mut color = self.sky_color(&direction);
+        if self.is_in_sun(&direction) {
+            color += self.sun_radiance;
+        }
+        self.intensity * color
+    }
+
+    fn pdf_value(&self, direction: &Vec3) -> Float {
+        if !self.is_in_sun(&unit_vector(*direction)) {
+            return 0.0;
+        }
+        // Solid angle 2 PI (1 - cos(r)) of the sun
+        1.0 / (PI * self.sun_chord_squared)
+    }
+
+    fn random(&self, rng: &mut SampleRng) -> Vec3 {
+        // Uniform in the cone, working with 1 - cos(theta) rather than cos(theta) which
+        // rounds to 1 in single precision
+        let one_minus_cos = rng.gen::<Float>() * 0.5 * self.sun_chord_squared;
+        let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).sqrt();
+        let phi = 2.0 * PI * rng.gen::<Float>();
+        self.sun_frame.local(&Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            1.0 - one_minus_cos,
+        ))
+    }
+}
+
+/// Perez's sky distribution for a direction at angle theta from the zenith and gamma from the
+/// sun.
+fn perez_function(c: &[Float; 5], cos_theta: Float, cos_gamma: Float) -> Float {
+    let gamma = cos_gamma.clamp(-1.0, 1.0).acos();
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+/// Fraction of the sunlight reaching the ground through the Rayleigh and aerosol scattering
+/// of the atmosphere, per channel.
+fn sun_transmittance(theta_s: Float, turbidity: Float) -> Color {
+    if theta_s >= PI / 2.0 {
+        return Color::zero();
+    }
+    // Kasten and Young's relative air mass
+    let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+    let beta = 0.046_08 * turbidity - 0.045_86;
+    let channel = |wavelength: Float| {
+        let rayleigh = 0.008_735 * wavelength.powf(-4.08);
+        let aerosol = beta * wavelength.powf(-1.3);
+        (-air_mass * (rayleigh + aerosol)).exp()
+    };
+    // Wavelengths of the red, green and blue channels in micrometers
+    Color::new(channel(0.65), channel(0.55), channel(0.45))
+}
+
+/// Converts CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: Float, y: Float, luminance: Float) -> Color {
+    if y <= 0.0 {
+        return Color::zero();
+    }
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    Color::new(
+        (3.2406 * cx - 1.5372 * luminance - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * luminance + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * luminance + 1.0570 * cz).max(0.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gradient() {
+        let bottom = Color::new(1.0, 1.0, 1.0);
+        let top = Color::new(0.5, 0.7, 1.0);
+        let gradient = Gradient::new(bottom, top);
+        assert_eq!(gradient.color(Vec3::new(0.0, 2.0, 0.0)), top);
+        assert_eq!(gradient.color(Vec3::new(0.0, -2.0, 0.0)), bottom);
+    }
+
+    #[test]
+    fn test_equirectangular_poles() {
+        let up = Color::new(1.0, 0.0, 0.0);
+        let down = Color::new(0.0, 0.0, 1.0);
+        let map = EquirectangularHdr::new(1, 2, vec![up, down], 2.0);
+        assert_eq!(map.color(Vec3::new(0.0, 1.0, 0.0)), 2.0 * up);
+        assert_eq!(map.color(Vec3::new(0.0, -1.0, 0.0)), 2.0 * down);
+    }
+
+    #[test]
+    fn test_sun_sky() {
+        let sky = SunSky::new(30.0, 0.0, 3.0);
+        let sun = sky.sun_direction();
+        // Much brighter towards the sun, and bluer at the zenith than at the horizon
+        let zenith = sky.color(Vec3::new(0.0, 1.0, 0.0));
+        assert!(sky.color(sun).y() > 1000.0 * zenith.y());
+        assert!(zenith.z() > zenith.x());
+
+        let mut rng = SampleRng::new(0);
+        for _ in 0..100 {
+            let direction = sky.random(&mut rng);
+            assert!(sky.pdf_value(&direction) > 0.0);
+            assert!(sky.color(direction).y() > 1000.0 * zenith.y());
+        }
+        assert_eq!(sky.pdf_value(&Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+}
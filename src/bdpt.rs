@@ -0,0 +1,536 @@
+//! Bidirectional path tracing: a subpath is traced from the camera and another one from the
+//! lights, and each vertex of the first is connected to each vertex of the second. Paths of a
+//! given length can be built in as many ways as they have vertices, which are weighted against
+//! each other with multiple importance sampling. Light coming in through small openings, which
+//! paths from the camera rarely find, is carried to the surfaces it lights by the light paths.
+
+use crate::background::Background;
+use crate::float::{Float, PI};
+use crate::integrator::{
+    count_ray, crossed_surface, power_heuristic, sample_background, sample_delta_light,
+};
+use crate::light::{sample_emission, Light};
+use crate::light_bvh::LightBvh;
+use crate::material::{MaterialList, ScatterPdf, ScatterRecord, ScatterType, Scatterable};
+use crate::medium::MediumStack;
+use crate::object::{HitRecord, Hittable};
+use crate::pdf::Pdf;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::stats::STATS;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use rand::Rng;
+
+/// Number of vertices after which subpaths are randomly terminated, less often the more light
+/// their last bounce kept.
+const ROULETTE_START: usize = 3;
+
+/// Point where a subpath hit a surface.
+struct Vertex {
+    hit_record: HitRecord,
+    /// Ray which reached the vertex.
+    in_ray: Ray,
+    /// Weight of the subpath up to the vertex: the fraction of the radiance reaching the camera
+    /// for camera subpaths, the flux carried for light subpaths.
+    throughput: Color,
+    /// How the surface scatters light, None if it doesn't or only in a specular direction.
+    scatter_pdf: Option<ScatterPdf>,
+    /// Whether the surface scattered the subpath in a specular direction.
+    specular: bool,
+}
+
+/// Ray leaving the scene at the end of a subpath.
+struct Escaped {
+    ray: Ray,
+    throughput: Color,
+    /// Density of the BSDF sample which produced the ray, None for camera and specular rays.
+    bsdf_pdf: Option<Float>,
+}
+
+/// Vertex of a complete path, as needed to weight the ways of building it.
+#[derive(Clone, Copy, Debug)]
+struct MisVertex {
+    point: Point3,
+    normal: Vec3,
+    specular: bool,
+}
+
+impl From<&Vertex> for MisVertex {
+    fn from(vertex: &Vertex) -> MisVertex {
+        MisVertex {
+            point: vertex.hit_record.point,
+            normal: vertex.hit_record.normal,
+            specular: vertex.specular,
+        }
+    }
+}
+
+/// Bidirectional path traces `ray` through `world`, connecting the vertices of its path to
+/// the vertices of a path leaving one of the `lights`, which must be able to sample their
+/// surface. Paths have at most `bounce_limit` vertices.
+///
+/// The `delta_lights` and the `background` can't be reached from the light paths: they are
+/// sampled from the vertices of the camera path, as by `path_trace`.
+#[allow(clippy::too_many_arguments)]
+pub fn bidirectional_path_trace<H: Hittable + ?Sized, B: Background + ?Sized>(
+    rng: &mut SampleRng,
+    ray: &Ray,
+    world: &H,
+    lights: &LightBvh,
+    delta_lights: &[Light],
+    materials: &MaterialList,
+    background: &B,
+    bounce_limit: u16,
+) -> Color {
+    let max_vertices = bounce_limit as usize;
+    let lights: Vec<&dyn Hittable> = lights.iter().collect();
+
+    let mut camera_path = Vec::new();
+    let one = Color::new(1.0, 1.0, 1.0);
+    let escaped = random_walk(
+        rng,
+        world,
+        materials,
+        *ray,
+        one,
+        true,
+        max_vertices,
+        &mut camera_path,
+    );
+
+    // The light path needs one vertex less, the camera path having at least one
+    let mut light_path = Vec::new();
+    let emission = sample_emission(&lights, materials, rng);
+    if let Some(emission) = emission {
+        // The cosine-weighted direction cancels out the cosine at the light
+        let throughput = PI * emission.radiance / emission.pdf;
+        random_walk(
+            rng,
+            world,
+            materials,
+            emission.ray,
+            throughput,
+            false,
+            max_vertices.saturating_sub(2),
+            &mut light_path,
+        );
+    }
+    let mut light_mis: Vec<MisVertex> = light_path.iter().map(MisVertex::from).collect();
+    if let Some(emission) = emission {
+        light_mis.insert(
+            0,
+            MisVertex {
+                point: emission.ray.origin(),
+                normal: emission.normal,
+                specular: false,
+            },
+        );
+    }
+
+    let mut color = Color::zero();
+    let mut path = Vec::with_capacity(2 * max_vertices);
+    for (i, vertex) in camera_path.iter().enumerate() {
+        let camera_vertices = i + 1;
+        let material = &materials[vertex.hit_record.material];
+        let camera_mis = || {
+            camera_path[..camera_vertices]
+                .iter()
+                .rev()
+                .map(MisVertex::from)
+        };
+
+        // The camera path finding a light
+        let emitted = material.emitted(&vertex.in_ray, &vertex.hit_record);
+        if emitted != Color::zero() {
+            path.clear();
+            path.extend(camera_mis());
+            let light_pdf = lights
+                .iter()
+                .find(|light| finds(**light, vertex))
+                .map_or(0.0, |light| {
+                    light.surface_pdf(&vertex.hit_record.point) / lights.len() as Float
+                });
+            color += mis_weight(&path, 0, light_pdf) * vertex.throughput * emitted;
+        }
+
+        let scatter_pdf = match vertex.scatter_pdf {
+            Some(ref scatter_pdf) => scatter_pdf,
+            None => continue,
+        };
+        let (in_ray, hit_record) = (&vertex.in_ray, &vertex.hit_record);
+        for light in delta_lights {
+            color += vertex.throughput
+                * sample_delta_light(
+                    rng, world, materials, light, None, in_ray, hit_record, false,
+                );
+        }
+        color += vertex.throughput
+            * sample_background(
+                rng,
+                world,
+                materials,
+                background,
+                None,
+                in_ray,
+                hit_record,
+                scatter_pdf,
+                false,
+            );
+
+        if camera_vertices + 1 > max_vertices {
+            continue;
+        }
+        if let Some((light_vertex, light_pdf, radiance)) =
+            connect_to_light(rng, world, materials, &lights, vertex)
+        {
+            path.clear();
+            path.push(light_vertex);
+            path.extend(camera_mis());
+            color += mis_weight(&path, 1, light_pdf) * radiance;
+        }
+
+        let light_pdf = emission.map_or(0.0, |emission| emission.pdf);
+        for (j, light_vertex) in light_path.iter().enumerate() {
+            let light_vertices = j + 2;
+            if camera_vertices + light_vertices > max_vertices {
+                break;
+            }
+            if light_vertex.scatter_pdf.is_none() {
+                continue;
+            }
+            let radiance = connect(world, materials, light_vertex, vertex);
+            if radiance == Color::zero() {
+                continue;
+            }
+            path.clear();
+            path.extend_from_slice(&light_mis[..light_vertices]);
+            path.extend(camera_mis());
+            color += mis_weight(&path, light_vertices, light_pdf) * radiance;
+        }
+    }
+
+    if let Some(escaped) = escaped {
+        let direction = escaped.ray.direction();
+        let weight = match escaped.bsdf_pdf {
+            Some(pdf) => power_heuristic(pdf, background.pdf_value(&direction)),
+            None => 1.0,
+        };
+        color += weight * escaped.throughput * background.color(direction);
+    }
+
+    color
+}
+
+/// Follows `ray`, carrying `throughput`, through `world` for at most `max_vertices` hits,
+/// appending the surfaces it hits to `vertices`. Returns the ray leaving the scene, if the
+/// path isn't absorbed first. `primary` tells whether `ray` leaves the camera.
+#[allow(clippy::too_many_arguments)]
+fn random_walk<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    materials: &MaterialList,
+    mut ray: Ray,
+    mut throughput: Color,
+    primary: bool,
+    max_vertices: usize,
+    vertices: &mut Vec<Vertex>,
+) -> Option<Escaped> {
+    let mut media = MediumStack::new();
+    let mut bsdf_pdf = None;
+    while vertices.len() < max_vertices {
+        count_ray(primary && vertices.is_empty());
+        let mut hit_record = HitRecord::empty();
+        if !world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            return Some(Escaped {
+                ray,
+                throughput,
+                bsdf_pdf,
+            });
+        }
+
+        let material = &materials[hit_record.material];
+        hit_record.outside_refraction_index = media.outside_of(hit_record.material);
+        let mut scatter_record = ScatterRecord::empty();
+        let scattered = material.scatter(&ray, &hit_record, &mut scatter_record, rng);
+        let scatter_pdf = match scatter_record.scatter_type {
+            ScatterType::Pdf(ref scatter_pdf) if scattered => Some(scatter_pdf.clone()),
+            _ => None,
+        };
+        vertices.push(Vertex {
+            hit_record,
+            in_ray: ray,
+            throughput,
+            specular: scattered && scatter_pdf.is_none(),
+            scatter_pdf,
+        });
+        if !scattered {
+            return None;
+        }
+
+        let mut weight = match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => {
+                if let Some(refraction_index) = material.refraction_index() {
+                    if crossed_surface(&hit_record, &specular_ray) {
+                        media.cross(hit_record.material, refraction_index, hit_record.front_face);
+                    }
+                }
+                ray = specular_ray;
+                bsdf_pdf = None;
+                scatter_record.attenuation
+            }
+            ScatterType::Pdf(scatter_pdf) => {
+                let direction = scatter_pdf.generate(rng);
+                let scattered_ray = Ray::new(hit_record.ray_origin(&direction), direction)
+                    .with_channel(ray.channel());
+                let pdf = scatter_pdf.value(&scattered_ray.direction());
+                if pdf <= 0.0 {
+                    return None;
+                }
+                let weight = material.eval(&ray, &hit_record, &scattered_ray) / pdf;
+                ray = scattered_ray;
+                bsdf_pdf = Some(pdf);
+                weight
+            }
+        };
+
+        if vertices.len() >= ROULETTE_START {
+            let survival = weight.x().max(weight.y()).max(weight.z()).min(1.0);
+            if rng.gen::<Float>() >= survival {
+                return None;
+            }
+            weight /= survival;
+        }
+        throughput *= weight;
+    }
+    None
+}
+
+/// Whether `light` is the surface the ray reaching `vertex` hit.
+fn finds(light: &dyn Hittable, vertex: &Vertex) -> bool {
+    let mut light_record = HitRecord::empty();
+    let t = vertex.hit_record.t;
+    light.hit(&vertex.in_ray, 0.0, Float::MAX, &mut light_record)
+        && (light_record.t - t).abs() <= 1e-4 * t.max(1.0)
+}
+
+/// Radiance reaching the camera through `vertex` from a random point of one of the `lights`,
+/// along with that point and the density of sampling it. None if the point can't be seen.
+fn connect_to_light<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    materials: &MaterialList,
+    lights: &[&dyn Hittable],
+    vertex: &Vertex,
+) -> Option<(MisVertex, Float, Color)> {
+    if lights.is_empty() {
+        return None;
+    }
+    let light = lights[rng.gen_range(0..lights.len())];
+    let sample = light.sample_surface(rng)?;
+    let light_pdf = sample.pdf / lights.len() as Float;
+
+    // Whatever the shadow ray hits first is what's seen from the vertex, an occluder simply
+    // isn't at the sampled point
+    let point = vertex.hit_record.point;
+    let origin = vertex.hit_record.ray_origin(&(sample.point - point));
+    let shadow_ray = Ray::new(origin, sample.point - origin);
+    STATS.add_secondary_ray();
+    let mut light_record = HitRecord::empty();
+    if !world.hit(&shadow_ray, 0.0, Float::MAX, &mut light_record)
+        || (light_record.t - 1.0).abs() > 1e-3
+    {
+        return None;
+    }
+
+    let emitted = materials[light_record.material].emitted(&shadow_ray, &light_record);
+    let direction = unit_vector(shadow_ray.direction());
+    let bsdf = materials[vertex.hit_record.material].eval(
+        &vertex.in_ray,
+        &vertex.hit_record,
+        &Ray::new(point, direction),
+    );
+    let cosine = sample.normal.dot(&direction).abs();
+    let distance_squared = shadow_ray.direction().length_squared();
+    let radiance = vertex.throughput * bsdf * emitted * cosine / (distance_squared * light_pdf);
+
+    let light_vertex = MisVertex {
+        point: sample.point,
+        normal: sample.normal,
+        specular: false,
+    };
+    Some((light_vertex, light_pdf, radiance))
+}
+
+/// Radiance reaching the camera along the path made of the light path up to `light_vertex`
+/// and the camera path up to `camera_vertex`, zero if they can't see each other.
+fn connect<H: Hittable + ?Sized>(
+    world: &H,
+    materials: &MaterialList,
+    light_vertex: &Vertex,
+    camera_vertex: &Vertex,
+) -> Color {
+    let (from, to) = (
+        camera_vertex.hit_record.point,
+        light_vertex.hit_record.point,
+    );
+    let direction = unit_vector(to - from);
+    let bsdfs = materials[light_vertex.hit_record.material].eval(
+        &light_vertex.in_ray,
+        &light_vertex.hit_record,
+        &Ray::new(to, -direction),
+    ) * materials[camera_vertex.hit_record.material].eval(
+        &camera_vertex.in_ray,
+        &camera_vertex.hit_record,
+        &Ray::new(from, direction),
+    );
+    if bsdfs == Color::zero() {
+        return Color::zero();
+    }
+
+    // Between the origins of rays leaving each surface towards the other
+    let origin = camera_vertex.hit_record.ray_origin(&direction);
+    let end = light_vertex.hit_record.ray_origin(&-direction);
+    let shadow_ray = Ray::new(origin, end - origin);
+    STATS.add_secondary_ray();
+    let mut occluder_record = HitRecord::empty();
+    if world.hit(&shadow_ray, 0.0, 1.0, &mut occluder_record) {
+        return Color::zero();
+    }
+
+    light_vertex.throughput * bsdfs * camera_vertex.throughput / (to - from).length_squared()
+}
+
+/// Power heuristic weight of building `path`, ordered from the light to the camera, with its
+/// first `light_vertices` vertices taken from the light, `light_pdf` being the density of
+/// sampling its first vertex on the lights. Sampling that vertex is impossible with a zero
+/// density, which leaves the camera path alone.
+///
+/// The densities of the other strategies are approximated by cosine-weighted sampling at all
+/// the non specular vertices, the weights only need to add up to 1.
+fn mis_weight(path: &[MisVertex], light_vertices: usize, light_pdf: Float) -> Float {
+    // Densities of each vertex, with respect to area, when sampled from the light side and
+    // from the camera side. Specular vertices sample their neighbor with a density of 1, both
+    // ways, as their strategies are left out.
+    let n = path.len();
+    let area_pdf = |from: usize, to: usize| {
+        if path[from].specular {
+            return 1.0;
+        }
+        let offset = path[to].point - path[from].point;
+        let distance_squared = offset.length_squared();
+        let direction = offset / distance_squared.sqrt();
+        let pdf = path[from].normal.dot(&direction).abs() / PI
+            * path[to].normal.dot(&direction).abs()
+            / distance_squared;
+        if pdf > 0.0 {
+            pdf
+        } else {
+            1.0
+        }
+    };
+    let from_light = |i: usize| {
+        if i == 0 {
+            if light_pdf > 0.0 {
+                light_pdf
+            } else {
+                1.0
+            }
+        } else {
+            area_pdf(i - 1, i)
+        }
+    };
+    let from_camera = |i: usize| area_pdf(i + 1, i);
+    let possible =
+        |s: usize| s == 0 || (light_pdf > 0.0 && !path[s - 1].specular && !path[s].specular);
+
+    // Ratios of the densities of the other strategies to the one of this strategy, the camera
+    // path always keeping a vertex
+    let mut sum = 1.0;
+    let mut ratio = 1.0;
+    for s in (0..light_vertices).rev() {
+        ratio *= from_camera(s) / from_light(s);
+        if possible(s) {
+            sum += ratio * ratio;
+        }
+    }
+    ratio = 1.0;
+    for s in light_vertices + 1..n {
+        ratio *= from_light(s - 1) / from_camera(s - 1);
+        if possible(s) {
+            sum += ratio * ratio;
+        }
+    }
+    1.0 / sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::Integrator;
+    use crate::scenes::BuiltinScene;
+    use crate::settings::RenderSettings;
+
+    #[test]
+    fn test_weights_add_up_to_one() {
+        let vertex = |x: Float, y: Float, specular: bool| MisVertex {
+            point: Point3::new(x, y, 0.0),
+            normal: unit_vector(Vec3::new(0.3 - x, 1.0, 0.2)),
+            specular,
+        };
+        let path = [
+            vertex(0.0, 2.0, false),
+            vertex(1.0, 0.0, false),
+            vertex(2.0, 1.0, true),
+            vertex(3.0, 0.0, false),
+            vertex(4.0, 0.5, false),
+        ];
+        let light_pdf = 0.25;
+
+        // Strategies connecting a specular vertex don't exist
+        let total: Float = (0..path.len())
+            .filter(|&s| s == 0 || (!path[s - 1].specular && !path[s].specular))
+            .map(|s| mis_weight(&path, s, light_pdf))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert_eq!(mis_weight(&path, 0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let mut settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 16,
+            image_height: 16,
+            samples_per_pixel: 64,
+            ..RenderSettings::default()
+        };
+        let scene = settings.scene.build(&settings);
+        let mut mean = |integrator: Integrator| {
+            settings.integrator = integrator;
+            let mut sum = Color::zero();
+            for y in 0..settings.image_height {
+                for x in 0..settings.image_width {
+                    let index = y as usize * settings.image_width as usize + x as usize;
+                    for sample in 0..settings.samples_per_pixel as u32 {
+                        let mut rng = SampleRng::for_sample(settings.seed, index, sample);
+                        let (ray, _, _) = scene.camera_ray(&settings, x, y, sample, &mut rng);
+                        sum += scene.ray_color(&settings, &ray.unwrap(), &mut rng);
+                    }
+                }
+            }
+            let sample_count = settings.image_width as usize
+                * settings.image_height as usize
+                * settings.samples_per_pixel as usize;
+            (sum.x() + sum.y() + sum.z()) / (3 * sample_count) as Float
+        };
+
+        let expected = mean(Integrator::PathTracer);
+        let bidirectional = mean(Integrator::Bidirectional);
+        assert!(
+            (bidirectional - expected).abs() < 0.05 * expected,
+            "{} != {}",
+            bidirectional,
+            expected
+        );
+    }
+}
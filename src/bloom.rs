@@ -0,0 +1,181 @@
+use crate::float::Float;
+use crate::tonemap::luminance;
+use crate::vec3::Color;
+
+/// Levels of the blur pyramid, each half the size of the previous one. The glow of the last
+/// one spans about a sixth of a 1200 pixels wide image.
+const MAX_LEVELS: usize = 6;
+
+/// Glow around the parts of an HDR image brighter than a threshold, like the light scattered
+/// in the lenses of a camera or in the eye.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bloom {
+    /// Luminance above which pixels glow.
+    pub threshold: Float,
+    /// Fraction of the light above the threshold spread into the glow.
+    pub intensity: Float,
+}
+
+impl Bloom {
+    pub fn new(threshold: Float, intensity: Float) -> Bloom {
+        Bloom {
+            threshold,
+            intensity,
+        }
+    }
+
+    /// Adds the glow to the linear radiance `pixels`, given in row-major order.
+    pub fn apply(&self, width: usize, height: usize, pixels: &[Color]) -> Vec<Color> {
+        let bright = Image {
+            width,
+            height,
+            pixels: pixels.iter().map(|p| self.bright_pass(*p)).collect(),
+        };
+
+        // Blurring ever smaller copies gives wide glows at a small cost
+        let mut levels = vec![bright.blur()];
+        while levels.len() < MAX_LEVELS {
+            let last = &levels[levels.len() - 1];
+            if last.width < 4 || last.height < 4 {
+                break;
+            }
+            levels.push(last.downsample().blur());
+        }
+
+        let level_count = levels.len() as Float;
+        let mut glow = levels.pop().unwrap();
+        while let Some(level) = levels.pop() {
+            glow = glow.upsample(level.width, level.height);
+            for (g, l) in glow.pixels.iter_mut().zip(&level.pixels) {
+                *g += *l;
+            }
+        }
+
+        // Every level keeps the energy of the bright pass, their average does too
+        let scale = self.intensity / level_count;
+        pixels
+            .iter()
+            .zip(&glow.pixels)
+            .map(|(p, g)| *p + scale * *g)
+            .collect()
+    }
+
+    /// Part of the color above the threshold, keeping its hue.
+    fn bright_pass(&self, color: Color) -> Color {
+        let l = luminance(color);
+        if l <= self.threshold || !l.is_finite() {
+            return Color::zero();
+        }
+        (l - self.threshold) / l * color
+    }
+}
+
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![Color::zero(); width * height],
+        }
+    }
+
+    /// Pixel at the given coordinates, clamped to the edges.
+    fn get(&self, x: isize, y: isize) -> Color {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Separable Gaussian blur with binomial weights, over 5 pixels along each axis.
+    fn blur(&self) -> Image {
+        const WEIGHTS: [Float; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let pass = |image: &Image, dx: isize, dy: isize| {
+            let mut out = Image::new(image.width, image.height);
+            for y in 0..image.height as isize {
+                for x in 0..image.width as isize {
+                    let mut sum = Color::zero();
+                    for (i, w) in WEIGHTS.iter().enumerate() {
+                        let offset = i as isize - 2;
+                        sum += *w * image.get(x + offset * dx, y + offset * dy);
+                    }
+                    out.pixels[y as usize * image.width + x as usize] = sum;
+                }
+            }
+            out
+        };
+        pass(&pass(self, 1, 0), 0, 1)
+    }
+
+    /// Half-size image, averaging blocks of 2x2 pixels.
+    fn downsample(&self) -> Image {
+        let mut out = Image::new(self.width.div_ceil(2), self.height.div_ceil(2));
+        for y in 0..out.height {
+            for x in 0..out.width {
+                let (x2, y2) = (2 * x as isize, 2 * y as isize);
+                out.pixels[y * out.width + x] = 0.25
+                    * (self.get(x2, y2)
+                        + self.get(x2 + 1, y2)
+                        + self.get(x2, y2 + 1)
+                        + self.get(x2 + 1, y2 + 1));
+            }
+        }
+        out
+    }
+
+    /// Bilinear interpolation of the image at a larger size.
+    fn upsample(&self, width: usize, height: usize) -> Image {
+        let mut out = Image::new(width, height);
+        let scale_x = self.width as Float / width as Float;
+        let scale_y = self.height as Float / height as Float;
+        for y in 0..height {
+            let sy = (y as Float + 0.5) * scale_y - 0.5;
+            let y0 = sy.floor();
+            let ty = sy - y0;
+            for x in 0..width {
+                let sx = (x as Float + 0.5) * scale_x - 0.5;
+                let x0 = sx.floor();
+                let tx = sx - x0;
+                let (x0, y0) = (x0 as isize, y0 as isize);
+                let top = (1.0 - tx) * self.get(x0, y0) + tx * self.get(x0 + 1, y0);
+                let bottom = (1.0 - tx) * self.get(x0, y0 + 1) + tx * self.get(x0 + 1, y0 + 1);
+                out.pixels[y * width + x] = (1.0 - ty) * top + ty * bottom;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dim_image_is_unchanged() {
+        let pixels = vec![Color::new(0.5, 0.2, 0.9); 64];
+        assert_eq!(Bloom::new(1.0, 0.5).apply(8, 8, &pixels), pixels);
+    }
+
+    #[test]
+    fn test_glow_spreads_light() {
+        let size = 32;
+        let mut pixels = vec![Color::zero(); size * size];
+        let center = 16 * size + 16;
+        pixels[center] = Color::new(11.0, 11.0, 11.0);
+
+        let bloomed = Bloom::new(1.0, 0.5).apply(size, size, &pixels);
+        // The neighbours glow, less and less away from the light
+        assert!(bloomed[center + 1].x() > bloomed[center + 4].x());
+        assert!(bloomed[center + 4].x() > 0.0);
+        assert!(bloomed[center].x() > 11.0);
+
+        // Adding half of the light above the threshold
+        let added: Float = bloomed.iter().map(|p| p.x()).sum::<Float>() - 11.0;
+        assert!((added - 5.0).abs() < 0.05 * 5.0, "added {}", added);
+    }
+}
@@ -0,0 +1,489 @@
+use crate::aabb::Aabb;
+use crate::accelerator::Accelerator;
+use crate::float::Float;
+use crate::object::{HitRecord, Hittable, ObjectId};
+use crate::primitive::Primitive;
+use crate::ray::Ray;
+use crate::stats::STATS;
+use crate::vec3::{Point3, Vec3};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Objects are split until there are at most this many in a node...
+const MAX_OBJECTS_PER_LEAF: usize = 4;
+/// ...or the tree is this deep, which bounds the traversal stack.
+const MAX_DEPTH: usize = 64;
+/// Candidate split planes of the surface area heuristic, at the boundaries of this many bins.
+const SAH_BINS: usize = 12;
+/// Cost of visiting a node, relative to intersecting an object.
+const TRAVERSAL_COST: Float = 0.125;
+
+/// How the objects of a node are divided between its two children.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BvhSplit {
+    /// Halves the objects along the longest axis of their centers. Fast to build, but the
+    /// children overlap a lot when the objects have uneven sizes or densities.
+    Median,
+    /// Surface area heuristic: picks among binned candidate planes the one minimizing the
+    /// expected cost of tracing a ray through the children, and stops splitting when
+    /// intersecting all the objects is cheaper.
+    Sah,
+}
+
+impl fmt::Display for BvhSplit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BvhSplit::Median => "median",
+            BvhSplit::Sah => "sah",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for BvhSplit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BvhSplit> {
+        match s {
+            "median" => Ok(BvhSplit::Median),
+            "sah" => Ok(BvhSplit::Sah),
+            _ => bail!("Unknown BVH split '{}', expected one of: median, sah", s),
+        }
+    }
+}
+
+/// Node of the flattened tree, whose first child directly follows it.
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds: Aabb,
+    /// Index of the first object of leaves, of the second child of interior nodes.
+    offset: u32,
+    /// Number of objects of leaves, 0 for interior nodes.
+    count: u32,
+    /// Axis of the split of interior nodes, for a front-to-back traversal.
+    axis: u8,
+}
+
+/// Object being sorted into the tree.
+struct BuildObject {
+    bounds: Aabb,
+    centroid: Point3,
+    index: usize,
+}
+
+/// Binary tree of boxes over items known by their bounds only, leaving their storage and
+/// intersection to its owner.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BvhTree {
+    nodes: Vec<BvhNode>,
+}
+
+impl BvhTree {
+    /// Builds the tree over the items with the given `bounds`, also returning their indices in
+    /// the order of the leaves, in which the owner must store them.
+    pub(crate) fn build(bounds: &[Aabb], split: BvhSplit) -> (BvhTree, Vec<usize>) {
+        BvhTree::build_batched(bounds, split, 1)
+    }
+
+    /// Same as `build`, for items intersected `lanes` at a time: leaves hold up to that many,
+    /// at the cost of a single one.
+    pub(crate) fn build_batched(
+        bounds: &[Aabb],
+        split: BvhSplit,
+        lanes: usize,
+    ) -> (BvhTree, Vec<usize>) {
+        let mut build_objects: Vec<BuildObject> = bounds
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| BuildObject {
+                bounds: *bounds,
+                centroid: bounds.centroid(),
+                index,
+            })
+            .collect();
+        let mut nodes = Vec::new();
+        let mut order = Vec::with_capacity(bounds.len());
+        if !build_objects.is_empty() {
+            build(&mut build_objects, split, lanes, 0, &mut nodes, &mut order);
+        }
+        (BvhTree { nodes }, order)
+    }
+
+    pub(crate) fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Bytes taken by the nodes.
+    pub(crate) fn memory_size(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<BvhNode>()
+    }
+
+    /// Box enclosing all the items, empty if there are none.
+    pub(crate) fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |root| root.bounds)
+    }
+
+    /// Visits the leaves whose boxes the ray goes through before the closest hit so far, front
+    /// to back. `hit_leaf` gets the range of the items of the leaf and the closest distance so
+    /// far, and returns the distance of a closer hit among them, if any.
+    pub(crate) fn traverse<F>(&self, ray: &Ray, t_min: Float, t_max: Float, mut hit_leaf: F)
+    where
+        F: FnMut(Range<usize>, Float) -> Option<Float>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let direction = ray.direction();
+        let inverse_direction = Vec3::new(
+            1.0 / direction.x(),
+            1.0 / direction.y(),
+            1.0 / direction.z(),
+        );
+        let mut closest_so_far = t_max;
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_len = 0;
+        let mut node_index = 0;
+        let mut node_traversals = 0;
+        let mut primitive_tests = 0;
+        loop {
+            node_traversals += 1;
+            let node = &self.nodes[node_index];
+            if node
+                .bounds
+                .hit(ray, &inverse_direction, t_min, closest_so_far)
+            {
+                if node.count == 0 {
+                    // Visit the child on the side the ray comes from first, so that the hits
+                    // found in it cull the other one
+                    let (first, second) = if direction[node.axis as usize] < 0.0 {
+                        (node.offset as usize, node_index + 1)
+                    } else {
+                        (node_index + 1, node.offset as usize)
+                    };
+                    stack[stack_len] = second;
+                    stack_len += 1;
+                    node_index = first;
+                    continue;
+                }
+
+                let first = node.offset as usize;
+                primitive_tests += node.count as u64;
+                if let Some(t) = hit_leaf(first..first + node.count as usize, closest_so_far) {
+                    closest_so_far = t;
+                }
+            }
+
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            node_index = stack[stack_len];
+        }
+
+        STATS.add_node_traversals(node_traversals);
+        STATS.add_primitive_tests(primitive_tests);
+    }
+}
+
+/// Bounding volume hierarchy: binary tree of boxes, each enclosing the objects below it, so
+/// that rays only test the objects whose boxes they go through.
+pub struct Bvh {
+    tree: BvhTree,
+    /// Objects in the order of the leaves, with their position in the list given to `new`.
+    objects: Vec<(ObjectId, Primitive)>,
+    /// Objects without bounds, tested by every ray.
+    unbounded: Vec<(ObjectId, Box<dyn Hittable>)>,
+}
+
+impl Bvh {
+    pub fn new(objects: Vec<Box<dyn Hittable>>, split: BvhSplit) -> Bvh {
+        let mut unbounded = Vec::new();
+        let mut bounded = Vec::new();
+        let mut bounds = Vec::new();
+        for (i, object) in objects.into_iter().enumerate() {
+            let id = ObjectId::new(i);
+            match object.bounding_box() {
+                Some(object_bounds) if object_bounds.is_empty() => {
+                    // Nothing to hit
+                }
+                Some(object_bounds) => {
+                    bounds.push(object_bounds);
+                    bounded.push(Some((id, Primitive::new(object))));
+                }
+                None => unbounded.push((id, object)),
+            }
+        }
+
+        let (tree, order) = BvhTree::build(&bounds, split);
+        let objects = order
+            .into_iter()
+            .map(|index| bounded[index].take().unwrap())
+            .collect();
+
+        Bvh {
+            tree,
+            objects,
+            unbounded,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.tree.node_count()
+    }
+}
+
+/// Builds the subtree of `objects` at the end of `nodes`, returning the index of its root and
+/// appending its objects to `order` leaf by leaf.
+fn build(
+    objects: &mut [BuildObject],
+    split: BvhSplit,
+    lanes: usize,
+    depth: usize,
+    nodes: &mut Vec<BvhNode>,
+    order: &mut Vec<usize>,
+) -> usize {
+    let bounds = objects
+        .iter()
+        .fold(Aabb::empty(), |aabb, object| aabb.union(&object.bounds));
+    let node_index = nodes.len();
+    nodes.push(BvhNode {
+        bounds,
+        offset: order.len() as u32,
+        count: objects.len() as u32,
+        axis: 0,
+    });
+
+    let centroid_bounds = objects
+        .iter()
+        .fold(Aabb::empty(), |aabb, object| aabb.grow(&object.centroid));
+    let axis = centroid_bounds.longest_axis();
+    // Objects with the same center can't be told apart
+    let can_split =
+        objects.len() > 1 && depth + 1 < MAX_DEPTH && centroid_bounds.extent()[axis] > 0.0;
+    let mid = if !can_split {
+        None
+    } else {
+        match split {
+            BvhSplit::Median if objects.len() <= MAX_OBJECTS_PER_LEAF.max(lanes) => None,
+            BvhSplit::Median => Some(median_split(objects, axis)),
+            BvhSplit::Sah => sah_split(objects, lanes, &bounds, &centroid_bounds, axis),
+        }
+    };
+
+    match mid {
+        None => order.extend(objects.iter().map(|object| object.index)),
+        Some(mid) => {
+            let (left, right) = objects.split_at_mut(mid);
+            build(left, split, lanes, depth + 1, nodes, order);
+            let second_child = build(right, split, lanes, depth + 1, nodes, order);
+            nodes[node_index].offset = second_child as u32;
+            nodes[node_index].count = 0;
+            nodes[node_index].axis = axis as u8;
+        }
+    }
+    node_index
+}
+
+fn median_split(objects: &mut [BuildObject], axis: usize) -> usize {
+    let mid = objects.len() / 2;
+    objects.select_nth_unstable_by(mid, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+    mid
+}
+
+/// Partitions the objects along the best plane according to the surface area heuristic,
+/// returning the number of objects on its left, or None if a leaf is cheaper.
+fn sah_split(
+    objects: &mut [BuildObject],
+    lanes: usize,
+    bounds: &Aabb,
+    centroid_bounds: &Aabb,
+    axis: usize,
+) -> Option<usize> {
+    let min = centroid_bounds.min()[axis];
+    let extent = centroid_bounds.extent()[axis];
+    let bin_of = |object: &BuildObject| {
+        let bin = ((object.centroid[axis] - min) / extent * SAH_BINS as Float) as usize;
+        bin.min(SAH_BINS - 1)
+    };
+
+    let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+    let mut bin_counts = [0; SAH_BINS];
+    for object in objects.iter() {
+        let bin = bin_of(object);
+        bin_bounds[bin] = bin_bounds[bin].union(&object.bounds);
+        bin_counts[bin] += 1;
+    }
+
+    // Cost of splitting after each bin, from the areas and counts on both sides. Both sides
+    // are never empty, the first and last bins holding the extreme centroids.
+    let mut costs = [0.0; SAH_BINS - 1];
+    let (mut left_bounds, mut left_count) = (Aabb::empty(), 0);
+    for bin in 0..SAH_BINS - 1 {
+        left_bounds = left_bounds.union(&bin_bounds[bin]);
+        left_count += bin_counts[bin];
+        costs[bin] = left_bounds.surface_area() * left_count as Float;
+    }
+    let (mut right_bounds, mut right_count) = (Aabb::empty(), 0);
+    for bin in (1..SAH_BINS).rev() {
+        right_bounds = right_bounds.union(&bin_bounds[bin]);
+        right_count += bin_counts[bin];
+        costs[bin - 1] += right_bounds.surface_area() * right_count as Float;
+    }
+    let (best_bin, best_cost) = costs
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+
+    // Both costs are relative to the area of the node
+    let area = bounds.surface_area();
+    let split_cost = TRAVERSAL_COST * area + best_cost;
+    let leaf_cost = objects.len().div_ceil(lanes) as Float * area;
+    if objects.len() <= MAX_OBJECTS_PER_LEAF.max(lanes) && leaf_cost <= split_cost {
+        return None;
+    }
+
+    let mut mid = 0;
+    for i in 0..objects.len() {
+        if bin_of(&objects[i]) <= best_bin {
+            objects.swap(i, mid);
+            mid += 1;
+        }
+    }
+    Some(mid)
+}
+
+impl Hittable for Bvh {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut tmp_hit_record = HitRecord::empty();
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+
+        STATS.add_primitive_tests(self.unbounded.len() as u64);
+        for (id, object) in &self.unbounded {
+            if object.hit(ray, t_min, closest_so_far, &mut tmp_hit_record) {
+                hit_anything = true;
+                closest_so_far = tmp_hit_record.t;
+                *hit_record = tmp_hit_record;
+                hit_record.object = *id;
+            }
+        }
+
+        self.tree
+            .traverse(ray, t_min, closest_so_far, |leaf, mut closest_so_far| {
+                let mut closest_hit = None;
+                for (id, object) in &self.objects[leaf] {
+                    if object.hit(ray, t_min, closest_so_far, &mut tmp_hit_record) {
+                        hit_anything = true;
+                        closest_so_far = tmp_hit_record.t;
+                        closest_hit = Some(closest_so_far);
+                        *hit_record = tmp_hit_record;
+                        hit_record.object = *id;
+                    }
+                }
+                closest_hit
+            });
+        hit_anything
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        Some(self.tree.bounds())
+    }
+}
+
+impl Accelerator for Bvh {
+    fn len(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &dyn Hittable> + '_> {
+        Box::new(
+            self.objects
+                .iter()
+                .map(|(_, primitive)| primitive.as_hittable())
+                .chain(self.unbounded.iter().map(|(_, object)| object.as_ref())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialId;
+    use crate::object::HittableList;
+    use crate::sphere::Sphere;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_spheres(count: usize) -> Vec<Sphere> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..count)
+            .map(|_| {
+                let center = Vec3::random_range(&mut rng, -10.0, 10.0);
+                // A few large spheres among many small ones
+                let radius = if rng.gen::<Float>() < 0.05 { 3.0 } else { 0.2 };
+                Sphere::new(center, radius, MaterialId::default())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_same_hits_as_list() {
+        let spheres = random_spheres(200);
+        let mut list = HittableList::new();
+        for sphere in &spheres {
+            list.add(Box::new(sphere.clone()));
+        }
+        let boxed = || {
+            spheres
+                .iter()
+                .map(|sphere| Box::new(sphere.clone()) as Box<dyn Hittable>)
+                .collect()
+        };
+        let median = Bvh::new(boxed(), BvhSplit::Median);
+        let sah = Bvh::new(boxed(), BvhSplit::Sah);
+        assert_eq!(sah.len(), 200);
+
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..500 {
+            let ray = Ray::new(
+                Vec3::random_range(&mut rng, -15.0, 15.0),
+                Vec3::random_in_unit_sphere(&mut rng),
+            );
+            let mut expected = HitRecord::empty();
+            let hit = list.hit(&ray, 0.001, Float::MAX, &mut expected);
+            for bvh in &[&median, &sah] {
+                let mut hit_record = HitRecord::empty();
+                assert_eq!(bvh.hit(&ray, 0.001, Float::MAX, &mut hit_record), hit);
+                if hit {
+                    assert_eq!(hit_record.t, expected.t);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sah_separates_clusters() {
+        // Two far apart clusters end up in different children of the root
+        let material = MaterialId::default();
+        let objects: Vec<Box<dyn Hittable>> = (0..16)
+            .map(|i| {
+                let x = if i % 2 == 0 { -100.0 } else { 100.0 } + i as Float * 0.1;
+                Box::new(Sphere::new(Point3::new(x, 0.0, 0.0), 0.5, material)) as _
+            })
+            .collect();
+        let bvh = Bvh::new(objects, BvhSplit::Sah);
+        let nodes = &bvh.tree.nodes;
+        let root = &nodes[0];
+        assert_eq!(root.count, 0);
+        let left = &nodes[1].bounds;
+        let right = &nodes[root.offset as usize].bounds;
+        assert!(left.max().x() < 0.0 && right.min().x() > 0.0);
+    }
+}
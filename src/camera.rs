@@ -1,18 +1,88 @@
+use crate::float::{Float, PI};
 use crate::ray::Ray;
+use crate::rng::SampleRng;
 use crate::vec3::{unit_vector, Point3, Vec3};
-use crate::ASPECT_RATIO;
-use rand::rngs::ThreadRng;
-use std::f32::consts::PI;
+use rand::Rng;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    /// Full 360x180 degrees latitude-longitude panorama, meant for 2:1 images.
+    Equirectangular,
+    /// Circular fisheye inscribed in the image height, `fov_deg` may exceed 180 degrees. The
+    /// image is black outside of the circle.
+    Fisheye {
+        fov_deg: Float,
+        mapping: FisheyeMapping,
+    },
+}
+
+/// How the angle from the optical axis maps to the distance from the image center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FisheyeMapping {
+    /// r = f * theta
+    Equidistant,
+    /// r = 2 * f * sin(theta / 2)
+    Equisolid,
+}
+
+/// Shape of the lens opening, which gives its shape to out-of-focus highlights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApertureShape {
+    Circular,
+    /// Regular polygon with `blades` sides, inscribed in the unit circle.
+    Polygonal {
+        blades: u32,
+        rotation_deg: Float,
+    },
+}
+
+impl ApertureShape {
+    /// Uniform random point of the aperture, in the unit disk of the z=0 plane.
+    pub fn sample(&self, rng: &mut SampleRng) -> Vec3 {
+        match *self {
+            ApertureShape::Polygonal {
+                blades,
+                rotation_deg,
+            } if blades >= 3 => {
+                // Pick one of the identical triangles formed by the center and an edge,
+                // then a uniform point inside of it
+                let edge_angle = 2.0 * PI / blades as Float;
+                let edge = rng.gen_range(0..blades) as Float;
+                let a0 = degrees_to_radians(rotation_deg) + edge * edge_angle;
+                let a1 = a0 + edge_angle;
+
+                let r1 = rng.gen::<Float>().sqrt();
+                let r2 = rng.gen::<Float>();
+                let b0 = r1 * (1.0 - r2);
+                let b1 = r1 * r2;
+                Vec3::new(
+                    b0 * a0.cos() + b1 * a1.cos(),
+                    b0 * a0.sin() + b1 * a1.sin(),
+                    0.0,
+                )
+            }
+            _ => Vec3::random_in_unit_disk(rng),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Camera {
-    origin: Point3,
-    lower_left_corner: Point3,
-    horizontal: Vec3,
-    vertical: Vec3,
-    u: Vec3,
-    v: Vec3,
+    pub(crate) origin: Point3,
+    pub(crate) lower_left_corner: Point3,
+    pub(crate) horizontal: Vec3,
+    pub(crate) vertical: Vec3,
+    pub(crate) u: Vec3,
+    pub(crate) v: Vec3,
     w: Vec3,
-    lens_radius: f32,
+    pub(crate) lens_radius: Float,
+    pub(crate) aperture_shape: ApertureShape,
+    /// Point the camera was pointed at.
+    look_at: Point3,
+    focus_dist: Float,
+    aspect_ratio: Float,
+    projection: Projection,
 }
 
 impl Camera {
@@ -20,10 +90,10 @@ impl Camera {
         look_from: Point3,
         look_at: Point3,
         v_up: Vec3,
-        vertical_fov_deg: f32,
-        aspect_ratio: f32,
-        aperture: f32,
-        focus_dist: f32,
+        vertical_fov_deg: Float,
+        aspect_ratio: Float,
+        aperture: Float,
+        focus_dist: Float,
     ) -> Camera {
         let theta = degrees_to_radians(vertical_fov_deg);
         let h = (theta / 2.0).tan();
@@ -53,20 +123,417 @@ impl Camera {
             v,
             w,
             lens_radius,
+            aperture_shape: ApertureShape::Circular,
+            look_at,
+            focus_dist,
+            aspect_ratio,
+            projection: Projection::Perspective,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Ray {
-        let rd = self.lens_radius * Vec3::random_unit_vector(rng);
-        let offset = self.u * rd.x() + self.v * rd.y();
+    /// Panoramic camera seeing the whole scene around `look_from`, `look_at` being at the
+    /// center of the image.
+    pub fn equirectangular(look_from: Point3, look_at: Point3, v_up: Vec3) -> Camera {
+        let mut camera = Camera::new(look_from, look_at, v_up, 90.0, 2.0, 0.0, 1.0);
+        camera.projection = Projection::Equirectangular;
+        camera
+    }
+
+    /// Switches the projection while keeping the position, orientation and lens settings.
+    pub fn with_projection(mut self, projection: Projection) -> Camera {
+        self.projection = projection;
+        self
+    }
+
+    /// Uses a polygonal lens opening of `blades` sides (circular below 3).
+    pub fn with_aperture_blades(mut self, blades: u32, rotation_deg: Float) -> Camera {
+        self.aperture_shape = ApertureShape::Polygonal {
+            blades,
+            rotation_deg,
+        };
+        self
+    }
+
+    /// Camera moved around the point it's focused on, by `yaw` radians counterclockwise
+    /// around the vertical axis seen from above and `pitch` radians up, its distance to the
+    /// point multiplied by `zoom`. Keeps the field of view and lens settings, the up direction
+    /// becoming the y axis.
+    pub fn orbit(&self, yaw: Float, pitch: Float, zoom: Float) -> Camera {
+        let target = self.origin - self.focus_dist * self.w;
+        // Short of the poles, where the up direction is undefined
+        let max_elevation = 0.49 * PI;
+        let elevation =
+            (self.w.y().clamp(-1.0, 1.0).asin() + pitch).clamp(-max_elevation, max_elevation);
+        let azimuth = self.w.z().atan2(self.w.x()) - yaw;
+        let w = Vec3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.sin(),
+            elevation.cos() * azimuth.sin(),
+        );
+
+        let focus_dist = zoom * self.focus_dist;
+        let vertical_fov = 2.0 * (self.vertical.length() / (2.0 * self.focus_dist)).atan();
+        let camera = Camera::new(
+            target + focus_dist * w,
+            target,
+            Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov.to_degrees(),
+            self.aspect_ratio,
+            2.0 * self.lens_radius,
+            focus_dist,
+        );
+        Camera {
+            aperture_shape: self.aperture_shape,
+            projection: self.projection,
+            ..camera
+        }
+    }
+
+    /// Camera turned by `angle` radians counterclockwise seen from above, around the vertical
+    /// axis through the point it was pointed at, at the same height and distance from it: a
+    /// turntable going round the scene.
+    pub fn turned(&self, angle: Float) -> Camera {
+        let (sin, cos) = angle.sin_cos();
+        let rotate =
+            |v: Vec3| Vec3::new(cos * v.x() + sin * v.z(), v.y(), cos * v.z() - sin * v.x());
+        Camera {
+            origin: self.look_at + rotate(self.origin - self.look_at),
+            lower_left_corner: self.look_at + rotate(self.lower_left_corner - self.look_at),
+            horizontal: rotate(self.horizontal),
+            vertical: rotate(self.vertical),
+            u: rotate(self.u),
+            v: rotate(self.v),
+            w: rotate(self.w),
+            ..self.clone()
+        }
+    }
+
+    /// Sets the diameter of the lens, 0 for a pinhole keeping everything in focus.
+    pub fn with_aperture(mut self, aperture: Float) -> Camera {
+        self.lens_radius = aperture / 2.0;
+        self
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    /// Image coordinates (s, t) at which `point` is seen from the center of the lens, the
+    /// inverse of `get_ray`, or none if the projection doesn't reach it.
+    pub fn project(&self, point: &Point3) -> Option<(Float, Float)> {
+        let direction = *point - self.origin;
+        let (x, y, z) = (
+            direction.dot(&self.u),
+            direction.dot(&self.v),
+            -direction.dot(&self.w),
+        );
+        match self.projection {
+            Projection::Perspective => {
+                if z <= 0.0 {
+                    return None;
+                }
+                // Onto the plane of focus, where the viewport spans `horizontal` and `vertical`
+                let scale = self.focus_dist / z;
+                Some((
+                    scale * x / self.horizontal.length() + 0.5,
+                    scale * y / self.vertical.length() + 0.5,
+                ))
+            }
+            Projection::Equirectangular => {
+                let length = direction.length();
+                if length == 0.0 {
+                    return None;
+                }
+                let phi = x.atan2(z);
+                let theta = (y / length).clamp(-1.0, 1.0).asin();
+                Some((phi / (2.0 * PI) + 0.5, theta / PI + 0.5))
+            }
+            Projection::Fisheye { fov_deg, mapping } => {
+                let length = direction.length();
+                if length == 0.0 {
+                    return None;
+                }
+                let half_fov = degrees_to_radians(fov_deg) / 2.0;
+                let theta = (z / length).clamp(-1.0, 1.0).acos();
+                if theta > half_fov {
+                    return None;
+                }
+                let r = match mapping {
+                    FisheyeMapping::Equidistant => theta / half_fov,
+                    FisheyeMapping::Equisolid => (theta / 2.0).sin() / (half_fov / 2.0).sin(),
+                };
+                let phi = y.atan2(x);
+                Some((
+                    (r * phi.cos() / self.aspect_ratio + 1.0) / 2.0,
+                    (r * phi.sin() + 1.0) / 2.0,
+                ))
+            }
+        }
+    }
+
+    /// Ray through the point (`s`, `t`) of the image, None where the lens doesn't project
+    /// anything, outside of the image circle of a fisheye.
+    pub fn get_ray(&self, s: Float, t: Float, rng: &mut SampleRng) -> Option<Ray> {
+        match self.projection {
+            Projection::Perspective => Some(self.get_perspective_ray(s, t, rng)),
+            Projection::Equirectangular => Some(self.get_equirectangular_ray(s, t)),
+            Projection::Fisheye { fov_deg, mapping } => {
+                self.get_fisheye_ray(s, t, degrees_to_radians(fov_deg), mapping, rng)
+            }
+        }
+    }
+
+    /// Random point on the lens, relative to its center.
+    fn lens_offset(&self, rng: &mut SampleRng) -> Vec3 {
+        let rd = self.lens_radius * self.aperture_shape.sample(rng);
+        self.u * rd.x() + self.v * rd.y()
+    }
+
+    fn get_perspective_ray(&self, s: Float, t: Float, rng: &mut SampleRng) -> Ray {
+        let offset = self.lens_offset(rng);
 
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
         )
     }
+
+    fn get_equirectangular_ray(&self, s: Float, t: Float) -> Ray {
+        // Longitude in [-PI, PI], latitude in [-PI/2, PI/2]
+        let phi = (s - 0.5) * 2.0 * PI;
+        let theta = (t - 0.5) * PI;
+
+        let direction =
+            theta.cos() * (phi.sin() * self.u - phi.cos() * self.w) + theta.sin() * self.v;
+        Ray::new(self.origin, direction)
+    }
+
+    fn get_fisheye_ray(
+        &self,
+        s: Float,
+        t: Float,
+        fov: Float,
+        mapping: FisheyeMapping,
+        rng: &mut SampleRng,
+    ) -> Option<Ray> {
+        // Image plane coordinates, r = 1 on the top and bottom edges
+        let x = (2.0 * s - 1.0) * self.aspect_ratio;
+        let y = 2.0 * t - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+
+        let half_fov = fov / 2.0;
+        let theta = match mapping {
+            FisheyeMapping::Equidistant => r * half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (r * (half_fov / 2.0).sin()).asin(),
+        }
+        .min(PI);
+        let phi = y.atan2(x);
+
+        let direction =
+            theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+
+        // Defocus blur: every lens sample converges on the plane of focus
+        let focus_point = self.origin + self.focus_dist * direction;
+        let offset = self.lens_offset(rng);
+
+        Some(Ray::new(
+            self.origin + offset,
+            focus_point - self.origin - offset,
+        ))
+    }
 }
 
-fn degrees_to_radians(degrees: f32) -> f32 {
+fn degrees_to_radians(degrees: Float) -> Float {
     degrees * PI / 180.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_turned() {
+        let look_at = Point3::new(1.0, 0.5, -2.0);
+        let camera = Camera::new(
+            Point3::new(4.0, 3.0, 2.0),
+            look_at,
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            1.5,
+            0.0,
+            1.0,
+        );
+        let distance = (Point3::new(4.0, 3.0, 2.0) - look_at).length();
+        for step in 0..8 {
+            let turned = camera.turned(step as Float * PI / 4.0);
+            // Still looking at the same point, from the same height and distance
+            let (s, t) = turned.project(&look_at).unwrap();
+            assert!((s - 0.5).abs() < 1e-5 && (t - 0.5).abs() < 1e-5);
+            assert!((turned.origin.y() - 3.0).abs() < 1e-5);
+            assert!(((turned.origin - look_at).length() - distance).abs() < 1e-4);
+        }
+        // A quarter turn counterclockwise seen from above, from +z to +x
+        let turned = camera.turned(PI / 2.0);
+        assert_near(turned.origin - look_at, Vec3::new(4.0, 2.5, -3.0));
+        assert_near(camera.turned(2.0 * PI).origin, camera.origin);
+    }
+
+    #[test]
+    fn test_equirectangular_directions() {
+        let mut rng = SampleRng::new(0);
+        let camera = Camera::equirectangular(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+
+        let forward = camera.get_ray(0.5, 0.5, &mut rng).unwrap();
+        assert_eq!(forward.origin(), Point3::zero());
+        assert_near(forward.direction(), Vec3::new(0.0, 0.0, -1.0));
+        assert_near(
+            camera.get_ray(0.75, 0.5, &mut rng).unwrap().direction(),
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+        assert_near(
+            camera.get_ray(0.0, 0.5, &mut rng).unwrap().direction(),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        assert_near(
+            camera.get_ray(0.5, 1.0, &mut rng).unwrap().direction(),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_orbit() {
+        let rng = SampleRng::new(0);
+        let camera = Camera::new(
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::zero(),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.5,
+            0.0,
+            10.0,
+        );
+        let center = |camera: &Camera| camera.get_ray(0.5, 0.5, &mut rng.clone()).unwrap();
+
+        // A quarter turn brings the camera to the side, still looking at the target
+        let ray = center(&camera.orbit(PI / 2.0, 0.0, 0.5));
+        assert!((ray.origin() - Point3::new(5.0, 0.0, 0.0)).length() < 1e-4);
+        assert!(unit_vector(ray.direction()).dot(&Vec3::new(-1.0, 0.0, 0.0)) > 0.9999);
+
+        // Looking down from above, stopping short of the pole
+        let ray = center(&camera.orbit(0.0, PI, 1.0));
+        assert!(ray.origin().y() > 9.9 && ray.origin().z() > 0.0);
+
+        // Same field of view
+        let orbited = camera.orbit(1.0, 0.3, 2.0);
+        assert!((orbited.vertical.length() / 20.0 - camera.vertical.length() / 10.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_project_inverts_get_ray() {
+        let mut rng = SampleRng::new(0);
+        let camera = Camera::new(
+            Point3::new(1.0, 2.0, 10.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.5,
+            0.0,
+            8.0,
+        );
+        let fisheye = camera.clone().with_projection(Projection::Fisheye {
+            fov_deg: 200.0,
+            mapping: FisheyeMapping::Equisolid,
+        });
+        let panorama = camera.clone().with_projection(Projection::Equirectangular);
+
+        for camera in [camera, fisheye, panorama] {
+            for &(s, t) in &[(0.5, 0.5), (0.3, 0.8), (0.7, 0.3)] {
+                let ray = camera.get_ray(s, t, &mut rng).unwrap();
+                let (ps, pt) = camera.project(&ray.at(3.0)).unwrap();
+                assert!((ps - s).abs() < 1e-4 && (pt - t).abs() < 1e-4);
+            }
+        }
+
+        // Behind a perspective camera
+        let camera = Camera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+        );
+        assert_eq!(camera.project(&Point3::new(0.0, 0.0, 1.0)), None);
+    }
+
+    #[test]
+    fn test_polygonal_aperture_samples() {
+        let mut rng = SampleRng::new(0);
+        let blades = 6;
+        let shape = ApertureShape::Polygonal {
+            blades,
+            rotation_deg: 10.0,
+        };
+        let edge_angle = 2.0 * PI / blades as Float;
+        // Distance from the center to each edge
+        let apothem = (edge_angle / 2.0).cos();
+
+        for _ in 0..1000 {
+            let p = shape.sample(&mut rng);
+            assert_eq!(p.z(), 0.0);
+            for edge in 0..blades {
+                let normal_angle = degrees_to_radians(10.0) + (edge as Float + 0.5) * edge_angle;
+                let normal = Vec3::new(normal_angle.cos(), normal_angle.sin(), 0.0);
+                assert!(p.dot(&normal) <= apothem + 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fisheye_directions() {
+        let mut rng = SampleRng::new(0);
+        for &mapping in &[FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let camera = Camera::new(
+                Point3::zero(),
+                Point3::new(0.0, 0.0, -1.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                90.0,
+                1.0,
+                0.0,
+                1.0,
+            )
+            .with_projection(Projection::Fisheye {
+                fov_deg: 180.0,
+                mapping,
+            });
+
+            assert_near(
+                unit_vector(camera.get_ray(0.5, 0.5, &mut rng).unwrap().direction()),
+                Vec3::new(0.0, 0.0, -1.0),
+            );
+            assert_near(
+                unit_vector(camera.get_ray(0.5, 1.0, &mut rng).unwrap().direction()),
+                Vec3::new(0.0, 1.0, 0.0),
+            );
+            assert_near(
+                unit_vector(camera.get_ray(0.0, 0.5, &mut rng).unwrap().direction()),
+                Vec3::new(-1.0, 0.0, 0.0),
+            );
+            // Black outside of the image circle
+            assert_eq!(camera.get_ray(0.0, 0.0, &mut rng), None);
+            assert_eq!(camera.get_ray(0.9, 0.9, &mut rng), None);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! C bindings of the renderer, declared in `include/rust_ray_tracing.h`, built as a shared
+//! library with `cargo rustc --release --lib --features capi --crate-type cdylib`.
+//!
+//! Functions returning an `int` return 0 on success and -1 on failure, those returning a
+//! pointer return null on failure, `rt_last_error` then describing the error.
+
+use crate::camera::Camera;
+use crate::float::Float;
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+use crate::scene_file::SceneFile;
+use crate::scenes::BuiltinScene;
+use crate::settings::RenderSettings;
+use crate::vec3::{Point3, Vec3};
+use anyhow::{bail, Context, Result};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_float, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    /// Message of the last error on the current thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Scene to render, with the settings of its image, opaque to C.
+pub struct RtScene {
+    source: SceneSource,
+    camera: Option<CameraSetup>,
+    settings: RenderSettings,
+}
+
+#[derive(Clone)]
+enum SceneSource {
+    Builtin(BuiltinScene),
+    File(Box<SceneFile>),
+}
+
+/// Camera replacing the one of the scene, made for the aspect ratio of the image once known.
+#[derive(Clone, Copy)]
+struct CameraSetup {
+    look_from: Point3,
+    look_at: Point3,
+    vertical_fov_deg: Float,
+    aperture: Float,
+    focus_dist: Float,
+}
+
+impl RtScene {
+    fn new(source: SceneSource) -> RtScene {
+        let scene = match source {
+            SceneSource::Builtin(scene) => scene,
+            SceneSource::File(_) => RenderSettings::default().scene,
+        };
+        RtScene {
+            source,
+            camera: None,
+            settings: RenderSettings {
+                scene,
+                ..RenderSettings::default()
+            },
+        }
+    }
+
+    fn build(&self) -> Scene {
+        let mut scene = match self.source {
+            SceneSource::Builtin(ref builtin) => builtin.build(&self.settings),
+            SceneSource::File(ref file) => file.build(&self.settings),
+        };
+        if let Some(camera) = self.camera {
+            scene.camera = Camera::new(
+                camera.look_from,
+                camera.look_at,
+                Vec3::new(0.0, 1.0, 0.0),
+                camera.vertical_fov_deg,
+                self.settings.aspect_ratio(),
+                camera.aperture,
+                camera.focus_dist,
+            );
+        }
+        scene
+    }
+
+    /// Renders the linear RGB radiance of the pixels into `pixels`, row-major from the top row.
+    #[allow(clippy::unnecessary_cast)]
+    fn render(&self, threads: u32, pixels: &mut [c_float]) -> Result<()> {
+        let settings = self.settings;
+        let len = 3 * settings.image_width as usize * settings.image_height as usize;
+        if pixels.len() < len {
+            bail!(
+                "Buffer of {} floats too small for {} pixels",
+                pixels.len(),
+                len / 3
+            );
+        }
+        let mut renderer = Renderer::builder(settings).with_scene(|| self.build());
+        if threads > 0 {
+            renderer = renderer.with_threads(threads as usize);
+        }
+        let framebuffer = renderer.build().render();
+        for (pixel, rgb) in framebuffer.pixels().iter().zip(pixels.chunks_exact_mut(3)) {
+            // Stored as f32, whatever the precision of `Float`
+            rgb[0] = pixel.x() as c_float;
+            rgb[1] = pixel.y() as c_float;
+            rgb[2] = pixel.z() as c_float;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the error of the current thread for `rt_last_error`.
+fn set_last_error(error: anyhow::Error) {
+    // Messages can't hold nul bytes
+    let message = format!("{:#}", error).replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+/// Runs `f`, turning its errors and panics into the error of the current thread. Panics must
+/// not unwind into C.
+fn catch<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("The renderer panicked")));
+    result.map_err(set_last_error).ok()
+}
+
+fn status(result: Option<()>) -> c_int {
+    match result {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// # Safety
+///
+/// `string` must be null or a nul-terminated string.
+unsafe fn str_arg<'a>(string: *const c_char, name: &str) -> Result<&'a str> {
+    if string.is_null() {
+        bail!("Null {}", name);
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .with_context(|| format!("Invalid UTF-8 in the {}", name))
+}
+
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet.
+unsafe fn scene_arg<'a>(scene: *mut RtScene) -> Result<&'a mut RtScene> {
+    match scene.as_mut() {
+        Some(scene) => Ok(scene),
+        None => bail!("Null scene"),
+    }
+}
+
+/// # Safety
+///
+/// `point` must be null or point to 3 floats.
+#[allow(clippy::unnecessary_cast)]
+unsafe fn point_arg(point: *const c_float, name: &str) -> Result<Point3> {
+    if point.is_null() {
+        bail!("Null {}", name);
+    }
+    let point = std::slice::from_raw_parts(point, 3);
+    Ok(Point3::new(
+        point[0] as Float,
+        point[1] as Float,
+        point[2] as Float,
+    ))
+}
+
+/// Built-in scene of the given name, e.g. "cornell-box", with its camera, to be freed with
+/// `rt_scene_free`.
+///
+/// # Safety
+///
+/// `name` must be null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_builtin(name: *const c_char) -> *mut RtScene {
+    catch(|| {
+        let scene = str_arg(name, "scene name")?.parse()?;
+        Ok(Box::into_raw(Box::new(RtScene::new(SceneSource::Builtin(
+            scene,
+        )))))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Scene of a scene file, to be freed with `rt_scene_free`.
+///
+/// # Safety
+///
+/// `path` must be null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_load(path: *const c_char) -> *mut RtScene {
+    catch(|| {
+        let file = SceneFile::load(str_arg(path, "path")?)?;
+        Ok(Box::into_raw(Box::new(RtScene::new(SceneSource::File(
+            Box::new(file),
+        )))))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_free(scene: *mut RtScene) {
+    if !scene.is_null() {
+        drop(Box::from_raw(scene));
+    }
+}
+
+/// Replaces the camera of the scene by a camera at `look_from` looking at `look_at`, both of
+/// 3 floats, the lens of diameter `aperture` focusing at `focus_dist`.
+///
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet, `look_from` and
+/// `look_at` null or pointing to 3 floats.
+#[no_mangle]
+#[allow(clippy::unnecessary_cast)]
+pub unsafe extern "C" fn rt_scene_set_camera(
+    scene: *mut RtScene,
+    look_from: *const c_float,
+    look_at: *const c_float,
+    vertical_fov_deg: c_float,
+    aperture: c_float,
+    focus_dist: c_float,
+) -> c_int {
+    status(catch(|| {
+        let scene = scene_arg(scene)?;
+        if !(vertical_fov_deg > 0.0 && vertical_fov_deg < 180.0) {
+            bail!(
+                "Vertical field of view out of (0, 180): {}",
+                vertical_fov_deg
+            );
+        }
+        if aperture < 0.0 || focus_dist <= 0.0 {
+            bail!("Negative aperture or focus distance");
+        }
+        scene.camera = Some(CameraSetup {
+            look_from: point_arg(look_from, "camera position")?,
+            look_at: point_arg(look_at, "camera target")?,
+            vertical_fov_deg: vertical_fov_deg as Float,
+            aperture: aperture as Float,
+            focus_dist: focus_dist as Float,
+        });
+        Ok(())
+    }))
+}
+
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_image_size(
+    scene: *mut RtScene,
+    width: u16,
+    height: u16,
+) -> c_int {
+    status(catch(|| {
+        let scene = scene_arg(scene)?;
+        if width < 2 || height < 2 {
+            bail!(
+                "Image of {}x{} pixels, at least 2x2 expected",
+                width,
+                height
+            );
+        }
+        scene.settings.image_width = width;
+        scene.settings.image_height = height;
+        Ok(())
+    }))
+}
+
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_samples(
+    scene: *mut RtScene,
+    samples_per_pixel: u16,
+) -> c_int {
+    status(catch(|| {
+        let scene = scene_arg(scene)?;
+        if samples_per_pixel == 0 {
+            bail!("No samples per pixel");
+        }
+        scene.settings.samples_per_pixel = samples_per_pixel;
+        Ok(())
+    }))
+}
+
+/// Seed of the random numbers of the samples, the same seed giving the same image.
+///
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_seed(scene: *mut RtScene, seed: u64) -> c_int {
+    status(catch(|| {
+        scene_arg(scene)?.settings.seed = seed;
+        Ok(())
+    }))
+}
+
+/// Renders the scene on `threads` threads, 0 for one per core, into `pixels`: `len` floats,
+/// at least 3 per pixel, filled with the linear RGB radiance of the pixels in row-major order
+/// from the top row.
+///
+/// # Safety
+///
+/// `scene` must be null or a scene made by this library and not freed yet, `pixels` null or
+/// pointing to `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(
+    scene: *mut RtScene,
+    threads: u32,
+    pixels: *mut c_float,
+    len: usize,
+) -> c_int {
+    status(catch(|| {
+        let scene = scene_arg(scene)?;
+        if pixels.is_null() {
+            bail!("Null pixel buffer");
+        }
+        scene.render(threads, std::slice::from_raw_parts_mut(pixels, len))
+    }))
+}
+
+/// Message of the last error on the calling thread, null if none. It stays valid until the
+/// next call failing on the thread.
+#[no_mangle]
+pub extern "C" fn rt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(rt_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_render() {
+        let name = CString::new("cornell-box").unwrap();
+        unsafe {
+            let scene = rt_scene_builtin(name.as_ptr());
+            assert!(!scene.is_null());
+            assert_eq!(rt_scene_set_image_size(scene, 16, 12), 0);
+            assert_eq!(rt_scene_set_samples(scene, 2), 0);
+            let (look_from, look_at) = ([278.0, 278.0, -800.0], [278.0, 278.0, 0.0]);
+            let status =
+                rt_scene_set_camera(scene, look_from.as_ptr(), look_at.as_ptr(), 40.0, 0.0, 10.0);
+            assert_eq!(status, 0);
+
+            let mut pixels = vec![-1.0; 16 * 12 * 3];
+            assert_eq!(
+                rt_render(scene, 2, pixels.as_mut_ptr(), pixels.len() - 1),
+                -1
+            );
+            assert!(last_error().contains("too small"));
+            assert_eq!(rt_render(scene, 2, pixels.as_mut_ptr(), pixels.len()), 0);
+            assert!(pixels.iter().all(|&value| value >= 0.0));
+            assert!(pixels.iter().any(|&value| value > 0.0));
+            rt_scene_free(scene);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let name = CString::new("teapot").unwrap();
+        unsafe {
+            assert!(rt_scene_builtin(name.as_ptr()).is_null());
+            assert!(last_error().starts_with("Unknown scene 'teapot'"));
+            assert!(rt_scene_load(ptr::null()).is_null());
+            assert_eq!(last_error(), "Null path");
+            assert_eq!(rt_scene_set_seed(ptr::null_mut(), 1), -1);
+            assert_eq!(last_error(), "Null scene");
+        }
+    }
+}
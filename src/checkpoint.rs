@@ -0,0 +1,197 @@
+use crate::float::Float;
+use crate::framebuffer::Framebuffer;
+use crate::settings::RenderSettings;
+use crate::vec3::Color;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RTCKPT3\n";
+/// Bytes of the magic bytes, width, height and fingerprint.
+const HEADER_SIZE: u64 = 24;
+/// Bytes of each pixel.
+const PIXEL_SIZE: u64 = 20;
+
+/// Fingerprint of what the samples of a render depend on: the scene, described by the contents
+/// of `scene_file` if it isn't a built-in one, and the settings. Saved in the checkpoints, so
+/// that another render doesn't resume from them.
+pub fn fingerprint(settings: &RenderSettings, scene_file: Option<&[u8]>) -> u64 {
+    let settings = format!(
+        "{} {} {} {} {} {} {} {} {:?} {} {} {}",
+        settings.scene,
+        settings.samples_per_pixel,
+        settings.bounce_limit,
+        settings.integrator,
+        settings.sampler,
+        settings.filter,
+        settings.seed,
+        settings.first_sample,
+        settings.fog,
+        settings.caustic_photons,
+        settings.irradiance_cache,
+        settings.transparent_shadows
+    );
+    // FNV-1a, the same on every platform, unlike the hashers of the standard library
+    let bytes = settings
+        .bytes()
+        .chain(scene_file.unwrap_or(&[]).iter().copied());
+    bytes.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Saves the framebuffer of an in-progress render, to resume it later.
+///
+/// Stored as the magic bytes, the width and height as u32, the `fingerprint` of the render as
+/// u64, then for each pixel its weighted sum as three f32, its weight as f32 and its sample
+/// count as u32, all little-endian.
+pub fn save<P: AsRef<Path>>(framebuffer: &Framebuffer, fingerprint: u64, path: P) -> Result<()> {
+    let path = path.as_ref();
+    // Write next to the target then rename, so a crash mid-write keeps the previous checkpoint
+    let tmp_path = path.with_extension("tmp");
+    {
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create checkpoint file {}", tmp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(framebuffer.width() as u32).to_le_bytes())?;
+        writer.write_all(&(framebuffer.height() as u32).to_le_bytes())?;
+        writer.write_all(&fingerprint.to_le_bytes())?;
+        for index in 0..framebuffer.len() {
+            let sum = framebuffer.sum(index);
+            write_f32(&mut writer, sum.x())?;
+            write_f32(&mut writer, sum.y())?;
+            write_f32(&mut writer, sum.z())?;
+            write_f32(&mut writer, framebuffer.weight(index))?;
+            writer.write_all(&framebuffer.sample_count(index).to_le_bytes())?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write checkpoint file {}", path.display()))
+}
+
+/// Loads the framebuffer saved by `save`, failing if it was saved by a render of another
+/// `fingerprint`.
+pub fn load<P: AsRef<Path>>(path: P, fingerprint: u64) -> Result<Framebuffer> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open checkpoint file {}", path.display()))?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("{} is not a checkpoint file", path.display());
+    }
+
+    let width = read_u32(&mut reader)? as usize;
+    let height = read_u32(&mut reader)? as usize;
+    let saved_fingerprint = read_u64(&mut reader)?;
+    // Checked before allocating the framebuffer, whose size comes from the file
+    let expected_size = (width as u64)
+        .checked_mul(height as u64)
+        .and_then(|pixels| pixels.checked_mul(PIXEL_SIZE))
+        .and_then(|size| size.checked_add(HEADER_SIZE));
+    if expected_size != Some(file_size) {
+        bail!(
+            "{} is not a checkpoint of a {}x{} image, corrupt or truncated",
+            path.display(),
+            width,
+            height
+        );
+    }
+    if saved_fingerprint != fingerprint {
+        bail!(
+            "{} is a checkpoint of another render, of another scene or with other settings",
+            path.display()
+        );
+    }
+    let mut framebuffer = Framebuffer::new(width, height);
+    for index in 0..width * height {
+        let r = read_f32(&mut reader)?;
+        let g = read_f32(&mut reader)?;
+        let b = read_f32(&mut reader)?;
+        let weight = read_f32(&mut reader)?;
+        let sample_count = read_u32(&mut reader)?;
+        framebuffer.merge(index, Color::new(r, g, b), weight, sample_count);
+    }
+
+    Ok(framebuffer)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("Truncated checkpoint file")?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader
+        .read_exact(&mut bytes)
+        .context("Truncated checkpoint file")?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Sums are always stored as f32, whatever the precision of `Float`
+#[allow(clippy::unnecessary_cast)]
+fn write_f32<W: Write>(writer: &mut W, v: Float) -> Result<()> {
+    Ok(writer.write_all(&(v as f32).to_le_bytes())?)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn read_f32<R: Read>(reader: &mut R) -> Result<Float> {
+    Ok(f32::from_bits(read_u32(reader)?) as Float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let mut framebuffer = Framebuffer::new(2, 1);
+        framebuffer.merge(0, Color::new(1.0, 2.0, 3.0), 5.0, 10);
+        let path = std::env::temp_dir().join("rust-ray-tracing-test.ckpt");
+        save(&framebuffer, 42, &path).unwrap();
+        let loaded = load(&path, 42).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, framebuffer);
+    }
+
+    #[test]
+    fn test_load_other_render() {
+        let settings = RenderSettings::default();
+        let other_seed = RenderSettings {
+            seed: settings.seed + 1,
+            ..settings
+        };
+        let saved = fingerprint(&settings, None);
+        assert_ne!(fingerprint(&other_seed, None), saved);
+        assert_ne!(fingerprint(&settings, Some(b"sphere")), saved);
+
+        let path = std::env::temp_dir().join("rust-ray-tracing-test-other.ckpt");
+        save(&Framebuffer::new(2, 1), saved, &path).unwrap();
+        let error = load(&path, fingerprint(&other_seed, None)).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("another render"));
+    }
+
+    #[test]
+    fn test_load_corrupt() {
+        let path = std::env::temp_dir().join("rust-ray-tracing-test-corrupt.ckpt");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let error = load(&path, 0).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("4294967295x4294967295"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+
+/// Distance to step past a surface before looking for the next one along the ray.
+const CROSSING_EPSILON: Float = 1e-4;
+/// Bound on the number of surfaces crossed while looking for the boundary of the solid.
+const MAX_CROSSINGS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    /// Points inside either child.
+    Union,
+    /// Points inside both children.
+    Intersection,
+    /// Points inside the left child but not the right one.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, inside_left: bool, inside_right: bool) -> bool {
+        match *self {
+            CsgOperation::Union => inside_left || inside_right,
+            CsgOperation::Intersection => inside_left && inside_right,
+            CsgOperation::Difference => inside_left && !inside_right,
+        }
+    }
+}
+
+/// Boolean combination of two closed hittables.
+///
+/// The ray is walked through the successive surfaces of both children, keeping track of
+/// whether it is inside each of them, and the first crossing that changes whether it is inside
+/// the combined solid is reported.
+pub struct Csg {
+    left: Box<dyn Hittable>,
+    right: Box<dyn Hittable>,
+    operation: CsgOperation,
+}
+
+impl Csg {
+    pub fn new(left: Box<dyn Hittable>, right: Box<dyn Hittable>, operation: CsgOperation) -> Csg {
+        Csg {
+            left,
+            right,
+            operation,
+        }
+    }
+
+    pub fn union(left: Box<dyn Hittable>, right: Box<dyn Hittable>) -> Csg {
+        Csg::new(left, right, CsgOperation::Union)
+    }
+
+    pub fn intersection(left: Box<dyn Hittable>, right: Box<dyn Hittable>) -> Csg {
+        Csg::new(left, right, CsgOperation::Intersection)
+    }
+
+    pub fn difference(left: Box<dyn Hittable>, right: Box<dyn Hittable>) -> Csg {
+        Csg::new(left, right, CsgOperation::Difference)
+    }
+}
+
+fn next_hit(object: &dyn Hittable, ray: &Ray, t_min: Float) -> Option<HitRecord> {
+    let mut hit_record = HitRecord::empty();
+    if object.hit(ray, t_min, Float::MAX, &mut hit_record) {
+        Some(hit_record)
+    } else {
+        None
+    }
+}
+
+impl Hittable for Csg {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut next_left = next_hit(&*self.left, ray, t_min);
+        let mut next_right = next_hit(&*self.right, ray, t_min);
+
+        // The next surface of a closed object is a back face iff the ray starts inside of it
+        let mut inside_left = next_left.is_some_and(|hit| !hit.front_face);
+        let mut inside_right = next_right.is_some_and(|hit| !hit.front_face);
+        let inside = self.operation.contains(inside_left, inside_right);
+
+        for _ in 0..MAX_CROSSINGS {
+            let from_left = match (&next_left, &next_right) {
+                (None, None) => return false,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(left), Some(right)) => left.t <= right.t,
+            };
+
+            let mut crossing = if from_left {
+                next_left.take().unwrap()
+            } else {
+                next_right.take().unwrap()
+            };
+            if crossing.t > t_max {
+                return false;
+            }
+
+            if from_left {
+                inside_left = crossing.front_face;
+                next_left = next_hit(&*self.left, ray, crossing.t + CROSSING_EPSILON);
+            } else {
+                inside_right = crossing.front_face;
+                next_right = next_hit(&*self.right, ray, crossing.t + CROSSING_EPSILON);
+            }
+
+            if self.operation.contains(inside_left, inside_right) != inside {
+                if !from_left && self.operation == CsgOperation::Difference {
+                    // The surface of the subtracted object faces inwards
+                    let outward_normal = if crossing.front_face {
+                        crossing.normal
+                    } else {
+                        -crossing.normal
+                    };
+                    crossing.set_face_normal(ray, &-outward_normal);
+                }
+                *hit_record = crossing;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            CsgOperation::Union => Some(left?.union(&right?)),
+            // Within both boxes, only the bounded one matters
+            CsgOperation::Intersection => match (left, right) {
+                (Some(left), Some(right)) => Some(left.intersection(&right)),
+                (left, right) => left.or(right),
+            },
+            CsgOperation::Difference => left,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialId;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+
+    fn sphere(x: Float) -> Box<dyn Hittable> {
+        let material = MaterialId::default();
+        Box::new(Sphere::new(Point3::new(x, 0.0, 0.0), 1.0, material))
+    }
+
+    /// Hits along the X axis, from the left.
+    fn hit_x(csg: &Csg) -> Option<HitRecord> {
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let mut hit_record = HitRecord::empty();
+        if csg.hit(&ray, 0.001, Float::MAX, &mut hit_record) {
+            Some(hit_record)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_union() {
+        let hit = hit_x(&Csg::union(sphere(0.0), sphere(1.0))).unwrap();
+        assert!((hit.point.x() + 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_intersection() {
+        // Lens shape between x = 0 and x = 1
+        let hit = hit_x(&Csg::intersection(sphere(0.0), sphere(1.0))).unwrap();
+        assert!(hit.point.x().abs() < 1e-4);
+        assert!(hit.front_face);
+
+        assert!(hit_x(&Csg::intersection(sphere(0.0), sphere(3.0))).is_none());
+    }
+
+    #[test]
+    fn test_difference() {
+        // Bite taken from the left of the sphere: the ray enters through the bite at x = 0.5
+        let hit = hit_x(&Csg::difference(sphere(1.0), sphere(-0.5))).unwrap();
+        assert!((hit.point.x() - 0.5).abs() < 1e-4);
+        assert!((hit.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-4);
+        assert!(hit.front_face);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::aabb::Aabb;
+use crate::bvh::{BvhSplit, BvhTree};
+use crate::float::Float;
+use crate::material::MaterialId;
+use crate::object::{HitRecord, Hittable};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::vec3::{unit_vector, Point3, Vec3};
+
+/// Segments each curve is split into for the BVH, for the boxes of bent curves to fit them
+/// closely.
+const SEGMENTS_PER_CURVE: usize = 4;
+/// Deepest subdivision of a segment while intersecting it, each level halving the pieces.
+const MAX_DEPTH: i32 = 10;
+
+/// Cubic Bézier curve whose width varies linearly from its start to its end, such as a strand
+/// of hair or a blade of grass.
+#[derive(Clone, Copy, Debug)]
+pub struct Curve {
+    control_points: [Point3; 4],
+    widths: [Float; 2],
+}
+
+impl Curve {
+    pub fn new(control_points: [Point3; 4], start_width: Float, end_width: Float) -> Curve {
+        assert!(start_width >= 0.0 && end_width >= 0.0);
+        Curve {
+            control_points,
+            widths: [start_width, end_width],
+        }
+    }
+
+    /// Point at `u` in [0, 1] along the curve.
+    pub fn point(&self, u: Float) -> Point3 {
+        bezier(&self.control_points, u).0
+    }
+
+    /// The part of the curve between `u_min` and `u_max`, with its own control points.
+    fn segment(&self, u_min: Float, u_max: Float) -> Segment {
+        let blossom = |u0, u1, u2| blossom(&self.control_points, u0, u1, u2);
+        Segment {
+            control_points: [
+                blossom(u_min, u_min, u_min),
+                blossom(u_min, u_min, u_max),
+                blossom(u_min, u_max, u_max),
+                blossom(u_max, u_max, u_max),
+            ],
+            widths: [
+                lerp(self.widths[0], self.widths[1], u_min),
+                lerp(self.widths[0], self.widths[1], u_max),
+            ],
+            u_range: [u_min, u_max],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    control_points: [Point3; 4],
+    /// Widths at the ends of the segment.
+    widths: [Float; 2],
+    /// Part of the curve it covers.
+    u_range: [Float; 2],
+}
+
+impl Segment {
+    fn width(&self, w: Float) -> Float {
+        lerp(self.widths[0], self.widths[1], w)
+    }
+
+    fn bounds(&self) -> Aabb {
+        let hull = self
+            .control_points
+            .iter()
+            .fold(Aabb::empty(), |aabb, point| aabb.grow(point));
+        hull.padded(0.5 * self.widths[0].max(self.widths[1]))
+    }
+
+    /// Distance of the closest hit of the ray between `t_min` and `t_max`, and position along
+    /// the segment, from 0 to 1, of the point of the curve hit.
+    fn hit(&self, ray: &RayFrame, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let control_points = self.control_points.map(|point| ray.to_local(&point));
+        // Deep enough for the flattened pieces to depart from the curve by a small fraction of
+        // its width (Nakamaru and Ohno)
+        let curvature = (0..2)
+            .map(|i| {
+                let d = control_points[i] - 2.0 * control_points[i + 1] + control_points[i + 2];
+                d.x().abs().max(d.y().abs()).max(d.z().abs())
+            })
+            .fold(0.0, Float::max);
+        let tolerance = 0.05 * self.widths[0].max(self.widths[1]);
+        let depth = ((std::f64::consts::SQRT_2 as Float * 6.0 * curvature / (8.0 * tolerance))
+            .log2()
+            / 2.0) as i32;
+
+        let length = ray.length;
+        self.hit_piece(
+            &control_points,
+            [0.0, 1.0],
+            t_min * length,
+            t_max * length,
+            depth.clamp(0, MAX_DEPTH),
+        )
+        .map(|(z, w)| (z / length, w))
+    }
+
+    /// Closest hit of the ray along +z from the origin, between `z_min` and `z_max`, on the
+    /// piece of the segment between `w_range` of control points `control_points` in the ray's
+    /// frame. The piece is seen as a ribbon facing the ray, its width varying along it.
+    fn hit_piece(
+        &self,
+        control_points: &[Point3; 4],
+        w_range: [Float; 2],
+        z_min: Float,
+        z_max: Float,
+        depth: i32,
+    ) -> Option<(Float, Float)> {
+        let half_width = 0.5 * self.width(w_range[0]).max(self.width(w_range[1]));
+        let hull = control_points
+            .iter()
+            .fold(Aabb::empty(), |aabb, point| aabb.grow(point))
+            .padded(half_width);
+        let (min, max) = (hull.min(), hull.max());
+        if min.x() > 0.0 || max.x() < 0.0 || min.y() > 0.0 || max.y() < 0.0 {
+            return None;
+        }
+        if min.z() > z_max || max.z() < z_min {
+            return None;
+        }
+
+        if depth > 0 {
+            let [p0, p1, p2, p3, p4, p5, p6] = subdivide(control_points);
+            let middle = 0.5 * (w_range[0] + w_range[1]);
+            let first = self.hit_piece(
+                &[p0, p1, p2, p3],
+                [w_range[0], middle],
+                z_min,
+                z_max,
+                depth - 1,
+            );
+            let z_max = first.map_or(z_max, |(z, _)| z);
+            let second = self.hit_piece(
+                &[p3, p4, p5, p6],
+                [middle, w_range[1]],
+                z_min,
+                z_max,
+                depth - 1,
+            );
+            return second.or(first);
+        }
+
+        // Flat enough to be taken as the line between its ends, only hit between the planes
+        // across the curve there
+        let [p0, p1, p2, p3] = *control_points;
+        let dot_2d = |a: Vec3, b: Vec3| a.x() * b.x() + a.y() * b.y();
+        if dot_2d(p1 - p0, -p0) < 0.0 || dot_2d(p2 - p3, -p3) < 0.0 {
+            return None;
+        }
+        let chord = p3 - p0;
+        let chord_length_squared = dot_2d(chord, chord);
+        if chord_length_squared == 0.0 {
+            return None;
+        }
+        // Closest point of the line to the ray
+        let w = (dot_2d(-p0, chord) / chord_length_squared).clamp(0.0, 1.0);
+        let (point, _) = bezier(control_points, w);
+        let w = lerp(w_range[0], w_range[1], w);
+        let width = self.width(w);
+        if dot_2d(point, point) > 0.25 * width * width || point.z() < z_min || point.z() > z_max {
+            return None;
+        }
+        Some((point.z(), w))
+    }
+}
+
+/// Ray rotated for its direction to be the +z axis, once for all the segments it is tested
+/// against.
+struct RayFrame {
+    origin: Point3,
+    uvw: Onb,
+    /// Length of the direction, distances along +z being `length` times the distances along
+    /// the ray.
+    length: Float,
+}
+
+impl RayFrame {
+    fn new(ray: &Ray) -> RayFrame {
+        RayFrame {
+            origin: ray.origin(),
+            uvw: Onb::build_from_w(&ray.direction()),
+            length: ray.direction().length(),
+        }
+    }
+
+    fn to_local(&self, point: &Point3) -> Point3 {
+        self.uvw.to_local(&(*point - self.origin))
+    }
+}
+
+/// Curves sharing a material, such as the strands of a head of hair. They are intersected as
+/// ribbons facing the rays, shaded as if they were round, and their segments are searched with
+/// their own BVH, so that many curves are a single object of the scene.
+#[derive(Clone, Debug)]
+pub struct Curves {
+    /// Segments of the curves, in the order of the leaves of `bvh`.
+    segments: Vec<Segment>,
+    bvh: BvhTree,
+    material: MaterialId,
+}
+
+impl Curves {
+    pub fn new(curves: Vec<Curve>, material: MaterialId) -> Curves {
+        let segments: Vec<Segment> = curves
+            .iter()
+            .flat_map(|curve| {
+                (0..SEGMENTS_PER_CURVE).map(move |i| {
+                    let u_min = i as Float / SEGMENTS_PER_CURVE as Float;
+                    let u_max = (i + 1) as Float / SEGMENTS_PER_CURVE as Float;
+                    curve.segment(u_min, u_max)
+                })
+            })
+            .collect();
+        let bounds: Vec<Aabb> = segments.iter().map(Segment::bounds).collect();
+        let (bvh, order) = BvhTree::build(&bounds, BvhSplit::Sah);
+        Curves {
+            segments: order.into_iter().map(|i| segments[i]).collect(),
+            bvh,
+            material,
+        }
+    }
+}
+
+impl Hittable for Curves {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let frame = RayFrame::new(ray);
+        // Closest segment with the distance and position along it of its hit
+        let mut closest_hit = None;
+        self.bvh
+            .traverse(ray, t_min, t_max, |leaf, mut closest_so_far| {
+                let mut closer = None;
+                for index in leaf {
+                    if let Some((t, w)) = self.segments[index].hit(&frame, t_min, closest_so_far) {
+                        closest_so_far = t;
+                        closer = Some(t);
+                        closest_hit = Some((index, t, w));
+                    }
+                }
+                closer
+            });
+        let (index, t, w) = match closest_hit {
+            Some(hit) => hit,
+            None => return false,
+        };
+
+        let segment = &self.segments[index];
+        let (center, derivative) = bezier(&segment.control_points, w);
+        let tangent = if derivative.length_squared() > 0.0 {
+            unit_vector(derivative)
+        } else {
+            unit_vector(segment.control_points[3] - segment.control_points[0])
+        };
+        // The ribbon contains the tangent and faces the ray
+        let direction = unit_vector(ray.direction());
+        let facing = direction.dot(&tangent) * tangent - direction;
+        let facing = if facing.length_squared() > 0.0 {
+            unit_vector(facing)
+        } else {
+            Onb::build_from_w(&tangent).u()
+        };
+        hit_record.t = t;
+        hit_record.point = ray.at(t);
+        hit_record.set_face_normal(ray, &facing);
+        // Shaded as a tube, the normal turning towards the edges of the ribbon
+        let side = tangent.cross(&facing);
+        let width = segment.width(w);
+        let offset = if width > 0.0 {
+            ((hit_record.point - center).dot(&side) / (0.5 * width)).clamp(-1.0, 1.0)
+        } else {
+            0.0
+        };
+        hit_record.normal = (1.0 - offset * offset).sqrt() * facing + offset * side;
+        // The ribbon turns to face the rays leaving it, which start beyond its reach
+        hit_record.point_error = 2.0 * width;
+        hit_record.u = lerp(segment.u_range[0], segment.u_range[1], w);
+        hit_record.v = 0.5 * (offset + 1.0);
+        hit_record.tangent = tangent;
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bvh.bounds())
+    }
+}
+
+fn lerp<T>(a: T, b: T, t: Float) -> T
+where
+    T: std::ops::Mul<Float, Output = T> + std::ops::Add<Output = T>,
+{
+    a * (1.0 - t) + b * t
+}
+
+/// Point of the Bézier curve of `control_points` at `u` and derivative there (de Casteljau).
+fn bezier(control_points: &[Point3; 4], u: Float) -> (Point3, Vec3) {
+    let [p0, p1, p2, p3] = *control_points;
+    let (a, b, c) = (lerp(p0, p1, u), lerp(p1, p2, u), lerp(p2, p3, u));
+    let (d, e) = (lerp(a, b, u), lerp(b, c, u));
+    (lerp(d, e, u), 3.0 * (e - d))
+}
+
+/// The control points of both halves of the curve of `control_points`, sharing the middle one.
+fn subdivide(control_points: &[Point3; 4]) -> [Point3; 7] {
+    let [p0, p1, p2, p3] = *control_points;
+    let (a, b, c) = (lerp(p0, p1, 0.5), lerp(p1, p2, 0.5), lerp(p2, p3, 0.5));
+    let (d, e) = (lerp(a, b, 0.5), lerp(b, c, 0.5));
+    [p0, a, d, lerp(d, e, 0.5), e, c, p3]
+}
+
+/// Blossom of the curve of `control_points`: de Casteljau with a different parameter at each
+/// level, the control points of the part from u to v being those of (u, u, u), (u, u, v),
+/// (u, v, v) and (v, v, v).
+fn blossom(control_points: &[Point3; 4], u0: Float, u1: Float, u2: Float) -> Point3 {
+    let [p0, p1, p2, p3] = *control_points;
+    let (a, b, c) = (lerp(p0, p1, u0), lerp(p1, p2, u0), lerp(p2, p3, u0));
+    let (d, e) = (lerp(a, b, u1), lerp(b, c, u1));
+    lerp(d, e, u2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn hit(curves: &Curves, origin: Point3, direction: Vec3) -> Option<HitRecord> {
+        let mut hit_record = HitRecord::empty();
+        curves
+            .hit(
+                &Ray::new(origin, direction),
+                0.0,
+                Float::MAX,
+                &mut hit_record,
+            )
+            .then_some(hit_record)
+    }
+
+    #[test]
+    fn test_straight_ribbon() {
+        // Along x, 0.2 wide
+        let curve = Curve::new(
+            [
+                Point3::new(-1.0, 0.0, 0.0),
+                Point3::new(-0.5, 0.0, 0.0),
+                Point3::new(0.5, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+            ],
+            0.2,
+            0.2,
+        );
+        let curves = Curves::new(vec![curve], MaterialId::default());
+        let down = Vec3::new(0.0, 0.0, -2.0);
+
+        let hit_record = hit(&curves, Point3::new(0.0, 0.0, 5.0), down).unwrap();
+        assert!((hit_record.t - 2.5).abs() < 1e-4);
+        assert!((hit_record.u - 0.5).abs() < 1e-3);
+        assert!((hit_record.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-3);
+        assert!((hit_record.tangent - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-3);
+
+        // Near its edge, the normal turns towards it
+        let hit_record = hit(&curves, Point3::new(0.5, 0.09, 5.0), down).unwrap();
+        assert!(hit_record.normal.y() > 0.8 && hit_record.normal.z() > 0.0);
+        assert!((hit_record.v - 0.05).abs() < 1e-3);
+
+        assert!(hit(&curves, Point3::new(0.5, 0.11, 5.0), down).is_none());
+        assert!(hit(&curves, Point3::new(1.05, 0.0, 5.0), down).is_none());
+        // Facing rays from any side
+        let hit_record = hit(
+            &curves,
+            Point3::new(0.0, -5.0, 0.05),
+            Vec3::new(0.0, 1.0, 0.0),
+        )
+        .unwrap();
+        assert!((hit_record.normal - Vec3::new(0.0, -0.866, 0.5)).length() < 1e-2);
+    }
+
+    #[test]
+    fn test_bent_strands() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let strands: Vec<Curve> = (0..20)
+            .map(|_| {
+                let root = Vec3::random_range(&mut rng, -5.0, 5.0);
+                let control_points = [
+                    root,
+                    root + Vec3::new(0.0, 1.0, 0.0),
+                    root + Vec3::random_range(&mut rng, -1.0, 1.0) + Vec3::new(0.0, 2.0, 0.0),
+                    root + Vec3::random_range(&mut rng, -2.0, 2.0) + Vec3::new(0.0, 3.0, 0.0),
+                ];
+                Curve::new(control_points, 0.05, 0.01)
+            })
+            .collect();
+        let curves = Curves::new(strands.clone(), MaterialId::default());
+
+        // Rays aimed at points of the strands find them, or one in front of them
+        for _ in 0..200 {
+            let strand = &strands[rng.gen_range(0..strands.len())];
+            let u = rng.gen_range(0.0..1.0);
+            let target = strand.point(u);
+            let origin = target + 20.0 * Vec3::random_unit_vector(&mut rng);
+            let hit_record = hit(&curves, origin, target - origin).unwrap();
+            assert!(hit_record.t <= 1.0 + 1e-3);
+            if (hit_record.point - target).length() < 0.05 {
+                assert!((hit_record.u - u).abs() < 0.05);
+            }
+        }
+    }
+}
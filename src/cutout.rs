@@ -0,0 +1,104 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::object::{HitRecord, Hittable, SurfaceSample};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::texture::Texture;
+use crate::vec3::{Point3, Vec3};
+
+/// Opacity under which the surface is cut out.
+const OPACITY_THRESHOLD: Float = 0.5;
+
+/// Object with holes cut out where an opacity texture, looked up at the surface coordinates of
+/// the hits, is below one half: rays go through the holes as if the surface wasn't there. Leaves
+/// or fences are then modeled as a few textured quads rather than with detailed geometry.
+///
+/// The lights sampled over a cutout object are still sampled over the holes.
+pub struct Cutout {
+    object: Box<dyn Hittable>,
+    opacity: Texture,
+}
+
+impl Cutout {
+    /// Cuts out `object` where the average of the channels of `opacity` is below one half, see
+    /// `ImageTexture::load_opacity` for the alpha channel of an image.
+    pub fn new(object: Box<dyn Hittable>, opacity: Texture) -> Cutout {
+        Cutout { object, opacity }
+    }
+}
+
+impl Hittable for Cutout {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut t_min = t_min;
+        loop {
+            if !self.object.hit(ray, t_min, t_max, hit_record) {
+                return false;
+            }
+            let opacity = self
+                .opacity
+                .scalar(hit_record.u, hit_record.v, &hit_record.point);
+            if opacity >= OPACITY_THRESHOLD {
+                return true;
+            }
+            // Through the hole, on to the next hit of the object
+            t_min = hit_record.t.next_up();
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object.bounding_box()
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.object.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        self.object.random(origin, rng)
+    }
+
+    fn sample_surface(&self, rng: &mut SampleRng) -> Option<SurfaceSample> {
+        self.object.sample_surface(rng)
+    }
+
+    fn surface_pdf(&self, point: &Point3) -> Float {
+        self.object.surface_pdf(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialId;
+    use crate::object::HittableList;
+    use crate::rect::Rect;
+    use crate::texture::ImageTexture;
+    use crate::vec3::Color;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rays_go_through_holes() {
+        // Two quads facing up, the top one opaque on its left half only
+        let quad = |y: Float| {
+            Box::new(Rect::new(
+                Point3::new(0.0, y, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, -1.0),
+                MaterialId::default(),
+            ))
+        };
+        let opaque = Color::new(1.0, 1.0, 1.0);
+        let left_half = ImageTexture::new(2, 1, vec![opaque, Color::zero()]);
+        let top = Cutout::new(quad(1.0), Texture::Image(Arc::new(left_half)));
+        let mut objects = HittableList::new();
+        objects.add(Box::new(top));
+        objects.add(quad(0.0));
+
+        let mut hit_record = HitRecord::empty();
+        let down = |x: Float| Ray::new(Point3::new(x, 2.0, -0.5), Vec3::new(0.0, -1.0, 0.0));
+        assert!(objects.hit(&down(0.25), 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 1.0).abs() < 1e-5);
+        assert!(objects.hit(&down(0.75), 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 2.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::material::MaterialId;
+use crate::object::{rounding_error, HitRecord, Hittable};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::vec3::{Point3, Vec3};
+
+/// Finite cylinder between the centers of its two ends, optionally closed by disks.
+#[derive(Clone)]
+pub struct Cylinder {
+    base: Point3,
+    height: Float,
+    radius: Float,
+    capped: bool,
+    /// Local frame, `w` going from the base to the top.
+    uvw: Onb,
+    material: MaterialId,
+}
+
+impl Cylinder {
+    pub fn new(
+        base: Point3,
+        top: Point3,
+        radius: Float,
+        capped: bool,
+        material: MaterialId,
+    ) -> Cylinder {
+        let axis = top - base;
+        Cylinder {
+            base,
+            height: axis.length(),
+            radius,
+            capped,
+            uvw: Onb::build_from_w(&axis),
+            material,
+        }
+    }
+
+    /// Nearest hit of the body, `origin` and `direction` being expressed in the cylinder frame.
+    fn hit_body(
+        &self,
+        origin: &Vec3,
+        direction: &Vec3,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<Float> {
+        let a = direction.x() * direction.x() + direction.y() * direction.y();
+        if a == 0.0 {
+            // Parallel to the axis
+            return None;
+        }
+        let half_b = origin.x() * direction.x() + origin.y() * direction.y();
+        let c = origin.x() * origin.x() + origin.y() * origin.y() - self.radius * self.radius;
+
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrt_discriminant = discriminant.sqrt();
+
+        [
+            (-half_b - sqrt_discriminant) / a,
+            (-half_b + sqrt_discriminant) / a,
+        ]
+        .iter()
+        .copied()
+        .find(|&t| {
+            let z = origin.z() + t * direction.z();
+            t >= t_min && t <= t_max && z >= 0.0 && z <= self.height
+        })
+    }
+
+    /// Hit of the disk closing the cylinder at height `z`.
+    fn hit_cap(
+        &self,
+        origin: &Vec3,
+        direction: &Vec3,
+        z: Float,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<Float> {
+        if direction.z() == 0.0 {
+            return None;
+        }
+        let t = (z - origin.z()) / direction.z();
+        let x = origin.x() + t * direction.x();
+        let y = origin.y() + t * direction.y();
+        if t < t_min || t > t_max || x * x + y * y > self.radius * self.radius {
+            return None;
+        }
+        Some(t)
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let origin = self.uvw.to_local(&(ray.origin() - self.base));
+        let direction = self.uvw.to_local(&ray.direction());
+
+        let mut closest = self
+            .hit_body(&origin, &direction, t_min, t_max)
+            .map(|t| (t, false));
+        if self.capped {
+            for &z in &[0.0, self.height] {
+                let t_max = closest.map_or(t_max, |(t, _)| t);
+                if let Some(t) = self.hit_cap(&origin, &direction, z, t_min, t_max) {
+                    closest = Some((t, true));
+                }
+            }
+        }
+
+        let (t, on_cap) = match closest {
+            Some(hit) => hit,
+            None => return false,
+        };
+
+        let mut p = origin + t * direction;
+        if !on_cap {
+            // Reprojected onto the body, the root being inaccurate for wide cylinders
+            let scale = self.radius / (p.x() * p.x() + p.y() * p.y()).sqrt();
+            p = Vec3::new(scale * p.x(), scale * p.y(), p.z());
+        }
+        let (local_normal, local_tangent, u, v) = if on_cap {
+            let normal = if p.z() > 0.5 * self.height {
+                Vec3::new(0.0, 0.0, 1.0)
+            } else {
+                Vec3::new(0.0, 0.0, -1.0)
+            };
+            let u = 0.5 * (p.x() / self.radius + 1.0);
+            let v = 0.5 * (p.y() / self.radius + 1.0);
+            (normal, Vec3::new(1.0, 0.0, 0.0), u, v)
+        } else {
+            let normal = Vec3::new(p.x() / self.radius, p.y() / self.radius, 0.0);
+            let u = (p.y().atan2(p.x()) + PI) / (2.0 * PI);
+            let v = p.z() / self.height;
+            (normal, Vec3::new(-p.y(), p.x(), 0.0), u, v)
+        };
+
+        hit_record.t = t;
+        hit_record.point = if on_cap {
+            ray.at(t)
+        } else {
+            self.base + self.uvw.local(&p)
+        };
+        hit_record.set_face_normal(ray, &self.uvw.local(&local_normal));
+        if !on_cap {
+            hit_record.point_error = rounding_error(self.radius + self.height);
+        }
+        hit_record.u = u;
+        hit_record.v = v;
+        hit_record.tangent = self.uvw.local(&local_tangent);
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let axis = self.uvw.w();
+        let top = self.base + self.height * axis;
+        Some(Aabb::disk(self.base, axis, self.radius).union(&Aabb::disk(top, axis, self.radius)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cylinder(capped: bool) -> Cylinder {
+        let material = MaterialId::default();
+        Cylinder::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+            1.0,
+            capped,
+            material,
+        )
+    }
+
+    #[test]
+    fn test_hit_body() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(5.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(cylinder(true).hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 4.0).abs() < 1e-5);
+        assert!((hit_record.normal - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-5);
+        assert!((hit_record.v - 0.5).abs() < 1e-5);
+        assert!(hit_record.front_face);
+    }
+
+    #[test]
+    fn test_hit_cap() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(0.5, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(cylinder(true).hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 3.0).abs() < 1e-5);
+        assert!((hit_record.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+
+        // Without caps, a ray parallel to the axis goes through the open ends
+        assert!(!cylinder(false).hit(&ray, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    #[test]
+    fn test_miss_above() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(5.0, 3.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(!cylinder(true).hit(&ray, 0.001, Float::MAX, &mut hit_record));
+    }
+}
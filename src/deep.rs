@@ -0,0 +1,417 @@
+use crate::float::Float;
+use crate::material::{Material, MaterialList};
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Color};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Depth difference, relative to the depth, under which the samples of a pixel are merged into
+/// one, as hits of the same surface.
+const DEEP_MERGE_TOLERANCE: Float = 1e-3;
+/// Number of invisible surfaces, such as the boundaries of volumes, a camera ray goes through
+/// before its depth is given up on.
+const DEEP_MAX_PASSES: usize = 16;
+
+/// Distance along `ray` of the first thing it sees, none for the background: the first surface
+/// hit, or inside of volumes the collision delta tracking samples. Called with a copy of the
+/// random numbers of the camera sample, it mostly picks the collision its path goes through.
+pub fn sample_depth<H: Hittable + ?Sized>(
+    ray: &Ray,
+    world: &H,
+    materials: &MaterialList,
+    rng: &mut SampleRng,
+) -> Option<Float> {
+    let mut ray = *ray;
+    let mut depth = 0.0;
+    for _ in 0..DEEP_MAX_PASSES {
+        let mut hit_record = HitRecord::empty();
+        if !world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            return None;
+        }
+        let distance = hit_record.t * ray.direction().length();
+        match materials[hit_record.material] {
+            Material::Volume(ref volume) => {
+                if !hit_record.front_face {
+                    if let Some(travelled) = volume.sample_collision(&ray, distance, rng) {
+                        return Some(depth + travelled);
+                    }
+                }
+            }
+            _ => return Some(depth + distance),
+        }
+        depth += distance;
+        let direction = unit_vector(ray.direction());
+        ray = Ray::new(hit_record.ray_origin(&direction), direction);
+    }
+    None
+}
+
+/// Part of a pixel at a given depth, its color premultiplied by its alpha.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeepSample {
+    pub depth: Float,
+    /// Farthest depth of the camera samples merged into this one.
+    pub depth_back: Float,
+    pub color: Color,
+    pub alpha: Float,
+}
+
+/// Image of the samples of each pixel at their depth, front to back, for compositing with other
+/// renders by depth. Flattening the samples of a pixel by compositing them over each other
+/// gives back the average of its camera samples, the background being left out.
+pub struct DeepImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec<DeepSample>>,
+}
+
+impl DeepImage {
+    pub fn new(width: usize, height: usize) -> DeepImage {
+        DeepImage {
+            width,
+            height,
+            pixels: Vec::with_capacity(width * height),
+        }
+    }
+
+    /// Appends the next pixel, in output order, from the color of each of its camera samples
+    /// and the depth of what it saw, none for the background.
+    pub fn push(&mut self, samples: &[(Option<Float>, Color)]) {
+        let count = samples.len() as Float;
+        let mut hits: Vec<(Float, Color)> = samples
+            .iter()
+            .filter_map(|&(depth, color)| Some((depth?, color)))
+            .collect();
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut pixel: Vec<DeepSample> = Vec::new();
+        // Coverage of the samples in front, which the samples behind are seen through
+        let mut coverage_in_front = 0.0;
+        let mut start = 0;
+        while start < hits.len() {
+            let depth = hits[start].0;
+            let end = start
+                + hits[start..]
+                    .iter()
+                    .take_while(|(d, _)| *d - depth <= DEEP_MERGE_TOLERANCE * depth)
+                    .count();
+            let color: Color = hits[start..end]
+                .iter()
+                .fold(Color::zero(), |sum, (_, color)| sum + *color);
+
+            // Scaled up by the transmittance in front, for the group to contribute its share
+            let coverage = (end - start) as Float / count;
+            let transmittance = 1.0 - coverage_in_front;
+            pixel.push(DeepSample {
+                depth,
+                depth_back: hits[end - 1].0,
+                color: color / count / transmittance,
+                alpha: (coverage / transmittance).min(1.0),
+            });
+            coverage_in_front += coverage;
+            start = end;
+        }
+        self.pixels.push(pixel);
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec<DeepSample>] {
+        &self.pixels
+    }
+
+    /// Samples of each pixel composited over each other, front to back, over black.
+    pub fn flatten(&self) -> Vec<Color> {
+        self.pixels
+            .iter()
+            .map(|samples| {
+                let mut color = Color::zero();
+                let mut transmittance = 1.0;
+                for sample in samples {
+                    color += transmittance * sample.color;
+                    transmittance *= 1.0 - sample.alpha;
+                }
+                color
+            })
+            .collect()
+    }
+
+    /// Writes the image as a deep scanline OpenEXR file, through a buffer.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create output file {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Deep scanline OpenEXR, uncompressed, with the premultiplied R, G, B, the A and the Z and
+    /// ZBack channels as 32-bit floats, one scanline per chunk.
+    // Stored as f32, whatever the precision of `Float`
+    #[allow(clippy::unnecessary_cast)]
+    pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        if self.pixels.len() != self.width * self.height {
+            bail!("Image size doesn't match the pixel count");
+        }
+        let max_samples = self.pixels.iter().map(Vec::len).max().unwrap_or(0);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&EXR_MAGIC.to_le_bytes());
+        header.extend_from_slice(&(EXR_VERSION | EXR_NON_IMAGE_FLAG).to_le_bytes());
+        let mut channels = Vec::new();
+        for name in EXR_CHANNELS {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&EXR_PIXEL_TYPE_FLOAT.to_le_bytes());
+            // Not perceptually linear, reserved bytes, no subsampling
+            channels.extend_from_slice(&[0; 4]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        let window: Vec<u8> = [0, 0, self.width as i32 - 1, self.height as i32 - 1]
+            .iter()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        write_attribute(&mut header, "channels", "chlist", &channels);
+        write_attribute(&mut header, "compression", "compression", &[0]);
+        write_attribute(&mut header, "dataWindow", "box2i", &window);
+        write_attribute(&mut header, "displayWindow", "box2i", &window);
+        write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        write_attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        write_attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        write_attribute(&mut header, "name", "string", b"deep");
+        write_attribute(&mut header, "type", "string", b"deepscanline");
+        write_attribute(&mut header, "version", "int", &1i32.to_le_bytes());
+        write_attribute(
+            &mut header,
+            "chunkCount",
+            "int",
+            &(self.height as i32).to_le_bytes(),
+        );
+        write_attribute(
+            &mut header,
+            "maxSamplesPerPixel",
+            "int",
+            &(max_samples as i32).to_le_bytes(),
+        );
+        header.push(0);
+
+        let chunks: Vec<Vec<u8>> = self
+            .pixels
+            .chunks(self.width)
+            .enumerate()
+            .map(|(y, row)| {
+                // Running sample count at the end of each pixel
+                let mut offsets = Vec::with_capacity(4 * self.width);
+                let mut total = 0;
+                for pixel in row {
+                    total += pixel.len() as i32;
+                    offsets.extend_from_slice(&total.to_le_bytes());
+                }
+                // All the samples of the row, channel after channel
+                let mut data = Vec::with_capacity(4 * EXR_CHANNELS.len() * total as usize);
+                let channel_values: [fn(&DeepSample) -> Float; 6] = [
+                    |s| s.alpha,
+                    |s| s.color.z(),
+                    |s| s.color.y(),
+                    |s| s.color.x(),
+                    |s| s.depth,
+                    |s| s.depth_back,
+                ];
+                for value in channel_values {
+                    for sample in row.iter().flatten() {
+                        data.extend_from_slice(&(value(sample) as f32).to_le_bytes());
+                    }
+                }
+
+                let mut chunk = Vec::with_capacity(28 + offsets.len() + data.len());
+                chunk.extend_from_slice(&(y as i32).to_le_bytes());
+                chunk.extend_from_slice(&(offsets.len() as u64).to_le_bytes());
+                // Packed and unpacked sizes, the same without compression
+                chunk.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                chunk.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                chunk.extend_from_slice(&offsets);
+                chunk.extend_from_slice(&data);
+                chunk
+            })
+            .collect();
+
+        // Offsets of the chunks from the start of the file, after their table
+        writer.write_all(&header)?;
+        let mut offset = (header.len() + 8 * chunks.len()) as u64;
+        for chunk in &chunks {
+            writer.write_all(&offset.to_le_bytes())?;
+            offset += chunk.len() as u64;
+        }
+        for chunk in &chunks {
+            writer.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+const EXR_MAGIC: u32 = 20000630;
+const EXR_VERSION: u32 = 2;
+/// Version flag of the files holding deep data.
+const EXR_NON_IMAGE_FLAG: u32 = 0x800;
+const EXR_PIXEL_TYPE_FLOAT: i32 = 2;
+/// Channels in the alphabetical order they are stored in.
+const EXR_CHANNELS: [&str; 6] = ["A", "B", "G", "R", "Z", "ZBack"];
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Lambertian, Volume};
+    use crate::object::HittableList;
+    use crate::scenes::cuboid;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+    use crate::volume::{Density, Pyroclastic};
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_flatten_averages_samples() {
+        let red = Color::new(1.0, 0.0, 0.0);
+        let blue = Color::new(0.0, 0.0, 2.0);
+        let mut image = DeepImage::new(2, 1);
+        image.push(&[
+            (Some(5.0), blue),
+            (Some(2.0), red),
+            (None, Color::new(9.0, 9.0, 9.0)),
+            (Some(2.0 + 1e-4), red),
+        ]);
+        image.push(&[(None, red)]);
+
+        // The two hits of the red surface are merged, in front of the blue one
+        let samples = &image.pixels()[0];
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[0].depth, samples[0].depth_back), (2.0, 2.0 + 1e-4));
+        assert_eq!(samples[0].alpha, 0.5);
+        assert_eq!(samples[1].depth, 5.0);
+        assert_eq!(samples[1].alpha, 0.5);
+        assert!(image.pixels()[1].is_empty());
+
+        let flat = image.flatten();
+        assert!((flat[0] - Color::new(0.5, 0.0, 0.5)).length() < 1e-6);
+        assert_eq!(flat[1], Color::zero());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut image = DeepImage::new(2, 2);
+        image.push(&[(Some(1.0), Color::new(1.0, 2.0, 3.0))]);
+        image.push(&[(None, Color::zero())]);
+        image.push(&[(Some(1.0), Color::zero()), (Some(3.0), Color::zero())]);
+        image.push(&[(Some(2.0), Color::zero())]);
+        let mut exr = Vec::new();
+        image.write(&mut exr).unwrap();
+
+        let u32_at = |at: usize| u32::from_le_bytes(exr[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(exr[at..at + 8].try_into().unwrap());
+        let f32_at = |at: usize| f32::from_le_bytes(exr[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), EXR_MAGIC);
+        assert_eq!(u32_at(4), 0x802);
+        let header_end = exr
+            .windows(b"maxSamplesPerPixel\0int\0".len())
+            .position(|w| w == b"maxSamplesPerPixel\0int\0")
+            .unwrap()
+            + b"maxSamplesPerPixel\0int\0".len();
+        assert_eq!(u32_at(header_end), 4);
+        assert_eq!(u32_at(header_end + 4), 2);
+        assert_eq!(exr[header_end + 8], 0);
+
+        // The first row: one sample, then none
+        let first = u64_at(header_end + 9) as usize;
+        assert_eq!(first, header_end + 9 + 16);
+        assert_eq!(u32_at(first), 0);
+        assert_eq!(u64_at(first + 4), 8);
+        assert_eq!(u64_at(first + 12), 6 * 4);
+        assert_eq!((u32_at(first + 28), u32_at(first + 32)), (1, 1));
+        let values: Vec<f32> = (0..6).map(|i| f32_at(first + 36 + 4 * i)).collect();
+        assert_eq!(values, vec![1.0, 3.0, 2.0, 1.0, 1.0, 1.0]);
+
+        // The second row: two samples, then one, all in the last chunk
+        let second = u64_at(header_end + 17) as usize;
+        assert_eq!(second, first + 36 + 6 * 4);
+        assert_eq!((u32_at(second + 28), u32_at(second + 32)), (2, 3));
+        let depths: Vec<f32> = (0..3).map(|i| f32_at(second + 36 + 4 * (12 + i))).collect();
+        assert_eq!(depths, vec![1.0, 3.0, 2.0]);
+        assert_eq!(exr.len(), second + 36 + 18 * 4);
+    }
+
+    #[test]
+    fn test_sample_depth() {
+        let mut materials = MaterialList::new();
+        let diffuse = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+            0.5, 0.5, 0.5,
+        ))));
+        let cloud = Pyroclastic::new(Point3::zero(), 1.0, 0.0, 0);
+        let volume = materials.add(Material::Volume(Volume::new(
+            Density::Pyroclastic(cloud),
+            0.5,
+            Color::new(1.0, 1.0, 1.0),
+        )));
+        let mut world = HittableList::new();
+        world.add(Box::new(cuboid(
+            Point3::new(-1.0, -1.0, -1.0),
+            Point3::new(1.0, 1.0, 1.0),
+            volume,
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -10.0),
+            1.0,
+            diffuse,
+        )));
+
+        // The ray sees the sphere through the volume, or collides in the cloud
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut rng = SampleRng::new(0);
+        let mut collisions = 0;
+        for _ in 0..100 {
+            let depth = sample_depth(&ray, &world, &materials, &mut rng).unwrap();
+            if depth < 7.0 {
+                assert!(depth > 4.0);
+                collisions += 1;
+            } else {
+                assert!((depth - 14.0).abs() < 1e-3);
+            }
+        }
+        assert!(collisions > 50 && collisions < 100);
+
+        let up = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(sample_depth(&up, &world, &materials, &mut rng), None);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::material::MaterialId;
+use crate::object::{HitRecord, Hittable, SurfaceSample};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::vec3::{Point3, Vec3};
+use rand::Rng;
+
+/// Flat disk facing `normal`, with a hole of `inner_radius` in its middle (0 for a full disk).
+#[derive(Clone)]
+pub struct Disk {
+    center: Point3,
+    inner_radius: Float,
+    outer_radius: Float,
+    /// Local frame, `w` being the normal.
+    uvw: Onb,
+    material: MaterialId,
+}
+
+impl Disk {
+    pub fn new(
+        center: Point3,
+        normal: Vec3,
+        inner_radius: Float,
+        outer_radius: Float,
+        material: MaterialId,
+    ) -> Disk {
+        Disk {
+            center,
+            inner_radius,
+            outer_radius,
+            uvw: Onb::build_from_w(&normal),
+            material,
+        }
+    }
+
+    fn area(&self) -> Float {
+        PI * (self.outer_radius * self.outer_radius - self.inner_radius * self.inner_radius)
+    }
+
+    /// Point uniformly distributed on the area of the annulus.
+    fn random_point(&self, rng: &mut SampleRng) -> Point3 {
+        let inner_squared = self.inner_radius * self.inner_radius;
+        let outer_squared = self.outer_radius * self.outer_radius;
+        let radius = (inner_squared + rng.gen::<Float>() * (outer_squared - inner_squared)).sqrt();
+        let phi = 2.0 * PI * rng.gen::<Float>();
+        self.center
+            + self
+                .uvw
+                .local(&Vec3::new(radius * phi.cos(), radius * phi.sin(), 0.0))
+    }
+}
+
+impl Hittable for Disk {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let normal = self.uvw.w();
+        let denominator = ray.direction().dot(&normal);
+        if denominator.abs() < 1e-8 || self.material.culls(ray, &normal) {
+            // Parallel to the disk, or seen from a culled back
+            return false;
+        }
+
+        let t = (self.center - ray.origin()).dot(&normal) / denominator;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        let p = self.uvw.to_local(&(ray.at(t) - self.center));
+        let radius_squared = p.x() * p.x() + p.y() * p.y();
+        if radius_squared > self.outer_radius * self.outer_radius
+            || radius_squared < self.inner_radius * self.inner_radius
+        {
+            return false;
+        }
+
+        hit_record.t = t;
+        hit_record.point = ray.at(t);
+        hit_record.set_face_normal(ray, &normal);
+        // Polar coordinates: u around the center, v from the inner to the outer edge
+        hit_record.u = (p.y().atan2(p.x()) + PI) / (2.0 * PI);
+        hit_record.v =
+            (radius_squared.sqrt() - self.inner_radius) / (self.outer_radius - self.inner_radius);
+        hit_record.tangent = self.uvw.local(&Vec3::new(-p.y(), p.x(), 0.0));
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::disk(self.center, self.uvw.w(), self.outer_radius))
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let mut hit_record = HitRecord::empty();
+        if !self.hit(
+            &Ray::new(*origin, *direction),
+            0.001,
+            Float::MAX,
+            &mut hit_record,
+        ) {
+            return 0.0;
+        }
+
+        let distance_squared = hit_record.t * hit_record.t * direction.length_squared();
+        let cosine = (direction.dot(&hit_record.normal) / direction.length()).abs();
+
+        distance_squared / (cosine * self.area())
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        self.random_point(rng) - *origin
+    }
+
+    fn sample_surface(&self, rng: &mut SampleRng) -> Option<SurfaceSample> {
+        Some(SurfaceSample {
+            point: self.random_point(rng),
+            normal: self.uvw.w(),
+            pdf: 1.0 / self.area(),
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point3) -> Float {
+        1.0 / self.area()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annulus() -> Disk {
+        let material = MaterialId::default();
+        Disk::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            1.0,
+            2.0,
+            material,
+        )
+    }
+
+    #[test]
+    fn test_hit_ring() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(1.5, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(annulus().hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 3.0).abs() < 1e-5);
+        assert!((hit_record.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+        assert!((hit_record.v - 0.5).abs() < 1e-5);
+        assert!(hit_record.front_face);
+    }
+
+    #[test]
+    fn test_miss_hole_and_outside() {
+        let mut hit_record = HitRecord::empty();
+        let disk = annulus();
+        let through_hole = Ray::new(Point3::new(0.5, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(!disk.hit(&through_hole, 0.001, Float::MAX, &mut hit_record));
+        let outside = Ray::new(Point3::new(2.5, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(!disk.hit(&outside, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    #[test]
+    fn test_random_hits_disk() {
+        let disk = annulus();
+        let origin = Point3::new(0.0, 5.0, 0.0);
+        let mut rng = SampleRng::new(0);
+        for _ in 0..100 {
+            let direction = disk.random(&origin, &mut rng);
+            assert!(disk.pdf_value(&origin, &direction) > 0.0);
+        }
+    }
+}
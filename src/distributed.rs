@@ -0,0 +1,661 @@
+use crate::filter::Filter;
+use crate::float::Float;
+use crate::fog::HeightFog;
+use crate::framebuffer::{Framebuffer, TileSamples};
+use crate::integrator::Integrator;
+use crate::sampler::Sampler;
+use crate::scenes::BuiltinScene;
+use crate::settings::RenderSettings;
+use crate::vec3::Color;
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MAGIC: &[u8; 5] = b"RTNET";
+const PROTOCOL_VERSION: u8 = 11;
+
+pub const TILE_SIZE: u16 = 32;
+
+/// Rectangle of pixels, `y` = 0 being the top row of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Tile {
+    /// Indices of the tile pixels in the image buffer, in row-major order.
+    pub fn pixel_indices(&self, image_width: u16) -> impl Iterator<Item = usize> {
+        let tile = *self;
+        (tile.y..tile.y + tile.height).flat_map(move |y| {
+            (tile.x..tile.x + tile.width)
+                .map(move |x| y as usize * image_width as usize + x as usize)
+        })
+    }
+
+    /// The tile grown by `margin` pixels on each side, within an image of `image_width` by
+    /// `image_height` pixels.
+    pub fn with_margin(&self, margin: u16, image_width: u16, image_height: u16) -> Tile {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        let right = (self.x + self.width)
+            .saturating_add(margin)
+            .min(image_width);
+        let bottom = (self.y + self.height)
+            .saturating_add(margin)
+            .min(image_height);
+        Tile {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+pub fn split_into_tiles(image_width: u16, image_height: u16, tile_size: u16) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..image_height).step_by(tile_size as usize) {
+        for x in (0..image_width).step_by(tile_size as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_size.min(image_width - x),
+                height: tile_size.min(image_height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Messages exchanged between the coordinator and its workers.
+///
+/// Each message is a one byte tag followed by its fields, little-endian.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Worker -> coordinator, first message of the connection.
+    Hello { version: u8 },
+    /// Coordinator -> worker, the settings to render the tiles with.
+    Job(RenderSettings),
+    /// Coordinator -> worker, a tile to render.
+    Tile(Tile),
+    /// Worker -> coordinator, the filtered HDR samples of a tile.
+    TileResult(Tile, TileSamples),
+    /// Coordinator -> worker, no tile left.
+    Done,
+}
+
+impl Message {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Message::Hello { version } => {
+                writer.write_all(&[0])?;
+                writer.write_all(MAGIC)?;
+                writer.write_all(&[*version])?;
+            }
+            Message::Job(settings) => {
+                writer.write_all(&[1])?;
+                let scene = settings.scene.to_string();
+                write_u16(writer, scene.len() as u16)?;
+                writer.write_all(scene.as_bytes())?;
+                write_u16(writer, settings.image_width)?;
+                write_u16(writer, settings.image_height)?;
+                write_u16(writer, settings.samples_per_pixel)?;
+                write_u16(writer, settings.bounce_limit)?;
+                let integrator = settings.integrator.to_string();
+                write_u16(writer, integrator.len() as u16)?;
+                writer.write_all(integrator.as_bytes())?;
+                let sampler = settings.sampler.to_string();
+                write_u16(writer, sampler.len() as u16)?;
+                writer.write_all(sampler.as_bytes())?;
+                let filter = settings.filter.to_string();
+                write_u16(writer, filter.len() as u16)?;
+                writer.write_all(filter.as_bytes())?;
+                write_u64(writer, settings.seed)?;
+                match settings.fog {
+                    Some(fog) => {
+                        writer.write_all(&[1])?;
+                        write_f64(writer, fog.density)?;
+                        write_f64(writer, fog.falloff)?;
+                        write_f64(writer, fog.base_height)?;
+                        write_f64(writer, fog.albedo)?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+                write_u32(writer, settings.caustic_photons)?;
+                writer.write_all(&[settings.transparent_shadows as u8])?;
+                writer.write_all(&[settings.irradiance_cache as u8])?;
+            }
+            Message::Tile(tile) => {
+                writer.write_all(&[2])?;
+                write_tile(writer, tile)?;
+            }
+            Message::TileResult(tile, samples) => {
+                writer.write_all(&[3])?;
+                write_tile(writer, tile)?;
+                write_tile(writer, &samples.region)?;
+                let framebuffer = &samples.framebuffer;
+                for i in 0..framebuffer.len() {
+                    let sum = framebuffer.sum(i);
+                    write_f32(writer, sum.x())?;
+                    write_f32(writer, sum.y())?;
+                    write_f32(writer, sum.z())?;
+                    write_f32(writer, framebuffer.weight(i))?;
+                    write_u32(writer, framebuffer.sample_count(i))?;
+                }
+            }
+            Message::Done => writer.write_all(&[4])?,
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Message> {
+        Message::try_read_from(reader)?.context("Connection closed")
+    }
+
+    /// Reads the next message, None if the stream was closed in between two messages.
+    pub fn try_read_from<R: Read>(reader: &mut R) -> Result<Option<Message>> {
+        let mut tag = [0u8; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+
+        let message = match tag[0] {
+            0 => {
+                let mut magic = [0u8; 5];
+                reader.read_exact(&mut magic)?;
+                if &magic != MAGIC {
+                    bail!("Not a ray tracing worker");
+                }
+                let mut version = [0u8; 1];
+                reader.read_exact(&mut version)?;
+                Message::Hello {
+                    version: version[0],
+                }
+            }
+            1 => {
+                let mut scene = vec![0u8; read_u16(reader)? as usize];
+                reader.read_exact(&mut scene)?;
+                let scene: BuiltinScene = String::from_utf8(scene)?.parse()?;
+                let image_width = read_u16(reader)?;
+                let image_height = read_u16(reader)?;
+                let samples_per_pixel = read_u16(reader)?;
+                let bounce_limit = read_u16(reader)?;
+                let mut integrator = vec![0u8; read_u16(reader)? as usize];
+                reader.read_exact(&mut integrator)?;
+                let integrator: Integrator = String::from_utf8(integrator)?.parse()?;
+                let mut sampler = vec![0u8; read_u16(reader)? as usize];
+                reader.read_exact(&mut sampler)?;
+                let sampler: Sampler = String::from_utf8(sampler)?.parse()?;
+                let mut filter = vec![0u8; read_u16(reader)? as usize];
+                reader.read_exact(&mut filter)?;
+                let filter: Filter = String::from_utf8(filter)?.parse()?;
+                let seed = read_u64(reader)?;
+                let mut has_fog = [0u8; 1];
+                reader.read_exact(&mut has_fog)?;
+                let fog = match has_fog[0] {
+                    0 => None,
+                    _ => Some(HeightFog {
+                        density: read_f64(reader)?,
+                        falloff: read_f64(reader)?,
+                        base_height: read_f64(reader)?,
+                        albedo: read_f64(reader)?,
+                    }),
+                };
+                let caustic_photons = read_u32(reader)?;
+                let mut transparent_shadows = [0u8; 1];
+                reader.read_exact(&mut transparent_shadows)?;
+                let mut irradiance_cache = [0u8; 1];
+                reader.read_exact(&mut irradiance_cache)?;
+                Message::Job(RenderSettings {
+                    scene,
+                    image_width,
+                    image_height,
+                    samples_per_pixel,
+                    bounce_limit,
+                    integrator,
+                    sampler,
+                    filter,
+                    seed,
+                    fog,
+                    caustic_photons,
+                    transparent_shadows: transparent_shadows[0] != 0,
+                    irradiance_cache: irradiance_cache[0] != 0,
+                    ..RenderSettings::default()
+                })
+            }
+            2 => Message::Tile(read_tile(reader)?),
+            3 => {
+                let tile = read_tile(reader)?;
+                let region = read_tile(reader)?;
+                // A tile and the margin of its filter, a few pixels wide
+                if region.width > 2 * TILE_SIZE || region.height > 2 * TILE_SIZE {
+                    bail!(
+                        "Tile of {}x{} pixels too large",
+                        region.width,
+                        region.height
+                    );
+                }
+                let mut framebuffer =
+                    Framebuffer::new(region.width as usize, region.height as usize);
+                for i in 0..framebuffer.len() {
+                    let r = read_f32(reader)?;
+                    let g = read_f32(reader)?;
+                    let b = read_f32(reader)?;
+                    let weight = read_f32(reader)?;
+                    let sample_count = read_u32(reader)?;
+                    framebuffer.merge(i, Color::new(r, g, b), weight, sample_count);
+                }
+                Message::TileResult(
+                    tile,
+                    TileSamples {
+                        region,
+                        framebuffer,
+                    },
+                )
+            }
+            4 => Message::Done,
+            tag => bail!("Unknown message tag {}", tag),
+        };
+        Ok(Some(message))
+    }
+}
+
+fn write_u16<W: Write>(writer: &mut W, v: u16) -> Result<()> {
+    Ok(writer.write_all(&v.to_le_bytes())?)
+}
+
+fn write_u32<W: Write>(writer: &mut W, v: u32) -> Result<()> {
+    Ok(writer.write_all(&v.to_le_bytes())?)
+}
+
+fn write_u64<W: Write>(writer: &mut W, v: u64) -> Result<()> {
+    Ok(writer.write_all(&v.to_le_bytes())?)
+}
+
+// Pixels always travel as f32, whatever the precision of `Float`
+#[allow(clippy::unnecessary_cast)]
+fn write_f32<W: Write>(writer: &mut W, v: Float) -> Result<()> {
+    Ok(writer.write_all(&(v as f32).to_le_bytes())?)
+}
+
+// Settings travel as f64, so that workers render with the exact values of the coordinator
+#[allow(clippy::unnecessary_cast)]
+fn write_f64<W: Write>(writer: &mut W, v: Float) -> Result<()> {
+    Ok(writer.write_all(&(v as f64).to_le_bytes())?)
+}
+
+fn write_tile<W: Write>(writer: &mut W, tile: &Tile) -> Result<()> {
+    write_u16(writer, tile.x)?;
+    write_u16(writer, tile.y)?;
+    write_u16(writer, tile.width)?;
+    write_u16(writer, tile.height)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn read_f32<R: Read>(reader: &mut R) -> Result<Float> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes) as Float)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn read_f64<R: Read>(reader: &mut R) -> Result<Float> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes) as Float)
+}
+
+fn read_tile<R: Read>(reader: &mut R) -> Result<Tile> {
+    Ok(Tile {
+        x: read_u16(reader)?,
+        y: read_u16(reader)?,
+        width: read_u16(reader)?,
+        height: read_u16(reader)?,
+    })
+}
+
+struct TileQueue {
+    pending: VecDeque<Tile>,
+    /// Tiles not yet returned by a worker, pending or in progress.
+    remaining: usize,
+}
+
+/// Listens on `address` and hands out `tiles` to the workers connecting to it, calling
+/// `on_tile` with each rendered tile. Stops early when `on_tile` returns false or `interrupted`
+/// is set, even while no worker is connected.
+///
+/// Tiles of a worker which disconnects are handed out again to the other workers.
+pub fn run_coordinator<A, F>(
+    address: A,
+    settings: &RenderSettings,
+    tiles: Vec<Tile>,
+    interrupted: &AtomicBool,
+    on_tile: F,
+) -> Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut(Tile, &TileSamples) -> bool,
+{
+    let listener = TcpListener::bind(address).context("Failed to listen for workers")?;
+    coordinate(listener, settings, tiles, interrupted, on_tile)
+}
+
+/// Same as `run_coordinator`, with the workers connecting to `listener`.
+fn coordinate<F>(
+    listener: TcpListener,
+    settings: &RenderSettings,
+    tiles: Vec<Tile>,
+    interrupted: &AtomicBool,
+    mut on_tile: F,
+) -> Result<()>
+where
+    F: FnMut(Tile, &TileSamples) -> bool,
+{
+    let tile_count = tiles.len();
+    let queue = Arc::new(Mutex::new(TileQueue {
+        remaining: tile_count,
+        pending: tiles.into(),
+    }));
+    let (sender, receiver) = mpsc::channel();
+    let settings = *settings;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept worker: {}", e);
+                    continue;
+                }
+            };
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                if let Err(e) = serve_worker(stream, &settings, &queue, &sender) {
+                    eprintln!("Worker {} failed: {:#}", peer, e);
+                }
+            });
+        }
+    });
+
+    let mut received = 0;
+    while received < tile_count && !interrupted.load(Ordering::SeqCst) {
+        let (tile, samples) = match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        received += 1;
+        if !on_tile(tile, &samples) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_worker(
+    stream: TcpStream,
+    settings: &RenderSettings,
+    queue: &Mutex<TileQueue>,
+    sender: &Sender<(Tile, TileSamples)>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    match Message::read_from(&mut reader)? {
+        Message::Hello { version } if version == PROTOCOL_VERSION => {}
+        Message::Hello { version } => bail!("Unsupported protocol version {}", version),
+        message => bail!("Unexpected message {:?}", message),
+    }
+    Message::Job(*settings).write_to(&mut writer)?;
+
+    loop {
+        let tile = {
+            let mut queue = queue.lock().unwrap();
+            match queue.pending.pop_front() {
+                Some(tile) => tile,
+                None if queue.remaining == 0 => break,
+                None => {
+                    // Other workers may still fail and give back their tile
+                    drop(queue);
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            }
+        };
+
+        let result = Message::Tile(tile)
+            .write_to(&mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .and_then(|_| Message::read_from(&mut reader));
+        match result {
+            Ok(Message::TileResult(result_tile, samples))
+                if result_tile == tile && samples.region == region(settings, tile) =>
+            {
+                queue.lock().unwrap().remaining -= 1;
+                // The coordinator may have stopped listening, nothing to do about it
+                let _ = sender.send((tile, samples));
+            }
+            result => {
+                queue.lock().unwrap().pending.push_back(tile);
+                match result {
+                    Ok(message) => bail!("Unexpected message {:?}", message),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    Message::Done.write_to(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Pixels the samples of `tile` are splatted to.
+fn region(settings: &RenderSettings, tile: Tile) -> Tile {
+    let margin = settings.filter.margin();
+    tile.with_margin(margin, settings.image_width, settings.image_height)
+}
+
+/// Connects to the coordinator at `address` and renders the tiles it hands out with
+/// `render_tile`, until there are none left.
+pub fn run_worker<A, F>(address: A, mut render_tile: F) -> Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut(&RenderSettings, Tile) -> TileSamples,
+{
+    let stream = TcpStream::connect(address).context("Failed to connect to the coordinator")?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+    }
+    .write_to(&mut writer)?;
+    writer.flush()?;
+
+    let settings = match Message::read_from(&mut reader)? {
+        Message::Job(settings) => settings,
+        message => bail!("Unexpected message {:?}", message),
+    };
+
+    loop {
+        // The coordinator may exit without saying goodbye once it has all its tiles
+        match Message::try_read_from(&mut reader)?.unwrap_or(Message::Done) {
+            Message::Tile(tile) => {
+                let samples = render_tile(&settings, tile);
+                Message::TileResult(tile, samples).write_to(&mut writer)?;
+                writer.flush()?;
+            }
+            Message::Done => return Ok(()),
+            message => bail!("Unexpected message {:?}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_into_tiles() {
+        let tiles = split_into_tiles(70, 40, 32);
+        assert_eq!(tiles.len(), 6);
+        let area: usize = tiles
+            .iter()
+            .map(|t| t.width as usize * t.height as usize)
+            .sum();
+        assert_eq!(area, 70 * 40);
+        assert_eq!(
+            tiles[5],
+            Tile {
+                x: 64,
+                y: 32,
+                width: 6,
+                height: 8
+            }
+        );
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let tile = Tile {
+            x: 1,
+            y: 2,
+            width: 2,
+            height: 1,
+        };
+        let mut samples = TileSamples::new(tile, &Filter::Tent, 8, 4);
+        samples.splat(1.5, 2.5, Color::new(1.0, 2.0, 3.0), &Filter::Tent);
+        samples.splat(3.0, 2.5, Color::new(4.0, 5.0, 6.0), &Filter::Tent);
+        let messages = vec![
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+            },
+            Message::Job(RenderSettings {
+                scene: BuiltinScene::CornellBox,
+                samples_per_pixel: 16,
+                sampler: Sampler::Sobol,
+                filter: Filter::Mitchell,
+                seed: 0x1234_5678_9abc_def0,
+                fog: Some(HeightFog::new(0.25, 0.5)),
+                caustic_photons: 100_000,
+                transparent_shadows: true,
+                irradiance_cache: true,
+                ..RenderSettings::default()
+            }),
+            Message::Tile(tile),
+            Message::TileResult(tile, samples),
+            Message::Done,
+        ];
+
+        let mut bytes = Vec::new();
+        for message in &messages {
+            message.write_to(&mut bytes).unwrap();
+        }
+        let mut reader = &bytes[..];
+        for message in &messages {
+            assert_eq!(&Message::read_from(&mut reader).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_oversized_tile_result() {
+        let tile = Tile {
+            x: 0,
+            y: 0,
+            width: u16::MAX,
+            height: u16::MAX,
+        };
+        let mut bytes = vec![3];
+        write_tile(&mut bytes, &tile).unwrap();
+        write_tile(&mut bytes, &tile).unwrap();
+        assert!(Message::read_from(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_coordinator_and_worker() {
+        let settings = RenderSettings {
+            image_width: 8,
+            image_height: 4,
+            ..RenderSettings::default()
+        };
+        let tiles = split_into_tiles(8, 4, 4);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let worker = thread::spawn(move || {
+            run_worker(address, |settings, tile| {
+                let (width, height) = (settings.image_width, settings.image_height);
+                let mut samples = TileSamples::new(tile, &settings.filter, width, height);
+                let v = settings.image_width as Float + tile.x as Float;
+                for i in 0..samples.framebuffer.len() {
+                    samples
+                        .framebuffer
+                        .merge(i, Color::new(v, 0.0, 0.0), 1.0, 1);
+                }
+                samples
+            })
+        });
+
+        let mut received = Vec::new();
+        coordinate(
+            listener,
+            &settings,
+            tiles,
+            &AtomicBool::new(false),
+            |tile, samples| {
+                received.push((tile.x, samples.framebuffer.pixel(0).x()));
+                true
+            },
+        )
+        .unwrap();
+        worker.join().unwrap().unwrap();
+
+        received.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(received, vec![(0, 8.0), (4, 12.0)]);
+    }
+
+    #[test]
+    fn test_interrupted_coordinator() {
+        let settings = RenderSettings::default();
+        let tiles = split_into_tiles(8, 4, 4);
+        let interrupted = AtomicBool::new(true);
+        run_coordinator("127.0.0.1:0", &settings, tiles, &interrupted, |_, _| {
+            panic!("No worker connected")
+        })
+        .unwrap();
+    }
+}
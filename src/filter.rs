@@ -0,0 +1,138 @@
+use crate::float::Float;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Pixel reconstruction filter, weighting the samples by their offset from the pixel centers.
+///
+/// All the filters are separable, the product of a 1D filter along each axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Samples only count for the pixel they are in.
+    Box,
+    /// Linear falloff over one pixel.
+    Tent,
+    /// Truncated Gaussian, softer than the tent.
+    Gaussian,
+    /// Mitchell-Netravali cubic with B = C = 1/3, sharper thanks to its small negative lobes.
+    Mitchell,
+}
+
+impl Filter {
+    /// Offset from the pixel center beyond which samples get no weight, in pixels.
+    pub fn radius(&self) -> Float {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    /// Pixels around the one a sample lies in which it may be splatted to, on each side.
+    pub fn margin(&self) -> u16 {
+        (self.radius() - 0.5).ceil() as u16
+    }
+
+    /// Weight of a sample at offset (`dx`, `dy`) from the pixel center.
+    pub fn eval(&self, dx: Float, dy: Float) -> Float {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    fn eval_1d(&self, x: Float) -> Float {
+        match *self {
+            // Half-open, so that samples on an edge only count for one pixel
+            Filter::Box => {
+                if x > -0.5 && x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent => (1.0 - x.abs()).max(0.0),
+            Filter::Gaussian => {
+                const ALPHA: Float = 2.0;
+                let r = self.radius();
+                ((-ALPHA * x * x).exp() - (-ALPHA * r * r).exp()).max(0.0)
+            }
+            Filter::Mitchell => mitchell_netravali(x.abs(), 1.0 / 3.0, 1.0 / 3.0),
+        }
+    }
+}
+
+fn mitchell_netravali(x: Float, b: Float, c: Float) -> Float {
+    let x2 = x * x;
+    let x3 = x2 * x;
+    let value = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)
+    } else if x < 2.0 {
+        (-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    value / 6.0
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Filter::Box => "box",
+            Filter::Tent => "tent",
+            Filter::Gaussian => "gaussian",
+            Filter::Mitchell => "mitchell",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Filter> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            "mitchell" => Ok(Filter::Mitchell),
+            _ => bail!(
+                "Unknown filter '{}', expected one of: box, tent, gaussian, mitchell",
+                s
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_vanish_at_radius() {
+        for &filter in &[
+            Filter::Box,
+            Filter::Tent,
+            Filter::Gaussian,
+            Filter::Mitchell,
+        ] {
+            assert!(filter.eval(0.0, 0.0) > 0.0);
+            let r = filter.radius();
+            assert!(filter.eval(r + 1e-3, 0.0).abs() < 1e-6);
+            assert!(filter.eval(0.0, -r - 1e-3).abs() < 1e-6);
+            assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+        }
+    }
+
+    #[test]
+    fn test_mitchell_partition_of_unity() {
+        // The weights of a sample over a row of pixels sum to 1
+        for &x in &[0.0, 0.3, 0.5] {
+            let sum: Float = (-3..=3)
+                .map(|i| Filter::Mitchell.eval_1d(i as Float - x))
+                .sum();
+            assert!((sum - 1.0).abs() < 1e-5, "sum = {}", sum);
+        }
+    }
+}
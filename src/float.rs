@@ -0,0 +1,26 @@
+//! Scalar type used by the math types and all the geometry and radiance computations.
+//!
+//! Single precision by default; the `f64` feature switches to double precision, which avoids
+//! the self-intersection artifacts that show up with large scenes and thin glass.
+
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts::PI;
+
+#[cfg(feature = "f64")]
+pub type Float = f64;
+#[cfg(feature = "f64")]
+pub use std::f64::consts::PI;
+
+/// `x` moved by `ulps` units in the last place, away from zero for positive `ulps`.
+#[cfg(not(feature = "f64"))]
+pub fn add_ulps(x: Float, ulps: i32) -> Float {
+    Float::from_bits((x.to_bits() as i32).wrapping_add(ulps) as u32)
+}
+
+/// `x` moved by `ulps` units in the last place, away from zero for positive `ulps`.
+#[cfg(feature = "f64")]
+pub fn add_ulps(x: Float, ulps: i32) -> Float {
+    Float::from_bits((x.to_bits() as i64).wrapping_add(ulps as i64) as u64)
+}
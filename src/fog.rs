@@ -0,0 +1,130 @@
+use crate::float::Float;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use rand::Rng;
+
+/// Fraction of the light intercepted by the fog which is scattered rather than absorbed.
+pub const DEFAULT_FOG_ALBEDO: Float = 0.9;
+
+/// Fog filling the whole scene, thinning out exponentially with height, which fades distant
+/// objects into the light it scatters. Looking down, it thickens without bound.
+///
+/// The fog is gray, it attenuates all the wavelengths the same way, and scatters light evenly
+/// in all directions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeightFog {
+    /// Extinction coefficient at `base_height`, per unit of length of the scene.
+    pub density: Float,
+    /// Inverse of the height over which the density is divided by e, 0 for a uniform fog.
+    pub falloff: Float,
+    pub base_height: Float,
+    pub albedo: Float,
+}
+
+impl HeightFog {
+    pub fn new(density: Float, falloff: Float) -> HeightFog {
+        HeightFog {
+            density,
+            falloff,
+            base_height: 0.0,
+            albedo: DEFAULT_FOG_ALBEDO,
+        }
+    }
+
+    /// Fraction of the light going through the fog along `ray`, from its origin to `t`.
+    pub fn transmittance(&self, ray: &Ray, t: Float) -> Float {
+        (-self.optical_depth(ray, t)).exp()
+    }
+
+    /// Integral of the density along `ray`, from its origin to `t`.
+    pub fn optical_depth(&self, ray: &Ray, t: Float) -> Float {
+        let (density, slope, length) = self.along(ray);
+        let distance = t * length;
+        if slope == 0.0 {
+            return density * distance;
+        }
+        -density * (-slope * distance).exp_m1() / slope
+    }
+
+    /// Parameter along `ray`, before `t_max`, where it's scattered or absorbed by the fog,
+    /// sampled proportionally to the density times the transmittance. None if it goes through,
+    /// which happens with a probability equal to the transmittance.
+    pub fn sample_interaction(
+        &self,
+        ray: &Ray,
+        t_max: Float,
+        rng: &mut SampleRng,
+    ) -> Option<Float> {
+        let target = -(1.0 - rng.gen::<Float>()).ln();
+        if target >= self.optical_depth(ray, t_max) {
+            return None;
+        }
+
+        // Inverse of the optical depth
+        let (density, slope, length) = self.along(ray);
+        let distance = if slope == 0.0 {
+            target / density
+        } else {
+            -(-target * slope / density).ln_1p() / slope
+        };
+        Some(distance / length)
+    }
+
+    /// Density at the origin of `ray`, rate of decrease of the density per unit of distance
+    /// along it, and length of its direction.
+    fn along(&self, ray: &Ray) -> (Float, Float, Float) {
+        let length = ray.direction().length();
+        let height = ray.origin().y() - self.base_height;
+        let density = self.density * (-self.falloff * height).exp();
+        let slope = self.falloff * ray.direction().y() / length;
+        (density, slope, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::{Point3, Vec3};
+
+    #[test]
+    fn test_optical_depth() {
+        let fog = HeightFog::new(0.5, 1.0);
+        let e = (1.0 as Float).exp();
+
+        // Horizontal rays see a constant density
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        assert!((fog.optical_depth(&ray, 3.0) - 0.5 / e * 6.0).abs() < 1e-5);
+
+        // Looking up, the fog ends, looking down it doesn't
+        let up = Ray::new(Point3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        assert!((fog.optical_depth(&up, 1.0) - 0.5 * (1.0 - 1.0 / e)).abs() < 1e-5);
+        assert!((fog.optical_depth(&up, Float::INFINITY) - 0.5).abs() < 1e-5);
+        let down = Ray::new(Point3::zero(), Vec3::new(1.0, -1.0, 0.0));
+        assert_eq!(fog.transmittance(&down, Float::INFINITY), 0.0);
+    }
+
+    #[test]
+    fn test_sample_interaction() {
+        let mut rng = SampleRng::new(0);
+        let fog = HeightFog::new(0.3, 0.5);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.5, 0.0));
+        let t_max = 4.0;
+
+        // Rays get through as often as the light does, and interact before the middle of the
+        // segment as often as the light doesn't reach it
+        let t_middle = t_max / 2.0;
+        let sample_count = 100_000;
+        let (mut through, mut before_middle) = (0, 0);
+        for _ in 0..sample_count {
+            match fog.sample_interaction(&ray, t_max, &mut rng) {
+                Some(t) if t < t_middle => before_middle += 1,
+                Some(t) => assert!(t < t_max),
+                None => through += 1,
+            }
+        }
+        let fraction = |count: i32| count as Float / sample_count as Float;
+        let reaching_middle = fog.transmittance(&ray, t_middle);
+        assert!((fraction(through) - fog.transmittance(&ray, t_max)).abs() < 0.01);
+        assert!((fraction(before_middle) - (1.0 - reaching_middle)).abs() < 0.01);
+    }
+}
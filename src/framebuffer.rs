@@ -0,0 +1,241 @@
+use crate::distributed::Tile;
+use crate::filter::Filter;
+use crate::float::Float;
+use crate::vec3::Color;
+
+/// Render target accumulating the radiance samples of each pixel, in row-major order from the
+/// top row.
+///
+/// Pixels keep the weighted sum of the samples around them with the sum of their weights, and
+/// the number of samples taken inside of them, so that renders can be resumed, refined
+/// progressively or merged from several sources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    sums: Vec<Color>,
+    weights: Vec<Float>,
+    sample_counts: Vec<u32>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            sums: vec![Color::zero(); width * height],
+            weights: vec![0.0; width * height],
+            sample_counts: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn len(&self) -> usize {
+        self.sums.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sums.is_empty()
+    }
+
+    /// Index of the pixel in column `x` and row `y`, counted from the top.
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    /// Adds a sample to the pixel only, as with a box filter.
+    pub fn add_sample(&mut self, index: usize, color: Color) {
+        self.sums[index] += color;
+        self.weights[index] += 1.0;
+        self.sample_counts[index] += 1;
+    }
+
+    /// Adds a sample taken at (`x`, `y`), in pixels from the top left corner of the image, to
+    /// all the pixels within the radius of the `filter`. It counts as a sample of the pixel
+    /// it lies in.
+    pub fn splat(&mut self, x: Float, y: Float, color: Color, filter: &Filter) {
+        let col = (x.floor() as usize).min(self.width - 1);
+        let row = (y.floor() as usize).min(self.height - 1);
+        self.sample_counts[row * self.width + col] += 1;
+
+        // Pixels whose center is within the radius
+        let radius = filter.radius();
+        let min_col = (x - 0.5 - radius).ceil().max(0.0) as usize;
+        let max_col = ((x - 0.5 + radius).floor() as usize).min(self.width - 1);
+        let min_row = (y - 0.5 - radius).ceil().max(0.0) as usize;
+        let max_row = ((y - 0.5 + radius).floor() as usize).min(self.height - 1);
+        for pixel_row in min_row..=max_row {
+            for pixel_col in min_col..=max_col {
+                let dx = pixel_col as Float + 0.5 - x;
+                let dy = pixel_row as Float + 0.5 - y;
+                let weight = filter.eval(dx, dy);
+                if weight != 0.0 {
+                    let index = pixel_row * self.width + pixel_col;
+                    self.sums[index] += weight * color;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+    }
+
+    /// Accumulates `sample_count` samples whose weights sum to `weight` and weighted values to
+    /// `sum`, e.g. from another framebuffer.
+    pub fn merge(&mut self, index: usize, sum: Color, weight: Float, sample_count: u32) {
+        self.sums[index] += sum;
+        self.weights[index] += weight;
+        self.sample_counts[index] += sample_count;
+    }
+
+    /// Replaces the samples of the pixel by `sample_count` samples averaging to `color`.
+    pub fn set_pixel(&mut self, index: usize, color: Color, sample_count: u32) {
+        self.sums[index] = sample_count as Float * color;
+        self.weights[index] = sample_count as Float;
+        self.sample_counts[index] = sample_count;
+    }
+
+    pub fn sum(&self, index: usize) -> Color {
+        self.sums[index]
+    }
+
+    pub fn weight(&self, index: usize) -> Float {
+        self.weights[index]
+    }
+
+    pub fn sample_count(&self, index: usize) -> u32 {
+        self.sample_counts[index]
+    }
+
+    /// Weighted average of the samples of the pixel, black if it has none.
+    pub fn pixel(&self, index: usize) -> Color {
+        let weight = self.weights[index];
+        if weight <= 0.0 {
+            return Color::zero();
+        }
+        self.sums[index] / weight
+    }
+
+    /// Averages of all the pixels, each normalized by the weights it actually received.
+    pub fn pixels(&self) -> Vec<Color> {
+        (0..self.len()).map(|index| self.pixel(index)).collect()
+    }
+}
+
+/// Samples of the pixels of a tile, splatted to the pixels of its `region`: the tile and the
+/// pixels around it within the margin of the filter, which the samples of the neighbouring
+/// tiles are also splatted to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileSamples {
+    pub region: Tile,
+    pub framebuffer: Framebuffer,
+}
+
+impl TileSamples {
+    /// No samples yet of the pixels of `tile`, in an image of `image_width` by `image_height`
+    /// pixels.
+    pub fn new(tile: Tile, filter: &Filter, image_width: u16, image_height: u16) -> TileSamples {
+        let region = tile.with_margin(filter.margin(), image_width, image_height);
+        TileSamples {
+            region,
+            framebuffer: Framebuffer::new(region.width as usize, region.height as usize),
+        }
+    }
+
+    /// Adds a sample taken at (`x`, `y`), in pixels from the top left corner of the image, as
+    /// with `Framebuffer::splat`.
+    pub fn splat(&mut self, x: Float, y: Float, color: Color, filter: &Filter) {
+        let x = x - self.region.x as Float;
+        let y = y - self.region.y as Float;
+        self.framebuffer.splat(x, y, color, filter);
+    }
+
+    /// Accumulates the samples into the framebuffer of the whole image.
+    pub fn merge_into(&self, image: &mut Framebuffer) {
+        let indices = self.region.pixel_indices(image.width() as u16);
+        for (i, index) in indices.enumerate() {
+            image.merge(
+                index,
+                self.framebuffer.sum(i),
+                self.framebuffer.weight(i),
+                self.framebuffer.sample_count(i),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulation() {
+        let mut framebuffer = Framebuffer::new(2, 1);
+        framebuffer.add_sample(0, Color::new(1.0, 0.0, 0.0));
+        framebuffer.add_sample(0, Color::new(0.0, 1.0, 0.0));
+        framebuffer.merge(0, Color::new(0.0, 0.0, 2.0), 2.0, 2);
+        assert_eq!(framebuffer.sample_count(0), 4);
+        assert_eq!(framebuffer.pixel(0), Color::new(0.25, 0.25, 0.5));
+        // Pixels without samples are black
+        assert_eq!(framebuffer.pixel(1), Color::zero());
+
+        framebuffer.set_pixel(0, Color::new(1.0, 1.0, 1.0), 3);
+        assert_eq!(framebuffer.sum(0), Color::new(3.0, 3.0, 3.0));
+        assert_eq!(framebuffer.sample_count(0), 3);
+    }
+
+    #[test]
+    fn test_splat() {
+        let mut framebuffer = Framebuffer::new(3, 3);
+        let color = Color::new(1.0, 1.0, 1.0);
+        // A box filtered sample only reaches its own pixel
+        framebuffer.splat(1.2, 1.7, color, &Filter::Box);
+        assert_eq!(framebuffer.weight(4), 1.0);
+        assert_eq!((0..9).map(|i| framebuffer.weight(i)).sum::<Float>(), 1.0);
+
+        // A tent filtered sample on a pixel corner is shared by the four pixels around it
+        let mut framebuffer = Framebuffer::new(3, 3);
+        framebuffer.splat(1.0, 2.0, color, &Filter::Tent);
+        assert_eq!(framebuffer.sample_count(framebuffer.index(1, 2)), 1);
+        for &(x, y) in &[(0, 1), (1, 1), (0, 2), (1, 2)] {
+            assert_eq!(framebuffer.weight(framebuffer.index(x, y)), 0.25);
+            assert_eq!(framebuffer.pixel(framebuffer.index(x, y)), color);
+        }
+        assert_eq!(framebuffer.weight(framebuffer.index(2, 2)), 0.0);
+    }
+
+    #[test]
+    fn test_tile_samples() {
+        let tile = Tile {
+            x: 2,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let mut samples = TileSamples::new(tile, &Filter::Tent, 6, 2);
+        assert_eq!(
+            samples.region,
+            Tile {
+                x: 1,
+                y: 0,
+                width: 4,
+                height: 2
+            }
+        );
+
+        // Shared with the pixel of the tile on the left
+        let color = Color::new(1.0, 1.0, 1.0);
+        samples.splat(2.0, 1.5, color, &Filter::Tent);
+        let mut image = Framebuffer::new(6, 2);
+        samples.merge_into(&mut image);
+        assert_eq!(image.weight(image.index(1, 1)), 0.5);
+        assert_eq!(image.weight(image.index(2, 1)), 0.5);
+        assert_eq!(image.sample_count(image.index(1, 1)), 0);
+        assert_eq!(image.sample_count(image.index(2, 1)), 1);
+    }
+}
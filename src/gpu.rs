@@ -0,0 +1,408 @@
+//! Path tracing on the GPU with a wgpu compute shader, for scenes made of spheres.
+//!
+//! Only the subset of the renderer the shader implements is supported: spheres, Lambertian,
+//! metal, plain dielectric and emissive materials, a gradient or solid color background and
+//! a perspective camera with a circular aperture. Samples are placed randomly in the pixels
+//! and box filtered. Anything else makes `render` fail, so that the caller can fall back to
+//! the CPU.
+
+use crate::background::{Gradient, SolidColor};
+use crate::camera::{ApertureShape, Projection};
+use crate::float::Float;
+use crate::framebuffer::Framebuffer;
+use crate::integrator::Integrator;
+use crate::material::{BackFaces, Material};
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::sphere::Sphere;
+use crate::sphere_set::SphereSet;
+use crate::vec3::{Color, Vec3};
+use anyhow::{bail, Context, Result};
+use bytemuck::{Pod, Zeroable};
+use std::any::Any;
+use std::sync::mpsc;
+
+const SHADER: &str = include_str!("gpu.wgsl");
+
+/// Samples per pixel added by each dispatch, small enough for a dispatch not to run into the
+/// watchdog of the driver.
+const SAMPLES_PER_PASS: u32 = 4;
+const WORKGROUP_SIZE: u32 = 8;
+
+const LAMBERTIAN: u32 = 0;
+const METAL: u32 = 1;
+const DIELECTRIC: u32 = 2;
+const DIFFUSE_LIGHT: u32 = 3;
+
+/// Layout of `Params` in the shader.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct GpuParams {
+    origin: [f32; 3],
+    lens_radius: f32,
+    lower_left_corner: [f32; 3],
+    width: u32,
+    horizontal: [f32; 3],
+    height: u32,
+    vertical: [f32; 3],
+    samples_per_pass: u32,
+    u: [f32; 3],
+    bounce_limit: u32,
+    v: [f32; 3],
+    sphere_count: u32,
+    background_bottom: [f32; 3],
+    pass_index: u32,
+    background_top: [f32; 3],
+    _padding: u32,
+}
+
+/// Layout of `Sphere` in the shader, whose structures are aligned on 16 bytes.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct GpuSphere {
+    center: [f32; 3],
+    radius: f32,
+    material: u32,
+    _padding: [u32; 3],
+}
+
+/// Layout of `Material` in the shader.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct GpuMaterial {
+    albedo: [f32; 3],
+    kind: u32,
+    parameter: f32,
+    _padding: [u32; 3],
+}
+
+/// Renders all the samples of the image on the first GPU found.
+///
+/// Fails if there is no usable GPU or if the scene uses features the shader doesn't support.
+pub fn render(scene: &Scene, settings: &RenderSettings) -> Result<Framebuffer> {
+    if settings.integrator != Integrator::PathTracer {
+        bail!("The GPU only supports the path integrator");
+    }
+    if settings.fog.is_some() {
+        bail!("The GPU doesn't support fog");
+    }
+    if scene.caustics.is_some() {
+        bail!("The GPU doesn't support photon mapping");
+    }
+    if scene.irradiance.is_some() {
+        bail!("The GPU doesn't support the irradiance cache");
+    }
+    if settings.transparent_shadows {
+        bail!("The GPU doesn't support transparent shadows");
+    }
+    if !scene.delta_lights.is_empty() {
+        bail!("The GPU doesn't support point, directional and spot lights");
+    }
+    let spheres = gpu_spheres(scene)?;
+    let materials = scene
+        .materials
+        .iter()
+        .map(gpu_material)
+        .collect::<Result<Vec<_>>>()?;
+    let params = gpu_params(scene, settings, spheres.len() as u32)?;
+
+    pollster::block_on(render_on_device(params, &spheres, &materials, settings))
+}
+
+fn gpu_spheres(scene: &Scene) -> Result<Vec<GpuSphere>> {
+    let mut spheres = Vec::with_capacity(scene.world.len());
+    for object in scene.world.iter() {
+        let object: &dyn Any = object;
+        if let Some(sphere) = object.downcast_ref::<Sphere>() {
+            spheres.push(gpu_sphere(sphere)?);
+        } else if let Some(set) = object.downcast_ref::<SphereSet>() {
+            for sphere in set.spheres() {
+                spheres.push(gpu_sphere(&sphere)?);
+            }
+        } else {
+            bail!("The GPU only supports spheres");
+        }
+    }
+    Ok(spheres)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn gpu_sphere(sphere: &Sphere) -> Result<GpuSphere> {
+    if sphere.material().back_faces() != BackFaces::Shaded {
+        bail!("The GPU doesn't support black or culled back faces");
+    }
+    Ok(GpuSphere {
+        center: to_array(sphere.center()),
+        radius: sphere.radius() as f32,
+        material: sphere.material().index() as u32,
+        _padding: [0; 3],
+    })
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn gpu_material(material: &Material) -> Result<GpuMaterial> {
+    let (kind, parameter) = match *material {
+        Material::Lambertian(ref inner) if inner.albedo_texture.is_none() => (LAMBERTIAN, 0.0),
+        Material::Metal(ref inner) if inner.fuzz_texture.is_none() => (METAL, inner.fuzz),
+        Material::Dielectric(ref inner)
+            if inner.absorption == Color::zero()
+                && inner.distribution.is_none()
+                && inner.channel_refraction_indices.is_none()
+                && inner.refraction_index_texture.is_none() =>
+        {
+            (DIELECTRIC, inner.refraction_index)
+        }
+        Material::DiffuseLight(ref inner) if inner.emission_texture.is_none() => {
+            (DIFFUSE_LIGHT, 0.0)
+        }
+        _ => bail!(
+            "The GPU only supports Lambertian, metal, plain dielectric and emissive materials, \
+             without textured parameters"
+        ),
+    };
+    Ok(GpuMaterial {
+        albedo: to_array(material.albedo()),
+        kind,
+        parameter: parameter as f32,
+        _padding: [0; 3],
+    })
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn gpu_params(scene: &Scene, settings: &RenderSettings, sphere_count: u32) -> Result<GpuParams> {
+    let camera = &scene.camera;
+    if camera.projection() != Projection::Perspective
+        || camera.aperture_shape != ApertureShape::Circular
+    {
+        bail!("The GPU only supports perspective cameras with a circular aperture");
+    }
+
+    let background: &dyn Any = &*scene.background;
+    let (bottom, top) = if let Some(gradient) = background.downcast_ref::<Gradient>() {
+        (gradient.bottom, gradient.top)
+    } else if let Some(solid) = background.downcast_ref::<SolidColor>() {
+        (solid.color, solid.color)
+    } else {
+        bail!("The GPU only supports gradient and solid color backgrounds");
+    };
+
+    Ok(GpuParams {
+        origin: to_array(camera.origin),
+        lens_radius: camera.lens_radius as f32,
+        lower_left_corner: to_array(camera.lower_left_corner),
+        width: settings.image_width as u32,
+        horizontal: to_array(camera.horizontal),
+        height: settings.image_height as u32,
+        vertical: to_array(camera.vertical),
+        samples_per_pass: SAMPLES_PER_PASS,
+        u: to_array(camera.u),
+        bounce_limit: settings.bounce_limit as u32,
+        v: to_array(camera.v),
+        sphere_count,
+        background_bottom: to_array(bottom),
+        pass_index: 0,
+        background_top: to_array(top),
+        _padding: 0,
+    })
+}
+
+#[allow(clippy::unnecessary_cast)]
+async fn render_on_device(
+    mut params: GpuParams,
+    spheres: &[GpuSphere],
+    materials: &[GpuMaterial],
+    settings: &RenderSettings,
+) -> Result<Framebuffer> {
+    use wgpu::util::DeviceExt;
+
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        })
+        .await
+        .context("No GPU adapter found")?;
+    // Emulated GPUs are slower than the CPU renderer
+    if adapter.get_info().device_type == wgpu::DeviceType::Cpu {
+        bail!("Only a software GPU adapter was found");
+    }
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor::default(), None)
+        .await
+        .context("Failed to open the GPU device")?;
+
+    // Storage buffers can't be empty
+    let dummy_sphere = [GpuSphere::zeroed()];
+    let spheres = if spheres.is_empty() {
+        &dummy_sphere[..]
+    } else {
+        spheres
+    };
+    let dummy_material = [GpuMaterial::zeroed()];
+    let materials = if materials.is_empty() {
+        &dummy_material[..]
+    } else {
+        materials
+    };
+
+    let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("params"),
+        size: std::mem::size_of::<GpuParams>() as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let sphere_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("spheres"),
+        contents: bytemuck::cast_slice(spheres),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let material_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("materials"),
+        contents: bytemuck::cast_slice(materials),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    // Buffers are zeroed on creation
+    let pixel_count = params.width as u64 * params.height as u64;
+    let accumulation_size = pixel_count * 4 * std::mem::size_of::<f32>() as u64;
+    let accumulation_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("accumulation"),
+        size: accumulation_size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback"),
+        size: accumulation_size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("path tracer"),
+        source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("path tracer"),
+        layout: None,
+        module: &module,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: sphere_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: material_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: accumulation_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    let samples_per_pixel = settings.samples_per_pixel as u32;
+    let pass_count = samples_per_pixel.div_ceil(SAMPLES_PER_PASS);
+    for pass_index in 0..pass_count {
+        params.pass_index = pass_index;
+        params.samples_per_pass =
+            SAMPLES_PER_PASS.min(samples_per_pixel - pass_index * SAMPLES_PER_PASS);
+        queue.write_buffer(&params_buffer, 0, bytemuck::bytes_of(&params));
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                params.width.div_ceil(WORKGROUP_SIZE),
+                params.height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+
+    let mut encoder = device.create_command_encoder(&Default::default());
+    encoder.copy_buffer_to_buffer(
+        &accumulation_buffer,
+        0,
+        &readback_buffer,
+        0,
+        accumulation_size,
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let slice = readback_buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    let _ = device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .context("The GPU device was lost")?
+        .context("Failed to read the image back from the GPU")?;
+
+    let data = slice.get_mapped_range();
+    let values: &[f32] = bytemuck::cast_slice(&data);
+    let mut framebuffer = Framebuffer::new(params.width as usize, params.height as usize);
+    for (index, pixel) in values.chunks_exact(4).enumerate() {
+        let sum = Color::new(pixel[0] as Float, pixel[1] as Float, pixel[2] as Float);
+        framebuffer.merge(index, sum, pixel[3] as Float, pixel[3] as u32);
+    }
+    Ok(framebuffer)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn to_array(v: Vec3) -> [f32; 3] {
+    [v.x() as f32, v.y() as f32, v.z() as f32]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wgpu::naga;
+
+    #[test]
+    fn test_shader_is_valid() {
+        let module = naga::front::wgsl::parse_str(SHADER).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_buffer_layouts() {
+        // The structures must have the size the shader gives them
+        let module = naga::front::wgsl::parse_str(SHADER).unwrap();
+        let mut layouter = naga::proc::Layouter::default();
+        layouter.update(module.to_ctx()).unwrap();
+        let shader_size = |name: &str| {
+            let (handle, _) = module
+                .types
+                .iter()
+                .find(|(_, ty)| ty.name.as_deref() == Some(name))
+                .unwrap();
+            layouter[handle].size as usize
+        };
+        assert_eq!(std::mem::size_of::<GpuParams>(), shader_size("Params"));
+        assert_eq!(std::mem::size_of::<GpuSphere>(), shader_size("Sphere"));
+        assert_eq!(std::mem::size_of::<GpuMaterial>(), shader_size("Material"));
+    }
+}
@@ -0,0 +1,219 @@
+// Path tracer of sphere scenes, one invocation per pixel. Each dispatch adds
+// `samples_per_pass` samples to the accumulated sums of the pixels.
+
+struct Params {
+    origin: vec3<f32>,
+    lens_radius: f32,
+    lower_left_corner: vec3<f32>,
+    width: u32,
+    horizontal: vec3<f32>,
+    height: u32,
+    vertical: vec3<f32>,
+    samples_per_pass: u32,
+    u: vec3<f32>,
+    bounce_limit: u32,
+    v: vec3<f32>,
+    sphere_count: u32,
+    background_bottom: vec3<f32>,
+    pass_index: u32,
+    background_top: vec3<f32>,
+    padding: u32,
+}
+
+struct Sphere {
+    center: vec3<f32>,
+    radius: f32,
+    material: u32,
+}
+
+struct Material {
+    albedo: vec3<f32>,
+    kind: u32,
+    // Fuzz of metals, refraction index of dielectrics
+    parameter: f32,
+}
+
+struct Hit {
+    point: vec3<f32>,
+    normal: vec3<f32>,
+    front_face: bool,
+    material: u32,
+}
+
+const LAMBERTIAN: u32 = 0u;
+const METAL: u32 = 1u;
+const DIELECTRIC: u32 = 2u;
+const DIFFUSE_LIGHT: u32 = 3u;
+
+const TAU: f32 = 6.28318530718;
+const T_MIN: f32 = 0.001;
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> spheres: array<Sphere>;
+@group(0) @binding(2) var<storage, read> materials: array<Material>;
+// Sum of the samples of each pixel, with their count in w
+@group(0) @binding(3) var<storage, read_write> accumulation: array<vec4<f32>>;
+
+var<private> rng_state: u32;
+
+// ------------
+//  RANDOMNESS
+// ------------
+
+fn pcg_hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Uniform in [0, 1), from the 24 most significant bits
+fn random_float() -> f32 {
+    rng_state = pcg_hash(rng_state);
+    return f32(rng_state >> 8u) / 16777216.0;
+}
+
+fn random_unit_vector() -> vec3<f32> {
+    let z = 2.0 * random_float() - 1.0;
+    let phi = TAU * random_float();
+    let r = sqrt(max(0.0, 1.0 - z * z));
+    return vec3<f32>(r * cos(phi), r * sin(phi), z);
+}
+
+fn random_in_unit_sphere() -> vec3<f32> {
+    return random_unit_vector() * pow(random_float(), 1.0 / 3.0);
+}
+
+fn random_in_unit_disk() -> vec2<f32> {
+    let r = sqrt(random_float());
+    let theta = TAU * random_float();
+    return vec2<f32>(r * cos(theta), r * sin(theta));
+}
+
+// -----------
+//  GEOMETRY
+// -----------
+
+fn hit_spheres(origin: vec3<f32>, direction: vec3<f32>, hit: ptr<function, Hit>) -> bool {
+    var closest_so_far = 3.4e38;
+    var hit_anything = false;
+    for (var i = 0u; i < params.sphere_count; i++) {
+        let sphere = spheres[i];
+        let origin_center = origin - sphere.center;
+        let a = dot(direction, direction);
+        let half_b = dot(origin_center, direction);
+        let c = dot(origin_center, origin_center) - sphere.radius * sphere.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            continue;
+        }
+
+        // Nearest root in the acceptable range
+        let sqrt_discriminant = sqrt(discriminant);
+        var root = (-half_b - sqrt_discriminant) / a;
+        if root < T_MIN || root > closest_so_far {
+            root = (-half_b + sqrt_discriminant) / a;
+            if root < T_MIN || root > closest_so_far {
+                continue;
+            }
+        }
+
+        closest_so_far = root;
+        hit_anything = true;
+        let point = origin + root * direction;
+        let outward_normal = (point - sphere.center) / sphere.radius;
+        let front_face = dot(direction, outward_normal) < 0.0;
+        (*hit).point = point;
+        (*hit).normal = select(-outward_normal, outward_normal, front_face);
+        (*hit).front_face = front_face;
+        (*hit).material = sphere.material;
+    }
+    return hit_anything;
+}
+
+// -----------
+//  MATERIALS
+// -----------
+
+// Schlick's approximation of the reflectance of dielectrics
+fn reflectance(cosine: f32, refraction_ratio: f32) -> f32 {
+    var r0 = (1.0 - refraction_ratio) / (1.0 + refraction_ratio);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow(1.0 - cosine, 5.0);
+}
+
+fn trace(ray_origin: vec3<f32>, ray_direction: vec3<f32>) -> vec3<f32> {
+    var origin = ray_origin;
+    var direction = ray_direction;
+    var color = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+
+    for (var bounce = 0u; bounce < params.bounce_limit; bounce++) {
+        var hit: Hit;
+        let unit_direction = normalize(direction);
+        if !hit_spheres(origin, direction, &hit) {
+            let t = 0.5 * (unit_direction.y + 1.0);
+            color += throughput * mix(params.background_bottom, params.background_top, t);
+            break;
+        }
+
+        let material = materials[hit.material];
+        origin = hit.point;
+        if material.kind == DIFFUSE_LIGHT {
+            if hit.front_face {
+                color += throughput * material.albedo;
+            }
+            break;
+        } else if material.kind == LAMBERTIAN {
+            var scattered = hit.normal + random_unit_vector();
+            // Catch degenerate scatter directions
+            if all(abs(scattered) < vec3<f32>(1e-8)) {
+                scattered = hit.normal;
+            }
+            direction = scattered;
+            throughput *= material.albedo;
+        } else if material.kind == METAL {
+            let reflected = reflect(unit_direction, hit.normal)
+                + material.parameter * random_in_unit_sphere();
+            if dot(reflected, hit.normal) <= 0.0 {
+                break;
+            }
+            direction = reflected;
+            throughput *= material.albedo;
+        } else if material.kind == DIELECTRIC {
+            let refraction_ratio = select(material.parameter, 1.0 / material.parameter, hit.front_face);
+            let cos_theta = min(dot(-unit_direction, hit.normal), 1.0);
+            let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+            let cannot_refract = refraction_ratio * sin_theta > 1.0;
+            if cannot_refract || reflectance(cos_theta, refraction_ratio) > random_float() {
+                direction = reflect(unit_direction, hit.normal);
+            } else {
+                direction = refract(unit_direction, hit.normal, refraction_ratio);
+            }
+        }
+    }
+    return color;
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.width || id.y >= params.height {
+        return;
+    }
+    // Rows of the framebuffer start from the top, those of the camera from the bottom
+    let index = id.y * params.width + id.x;
+    let row = params.height - 1u - id.y;
+    rng_state = pcg_hash(index ^ pcg_hash(params.pass_index + 0x9e3779b9u));
+
+    var sum = vec3<f32>(0.0);
+    for (var s = 0u; s < params.samples_per_pass; s++) {
+        let u = (f32(id.x) + random_float()) / f32(params.width - 1u);
+        let v = (f32(row) + 1.0 - random_float()) / f32(params.height - 1u);
+        let lens = params.lens_radius * random_in_unit_disk();
+        let offset = params.u * lens.x + params.v * lens.y;
+        let origin = params.origin + offset;
+        let direction = params.lower_left_corner + u * params.horizontal + v * params.vertical
+            - params.origin - offset;
+        sum += trace(origin, direction);
+    }
+    accumulation[index] += vec4<f32>(sum, f32(params.samples_per_pass));
+}
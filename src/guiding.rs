@@ -0,0 +1,568 @@
+//! Path guiding: the light arriving at the points of the scene is learnt while rendering, in
+//! an SD-tree, and the path tracer samples directions from it as often as from the BSDFs
+//! (Müller et al., "Practical Path Guiding for Efficient Light-Transport Simulation", 2017).
+//!
+//! The tree splits the bounds of the scene in halves, along alternating axes, and each of its
+//! leaves holds a quadtree over the directions, refined where the most light arrives from. The
+//! image is rendered in passes of doubling samples per pixel, each one sampling the tree learnt
+//! by the previous passes while recording into a new one.
+
+use crate::aabb::Aabb;
+use crate::distributed::{split_into_tiles, TILE_SIZE};
+use crate::float::{Float, PI};
+use crate::framebuffer::Framebuffer;
+use crate::parallel;
+use crate::pdf::Pdf;
+use crate::rng::SampleRng;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use anyhow::{bail, Result};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Fraction of the light arriving at a leaf of the SD-tree above which the directions it comes
+/// from are subdivided.
+const SUBDIVISION_THRESHOLD: f64 = 0.01;
+/// Depth of the finest cells of the directional quadtrees.
+const MAX_DEPTH: usize = 20;
+/// Samples recorded in a leaf of the SD-tree, per square root of the samples per pixel of the
+/// pass, above which it's split in two.
+const SPLIT_THRESHOLD: Float = 12_000.0;
+
+/// Renders the image built by `build_scene` with the path integrator guided by an SD-tree, on
+/// `threads` threads. `on_pass` is called with the samples per pixel of each pass once it's
+/// rendered, and stops the render when it returns false.
+///
+/// All the passes add up to the samples per pixel of the `settings`: the noisier first passes
+/// are kept in the image, none of its samples are wasted on learning.
+pub fn render(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    mut on_pass: impl FnMut(u32) -> bool,
+) -> Result<Framebuffer> {
+    let bounds = match build_scene().world.bounding_box() {
+        Some(bounds) => bounds,
+        None => bail!("Path guiding needs a scene with bounds"),
+    };
+    let mut guide = Arc::new(SdTree::new(&bounds));
+    let (width, height) = (settings.image_width, settings.image_height);
+    let mut framebuffer = Framebuffer::new(width as usize, height as usize);
+    let tiles = split_into_tiles(width, height, TILE_SIZE);
+
+    let samples_per_pixel = settings.samples_per_pixel as u32;
+    let mut first_sample = 0;
+    while first_sample < samples_per_pixel {
+        // The last pass takes all the samples left rather than less than the one before
+        let mut sample_count = first_sample.max(1);
+        if first_sample + 2 * sample_count > samples_per_pixel {
+            sample_count = samples_per_pixel - first_sample;
+        }
+        let samples = first_sample..first_sample + sample_count;
+
+        let new_renderer = || {
+            let mut scene = build_scene();
+            scene.guide = Some(Arc::clone(&guide));
+            let samples = samples.clone();
+            move |tile| scene.render_samples(settings, tile, samples.clone())
+        };
+        parallel::render_tiles(threads, tiles.clone(), new_renderer, |_, samples| {
+            samples.merge_into(&mut framebuffer);
+            true
+        });
+
+        first_sample += sample_count;
+        guide = Arc::new(guide.refined(sample_count));
+        if !on_pass(sample_count) {
+            break;
+        }
+    }
+    Ok(framebuffer)
+}
+
+// ---------
+//  SD-TREE
+// ---------
+
+/// Binary tree over the space of the scene, with the light arriving in each of its leaves.
+pub struct SdTree {
+    /// Cube around the scene.
+    bounds: Aabb,
+    nodes: Vec<SpatialNode>,
+}
+
+enum SpatialNode {
+    /// Node split in halves along `axis`, the lower half being the first child.
+    Inner {
+        axis: usize,
+        children: [usize; 2],
+    },
+    Leaf(Leaf),
+}
+
+struct Leaf {
+    /// Light learnt by the previous passes, sampled by the current one.
+    sampling: DirectionTree,
+    /// Light recorded by the current pass.
+    building: DirectionTree,
+    sample_count: AtomicU64,
+}
+
+/// Vertex of a path traced with the guide, where a direction was sampled.
+pub(crate) struct GuidedVertex {
+    pub point: Point3,
+    pub direction: Vec3,
+    /// Density of sampling the direction.
+    pub pdf: Float,
+    /// Throughput of the path after the vertex.
+    pub throughput: Color,
+    /// Radiance the path had gathered when it got to the vertex.
+    pub color: Color,
+}
+
+impl SdTree {
+    /// Tree around `bounds` which hasn't learnt anything yet.
+    pub fn new(bounds: &Aabb) -> SdTree {
+        let extent = bounds.max() - bounds.min();
+        let half_size = 0.5 * extent.x().max(extent.y()).max(extent.z());
+        let center = bounds.min() + 0.5 * extent;
+        SdTree {
+            bounds: Aabb::around(center, half_size.max(Float::EPSILON)),
+            nodes: vec![SpatialNode::Leaf(Leaf {
+                sampling: DirectionTree::new(),
+                building: DirectionTree::new(),
+                sample_count: AtomicU64::new(0),
+            })],
+        }
+    }
+
+    /// Distribution of the light arriving at `point`, None if none has been learnt there.
+    pub fn distribution(&self, point: &Point3) -> Option<&DirectionTree> {
+        let leaf = self.leaf(point);
+        (leaf.sampling.total() > 0.0).then_some(&leaf.sampling)
+    }
+
+    /// Records the light reaching each of the `vertices` of a path which gathered `color`.
+    // Summed as f64, whatever the precision of `Float`
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn record_path(&self, vertices: &[GuidedVertex], color: Color) {
+        for vertex in vertices {
+            // Radiance gathered after the vertex, with the throughput up to it divided out
+            let gathered = color - vertex.color;
+            let mut radiance = 0.0;
+            for i in 0..3 {
+                if vertex.throughput[i] > 0.0 {
+                    radiance += gathered[i] / vertex.throughput[i];
+                }
+            }
+            let value = (radiance / 3.0 / vertex.pdf) as f64;
+            if !value.is_finite() {
+                continue;
+            }
+
+            let leaf = self.leaf(&vertex.point);
+            leaf.sample_count.fetch_add(1, Ordering::Relaxed);
+            leaf.building
+                .record(to_square(&vertex.direction), value.max(0.0));
+        }
+    }
+
+    /// Tree to sample in the next pass, sampling the light recorded in this one, after
+    /// `samples_per_pixel` samples per pixel.
+    pub fn refined(&self, samples_per_pixel: u32) -> SdTree {
+        let threshold = SPLIT_THRESHOLD * (samples_per_pixel as Float).sqrt();
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        self.refine_node(0, 0, threshold, &mut nodes);
+        SdTree {
+            bounds: self.bounds,
+            nodes,
+        }
+    }
+
+    fn refine_node(
+        &self,
+        index: usize,
+        depth: usize,
+        threshold: Float,
+        nodes: &mut Vec<SpatialNode>,
+    ) -> usize {
+        match self.nodes[index] {
+            SpatialNode::Inner { axis, children } => {
+                let new_index = nodes.len();
+                nodes.push(SpatialNode::Inner {
+                    axis,
+                    children: [0, 0],
+                });
+                let children = [
+                    self.refine_node(children[0], depth + 1, threshold, nodes),
+                    self.refine_node(children[1], depth + 1, threshold, nodes),
+                ];
+                nodes[new_index] = SpatialNode::Inner { axis, children };
+                new_index
+            }
+            SpatialNode::Leaf(ref leaf) => split_leaf(
+                &leaf.building,
+                leaf.sample_count.load(Ordering::Relaxed),
+                depth,
+                threshold,
+                nodes,
+            ),
+        }
+    }
+
+    fn leaf(&self, point: &Point3) -> &Leaf {
+        let size = self.bounds.max() - self.bounds.min();
+        let offset = *point - self.bounds.min();
+        let mut p = [0.0; 3];
+        for (axis, p) in p.iter_mut().enumerate() {
+            *p = (offset[axis] / size[axis]).clamp(0.0, 1.0);
+        }
+
+        let mut index = 0;
+        loop {
+            match self.nodes[index] {
+                SpatialNode::Inner { axis, children } => {
+                    let upper = p[axis] >= 0.5;
+                    p[axis] = 2.0 * p[axis] - if upper { 1.0 } else { 0.0 };
+                    index = children[upper as usize];
+                }
+                SpatialNode::Leaf(ref leaf) => return leaf,
+            }
+        }
+    }
+}
+
+/// New node for a leaf which recorded `sample_count` samples of the light in `building`,
+/// split in halves as long as they have more samples than `threshold`.
+fn split_leaf(
+    building: &DirectionTree,
+    sample_count: u64,
+    depth: usize,
+    threshold: Float,
+    nodes: &mut Vec<SpatialNode>,
+) -> usize {
+    let index = nodes.len();
+    if sample_count as Float > threshold {
+        let axis = depth % 3;
+        nodes.push(SpatialNode::Inner {
+            axis,
+            children: [0, 0],
+        });
+        let children = [
+            split_leaf(building, sample_count / 2, depth + 1, threshold, nodes),
+            split_leaf(building, sample_count / 2, depth + 1, threshold, nodes),
+        ];
+        nodes[index] = SpatialNode::Inner { axis, children };
+    } else {
+        let sampling = building.refined();
+        nodes.push(SpatialNode::Leaf(Leaf {
+            building: sampling.cleared(),
+            sampling,
+            sample_count: AtomicU64::new(0),
+        }));
+    }
+    index
+}
+
+// ----------------
+//  DIRECTION TREE
+// ----------------
+
+/// Quadtree over the unit square, which the directions are mapped to with an equal-area
+/// projection, holding the light arriving from each of its cells.
+#[derive(Clone)]
+pub struct DirectionTree {
+    nodes: Vec<Quad>,
+}
+
+#[derive(Clone, Default)]
+struct Quad {
+    /// Light arriving from each quadrant, in the order (0, 0), (1, 0), (0, 1), (1, 1).
+    sums: [AtomicF64; 4],
+    /// Index of the node subdividing each quadrant, 0 for the quadrants which aren't.
+    children: [u32; 4],
+}
+
+impl DirectionTree {
+    fn new() -> DirectionTree {
+        DirectionTree {
+            nodes: vec![Quad::default()],
+        }
+    }
+
+    /// Light recorded from all the directions.
+    fn total(&self) -> f64 {
+        self.sums(0).iter().sum()
+    }
+
+    fn sums(&self, node: usize) -> [f64; 4] {
+        let sums = &self.nodes[node].sums;
+        [
+            sums[0].load(),
+            sums[1].load(),
+            sums[2].load(),
+            sums[3].load(),
+        ]
+    }
+
+    fn record(&self, mut point: [Float; 2], value: f64) {
+        let mut node = 0;
+        loop {
+            let quadrant = descend(&mut point);
+            self.nodes[node].sums[quadrant].add(value);
+            match self.nodes[node].children[quadrant] {
+                0 => return,
+                child => node = child as usize,
+            }
+        }
+    }
+
+    /// Density, over the unit square, of sampling `point`.
+    fn square_pdf(&self, mut point: [Float; 2]) -> Float {
+        let mut pdf = 1.0;
+        let mut node = 0;
+        loop {
+            let sums = self.sums(node);
+            let total: f64 = sums.iter().sum();
+            if total <= 0.0 {
+                return pdf;
+            }
+            let quadrant = descend(&mut point);
+            pdf *= (4.0 * sums[quadrant] / total) as Float;
+            match self.nodes[node].children[quadrant] {
+                0 => return pdf,
+                child => node = child as usize,
+            }
+        }
+    }
+
+    /// Point of the unit square, in a cell chosen in proportion to its light and uniformly
+    /// within it.
+    fn sample_square(&self, rng: &mut SampleRng) -> [Float; 2] {
+        let (mut origin, mut size) = ([0.0, 0.0], 1.0);
+        let mut node = 0;
+        loop {
+            let sums = self.sums(node);
+            let total: f64 = sums.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            let mut target = rng.gen::<f64>() * total;
+            let mut quadrant = 0;
+            while quadrant < 3 && target >= sums[quadrant] {
+                target -= sums[quadrant];
+                quadrant += 1;
+            }
+            size *= 0.5;
+            origin[0] += (quadrant % 2) as Float * size;
+            origin[1] += (quadrant / 2) as Float * size;
+            match self.nodes[node].children[quadrant] {
+                0 => break,
+                child => node = child as usize,
+            }
+        }
+        [
+            origin[0] + size * rng.gen::<Float>(),
+            origin[1] + size * rng.gen::<Float>(),
+        ]
+    }
+
+    /// Tree with the light recorded in this one, subdivided where more than a fraction of it
+    /// arrives from and merged elsewhere.
+    fn refined(&self) -> DirectionTree {
+        let mut tree = DirectionTree::new();
+        let total = self.total();
+        if total <= 0.0 {
+            return tree;
+        }
+
+        // Nodes of the new tree, the nodes of this one at the same place and their light
+        let mut stack = vec![(0, Some(0), self.sums(0), 1)];
+        while let Some((node, old_node, sums, depth)) = stack.pop() {
+            for (quadrant, &sum) in sums.iter().enumerate() {
+                tree.nodes[node].sums[quadrant] = AtomicF64::new(sum);
+                if depth >= MAX_DEPTH || sum <= SUBDIVISION_THRESHOLD * total {
+                    continue;
+                }
+
+                let child = tree.nodes.len();
+                tree.nodes.push(Quad::default());
+                tree.nodes[node].children[quadrant] = child as u32;
+                let old_child = old_node
+                    .map(|old_node: usize| self.nodes[old_node].children[quadrant] as usize)
+                    .filter(|&old_child| old_child != 0);
+                let child_sums = match old_child {
+                    Some(old_child) => self.sums(old_child),
+                    None => [sum / 4.0; 4],
+                };
+                stack.push((child, old_child, child_sums, depth + 1));
+            }
+        }
+        tree
+    }
+
+    /// Tree with the same cells, without any light.
+    fn cleared(&self) -> DirectionTree {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|quad| Quad {
+                sums: Default::default(),
+                children: quad.children,
+            })
+            .collect();
+        DirectionTree { nodes }
+    }
+}
+
+impl Pdf for DirectionTree {
+    fn value(&self, direction: &Vec3) -> Float {
+        self.square_pdf(to_square(direction)) / (4.0 * PI)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        from_square(self.sample_square(rng))
+    }
+}
+
+/// Quadrant of the unit square `point` is in, `point` becoming its position in the quadrant.
+fn descend(point: &mut [Float; 2]) -> usize {
+    let mut quadrant = 0;
+    for (axis, coordinate) in point.iter_mut().enumerate() {
+        if *coordinate >= 0.5 {
+            *coordinate = 2.0 * *coordinate - 1.0;
+            quadrant += 1 << axis;
+        } else {
+            *coordinate *= 2.0;
+        }
+    }
+    quadrant
+}
+
+/// Equal-area projection of a direction to the unit square: its height along Z and its angle
+/// around it.
+fn to_square(direction: &Vec3) -> [Float; 2] {
+    let direction = unit_vector(*direction);
+    let cos_theta = direction.z().clamp(-1.0, 1.0);
+    let mut phi = direction.y().atan2(direction.x());
+    if phi < 0.0 {
+        phi += 2.0 * PI;
+    }
+    [0.5 * (cos_theta + 1.0), phi / (2.0 * PI)]
+}
+
+fn from_square(point: [Float; 2]) -> Vec3 {
+    let cos_theta = 2.0 * point[0] - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * point[1];
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Float which threads can add to concurrently.
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> AtomicF64 {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, value: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+}
+
+impl Clone for AtomicF64 {
+    fn clone(&self) -> AtomicF64 {
+        AtomicF64::new(self.load())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::BuiltinScene;
+
+    #[test]
+    fn test_square_projection() {
+        let direction = unit_vector(Vec3::new(-0.3, -0.5, 0.8));
+        let back = from_square(to_square(&direction));
+        assert!((back - direction).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_direction_tree_pdf() {
+        // Light coming from around +X
+        let mut rng = SampleRng::new(0);
+        let mut tree = DirectionTree::new();
+        for _ in 0..4 {
+            for _ in 0..10_000 {
+                let direction = Vec3::new(1.0, 0.0, 0.0) + 0.3 * Vec3::random_unit_vector(&mut rng);
+                tree.record(to_square(&direction), 1.0);
+            }
+            tree = tree.refined();
+        }
+        assert!(tree.nodes.len() > 1);
+
+        // The density integrates to 1 over the sphere, and most samples go towards the light,
+        // where 15% of uniform samples would
+        let sample_count = 100_000;
+        let mut integral = 0.0;
+        let mut towards_x = 0;
+        for _ in 0..sample_count {
+            let direction = Vec3::random_unit_vector(&mut rng);
+            integral += tree.value(&direction) * 4.0 * PI / sample_count as Float;
+            if tree.generate(&mut rng).x() > 0.7 {
+                towards_x += 1;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.05);
+        assert!(towards_x as Float > 0.6 * sample_count as Float);
+        let pdf_x = tree.value(&Vec3::new(1.0, 0.0, 0.0));
+        assert!(pdf_x > 10.0 * tree.value(&Vec3::new(-1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 16,
+            image_height: 16,
+            samples_per_pixel: 32,
+            ..RenderSettings::default()
+        };
+        let build_scene = || settings.scene.build(&settings);
+        let mut passes = Vec::new();
+        let guided = render(&settings, 1, &build_scene, |samples| {
+            passes.push(samples);
+            true
+        })
+        .unwrap();
+        assert_eq!(passes, [1, 1, 2, 4, 8, 16]);
+
+        let scene = build_scene();
+        let tile = split_into_tiles(16, 16, 16)[0];
+        let unguided = scene.render_tile(&settings, tile).framebuffer.pixels();
+        let mean = |pixels: &[Color]| {
+            let sum = pixels.iter().fold(Color::zero(), |sum, pixel| sum + *pixel);
+            (sum.x() + sum.y() + sum.z()) / (3 * pixels.len()) as Float
+        };
+        let (guided, unguided) = (mean(&guided.pixels()), mean(&unguided));
+        assert!(
+            (guided - unguided).abs() < 0.05 * unguided,
+            "{} != {}",
+            guided,
+            unguided
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::transform::Transform;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use std::rc::Rc;
+
+/// Copy of a shared object placed in the scene by a transformation.
+///
+/// Instances of a prototype share it, along with its acceleration structure, such as the BVH
+/// of a group of objects. Put in the accelerator of the scene, they make a two-level
+/// structure: the top level only bounds the instances, rays are transformed into the space of
+/// the prototype to traverse the bottom level, so neither memory nor build time grows with
+/// the size of the instanced object.
+#[derive(Clone)]
+pub struct Instance {
+    object: Rc<dyn Hittable>,
+    object_to_world: Transform,
+    world_to_object: Transform,
+}
+
+impl Instance {
+    pub fn new(object: Rc<dyn Hittable>, object_to_world: Transform) -> Instance {
+        Instance {
+            object,
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+        }
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        // The direction isn't normalized, so that distances along the ray stay the same
+        let local_ray = Ray::new(
+            self.world_to_object.transform_point(&ray.origin()),
+            self.world_to_object.transform_vector(&ray.direction()),
+        );
+        if !self.object.hit(&local_ray, t_min, t_max, hit_record) {
+            return false;
+        }
+
+        hit_record.point = self.object_to_world.transform_point(&hit_record.point);
+        // Bounded by the largest stretch of the transform
+        let stretch = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ]
+        .iter()
+        .map(|axis| self.object_to_world.transform_vector(axis).length())
+        .fold(0.0, Float::max);
+        hit_record.point_error *= stretch;
+        // The normal keeps facing against the ray
+        hit_record.normal = unit_vector(self.object_to_world.transform_normal(&hit_record.normal));
+        hit_record.geometric_normal = unit_vector(
+            self.object_to_world
+                .transform_normal(&hit_record.geometric_normal),
+        );
+        hit_record.tangent = self.object_to_world.transform_vector(&hit_record.tangent);
+        hit_record.terminator_offset = self
+            .object_to_world
+            .transform_vector(&hit_record.terminator_offset);
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object
+            .bounding_box()
+            .map(|aabb| self.object_to_world.transform_aabb(&aabb))
+    }
+
+    /// Exact for rigid motions and uniform scalings, which preserve solid angles.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.object.pdf_value(
+            &self.world_to_object.transform_point(origin),
+            &self.world_to_object.transform_vector(direction),
+        )
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        let local_origin = self.world_to_object.transform_point(origin);
+        self.object_to_world
+            .transform_vector(&self.object.random(&local_origin, rng))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::{Bvh, BvhSplit};
+    use crate::material::MaterialId;
+    use crate::object::HittableList;
+    use crate::sphere::Sphere;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_transformed_sphere() {
+        let material = MaterialId::default();
+        let unit_sphere: Rc<dyn Hittable> = Rc::new(Sphere::new(Point3::zero(), 1.0, material));
+        let transform = Transform::scaling(Vec3::new(2.0, 2.0, 2.0))
+            .then(&Transform::translation(Vec3::new(0.0, 0.0, -10.0)));
+        let instance = Instance::new(unit_sphere, transform);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::empty();
+        assert!(instance.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 8.0).abs() < 1e-5);
+        assert!((hit_record.point - Point3::new(0.0, 0.0, -8.0)).length() < 1e-5);
+        assert!((hit_record.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-5);
+        assert!(hit_record.front_face);
+
+        let bounds = instance.bounding_box().unwrap();
+        assert!((bounds.min() - Point3::new(-2.0, -2.0, -12.0)).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_two_levels() {
+        // Many instances of a shared cluster of spheres, against all the spheres moved in place
+        let material = MaterialId::default();
+        let centers = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.5, 0.0, 0.0),
+            Point3::new(0.0, 1.5, 0.5),
+        ];
+        let cluster: Vec<Box<dyn Hittable>> = centers
+            .iter()
+            .map(|&center| Box::new(Sphere::new(center, 0.5, material)) as _)
+            .collect();
+        let prototype: Rc<dyn Hittable> = Rc::new(Bvh::new(cluster, BvhSplit::Sah));
+
+        let mut instances: Vec<Box<dyn Hittable>> = Vec::new();
+        let mut flat = HittableList::new();
+        for i in 0..50 {
+            let transform = Transform::rotation(Vec3::new(0.0, 1.0, 0.0), 7.0 * i as Float).then(
+                &Transform::translation(Vec3::new(
+                    (i % 10) as Float * 4.0,
+                    0.0,
+                    (i / 10) as Float * 4.0,
+                )),
+            );
+            instances.push(Box::new(Instance::new(prototype.clone(), transform)));
+            for &center in &centers {
+                let moved = transform.transform_point(&center);
+                flat.add(Box::new(Sphere::new(moved, 0.5, material)));
+            }
+        }
+        let top_level = Bvh::new(instances, BvhSplit::Sah);
+
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..500 {
+            let ray = Ray::new(
+                Vec3::random_range(&mut rng, -5.0, 40.0),
+                Vec3::random_in_unit_sphere(&mut rng),
+            );
+            let mut expected = HitRecord::empty();
+            let hit = flat.hit(&ray, 0.001, Float::MAX, &mut expected);
+            let mut hit_record = HitRecord::empty();
+            assert_eq!(top_level.hit(&ray, 0.001, Float::MAX, &mut hit_record), hit);
+            if hit {
+                assert!((hit_record.t - expected.t).abs() < 1e-3 * expected.t.max(1.0));
+            }
+        }
+    }
+}
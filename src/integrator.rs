@@ -0,0 +1,831 @@
+use crate::background::Background;
+use crate::bdpt::bidirectional_path_trace;
+use crate::float::Float;
+use crate::fog::HeightFog;
+use crate::guiding::{GuidedVertex, SdTree};
+use crate::irradiance_cache::{is_cached, IrradianceCache};
+use crate::light::Light;
+use crate::light_bvh::LightBvh;
+use crate::material::{MaterialList, ScatterRecord, ScatterType, Scatterable};
+use crate::medium::MediumStack;
+use crate::object::{HitRecord, Hittable};
+use crate::pdf::{MixturePdf, Pdf};
+use crate::photon_map::PhotonMap;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::stats::{self, STATS};
+use crate::vec3::{Color, Point3, Vec3};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    PathTracer,
+    /// Stochastic progressive photon mapping, which renders whole images with
+    /// `sppm::render`. Single rays are path traced.
+    Sppm,
+    /// Bidirectional path tracing, connecting paths from the camera to paths from the lights.
+    Bidirectional,
+    /// First-hit shading normal, remapped to [0, 1].
+    DebugNormals,
+    /// First-hit distance from the camera, black for the background.
+    DebugDepth,
+    /// Number of bounces of the path, relative to the bounce limit.
+    DebugBounces,
+    /// Number of nodes of the acceleration structures visited by the camera ray, the cost of
+    /// finding what it hits.
+    DebugNodes,
+    /// Number of intersection tests of the camera ray against the objects in the leaves of the
+    /// acceleration structures.
+    DebugPrimitives,
+}
+
+impl Integrator {
+    #[allow(clippy::too_many_arguments)]
+    pub fn ray_color<H: Hittable + ?Sized, B: Background + ?Sized>(
+        &self,
+        rng: &mut SampleRng,
+        ray: &Ray,
+        world: &H,
+        lights: &LightBvh,
+        delta_lights: &[Light],
+        materials: &MaterialList,
+        background: &B,
+        fog: Option<&HeightFog>,
+        caustics: Option<&PhotonMap>,
+        guide: Option<&SdTree>,
+        irradiance: Option<&IrradianceCache>,
+        transparent_shadows: bool,
+        bounce_limit: u16,
+    ) -> Color {
+        match *self {
+            Integrator::PathTracer | Integrator::Sppm => path_trace(
+                rng,
+                ray,
+                world,
+                lights,
+                delta_lights,
+                materials,
+                background,
+                fog,
+                caustics,
+                guide,
+                irradiance,
+                transparent_shadows,
+                bounce_limit,
+            ),
+            Integrator::Bidirectional => bidirectional_path_trace(
+                rng,
+                ray,
+                world,
+                lights,
+                delta_lights,
+                materials,
+                background,
+                bounce_limit,
+            ),
+            Integrator::DebugNormals => debug_normal(ray, world),
+            Integrator::DebugDepth => debug_depth(ray, world),
+            Integrator::DebugBounces => debug_bounces(rng, ray, world, materials, bounce_limit),
+            Integrator::DebugNodes => debug_traversal(ray, world).0,
+            Integrator::DebugPrimitives => debug_traversal(ray, world).1,
+        }
+    }
+
+    /// Debug integrators output data, not radiance, which must not be tone mapped.
+    pub fn is_debug(&self) -> bool {
+        matches!(
+            *self,
+            Integrator::DebugNormals
+                | Integrator::DebugDepth
+                | Integrator::DebugBounces
+                | Integrator::DebugNodes
+                | Integrator::DebugPrimitives
+        )
+    }
+}
+
+impl fmt::Display for Integrator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Integrator::PathTracer => "path",
+            Integrator::Sppm => "sppm",
+            Integrator::Bidirectional => "bdpt",
+            Integrator::DebugNormals => "debug-normals",
+            Integrator::DebugDepth => "debug-depth",
+            Integrator::DebugBounces => "debug-bounces",
+            Integrator::DebugNodes => "debug-nodes",
+            Integrator::DebugPrimitives => "debug-primitives",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Integrator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Integrator> {
+        match s {
+            "path" => Ok(Integrator::PathTracer),
+            "sppm" => Ok(Integrator::Sppm),
+            "bdpt" => Ok(Integrator::Bidirectional),
+            "debug-normals" => Ok(Integrator::DebugNormals),
+            "debug-depth" => Ok(Integrator::DebugDepth),
+            "debug-bounces" => Ok(Integrator::DebugBounces),
+            "debug-nodes" => Ok(Integrator::DebugNodes),
+            "debug-primitives" => Ok(Integrator::DebugPrimitives),
+            _ => bail!(
+                "Unknown integrator '{}', expected one of: path, sppm, bdpt, debug-normals, debug-depth, debug-bounces, debug-nodes, debug-primitives",
+                s
+            ),
+        }
+    }
+}
+
+/// Path traces `ray` through `world`.
+///
+/// At each diffuse bounce, one of the `lights` is sampled explicitly with a shadow ray
+/// (next-event estimation) and combined with the BSDF sample using multiple importance
+/// sampling, so small light sources converge as fast as large ones. Backgrounds with bright
+/// features, such as the sun, are sampled the same way. The `delta_lights`, which can't be hit,
+/// are all sampled at each diffuse bounce.
+///
+/// In the `fog`, rays are scattered at distances sampled along them, and shadow rays are
+/// attenuated by its transmittance. Lights are not sampled from the points scattered by the
+/// fog, which only see them when the scattered rays hit them.
+///
+/// With a map of the `caustics`, the light reaching diffuse surfaces through specular bounces
+/// is gathered from its photons instead, and not counted again when the path finds it.
+///
+/// With a `guide`, half of the directions are sampled from the light it learnt arrives at the
+/// surfaces, and the light the path finds is recorded into it.
+///
+/// With an `irradiance` cache, the indirect light of the first diffuse surface the path finds
+/// is interpolated from it when it has records around. The path then only goes on to the next
+/// hit, for the light it finds there directly.
+///
+/// With `transparent_shadows`, the shadow rays go through dielectrics, straight, for the
+/// colored shadows of glass. The light the path finds through them after a diffuse bounce is
+/// then left to the shadow rays.
+#[allow(clippy::too_many_arguments)]
+pub fn path_trace<H: Hittable + ?Sized, B: Background + ?Sized>(
+    rng: &mut SampleRng,
+    ray: &Ray,
+    world: &H,
+    lights: &LightBvh,
+    delta_lights: &[Light],
+    materials: &MaterialList,
+    background: &B,
+    fog: Option<&HeightFog>,
+    caustics: Option<&PhotonMap>,
+    guide: Option<&SdTree>,
+    irradiance: Option<&IrradianceCache>,
+    transparent_shadows: bool,
+    bounce_limit: u16,
+) -> Color {
+    let mut color = Color::zero();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+    // Density of the BSDF sample which produced `ray`, None for camera and specular rays
+    let mut bsdf_pdf: Option<Float> = None;
+    let mut media = MediumStack::new();
+    // Whether the path went through a diffuse bounce then only specular ones, the caustics
+    let mut after_diffuse = false;
+    let mut caustic_path = false;
+    let mut guided_vertices = Vec::new();
+    // Whether the indirect light of the previous hit came from the irradiance cache
+    let mut cached = false;
+    // Point of the last diffuse hit when the path only went through dielectrics since, for
+    // transparent shadows: the shadow rays from there already found the lights behind them
+    let mut shadow_origin: Option<Point3> = None;
+
+    // If we've exceeded the ray bounce limit, no more light is gathered
+    for bounce in 0..bounce_limit {
+        count_ray(bounce == 0);
+        let mut hit_record = HitRecord::empty();
+        let hit = world.hit(&ray, 0.0, Float::MAX, &mut hit_record);
+
+        if let Some(fog) = fog {
+            let t_max = if hit { hit_record.t } else { Float::INFINITY };
+            if let Some(t) = fog.sample_interaction(&ray, t_max, rng) {
+                // Scattered evenly in all directions, the probability of getting here
+                // cancelling out the transmittance and density
+                throughput *= fog.albedo;
+                ray =
+                    Ray::new(ray.at(t), Vec3::random_unit_vector(rng)).with_channel(ray.channel());
+                bsdf_pdf = None;
+                after_diffuse = false;
+                caustic_path = false;
+                shadow_origin = None;
+                continue;
+            }
+        }
+
+        if !hit {
+            let background_pdf = background.pdf_value(&ray.direction());
+            let weight = match bsdf_pdf {
+                _ if shadow_origin.is_some() && background_pdf > 0.0 => 0.0,
+                Some(pdf) => power_heuristic(pdf, background_pdf),
+                None => 1.0,
+            };
+            color += weight * throughput * background.color(ray.direction());
+            break;
+        }
+
+        let material = &materials[hit_record.material];
+        hit_record.outside_refraction_index = media.outside_of(hit_record.material);
+        let emitted = if caustic_path && caustics.is_some() {
+            Color::zero()
+        } else {
+            material.emitted(&ray, &hit_record)
+        };
+        let weight = match (bsdf_pdf, shadow_origin) {
+            (_, Some(origin)) if lights.pdf_value(&origin, &(hit_record.point - origin)) > 0.0 => {
+                0.0
+            }
+            (Some(pdf), _) if !lights.is_empty() => {
+                power_heuristic(pdf, lights.pdf_value(&ray.origin(), &ray.direction()))
+            }
+            _ => 1.0,
+        };
+        color += weight * throughput * emitted;
+        if cached {
+            break;
+        }
+
+        let mut scatter_record = ScatterRecord::empty();
+        if !material.scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            break;
+        }
+
+        let material_pdf = match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => {
+                if let Some(refraction_index) = material.refraction_index() {
+                    if crossed_surface(&hit_record, &specular_ray) {
+                        media.cross(hit_record.material, refraction_index, hit_record.front_face);
+                    }
+                }
+                let through_dielectric = transparent_shadows
+                    && crossed_surface(&hit_record, &specular_ray)
+                    && material
+                        .shadow_transmittance(&specular_ray, &hit_record, 0.0, rng)
+                        .is_some();
+                shadow_origin = match bsdf_pdf {
+                    Some(_) if through_dielectric => Some(ray.origin()),
+                    _ if through_dielectric => shadow_origin,
+                    _ => None,
+                };
+                throughput *= scatter_record.attenuation;
+                ray = specular_ray;
+                bsdf_pdf = None;
+                caustic_path = after_diffuse;
+                continue;
+            }
+            ScatterType::Pdf(material_pdf) => material_pdf,
+        };
+        let guided_pdf;
+        let scatter_pdf: &dyn Pdf =
+            match guide.and_then(|guide| guide.distribution(&hit_record.point)) {
+                Some(distribution) => {
+                    guided_pdf = MixturePdf::new(&material_pdf, distribution);
+                    &guided_pdf
+                }
+                None => &material_pdf,
+            };
+
+        color += throughput
+            * sample_direct_light(
+                rng,
+                world,
+                lights,
+                delta_lights,
+                materials,
+                background,
+                fog,
+                &ray,
+                &hit_record,
+                scatter_pdf,
+                transparent_shadows,
+            );
+        let cached_irradiance = match irradiance {
+            Some(irradiance) if !after_diffuse && is_cached(material) => {
+                irradiance.irradiance(&hit_record.point, &hit_record.normal)
+            }
+            _ => None,
+        };
+        if let Some(cached_irradiance) = cached_irradiance {
+            // The BSDF includes the cosine, which the irradiance already accounts for
+            let normal_ray = Ray::new(hit_record.point, hit_record.normal);
+            color += throughput * material.eval(&ray, &hit_record, &normal_ray) * cached_irradiance;
+        } else if let Some(caustics) = caustics {
+            color += throughput * caustics.radiance(material, &ray, &hit_record);
+        }
+
+        let direction = scatter_pdf.generate(rng);
+        let scattered =
+            Ray::new(hit_record.ray_origin(&direction), direction).with_channel(ray.channel());
+        let pdf = scatter_pdf.value(&scattered.direction());
+        if pdf <= 0.0 {
+            break;
+        }
+
+        throughput *= material.eval(&ray, &hit_record, &scattered) / pdf;
+        if guide.is_some() {
+            guided_vertices.push(GuidedVertex {
+                point: hit_record.point,
+                direction: scattered.direction(),
+                pdf,
+                throughput,
+                color,
+            });
+        }
+        ray = scattered;
+        bsdf_pdf = Some(pdf);
+        after_diffuse = true;
+        caustic_path = false;
+        cached = cached_irradiance.is_some();
+        shadow_origin = None;
+    }
+
+    if let Some(guide) = guide {
+        guide.record_path(&guided_vertices, color);
+    }
+    color
+}
+
+pub(crate) fn count_ray(primary: bool) {
+    if primary {
+        STATS.add_primary_ray();
+    } else {
+        STATS.add_secondary_ray();
+    }
+}
+
+/// Whether `scattered` goes through the surface at `hit_record`. Rays restarted inside the
+/// object, by random walks, don't start from the hit point.
+pub(crate) fn crossed_surface(hit_record: &HitRecord, scattered: &Ray) -> bool {
+    let direction = scattered.direction();
+    scattered.origin() == hit_record.ray_origin(&direction)
+        && direction.dot(&hit_record.normal) < 0.0
+}
+
+fn debug_normal<H: Hittable + ?Sized>(ray: &Ray, world: &H) -> Color {
+    STATS.add_primary_ray();
+    let mut hit_record = HitRecord::empty();
+    if !world.hit(ray, 0.0, Float::MAX, &mut hit_record) {
+        return Color::zero();
+    }
+    0.5 * (hit_record.normal + Color::new(1.0, 1.0, 1.0))
+}
+
+fn debug_depth<H: Hittable + ?Sized>(ray: &Ray, world: &H) -> Color {
+    STATS.add_primary_ray();
+    let mut hit_record = HitRecord::empty();
+    if !world.hit(ray, 0.0, Float::MAX, &mut hit_record) {
+        return Color::zero();
+    }
+    let depth = hit_record.t * ray.direction().length();
+    Color::new(depth, depth, depth)
+}
+
+/// Node traversals and primitive tests of the search for the first hit of `ray`, as gray
+/// levels.
+fn debug_traversal<H: Hittable + ?Sized>(ray: &Ray, world: &H) -> (Color, Color) {
+    STATS.add_primary_ray();
+    let (nodes, primitives) = stats::thread_work();
+    let mut hit_record = HitRecord::empty();
+    world.hit(ray, 0.0, Float::MAX, &mut hit_record);
+    let (end_nodes, end_primitives) = stats::thread_work();
+
+    let gray = |count: u64| Color::new(count as Float, count as Float, count as Float);
+    (gray(end_nodes - nodes), gray(end_primitives - primitives))
+}
+
+/// Follows the scattered rays, without light sampling, until the path is absorbed or escapes.
+fn debug_bounces<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    ray: &Ray,
+    world: &H,
+    materials: &MaterialList,
+    bounce_limit: u16,
+) -> Color {
+    let mut ray = *ray;
+    let mut bounces = 0;
+
+    while bounces < bounce_limit {
+        count_ray(bounces == 0);
+        let mut hit_record = HitRecord::empty();
+        if !world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            break;
+        }
+
+        let mut scatter_record = ScatterRecord::empty();
+        if !materials[hit_record.material].scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            break;
+        }
+
+        bounces += 1;
+        ray = match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => specular_ray,
+            ScatterType::Pdf(material_pdf) => {
+                let direction = material_pdf.generate(rng);
+                Ray::new(hit_record.ray_origin(&direction), direction).with_channel(ray.channel())
+            }
+        };
+    }
+
+    let v = bounces as Float / bounce_limit.max(1) as Float;
+    Color::new(v, v, v)
+}
+
+/// Direct lighting estimate at `hit_record` from one sample of the `lights`, one of the
+/// background and all the `delta_lights`. The first two are weighted against sampling
+/// `material_pdf`, whose samples finding them are left to the caller. With
+/// `transparent_shadows`, the shadow rays go through dielectrics, and the light they find through
+/// them isn't weighted: it is all left to them.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sample_direct_light<H: Hittable + ?Sized, B: Background + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    lights: &LightBvh,
+    delta_lights: &[Light],
+    materials: &MaterialList,
+    background: &B,
+    fog: Option<&HeightFog>,
+    in_ray: &Ray,
+    hit_record: &HitRecord,
+    material_pdf: &dyn Pdf,
+    transparent_shadows: bool,
+) -> Color {
+    let mut color = Color::zero();
+    if !lights.is_empty() {
+        color += sample_light(
+            rng,
+            world,
+            lights,
+            materials,
+            fog,
+            in_ray,
+            hit_record,
+            material_pdf,
+            transparent_shadows,
+        );
+    }
+    color += sample_background(
+        rng,
+        world,
+        materials,
+        background,
+        fog,
+        in_ray,
+        hit_record,
+        material_pdf,
+        transparent_shadows,
+    );
+    for light in delta_lights {
+        color += sample_delta_light(
+            rng,
+            world,
+            materials,
+            light,
+            fog,
+            in_ray,
+            hit_record,
+            transparent_shadows,
+        );
+    }
+    color
+}
+
+/// Direct lighting estimate at `hit_record` from one light sample.
+#[allow(clippy::too_many_arguments)]
+fn sample_light<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    lights: &LightBvh,
+    materials: &MaterialList,
+    fog: Option<&HeightFog>,
+    in_ray: &Ray,
+    hit_record: &HitRecord,
+    material_pdf: &dyn Pdf,
+    transparent_shadows: bool,
+) -> Color {
+    let direction: Vec3 = lights.random(&hit_record.point, rng);
+    let light_pdf = lights.pdf_value(&hit_record.point, &direction);
+    if light_pdf <= 0.0 {
+        return Color::zero();
+    }
+
+    // Whatever the shadow ray hits first is what's seen from the hit point,
+    // an occluder simply doesn't emit anything.
+    let shadow_ray = Ray::new(hit_record.ray_origin(&direction), direction);
+    let mut light_record = HitRecord::empty();
+    let (hit, through_dielectrics) = trace_shadow_ray(
+        rng,
+        world,
+        materials,
+        &shadow_ray,
+        Float::MAX,
+        transparent_shadows,
+        &mut light_record,
+    );
+    if !hit {
+        return Color::zero();
+    }
+
+    let emitted = materials[light_record.material].emitted(&shadow_ray, &light_record);
+    let bsdf = materials[hit_record.material].eval(in_ray, hit_record, &shadow_ray);
+    let (weight, dielectrics) = match through_dielectrics {
+        Some(dielectrics) => (1.0, dielectrics),
+        None => (
+            power_heuristic(light_pdf, material_pdf.value(&direction)),
+            Color::new(1.0, 1.0, 1.0),
+        ),
+    };
+    let transmittance = transmittance(fog, &shadow_ray, light_record.t);
+
+    weight * transmittance / light_pdf * dielectrics * bsdf * emitted
+}
+
+/// Direct lighting estimate at `hit_record` from one sample of the background, zero if it has
+/// nothing to sample.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sample_background<H: Hittable + ?Sized, B: Background + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    materials: &MaterialList,
+    background: &B,
+    fog: Option<&HeightFog>,
+    in_ray: &Ray,
+    hit_record: &HitRecord,
+    material_pdf: &dyn Pdf,
+    transparent_shadows: bool,
+) -> Color {
+    let direction = background.random(rng);
+    let background_pdf = background.pdf_value(&direction);
+    if background_pdf <= 0.0 {
+        return Color::zero();
+    }
+
+    let shadow_ray = Ray::new(hit_record.ray_origin(&direction), direction);
+    let mut occluder_record = HitRecord::empty();
+    let (hit, through_dielectrics) = trace_shadow_ray(
+        rng,
+        world,
+        materials,
+        &shadow_ray,
+        Float::MAX,
+        transparent_shadows,
+        &mut occluder_record,
+    );
+    if hit {
+        return Color::zero();
+    }
+
+    let bsdf = materials[hit_record.material].eval(in_ray, hit_record, &shadow_ray);
+    let (weight, dielectrics) = match through_dielectrics {
+        Some(dielectrics) => (1.0, dielectrics),
+        None => (
+            power_heuristic(background_pdf, material_pdf.value(&direction)),
+            Color::new(1.0, 1.0, 1.0),
+        ),
+    };
+    let transmittance = transmittance(fog, &shadow_ray, Float::INFINITY);
+
+    weight * transmittance / background_pdf * dielectrics * bsdf * background.color(direction)
+}
+
+/// Direct lighting estimate at `hit_record` from a light which can't be hit. Its direction is
+/// known exactly, there is nothing to weight against the BSDF samples.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sample_delta_light<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    materials: &MaterialList,
+    light: &Light,
+    fog: Option<&HeightFog>,
+    in_ray: &Ray,
+    hit_record: &HitRecord,
+    transparent_shadows: bool,
+) -> Color {
+    let sample = match light.illuminate(&hit_record.point) {
+        Some(sample) => sample,
+        None => return Color::zero(),
+    };
+
+    let shadow_ray = Ray::new(hit_record.ray_origin(&sample.direction), sample.direction);
+    let mut occluder_record = HitRecord::empty();
+    let (hit, through_dielectrics) = trace_shadow_ray(
+        rng,
+        world,
+        materials,
+        &shadow_ray,
+        sample.distance,
+        transparent_shadows,
+        &mut occluder_record,
+    );
+    if hit {
+        return Color::zero();
+    }
+
+    let transmittance = transmittance(fog, &shadow_ray, sample.distance);
+    let dielectrics = through_dielectrics.unwrap_or_else(|| Color::new(1.0, 1.0, 1.0));
+    transmittance
+        * dielectrics
+        * materials[hit_record.material].eval(in_ray, hit_record, &shadow_ray)
+        * sample.irradiance
+}
+
+/// Finds the first surface along `shadow_ray` before `t_max`, into `hit_record`, going through
+/// the dielectrics and volumes on the way with `transparent_shadows`. Returns whether there was
+/// one, and the fraction of the light the dielectrics let through, None if the ray didn't go
+/// through any.
+fn trace_shadow_ray<H: Hittable + ?Sized>(
+    rng: &mut SampleRng,
+    world: &H,
+    materials: &MaterialList,
+    shadow_ray: &Ray,
+    t_max: Float,
+    transparent_shadows: bool,
+    hit_record: &mut HitRecord,
+) -> (bool, Option<Color>) {
+    let mut through_dielectrics: Option<Color> = None;
+    // The ray goes on from each dielectric, `t_previous` along `shadow_ray`
+    let mut segment = *shadow_ray;
+    let mut t_previous = 0.0;
+    loop {
+        STATS.add_secondary_ray();
+        if !world.hit(&segment, 0.0, t_max - t_previous, hit_record) {
+            return (false, through_dielectrics);
+        }
+        if !transparent_shadows {
+            return (true, None);
+        }
+        let distance = hit_record.t * segment.direction().length();
+        let material = &materials[hit_record.material];
+        match material.shadow_transmittance(&segment, hit_record, distance, rng) {
+            Some(transmittance) => {
+                let through = through_dielectrics.unwrap_or_else(|| Color::new(1.0, 1.0, 1.0));
+                through_dielectrics = Some(through * transmittance);
+                t_previous += hit_record.t;
+                let direction = segment.direction();
+                segment = Ray::new(hit_record.ray_origin(&direction), direction);
+            }
+            None => {
+                hit_record.t += t_previous;
+                return (true, through_dielectrics);
+            }
+        }
+    }
+}
+
+/// Fraction of the light going through the fog along `ray` up to `t`, all of it without fog.
+fn transmittance(fog: Option<&HeightFog>, ray: &Ray, t: Float) -> Float {
+    fog.map_or(1.0, |fog| fog.transmittance(ray, t))
+}
+
+/// Veach's power heuristic (beta = 2) weight of a sample drawn from `pdf_f`.
+pub fn power_heuristic(pdf_f: Float, pdf_g: Float) -> Float {
+    let f = pdf_f * pdf_f;
+    let g = pdf_g * pdf_g;
+    if f + g == 0.0 {
+        return 0.0;
+    }
+    f / (f + g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::PI;
+    use crate::light::PointLight;
+    use crate::material::{Dielectric, Lambertian, Material};
+    use crate::object::HittableList;
+    use crate::rect::Rect;
+    use crate::scenes::BuiltinScene;
+    use crate::settings::RenderSettings;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_transparent_shadows() {
+        // A point light above a red glass ball, over a white floor
+        let mut materials = MaterialList::new();
+        let white = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+            1.0, 1.0, 1.0,
+        ))));
+        let red_glass = Dielectric::new(1.5).with_absorption(Color::new(0.8, 0.2, 0.2), 0.5);
+        let glass = materials.add(Material::Dielectric(red_glass));
+        let mut world = HittableList::new();
+        world.add(Box::new(Rect::new(
+            Point3::new(-5.0, 0.0, -5.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            white,
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 1.5, 0.0),
+            1.0,
+            glass,
+        )));
+        let light = Light::Point(PointLight::new(
+            Point3::new(0.0, 4.0, 0.0),
+            Color::new(16.0, 16.0, 16.0),
+        ));
+
+        let ray = Ray::new(Point3::new(0.0, 0.25, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut hit_record = HitRecord::empty();
+        assert!(world.hit(&ray, 0.0, Float::MAX, &mut hit_record));
+        let mut rng = SampleRng::new(0);
+        let mut direct_light = |transparent_shadows: bool| {
+            sample_delta_light(
+                &mut rng,
+                &world,
+                &materials,
+                &light,
+                None,
+                &ray,
+                &hit_record,
+                transparent_shadows,
+            )
+        };
+
+        assert_eq!(direct_light(false), Color::zero());
+        // Through 2 units of glass, less the reflections off its two surfaces
+        let shadow = direct_light(true);
+        let unshadowed = 16.0 / (4.0 * 4.0) / PI;
+        let reflected = 1.0 - 0.04;
+        let red = unshadowed * reflected * reflected * 0.8;
+        assert!((shadow.x() - red).abs() < 1e-3 * red);
+        assert!((shadow.y() - red * 0.25).abs() < 1e-3 * red);
+    }
+
+    #[test]
+    fn test_debug_traversal() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::RandomSpheres,
+            ..RenderSettings::default()
+        };
+        let scene = settings.scene.build(&settings);
+
+        // Down through the field of spheres
+        let down = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let (nodes, primitives) = debug_traversal(&down, &*scene.world);
+        assert!(nodes.x() >= 1.0 && primitives.x() >= 1.0);
+        assert_eq!(nodes, Color::new(nodes.x(), nodes.x(), nodes.x()));
+
+        // Up into the sky, away from the boxes of the spheres
+        let up = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!(debug_traversal(&up, &*scene.world).0.x() < nodes.x());
+    }
+
+    #[test]
+    fn test_debug_bounces() {
+        // Down onto a diffuse floor, the scattered rays all escaping to the sky
+        let mut materials = MaterialList::new();
+        let white = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+            0.5, 0.5, 0.5,
+        ))));
+        let mut world = HittableList::new();
+        world.add(Box::new(Rect::new(
+            Point3::new(-5.0, 0.0, -5.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            white,
+        )));
+
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut rng = SampleRng::new(0);
+        for _ in 0..100 {
+            let bounces = debug_bounces(&mut rng, &ray, &world, &materials, 4);
+            assert_eq!(bounces, Color::new(0.25, 0.25, 0.25));
+        }
+    }
+
+    #[test]
+    fn test_power_heuristic() {
+        assert_eq!(power_heuristic(1.0, 0.0), 1.0);
+        assert_eq!(power_heuristic(0.0, 1.0), 0.0);
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+    }
+
+    #[test]
+    fn test_integrator_from_str() {
+        assert_eq!(
+            "debug-normals".parse::<Integrator>().unwrap(),
+            Integrator::DebugNormals
+        );
+        assert_eq!(
+            "path".parse::<Integrator>().unwrap(),
+            Integrator::PathTracer
+        );
+        assert!("whitted".parse::<Integrator>().is_err());
+        assert_eq!(
+            Integrator::DebugBounces
+                .to_string()
+                .parse::<Integrator>()
+                .unwrap(),
+            Integrator::DebugBounces
+        );
+    }
+}
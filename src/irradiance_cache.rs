@@ -0,0 +1,337 @@
+//! Irradiance caching: the indirect light reaching diffuse surfaces varies slowly over them, so
+//! it is computed with many rays at sparse points before rendering, and interpolated in between
+//! instead of being path traced again by each sample of each pixel.
+//!
+//! Records are computed where the first diffuse surfaces seen through a grid of pixels aren't
+//! covered by the records before, more densely close to other objects, where the light changes
+//! the most. Points no record covers are path traced as usual. The interpolation leaves a slight
+//! blur and blotches in the indirect light, instead of its noise.
+
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::integrator::path_trace;
+use crate::material::{Material, ScatterRecord, ScatterType, Scatterable};
+use crate::object::HitRecord;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vec3::{Color, Point3, Vec3};
+use std::collections::HashMap;
+
+/// Largest error allowed when interpolating a record, the smaller the more records.
+const ACCURACY: Float = 0.25;
+/// Directions sampled over the hemisphere above each record.
+const HEMISPHERE_SAMPLES: u32 = 128;
+/// Pixels between the camera rays looking for surfaces to cover with records.
+const PIXEL_STEP: u16 = 4;
+/// Bounds of the distance to the other objects of the records, relative to the diagonal of the
+/// box around the points seen, so that corners don't get too many records and open spaces
+/// still get some.
+const MIN_RADIUS: Float = 0.02;
+const MAX_RADIUS: Float = 0.5;
+
+/// Indirect light arriving at a point of a diffuse surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IrradianceRecord {
+    pub point: Point3,
+    /// Unit normal of the surface, on the side the light arrives from.
+    pub normal: Vec3,
+    /// Irradiance of the light reflected or scattered by the objects around.
+    pub irradiance: Color,
+    /// Harmonic mean of the distances to the objects around, over which the irradiance changes.
+    pub radius: Float,
+}
+
+/// Records stored in a grid, for finding the ones close enough to a point to interpolate.
+pub struct IrradianceCache {
+    records: Vec<IrradianceRecord>,
+    /// Records by cell of the grid, in all the cells their region of influence overlaps.
+    cells: HashMap<(i32, i32, i32), Vec<u32>>,
+    cell_size: Float,
+}
+
+impl IrradianceCache {
+    /// Computes the records covering the diffuse surfaces seen by the camera of the `scene`,
+    /// from the samples of the pixels at `PIXEL_STEP` of each other, themselves starting
+    /// from the surface seen through the pixel or through mirrors and glass.
+    pub fn build(scene: &Scene, settings: &RenderSettings) -> IrradianceCache {
+        // The same records on all the threads and machines rendering the image
+        let mut rng = SampleRng::new(settings.seed);
+        let mut surfaces = Vec::new();
+        for row in (0..settings.image_height).step_by(PIXEL_STEP as usize) {
+            for col in (0..settings.image_width).step_by(PIXEL_STEP as usize) {
+                let u = (col as Float + 0.5) / (settings.image_width - 1) as Float;
+                let v = (row as Float + 0.5) / (settings.image_height - 1) as Float;
+                let surface = scene
+                    .camera
+                    .get_ray(u, v, &mut rng)
+                    .and_then(|ray| first_diffuse_surface(scene, settings, ray, &mut rng));
+                if let Some(surface) = surface {
+                    surfaces.push(surface);
+                }
+            }
+        }
+
+        let diagonal = surfaces
+            .iter()
+            .fold(Aabb::empty(), |bounds, surface| bounds.grow(&surface.point))
+            .extent()
+            .length();
+        let (min_radius, max_radius) = (MIN_RADIUS * diagonal, MAX_RADIUS * diagonal);
+        let mut cache = IrradianceCache {
+            records: Vec::new(),
+            cells: HashMap::new(),
+            cell_size: (2.0 * ACCURACY * max_radius).max(Float::MIN_POSITIVE),
+        };
+        for surface in surfaces {
+            if cache.irradiance(&surface.point, &surface.normal).is_none() {
+                let record = compute_record(scene, settings, &surface, &mut rng);
+                let radius = record.radius.clamp(min_radius, max_radius);
+                cache.insert(IrradianceRecord { radius, ..record });
+            }
+        }
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Indirect irradiance at `point` on a surface of unit `normal`, interpolated from the
+    /// records around weighted by their estimated error. None if no record is close enough.
+    pub fn irradiance(&self, point: &Point3, normal: &Vec3) -> Option<Color> {
+        let candidates = self.cells.get(&self.cell(point))?;
+        let mut irradiance = Color::zero();
+        let mut total_weight = 0.0;
+        for &index in candidates {
+            let record = &self.records[index as usize];
+            if let Some(weight) = record.weight(point, normal) {
+                irradiance += weight * record.irradiance;
+                total_weight += weight;
+            }
+        }
+        (total_weight > 0.0).then(|| irradiance / total_weight)
+    }
+
+    fn insert(&mut self, record: IrradianceRecord) {
+        let index = self.records.len() as u32;
+        let influence = ACCURACY * record.radius;
+        let (min, max) = (
+            self.cell(&(record.point - Vec3::new(influence, influence, influence))),
+            self.cell(&(record.point + Vec3::new(influence, influence, influence))),
+        );
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    self.cells.entry((x, y, z)).or_default().push(index);
+                }
+            }
+        }
+        self.records.push(record);
+    }
+
+    fn cell(&self, point: &Point3) -> (i32, i32, i32) {
+        let coordinate = |x: Float| (x / self.cell_size).floor() as i32;
+        (
+            coordinate(point.x()),
+            coordinate(point.y()),
+            coordinate(point.z()),
+        )
+    }
+}
+
+impl IrradianceRecord {
+    /// Weight of the record when interpolating at `point` on a surface of unit `normal`, the
+    /// inverse of the error it would make there. None if the error is too large, or if the
+    /// record is in front of the point, where it sees objects the point doesn't.
+    fn weight(&self, point: &Point3, normal: &Vec3) -> Option<Float> {
+        let offset = *point - self.point;
+        let distance = offset.length();
+        let error = distance / self.radius + (1.0 - normal.dot(&self.normal)).max(0.0).sqrt();
+        if error >= ACCURACY {
+            return None;
+        }
+        let in_front = offset.dot(&(0.5 * (*normal + self.normal)));
+        if in_front < -0.05 * self.radius {
+            return None;
+        }
+        Some(1.0 / error.max(1e-3))
+    }
+}
+
+/// Hit of the first diffuse surface found by `ray`, after the mirrors and glass it goes
+/// through. None if it escapes or finds another kind of material.
+fn first_diffuse_surface(
+    scene: &Scene,
+    settings: &RenderSettings,
+    ray: Ray,
+    rng: &mut SampleRng,
+) -> Option<HitRecord> {
+    let mut ray = ray;
+    for _ in 0..settings.bounce_limit {
+        let mut hit_record = HitRecord::empty();
+        if !scene.world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            return None;
+        }
+        let material = &scene.materials[hit_record.material];
+        if is_cached(material) {
+            return Some(hit_record);
+        }
+        let mut scatter_record = ScatterRecord::empty();
+        if !material.scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            return None;
+        }
+        match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => ray = specular_ray,
+            ScatterType::Pdf(_) => return None,
+        }
+    }
+    None
+}
+
+/// Whether the light reflected by `material` is interpolated from the cache: only perfectly
+/// diffuse surfaces reflect the irradiance the same way whatever the directions it comes from.
+pub(crate) fn is_cached(material: &Material) -> bool {
+    matches!(*material, Material::Lambertian(_))
+}
+
+/// Indirect irradiance at the point of `surface`, from path traced rays in cosine-weighted
+/// directions around its normal. The light emitted by the surfaces they first hit is direct
+/// light, left out.
+fn compute_record(
+    scene: &Scene,
+    settings: &RenderSettings,
+    surface: &HitRecord,
+    rng: &mut SampleRng,
+) -> IrradianceRecord {
+    let onb = Onb::build_from_w(&surface.normal);
+    let mut radiance = Color::zero();
+    let mut inverse_distances = 0.0;
+    for _ in 0..HEMISPHERE_SAMPLES {
+        let direction = onb.local(&Vec3::random_cosine_direction(rng));
+        let ray = Ray::new(surface.ray_origin(&direction), direction);
+        let mut hit_record = HitRecord::empty();
+        if !scene.world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            continue;
+        }
+        inverse_distances += 1.0 / (hit_record.t * ray.direction().length());
+        let emitted = scene.materials[hit_record.material].emitted(&ray, &hit_record);
+        radiance += path_trace(
+            rng,
+            &ray,
+            &*scene.world,
+            &scene.lights,
+            &scene.delta_lights,
+            &scene.materials,
+            &*scene.background,
+            None,
+            scene.caustics.as_ref(),
+            None,
+            None,
+            settings.transparent_shadows,
+            settings.bounce_limit.saturating_sub(1),
+        ) - emitted;
+    }
+
+    // The cosine of the irradiance is accounted for by the distribution of the directions,
+    // leaving the integral of the cosine over the hemisphere
+    let irradiance = PI * radiance / HEMISPHERE_SAMPLES as Float;
+    let radius = if inverse_distances > 0.0 {
+        HEMISPHERE_SAMPLES as Float / inverse_distances
+    } else {
+        Float::INFINITY
+    };
+    IrradianceRecord {
+        point: surface.point,
+        normal: surface.normal,
+        irradiance,
+        radius,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::BuiltinScene;
+
+    fn record(point: Point3, normal: Vec3) -> IrradianceRecord {
+        IrradianceRecord {
+            point,
+            normal,
+            irradiance: Color::new(1.0, 1.0, 1.0),
+            radius: 10.0,
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut cache = IrradianceCache {
+            records: Vec::new(),
+            cells: HashMap::new(),
+            cell_size: 5.0,
+        };
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        cache.insert(record(Point3::zero(), up));
+        cache.insert(IrradianceRecord {
+            irradiance: Color::new(3.0, 3.0, 3.0),
+            ..record(Point3::new(2.0, 0.0, 0.0), up)
+        });
+
+        // Halfway, both records weigh the same
+        let halfway = cache.irradiance(&Point3::new(1.0, 0.0, 0.0), &up).unwrap();
+        assert!((halfway - Color::new(2.0, 2.0, 2.0)).length() < 1e-5);
+        // Far from both, on a surface facing another way, or behind them, they don't apply
+        assert!(cache.irradiance(&Point3::new(6.0, 0.0, 0.0), &up).is_none());
+        let side = Vec3::new(1.0, 0.0, 0.0);
+        assert!(cache.irradiance(&Point3::zero(), &side).is_none());
+        let behind = Point3::new(0.0, -1.0, 0.0);
+        assert!(cache.irradiance(&behind, &up).is_none());
+    }
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 16,
+            image_height: 16,
+            samples_per_pixel: 64,
+            ..RenderSettings::default()
+        };
+        let mut scene = settings.scene.build(&settings);
+        let mean = |scene: &Scene| {
+            let mut sum = Color::zero();
+            for y in 0..settings.image_height {
+                for x in 0..settings.image_width {
+                    let index = y as usize * settings.image_width as usize + x as usize;
+                    for sample in 0..settings.samples_per_pixel as u32 {
+                        let mut rng = SampleRng::for_sample(settings.seed, index, sample);
+                        let (ray, _, _) = scene.camera_ray(&settings, x, y, sample, &mut rng);
+                        sum += scene.ray_color(&settings, &ray.unwrap(), &mut rng);
+                    }
+                }
+            }
+            let sample_count = settings.image_width as usize
+                * settings.image_height as usize
+                * settings.samples_per_pixel as usize;
+            (sum.x() + sum.y() + sum.z()) / (3 * sample_count) as Float
+        };
+
+        let expected = mean(&scene);
+        let cache = IrradianceCache::build(&scene, &settings);
+        assert!(!cache.is_empty());
+        scene.irradiance = Some(cache);
+        let cached = mean(&scene);
+        assert!(
+            (cached - expected).abs() < 0.05 * expected,
+            "{} != {}",
+            cached,
+            expected
+        );
+    }
+}
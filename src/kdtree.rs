@@ -0,0 +1,438 @@
+use crate::aabb::Aabb;
+use crate::accelerator::Accelerator;
+use crate::float::Float;
+use crate::object::{HitRecord, Hittable, ObjectId};
+use crate::ray::Ray;
+use crate::stats::STATS;
+use crate::vec3::Vec3;
+
+/// Cost of intersecting an object, relative to visiting a node.
+const INTERSECTION_COST: Float = 80.0;
+const TRAVERSAL_COST: Float = 1.0;
+/// Discount on splits leaving one side empty, which cull the rays through it for free.
+const EMPTY_BONUS: Float = 0.5;
+const MAX_OBJECTS_PER_LEAF: usize = 1;
+/// Bound on the depth of the tree, and so on the traversal stack.
+const MAX_DEPTH: usize = 64;
+/// Number of successive splits allowed to cost more than a leaf, hoping for better ones below.
+const MAX_BAD_REFINES: usize = 3;
+
+/// Node of the flattened tree, whose child below the split plane directly follows it.
+#[derive(Clone, Copy, Debug)]
+enum KdNode {
+    Interior {
+        axis: u8,
+        split: Float,
+        above_child: u32,
+    },
+    Leaf {
+        /// Range of `object_indices`.
+        first: u32,
+        count: u32,
+    },
+}
+
+/// Edge of the box of an object along the axis being split.
+#[derive(Clone, Copy)]
+struct BoundEdge {
+    t: Float,
+    start: bool,
+}
+
+/// kd-tree: binary space partition by axis-aligned planes chosen with the surface area
+/// heuristic.
+///
+/// Unlike the nodes of a BVH, the cells of the tree don't overlap, so rays visit them front to
+/// back and stop at the first cell containing a hit, at the price of objects straddling a
+/// plane being referenced on both sides. It suits scenes of many axis-aligned, evenly spread
+/// objects.
+pub struct KdTree {
+    bounds: Aabb,
+    nodes: Vec<KdNode>,
+    /// Objects of the leaves, concatenated.
+    object_indices: Vec<u32>,
+    /// Objects with their position in the list given to `new`.
+    objects: Vec<(ObjectId, Box<dyn Hittable>)>,
+    /// Objects without bounds, tested by every ray.
+    unbounded: Vec<(ObjectId, Box<dyn Hittable>)>,
+}
+
+impl KdTree {
+    pub fn new(objects: Vec<Box<dyn Hittable>>) -> KdTree {
+        let mut unbounded = Vec::new();
+        let mut bounded = Vec::new();
+        let mut object_bounds = Vec::new();
+        for (i, object) in objects.into_iter().enumerate() {
+            let id = ObjectId::new(i);
+            match object.bounding_box() {
+                Some(bounds) if bounds.is_empty() => {
+                    // Nothing to hit
+                }
+                Some(bounds) => {
+                    object_bounds.push(bounds);
+                    bounded.push((id, object));
+                }
+                None => unbounded.push((id, object)),
+            }
+        }
+
+        let bounds = object_bounds
+            .iter()
+            .fold(Aabb::empty(), |aabb, object| aabb.union(object));
+        let mut tree = KdTree {
+            bounds,
+            nodes: Vec::new(),
+            object_indices: Vec::new(),
+            objects: bounded,
+            unbounded,
+        };
+        if !tree.objects.is_empty() {
+            // Usual rule of thumb for the depth, 8 + 1.3 log2(n)
+            let max_depth = (8.0 + 1.3 * (tree.objects.len() as Float).log2()).round() as usize;
+            let indices = (0..tree.objects.len() as u32).collect();
+            tree.build(
+                &object_bounds,
+                bounds,
+                indices,
+                max_depth.min(MAX_DEPTH - 1),
+                0,
+            );
+        }
+        tree
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Appends the subtree of the objects `indices` within `node_bounds` to the nodes.
+    fn build(
+        &mut self,
+        object_bounds: &[Aabb],
+        node_bounds: Aabb,
+        indices: Vec<u32>,
+        depth_left: usize,
+        mut bad_refines: usize,
+    ) {
+        let node_index = self.nodes.len();
+        if indices.len() <= MAX_OBJECTS_PER_LEAF || depth_left == 0 {
+            self.push_leaf(&indices);
+            return;
+        }
+
+        let leaf_cost = INTERSECTION_COST * indices.len() as Float;
+        let (axis, split, cost) = match best_split(object_bounds, &node_bounds, &indices) {
+            Some(best) => best,
+            None => {
+                self.push_leaf(&indices);
+                return;
+            }
+        };
+        if cost > leaf_cost {
+            bad_refines += 1;
+        }
+        if (cost > 4.0 * leaf_cost && indices.len() < 16) || bad_refines == MAX_BAD_REFINES {
+            self.push_leaf(&indices);
+            return;
+        }
+
+        // Objects touching the plane go to the side they extend to, flat ones on it to both
+        let below: Vec<u32> = indices
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let b = &object_bounds[i as usize];
+                b.min()[axis] < split || b.max()[axis] <= split
+            })
+            .collect();
+        let above: Vec<u32> = indices
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let b = &object_bounds[i as usize];
+                b.max()[axis] > split || b.min()[axis] >= split
+            })
+            .collect();
+        drop(indices);
+
+        let (below_bounds, above_bounds) = split_box(&node_bounds, axis, split);
+        self.nodes.push(KdNode::Interior {
+            axis: axis as u8,
+            split,
+            above_child: 0,
+        });
+        self.build(
+            object_bounds,
+            below_bounds,
+            below,
+            depth_left - 1,
+            bad_refines,
+        );
+        let above_index = self.nodes.len() as u32;
+        self.build(
+            object_bounds,
+            above_bounds,
+            above,
+            depth_left - 1,
+            bad_refines,
+        );
+        if let KdNode::Interior {
+            ref mut above_child,
+            ..
+        } = self.nodes[node_index]
+        {
+            *above_child = above_index;
+        }
+    }
+
+    fn push_leaf(&mut self, indices: &[u32]) {
+        self.nodes.push(KdNode::Leaf {
+            first: self.object_indices.len() as u32,
+            count: indices.len() as u32,
+        });
+        self.object_indices.extend_from_slice(indices);
+    }
+}
+
+/// Split plane of least cost over the three axes, as (axis, position, cost).
+fn best_split(
+    object_bounds: &[Aabb],
+    node_bounds: &Aabb,
+    indices: &[u32],
+) -> Option<(usize, Float, Float)> {
+    let node_area = node_bounds.surface_area();
+    if node_area <= 0.0 {
+        return None;
+    }
+
+    let mut best: Option<(usize, Float, Float)> = None;
+    let mut edges = Vec::with_capacity(2 * indices.len());
+    for axis in 0..3 {
+        edges.clear();
+        for &i in indices {
+            let b = &object_bounds[i as usize];
+            edges.push(BoundEdge {
+                t: b.min()[axis],
+                start: true,
+            });
+            edges.push(BoundEdge {
+                t: b.max()[axis],
+                start: false,
+            });
+        }
+        // Starts before ends at the same position, so that flat objects count on both sides
+        edges.sort_unstable_by(|a, b| a.t.total_cmp(&b.t).then(b.start.cmp(&a.start)));
+
+        let (mut below_count, mut above_count) = (0, indices.len());
+        for edge in &edges {
+            if !edge.start {
+                above_count -= 1;
+            }
+            if edge.t > node_bounds.min()[axis] && edge.t < node_bounds.max()[axis] {
+                let (below_bounds, above_bounds) = split_box(node_bounds, axis, edge.t);
+                let below_probability = below_bounds.surface_area() / node_area;
+                let above_probability = above_bounds.surface_area() / node_area;
+                let bonus = if below_count == 0 || above_count == 0 {
+                    EMPTY_BONUS
+                } else {
+                    0.0
+                };
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (1.0 - bonus)
+                        * (below_probability * below_count as Float
+                            + above_probability * above_count as Float);
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, edge.t, cost));
+                }
+            }
+            if edge.start {
+                below_count += 1;
+            }
+        }
+    }
+    best
+}
+
+/// Halves of `aabb` on each side of the plane at `split` along `axis`.
+fn split_box(aabb: &Aabb, axis: usize, split: Float) -> (Aabb, Aabb) {
+    let with_axis = |p: Vec3, value: Float| match axis {
+        0 => Vec3::new(value, p.y(), p.z()),
+        1 => Vec3::new(p.x(), value, p.z()),
+        _ => Vec3::new(p.x(), p.y(), value),
+    };
+    (
+        Aabb::new(aabb.min(), with_axis(aabb.max(), split)),
+        Aabb::new(with_axis(aabb.min(), split), aabb.max()),
+    )
+}
+
+impl Hittable for KdTree {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut tmp_hit_record = HitRecord::empty();
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+
+        STATS.add_primitive_tests(self.unbounded.len() as u64);
+        for (id, object) in &self.unbounded {
+            if object.hit(ray, t_min, closest_so_far, &mut tmp_hit_record) {
+                hit_anything = true;
+                closest_so_far = tmp_hit_record.t;
+                *hit_record = tmp_hit_record;
+                hit_record.object = *id;
+            }
+        }
+        if self.nodes.is_empty() {
+            return hit_anything;
+        }
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inverse_direction = Vec3::new(
+            1.0 / direction.x(),
+            1.0 / direction.y(),
+            1.0 / direction.z(),
+        );
+        let (mut node_t_min, mut node_t_max) =
+            match self
+                .bounds
+                .clip(ray, &inverse_direction, t_min, closest_so_far)
+            {
+                Some(interval) => interval,
+                None => return hit_anything,
+            };
+
+        // Cells still to visit, with the part of the ray within them
+        let mut stack = [(0, 0.0, 0.0); MAX_DEPTH];
+        let mut stack_len = 0;
+        let mut node_index = 0;
+        let mut node_traversals = 0;
+        let mut primitive_tests = 0;
+        loop {
+            // Cells are visited in order, none beyond a hit can hold a closer one
+            if closest_so_far < node_t_min {
+                break;
+            }
+
+            node_traversals += 1;
+            match self.nodes[node_index] {
+                KdNode::Interior {
+                    axis,
+                    split,
+                    above_child,
+                } => {
+                    let axis = axis as usize;
+                    let t_plane = (split - origin[axis]) * inverse_direction[axis];
+                    let below_first =
+                        origin[axis] < split || (origin[axis] == split && direction[axis] <= 0.0);
+                    let (first, second) = if below_first {
+                        (node_index + 1, above_child as usize)
+                    } else {
+                        (above_child as usize, node_index + 1)
+                    };
+
+                    if t_plane > node_t_max || t_plane <= 0.0 {
+                        node_index = first;
+                    } else if t_plane < node_t_min {
+                        node_index = second;
+                    } else {
+                        stack[stack_len] = (second, t_plane, node_t_max);
+                        stack_len += 1;
+                        node_index = first;
+                        node_t_max = t_plane;
+                    }
+                    continue;
+                }
+                KdNode::Leaf { first, count } => {
+                    let indices = &self.object_indices[first as usize..(first + count) as usize];
+                    primitive_tests += count as u64;
+                    for &i in indices {
+                        let (id, object) = &self.objects[i as usize];
+                        if object.hit(ray, t_min, closest_so_far, &mut tmp_hit_record) {
+                            hit_anything = true;
+                            closest_so_far = tmp_hit_record.t;
+                            *hit_record = tmp_hit_record;
+                            hit_record.object = *id;
+                        }
+                    }
+                }
+            }
+
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            let (next, next_t_min, next_t_max) = stack[stack_len];
+            node_index = next;
+            node_t_min = next_t_min;
+            node_t_max = next_t_max;
+        }
+
+        STATS.add_node_traversals(node_traversals);
+        STATS.add_primitive_tests(primitive_tests);
+        hit_anything
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        Some(self.bounds)
+    }
+}
+
+impl Accelerator for KdTree {
+    fn len(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &dyn Hittable> + '_> {
+        Box::new(
+            self.objects
+                .iter()
+                .chain(&self.unbounded)
+                .map(|(_, object)| object.as_ref()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialId;
+    use crate::object::HittableList;
+    use crate::sphere::Sphere;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_same_hits_as_list() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut list = HittableList::new();
+        let mut objects: Vec<Box<dyn Hittable>> = Vec::new();
+        for _ in 0..300 {
+            let center = Vec3::random_range(&mut rng, -10.0, 10.0);
+            let radius = if rng.gen::<Float>() < 0.05 { 3.0 } else { 0.3 };
+            let sphere = Sphere::new(center, radius, MaterialId::default());
+            list.add(Box::new(sphere.clone()));
+            objects.push(Box::new(sphere));
+        }
+        let tree = KdTree::new(objects);
+        assert_eq!(tree.len(), 300);
+        assert!(tree.node_count() > 1);
+
+        for _ in 0..1000 {
+            let ray = Ray::new(
+                Vec3::random_range(&mut rng, -15.0, 15.0),
+                Vec3::random_in_unit_sphere(&mut rng),
+            );
+            let mut expected = HitRecord::empty();
+            let hit = list.hit(&ray, 0.001, Float::MAX, &mut expected);
+            let mut hit_record = HitRecord::empty();
+            assert_eq!(tree.hit(&ray, 0.001, Float::MAX, &mut hit_record), hit);
+            if hit {
+                assert_eq!(hit_record.t, expected.t);
+            }
+        }
+    }
+}
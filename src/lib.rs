@@ -0,0 +1,72 @@
+pub mod aabb;
+pub mod accelerator;
+pub mod animation;
+pub mod aov;
+pub mod assets;
+pub mod background;
+pub mod bdpt;
+pub mod bloom;
+pub mod bvh;
+pub mod camera;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod checkpoint;
+pub mod csg;
+pub mod curve;
+pub mod cutout;
+pub mod cylinder;
+pub mod deep;
+pub mod disk;
+pub mod distributed;
+pub mod filter;
+pub mod float;
+pub mod fog;
+pub mod framebuffer;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod guiding;
+pub mod instance;
+pub mod integrator;
+pub mod irradiance_cache;
+pub mod kdtree;
+pub mod light;
+pub mod light_bvh;
+pub mod material;
+pub mod medium;
+pub mod mesh;
+pub mod microfacet;
+pub mod nanovdb;
+pub mod noise;
+pub mod object;
+pub mod onb;
+pub mod output;
+pub mod parallel;
+pub mod pdf;
+pub mod photon_map;
+pub mod primitive;
+pub mod progress;
+pub mod ray;
+pub mod rect;
+pub mod renderer;
+pub mod rng;
+pub mod sampler;
+pub mod scene;
+pub mod scene_file;
+pub mod scene_graph;
+pub mod scenes;
+pub mod sdf;
+pub mod settings;
+pub mod sphere;
+pub mod sphere_set;
+pub mod sppm;
+pub mod stats;
+pub mod texture;
+pub mod tonemap;
+pub mod torus;
+pub mod transform;
+pub mod util;
+pub mod vec3;
+#[cfg(feature = "viewer")]
+pub mod viewer;
+pub mod volume;
+pub mod voxel;
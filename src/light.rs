@@ -0,0 +1,243 @@
+use crate::float::Float;
+use crate::material::{MaterialList, Scatterable};
+use crate::object::{HitRecord, Hittable};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use rand::Rng;
+
+/// Light without an area, which rays can't hit: it only contributes through the direct
+/// lighting of the surfaces it illuminates.
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Point(PointLight),
+    Directional(DirectionalLight),
+    Spot(SpotLight),
+}
+
+/// Light arriving at a point from a `Light`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightSample {
+    /// Unit direction from the point towards the light.
+    pub direction: Vec3,
+    /// Distance to the light, infinite for directional lights.
+    pub distance: Float,
+    /// Irradiance on a surface facing the light.
+    pub irradiance: Color,
+}
+
+impl Light {
+    /// Light received at `point`, None if it isn't lit at all.
+    pub fn illuminate(&self, point: &Point3) -> Option<LightSample> {
+        match *self {
+            Light::Point(ref inner) => inner.illuminate(point),
+            Light::Directional(ref inner) => inner.illuminate(point),
+            Light::Spot(ref inner) => inner.illuminate(point),
+        }
+    }
+}
+
+// -------
+//  POINT
+// -------
+
+/// Light emitting `intensity` equally in all directions from `position`.
+#[derive(Clone, Copy, Debug)]
+pub struct PointLight {
+    position: Point3,
+    intensity: Color,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, intensity: Color) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+        }
+    }
+
+    pub fn illuminate(&self, point: &Point3) -> Option<LightSample> {
+        let to_light = self.position - *point;
+        let distance_squared = to_light.length_squared();
+        if distance_squared == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: unit_vector(to_light),
+            distance: distance_squared.sqrt(),
+            irradiance: self.intensity / distance_squared,
+        })
+    }
+}
+
+// -------------
+//  DIRECTIONAL
+// -------------
+
+/// Infinitely distant light, such as the sun, with parallel rays travelling along `direction`.
+#[derive(Clone, Copy, Debug)]
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Color,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Color) -> DirectionalLight {
+        DirectionalLight {
+            direction: unit_vector(direction),
+            irradiance,
+        }
+    }
+
+    pub fn illuminate(&self, _point: &Point3) -> Option<LightSample> {
+        Some(LightSample {
+            direction: -self.direction,
+            distance: Float::INFINITY,
+            irradiance: self.irradiance,
+        })
+    }
+}
+
+// ------
+//  SPOT
+// ------
+
+/// Point light restricted to a cone around `direction`. The intensity is full up to
+/// `inner_angle_deg` from the axis, then fades smoothly to nothing at `outer_angle_deg`.
+#[derive(Clone, Copy, Debug)]
+pub struct SpotLight {
+    position: Point3,
+    direction: Vec3,
+    intensity: Color,
+    cos_inner: Float,
+    cos_outer: Float,
+}
+
+impl SpotLight {
+    pub fn new(
+        position: Point3,
+        direction: Vec3,
+        intensity: Color,
+        inner_angle_deg: Float,
+        outer_angle_deg: Float,
+    ) -> SpotLight {
+        assert!(inner_angle_deg <= outer_angle_deg);
+        SpotLight {
+            position,
+            direction: unit_vector(direction),
+            intensity,
+            cos_inner: inner_angle_deg.to_radians().cos(),
+            cos_outer: outer_angle_deg.to_radians().cos(),
+        }
+    }
+
+    pub fn illuminate(&self, point: &Point3) -> Option<LightSample> {
+        let to_light = self.position - *point;
+        let distance_squared = to_light.length_squared();
+        if distance_squared == 0.0 {
+            return None;
+        }
+        let direction = unit_vector(to_light);
+
+        let cos_angle = -direction.dot(&self.direction);
+        if cos_angle <= self.cos_outer {
+            return None;
+        }
+        let falloff = if cos_angle >= self.cos_inner {
+            1.0
+        } else {
+            let t = (cos_angle - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        };
+
+        Some(LightSample {
+            direction,
+            distance: distance_squared.sqrt(),
+            irradiance: falloff * self.intensity / distance_squared,
+        })
+    }
+}
+
+// ----------
+//  EMISSION
+// ----------
+
+/// Light leaving a random point of an object sampled as a light.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Emission {
+    /// Ray leaving the point, out of reach of the light, in a cosine-weighted direction around
+    /// the normal.
+    pub ray: Ray,
+    /// Unit outward normal of the light at the point.
+    pub normal: Vec3,
+    /// Density, with respect to area, of choosing the light then the point on it.
+    pub pdf: Float,
+    /// Radiance leaving the point along the ray.
+    pub radiance: Color,
+}
+
+/// Samples the light leaving one of the `lights`, chosen uniformly. None if the light can't
+/// sample its surface or doesn't emit in the sampled direction.
+pub(crate) fn sample_emission(
+    lights: &[&dyn Hittable],
+    materials: &MaterialList,
+    rng: &mut SampleRng,
+) -> Option<Emission> {
+    if lights.is_empty() {
+        return None;
+    }
+    let light = lights[rng.gen_range(0..lights.len())];
+    let sample = light.sample_surface(rng)?;
+    let direction = Onb::build_from_w(&sample.normal).local(&Vec3::random_cosine_direction(rng));
+
+    // Radiance leaving the light in that direction, as seen from it
+    let towards_light = Ray::new(sample.point + direction, -direction);
+    let mut light_record = HitRecord::empty();
+    if !light.hit(&towards_light, 0.5, 1.5, &mut light_record) {
+        return None;
+    }
+    let radiance = materials[light_record.material].emitted(&towards_light, &light_record);
+    if radiance == Color::zero() {
+        return None;
+    }
+
+    Some(Emission {
+        ray: Ray::new(light_record.ray_origin(&direction), direction),
+        normal: sample.normal,
+        pdf: sample.pdf / lights.len() as Float,
+        radiance,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_falloff() {
+        let light = PointLight::new(Point3::new(0.0, 2.0, 0.0), Color::new(4.0, 4.0, 4.0));
+        let sample = light.illuminate(&Point3::zero()).unwrap();
+        assert_eq!(sample.direction, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(sample.distance, 2.0);
+        assert_eq!(sample.irradiance, Color::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_spot_cone() {
+        let light = SpotLight::new(
+            Point3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Color::new(1.0, 1.0, 1.0),
+            20.0,
+            40.0,
+        );
+        // On the axis, between the two angles (30 degrees) and outside of the cone
+        let full = light.illuminate(&Point3::zero()).unwrap().irradiance;
+        assert_eq!(full, Color::new(1.0, 1.0, 1.0));
+        let x = (30.0 as Float).to_radians().tan();
+        let fading = light.illuminate(&Point3::new(x, 0.0, 0.0)).unwrap();
+        assert!(fading.irradiance.x() > 0.0 && fading.irradiance.x() < full.x());
+        assert!(light.illuminate(&Point3::new(1.0, 0.0, 0.0)).is_none());
+    }
+}
@@ -0,0 +1,536 @@
+//! Tree over the lights of the scene, for picking the light to sample at a point according to
+//! how much light it could send there.
+//!
+//! Picking one of the lights uniformly wastes most shadow rays once there are many: the few
+//! lights near the point, or much brighter than the others, bring most of its light. Each node
+//! of the tree bounds the position, power and orientation of its lights, from which the
+//! importance of each child for a point is estimated. Sampling walks down the tree choosing the
+//! children in proportion to their importance, which is a logarithmic rather than linear cost in
+//! the number of lights.
+
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::material::{MaterialList, Scatterable};
+use crate::object::{HitRecord, Hittable, HittableList, ObjectId};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::tonemap::luminance;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use rand::Rng;
+
+/// Points sampled on each light to estimate its power and orientation.
+const POWER_SAMPLES: u32 = 16;
+
+/// Lights sampled in proportion to an estimate of the light each sends to the point being lit.
+///
+/// Lights without bounds are left out of the tree and picked uniformly, as one light among the
+/// others.
+#[derive(Default)]
+pub struct LightBvh {
+    lights: Vec<Box<dyn Hittable>>,
+    /// Flattened tree over the bounded lights, empty if there are none.
+    nodes: Vec<LightNode>,
+    unbounded: Vec<usize>,
+}
+
+/// Node of the flattened tree, whose first child directly follows it.
+#[derive(Clone, Copy, Debug)]
+struct LightNode {
+    bounds: LightBounds,
+    /// Index of the light of leaves, of the second child of interior nodes.
+    offset: u32,
+    is_leaf: bool,
+}
+
+/// What is known of the light sent by a group of lights, to bound how much of it reaches a
+/// point. The lights emit from the front of their surface, on one side of their normals.
+#[derive(Clone, Copy, Debug)]
+struct LightBounds {
+    bounds: Aabb,
+    /// Power emitted, up to a constant factor shared by all the lights.
+    power: Float,
+    /// Cone containing the normals of the lights.
+    normals: DirectionCone,
+}
+
+/// Unit directions making an angle of at most acos(`cos_theta`) with `axis`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DirectionCone {
+    axis: Vec3,
+    cos_theta: Float,
+}
+
+impl LightBvh {
+    /// Builds the tree over `lights`, evaluating their emission with the `materials`.
+    pub fn new(lights: HittableList, materials: &MaterialList) -> LightBvh {
+        let lights = lights.into_objects();
+        let mut unbounded = Vec::new();
+        let mut bounded = Vec::new();
+        for (index, light) in lights.iter().enumerate() {
+            match light.bounding_box() {
+                Some(bounds) => bounded.push((index, bounds, estimate_bounds(&**light, materials))),
+                None => unbounded.push(index),
+            }
+        }
+
+        // Lights whose power can't be estimated are as bright as the others on average
+        let (known_power, known_count) = bounded
+            .iter()
+            .filter_map(|(_, _, estimate)| estimate.map(|(_, power)| power))
+            .fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+        let default_power = if known_count > 0 {
+            known_power / known_count as Float
+        } else {
+            1.0
+        };
+        let mut leaves: Vec<(usize, LightBounds)> = bounded
+            .into_iter()
+            .map(|(index, bounds, estimate)| {
+                let (normals, power) =
+                    estimate.unwrap_or_else(|| (DirectionCone::entire(), default_power));
+                let light_bounds = LightBounds {
+                    bounds,
+                    power,
+                    normals,
+                };
+                (index, light_bounds)
+            })
+            .collect();
+
+        let mut nodes = Vec::with_capacity(2 * leaves.len());
+        if !leaves.is_empty() {
+            build(&mut leaves, &mut nodes);
+        }
+        LightBvh {
+            lights,
+            nodes,
+            unbounded,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Hittable> {
+        self.lights.iter().map(|light| light.as_ref())
+    }
+
+    /// Probability of picking the tree rather than one of the unbounded lights.
+    fn tree_probability(&self) -> Float {
+        (self.lights.len() - self.unbounded.len()) as Float / self.lights.len() as Float
+    }
+
+    /// Probability of picking the first child of the interior node `index` to light `point`.
+    fn first_child_probability(&self, index: usize, point: &Point3) -> Float {
+        let first = self.nodes[index + 1].bounds.importance(point);
+        let second = self.nodes[self.nodes[index].offset as usize]
+            .bounds
+            .importance(point);
+        if first + second == 0.0 {
+            // Nothing known to reach the point, no reason to prefer either child
+            return 0.5;
+        }
+        first / (first + second)
+    }
+
+    /// Density of sampling `direction` from `origin` through the lights below the node `index`,
+    /// picked with probability `probability`.
+    fn node_pdf_value(
+        &self,
+        index: usize,
+        probability: Float,
+        origin: &Point3,
+        direction: &Vec3,
+        inverse_direction: &Vec3,
+    ) -> Float {
+        let node = &self.nodes[index];
+        let ray = Ray::new(*origin, *direction);
+        if probability == 0.0
+            || !node
+                .bounds
+                .bounds
+                .hit(&ray, inverse_direction, 0.0, Float::MAX)
+        {
+            return 0.0;
+        }
+        if node.is_leaf {
+            return probability * self.lights[node.offset as usize].pdf_value(origin, direction);
+        }
+        let first = self.first_child_probability(index, origin);
+        self.node_pdf_value(
+            index + 1,
+            probability * first,
+            origin,
+            direction,
+            inverse_direction,
+        ) + self.node_pdf_value(
+            node.offset as usize,
+            probability * (1.0 - first),
+            origin,
+            direction,
+            inverse_direction,
+        )
+    }
+}
+
+impl Hittable for LightBvh {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+        for (i, light) in self.lights.iter().enumerate() {
+            if light.hit(ray, t_min, closest_so_far, hit_record) {
+                hit_anything = true;
+                closest_so_far = hit_record.t;
+                hit_record.object = ObjectId::new(i);
+            }
+        }
+        hit_anything
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        Some(
+            self.nodes
+                .first()
+                .map_or_else(Aabb::empty, |root| root.bounds.bounds),
+        )
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let unbounded_pdf: Float = self
+            .unbounded
+            .iter()
+            .map(|&index| self.lights[index].pdf_value(origin, direction))
+            .sum();
+        let tree_pdf = if self.nodes.is_empty() {
+            0.0
+        } else {
+            let inverse_direction = Vec3::new(
+                1.0 / direction.x(),
+                1.0 / direction.y(),
+                1.0 / direction.z(),
+            );
+            self.node_pdf_value(
+                0,
+                self.tree_probability(),
+                origin,
+                direction,
+                &inverse_direction,
+            )
+        };
+        unbounded_pdf / self.lights.len() as Float + tree_pdf
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        if self.lights.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
+        }
+        if self.nodes.is_empty()
+            || (!self.unbounded.is_empty() && rng.gen::<Float>() >= self.tree_probability())
+        {
+            let index = self.unbounded[rng.gen_range(0..self.unbounded.len())];
+            return self.lights[index].random(origin, rng);
+        }
+
+        let mut index = 0;
+        while !self.nodes[index].is_leaf {
+            index = if rng.gen::<Float>() < self.first_child_probability(index, origin) {
+                index + 1
+            } else {
+                self.nodes[index].offset as usize
+            };
+        }
+        self.lights[self.nodes[index].offset as usize].random(origin, rng)
+    }
+}
+
+/// Builds the subtree over `leaves` at the end of `nodes`, splitting them where the sum over
+/// both sides of the power times the surface area is the lowest, to keep the bright lights and
+/// the close lights together.
+fn build(leaves: &mut [(usize, LightBounds)], nodes: &mut Vec<LightNode>) {
+    let bounds = leaves[1..]
+        .iter()
+        .fold(leaves[0].1, |bounds, (_, leaf)| bounds.union(leaf));
+    let index = nodes.len();
+    nodes.push(LightNode {
+        bounds,
+        offset: leaves[0].0 as u32,
+        is_leaf: true,
+    });
+    if leaves.len() == 1 {
+        return;
+    }
+
+    let centroids = leaves.iter().fold(Aabb::empty(), |centroids, (_, leaf)| {
+        centroids.grow(&leaf.bounds.centroid())
+    });
+    let axis = centroids.longest_axis();
+    leaves.sort_unstable_by(|(_, a), (_, b)| {
+        a.bounds.centroid()[axis].total_cmp(&b.bounds.centroid()[axis])
+    });
+
+    // Cost of the lights before each split, then of the lights after it
+    let cost = |bounds: &LightBounds| bounds.power * bounds.bounds.surface_area();
+    let mut before = Vec::with_capacity(leaves.len());
+    let mut accumulated = leaves[0].1;
+    for (_, leaf) in &leaves[1..] {
+        before.push(cost(&accumulated));
+        accumulated = accumulated.union(leaf);
+    }
+    let mut after = vec![0.0; leaves.len() - 1];
+    let mut accumulated = leaves[leaves.len() - 1].1;
+    for split in (0..leaves.len() - 1).rev() {
+        after[split] = cost(&accumulated);
+        accumulated = accumulated.union(&leaves[split].1);
+    }
+    // Ties, such as lights without power, are split in the middle
+    let middle = leaves.len() / 2;
+    let split = (1..leaves.len())
+        .min_by(|&a, &b| {
+            let cost = |split: usize| before[split - 1] + after[split - 1];
+            cost(a)
+                .total_cmp(&cost(b))
+                .then(a.abs_diff(middle).cmp(&b.abs_diff(middle)))
+        })
+        .unwrap();
+
+    let (first, second) = leaves.split_at_mut(split);
+    build(first, nodes);
+    nodes[index].offset = nodes.len() as u32;
+    nodes[index].is_leaf = false;
+    build(second, nodes);
+}
+
+/// Cone containing the normals of `light` and its power, estimated from a few points of its
+/// surface. None if it can't sample its surface.
+fn estimate_bounds(
+    light: &dyn Hittable,
+    materials: &MaterialList,
+) -> Option<(DirectionCone, Float)> {
+    // The same estimate from one run to the next
+    let mut rng = SampleRng::new(0);
+    let mut power = 0.0;
+    let mut normals: Option<DirectionCone> = None;
+    for _ in 0..POWER_SAMPLES {
+        let sample = light.sample_surface(&mut rng)?;
+
+        // Radiance leaving the light along its normal, as seen from it
+        let towards_light = Ray::new(sample.point + sample.normal, -sample.normal);
+        let mut light_record = HitRecord::empty();
+        if light.hit(&towards_light, 0.5, 1.5, &mut light_record) {
+            let radiance = materials[light_record.material].emitted(&towards_light, &light_record);
+            power += luminance(radiance) / sample.pdf;
+        }
+
+        normals = Some(match normals {
+            Some(cone) if cone.axis.dot(&sample.normal) > 1.0 - 1e-4 => cone,
+            Some(_) => DirectionCone::entire(),
+            None => DirectionCone::new(sample.normal, 1.0),
+        });
+    }
+    // Radiance times the area, the integral over directions bringing the same factor of PI to
+    // all the lights
+    let power = power / POWER_SAMPLES as Float;
+    if !power.is_finite() {
+        return None;
+    }
+    normals.map(|normals| (normals, power))
+}
+
+impl LightBounds {
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        // Lights which don't emit don't widen the cone of the others
+        let normals = if self.power == 0.0 {
+            other.normals
+        } else if other.power == 0.0 {
+            self.normals
+        } else {
+            self.normals.union(&other.normals)
+        };
+        LightBounds {
+            bounds: self.bounds.union(&other.bounds),
+            power: self.power + other.power,
+            normals,
+        }
+    }
+
+    /// Bound on the light reaching `point`, up to the same factor for all the nodes: the power
+    /// over the squared distance, times the cosine of the smallest angle any of the lights
+    /// could make with the direction of the point.
+    fn importance(&self, point: &Point3) -> Float {
+        if self.power == 0.0 {
+            return 0.0;
+        }
+        let center = self.bounds.centroid();
+        let radius_squared = 0.25 * self.bounds.extent().length_squared();
+        let to_point = *point - center;
+        let distance_squared = to_point.length_squared();
+        if distance_squared <= radius_squared {
+            // Inside the bounding sphere, the lights could face the point from up close
+            return self.power / radius_squared.max(Float::MIN_POSITIVE);
+        }
+
+        // Smallest angle between a normal of the cone and a direction from the bounding
+        // sphere towards the point
+        let cos_w = self
+            .normals
+            .axis
+            .dot(&unit_vector(to_point))
+            .clamp(-1.0, 1.0);
+        let theta_w = cos_w.acos();
+        let theta_o = self.normals.cos_theta.acos();
+        let theta_b = (radius_squared / distance_squared).sqrt().asin();
+        let theta = (theta_w - theta_o - theta_b).max(0.0);
+        if theta >= 0.5 * PI {
+            return 0.0;
+        }
+        self.power * theta.cos() / distance_squared
+    }
+}
+
+impl DirectionCone {
+    /// All the directions.
+    fn entire() -> DirectionCone {
+        DirectionCone::new(Vec3::new(0.0, 0.0, 1.0), -1.0)
+    }
+
+    fn new(axis: Vec3, cos_theta: Float) -> DirectionCone {
+        DirectionCone {
+            axis: unit_vector(axis),
+            cos_theta,
+        }
+    }
+
+    /// Smallest cone containing both cones.
+    fn union(&self, other: &DirectionCone) -> DirectionCone {
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = self.axis.dot(&other.axis).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return *self;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return *other;
+        }
+
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        let rotation_axis = self.axis.cross(&other.axis);
+        if theta_o >= PI || rotation_axis.length_squared() == 0.0 {
+            return DirectionCone::entire();
+        }
+        // Rotates the axis of this cone towards the other one, which the rotation axis is
+        // orthogonal to
+        let theta_r = theta_o - theta_a;
+        let towards_other = unit_vector(rotation_axis).cross(&self.axis);
+        let axis = theta_r.cos() * self.axis + theta_r.sin() * towards_other;
+        DirectionCone::new(axis, theta_o.cos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{DiffuseLight, Material};
+    use crate::rect::Rect;
+    use crate::sphere::Sphere;
+    use crate::vec3::Color;
+
+    /// Row of small lights of increasing brightness along the x axis, facing down.
+    fn light_row(count: usize) -> (HittableList, MaterialList) {
+        let mut lights = HittableList::new();
+        let mut materials = MaterialList::new();
+        for i in 0..count {
+            let emit = (i + 1) as Float;
+            let light = materials.add(Material::DiffuseLight(DiffuseLight::new(Color::new(
+                emit, emit, emit,
+            ))));
+            lights.add(Box::new(Rect::new(
+                Point3::new(2.0 * i as Float, 1.0, 0.0),
+                Vec3::new(0.5, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.5),
+                light,
+            )));
+        }
+        (lights, materials)
+    }
+
+    #[test]
+    fn test_cone_union() {
+        let x = DirectionCone::new(Vec3::new(1.0, 0.0, 0.0), 1.0);
+        let y = DirectionCone::new(Vec3::new(0.0, 1.0, 0.0), 1.0);
+        let union = x.union(&y);
+        let diagonal = unit_vector(Vec3::new(1.0, 1.0, 0.0));
+        assert!((union.axis - diagonal).length() < 1e-5);
+        assert!((union.cos_theta - (0.25 * PI).cos()).abs() < 1e-5);
+        assert_eq!(x.union(&DirectionCone::entire()), DirectionCone::entire());
+        assert_eq!(
+            x.union(&DirectionCone::new(-x.axis, 1.0)),
+            DirectionCone::entire()
+        );
+    }
+
+    #[test]
+    fn test_pdf_matches_sampling() {
+        // Lights facing down, and a sphere lighting all around it
+        let (mut lights, mut materials) = light_row(8);
+        let light = materials.add(Material::DiffuseLight(DiffuseLight::new(Color::new(
+            2.0, 2.0, 2.0,
+        ))));
+        lights.add(Box::new(Sphere::new(
+            Point3::new(3.0, 3.0, 1.0),
+            0.5,
+            light,
+        )));
+        let bvh = LightBvh::new(lights, &materials);
+        let origin = Point3::new(5.0, 0.0, 0.3);
+
+        // The density integrates to one over the directions...
+        let mut rng = SampleRng::new(0);
+        let sample_count = 200_000;
+        let integral: Float = (0..sample_count)
+            .map(|_| {
+                let direction = Vec3::random_unit_vector(&mut rng);
+                bvh.pdf_value(&origin, &direction) * 4.0 * PI
+            })
+            .sum::<Float>()
+            / sample_count as Float;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+
+        // ...and the directions sampled all have a density
+        for _ in 0..1000 {
+            let direction = bvh.random(&origin, &mut rng);
+            assert!(bvh.pdf_value(&origin, &direction) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_prefers_close_and_bright_lights() {
+        let (lights, materials) = light_row(8);
+        let bvh = LightBvh::new(lights, &materials);
+        let mut rng = SampleRng::new(0);
+
+        // Right below the brightest light, most samples go to it rather than to the others
+        let origin = Point3::new(14.25, 0.0, 0.25);
+        let sample_count = 10_000;
+        let towards_brightest = (0..sample_count)
+            .filter(|_| bvh.random(&origin, &mut rng).x().abs() < 0.5)
+            .count();
+        assert!(towards_brightest as Float > 0.5 * sample_count as Float);
+
+        // Above the lights, which face down, none of them is worth sampling
+        assert_eq!(
+            bvh.nodes[0].bounds.importance(&Point3::new(7.0, 20.0, 0.0)),
+            0.0
+        );
+    }
+}
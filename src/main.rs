@@ -1,190 +1,1266 @@
-mod camera;
-mod material;
-mod object;
-mod ray;
-mod sphere;
-mod util;
-mod vec3;
-
-use crate::camera::Camera;
-use crate::material::{Dielectric, Lambertian, Material, Metal, Scatterable};
-use crate::object::{HitRecord, Hittable, HittableList};
-use crate::ray::Ray;
-use crate::sphere::Sphere;
-use crate::util::clamp;
-use crate::vec3::{unit_vector, Color, Point3, Vec3};
-use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
-use rand::rngs::ThreadRng;
-use rand::Rng;
-use std::fs::File;
-use std::io::Write;
-
-pub const ASPECT_RATIO: f32 = 3.0 / 2.0;
-pub const IMAGE_WIDTH: u16 = 1200;
-pub const IMAGE_HEIGHT: u16 = ((IMAGE_WIDTH as f32) / ASPECT_RATIO) as u16;
-
-pub const SAMPLES_PER_PIXEL: u16 = 500;
-pub const BOUNCE_LIMIT: u16 = 50;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use rust_ray_tracing::accelerator::AcceleratorType;
+use rust_ray_tracing::animation::FrameSeed;
+use rust_ray_tracing::aov::{heatmap, AovBuffers, AovSample};
+use rust_ray_tracing::bloom::Bloom;
+use rust_ray_tracing::bvh::BvhSplit;
+use rust_ray_tracing::camera::Camera;
+use rust_ray_tracing::checkpoint;
+use rust_ray_tracing::deep::{self, DeepImage};
+use rust_ray_tracing::distributed::{self, split_into_tiles, Tile, TILE_SIZE};
+use rust_ray_tracing::filter::Filter;
+use rust_ray_tracing::float::{Float, PI};
+use rust_ray_tracing::fog::HeightFog;
+use rust_ray_tracing::framebuffer::{Framebuffer, TileSamples};
+use rust_ray_tracing::guiding;
+use rust_ray_tracing::integrator::Integrator;
+use rust_ray_tracing::output::{
+    post_process, save_image, save_object_ids, Dither, FfmpegPipe, ImageWriter, OutputFormat, Pfm,
+    Png, Png16, Ppm,
+};
+use rust_ray_tracing::parallel;
+use rust_ray_tracing::progress::{Progress, ProgressFormat, ProgressUnit};
+use rust_ray_tracing::rng::SampleRng;
+use rust_ray_tracing::sampler::Sampler;
+use rust_ray_tracing::scene::Scene;
+use rust_ray_tracing::scene_file::SceneFile;
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::{RenderSettings, PHOTONS_PER_ITERATION, SAMPLES_PER_PIXEL};
+use rust_ray_tracing::sppm;
+use rust_ray_tracing::stats::STATS;
+use rust_ray_tracing::tonemap::{Exposure, ToneMapper, TransferFunction};
+use rust_ray_tracing::vec3::Color;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Prints a message about the render, to the standard error when the standard output carries
+/// the JSON progress, so that it only holds JSON lines.
+macro_rules! status {
+    ($settings:expr, $($arg:tt)*) => {
+        if $settings.progress == ProgressFormat::Json {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Parser)]
+#[command(about = "Ray Tracing in One Weekend, in Rust")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render the final image, or the frames of an animation
+    Render(RenderArgs),
+    /// Render a quick noisy image, with a few samples per pixel
+    Preview(RenderArgs),
+    /// Time the rendering of the built-in scenes
+    Bench(BenchArgs),
+    /// Check a scene file for errors, without rendering it
+    Validate {
+        /// Scene file to check
+        path: PathBuf,
+    },
+}
+
+#[derive(clap::Args)]
+#[command(group(clap::ArgGroup::new("animation").args(["frames", "turntable"])))]
+struct RenderArgs {
+    /// Scene to render: random-spheres, cornell-box, three-spheres, checkered-ground,
+    /// smoke-box, final-next-week or fractals
+    #[arg(long, default_value = "random-spheres")]
+    scene: BuiltinScene,
+
+    /// Render the scene described in this file instead of a built-in one
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["coordinator", "worker"])]
+    scene_file: Option<PathBuf>,
+
+    /// Render the scene file again each time it changes, first with a few samples per pixel
+    #[arg(long, requires = "scene_file", conflicts_with_all = ["resume", "gpu", "aovs", "deep"])]
+    watch: bool,
+
+    /// Rendering algorithm: path, sppm, bdpt, debug-normals, debug-depth, debug-bounces, or
+    /// debug-nodes and debug-primitives for heatmaps of the work of finding the first hits
+    #[arg(long, default_value = "path")]
+    integrator: Integrator,
+
+    /// Positions of the samples within the pixels: random, halton, sobol or blue-noise
+    #[arg(long, default_value = "random")]
+    sampler: Sampler,
+
+    /// Pixel reconstruction filter: box, tent, gaussian or mitchell
+    #[arg(long, default_value = "box")]
+    filter: Filter,
+
+    /// Structure speeding up the search for hits: bvh or kd-tree
+    #[arg(long, default_value = "bvh")]
+    accelerator: AcceleratorType,
+
+    /// Construction of the BVH: sah (faster to render) or median (faster to build)
+    #[arg(long, default_value = "sah")]
+    bvh: BvhSplit,
+
+    /// Fill the scene with fog of this density at height 0, in extinctions per unit of length
+    #[arg(long, value_name = "DENSITY")]
+    fog: Option<Float>,
+
+    /// Inverse of the height over which the density of the fog is divided by e, 0 for a
+    /// uniform fog
+    #[arg(long, default_value_t = 0.5, requires = "fog")]
+    fog_falloff: Float,
+
+    /// Trace this many photons from the lights before rendering, for sharper caustics
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    caustic_photons: u32,
+
+    /// Photons traced at each iteration of the SPPM integrator, the iterations being the
+    /// samples per pixel
+    #[arg(long, value_name = "COUNT", default_value_t = PHOTONS_PER_ITERATION)]
+    photons_per_iteration: u32,
+
+    /// Learn where the light comes from while rendering, in passes of doubling samples per
+    /// pixel, and sample the paths towards it
+    #[arg(long)]
+    path_guiding: bool,
+
+    /// Interpolate the indirect light of diffuse surfaces between points computed before
+    /// rendering: much faster in mostly diffuse scenes, slightly blurring the indirect light
+    #[arg(long)]
+    irradiance_cache: bool,
+
+    /// Let shadow rays through glass, attenuated and tinted by it, for the colored shadows of
+    /// stained glass without waiting for the paths refracting through it to find the lights
+    #[arg(long)]
+    transparent_shadows: bool,
+
+    /// Continue the render saved in the checkpoint file
+    #[arg(long)]
+    resume: bool,
+
+    /// Seconds between two checkpoints of the render in progress
+    #[arg(long, default_value_t = 60)]
+    checkpoint_interval: u64,
+
+    /// Listen on this address (e.g. 0.0.0.0:7878) and distribute the tiles to workers
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "worker")]
+    coordinator: Option<String>,
+
+    /// Render tiles for the coordinator at this address instead of a whole image
+    #[arg(long, value_name = "ADDRESS")]
+    worker: Option<String>,
+
+    /// Render this many frames along the camera path of the scene, to frame_0001.png and
+    /// following
+    #[arg(long, conflicts_with_all = ["coordinator", "worker", "watch"])]
+    frames: Option<u32>,
+
+    /// Frames per second of the animation
+    #[arg(long, default_value_t = 24.0)]
+    fps: Float,
+
+    /// Render this many frames of the camera going once round the point it looks at, at the
+    /// same height, to frame_0001.png and following
+    #[arg(
+        long,
+        value_name = "FRAMES",
+        conflicts_with_all = ["coordinator", "worker", "watch"],
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    turntable: Option<u32>,
+
+    /// With --frames or --turntable, also write the motion of each pixel since the previous
+    /// frame, in pixels, to motion_0001.pfm and following, for temporal denoising or motion blur
+    #[arg(long, requires = "animation")]
+    motion_vectors: bool,
+
+    /// With --frames or --turntable, encode the frames to this video, e.g. animation.mp4,
+    /// streaming them to ffmpeg instead of writing them to PNG images
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "animation",
+        conflicts_with = "resume"
+    )]
+    video: Option<PathBuf>,
+
+    /// Random numbers of the frames: fixed (the noise stays still on the screen), varying
+    /// (independent noise, flickering) or correlated (each frame renewing a quarter of the
+    /// samples of the previous one)
+    #[arg(long, default_value = "fixed", requires = "animation")]
+    frame_seed: FrameSeed,
+
+    /// Tone mapping of the radiance into the displayable range: exposure (clipping the values
+    /// above 1), reinhard or aces
+    #[arg(long, default_value = "exposure")]
+    tone_map: ToneMapper,
+
+    /// Transfer function of the output image: srgb, or a gamma value such as 2.2
+    #[arg(long, default_value = "srgb")]
+    gamma: TransferFunction,
+
+    /// Exposure of the image in stops, added to the automatic exposure if enabled
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: Float,
+
+    /// Adjust the exposure to the average brightness of the image
+    #[arg(long)]
+    auto_exposure: bool,
+
+    /// Add a glow around the parts of the image brighter than the bloom threshold
+    #[arg(long)]
+    bloom: bool,
+
+    /// Luminance above which the image glows, after exposure
+    #[arg(long, default_value_t = 1.0, requires = "bloom")]
+    bloom_threshold: Float,
+
+    /// Fraction of the light above the threshold spread into the glow
+    #[arg(long, default_value_t = 0.1, requires = "bloom")]
+    bloom_intensity: Float,
+
+    /// Format of the image: ppm, png, png16 (16 bits per channel) or pfm (linear radiance as
+    /// floats, before tone mapping)
+    #[arg(long, default_value = "ppm")]
+    format: OutputFormat,
+
+    /// Noise added before quantizing the image, against banding: none, triangular or
+    /// blue-noise
+    #[arg(long, default_value = "none")]
+    dither: Dither,
+
+    /// Encoding of the PPM images: p6 (binary) or p3 (ASCII)
+    #[arg(long, default_value = "p6")]
+    ppm_format: Ppm,
+
+    /// Render on the GPU when the scene allows it (random box filtered samples, no AOVs),
+    /// falling back to the CPU otherwise
+    #[arg(long, conflicts_with_all = ["resume", "coordinator", "worker", "animation"])]
+    gpu: bool,
+
+    /// Explore the scene in a window instead of writing an image: drag to orbit the camera,
+    /// scroll to zoom. Needs the viewer feature
+    #[arg(long, conflicts_with_all = ["resume", "coordinator", "worker", "animation", "watch", "gpu"])]
+    viewer: bool,
+
+    /// Also write the albedo, normal, depth and object id buffers next to the image, with
+    /// heatmaps of the samples per pixel and of the estimated noise, and the variance as PFM
+    #[arg(long, conflicts_with_all = ["coordinator", "worker", "animation", "gpu"])]
+    aovs: bool,
+
+    /// With --aovs, also write the object ids with a color per object, for viewing
+    #[arg(long, requires = "aovs")]
+    object_id_colors: bool,
+
+    /// Also write the samples of each pixel at the depth of what they saw, to the deep OpenEXR
+    /// image_deep.exr, for compositing with other deep renders. Volumes spread their samples
+    /// over their inside. Samples aren't filtered, and the background is left out
+    #[arg(long, conflicts_with_all = ["resume", "coordinator", "worker", "animation", "gpu"])]
+    deep: bool,
+
+    /// Seed of the random numbers of the render. The image only depends on it, not on the
+    /// number of threads or on the way the render is distributed
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Render on this many threads, one per core by default. AOVs and deep images are
+    /// rendered on a single thread
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Run with the lowest priority, leaving the cores to interactive work when it needs them
+    #[arg(long)]
+    low_priority: bool,
+
+    /// Also write the statistics of the render to this file, as JSON
+    #[arg(long, value_name = "PATH")]
+    stats_json: Option<PathBuf>,
+
+    /// Progress of the render: bar, or json for a line of JSON on the standard output every
+    /// second, with the units done, the samples per pixel, the rays per second and the ETA
+    #[arg(long, default_value = "bar")]
+    progress: ProgressFormat,
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// Samples per pixel of each render
+    #[arg(long, default_value_t = BENCH_SAMPLES_PER_PIXEL)]
+    samples_per_pixel: u16,
+
+    /// Render on this many threads, one per core by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+}
+
+const CHECKPOINT_PATH: &str = "image.ckpt";
+
+/// Samples per pixel of previews, and of the quick render done first by --watch.
+const PREVIEW_SAMPLES_PER_PIXEL: u16 = 8;
+/// Size of the images rendered by the benchmark.
+const BENCH_IMAGE_WIDTH: u16 = 300;
+const BENCH_IMAGE_HEIGHT: u16 = 200;
+const BENCH_SAMPLES_PER_PIXEL: u16 = 16;
+/// Time between two checks of the scene file by --watch.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
 fn main() -> Result<()> {
-    let mut rng = rand::thread_rng();
-    // World
-    let world = random_world(&mut rng);
-
-    // Camera
-    let look_from = Point3::new(13.0, 2.0, 3.0);
-    let look_at = Point3::new(0.0, 0.0, 0.0);
-    let v_up = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
-    let aperture = 0.1;
-
-    let camera = Camera::new(
-        look_from,
-        look_at,
-        v_up,
-        20.0,
-        ASPECT_RATIO,
-        aperture,
-        dist_to_focus,
-    );
+    match Cli::parse().command {
+        Command::Render(args) => render(&args, SAMPLES_PER_PIXEL),
+        Command::Preview(args) => render(&args, PREVIEW_SAMPLES_PER_PIXEL),
+        Command::Bench(args) => bench(&args),
+        Command::Validate { path } => validate(&path),
+    }
+}
+
+/// Renders the image or animation described by `args`, with `samples_per_pixel` samples per
+/// pixel.
+fn render(args: &RenderArgs, samples_per_pixel: u16) -> Result<()> {
+    let settings = RenderSettings {
+        scene: args.scene,
+        samples_per_pixel,
+        integrator: args.integrator,
+        sampler: args.sampler,
+        filter: args.filter,
+        accelerator: args.accelerator,
+        bvh_split: args.bvh,
+        tone_mapper: args.tone_map,
+        transfer_function: args.gamma,
+        dither: args.dither,
+        exposure: if args.auto_exposure {
+            Exposure::Auto(args.exposure)
+        } else {
+            Exposure::Manual(args.exposure)
+        },
+        bloom: if args.bloom {
+            Some(Bloom::new(args.bloom_threshold, args.bloom_intensity))
+        } else {
+            None
+        },
+        fog: args
+            .fog
+            .map(|density| HeightFog::new(density, args.fog_falloff)),
+        caustic_photons: args.caustic_photons,
+        photons_per_iteration: args.photons_per_iteration,
+        path_guiding: args.path_guiding,
+        irradiance_cache: args.irradiance_cache,
+        transparent_shadows: args.transparent_shadows,
+        write_aovs: args.aovs,
+        progress: args.progress,
+        seed: args.seed,
+        ..RenderSettings::default()
+    };
+    let renders_passes = settings.integrator == Integrator::Sppm || settings.path_guiding;
+    if renders_passes
+        && (args.coordinator.is_some()
+            || args.worker.is_some()
+            || args.frames.is_some()
+            || args.turntable.is_some()
+            || args.watch
+            || args.viewer
+            || args.resume
+            || args.gpu
+            || args.aovs
+            || args.deep)
+    {
+        bail!(
+            "The SPPM integrator and path guiding only render still images on the CPU, without checkpoints, AOVs or deep images"
+        );
+    }
+    if settings.path_guiding && settings.integrator != Integrator::PathTracer {
+        bail!("Path guiding only applies to the path integrator");
+    }
+    if settings.integrator == Integrator::Bidirectional && settings.fog.is_some() {
+        bail!("The bidirectional integrator doesn't support fog");
+    }
+    if settings.irradiance_cache {
+        if settings.integrator != Integrator::PathTracer {
+            bail!("The irradiance cache only applies to the path integrator");
+        }
+        if settings.fog.is_some() {
+            bail!("The irradiance cache doesn't support fog");
+        }
+    }
+    if settings.transparent_shadows {
+        if settings.integrator != Integrator::PathTracer {
+            bail!("Transparent shadows only apply to the path integrator");
+        }
+        if settings.caustic_photons > 0 {
+            bail!("Transparent shadows and caustic photons both gather the light through glass");
+        }
+    }
+    let threads = thread_count(args.threads);
+    if args.low_priority {
+        parallel::lower_priority()?;
+    }
+
+    if let Some(address) = &args.worker {
+        let mut scene = None;
+        return distributed::run_worker(address.as_str(), |settings, tile| {
+            let scene = scene.get_or_insert_with(|| settings.scene.build(settings));
+            scene.render_tile(settings, tile)
+        });
+    }
+
+    // On Ctrl-C, stop sampling and write out what has been rendered so far
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || handler_interrupted.store(true, Ordering::SeqCst))
+        .context("Failed to install the Ctrl-C handler")?;
+
+    if let Some(path) = args.scene_file.as_deref().filter(|_| args.watch) {
+        return watch(args, path, &settings, threads, &interrupted);
+    }
+    let scene_file = args
+        .scene_file
+        .as_deref()
+        .map(SceneFile::load)
+        .transpose()?;
+    let build_scene_with = |settings: &RenderSettings| match &scene_file {
+        Some(scene_file) => scene_file.build(settings),
+        None => settings.scene.build(settings),
+    };
+    let build_scene = || build_scene_with(&settings);
+    if let Some(frames) = args.frames {
+        let camera_path = match &scene_file {
+            Some(scene_file) => scene_file.camera_path(),
+            None => settings.scene.camera_path(),
+        }
+        .context("The scene has no camera path, see the keyframes of scene files or --turntable")?;
+        let camera_at =
+            |frame| camera_path.camera_at(frame as Float / args.fps, settings.aspect_ratio());
+        return animate(
+            args,
+            &settings,
+            threads,
+            frames,
+            &build_scene_with,
+            &camera_at,
+            &interrupted,
+        );
+    }
+    if let Some(frames) = args.turntable {
+        let camera = build_scene().camera;
+        let camera_at = |frame| camera.turned(2.0 * PI * frame as Float / frames as Float);
+        return animate(
+            args,
+            &settings,
+            threads,
+            frames,
+            &build_scene_with,
+            &camera_at,
+            &interrupted,
+        );
+    }
+    if args.viewer {
+        return view(&settings, threads, &build_scene);
+    }
+    let scene = build_scene();
+    if let Some(usage) = scene_file.as_ref().map(SceneFile::memory_usage) {
+        if usage.texture_count + usage.mesh_count > 0 {
+            status!(settings, "Loaded {}", usage);
+        }
+    }
 
     // Render
-    let mut output_file = File::create("image.ppm").context("Failed to create output file")?;
-    output_file.write_all(b"P3\n")?;
-    output_file.write_all(format!("{} {}\n", IMAGE_WIDTH, IMAGE_HEIGHT).as_bytes())?;
-    output_file.write_all(b"255\n")?;
-
-    let progress_bar = ProgressBar::new(IMAGE_HEIGHT as u64);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len} Row, ETA {eta})"),
-    );
-    for row in (0..IMAGE_HEIGHT).rev().progress_with(progress_bar) {
-        //println!("Remaining row: {}", row);
-        for col in 0..IMAGE_WIDTH {
-            let mut pixel_color = Color::zero();
-            for s in 0..SAMPLES_PER_PIXEL {
-                let u = (col as f32 + rng.gen_range(0.0..1.0)) / (IMAGE_WIDTH - 1) as f32;
-                let v = (row as f32 + rng.gen_range(0.0..1.0)) / (IMAGE_HEIGHT - 1) as f32;
-                let ray = camera.get_ray(u, v, &mut rng);
-                pixel_color += ray_color(&mut rng, &ray, &world, BOUNCE_LIMIT);
-            }
+    let image_width = settings.image_width;
+    let image_height = settings.image_height;
+    let scene_source = match &args.scene_file {
+        Some(path) => Some(
+            fs::read(path)
+                .with_context(|| format!("Failed to read scene file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let checkpoints = Checkpoints {
+        interval: Duration::from_secs(args.checkpoint_interval),
+        fingerprint: checkpoint::fingerprint(&settings, scene_source.as_deref()),
+    };
+    let mut framebuffer = if args.resume {
+        let framebuffer = checkpoint::load(CHECKPOINT_PATH, checkpoints.fingerprint)?;
+        if framebuffer.width() != image_width as usize
+            || framebuffer.height() != image_height as usize
+        {
+            bail!(
+                "Checkpoint is {}x{}, expected {}x{}",
+                framebuffer.width(),
+                framebuffer.height(),
+                image_width,
+                image_height
+            );
+        }
+        framebuffer
+    } else {
+        Framebuffer::new(image_width as usize, image_height as usize)
+    };
+
+    // AOVs are only rendered locally
+    let mut aovs = if settings.write_aovs && args.coordinator.is_none() {
+        Some(AovBuffers::new(image_width as usize, image_height as usize))
+    } else {
+        None
+    };
+    let mut deep = if args.deep {
+        Some(DeepImage::new(image_width as usize, image_height as usize))
+    } else {
+        None
+    };
+
+    let render_start = Instant::now();
+    match &args.coordinator {
+        Some(address) => render_distributed(
+            address,
+            &settings,
+            &mut framebuffer,
+            &interrupted,
+            &checkpoints,
+        )?,
+        None if args.gpu && render_on_gpu(&scene, &settings, &mut framebuffer) => aovs = None,
+        None if settings.integrator == Integrator::Sppm => {
+            framebuffer = render_sppm(&settings, threads, &build_scene, &interrupted)?
+        }
+        None if settings.path_guiding => {
+            framebuffer = render_guided(&settings, threads, &build_scene, &interrupted)?
+        }
+        None if aovs.is_none() && deep.is_none() => render_parallel(
+            &settings,
+            threads,
+            &build_scene,
+            &mut framebuffer,
+            &interrupted,
+            &checkpoints,
+        )?,
+        None => render_local(
+            &scene,
+            &settings,
+            &mut framebuffer,
+            aovs.as_mut(),
+            deep.as_mut(),
+            &interrupted,
+            &checkpoints,
+        )?,
+    }
+    report_stats(
+        &settings,
+        render_start.elapsed(),
+        args.stats_json.as_deref(),
+    )?;
 
-            write_pixel(&mut output_file, &pixel_color, SAMPLES_PER_PIXEL)
-                .context("Failed to write pixel")?;
+    let interrupted = interrupted.load(Ordering::SeqCst);
+    if interrupted && renders_passes {
+        eprintln!("Interrupted, writing the partial image");
+    } else if interrupted {
+        checkpoints.save(&framebuffer)?;
+        eprintln!("Interrupted, writing the partial image (resume with --resume)");
+    }
+
+    save_output(args, &settings, &framebuffer)?;
+    let (width, height) = (image_width as usize, image_height as usize);
+
+    // AOVs of an interrupted render would be missing pixels
+    if let Some(aovs) = aovs.filter(|_| !interrupted) {
+        let format = &args.ppm_format;
+        save_image(
+            "image_albedo.ppm",
+            format,
+            width,
+            height,
+            aovs.albedo(),
+            Dither::None,
+        )?;
+        save_image(
+            "image_normal.ppm",
+            format,
+            width,
+            height,
+            &aovs.normal_image(),
+            Dither::None,
+        )?;
+        save_image(
+            "image_depth.ppm",
+            format,
+            width,
+            height,
+            &aovs.depth_image(),
+            Dither::None,
+        )?;
+        save_object_ids("image_object_id.png", width, height, aovs.object_ids())?;
+        save_image(
+            "image_variance.pfm",
+            &Pfm,
+            width,
+            height,
+            &aovs.variance_image(),
+            Dither::None,
+        )?;
+        // Standard errors, closer to what the eye sees as noise
+        let errors: Vec<Float> = aovs.variance().iter().map(|v| v.sqrt()).collect();
+        save_image(
+            "image_variance_heatmap.ppm",
+            format,
+            width,
+            height,
+            &heatmap(&errors),
+            Dither::None,
+        )?;
+        let sample_counts: Vec<Float> = (0..framebuffer.len())
+            .map(|index| framebuffer.sample_count(index) as Float)
+            .collect();
+        save_image(
+            "image_samples.ppm",
+            format,
+            width,
+            height,
+            &heatmap(&sample_counts),
+            Dither::None,
+        )?;
+        if args.object_id_colors {
+            save_image(
+                "image_object_id_color.ppm",
+                format,
+                width,
+                height,
+                &aovs.object_id_image(),
+                Dither::None,
+            )?;
         }
     }
 
+    if let Some(deep) = deep.filter(|_| !interrupted) {
+        deep.save("image_deep.exr")?;
+    }
+
+    // The render is complete, there is nothing left to resume
+    if !interrupted && Path::new(CHECKPOINT_PATH).exists() {
+        fs::remove_file(CHECKPOINT_PATH).context("Failed to remove checkpoint file")?;
+    }
+
+    Ok(())
+}
+
+/// Renders each built-in scene at a small size, reporting the time taken and the rays traced
+/// per second.
+fn bench(args: &BenchArgs) -> Result<()> {
+    let threads = thread_count(args.threads);
+    for scene in BuiltinScene::ALL {
+        let settings = RenderSettings {
+            scene,
+            image_width: BENCH_IMAGE_WIDTH,
+            image_height: BENCH_IMAGE_HEIGHT,
+            samples_per_pixel: args.samples_per_pixel,
+            ..RenderSettings::default()
+        };
+        let tiles = split_into_tiles(settings.image_width, settings.image_height, TILE_SIZE);
+        let new_renderer = || {
+            let scene = settings.scene.build(&settings);
+            move |tile| scene.render_tile(&settings, tile)
+        };
+
+        STATS.reset();
+        let start = Instant::now();
+        parallel::render_tiles(threads, tiles, new_renderer, |_, _| true);
+        let report = STATS.report(start.elapsed());
+        println!(
+            "{:<18} {:>8.3} s {:>10.2} Mrays/s",
+            scene.to_string(),
+            report.elapsed.as_secs_f64(),
+            report.rays_per_second() / 1e6
+        );
+    }
+    Ok(())
+}
+
+/// Loads the scene file, failing with the first error found in it.
+fn validate(path: &Path) -> Result<()> {
+    SceneFile::load(path)?;
+    println!("{} is valid", path.display());
     Ok(())
 }
 
-fn ray_color<H: Hittable>(rng: &mut ThreadRng, ray: &Ray, world: &H, bounce_limit: u16) -> Color {
-    let white: Color = Color::new(1.0, 1.0, 1.0);
-    let blue: Color = Color::new(0.5, 0.7, 1.0);
-    let red: Color = Color::new(1.0, 0.0, 0.0);
+fn thread_count(threads: Option<u32>) -> usize {
+    threads.map_or_else(parallel::default_thread_count, |threads| threads as usize)
+}
 
-    let mut hit_record = HitRecord::empty();
+/// Writes the image to image.ppm, or the extension of the format of the output.
+fn save_output(
+    args: &RenderArgs,
+    settings: &RenderSettings,
+    framebuffer: &Framebuffer,
+) -> Result<()> {
+    let image = if args.format.is_hdr() {
+        framebuffer.pixels()
+    } else {
+        post_process(settings, framebuffer)
+    };
+    let writer: &dyn ImageWriter = match args.format {
+        OutputFormat::Ppm => &args.ppm_format,
+        OutputFormat::Png => &Png,
+        OutputFormat::Png16 => &Png16,
+        OutputFormat::Pfm => &Pfm,
+    };
+    let path = format!("image.{}", args.format.extension());
+    save_image(
+        path,
+        writer,
+        framebuffer.width(),
+        framebuffer.height(),
+        &image,
+        settings.dither,
+    )
+}
 
-    // If we've exceeded the ray bounce limit, no more light is gathered
-    if bounce_limit <= 0 {
-        return Color::zero();
+fn view(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+) -> Result<()> {
+    #[cfg(feature = "viewer")]
+    return rust_ray_tracing::viewer::run(settings, threads, build_scene, &|framebuffer| {
+        post_process(settings, framebuffer)
+    });
+    #[cfg(not(feature = "viewer"))]
+    {
+        let _ = (settings, threads, build_scene);
+        bail!("The viewer needs the viewer feature, build with --features viewer")
     }
+}
 
-    if world.hit(ray, 0.001, f32::MAX, &mut hit_record) {
-        let mut scattered = Ray::new(Point3::zero(), Vec3::zero());
-        let mut attenuation = Color::zero();
+/// Renders the scene file each time it changes, until interrupted: first a preview with a few
+/// samples per pixel, then the full image, each written out when complete. A change of the
+/// file cancels the render in progress.
+fn watch(
+    args: &RenderArgs,
+    path: &Path,
+    settings: &RenderSettings,
+    threads: usize,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let modified = || fs::metadata(path).and_then(|m| m.modified()).ok();
+    'reload: loop {
+        let loaded = modified();
+        match SceneFile::load(path) {
+            Ok(scene_file) => {
+                let full_samples_per_pixel = settings.samples_per_pixel;
+                let preview_samples = PREVIEW_SAMPLES_PER_PIXEL.min(full_samples_per_pixel);
+                for samples_per_pixel in [preview_samples, full_samples_per_pixel] {
+                    let settings = RenderSettings {
+                        samples_per_pixel,
+                        ..*settings
+                    };
+                    let mut framebuffer = Framebuffer::new(
+                        settings.image_width as usize,
+                        settings.image_height as usize,
+                    );
 
-        if hit_record
-            .material
-            .scatter(ray, &hit_record, &mut attenuation, &mut scattered, rng)
-        {
-            return attenuation * ray_color(rng, &scattered, world, bounce_limit - 1);
+                    // Stopped by Ctrl-C or by a change of the file
+                    let cancelled = AtomicBool::new(false);
+                    let rendered = AtomicBool::new(false);
+                    let result = thread::scope(|scope| {
+                        scope.spawn(|| {
+                            while !rendered.load(Ordering::SeqCst) {
+                                if interrupted.load(Ordering::SeqCst) || modified() != loaded {
+                                    cancelled.store(true, Ordering::SeqCst);
+                                }
+                                thread::sleep(WATCH_INTERVAL);
+                            }
+                        });
+                        let result = render_parallel(
+                            &settings,
+                            threads,
+                            &|| scene_file.build(&settings),
+                            &mut framebuffer,
+                            &cancelled,
+                            &Checkpoints::NEVER,
+                        );
+                        rendered.store(true, Ordering::SeqCst);
+                        result
+                    });
+                    result?;
+
+                    if interrupted.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                    if cancelled.load(Ordering::SeqCst) {
+                        continue 'reload;
+                    }
+                    save_output(args, &settings, &framebuffer)?;
+                    status!(
+                        settings,
+                        "Rendered with {} samples per pixel",
+                        samples_per_pixel
+                    );
+                    // Previews are only rendered once
+                    if samples_per_pixel == full_samples_per_pixel {
+                        break;
+                    }
+                }
+            }
+            Err(error) => eprintln!("{:#}", error),
         }
 
-        return attenuation;
+        status!(settings, "Watching {} for changes", path.display());
+        while modified() == loaded {
+            if interrupted.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            thread::sleep(WATCH_INTERVAL);
+        }
     }
+}
 
-    let unit_direction = unit_vector(ray.direction());
-    let t = 0.5 * (unit_direction.y() + 1.0);
-    (1.0 - t) * white + t * blue
+/// Renders the frames of an animation as asked by `args`, then reports the statistics.
+fn animate(
+    args: &RenderArgs,
+    settings: &RenderSettings,
+    threads: usize,
+    frames: u32,
+    build_scene: &(dyn Fn(&RenderSettings) -> Scene + Sync),
+    camera_at: &(dyn Fn(u32) -> Camera + Sync),
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let render_start = Instant::now();
+    let mut video = video_pipe(args, settings)?;
+    render_animation(
+        settings,
+        threads,
+        frames,
+        build_scene,
+        camera_at,
+        args.frame_seed,
+        args.motion_vectors,
+        video.as_mut(),
+        args.resume,
+        interrupted,
+    )?;
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    report_stats(settings, render_start.elapsed(), args.stats_json.as_deref())
 }
 
-fn write_pixel(file: &mut File, color: &Color, samples_per_pixel: u16) -> Result<()> {
-    let mut r = color.x();
-    let mut g = color.y();
-    let mut b = color.z();
+/// Renders the frames of the scenes built by `build_scene`, seen from the camera of each frame,
+/// one after the other, to `video` or to PNG images, skipping those already on disk when
+/// resuming.
+#[allow(clippy::too_many_arguments)]
+fn render_animation(
+    settings: &RenderSettings,
+    threads: usize,
+    frames: u32,
+    build_scene: &(dyn Fn(&RenderSettings) -> Scene + Sync),
+    camera_at: &(dyn Fn(u32) -> Camera + Sync),
+    frame_seed: FrameSeed,
+    motion_vectors: bool,
+    mut video: Option<&mut FfmpegPipe>,
+    resume: bool,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    for frame in 0..frames {
+        let path = format!("frame_{:04}.png", frame + 1);
+        if resume && Path::new(&path).exists() {
+            continue;
+        }
+        match video {
+            Some(_) => status!(settings, "Rendering frame {} of {}", frame + 1, frames),
+            None => status!(settings, "Rendering {}", path),
+        }
+
+        let settings = &frame_seed.frame_settings(settings, frame);
+        let build_scene = || {
+            let mut scene = build_scene(settings);
+            scene.camera = camera_at(frame);
+            scene
+        };
+
+        let mut framebuffer = Framebuffer::new(
+            settings.image_width as usize,
+            settings.image_height as usize,
+        );
+        // Frames are short, they aren't checkpointed
+        render_parallel(
+            settings,
+            threads,
+            &build_scene,
+            &mut framebuffer,
+            interrupted,
+            &Checkpoints::NEVER,
+        )?;
 
-    // Divide the color by the number of samples and gamma-correct for gamma=2.0.
-    let scale = 1.0 / samples_per_pixel as f32;
+        if interrupted.load(Ordering::SeqCst) {
+            eprintln!("Interrupted, {} is left unfinished", path);
+            break;
+        }
+
+        let (width, height) = (
+            settings.image_width as usize,
+            settings.image_height as usize,
+        );
+        // Written before the frame, which marks both as done
+        if motion_vectors {
+            let scene = build_scene();
+            // The first frame didn't move
+            let previous_camera = camera_at(frame.saturating_sub(1));
+            let aovs = first_hits(&scene, settings);
+            save_image(
+                format!("motion_{:04}.pfm", frame + 1),
+                &Pfm,
+                width,
+                height,
+                &aovs.motion_image(&scene.camera, &previous_camera),
+                Dither::None,
+            )?;
+        }
 
-    r = (scale * r).sqrt();
-    g = (scale * g).sqrt();
-    b = (scale * b).sqrt();
+        let image = post_process(settings, &framebuffer);
+        match video {
+            Some(ref mut video) => video.write_frame(&image, settings.dither)?,
+            None => save_image(&path, &Png, width, height, &image, settings.dither)?,
+        }
+    }
 
-    let ir = (256.0 * clamp(r, 0.0, 0.999)) as u8;
-    let ig = (256.0 * clamp(g, 0.0, 0.999)) as u8;
-    let ib = (256.0 * clamp(b, 0.0, 0.999)) as u8;
-    Ok(file.write_all(format!("{} {} {}\n", ir, ig, ib).as_bytes())?)
+    Ok(())
 }
 
-fn random_world(rng: &mut ThreadRng) -> HittableList {
-    let mut world = HittableList::new();
+/// Encoder of the frames of the animation, when they make a video.
+fn video_pipe(args: &RenderArgs, settings: &RenderSettings) -> Result<Option<FfmpegPipe>> {
+    args.video
+        .as_ref()
+        .map(|path| {
+            let (width, height) = (settings.image_width, settings.image_height);
+            FfmpegPipe::spawn(path, width as usize, height as usize, args.fps)
+        })
+        .transpose()
+}
 
-    let ground_material = Material::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
-    world.add(Box::new(Sphere::new(
-        Point3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        ground_material,
-    )));
+/// AOVs of the rays through the center of each pixel, from the center of the lens, enough for
+/// the motion vectors.
+fn first_hits(scene: &Scene, settings: &RenderSettings) -> AovBuffers {
+    let (image_width, image_height) = (settings.image_width, settings.image_height);
+    let camera = scene.camera.clone().with_aperture(0.0);
+    let mut rng = SampleRng::new(settings.seed);
+    let mut aovs = AovBuffers::new(image_width as usize, image_height as usize);
+    for row in (0..image_height).rev() {
+        for col in 0..image_width {
+            let u = (col as Float + 0.5) / (image_width - 1) as Float;
+            let v = (row as Float + 0.5) / (image_height - 1) as Float;
+            let sample = match camera.get_ray(u, v, &mut rng) {
+                Some(ray) => {
+                    AovSample::trace(&ray, &*scene.world, &scene.materials, &*scene.background)
+                }
+                None => AovSample::empty(),
+            };
+            aovs.push(&[sample], &[]);
+        }
+    }
+    aovs
+}
 
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = rng.gen::<f32>();
-            let center = Point3::new(
-                a as f32 + 0.9 * rng.gen::<f32>(),
-                0.2,
-                b as f32 + 0.9 * rng.gen::<f32>(),
+/// Prints the statistics of the rays traced on the CPU, if any, and writes them to `json_path`.
+fn report_stats(
+    settings: &RenderSettings,
+    elapsed: Duration,
+    json_path: Option<&Path>,
+) -> Result<()> {
+    let report = STATS.report(elapsed);
+    if report.rays() > 0 {
+        status!(settings, "{}", report);
+    }
+    match json_path {
+        Some(path) => report.save_json(path),
+        None => Ok(()),
+    }
+}
+
+/// Renders the whole image on the GPU, false if it isn't possible so that the CPU takes over.
+fn render_on_gpu(scene: &Scene, settings: &RenderSettings, framebuffer: &mut Framebuffer) -> bool {
+    #[cfg(feature = "gpu")]
+    let result = rust_ray_tracing::gpu::render(scene, settings);
+    #[cfg(not(feature = "gpu"))]
+    let result: Result<Framebuffer> = {
+        let _ = (scene, settings);
+        Err(anyhow::anyhow!("built without the gpu feature"))
+    };
+
+    match result {
+        Ok(gpu_framebuffer) => {
+            *framebuffer = gpu_framebuffer;
+            true
+        }
+        Err(error) => {
+            eprintln!(
+                "Can't render on the GPU ({:#}), rendering on the CPU",
+                error
             );
+            false
+        }
+    }
+}
+
+fn render_local(
+    scene: &Scene,
+    settings: &RenderSettings,
+    framebuffer: &mut Framebuffer,
+    mut aovs: Option<&mut AovBuffers>,
+    mut deep: Option<&mut DeepImage>,
+    interrupted: &AtomicBool,
+    checkpoints: &Checkpoints,
+) -> Result<()> {
+    let image_width = settings.image_width;
+    let image_height = settings.image_height;
+    let mut last_checkpoint = Instant::now();
+    let mut aov_samples = Vec::with_capacity(settings.samples_per_pixel as usize);
+    let mut deep_samples = Vec::with_capacity(settings.samples_per_pixel as usize);
+    let mut colors = Vec::with_capacity(settings.samples_per_pixel as usize);
+
+    let mut progress = Progress::new(
+        settings.progress,
+        image_height as u64,
+        ProgressUnit::Row,
+        settings.samples_per_pixel,
+    );
+    for row in (0..image_height).rev() {
+        for col in 0..image_width {
+            if interrupted.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let index = framebuffer.index(col as usize, (image_height - 1 - row) as usize);
+            let remaining_samples =
+                (settings.samples_per_pixel as u32).saturating_sub(framebuffer.sample_count(index));
+            // AOVs aren't checkpointed, resumed pixels need their camera rays again
+            let ray_count = if aovs.is_some() {
+                settings.samples_per_pixel as u32
+            } else {
+                remaining_samples
+            };
 
-            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
-                    let albedo = Color::random(rng) * Color::random(rng);
-                    let material = Material::Lambertian(Lambertian::new(albedo));
-                    world.add(Box::new(Sphere::new(center, 0.2, material)));
-                } else if choose_mat < 0.95 {
-                    let albedo = Color::random_range(rng, 0.5, 1.0);
-                    let fuzz = rng.gen_range(0.0..0.5) as f32;
-                    let material = Material::Metal(Metal::new(albedo, fuzz));
-                    world.add(Box::new(Sphere::new(center, 0.2, material)));
-                } else {
-                    let material = Material::Dielectric(Dielectric::new(1.5));
-                    world.add(Box::new(Sphere::new(center, 0.2, material)));
+            aov_samples.clear();
+            deep_samples.clear();
+            colors.clear();
+            let first_sample = framebuffer.sample_count(index);
+            for s in 0..ray_count {
+                let sample_index = first_sample + s;
+                let mut rng = SampleRng::for_sample(settings.seed, index, sample_index);
+                let (ray, dx, dy) = scene.camera_ray(settings, col, row, sample_index, &mut rng);
+                if s < remaining_samples {
+                    // Deep images aren't resumed, every sample is rendered here
+                    let depth = deep.is_some().then(|| {
+                        ray.and_then(|ray| {
+                            let mut rng = rng.clone();
+                            deep::sample_depth(&ray, &*scene.world, &scene.materials, &mut rng)
+                        })
+                    });
+                    let color = match ray {
+                        Some(ray) => scene.ray_color(settings, &ray, &mut rng),
+                        None => Color::zero(),
+                    };
+                    let x = col as Float + dx;
+                    let y = (image_height - 1 - row) as Float + dy;
+                    framebuffer.splat(x, y, color, &settings.filter);
+                    colors.push(color);
+                    if let Some(depth) = depth {
+                        deep_samples.push((depth, color));
+                    }
+                }
+                if aovs.is_some() {
+                    aov_samples.push(match ray {
+                        Some(ray) => AovSample::trace(
+                            &ray,
+                            &*scene.world,
+                            &scene.materials,
+                            &*scene.background,
+                        ),
+                        None => AovSample::empty(),
+                    });
                 }
             }
+
+            if let Some(aovs) = aovs.as_mut() {
+                aovs.push(&aov_samples, &colors);
+            }
+            if let Some(deep) = deep.as_mut() {
+                deep.push(&deep_samples);
+            }
         }
+
+        if last_checkpoint.elapsed() >= checkpoints.interval {
+            checkpoints.save(framebuffer)?;
+            last_checkpoint = Instant::now();
+        }
+        progress.inc(1);
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// Renders the tiles not completed yet on `threads` threads, each with its own copy of the
+/// scene, built by `build_scene`.
+fn render_parallel(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    framebuffer: &mut Framebuffer,
+    interrupted: &AtomicBool,
+    checkpoints: &Checkpoints,
+) -> Result<()> {
+    let tiles = pending_tiles(settings, framebuffer);
+    let mut progress = tile_progress(settings, tiles.len());
+
+    let mut tile_store = TileStore::new(framebuffer, checkpoints);
+    let new_renderer = || {
+        let scene = build_scene();
+        move |tile| scene.render_tile(settings, tile)
+    };
+    parallel::render_tiles(threads, tiles, new_renderer, |_, samples| {
+        tile_store.store(samples);
+        progress.inc(1);
+        tile_store.result.is_ok() && !interrupted.load(Ordering::SeqCst)
+    });
+    progress.finish();
+
+    tile_store.result
+}
+
+/// Renders the image with the SPPM integrator, stopping after the current iteration when
+/// interrupted.
+fn render_sppm(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    interrupted: &AtomicBool,
+) -> Result<Framebuffer> {
+    let mut progress = Progress::new(
+        settings.progress,
+        settings.samples_per_pixel as u64,
+        ProgressUnit::Iteration,
+        settings.samples_per_pixel,
+    );
+    let framebuffer = sppm::render(settings, threads, build_scene, || {
+        progress.inc(1);
+        !interrupted.load(Ordering::SeqCst)
+    })?;
+    progress.finish();
+    Ok(framebuffer)
+}
+
+/// Renders the image with path guiding, stopping after the current pass when interrupted.
+fn render_guided(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    interrupted: &AtomicBool,
+) -> Result<Framebuffer> {
+    let mut progress = Progress::new(
+        settings.progress,
+        settings.samples_per_pixel as u64,
+        ProgressUnit::Sample,
+        settings.samples_per_pixel,
+    );
+    let framebuffer = guiding::render(settings, threads, build_scene, |sample_count| {
+        progress.inc(sample_count as u64);
+        !interrupted.load(Ordering::SeqCst)
+    })?;
+    progress.finish();
+    Ok(framebuffer)
+}
+
+/// Hands out the tiles not completed yet to the workers connecting to `address`.
+fn render_distributed(
+    address: &str,
+    settings: &RenderSettings,
+    framebuffer: &mut Framebuffer,
+    interrupted: &AtomicBool,
+    checkpoints: &Checkpoints,
+) -> Result<()> {
+    let tiles = pending_tiles(settings, framebuffer);
+    let mut progress = tile_progress(settings, tiles.len());
+    status!(settings, "Waiting for workers on {}", address);
+
+    let mut tile_store = TileStore::new(framebuffer, checkpoints);
+    distributed::run_coordinator(address, settings, tiles, interrupted, |_, samples| {
+        tile_store.store(samples);
+        progress.inc(1);
+        tile_store.result.is_ok()
+    })?;
+    progress.finish();
+
+    tile_store.result
+}
+
+/// Tiles with pixels still missing samples, in the framebuffer of a resumed render.
+fn pending_tiles(settings: &RenderSettings, framebuffer: &Framebuffer) -> Vec<Tile> {
+    let samples_per_pixel = settings.samples_per_pixel as u32;
+    split_into_tiles(settings.image_width, settings.image_height, TILE_SIZE)
+        .into_iter()
+        .filter(|tile| {
+            tile.pixel_indices(settings.image_width)
+                .any(|index| framebuffer.sample_count(index) < samples_per_pixel)
+        })
+        .collect()
+}
+
+fn tile_progress(settings: &RenderSettings, tile_count: usize) -> Progress {
+    Progress::new(
+        settings.progress,
+        tile_count as u64,
+        ProgressUnit::Tile,
+        settings.samples_per_pixel,
+    )
+}
+
+/// Checkpoints of the render in progress, saved every `interval`.
+struct Checkpoints {
+    interval: Duration,
+    /// Of the scene and settings of the render, resuming only from its own checkpoints.
+    fingerprint: u64,
+}
+
+impl Checkpoints {
+    /// For renders which aren't resumed.
+    const NEVER: Checkpoints = Checkpoints {
+        interval: Duration::from_secs(u64::MAX),
+        fingerprint: 0,
+    };
+
+    fn save(&self, framebuffer: &Framebuffer) -> Result<()> {
+        checkpoint::save(framebuffer, self.fingerprint, CHECKPOINT_PATH)
     }
+}
 
-    let material1 = Material::Dielectric(Dielectric::new(1.5));
-    world.add(Box::new(Sphere::new(
-        Point3::new(0.0, 1.0, 0.0),
-        1.0,
-        material1,
-    )));
+/// Adds the samples of the rendered tiles to the framebuffer, saving it to the checkpoint file
+/// every interval of the `checkpoints`.
+struct TileStore<'a> {
+    framebuffer: &'a mut Framebuffer,
+    checkpoints: &'a Checkpoints,
+    last_checkpoint: Instant,
+    /// Error of the last checkpoint.
+    result: Result<()>,
+}
 
-    let material2 = Material::Lambertian(Lambertian::new(Color::new(0.4, 0.2, 0.1)));
-    world.add(Box::new(Sphere::new(
-        Point3::new(-4.0, 1.0, 0.0),
-        1.0,
-        material2,
-    )));
+impl<'a> TileStore<'a> {
+    fn new(framebuffer: &'a mut Framebuffer, checkpoints: &'a Checkpoints) -> TileStore<'a> {
+        TileStore {
+            framebuffer,
+            checkpoints,
+            last_checkpoint: Instant::now(),
+            result: Ok(()),
+        }
+    }
 
-    let material3 = Material::Metal(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
-    world.add(Box::new(Sphere::new(
-        Point3::new(4.0, 1.0, 0.0),
-        1.0,
-        material3,
-    )));
+    fn store(&mut self, samples: &TileSamples) {
+        samples.merge_into(self.framebuffer);
 
-    world
+        if self.last_checkpoint.elapsed() >= self.checkpoints.interval {
+            self.result = self.checkpoints.save(self.framebuffer);
+            self.last_checkpoint = Instant::now();
+        }
+    }
 }
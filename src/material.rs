@@ -1,14 +1,276 @@
+use crate::float::{Float, PI};
+use crate::microfacet::{conductor_fresnel, schlick_fresnel, Ggx};
 use crate::object::HitRecord;
+use crate::onb::Onb;
+use crate::pdf::{CoatedPdf, CosinePdf, GgxPdf, HairPdf, Pdf, PrincipledPdf};
 use crate::ray::Ray;
-use crate::vec3::{unit_vector, Color, Vec3};
-use rand::rngs::ThreadRng;
+use crate::rng::SampleRng;
+use crate::texture::Texture;
+use crate::tonemap::luminance;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use crate::volume::Density;
+use anyhow::{bail, Result};
 use rand::Rng;
+use std::fmt;
+use std::ops::Index;
+use std::str::FromStr;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum Material {
     Lambertian(Lambertian),
+    OrenNayar(OrenNayar),
     Metal(Metal),
+    GgxMetal(GgxMetal),
+    Conductor(Conductor),
     Dielectric(Dielectric),
+    Subsurface(Subsurface),
+    Volume(Volume),
+    DiffuseLight(DiffuseLight),
+    Principled(Principled),
+    Hair(Hair),
+    Coated(Coated),
+    NormalMapped(NormalMapped),
+    Mix(Mix),
+}
+
+impl Material {
+    /// Refraction index of the inside of transmissive materials.
+    pub fn refraction_index(&self) -> Option<Float> {
+        match *self {
+            Material::Dielectric(ref inner) => Some(inner.refraction_index),
+            Material::Subsurface(ref inner) => Some(inner.surface.refraction_index),
+            Material::Coated(ref inner) => inner.base.refraction_index(),
+            Material::NormalMapped(ref inner) => inner.base.refraction_index(),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the light a shadow ray `ray` keeps through the surface at `hit_record`,
+    /// having travelled `distance` since the previous surface, None if the surface blocks it.
+    /// Only dielectrics, as if they weren't refracting them, and volumes let shadow rays
+    /// through, the transmittance of the latter being a random estimate.
+    pub fn shadow_transmittance(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        distance: Float,
+        rng: &mut SampleRng,
+    ) -> Option<Color> {
+        if is_black_back_face(hit_record) {
+            return None;
+        }
+        match *self {
+            Material::Dielectric(ref inner) => {
+                Some(inner.shadow_transmittance(ray, hit_record, distance))
+            }
+            Material::Volume(ref inner) => {
+                Some(inner.shadow_transmittance(ray, hit_record, distance, rng))
+            }
+            Material::NormalMapped(ref inner) => inner
+                .base
+                .shadow_transmittance(ray, hit_record, distance, rng),
+            _ => None,
+        }
+    }
+
+    /// Surface color, as expected in the albedo AOV of denoisers.
+    pub fn albedo(&self) -> Color {
+        match *self {
+            Material::Lambertian(ref inner) => inner.albedo,
+            Material::OrenNayar(ref inner) => inner.albedo,
+            Material::Metal(ref inner) => inner.albedo,
+            Material::GgxMetal(ref inner) => inner.albedo,
+            Material::Conductor(ref inner) => inner.reflectance(),
+            Material::Dielectric(_) => Color::new(1.0, 1.0, 1.0),
+            Material::Subsurface(ref inner) => inner.albedo,
+            Material::Volume(ref inner) => inner.albedo,
+            Material::DiffuseLight(ref inner) => inner.emit,
+            Material::Principled(ref inner) => inner.base_color,
+            Material::Hair(ref inner) => inner.color,
+            Material::Coated(ref inner) => inner.tint * inner.base.albedo(),
+            Material::NormalMapped(ref inner) => inner.base.albedo(),
+            Material::Mix(ref inner) => {
+                let factor = inner.factor.scalar(0.5, 0.5, &Point3::zero());
+                (1.0 - factor) * inner.first.albedo() + factor * inner.second.albedo()
+            }
+        }
+    }
+}
+
+/// Handle to a material of a `MaterialList`, which any number of objects can share.
+///
+/// It carries how the material renders back faces, for the objects to cull them while
+/// intersecting rays, without looking up the material.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MaterialId {
+    index: u32,
+    back_faces: BackFaces,
+}
+
+impl MaterialId {
+    /// Position of the material in its `MaterialList`.
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+
+    pub fn back_faces(self) -> BackFaces {
+        self.back_faces
+    }
+
+    /// Whether the hit of `ray` on a surface with the material, of outward normal
+    /// `outward_normal`, is ignored, being on a culled back face.
+    pub fn culls(self, ray: &Ray, outward_normal: &Vec3) -> bool {
+        self.back_faces == BackFaces::Culled && ray.direction().dot(outward_normal) >= 0.0
+    }
+}
+
+/// How the back faces of the surfaces made of a material, the side their outward normal points
+/// away from, are rendered. Single-sided geometry such as the walls of imported models is
+/// usually only meant to be seen from the front.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BackFaces {
+    /// Shaded like the front faces.
+    #[default]
+    Shaded,
+    /// Neither scattering nor emitting any light.
+    Black,
+    /// Ignored by the rays, which go through them. Only meshes, quads, disks and spheres cull
+    /// their back faces, other objects shade them.
+    Culled,
+}
+
+impl fmt::Display for BackFaces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BackFaces::Shaded => "shaded",
+            BackFaces::Black => "black",
+            BackFaces::Culled => "culled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for BackFaces {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BackFaces> {
+        match s {
+            "shaded" => Ok(BackFaces::Shaded),
+            "black" => Ok(BackFaces::Black),
+            "culled" => Ok(BackFaces::Culled),
+            _ => bail!(
+                "Unknown back faces '{}', expected one of: shaded, black, culled",
+                s
+            ),
+        }
+    }
+}
+
+/// Registry owning the materials of a scene, objects only refer to them by `MaterialId`.
+#[derive(Clone, Default)]
+pub struct MaterialList {
+    materials: Vec<Material>,
+}
+
+impl MaterialList {
+    pub fn new() -> MaterialList {
+        MaterialList {
+            materials: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, material: Material) -> MaterialId {
+        self.add_with_back_faces(material, BackFaces::Shaded)
+    }
+
+    /// Adds `material`, rendering the back faces of the objects made of it as `back_faces`.
+    pub fn add_with_back_faces(&mut self, material: Material, back_faces: BackFaces) -> MaterialId {
+        self.materials.push(material);
+        MaterialId {
+            index: (self.materials.len() - 1) as u32,
+            back_faces,
+        }
+    }
+
+    pub fn get(&self, id: MaterialId) -> &Material {
+        &self.materials[id.index()]
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Material> {
+        self.materials.iter()
+    }
+}
+
+impl Index<MaterialId> for MaterialList {
+    type Output = Material;
+
+    fn index(&self, id: MaterialId) -> &Material {
+        self.get(id)
+    }
+}
+
+/// PDFs a material can sample its scattered direction from.
+#[derive(Clone, Debug)]
+pub enum ScatterPdf {
+    Cosine(CosinePdf),
+    Ggx(GgxPdf),
+    Principled(PrincipledPdf),
+    Coated(CoatedPdf),
+    Hair(HairPdf),
+}
+
+impl Pdf for ScatterPdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        match *self {
+            ScatterPdf::Cosine(ref inner) => inner.value(direction),
+            ScatterPdf::Ggx(ref inner) => inner.value(direction),
+            ScatterPdf::Principled(ref inner) => inner.value(direction),
+            ScatterPdf::Coated(ref inner) => inner.value(direction),
+            ScatterPdf::Hair(ref inner) => inner.value(direction),
+        }
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        match *self {
+            ScatterPdf::Cosine(ref inner) => inner.generate(rng),
+            ScatterPdf::Ggx(ref inner) => inner.generate(rng),
+            ScatterPdf::Principled(ref inner) => inner.generate(rng),
+            ScatterPdf::Coated(ref inner) => inner.generate(rng),
+            ScatterPdf::Hair(ref inner) => inner.generate(rng),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ScatterType {
+    /// Delta distribution (mirror, glass): the scattered ray is fully determined.
+    Specular(Ray),
+    /// The scattered direction has to be sampled, possibly mixed with light sampling.
+    Pdf(ScatterPdf),
+}
+
+#[derive(Clone, Debug)]
+pub struct ScatterRecord {
+    /// Color filter of specular scattering, sampled scattering is weighted by `eval` instead.
+    pub attenuation: Color,
+    pub scatter_type: ScatterType,
+}
+
+impl ScatterRecord {
+    pub fn empty() -> ScatterRecord {
+        ScatterRecord {
+            attenuation: Color::zero(),
+            scatter_type: ScatterType::Specular(Ray::new(Point3::zero(), Vec3::zero())),
+        }
+    }
 }
 
 pub trait Scatterable {
@@ -16,10 +278,19 @@ pub trait Scatterable {
         &self,
         in_ray: &Ray,
         hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered_ray: &mut Ray,
-        rng: &mut ThreadRng,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
     ) -> bool;
+
+    /// BSDF for light coming from `scattered_ray`, multiplied by the cosine of its angle with
+    /// the normal. Only meaningful for materials scattering with `ScatterType::Pdf`.
+    fn eval(&self, _in_ray: &Ray, _hit_record: &HitRecord, _scattered_ray: &Ray) -> Color {
+        Color::zero()
+    }
+
+    fn emitted(&self, _in_ray: &Ray, _hit_record: &HitRecord) -> Color {
+        Color::zero()
+    }
 }
 
 impl Scatterable for Material {
@@ -27,79 +298,258 @@ impl Scatterable for Material {
         &self,
         in_ray: &Ray,
         hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered_ray: &mut Ray,
-        rng: &mut ThreadRng,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
     ) -> bool {
+        if is_black_back_face(hit_record) {
+            return false;
+        }
         match *self {
             Material::Lambertian(ref inner) => {
-                inner.scatter(in_ray, hit_record, attenuation, scattered_ray, rng)
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
             }
-            Material::Metal(ref inner) => {
-                inner.scatter(in_ray, hit_record, attenuation, scattered_ray, rng)
+            Material::OrenNayar(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
+            }
+            Material::Metal(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+            Material::GgxMetal(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+            Material::Conductor(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
             }
-
             Material::Dielectric(ref inner) => {
-                inner.scatter(in_ray, hit_record, attenuation, scattered_ray, rng)
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
+            }
+            Material::Subsurface(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
+            }
+            Material::Volume(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+            Material::DiffuseLight(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
             }
+            Material::Principled(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
+            }
+            Material::Hair(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+            Material::Coated(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+            Material::NormalMapped(ref inner) => {
+                inner.scatter(in_ray, hit_record, scatter_record, rng)
+            }
+            Material::Mix(ref inner) => inner.scatter(in_ray, hit_record, scatter_record, rng),
+        }
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        if is_black_back_face(hit_record) {
+            return Color::zero();
+        }
+        match *self {
+            Material::Lambertian(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::OrenNayar(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Metal(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::GgxMetal(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Conductor(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Dielectric(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Subsurface(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Volume(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::DiffuseLight(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Principled(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Hair(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Coated(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::NormalMapped(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+            Material::Mix(ref inner) => inner.eval(in_ray, hit_record, scattered_ray),
+        }
+    }
+
+    fn emitted(&self, in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        if is_black_back_face(hit_record) {
+            return Color::zero();
+        }
+        match *self {
+            Material::Lambertian(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::OrenNayar(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Metal(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::GgxMetal(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Conductor(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Dielectric(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Subsurface(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Volume(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::DiffuseLight(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Principled(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Hair(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Coated(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::NormalMapped(ref inner) => inner.emitted(in_ray, hit_record),
+            Material::Mix(ref inner) => inner.emitted(in_ray, hit_record),
         }
     }
 }
 
+/// Whether the hit is on a back face which its material renders black.
+fn is_black_back_face(hit_record: &HitRecord) -> bool {
+    !hit_record.front_face && hit_record.material.back_faces() == BackFaces::Black
+}
+
 // ------------
 //  LAMBERTIAN
 // ------------
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Lambertian {
     albedo: Color,
+    /// Albedo varying over the surface, replacing `albedo`.
+    pub(crate) albedo_texture: Option<Texture>,
 }
 
 impl Lambertian {
     pub fn new(albedo: Color) -> Lambertian {
-        Lambertian { albedo }
+        Lambertian {
+            albedo,
+            albedo_texture: None,
+        }
+    }
+
+    /// Albedo read from `texture`, such as a ramp of colors over the iterations of a fractal.
+    pub fn with_albedo_texture(mut self, texture: Texture) -> Lambertian {
+        self.albedo_texture = Some(texture);
+        self
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        match self.albedo_texture {
+            Some(ref texture) => texture.value(hit_record.u, hit_record.v, &hit_record.point),
+            None => self.albedo,
+        }
     }
 }
 
 impl Scatterable for Lambertian {
     fn scatter(
         &self,
-        in_ray: &Ray,
+        _in_ray: &Ray,
         hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered_ray: &mut Ray,
-        rng: &mut ThreadRng,
+        scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
     ) -> bool {
-        let mut scatter_direction = hit_record.normal + Vec3::random_unit_vector(rng);
+        scatter_record.attenuation = self.albedo(hit_record);
+        scatter_record.scatter_type =
+            ScatterType::Pdf(ScatterPdf::Cosine(CosinePdf::new(&hit_record.normal)));
+        true
+    }
+
+    fn eval(&self, _in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let cosine = hit_record
+            .normal
+            .dot(&unit_vector(scattered_ray.direction()));
+        (cosine / PI).max(0.0) * self.albedo(hit_record)
+    }
+}
+
+// ------------
+//  OREN-NAYAR
+// ------------
+
+/// Rough diffuse surface made of V-shaped Lambertian microfacets, whose slopes have a standard
+/// deviation of `sigma_deg` degrees (qualitative model of Oren and Nayar). Appears flatter than
+/// Lambertian surfaces, and brighter when lit from behind the viewer.
+#[derive(Clone, Copy, Debug)]
+pub struct OrenNayar {
+    albedo: Color,
+    a: Float,
+    b: Float,
+}
 
-        // Catch degenerate scatter direction
-        if scatter_direction.is_near_zero() {
-            scatter_direction = hit_record.normal;
+impl OrenNayar {
+    pub fn new(albedo: Color, sigma_deg: Float) -> OrenNayar {
+        let sigma = sigma_deg.to_radians();
+        let sigma2 = sigma * sigma;
+        OrenNayar {
+            albedo,
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
         }
+    }
+}
 
-        *scattered_ray = Ray::new(hit_record.point, scatter_direction);
-        *attenuation = self.albedo;
+impl Scatterable for OrenNayar {
+    fn scatter(
+        &self,
+        _in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
+    ) -> bool {
+        scatter_record.attenuation = self.albedo;
+        scatter_record.scatter_type =
+            ScatterType::Pdf(ScatterPdf::Cosine(CosinePdf::new(&hit_record.normal)));
         true
     }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let wi = uvw.to_local(&unit_vector(scattered_ray.direction()));
+        if wi.z() <= 0.0 {
+            return Color::zero();
+        }
+
+        let sin_o = (1.0 - wo.z() * wo.z()).max(0.0).sqrt();
+        let sin_i = (1.0 - wi.z() * wi.z()).max(0.0).sqrt();
+
+        // cos(phi_i - phi_o), from the projections on the tangent plane
+        let mut cos_phi = 0.0;
+        if sin_o > 1e-4 && sin_i > 1e-4 {
+            cos_phi = ((wi.x() * wo.x() + wi.y() * wo.y()) / (sin_i * sin_o)).max(0.0);
+        }
+
+        // sin(alpha) * tan(beta), alpha and beta being the max and min of the two angles
+        let (sin_alpha, tan_beta) = if wi.z() > wo.z().abs() {
+            (sin_o, sin_i / wi.z())
+        } else {
+            (sin_i, sin_o / wo.z().abs().max(1e-4))
+        };
+
+        (self.a + self.b * cos_phi * sin_alpha * tan_beta) * wi.z() / PI * self.albedo
+    }
 }
 
 // -------
 //  METAL
 // -------
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Metal {
     albedo: Color,
-    fuzz: f32,
+    pub(crate) fuzz: Float,
+    /// Fuzz varying over the surface, replacing `fuzz`.
+    pub(crate) fuzz_texture: Option<Texture>,
 }
 
 impl Metal {
-    pub fn new(albedo: Color, fuzz: f32) -> Metal {
+    pub fn new(albedo: Color, fuzz: Float) -> Metal {
         let mut f = 1.0;
         if fuzz < 1.0 {
             f = fuzz
         }
-        Metal { albedo, fuzz: f }
+        Metal {
+            albedo,
+            fuzz: f,
+            fuzz_texture: None,
+        }
+    }
+
+    /// Fuzz read from the average of the channels of `texture`, such as rust patches on a
+    /// polished plate.
+    pub fn with_fuzz_texture(mut self, texture: Texture) -> Metal {
+        self.fuzz_texture = Some(texture);
+        self
+    }
+
+    fn fuzz(&self, hit_record: &HitRecord) -> Float {
+        match self.fuzz_texture {
+            Some(ref texture) => texture
+                .scalar(hit_record.u, hit_record.v, &hit_record.point)
+                .clamp(0.0, 1.0),
+            None => self.fuzz,
+        }
     }
 }
 
@@ -108,34 +558,378 @@ impl Scatterable for Metal {
         &self,
         in_ray: &Ray,
         hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered_ray: &mut Ray,
-        rng: &mut ThreadRng,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
     ) -> bool {
         let reflected = reflect(unit_vector(in_ray.direction()), hit_record.normal);
-        *scattered_ray = Ray::new(
-            hit_record.point,
-            reflected + self.fuzz * Vec3::random_in_unit_sphere(rng),
-        );
-        *attenuation = self.albedo;
+        let direction = reflected + self.fuzz(hit_record) * Vec3::random_in_unit_sphere(rng);
+        let scattered_ray =
+            Ray::new(hit_record.ray_origin(&direction), direction).with_channel(in_ray.channel());
+        scatter_record.attenuation = self.albedo;
+        scatter_record.scatter_type = ScatterType::Specular(scattered_ray);
         scattered_ray.direction().dot(&hit_record.normal) > 0.0
     }
 }
 
+// -----------
+//  GGX METAL
+// -----------
+
+/// Rough conductor made of GGX microfacets, with a Schlick Fresnel term tinted by `albedo`.
+#[derive(Clone, Debug)]
+pub struct GgxMetal {
+    albedo: Color,
+    distribution: Ggx,
+    anisotropy: Float,
+    /// Roughness varying over the surface, replacing the one of `distribution`.
+    roughness_texture: Option<Texture>,
+}
+
+impl GgxMetal {
+    /// `roughness` and `anisotropy` are both in [0, 1], the highlights of anisotropic metals
+    /// being stretched along an arbitrary tangent of the surface.
+    pub fn new(albedo: Color, roughness: Float, anisotropy: Float) -> GgxMetal {
+        GgxMetal {
+            albedo,
+            distribution: Ggx::from_roughness(roughness, anisotropy),
+            anisotropy,
+            roughness_texture: None,
+        }
+    }
+
+    /// Roughness read from the average of the channels of `texture`.
+    pub fn with_roughness_texture(mut self, texture: Texture) -> GgxMetal {
+        self.roughness_texture = Some(texture);
+        self
+    }
+
+    fn distribution(&self, hit_record: &HitRecord) -> Ggx {
+        match self.roughness_texture {
+            Some(ref texture) => Ggx::from_roughness(
+                texture
+                    .scalar(hit_record.u, hit_record.v, &hit_record.point)
+                    .clamp(0.0, 1.0),
+                self.anisotropy,
+            ),
+            None => self.distribution,
+        }
+    }
+}
+
+impl Scatterable for GgxMetal {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
+    ) -> bool {
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        if wo.z() <= 0.0 {
+            return false;
+        }
+        scatter_record.attenuation = self.albedo;
+        scatter_record.scatter_type = ScatterType::Pdf(ScatterPdf::Ggx(GgxPdf::new(
+            uvw,
+            wo,
+            self.distribution(hit_record),
+        )));
+        true
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let wi = uvw.to_local(&unit_vector(scattered_ray.direction()));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Color::zero();
+        }
+
+        let h = unit_vector(wo + wi);
+        let fresnel = schlick_fresnel(self.albedo, wo.dot(&h));
+        let distribution = self.distribution(hit_record);
+        // f * cos(wi) = F D G / (4 cos(wo) cos(wi)) * cos(wi)
+        distribution.d(&h) * distribution.g2(&wo, &wi) / (4.0 * wo.z()) * fresnel
+    }
+}
+
+// -----------
+//  CONDUCTOR
+// -----------
+
+/// Measured metals, known by their complex refraction index at the wavelengths of the red,
+/// green and blue channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConductorPreset {
+    Gold,
+    Silver,
+    Copper,
+    Aluminum,
+}
+
+impl ConductorPreset {
+    /// Real and imaginary parts of the refraction index, per channel.
+    pub fn refraction_index(&self) -> (Color, Color) {
+        match *self {
+            ConductorPreset::Gold => (
+                Color::new(0.143, 0.374, 1.442),
+                Color::new(3.983, 2.385, 1.603),
+            ),
+            ConductorPreset::Silver => (
+                Color::new(0.155, 0.117, 0.138),
+                Color::new(4.828, 3.122, 2.147),
+            ),
+            ConductorPreset::Copper => (
+                Color::new(0.200, 0.924, 1.102),
+                Color::new(3.912, 2.452, 2.142),
+            ),
+            ConductorPreset::Aluminum => (
+                Color::new(1.657, 0.880, 0.521),
+                Color::new(9.224, 6.270, 4.837),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConductorPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ConductorPreset::Gold => "gold",
+            ConductorPreset::Silver => "silver",
+            ConductorPreset::Copper => "copper",
+            ConductorPreset::Aluminum => "aluminum",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ConductorPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ConductorPreset> {
+        match s {
+            "gold" => Ok(ConductorPreset::Gold),
+            "silver" => Ok(ConductorPreset::Silver),
+            "copper" => Ok(ConductorPreset::Copper),
+            "aluminum" => Ok(ConductorPreset::Aluminum),
+            _ => bail!(
+                "Unknown metal '{}', expected one of: gold, silver, copper, aluminum",
+                s
+            ),
+        }
+    }
+}
+
+/// Metal reflecting light by the Fresnel equations of its complex refraction index `eta` +
+/// i `k`, whose reflectance and tint change with the angle: gold and copper whiten at grazing
+/// angles. Polished, unless made of GGX microfacets by a roughness.
+#[derive(Clone, Copy, Debug)]
+pub struct Conductor {
+    eta: Color,
+    k: Color,
+    /// Microfacets of rough metals, None for polished ones.
+    distribution: Option<Ggx>,
+}
+
+impl Conductor {
+    pub fn new(eta: Color, k: Color) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            distribution: None,
+        }
+    }
+
+    pub fn from_preset(preset: ConductorPreset) -> Conductor {
+        let (eta, k) = preset.refraction_index();
+        Conductor::new(eta, k)
+    }
+
+    /// Brushed or sandblasted metal, `roughness` in [0, 1] spreading the reflected rays.
+    pub fn with_roughness(mut self, roughness: Float) -> Conductor {
+        self.distribution = if roughness > 0.0 {
+            Some(Ggx::from_roughness(roughness, 0.0))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Reflectance at normal incidence, the color of the metal.
+    pub fn reflectance(&self) -> Color {
+        conductor_fresnel(1.0, self.eta, self.k)
+    }
+}
+
+impl Scatterable for Conductor {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
+    ) -> bool {
+        let distribution = match self.distribution {
+            Some(distribution) => distribution,
+            None => {
+                let direction = unit_vector(in_ray.direction());
+                let cos = -direction.dot(&hit_record.normal);
+                let reflected = reflect(direction, hit_record.normal);
+                let reflected = Ray::new(hit_record.ray_origin(&reflected), reflected)
+                    .with_channel(in_ray.channel());
+                scatter_record.attenuation = conductor_fresnel(cos, self.eta, self.k);
+                scatter_record.scatter_type = ScatterType::Specular(reflected);
+                return true;
+            }
+        };
+
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        if wo.z() <= 0.0 {
+            return false;
+        }
+        scatter_record.attenuation = self.reflectance();
+        scatter_record.scatter_type =
+            ScatterType::Pdf(ScatterPdf::Ggx(GgxPdf::new(uvw, wo, distribution)));
+        true
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let distribution = match self.distribution {
+            Some(distribution) => distribution,
+            None => return Color::zero(),
+        };
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let wi = uvw.to_local(&unit_vector(scattered_ray.direction()));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Color::zero();
+        }
+
+        let h = unit_vector(wo + wi);
+        let fresnel = conductor_fresnel(wo.dot(&h), self.eta, self.k);
+        // f * cos(wi) = F D G / (4 cos(wo) cos(wi)) * cos(wi)
+        distribution.d(&h) * distribution.g2(&wo, &wi) / (4.0 * wo.z()) * fresnel
+    }
+}
+
 // ------------
 //  DIELECTRIC
 // ------------
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Dielectric {
-    refraction_index: f32,
+    pub(crate) refraction_index: Float,
+    /// Refraction index varying over the surface, replacing `refraction_index` at the
+    /// surface. Media tracking still uses `refraction_index` for the inside of the object.
+    pub(crate) refraction_index_texture: Option<Texture>,
+    /// Absorption coefficient per channel, per unit of distance travelled inside.
+    pub(crate) absorption: Color,
+    /// Microfacets of frosted surfaces, None for smooth glass.
+    pub(crate) distribution: Option<Ggx>,
+    /// Refraction index of the red, green and blue channels for dispersive materials.
+    pub(crate) channel_refraction_indices: Option<[Float; 3]>,
 }
 
+/// Wavelengths, in micrometers, standing for the red, green and blue channels.
+const CHANNEL_WAVELENGTHS: [Float; 3] = [0.65, 0.55, 0.45];
+
 impl Dielectric {
-    pub fn new(refraction_index: f32) -> Dielectric {
-        Dielectric { refraction_index }
+    pub fn new(refraction_index: Float) -> Dielectric {
+        Dielectric {
+            refraction_index,
+            refraction_index_texture: None,
+            absorption: Color::zero(),
+            distribution: None,
+            channel_refraction_indices: None,
+        }
+    }
+
+    /// Makes the refraction index vary with the wavelength following Cauchy's equation, so
+    /// that white light splits into its colors. The refraction index given to `new` is the one
+    /// at 587.6 nm, and `abbe_number` the usual measure of the dispersion of glasses: the lower,
+    /// the more dispersive (around 60 for crown glass, 30 for flint glass, 55 for diamond).
+    ///
+    /// Paths going through the surface only trace one of the color channels from then on.
+    pub fn with_dispersion(mut self, abbe_number: Float) -> Dielectric {
+        const D_LINE: Float = 0.5876;
+        const F_LINE: Float = 0.4861;
+        const C_LINE: Float = 0.6563;
+        let n_d = self.refraction_index;
+        let b = (n_d - 1.0) / (abbe_number * (1.0 / (F_LINE * F_LINE) - 1.0 / (C_LINE * C_LINE)));
+        let a = n_d - b / (D_LINE * D_LINE);
+        let index = |wavelength: Float| a + b / (wavelength * wavelength);
+        self.channel_refraction_indices = Some([
+            index(CHANNEL_WAVELENGTHS[0]),
+            index(CHANNEL_WAVELENGTHS[1]),
+            index(CHANNEL_WAVELENGTHS[2]),
+        ]);
+        self
+    }
+
+    /// Refraction index read from the average of the channels of `texture`, taken as is, for
+    /// glass of uneven density. Ignored by dispersive glass.
+    pub fn with_refraction_index_texture(mut self, texture: Texture) -> Dielectric {
+        self.refraction_index_texture = Some(texture);
+        self
+    }
+
+    /// Frosted glass, `roughness` in [0, 1] spreading the reflected and refracted rays.
+    pub fn with_roughness(mut self, roughness: Float) -> Dielectric {
+        self.distribution = if roughness > 0.0 {
+            Some(Ggx::from_roughness(roughness, 0.0))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Colored glass or liquid following the Beer-Lambert law: `color` is the fraction of the
+    /// light left after travelling a distance of 1 / `density` inside the object.
+    pub fn with_absorption(mut self, color: Color, density: Float) -> Dielectric {
+        let coefficient = |c: Float| -c.max(1e-6).ln() * density;
+        self.absorption = Color::new(
+            coefficient(color.x()),
+            coefficient(color.y()),
+            coefficient(color.z()),
+        );
+        self
+    }
+
+    /// Fraction of the light left after travelling `distance` inside the object.
+    fn transmittance(&self, distance: Float) -> Color {
+        Color::new(
+            (-self.absorption.x() * distance).exp(),
+            (-self.absorption.y() * distance).exp(),
+            (-self.absorption.z() * distance).exp(),
+        )
+    }
+
+    /// Light going straight through the surface, less what it reflects, and less what the
+    /// inside absorbed when leaving the object.
+    fn shadow_transmittance(&self, in_ray: &Ray, hit_record: &HitRecord, distance: Float) -> Color {
+        let outside = hit_record.outside_refraction_index;
+        let refraction_ratio = match hit_record.front_face {
+            true => outside / self.refraction_index,
+            false => self.refraction_index / outside,
+        };
+        let cos_theta = unit_vector(in_ray.direction())
+            .dot(&hit_record.normal)
+            .abs()
+            .min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        if refraction_ratio * sin_theta > 1.0 {
+            return Color::zero();
+        }
+        let transmitted = 1.0 - Self::reflectance(cos_theta, refraction_ratio);
+        if hit_record.front_face {
+            Color::new(transmitted, transmitted, transmitted)
+        } else {
+            transmitted * self.transmittance(distance)
+        }
     }
 
-    fn reflectance(cos: f32, refraction_index_src: f32) -> f32 {
+    fn reflectance(cos: Float, refraction_index_src: Float) -> Float {
         // Use Schlick's approximation for reflectance.
         let mut r0 = (1.0 - refraction_index_src) / (1.0 + refraction_index_src);
         r0 = r0 * r0;
@@ -148,59 +942,1174 @@ impl Scatterable for Dielectric {
         &self,
         in_ray: &Ray,
         hit_record: &HitRecord,
-        attenuation: &mut Color,
-        scattered_ray: &mut Ray,
-        rng: &mut ThreadRng,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
     ) -> bool {
-        const AIR_REFRACTION_INDEX: f32 = 1.0;
+        scatter_record.attenuation = if hit_record.front_face {
+            Color::new(1.0, 1.0, 1.0)
+        } else {
+            // The ray travelled from the previous surface hit through the inside
+            self.transmittance(hit_record.t * in_ray.direction().length())
+        };
+        let mut refraction_index = match self.refraction_index_texture {
+            Some(ref texture) => texture.scalar(hit_record.u, hit_record.v, &hit_record.point),
+            None => self.refraction_index,
+        };
+        let mut channel = in_ray.channel();
+        if let Some(indices) = self.channel_refraction_indices {
+            let traced = match channel {
+                Some(traced) => traced,
+                None => {
+                    // The path traces one channel from here on, picked uniformly, the others
+                    // are dropped
+                    let traced = rng.gen_range(0..3);
+                    let mut mask = [0.0; 3];
+                    mask[traced as usize] = 3.0;
+                    scatter_record.attenuation *= Color::new(mask[0], mask[1], mask[2]);
+                    traced
+                }
+            };
+            refraction_index = indices[traced as usize];
+            channel = Some(traced);
+        }
 
-        *attenuation = Color::new(1.0, 1.0, 1.0);
+        let outside = hit_record.outside_refraction_index;
         let (refraction_index_src, refraction_index_dst) = match hit_record.front_face {
-            true => (AIR_REFRACTION_INDEX, self.refraction_index),
-            false => (self.refraction_index, AIR_REFRACTION_INDEX),
+            true => (outside, refraction_index),
+            false => (refraction_index, outside),
         };
 
         let refraction_ratio = refraction_index_src / refraction_index_dst;
 
         let unit_direction = unit_vector(in_ray.direction());
-        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-
-        let mut direction = Vec3::zero();
+
+        // Rough surfaces reflect and refract about a microfacet normal visible from the ray
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_direction);
+        let normal = match self.distribution {
+            Some(distribution) if wo.z() > 0.0 => {
+                uvw.local(&distribution.sample_visible_normal(&wo, rng))
+            }
+            _ => hit_record.normal,
+        };
+
+        let cos_theta = (-unit_direction).dot(&normal).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
 
-        if cannot_refract || Self::reflectance(cos_theta, refraction_ratio) > rng.gen::<f32>() {
+        let reflected =
+            cannot_refract || Self::reflectance(cos_theta, refraction_ratio) > rng.gen::<Float>();
+        let direction = if reflected {
             // Total Reflection
-            direction = reflect(unit_direction, hit_record.normal);
+            reflect(unit_direction, normal)
         } else {
             // Refract
-            direction = refract(
+            refract(
                 unit_direction,
-                hit_record.normal,
+                normal,
                 refraction_index_src,
                 refraction_index_dst,
-            );
+            )
+        };
+
+        if let Some(distribution) = self.distribution {
+            let wi = uvw.to_local(&unit_vector(direction));
+            if reflected == (wi.z() <= 0.0) {
+                // Reflected below the macro surface, or refracted back to the side of the ray
+                return false;
+            }
+            // Weight of sampling the visible normals, the shadowing of the scattered direction
+            scatter_record.attenuation *= distribution.g2(&wo, &wi) / distribution.g1(&wo);
+        }
+
+        let scattered = Ray::new(hit_record.ray_origin(&direction), direction);
+        scatter_record.scatter_type = ScatterType::Specular(scattered.with_channel(channel));
+        true
+    }
+}
+
+// ------------
+//  SUBSURFACE
+// ------------
+
+/// Translucent material (wax, skin, marble) simulated with a random walk: light refracts into
+/// the object, bounces off particles in random directions, and eventually refracts out
+/// somewhere else.
+///
+/// The walk is resolved at the next surface hit: when a ray reaches the boundary from inside,
+/// a scattering distance is drawn and, if shorter than the segment, the ray is restarted from
+/// that point instead. Each particle bounce counts towards the bounce limit, so
+/// `mean_free_path` should not be too small with respect to the size of the object.
+#[derive(Clone, Debug)]
+pub struct Subsurface {
+    /// Color kept at each particle bounce.
+    albedo: Color,
+    /// Average distance between two particle bounces.
+    mean_free_path: Float,
+    surface: Dielectric,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Color, mean_free_path: Float, refraction_index: Float) -> Subsurface {
+        Subsurface {
+            albedo,
+            mean_free_path,
+            surface: Dielectric::new(refraction_index),
+        }
+    }
+}
+
+impl Scatterable for Subsurface {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        if !hit_record.front_face {
+            // The ray travelled through the inside of the object
+            let distance = hit_record.t * in_ray.direction().length();
+            let scatter_distance = -(1.0 - rng.gen::<Float>()).ln() * self.mean_free_path;
+            if scatter_distance < distance {
+                let point = in_ray.origin() + scatter_distance * unit_vector(in_ray.direction());
+                scatter_record.attenuation = self.albedo;
+                let scattered = Ray::new(point, Vec3::random_unit_vector(rng));
+                scatter_record.scatter_type =
+                    ScatterType::Specular(scattered.with_channel(in_ray.channel()));
+                return true;
+            }
+        }
+
+        // Entering or leaving through the smooth surface
+        self.surface
+            .scatter(in_ray, hit_record, scatter_record, rng)
+    }
+}
+
+// --------
+//  VOLUME
+// --------
+
+/// Estimated transmittance under which ratio tracking plays Russian roulette...
+const VOLUME_ROULETTE_THRESHOLD: Float = 0.1;
+/// ...going on with this probability.
+const VOLUME_ROULETTE_SURVIVAL: Float = 0.5;
+
+/// Participating medium of varying density (smoke, clouds) filling its object, whose surface
+/// doesn't refract: rays go straight through it, and are scattered evenly in all directions
+/// inside, at the collisions delta tracking samples.
+///
+/// As with `Subsurface`, the walk is resolved at the next surface hit from the inside, the
+/// object must not overlap others. Tracking steps as far as the `density` can be at most, the
+/// peaks of sparse grids make it slower.
+///
+/// Shadow rays going through, with transparent shadows, are attenuated by an estimate of the
+/// transmittance from ratio tracking.
+#[derive(Clone, Debug)]
+pub struct Volume {
+    /// Color kept at each scattering.
+    albedo: Color,
+    density: Density,
+    /// Extinction coefficient of a unit of `density`, per unit of length of the scene.
+    density_scale: Float,
+}
+
+impl Volume {
+    pub fn new(density: Density, density_scale: Float, albedo: Color) -> Volume {
+        Volume {
+            albedo,
+            density,
+            density_scale,
+        }
+    }
+
+    /// Distance along `in_ray`, from its origin in the medium, at which it collides with a
+    /// particle before `distance`, if it does. Delta tracking: collisions are sampled as if the
+    /// medium was as dense as it can be everywhere, then kept with the probability of the
+    /// actual density over that bound, the others being null collisions.
+    pub(crate) fn sample_collision(
+        &self,
+        in_ray: &Ray,
+        distance: Float,
+        rng: &mut SampleRng,
+    ) -> Option<Float> {
+        let majorant = self.density_scale * self.density.maximum();
+        if majorant <= 0.0 {
+            return None;
+        }
+        let direction = unit_vector(in_ray.direction());
+        let mut travelled = 0.0;
+        loop {
+            travelled -= (1.0 - rng.gen::<Float>()).ln() / majorant;
+            if travelled >= distance {
+                return None;
+            }
+            let point = in_ray.origin() + travelled * direction;
+            let extinction = self.density_scale * self.density.value(&point);
+            if rng.gen::<Float>() * majorant < extinction {
+                return Some(travelled);
+            }
+        }
+    }
+
+    /// Unbiased estimate of the fraction of the light going through the medium along `ray`,
+    /// from its origin, over `distance`. Ratio tracking: the product, over the collisions
+    /// sampled against the bound of the density, of the probabilities of them being null ones.
+    /// Russian roulette ends the estimates becoming negligible.
+    fn transmittance(&self, ray: &Ray, distance: Float, rng: &mut SampleRng) -> Float {
+        let majorant = self.density_scale * self.density.maximum();
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+        let direction = unit_vector(ray.direction());
+        let mut transmittance = 1.0;
+        let mut travelled = 0.0;
+        loop {
+            travelled -= (1.0 - rng.gen::<Float>()).ln() / majorant;
+            if travelled >= distance {
+                return transmittance;
+            }
+            let point = ray.origin() + travelled * direction;
+            let extinction = self.density_scale * self.density.value(&point);
+            transmittance *= 1.0 - extinction / majorant;
+            if transmittance < VOLUME_ROULETTE_THRESHOLD {
+                if rng.gen::<Float>() >= VOLUME_ROULETTE_SURVIVAL {
+                    return 0.0;
+                }
+                transmittance /= VOLUME_ROULETTE_SURVIVAL;
+            }
+        }
+    }
+
+    /// Light kept by a shadow ray leaving through the surface at `hit_record`, having
+    /// travelled `distance` inside.
+    fn shadow_transmittance(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        distance: Float,
+        rng: &mut SampleRng,
+    ) -> Color {
+        let transmittance = if hit_record.front_face {
+            1.0
+        } else {
+            self.transmittance(ray, distance, rng)
+        };
+        Color::new(transmittance, transmittance, transmittance)
+    }
+}
+
+impl Scatterable for Volume {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        if !hit_record.front_face {
+            // The ray travelled through the inside of the object
+            let distance = hit_record.t * in_ray.direction().length();
+            if let Some(travelled) = self.sample_collision(in_ray, distance, rng) {
+                let point = in_ray.origin() + travelled * unit_vector(in_ray.direction());
+                scatter_record.attenuation = self.albedo;
+                let scattered = Ray::new(point, Vec3::random_unit_vector(rng));
+                scatter_record.scatter_type =
+                    ScatterType::Specular(scattered.with_channel(in_ray.channel()));
+                return true;
+            }
+        }
+
+        // Entering or leaving through the invisible surface
+        let direction = in_ray.direction();
+        scatter_record.attenuation = Color::new(1.0, 1.0, 1.0);
+        let scattered = Ray::new(hit_record.ray_origin(&direction), direction);
+        scatter_record.scatter_type =
+            ScatterType::Specular(scattered.with_channel(in_ray.channel()));
+        true
+    }
+}
+
+// ---------------
+//  DIFFUSE LIGHT
+// ---------------
+
+#[derive(Clone, Debug)]
+pub struct DiffuseLight {
+    emit: Color,
+    /// Multiplies `emit`, varying over the surface.
+    pub(crate) emission_texture: Option<Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color) -> DiffuseLight {
+        DiffuseLight {
+            emit,
+            emission_texture: None,
+        }
+    }
+
+    /// Glow map scaling the emitted light, channel by channel.
+    pub fn with_emission_texture(mut self, texture: Texture) -> DiffuseLight {
+        self.emission_texture = Some(texture);
+        self
+    }
+}
+
+impl Scatterable for DiffuseLight {
+    fn scatter(
+        &self,
+        _in_ray: &Ray,
+        _hit_record: &HitRecord,
+        _scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
+    ) -> bool {
+        false
+    }
+
+    fn emitted(&self, _in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        if !hit_record.front_face {
+            return Color::zero();
+        }
+        match self.emission_texture {
+            Some(ref texture) => {
+                self.emit * texture.value(hit_record.u, hit_record.v, &hit_record.point)
+            }
+            None => self.emit,
+        }
+    }
+}
+
+// ------------
+//  PRINCIPLED
+// ------------
+
+/// Roughness of the clearcoat layer, which is always glossy.
+const CLEARCOAT_ROUGHNESS: Float = 0.1;
+
+/// Simplified Disney "principled" material: a single base color blending diffuse, metallic and
+/// glass behaviors, plus a clearcoat layer, all driven by parameters in [0, 1].
+///
+/// Built with `new`, then the `with_*` methods for the parameters differing from the defaults.
+#[derive(Clone, Debug)]
+pub struct Principled {
+    base_color: Color,
+    metallic: Float,
+    roughness: Float,
+    /// Reflectance of dielectrics at normal incidence, scaled so that 0.5 is 4% (IOR of 1.5).
+    specular: Float,
+    clearcoat: Float,
+    transmission: Float,
+    /// Metallic and roughness varying over the surface, replacing the constants.
+    metallic_texture: Option<Texture>,
+    roughness_texture: Option<Texture>,
+}
+
+impl Principled {
+    pub fn new(base_color: Color) -> Principled {
+        Principled {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            clearcoat: 0.0,
+            transmission: 0.0,
+            metallic_texture: None,
+            roughness_texture: None,
+        }
+    }
+
+    pub fn with_metallic(mut self, metallic: Float) -> Principled {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: Float) -> Principled {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn with_specular(mut self, specular: Float) -> Principled {
+        self.specular = specular;
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: Float) -> Principled {
+        self.clearcoat = clearcoat;
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: Float) -> Principled {
+        self.transmission = transmission;
+        self
+    }
+
+    /// Metallic read from the average of the channels of `texture`.
+    pub fn with_metallic_texture(mut self, texture: Texture) -> Principled {
+        self.metallic_texture = Some(texture);
+        self
+    }
+
+    /// Roughness read from the average of the channels of `texture`.
+    pub fn with_roughness_texture(mut self, texture: Texture) -> Principled {
+        self.roughness_texture = Some(texture);
+        self
+    }
+
+    /// Metallic and roughness at the hit point.
+    fn metallic_roughness(&self, hit_record: &HitRecord) -> (Float, Float) {
+        let lookup = |texture: &Option<Texture>, constant: Float| match texture {
+            Some(texture) => texture
+                .scalar(hit_record.u, hit_record.v, &hit_record.point)
+                .clamp(0.0, 1.0),
+            None => constant,
+        };
+        (
+            lookup(&self.metallic_texture, self.metallic),
+            lookup(&self.roughness_texture, self.roughness),
+        )
+    }
+
+    /// Reflectance at normal incidence, tinted by the base color for metals.
+    fn f0(&self, metallic: Float) -> Color {
+        let dielectric = 0.08 * self.specular;
+        (1.0 - metallic) * Color::new(dielectric, dielectric, dielectric)
+            + metallic * self.base_color
+    }
+
+    /// Probability of scattering through the glass lobe, which is specular.
+    fn transmission_weight(&self, metallic: Float) -> Float {
+        (1.0 - metallic) * self.transmission
+    }
+
+    fn diffuse_weight(&self, metallic: Float) -> Float {
+        (1.0 - metallic) * (1.0 - self.transmission)
+    }
+
+    fn clearcoat_distribution(&self) -> Ggx {
+        Ggx::from_roughness(CLEARCOAT_ROUGHNESS, 0.0)
+    }
+
+    /// Glass with the index of refraction matching `specular`.
+    fn glass(&self) -> Dielectric {
+        let sqrt_f0 = (0.08 * self.specular).sqrt().min(0.99);
+        Dielectric::new((1.0 + sqrt_f0) / (1.0 - sqrt_f0))
+    }
+}
+
+impl Scatterable for Principled {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        let (metallic, roughness) = self.metallic_roughness(hit_record);
+        if rng.gen::<Float>() < self.transmission_weight(metallic) {
+            self.glass()
+                .scatter(in_ray, hit_record, scatter_record, rng);
+            scatter_record.attenuation = self.base_color;
+            return true;
+        }
+
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        if wo.z() <= 0.0 {
+            return false;
+        }
+        scatter_record.attenuation = self.base_color;
+        scatter_record.scatter_type = ScatterType::Pdf(ScatterPdf::Principled(PrincipledPdf::new(
+            uvw,
+            wo,
+            Ggx::from_roughness(roughness, 0.0),
+            self.clearcoat_distribution(),
+            [self.diffuse_weight(metallic), 1.0, 0.25 * self.clearcoat],
+        )));
+        true
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let (metallic, roughness) = self.metallic_roughness(hit_record);
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let wi = uvw.to_local(&unit_vector(scattered_ray.direction()));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 || self.transmission_weight(metallic) >= 1.0 {
+            return Color::zero();
+        }
+        let h = unit_vector(wo + wi);
+
+        let diffuse = self.diffuse_weight(metallic) * wi.z() / PI * self.base_color;
+
+        let specular_distribution = Ggx::from_roughness(roughness, 0.0);
+        let specular = specular_distribution.d(&h) * specular_distribution.g2(&wo, &wi)
+            / (4.0 * wo.z())
+            * schlick_fresnel(self.f0(metallic), wo.dot(&h));
+
+        let clearcoat_distribution = self.clearcoat_distribution();
+        let clearcoat = 0.25
+            * self.clearcoat
+            * clearcoat_distribution.d(&h)
+            * clearcoat_distribution.g2(&wo, &wi)
+            / (4.0 * wo.z())
+            * schlick_fresnel(Color::new(0.04, 0.04, 0.04), wo.dot(&h));
+
+        // Only reached when the glass lobe wasn't picked
+        (diffuse + specular + clearcoat) / (1.0 - self.transmission_weight(metallic))
+    }
+}
+
+// ------
+//  HAIR
+// ------
+
+/// Refraction index of the keratin of hair, whose Fresnel reflectance weighs the highlight of
+/// the fibers against their color.
+const HAIR_REFRACTION_INDEX: Float = 1.55;
+
+/// Hair or fur fiber, for curves: a white highlight reflected by its surface, and a second one
+/// tinted by `color` from the light going through it. `roughness` in [0, 1] spreads them along
+/// the fiber, the light being scattered evenly all around it.
+#[derive(Clone, Copy, Debug)]
+pub struct Hair {
+    color: Color,
+    roughness: Float,
+}
+
+impl Hair {
+    pub fn new(color: Color, roughness: Float) -> Hair {
+        Hair { color, roughness }
+    }
+
+    fn pdf(&self, in_ray: &Ray, hit_record: &HitRecord) -> HairPdf {
+        let wo = -unit_vector(in_ray.direction());
+        let normal = hit_record.normal;
+        // Along the fiber, across the normal, for surfaces not providing it any direction will do
+        let tangent = hit_record.tangent - hit_record.tangent.dot(&normal) * normal;
+        let tangent = if tangent.length_squared() > 0.0 {
+            unit_vector(tangent)
+        } else {
+            Onb::build_from_w(&normal).u()
+        };
+        let f0 = ((HAIR_REFRACTION_INDEX - 1.0) / (HAIR_REFRACTION_INDEX + 1.0)).powi(2);
+        let reflectance = schlick_fresnel(Color::new(f0, f0, f0), wo.dot(&normal).abs()).x();
+        HairPdf::new(
+            Onb::from_tangent_frame(&tangent, &normal),
+            &wo,
+            self.roughness,
+            reflectance,
+        )
+    }
+}
+
+impl Scatterable for Hair {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        _rng: &mut SampleRng,
+    ) -> bool {
+        scatter_record.attenuation = self.color;
+        scatter_record.scatter_type =
+            ScatterType::Pdf(ScatterPdf::Hair(self.pdf(in_ray, hit_record)));
+        true
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        // The PDF follows the lobes exactly, but for the color of the light going through
+        let (reflected, through) = self
+            .pdf(in_ray, hit_record)
+            .lobes(&scattered_ray.direction());
+        reflected * Color::new(1.0, 1.0, 1.0) + through * self.color
+    }
+}
+
+// --------
+//  COATED
+// --------
+
+/// Thin layer of varnish on top of another material, as on car paint or varnished wood: a
+/// glossy dielectric reflection, and under it the base, seen through the coat.
+///
+/// The light reaching the base and coming back out loses what the coat reflects on the way in
+/// and on the way out, and is filtered by `tint`. Seen from the inside of the object, the coat
+/// is ignored.
+#[derive(Clone, Debug)]
+pub struct Coated {
+    base: Box<Material>,
+    refraction_index: Float,
+    distribution: Ggx,
+    tint: Color,
+}
+
+impl Coated {
+    /// Clear coat of refraction index `refraction_index` (1.5 for most varnishes) over `base`,
+    /// `roughness` in [0, 1] spreading its reflection.
+    pub fn new(base: Material, refraction_index: Float, roughness: Float) -> Coated {
+        Coated {
+            base: Box::new(base),
+            refraction_index,
+            distribution: Ggx::from_roughness(roughness, 0.0),
+            tint: Color::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Colored coat, `tint` being the fraction of the light it lets through to the base and
+    /// back.
+    pub fn with_tint(mut self, tint: Color) -> Coated {
+        self.tint = tint;
+        self
+    }
+
+    /// Fraction of the light the coat reflects at an angle of cosine `cos` with its normal.
+    fn fresnel(&self, cos: Float, hit_record: &HitRecord) -> Float {
+        let r0 = (hit_record.outside_refraction_index - self.refraction_index)
+            / (hit_record.outside_refraction_index + self.refraction_index);
+        let r0 = r0 * r0;
+        r0 + (1.0 - r0) * (1.0 - cos).max(0.0).powi(5)
+    }
+
+    /// Probability of sampling the coat rather than the base, from how much light each
+    /// reflects.
+    fn coat_weight(&self, cos: Float, hit_record: &HitRecord) -> Float {
+        let coat = self.fresnel(cos, hit_record);
+        let base = (1.0 - coat) * luminance(self.tint * self.base.albedo());
+        if coat + base <= 0.0 {
+            return 0.5;
+        }
+        (coat / (coat + base)).clamp(0.1, 0.9)
+    }
+
+    /// Fraction of the light the base sends along `wi` which goes through the coat, after it
+    /// came in along `wo`, both in the local frame.
+    fn transmittance(&self, wo: &Vec3, wi: &Vec3, hit_record: &HitRecord) -> Color {
+        let mut transmitted = 1.0 - self.fresnel(wo.z(), hit_record);
+        if wi.z() > 0.0 {
+            // Reflected by the base, through the coat again
+            transmitted *= 1.0 - self.fresnel(wi.z(), hit_record);
+        }
+        transmitted * self.tint
+    }
+}
+
+impl Scatterable for Coated {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let scattered = self.base.scatter(in_ray, hit_record, scatter_record, rng);
+        if wo.z() <= 0.0 {
+            return scattered;
         }
 
-        *scattered_ray = Ray::new(hit_record.point, direction);
+        let coat = GgxPdf::new(uvw, wo, self.distribution);
+        let coat_weight = self.coat_weight(wo.z(), hit_record);
+        let base = match scatter_record.scatter_type {
+            ScatterType::Pdf(ref base) if scattered => Some(base.clone()),
+            ScatterType::Specular(ray) if scattered && rng.gen::<Float>() >= coat_weight => {
+                let wi = uvw.to_local(&unit_vector(ray.direction()));
+                scatter_record.attenuation *=
+                    self.transmittance(&wo, &wi, hit_record) / (1.0 - coat_weight);
+                return true;
+            }
+            _ => None,
+        };
+        // A base which doesn't scatter still has the coat reflecting on top of it
+        let coat_weight = if scattered { coat_weight } else { 1.0 };
+        scatter_record.scatter_type =
+            ScatterType::Pdf(ScatterPdf::Coated(CoatedPdf::new(coat, base, coat_weight)));
         true
     }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        let base = self.base.eval(in_ray, hit_record, scattered_ray);
+        let uvw = Onb::build_from_w(&hit_record.normal);
+        let wo = uvw.to_local(&-unit_vector(in_ray.direction()));
+        let wi = uvw.to_local(&unit_vector(scattered_ray.direction()));
+        if wo.z() <= 0.0 {
+            return base;
+        }
+
+        let base = self.transmittance(&wo, &wi, hit_record) * base;
+        if wi.z() <= 0.0 {
+            return base;
+        }
+        let h = unit_vector(wo + wi);
+        let coat = self.distribution.d(&h) * self.distribution.g2(&wo, &wi) / (4.0 * wo.z())
+            * self.fresnel(wo.dot(&h), hit_record);
+        base + Color::new(coat, coat, coat)
+    }
+
+    fn emitted(&self, in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        let emitted = self.base.emitted(in_ray, hit_record);
+        let cos = -unit_vector(in_ray.direction()).dot(&hit_record.normal);
+        if emitted == Color::zero() || cos <= 0.0 {
+            return emitted;
+        }
+        (1.0 - self.fresnel(cos, hit_record)) * self.tint * emitted
+    }
+}
+
+// ---------------
+//  NORMAL MAPPED
+// ---------------
+
+/// Wraps a material to perturb its shading normal with a tangent space normal map: each texel
+/// encodes a normal whose components are remapped from [0, 1] to [-1, 1], X following the
+/// tangent of the surface, Y the bitangent and Z the unperturbed normal.
+#[derive(Clone, Debug)]
+pub struct NormalMapped {
+    base: Box<Material>,
+    normal_map: Texture,
+}
+
+impl NormalMapped {
+    pub fn new(base: Material, normal_map: Texture) -> NormalMapped {
+        NormalMapped {
+            base: Box::new(base),
+            normal_map,
+        }
+    }
+
+    /// Copy of `hit_record` with the shading normal from the normal map.
+    fn perturb(&self, hit_record: &HitRecord) -> HitRecord {
+        let normal = hit_record.normal;
+        // Gram-Schmidt, the tangent isn't always orthogonal to the normal
+        let mut tangent = hit_record.tangent - hit_record.tangent.dot(&normal) * normal;
+        if tangent.length_squared() < 1e-12 {
+            let uvw = Onb::build_from_w(&normal);
+            tangent = uvw.u();
+        }
+        let uvw = Onb::from_tangent_frame(&unit_vector(tangent), &normal);
+
+        let texel = self
+            .normal_map
+            .value(hit_record.u, hit_record.v, &hit_record.point);
+        let local_normal = 2.0 * texel - Vec3::new(1.0, 1.0, 1.0);
+
+        let mut perturbed = *hit_record;
+        perturbed.normal = unit_vector(uvw.local(&local_normal));
+        perturbed
+    }
+}
+
+impl Scatterable for NormalMapped {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        self.base
+            .scatter(in_ray, &self.perturb(hit_record), scatter_record, rng)
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        self.base
+            .eval(in_ray, &self.perturb(hit_record), scattered_ray)
+    }
+
+    fn emitted(&self, in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        self.base.emitted(in_ray, hit_record)
+    }
+}
+
+// -----
+//  MIX
+// -----
+
+/// Blend of two materials, each hit point behaving as `second` with a probability given by
+/// `factor`, the average of the channels of the texture, and as `first` otherwise.
+///
+/// The choice is made from a hash of the hit point rather than drawn at random, so that the
+/// sampling and the evaluation of the BSDF at a given point agree on the material.
+#[derive(Clone, Debug)]
+pub struct Mix {
+    first: Box<Material>,
+    second: Box<Material>,
+    factor: Texture,
+}
+
+impl Mix {
+    pub fn new(first: Material, second: Material, factor: Texture) -> Mix {
+        Mix {
+            first: Box::new(first),
+            second: Box::new(second),
+            factor,
+        }
+    }
+
+    fn choose(&self, hit_record: &HitRecord) -> &Material {
+        let factor = self
+            .factor
+            .scalar(hit_record.u, hit_record.v, &hit_record.point);
+        if hash_point(&hit_record.point) < factor {
+            &self.second
+        } else {
+            &self.first
+        }
+    }
+}
+
+impl Scatterable for Mix {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit_record: &HitRecord,
+        scatter_record: &mut ScatterRecord,
+        rng: &mut SampleRng,
+    ) -> bool {
+        self.choose(hit_record)
+            .scatter(in_ray, hit_record, scatter_record, rng)
+    }
+
+    fn eval(&self, in_ray: &Ray, hit_record: &HitRecord, scattered_ray: &Ray) -> Color {
+        self.choose(hit_record)
+            .eval(in_ray, hit_record, scattered_ray)
+    }
+
+    fn emitted(&self, in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        self.choose(hit_record).emitted(in_ray, hit_record)
+    }
+}
+
+/// Uniform value in [0, 1) derived from the bits of `point`.
+// The cast of the bits is a no-op with the `f64` feature
+#[allow(clippy::unnecessary_cast)]
+fn hash_point(point: &Point3) -> Float {
+    let mut h = 0x9e37_79b9_7f4a_7c15u64;
+    for c in &[point.x(), point.y(), point.z()] {
+        // SplitMix64 finalizer
+        h ^= c.to_bits() as u64;
+        h = h.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+    (h >> 11) as Float / (1u64 << 53) as Float
 }
 
 fn reflect(vec: Vec3, normal: Vec3) -> Vec3 {
-    return vec - 2.0 * vec.dot(&normal) * normal;
+    vec - 2.0 * vec.dot(&normal) * normal
 }
 
 fn refract(
     i_ray: Vec3,
     normal: Vec3,
-    refraction_index_src: f32,
-    refraction_index_dst: f32,
+    refraction_index_src: Float,
+    refraction_index_dst: Float,
 ) -> Vec3 {
     let etai_over_etat = refraction_index_src / refraction_index_dst;
-    let cos_theta = 1.0f32.min(-i_ray.dot(&normal));
+    let cos_theta = (-i_ray.dot(&normal)).min(1.0);
     let r_out_perp = etai_over_etat * (i_ray + cos_theta * normal);
     let r_out_parallel = -(1.0 - r_out_perp.length_squared()).abs().sqrt() * normal;
-    return r_out_perp + r_out_parallel;
+    r_out_perp + r_out_parallel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Checker;
+    use crate::volume::Pyroclastic;
+
+    fn hit_record() -> HitRecord {
+        let mut hit_record = HitRecord::empty();
+        hit_record.normal = Vec3::new(0.0, 1.0, 0.0);
+        hit_record.tangent = Vec3::new(1.0, 0.0, 0.0);
+        hit_record
+    }
+
+    #[test]
+    fn test_dielectric_absorption() {
+        let glass = Dielectric::new(1.5).with_absorption(Color::new(0.5, 1.0, 0.25), 2.0);
+        let t = glass.transmittance(0.5);
+        assert!((t - Color::new(0.5, 1.0, 0.25)).length() < 1e-5);
+        assert_eq!(
+            Dielectric::new(1.5).transmittance(10.0),
+            Color::new(1.0, 1.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn test_hair_lobes() {
+        // White fibers keep all the light, their BSDF following the PDF exactly
+        let white = Hair::new(Color::new(1.0, 1.0, 1.0), 0.4);
+        let brown = Hair::new(Color::new(0.4, 0.2, 0.1), 0.4);
+        let in_ray = Ray::new(Point3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0));
+        let mut scatter_record = ScatterRecord::empty();
+        let mut rng = SampleRng::new(0);
+        assert!(white.scatter(&in_ray, &hit_record(), &mut scatter_record, &mut rng));
+        let pdf = match scatter_record.scatter_type {
+            ScatterType::Pdf(pdf) => pdf,
+            ScatterType::Specular(_) => panic!("Hair scattering is not specular"),
+        };
+        for _ in 0..100 {
+            let scattered = Ray::new(Point3::zero(), pdf.generate(&mut rng));
+            let value = pdf.value(&scattered.direction());
+            let bsdf = white.eval(&in_ray, &hit_record(), &scattered);
+            assert!((bsdf - Color::new(value, value, value)).length() <= 1e-4 * value);
+            // Only the light going through is tinted
+            let tinted = brown.eval(&in_ray, &hit_record(), &scattered);
+            assert!(tinted.x() > tinted.z() && tinted.z() > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_volume_tracking() {
+        let cloud = Pyroclastic::new(Point3::zero(), 1.0, 0.5, 0);
+        let volume = Volume::new(Density::Pyroclastic(cloud), 0.5, Color::new(1.0, 1.0, 1.0));
+        let in_ray = Ray::new(Point3::new(-1.5, 0.1, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        let mut hit_record = hit_record();
+        hit_record.t = 1.5;
+        hit_record.point = in_ray.at(hit_record.t);
+        hit_record.set_face_normal(&in_ray, &Vec3::new(1.0, 0.0, 0.0));
+
+        // Delta tracking lets rays through as often as ratio tracking estimates they do
+        let mut rng = SampleRng::new(0);
+        let sample_count = 50_000;
+        let (mut through, mut transmittance) = (0, 0.0);
+        for _ in 0..sample_count {
+            let mut scatter_record = ScatterRecord::empty();
+            assert!(volume.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+            if let ScatterType::Specular(scattered) = scatter_record.scatter_type {
+                if scattered.origin().x() > 1.4 {
+                    through += 1;
+                }
+            }
+            let material = Material::Volume(volume.clone());
+            let estimate = material.shadow_transmittance(&in_ray, &hit_record, 3.0, &mut rng);
+            transmittance += estimate.unwrap().x();
+        }
+        let through = through as Float / sample_count as Float;
+        let transmittance = transmittance / sample_count as Float;
+        assert!(through > 0.05 && through < 0.95);
+        assert!((through - transmittance).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_conductor_presets() {
+        // Gold is yellow head on, and whitens at grazing angles
+        let gold = Conductor::from_preset(ConductorPreset::Gold);
+        let color = gold.reflectance();
+        assert!(color.x() > color.y() && color.y() > color.z());
+        let mut scatter_record = ScatterRecord::empty();
+        let grazing = Ray::new(Point3::new(-1.0, 0.01, 0.0), Vec3::new(1.0, -0.01, 0.0));
+        let mut rng = SampleRng::new(0);
+        assert!(gold.scatter(&grazing, &hit_record(), &mut scatter_record, &mut rng));
+        let attenuation = scatter_record.attenuation;
+        assert!(attenuation.z() > 0.9 && attenuation.z() > color.z());
+
+        let silver = Conductor::from_preset(ConductorPreset::Silver).reflectance();
+        assert!(silver.x() > 0.9 && silver.z() > 0.9);
+        assert!("brass".parse::<ConductorPreset>().is_err());
+    }
+
+    #[test]
+    fn test_coated_reflectance() {
+        // Fraction of the light coming from `direction` reflected by `material`
+        let albedo = |material: &Coated, direction: Vec3| {
+            let mut rng = SampleRng::new(0);
+            let in_ray = Ray::new(Point3::zero() - direction, direction);
+            let n = 20_000;
+            let sum = (0..n).fold(Color::zero(), |sum, _| {
+                let mut scatter_record = ScatterRecord::empty();
+                material.scatter(&in_ray, &hit_record(), &mut scatter_record, &mut rng);
+                match scatter_record.scatter_type {
+                    ScatterType::Pdf(ref pdf) => {
+                        let scattered_ray = Ray::new(Point3::zero(), pdf.generate(&mut rng));
+                        let pdf = pdf.value(&scattered_ray.direction());
+                        sum + material.eval(&in_ray, &hit_record(), &scattered_ray) / pdf
+                    }
+                    ScatterType::Specular(_) => sum + scatter_record.attenuation,
+                }
+            });
+            sum / n as Float
+        };
+        let head_on = Vec3::new(0.0, -1.0, 0.0);
+        let grazing = unit_vector(Vec3::new(1.0, -0.1, 0.0));
+
+        // A white base under the coat loses the light the coat reflects, but not more
+        let white = Material::Lambertian(Lambertian::new(Color::new(1.0, 1.0, 1.0)));
+        let coated = Coated::new(white, 1.5, 0.2);
+        for direction in &[head_on, grazing] {
+            let reflected = albedo(&coated, *direction).x();
+            assert!(reflected > 0.8 && reflected < 1.02, "{}", reflected);
+        }
+
+        // Over a black base, the coat reflects little head on and much more at grazing angles
+        let black = Material::Lambertian(Lambertian::new(Color::zero()));
+        let coated = Coated::new(black, 1.5, 0.2);
+        let head_on = albedo(&coated, head_on).x();
+        assert!((head_on - 0.04).abs() < 0.01, "{}", head_on);
+        let grazing = albedo(&coated, grazing).x();
+        assert!(grazing > 0.3, "{}", grazing);
+
+        // The tint filters the light seen through the coat
+        let mirror = Material::Metal(Metal::new(Color::new(1.0, 1.0, 1.0), 0.0));
+        let coated = Coated::new(mirror, 1.5, 0.2).with_tint(Color::new(1.0, 0.5, 0.0));
+        let color = albedo(&coated, Vec3::new(0.0, -1.0, 0.0));
+        assert!(color.x() > 0.95 && (color.y() - 0.5).abs() < 0.05 && color.z() < 0.1);
+    }
+
+    #[test]
+    fn test_black_back_faces() {
+        let mut materials = MaterialList::new();
+        let white = Material::Lambertian(Lambertian::new(Color::new(1.0, 1.0, 1.0)));
+        let black = materials.add_with_back_faces(white, BackFaces::Black);
+        let mut hit_record = hit_record();
+        hit_record.material = black;
+        let in_ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let scattered_ray = Ray::new(Point3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        let mut scatter_record = ScatterRecord::empty();
+        let mut rng = SampleRng::new(0);
+
+        hit_record.front_face = true;
+        let material = &materials[black];
+        assert!(material.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+        assert!(material.eval(&in_ray, &hit_record, &scattered_ray).x() > 0.0);
+        hit_record.front_face = false;
+        assert!(!material.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+        assert_eq!(
+            material.eval(&in_ray, &hit_record, &scattered_ray),
+            Color::zero()
+        );
+        assert_eq!("culled".parse::<BackFaces>().unwrap(), BackFaces::Culled);
+    }
+
+    #[test]
+    fn test_dispersion() {
+        let flint = Dielectric::new(1.62).with_dispersion(36.0);
+        let [red, green, blue] = flint.channel_refraction_indices.unwrap();
+        assert!(red < green && green < blue);
+        assert!((green - 1.62).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_dispersion_channel_kept() {
+        let flint = Dielectric::new(1.62).with_dispersion(36.0);
+        let mut hit_record = hit_record();
+        hit_record.geometric_normal = hit_record.normal;
+        hit_record.outside_refraction_index = 1.0;
+        let mut rng = SampleRng::new(0);
+        for _ in 0..10 {
+            // Picked at the first surface, weighted once
+            hit_record.front_face = true;
+            let in_ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+            let mut scatter_record = ScatterRecord::empty();
+            assert!(flint.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+            let channel = match scatter_record.scatter_type {
+                ScatterType::Specular(ray) => ray.channel().unwrap(),
+                ScatterType::Pdf(_) => panic!("Glass scattering is specular"),
+            };
+            let mut mask = [0.0; 3];
+            mask[channel as usize] = 3.0;
+            assert_eq!(
+                scatter_record.attenuation,
+                Color::new(mask[0], mask[1], mask[2])
+            );
+
+            hit_record.front_face = false;
+            let in_ray = in_ray.with_channel(Some(channel));
+            assert!(flint.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+            match scatter_record.scatter_type {
+                ScatterType::Specular(ray) => assert_eq!(ray.channel(), Some(channel)),
+                ScatterType::Pdf(_) => panic!("Glass scattering is specular"),
+            }
+            assert_eq!(scatter_record.attenuation, Color::new(1.0, 1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn test_mix_proportion() {
+        let mix = Mix::new(
+            Material::Lambertian(Lambertian::new(Color::new(1.0, 0.0, 0.0))),
+            Material::Lambertian(Lambertian::new(Color::new(0.0, 1.0, 0.0))),
+            Texture::Solid(Color::new(0.2, 0.2, 0.2)),
+        );
+        let mut rng = SampleRng::new(0);
+        let n = 10_000;
+        let second = (0..n)
+            .filter(|_| {
+                let mut hit_record = HitRecord::empty();
+                hit_record.point = Vec3::random(&mut rng);
+                mix.choose(&hit_record).albedo().y() > 0.0
+            })
+            .count();
+        let proportion = second as Float / n as Float;
+        assert!(
+            (proportion - 0.2).abs() < 0.02,
+            "proportion = {}",
+            proportion
+        );
+    }
+
+    #[test]
+    fn test_textured_fuzz() {
+        // Polished on even cells, fully fuzzy on odd ones
+        let checker = Checker::new(Color::zero(), Color::new(1.0, 1.0, 1.0), 1.0);
+        let metal =
+            Metal::new(Color::new(0.8, 0.8, 0.8), 0.5).with_fuzz_texture(Texture::Checker(checker));
+        let mut hit_record = hit_record();
+        hit_record.point = Point3::new(0.5, 0.5, 0.5);
+        assert_eq!(metal.fuzz(&hit_record), 0.0);
+        hit_record.point = Point3::new(1.5, 0.5, 0.5);
+        assert_eq!(metal.fuzz(&hit_record), 1.0);
+    }
+
+    #[test]
+    fn test_solid_texture_matches_constant() {
+        let base_color = Color::new(0.9, 0.6, 0.3);
+        let constant = Principled::new(base_color)
+            .with_metallic(0.25)
+            .with_roughness(0.4);
+        let textured = Principled::new(base_color)
+            .with_metallic_texture(Texture::Solid(Color::new(0.25, 0.25, 0.25)))
+            .with_roughness_texture(Texture::Solid(Color::new(0.2, 0.4, 0.6)));
+
+        let hit_record = hit_record();
+        let in_ray = Ray::new(Point3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0));
+        let scattered_ray = Ray::new(Point3::zero(), Vec3::new(1.0, 2.0, 0.5));
+        let expected = constant.eval(&in_ray, &hit_record, &scattered_ray);
+        let actual = textured.eval(&in_ray, &hit_record, &scattered_ray);
+        assert!((expected - actual).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_glow_map() {
+        let light = DiffuseLight::new(Color::new(4.0, 4.0, 4.0))
+            .with_emission_texture(Texture::Solid(Color::new(1.0, 0.5, 0.0)));
+        let mut hit_record = hit_record();
+        hit_record.front_face = true;
+        let in_ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert_eq!(
+            light.emitted(&in_ray, &hit_record),
+            Color::new(4.0, 2.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_flat_normal_map() {
+        let base = Material::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let material = NormalMapped::new(base, Texture::Solid(Color::new(0.5, 0.5, 1.0)));
+        let perturbed = material.perturb(&hit_record());
+        assert!((perturbed.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn test_normal_map_tilts_towards_tangent() {
+        let base = Material::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let material = NormalMapped::new(base, Texture::Solid(Color::new(1.0, 0.5, 1.0)));
+        let perturbed = material.perturb(&hit_record());
+        let expected = unit_vector(Vec3::new(1.0, 1.0, 0.0));
+        assert!((perturbed.normal - expected).length() < 1e-6);
+    }
 }
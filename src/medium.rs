@@ -0,0 +1,72 @@
+use crate::float::Float;
+use crate::material::MaterialId;
+
+/// Refraction index of the void surrounding all the objects.
+pub const AIR_REFRACTION_INDEX: Float = 1.0;
+
+/// Dielectric objects a path is currently inside of, innermost last.
+///
+/// Lets refraction at the interface between two objects use the index of the object the ray
+/// is actually leaving or entering, e.g. between a glass and the water it contains, instead of
+/// always assuming the other side is air.
+#[derive(Clone, Debug, Default)]
+pub struct MediumStack {
+    media: Vec<(MaterialId, Float)>,
+}
+
+impl MediumStack {
+    pub fn new() -> MediumStack {
+        MediumStack { media: Vec::new() }
+    }
+
+    /// Refraction index on the outside of the object made of `material`: the innermost medium,
+    /// ignoring the object itself if the path is inside of it.
+    pub fn outside_of(&self, material: MaterialId) -> Float {
+        let mut skipped = false;
+        for &(id, refraction_index) in self.media.iter().rev() {
+            if id == material && !skipped {
+                skipped = true;
+                continue;
+            }
+            return refraction_index;
+        }
+        AIR_REFRACTION_INDEX
+    }
+
+    /// Updates the stack after a ray went through the surface of an object made of `material`,
+    /// `entering` it if it hit a front face.
+    pub fn cross(&mut self, material: MaterialId, refraction_index: Float, entering: bool) {
+        if entering {
+            self.media.push((material, refraction_index));
+        } else if let Some(position) = self.media.iter().rposition(|&(id, _)| id == material) {
+            self.media.remove(position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Dielectric, Material, MaterialList};
+
+    #[test]
+    fn test_nested_media() {
+        let mut materials = MaterialList::new();
+        let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+        let water = materials.add(Material::Dielectric(Dielectric::new(1.33)));
+
+        let mut media = MediumStack::new();
+        assert_eq!(media.outside_of(glass), AIR_REFRACTION_INDEX);
+        media.cross(glass, 1.5, true);
+        // Entering the water from the glass
+        assert_eq!(media.outside_of(water), 1.5);
+        media.cross(water, 1.33, true);
+        // Leaving the water back into the glass
+        assert_eq!(media.outside_of(water), 1.5);
+        media.cross(water, 1.33, false);
+        // Leaving the glass
+        assert_eq!(media.outside_of(glass), AIR_REFRACTION_INDEX);
+        media.cross(glass, 1.5, false);
+        assert_eq!(media.outside_of(glass), AIR_REFRACTION_INDEX);
+    }
+}
@@ -0,0 +1,1046 @@
+use crate::aabb::Aabb;
+use crate::bvh::{BvhSplit, BvhTree};
+use crate::float::Float;
+use crate::material::MaterialId;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::mem;
+use std::path::Path;
+
+/// Triangle mesh sharing a single material, flat shaded unless it has vertex normals. The
+/// triangles index a shared vertex buffer and are searched with their own BVH, so that large
+/// meshes are a single object of the scene.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    positions: Vec<Point3>,
+    /// Vertex indices of the triangles, in the order of the leaves of `bvh`.
+    triangles: Vec<[u32; 3]>,
+    bvh: BvhTree,
+    /// Normals of the vertices, interpolated across the triangles for smooth shading.
+    normals: Option<Vec<Vec3>>,
+    material: MaterialId,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Point3>, triangles: Vec<[u32; 3]>, material: MaterialId) -> Mesh {
+        assert!(triangles
+            .iter()
+            .all(|t| t.iter().all(|&i| (i as usize) < positions.len())));
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|t| {
+                t.iter()
+                    .fold(Aabb::empty(), |aabb, &i| aabb.grow(&positions[i as usize]))
+            })
+            .collect();
+        let (bvh, order) = BvhTree::build(&bounds, BvhSplit::Sah);
+        let triangles = order.into_iter().map(|i| triangles[i]).collect();
+        Mesh {
+            positions,
+            triangles,
+            bvh,
+            normals: None,
+            material,
+        }
+    }
+
+    /// Smooth shading with `normals`, one per vertex. Zero normals are replaced by those of
+    /// `with_smooth_shading`.
+    pub fn with_normals(mut self, mut normals: Vec<Vec3>) -> Mesh {
+        assert_eq!(normals.len(), self.positions.len());
+        if normals.iter().any(|n| n.length_squared() == 0.0) {
+            let computed = self.vertex_normals();
+            for (n, computed) in normals.iter_mut().zip(computed) {
+                if n.length_squared() == 0.0 {
+                    *n = computed;
+                }
+            }
+        }
+        // Vertices of degenerate triangles only are left with no normal, the flat one is used
+        let normalize = |n: Vec3| {
+            if n.length_squared() > 0.0 {
+                unit_vector(n)
+            } else {
+                n
+            }
+        };
+        self.normals = Some(normals.into_iter().map(normalize).collect());
+        self
+    }
+
+    /// Smooth shading with the normal of each vertex averaged from the triangles around it,
+    /// weighted by their area. Vertices are only shared by triangles indexing the same one,
+    /// which isn't the case of STL files.
+    pub fn with_smooth_shading(self) -> Mesh {
+        let normals = self.vertex_normals();
+        self.with_normals(normals)
+    }
+
+    /// Drops the vertex normals, each triangle being shaded with its own normal.
+    pub fn with_flat_shading(mut self) -> Mesh {
+        self.normals = None;
+        self
+    }
+
+    fn vertex_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::zero(); self.positions.len()];
+        for triangle in &self.triangles {
+            let (p0, p1, p2) = self.vertices(triangle);
+            // Twice the area, along the normal
+            let normal = (p1 - p0).cross(&(p2 - p0));
+            for &i in triangle {
+                normals[i as usize] += normal;
+            }
+        }
+        normals
+    }
+
+    /// Loads an OBJ, STL or PLY file, depending on its extension.
+    pub fn load<P: AsRef<Path>>(path: P, material: MaterialId) -> Result<Mesh> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open mesh {}", path.display()))?;
+        let reader = BufReader::new(file);
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let mesh = match extension.as_deref() {
+            Some("obj") => Mesh::read_obj(reader, material),
+            Some("stl") => Mesh::read_stl(reader, material),
+            Some("ply") => Mesh::read_ply(reader, material),
+            _ => bail!("Unsupported mesh format {}", path.display()),
+        };
+        mesh.with_context(|| format!("Failed to load mesh {}", path.display()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    /// Bytes taken by the vertices, the triangles and their BVH.
+    pub fn memory_size(&self) -> usize {
+        let normals = self.normals.as_ref().map_or(0, Vec::len);
+        (self.positions.len() + normals) * mem::size_of::<Vec3>()
+            + self.triangles.len() * mem::size_of::<[u32; 3]>()
+            + self.bvh.memory_size()
+    }
+
+    fn vertices(&self, triangle: &[u32; 3]) -> (Point3, Point3, Point3) {
+        (
+            self.positions[triangle[0] as usize],
+            self.positions[triangle[1] as usize],
+            self.positions[triangle[2] as usize],
+        )
+    }
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        // Closest triangle with the distance and barycentric coordinates of its hit
+        let mut closest_hit = None;
+        let sheared_ray = ShearedRay::new(ray);
+        self.bvh
+            .traverse(ray, t_min, t_max, |leaf, mut closest_so_far| {
+                let mut closer = None;
+                for index in leaf {
+                    let (p0, p1, p2) = self.vertices(&self.triangles[index]);
+                    if self.material.culls(ray, &(p1 - p0).cross(&(p2 - p0))) {
+                        continue;
+                    }
+                    if let Some((t, b1, b2)) =
+                        intersect_triangle(&sheared_ray, p0, p1, p2, t_min, closest_so_far)
+                    {
+                        closest_so_far = t;
+                        closer = Some(t);
+                        closest_hit = Some((index, t, b1, b2));
+                    }
+                }
+                closer
+            });
+        let (index, t, b1, b2) = match closest_hit {
+            Some(hit) => hit,
+            None => return false,
+        };
+
+        let triangle = &self.triangles[index];
+        let (p0, p1, p2) = self.vertices(triangle);
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+        let geometric_normal = unit_vector(edge1.cross(&edge2));
+        hit_record.t = t;
+        // Interpolated, exactly on the triangle unlike the point at distance `t`
+        hit_record.point = (1.0 - b1 - b2) * p0 + b1 * p1 + b2 * p2;
+        hit_record.set_face_normal(ray, &geometric_normal);
+        if let Some(normals) = &self.normals {
+            let b0 = 1.0 - b1 - b2;
+            let (n0, n1, n2) = (
+                normals[triangle[0] as usize],
+                normals[triangle[1] as usize],
+                normals[triangle[2] as usize],
+            );
+            let shading_normal = b0 * n0 + b1 * n1 + b2 * n2;
+            // Kept on the side of the triangle, whatever the winding of the file
+            let sign = if shading_normal.dot(&hit_record.normal) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            if shading_normal.length_squared() > 1e-12 {
+                hit_record.normal = sign * unit_vector(shading_normal);
+            }
+            // Hanika's shadow terminator fix: the hit point is projected onto the tangent plane
+            // of each vertex it is below, and the projections interpolated like the normals
+            let p = hit_record.point;
+            let lift = |vertex: Point3, normal: Vec3| {
+                let normal = sign * normal;
+                -(p - vertex).dot(&normal).min(0.0) * normal
+            };
+            hit_record.terminator_offset =
+                b0 * lift(p0, n0) + b1 * lift(p1, n1) + b2 * lift(p2, n2);
+        }
+        hit_record.u = b1;
+        hit_record.v = b2;
+        hit_record.tangent = edge1;
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bvh.bounds())
+    }
+}
+
+/// Ray sheared and scaled for its direction to be the +z axis, once for all the triangles it is
+/// tested against.
+struct ShearedRay {
+    origin: Point3,
+    /// Axes of the largest component of the direction last, keeping the winding.
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    sx: Float,
+    sy: Float,
+    sz: Float,
+}
+
+impl ShearedRay {
+    fn new(ray: &Ray) -> ShearedRay {
+        let direction = ray.direction();
+        let kz = (0..3)
+            .max_by(|&a, &b| direction[a].abs().total_cmp(&direction[b].abs()))
+            .unwrap();
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        if direction[kz] < 0.0 {
+            mem::swap(&mut kx, &mut ky);
+        }
+        ShearedRay {
+            origin: ray.origin(),
+            kx,
+            ky,
+            kz,
+            sx: direction[kx] / direction[kz],
+            sy: direction[ky] / direction[kz],
+            sz: 1.0 / direction[kz],
+        }
+    }
+}
+
+/// Watertight intersection (Woop, Benthin and Wald), returning the distance and the barycentric
+/// coordinates of the hit with respect to `p1` and `p2`. Rays can't slip between triangles
+/// sharing an edge, the edge tests of both giving opposite results.
+fn intersect_triangle(
+    ray: &ShearedRay,
+    p0: Point3,
+    p1: Point3,
+    p2: Point3,
+    t_min: Float,
+    t_max: Float,
+) -> Option<(Float, Float, Float)> {
+    let (a, b, c) = (p0 - ray.origin, p1 - ray.origin, p2 - ray.origin);
+    let (ax, ay) = (
+        a[ray.kx] - ray.sx * a[ray.kz],
+        a[ray.ky] - ray.sy * a[ray.kz],
+    );
+    let (bx, by) = (
+        b[ray.kx] - ray.sx * b[ray.kz],
+        b[ray.ky] - ray.sy * b[ray.kz],
+    );
+    let (cx, cy) = (
+        c[ray.kx] - ray.sx * c[ray.kz],
+        c[ray.ky] - ray.sy * c[ray.kz],
+    );
+
+    // Scaled barycentric coordinates, from the edge functions
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        // On an edge, where only exact signs tell which of the triangles sharing it is hit
+        u = exact_difference(cx, by, cy, bx);
+        v = exact_difference(ax, cy, ay, cx);
+        w = exact_difference(bx, ay, by, ax);
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let determinant = u + v + w;
+    if determinant == 0.0 {
+        // Parallel to the triangle
+        return None;
+    }
+
+    let (az, bz, cz) = (ray.sz * a[ray.kz], ray.sz * b[ray.kz], ray.sz * c[ray.kz]);
+    let t = (u * az + v * bz + w * cz) / determinant;
+    if t < t_min || t > t_max {
+        return None;
+    }
+    Some((t, v / determinant, w / determinant))
+}
+
+/// `a * b - c * d`, computed in double precision, where single precision products are exact.
+#[cfg(not(feature = "f64"))]
+fn exact_difference(a: Float, b: Float, c: Float, d: Float) -> Float {
+    (a as f64 * b as f64 - c as f64 * d as f64) as Float
+}
+
+/// `a * b - c * d`.
+#[cfg(feature = "f64")]
+fn exact_difference(a: Float, b: Float, c: Float, d: Float) -> Float {
+    a * b - c * d
+}
+
+// -----
+//  OBJ
+// -----
+
+impl Mesh {
+    /// Reads the vertices and faces of a Wavefront OBJ file, polygons being split into fans of
+    /// triangles. Files with vertex normals give smooth shaded meshes. Texture coordinates,
+    /// groups and materials are ignored.
+    pub fn read_obj<R: BufRead>(reader: R, material: MaterialId) -> Result<Mesh> {
+        let mut file_positions = Vec::new();
+        let mut file_normals = Vec::new();
+        // A vertex of the mesh for each pair of position and normal used by the faces
+        let mut vertices: HashMap<(u32, Option<u32>), u32> = HashMap::new();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut triangles = Vec::new();
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => file_positions.push(parse_point(&mut tokens, line_number)?),
+                Some("vn") => file_normals.push(parse_point(&mut tokens, line_number)?),
+                Some("f") => {
+                    let mut indices = Vec::new();
+                    for token in tokens {
+                        // "v", "v/vt", "v//vn" or "v/vt/vn", 1-based or negative for relative
+                        let mut parts = token.split('/');
+                        let position = parse_obj_index(
+                            parts.next().unwrap_or(""),
+                            file_positions.len(),
+                            line_number,
+                        )?;
+                        // Normal indices are ignored in files without normals
+                        let normal = match parts.nth(1) {
+                            Some(part) if !part.is_empty() && !file_normals.is_empty() => {
+                                Some(parse_obj_index(part, file_normals.len(), line_number)?)
+                            }
+                            _ => None,
+                        };
+                        let index = *vertices.entry((position, normal)).or_insert_with(|| {
+                            positions.push(file_positions[position as usize]);
+                            normals.push(normal.map_or(Vec3::zero(), |n| file_normals[n as usize]));
+                            positions.len() as u32 - 1
+                        });
+                        indices.push(index);
+                    }
+                    for i in 1..indices.len().saturating_sub(1) {
+                        triangles.push([indices[0], indices[i], indices[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mesh = Mesh::new(positions, triangles, material);
+        if file_normals.is_empty() {
+            Ok(mesh)
+        } else {
+            Ok(mesh.with_normals(normals))
+        }
+    }
+}
+
+/// Index into the `count` elements read so far, from a 1-based or negative OBJ index.
+fn parse_obj_index(token: &str, count: usize, line_number: usize) -> Result<u32> {
+    let index: i64 = token
+        .parse()
+        .with_context(|| format!("Invalid face on line {}", line_number + 1))?;
+    let index = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    if index < 0 || index as usize >= count {
+        bail!("Face index out of range on line {}", line_number + 1);
+    }
+    Ok(index as u32)
+}
+
+fn parse_point<'a, I: Iterator<Item = &'a str>>(
+    tokens: &mut I,
+    line_number: usize,
+) -> Result<Point3> {
+    let mut coordinates = [0.0; 3];
+    for c in coordinates.iter_mut() {
+        *c = tokens
+            .next()
+            .and_then(|t| t.parse::<Float>().ok())
+            .with_context(|| format!("Invalid vertex on line {}", line_number + 1))?;
+    }
+    Ok(Point3::new(coordinates[0], coordinates[1], coordinates[2]))
+}
+
+// -----
+//  STL
+// -----
+
+impl Mesh {
+    /// Reads a binary or ASCII STL file. STL doesn't share vertices between triangles, so each
+    /// triangle gets its own three.
+    pub fn read_stl<R: Read>(mut reader: R, material: MaterialId) -> Result<Mesh> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        // Binary files have an 80 bytes header, a triangle count and 50 bytes per triangle.
+        // Their header may start with "solid" too, so rely on the size to tell them apart.
+        if bytes.len() >= 84 {
+            let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+            if bytes.len() == 84 + 50 * count {
+                return Ok(Mesh::read_binary_stl(&bytes[84..], count, material));
+            }
+        }
+        Mesh::read_ascii_stl(&bytes, material)
+    }
+
+    fn read_binary_stl(bytes: &[u8], count: usize, material: MaterialId) -> Mesh {
+        let read_f32 = |offset: usize| {
+            f32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ]) as Float
+        };
+
+        let mut positions = Vec::with_capacity(3 * count);
+        for triangle in 0..count {
+            // Skip the facet normal, recomputed from the vertices anyway
+            let offset = 50 * triangle + 12;
+            for vertex in 0..3 {
+                let offset = offset + 12 * vertex;
+                positions.push(Point3::new(
+                    read_f32(offset),
+                    read_f32(offset + 4),
+                    read_f32(offset + 8),
+                ));
+            }
+        }
+        Mesh::from_triangle_soup(positions, material)
+    }
+
+    fn read_ascii_stl(bytes: &[u8], material: MaterialId) -> Result<Mesh> {
+        let text = std::str::from_utf8(bytes).context("STL file is neither binary nor text")?;
+        let mut positions = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            if tokens.next() == Some("vertex") {
+                positions.push(parse_point(&mut tokens, line_number)?);
+            }
+        }
+        if positions.len() % 3 != 0 {
+            bail!("Facets of an STL file must have 3 vertices");
+        }
+        Ok(Mesh::from_triangle_soup(positions, material))
+    }
+
+    fn from_triangle_soup(positions: Vec<Point3>, material: MaterialId) -> Mesh {
+        let triangles = (0..positions.len() as u32 / 3)
+            .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+            .collect();
+        Mesh::new(positions, triangles, material)
+    }
+}
+
+// -----
+//  PLY
+// -----
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyScalar {
+    fn parse(name: &str) -> Result<PlyScalar> {
+        Ok(match name {
+            "char" | "int8" => PlyScalar::I8,
+            "uchar" | "uint8" => PlyScalar::U8,
+            "short" | "int16" => PlyScalar::I16,
+            "ushort" | "uint16" => PlyScalar::U16,
+            "int" | "int32" => PlyScalar::I32,
+            "uint" | "uint32" => PlyScalar::U32,
+            "float" | "float32" => PlyScalar::F32,
+            "double" | "float64" => PlyScalar::F64,
+            _ => bail!("Unknown PLY property type '{}'", name),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            PlyScalar::I8 | PlyScalar::U8 => 1,
+            PlyScalar::I16 | PlyScalar::U16 => 2,
+            PlyScalar::I32 | PlyScalar::U32 | PlyScalar::F32 => 4,
+            PlyScalar::F64 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PlyProperty {
+    Scalar(String, PlyScalar),
+    /// Name, type of the length and type of the items.
+    List(String, PlyScalar, PlyScalar),
+}
+
+#[derive(Clone, Debug)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Reads the values of PLY elements, whatever the format of the file.
+struct PlyReader<R: BufRead> {
+    reader: R,
+    format: PlyFormat,
+    /// Remaining values of the current line, for ASCII files.
+    tokens: Vec<String>,
+}
+
+impl<R: BufRead> PlyReader<R> {
+    fn read_value(&mut self, scalar: PlyScalar) -> Result<f64> {
+        if self.format == PlyFormat::Ascii {
+            while self.tokens.is_empty() {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    bail!("Truncated PLY file");
+                }
+                self.tokens = line.split_whitespace().rev().map(String::from).collect();
+            }
+            let token = self.tokens.pop().unwrap();
+            return token
+                .parse()
+                .with_context(|| format!("Invalid PLY value '{}'", token));
+        }
+
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..scalar.size()];
+        self.reader
+            .read_exact(bytes)
+            .context("Truncated PLY file")?;
+        if self.format == PlyFormat::BinaryBigEndian {
+            bytes.reverse();
+        }
+        Ok(match scalar {
+            PlyScalar::I8 => bytes[0] as i8 as f64,
+            PlyScalar::U8 => bytes[0] as f64,
+            PlyScalar::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            PlyScalar::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            PlyScalar::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            PlyScalar::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            PlyScalar::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            PlyScalar::F64 => f64::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        })
+    }
+}
+
+impl Mesh {
+    /// Reads the `vertex` positions and normals and the `face` vertex indices of an ASCII or
+    /// binary PLY file, polygons being split into fans of triangles. Files with normals give
+    /// smooth shaded meshes. Other properties and elements are skipped.
+    pub fn read_ply<R: BufRead>(mut reader: R, material: MaterialId) -> Result<Mesh> {
+        let (format, elements) = read_ply_header(&mut reader)?;
+        let mut reader = PlyReader {
+            reader,
+            format,
+            tokens: Vec::new(),
+        };
+
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut triangles = Vec::new();
+        for element in &elements {
+            for _ in 0..element.count {
+                let mut position = [0.0; 3];
+                let mut normal = [0.0; 3];
+                let mut indices = Vec::new();
+                for property in &element.properties {
+                    match property {
+                        PlyProperty::Scalar(name, scalar) => {
+                            let value = reader.read_value(*scalar)?;
+                            match (element.name.as_str(), name.as_str()) {
+                                ("vertex", "x") => position[0] = value as Float,
+                                ("vertex", "y") => position[1] = value as Float,
+                                ("vertex", "z") => position[2] = value as Float,
+                                ("vertex", "nx") => normal[0] = value as Float,
+                                ("vertex", "ny") => normal[1] = value as Float,
+                                ("vertex", "nz") => normal[2] = value as Float,
+                                _ => {}
+                            }
+                        }
+                        PlyProperty::List(name, length_scalar, item_scalar) => {
+                            let length = reader.read_value(*length_scalar)? as usize;
+                            let is_face_indices = element.name == "face"
+                                && (name == "vertex_indices" || name == "vertex_index");
+                            for _ in 0..length {
+                                let value = reader.read_value(*item_scalar)?;
+                                if is_face_indices {
+                                    indices.push(value as u32);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if element.name == "vertex" {
+                    positions.push(Point3::new(position[0], position[1], position[2]));
+                    normals.push(Vec3::new(normal[0], normal[1], normal[2]));
+                } else if element.name == "face" {
+                    for i in 1..indices.len().saturating_sub(1) {
+                        triangles.push([indices[0], indices[i], indices[i + 1]]);
+                    }
+                }
+            }
+        }
+
+        if triangles
+            .iter()
+            .any(|t| t.iter().any(|&i| i as usize >= positions.len()))
+        {
+            bail!("PLY face index out of range");
+        }
+        let has_normals = elements.iter().any(|element| {
+            element.name == "vertex"
+                && element
+                    .properties
+                    .iter()
+                    .any(|p| matches!(p, PlyProperty::Scalar(name, _) if name == "nx"))
+        });
+        let mesh = Mesh::new(positions, triangles, material);
+        if has_normals {
+            Ok(mesh.with_normals(normals))
+        } else {
+            Ok(mesh)
+        }
+    }
+}
+
+fn read_ply_header<R: BufRead>(reader: &mut R) -> Result<(PlyFormat, Vec<PlyElement>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != "ply" {
+        bail!("Not a PLY file");
+    }
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("Truncated PLY header");
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(PlyFormat::BinaryBigEndian),
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse().context("Invalid PLY element count")?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", length, item, name] => elements
+                .last_mut()
+                .context("PLY property outside of an element")?
+                .properties
+                .push(PlyProperty::List(
+                    name.to_string(),
+                    PlyScalar::parse(length)?,
+                    PlyScalar::parse(item)?,
+                )),
+            ["property", scalar, name] => elements
+                .last_mut()
+                .context("PLY property outside of an element")?
+                .properties
+                .push(PlyProperty::Scalar(
+                    name.to_string(),
+                    PlyScalar::parse(scalar)?,
+                )),
+            ["end_header"] => break,
+            _ => {}
+        }
+    }
+
+    let format = format.context("Missing PLY format")?;
+    Ok((format, elements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{BackFaces, Lambertian, Material, MaterialList};
+    use crate::vec3::{Color, Vec3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn square_hits(mesh: &Mesh) {
+        // Unit square in the z = 0 plane
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(0.25, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 1.0).abs() < 1e-5);
+        let ray = Ray::new(Point3::new(1.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!mesh.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    #[test]
+    fn test_obj() {
+        let obj = "# square\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1/1/1 2/2/1 3/3/1 -1/4/1\n";
+        let mesh = Mesh::read_obj(obj.as_bytes(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.len(), 2);
+        square_hits(&mesh);
+    }
+
+    #[test]
+    fn test_obj_normals() {
+        // Two triangles sharing an edge, each with its own normal: the vertices of the edge are
+        // split in two
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn -1 0 1\nvn 1 0 1\n\
+                   f 1//1 2//1 3//1\nf 1//2 3//2 4//2\n";
+        let mesh = Mesh::read_obj(obj.as_bytes(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.positions.len(), 6);
+        square_hits(&mesh);
+
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(0.75, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.normal - unit_vector(Vec3::new(-1.0, 0.0, 1.0))).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_culled_back_faces() {
+        // Two squares facing up, one above the other, only seen from above
+        let mut materials = MaterialList::new();
+        let culled = materials.add_with_back_faces(
+            Material::Lambertian(Lambertian::new(Color::zero())),
+            BackFaces::Culled,
+        );
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n\
+                   v 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\nf 5 6 7 8\n";
+        let mesh = Mesh::read_obj(obj.as_bytes(), culled).unwrap();
+        let mut hit_record = HitRecord::empty();
+        let down = Ray::new(Point3::new(0.25, 0.75, 2.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&down, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 1.0).abs() < 1e-5);
+        let up = Ray::new(Point3::new(0.25, 0.75, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(!mesh.hit(&up, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    /// Roof with a ridge along the y axis, its faces sloping down at 45 degrees.
+    fn roof() -> Mesh {
+        let positions = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(-1.0, 0.0, -1.0),
+            Point3::new(-1.0, 1.0, -1.0),
+            Point3::new(1.0, 0.0, -1.0),
+            Point3::new(1.0, 1.0, -1.0),
+        ];
+        let triangles = vec![[2, 0, 1], [2, 1, 3], [0, 4, 5], [0, 5, 1]];
+        Mesh::new(positions, triangles, MaterialId::default())
+    }
+
+    fn roof_normal(mesh: &Mesh, x: Float) -> Vec3 {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(x, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        hit_record.normal
+    }
+
+    #[test]
+    fn test_smooth_shading() {
+        // Flat shading shows the ridge, smooth shading hides it
+        let flat = roof();
+        let step = roof_normal(&flat, -0.001) - roof_normal(&flat, 0.001);
+        assert!(step.length() > 1.0);
+
+        let smooth = roof().with_smooth_shading();
+        let step = roof_normal(&smooth, -0.001) - roof_normal(&smooth, 0.001);
+        assert!(step.length() < 0.01);
+        // Away from the ridge, the normals turn towards those of the faces
+        assert!(roof_normal(&smooth, -0.9).x() < -0.5);
+
+        let flat_again = smooth.with_flat_shading();
+        assert_eq!(roof_normal(&flat_again, 0.5), roof_normal(&flat, 0.5));
+    }
+
+    #[test]
+    fn test_terminator_offset() {
+        // A triangle with the normals of a sphere centered below it
+        let positions = vec![
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(-0.5, 0.75_f64.sqrt() as Float, 0.0),
+            Point3::new(-0.5, -0.75_f64.sqrt() as Float, 0.0),
+        ];
+        let center = Point3::new(0.0, 0.0, -1.0);
+        let normals = positions.iter().map(|&p| p - center).collect();
+        let mesh = Mesh::new(positions, vec![[0, 1, 2]], MaterialId::default());
+        let smooth = mesh.with_normals(normals);
+
+        let mut hit_record = HitRecord::empty();
+        let down = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(smooth.hit(&down, 0.001, Float::MAX, &mut hit_record));
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        assert!((hit_record.ray_origin(&up) - Point3::new(0.0, 0.0, 0.5)).length() < 1e-4);
+        assert!((hit_record.ray_origin(&-up) - hit_record.point).length() < 1e-4);
+
+        // From below, the surface curves away from the rays
+        let up_ray = Ray::new(Point3::new(0.0, 0.0, -1.0), up);
+        assert!(smooth.hit(&up_ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.ray_origin(&-up) - hit_record.point).length() < 1e-4);
+
+        let flat = smooth.with_flat_shading();
+        assert!(flat.hit(&down, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.ray_origin(&up) - hit_record.point).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_watertight_shared_edges() {
+        // A closed octahedron: rays from inside, towards its edges and vertices, can't get out
+        let positions = vec![
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(-1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, -1.0, 0.0),
+            Point3::new(0.0, 0.0, 1.0),
+            Point3::new(0.0, 0.0, -1.0),
+        ];
+        let triangles = vec![
+            [0, 2, 4],
+            [2, 1, 4],
+            [1, 3, 4],
+            [3, 0, 4],
+            [2, 0, 5],
+            [1, 2, 5],
+            [3, 1, 5],
+            [0, 3, 5],
+        ];
+        let mesh = Mesh::new(positions.clone(), triangles, MaterialId::default());
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut hit_record = HitRecord::empty();
+        for _ in 0..10_000 {
+            let origin = 0.3 * Vec3::random_in_unit_sphere(&mut rng);
+            // A point of an edge, or a vertex
+            let i = rng.gen_range(0..6);
+            // Not the opposite vertex, i ^ 1
+            let j = (i / 2 * 2 + 2 + rng.gen_range(0..4)) % 6;
+            let s = if rng.gen_bool(0.1) { 0.0 } else { rng.gen() };
+            let target = positions[i] + s * (positions[j] - positions[i]);
+            let ray = Ray::new(origin, target - origin);
+            assert!(mesh.hit(&ray, 0.0, Float::MAX, &mut hit_record));
+        }
+    }
+
+    #[test]
+    fn test_same_hits_as_all_triangles() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let positions: Vec<Point3> = (0..3000)
+            .map(|_| Vec3::random_range(&mut rng, -10.0, 10.0))
+            .collect();
+        // Small triangles around random points, a few long ones across the mesh
+        let positions: Vec<Point3> = positions
+            .chunks(3)
+            .flat_map(|p| {
+                let scale = if rng.gen::<Float>() < 0.02 { 1.0 } else { 0.05 };
+                vec![
+                    p[0],
+                    p[0] + scale * (p[1] - p[0]),
+                    p[0] + scale * (p[2] - p[0]),
+                ]
+            })
+            .collect();
+        let mesh = Mesh::from_triangle_soup(positions, MaterialId::default());
+        assert!(mesh.bvh.node_count() > 1);
+
+        for _ in 0..500 {
+            let ray = Ray::new(
+                Vec3::random_range(&mut rng, -15.0, 15.0),
+                Vec3::random_in_unit_sphere(&mut rng),
+            );
+            let sheared_ray = ShearedRay::new(&ray);
+            let expected = mesh
+                .triangles
+                .iter()
+                .filter_map(|triangle| {
+                    let (p0, p1, p2) = mesh.vertices(triangle);
+                    intersect_triangle(&sheared_ray, p0, p1, p2, 0.001, Float::MAX)
+                })
+                .map(|(t, _, _)| t)
+                .min_by(|a, b| a.total_cmp(b));
+            let mut hit_record = HitRecord::empty();
+            assert_eq!(
+                mesh.hit(&ray, 0.001, Float::MAX, &mut hit_record),
+                expected.is_some()
+            );
+            if let Some(t) = expected {
+                assert_eq!(hit_record.t, t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ascii_stl() {
+        let stl = "solid square
+facet normal 0 0 1
+ outer loop
+  vertex 0 0 0
+  vertex 1 0 0
+  vertex 1 1 0
+ endloop
+endfacet
+facet normal 0 0 1
+ outer loop
+  vertex 0 0 0
+  vertex 1 1 0
+  vertex 0 1 0
+ endloop
+endfacet
+endsolid square
+";
+        let mesh = Mesh::read_stl(stl.as_bytes(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.len(), 2);
+        square_hits(&mesh);
+    }
+
+    #[test]
+    fn test_binary_stl() {
+        let triangles = [
+            [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+        ];
+        // The header starting with "solid" mustn't confuse the detection
+        let mut bytes = b"solid".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        for triangle in &triangles {
+            bytes.extend_from_slice(&[0u8; 12]);
+            for vertex in triangle {
+                for c in vertex {
+                    bytes.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+            bytes.extend_from_slice(&[0u8; 2]);
+        }
+
+        let mesh = Mesh::read_stl(bytes.as_slice(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.len(), 2);
+        square_hits(&mesh);
+    }
+
+    #[test]
+    fn test_ascii_ply() {
+        let ply = "ply
+format ascii 1.0
+comment square
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255
+1 0 0 255
+1 1 0 255
+0 1 0 255
+4 0 1 2 3
+";
+        let mesh = Mesh::read_ply(ply.as_bytes(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.len(), 2);
+        square_hits(&mesh);
+    }
+
+    #[test]
+    fn test_binary_ply() {
+        let mut bytes = b"ply
+format binary_little_endian 1.0
+element vertex 4
+property double x
+property double y
+property double z
+element face 1
+property list uchar uint vertex_indices
+end_header
+"
+        .to_vec();
+        for vertex in &[
+            [0.0f64, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ] {
+            for c in vertex {
+                bytes.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        bytes.push(4);
+        for i in 0u32..4 {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+
+        let mesh = Mesh::read_ply(bytes.as_slice(), MaterialId::default()).unwrap();
+        assert_eq!(mesh.len(), 2);
+        square_hits(&mesh);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::float::{Float, PI};
+use crate::vec3::{unit_vector, Vec3};
+use rand::Rng;
+
+/// Smallest alpha used, perfectly smooth surfaces make the distribution a Dirac.
+const MIN_ALPHA: Float = 1e-3;
+
+/// GGX (Trowbridge-Reitz) distribution of microfacet normals.
+///
+/// All the directions are expressed in the local shading frame, the macro surface normal being
+/// +Z. `alpha_x` and `alpha_y` are the roughnesses along X and Y.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ggx {
+    alpha_x: Float,
+    alpha_y: Float,
+}
+
+impl Ggx {
+    pub fn new(alpha_x: Float, alpha_y: Float) -> Ggx {
+        Ggx {
+            alpha_x: alpha_x.max(MIN_ALPHA),
+            alpha_y: alpha_y.max(MIN_ALPHA),
+        }
+    }
+
+    /// Maps the perceptual `roughness` in [0, 1] to alpha = roughness^2, stretched along X and
+    /// squeezed along Y by `anisotropy` in [0, 1].
+    pub fn from_roughness(roughness: Float, anisotropy: Float) -> Ggx {
+        let aspect = (1.0 - 0.9 * anisotropy).sqrt();
+        let alpha = roughness * roughness;
+        Ggx::new(alpha / aspect, alpha * aspect)
+    }
+
+    /// Density of microfacet normals `h`.
+    pub fn d(&self, h: &Vec3) -> Float {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let x = h.x() / self.alpha_x;
+        let y = h.y() / self.alpha_y;
+        let e = x * x + y * y + h.z() * h.z();
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Smith's auxiliary function, giving the masking of direction `w`.
+    fn lambda(&self, w: &Vec3) -> Float {
+        let cos2 = w.z() * w.z();
+        if cos2 == 0.0 {
+            return Float::INFINITY;
+        }
+        let x = self.alpha_x * w.x();
+        let y = self.alpha_y * w.y();
+        ((1.0 + (x * x + y * y) / cos2).sqrt() - 1.0) / 2.0
+    }
+
+    /// Fraction of the microfacets visible from `w`.
+    pub fn g1(&self, w: &Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of the microfacets visible from both `wo` and `wi` (height-correlated Smith).
+    pub fn g2(&self, wo: &Vec3, wi: &Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a microfacet normal visible from `wo`, proportionally to its projected area
+    /// (Heitz, "Sampling the GGX Distribution of Visible Normals", 2018).
+    pub fn sample_visible_normal<R: Rng + ?Sized>(&self, wo: &Vec3, rng: &mut R) -> Vec3 {
+        // Stretch the view direction to the configuration where the roughness is 1
+        let vh = unit_vector(Vec3::new(
+            self.alpha_x * wo.x(),
+            self.alpha_y * wo.y(),
+            wo.z(),
+        ));
+
+        // Orthonormal basis around it
+        let len_sq = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = if len_sq > 0.0 {
+            Vec3::new(-vh.y(), vh.x(), 0.0) / len_sq.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        // Uniform point on the projected hemisphere
+        let r = rng.gen::<Float>().sqrt();
+        let phi = 2.0 * PI * rng.gen::<Float>();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+        // Back to the original roughness
+        unit_vector(Vec3::new(
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(0.0),
+        ))
+    }
+
+    /// Density of reflecting `wo` into `wi` when the normal is drawn with
+    /// `sample_visible_normal`.
+    pub fn reflection_pdf(&self, wo: &Vec3, wi: &Vec3) -> Float {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = unit_vector(*wo + *wi);
+        self.g1(wo) * self.d(&h) / (4.0 * wo.z())
+    }
+}
+
+/// Schlick's approximation of the Fresnel reflectance, `f0` being the reflectance at normal
+/// incidence.
+pub fn schlick_fresnel(f0: Vec3, cos: Float) -> Vec3 {
+    f0 + (1.0 - cos).max(0.0).powi(5) * (Vec3::new(1.0, 1.0, 1.0) - f0)
+}
+
+/// Exact Fresnel reflectance of a conductor seen from the air, per channel, from the real and
+/// imaginary parts `eta` and `k` of its complex refraction index.
+pub fn conductor_fresnel(cos: Float, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos = cos.clamp(0.0, 1.0);
+    let channel = |eta: Float, k: Float| {
+        let cos2 = cos * cos;
+        let sin2 = 1.0 - cos2;
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos * a;
+        let s = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let p = s * (t3 - t4) / (t3 + t4);
+        0.5 * (s + p)
+    };
+    Vec3::new(
+        channel(eta.x(), k.x()),
+        channel(eta.y(), k.y()),
+        channel(eta.z(), k.z()),
+    )
+}
+
+/// Mirror reflection of `wo` about the microfacet normal `h`.
+pub fn reflect_about(wo: &Vec3, h: &Vec3) -> Vec3 {
+    2.0 * wo.dot(h) * *h - *wo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SampleRng;
+
+    #[test]
+    fn test_conductor_fresnel() {
+        // At normal incidence ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2), all reflected at grazing
+        let (eta, k) = (Vec3::new(0.2, 1.0, 1.5), Vec3::new(3.9, 2.4, 0.0));
+        let normal = conductor_fresnel(1.0, eta, k);
+        let expected =
+            |n: Float, k: Float| ((n - 1.0).powi(2) + k * k) / ((n + 1.0).powi(2) + k * k);
+        assert!((normal.x() - expected(0.2, 3.9)).abs() < 1e-5);
+        assert!((normal.z() - expected(1.5, 0.0)).abs() < 1e-5);
+        let grazing = conductor_fresnel(0.0, eta, k);
+        assert!((grazing - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_reflection_pdf_normalized() {
+        // Integrate the density over the sphere with uniform samples
+        let ggx = Ggx::from_roughness(0.6, 0.5);
+        let wo = unit_vector(Vec3::new(0.3, -0.2, 1.0));
+        let mut rng = SampleRng::new(0);
+        let n = 200_000;
+        let sum: Float = (0..n)
+            .map(|_| ggx.reflection_pdf(&wo, &Vec3::random_unit_vector(&mut rng)))
+            .sum();
+        let integral = sum * 4.0 * PI / n as Float;
+        assert!((integral - 1.0).abs() < 0.05, "integral = {}", integral);
+    }
+
+    #[test]
+    fn test_visible_normals_face_the_viewer() {
+        let ggx = Ggx::from_roughness(0.8, 0.0);
+        let wo = unit_vector(Vec3::new(1.0, 0.0, 0.2));
+        let mut rng = SampleRng::new(0);
+        for _ in 0..1000 {
+            let h = ggx.sample_visible_normal(&wo, &mut rng);
+            assert!(h.z() >= 0.0);
+            assert!(wo.dot(&h) >= -1e-4);
+        }
+    }
+}
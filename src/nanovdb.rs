@@ -0,0 +1,455 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::vec3::Point3;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// Layout of the NanoVDB 32.x buffers, the nodes being looked up in place
+const FILE_HEADER_SIZE: usize = 16;
+const FILE_METADATA_SIZE: usize = 176;
+const GRID_DATA_SIZE: usize = 672;
+const GRID_TYPE_FLOAT: u32 = 1;
+const CODEC_NONE: u16 = 0;
+const VERSION_MAJOR: u32 = 32;
+const ROOT_DATA_SIZE: usize = 64;
+const ROOT_TILE_SIZE: usize = 32;
+const UPPER_TABLE_OFFSET: usize = 8256;
+const LOWER_TABLE_OFFSET: usize = 1088;
+const LEAF_VALUES_OFFSET: usize = 96;
+
+/// Grid of float values, smoke or cloud densities, read from a NanoVDB file: a sparse tree
+/// of voxels (root, 32^3 and 16^3 internal nodes, 8^3 leaves) kept as stored, with the affine
+/// map from its index space to the world.
+#[derive(Debug)]
+pub struct NanoVdbGrid {
+    /// Grid buffer, from its `GridData` header.
+    data: Vec<u8>,
+    /// Offset of the root node in `data`.
+    root: usize,
+    tile_count: usize,
+    /// Value of the voxels out of the tree.
+    background: Float,
+    maximum: Float,
+    /// Box of the active voxels, in index space, bounds included.
+    index_min: [i32; 3],
+    index_max: [i32; 3],
+    /// Rows of the linear part of the map from index space to world space, and its translation.
+    matrix: [[Float; 3]; 3],
+    inverse_matrix: [[Float; 3]; 3],
+    translation: [Float; 3],
+}
+
+impl NanoVdbGrid {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NanoVdbGrid> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open grid {}", path.display()))?;
+        NanoVdbGrid::read(BufReader::new(file))
+            .with_context(|| format!("Failed to load grid {}", path.display()))
+    }
+
+    /// Reads the grid named "density" of an uncompressed NanoVDB file, or its first one. Only
+    /// float grids are supported, OpenVDB files must be converted first, with `nanovdb_convert`.
+    pub fn read<R: Read>(mut reader: R) -> Result<NanoVdbGrid> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() < FILE_HEADER_SIZE || &bytes[0..7] != b"NanoVDB" {
+            bail!("Not a NanoVDB file");
+        }
+        let grid_count = read_u16(&bytes, 12)? as usize;
+        if read_u16(&bytes, 14)? != CODEC_NONE {
+            bail!("Compressed NanoVDB files are not supported");
+        }
+
+        // The metadata of all the grids, then their buffers
+        let mut offset = FILE_HEADER_SIZE;
+        let mut grids = Vec::with_capacity(grid_count);
+        for _ in 0..grid_count {
+            let grid_size = read_u64(&bytes, offset)? as usize;
+            let name_size = read_u32(&bytes, offset + 136)? as usize;
+            let name_start = offset + FILE_METADATA_SIZE;
+            let name = bytes
+                .get(name_start..name_start + name_size)
+                .context("Truncated grid metadata")?;
+            let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+            grids.push((name == b"density", grid_size));
+            offset = name_start + name_size;
+        }
+        let index = grids.iter().position(|&(density, _)| density).unwrap_or(0);
+        let (_, grid_size) = *grids.get(index).context("NanoVDB file without grids")?;
+        let start = offset + grids[..index].iter().map(|&(_, size)| size).sum::<usize>();
+        let data = bytes
+            .get(start..start + grid_size)
+            .context("Truncated grid")?;
+        NanoVdbGrid::from_buffer(data.to_vec())
+    }
+
+    /// Grid of the buffer `data`, as laid out in memory by NanoVDB.
+    // The conversions to Float are no-ops with the `f64` feature
+    #[allow(clippy::unnecessary_cast)]
+    pub fn from_buffer(data: Vec<u8>) -> Result<NanoVdbGrid> {
+        if data.len() < GRID_DATA_SIZE + 64 || &data[0..7] != b"NanoVDB" {
+            bail!("Invalid grid");
+        }
+        let version = read_u32(&data, 16)?;
+        if version >> 21 != VERSION_MAJOR {
+            bail!(
+                "Unsupported NanoVDB version {}, expected {}.x",
+                version >> 21,
+                VERSION_MAJOR
+            );
+        }
+        if read_u32(&data, 636)? != GRID_TYPE_FLOAT {
+            bail!("Only float grids are supported");
+        }
+
+        let read_matrix = |offset: usize| -> Result<[[Float; 3]; 3]> {
+            let mut matrix = [[0.0; 3]; 3];
+            for (i, row) in matrix.iter_mut().enumerate() {
+                for (j, value) in row.iter_mut().enumerate() {
+                    *value = read_f64(&data, offset + 8 * (3 * i + j))? as Float;
+                }
+            }
+            Ok(matrix)
+        };
+        let matrix = read_matrix(384)?;
+        let inverse_matrix = read_matrix(456)?;
+        let translation = [
+            read_f64(&data, 528)? as Float,
+            read_f64(&data, 536)? as Float,
+            read_f64(&data, 544)? as Float,
+        ];
+
+        // The tree follows the grid header
+        let root_offset = read_i64(&data, GRID_DATA_SIZE + 24)?;
+        let root = GRID_DATA_SIZE + root_offset as usize;
+        let index_min = [
+            read_i32(&data, root)?,
+            read_i32(&data, root + 4)?,
+            read_i32(&data, root + 8)?,
+        ];
+        let index_max = [
+            read_i32(&data, root + 12)?,
+            read_i32(&data, root + 16)?,
+            read_i32(&data, root + 20)?,
+        ];
+        let tile_count = read_u32(&data, root + 24)? as usize;
+        let background = read_f32(&data, root + 28)? as Float;
+        let maximum = read_f32(&data, root + 36)? as Float;
+        if data.len() < root + ROOT_DATA_SIZE + tile_count * ROOT_TILE_SIZE {
+            bail!("Truncated root node");
+        }
+
+        let grid = NanoVdbGrid {
+            data,
+            root,
+            tile_count,
+            background,
+            maximum,
+            index_min,
+            index_max,
+            matrix,
+            inverse_matrix,
+            translation,
+        };
+        grid.check_nodes()?;
+        Ok(grid)
+    }
+
+    /// Largest value of the grid, interpolated ones included.
+    pub fn maximum(&self) -> Float {
+        self.maximum.max(self.background)
+    }
+
+    /// Value at `point`, in world space, trilinearly interpolated between the centers of the
+    /// voxels.
+    pub fn value(&self, point: &Point3) -> Float {
+        let local = [
+            point.x() - self.translation[0],
+            point.y() - self.translation[1],
+            point.z() - self.translation[2],
+        ];
+        let index = self
+            .inverse_matrix
+            .map(|row| row[0] * local[0] + row[1] * local[1] + row[2] * local[2]);
+        let base = index.map(|i| i.floor());
+        let (i, j, k) = (base[0] as i32, base[1] as i32, base[2] as i32);
+        let (fx, fy, fz) = (index[0] - base[0], index[1] - base[1], index[2] - base[2]);
+
+        let lerp = |a: Float, b: Float, t: Float| a + t * (b - a);
+        let row = |j: i32, k: i32| lerp(self.voxel([i, j, k]), self.voxel([i + 1, j, k]), fx);
+        let slice = |k: i32| lerp(row(j, k), row(j + 1, k), fy);
+        lerp(slice(k), slice(k + 1), fz)
+    }
+
+    /// Box around the voxels, wide enough for the values interpolated towards the background.
+    pub fn bounding_box(&self) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for corner in 0..8 {
+            let index = [0, 1, 2].map(|a| {
+                if corner & (1 << a) == 0 {
+                    self.index_min[a] as Float - 1.0
+                } else {
+                    self.index_max[a] as Float + 1.0
+                }
+            });
+            let world = [0, 1, 2].map(|a| {
+                let row = self.matrix[a];
+                row[0] * index[0] + row[1] * index[1] + row[2] * index[2] + self.translation[a]
+            });
+            bounds = bounds.grow(&Point3::new(world[0], world[1], world[2]));
+        }
+        bounds
+    }
+
+    /// Value of the voxel `ijk`, going down the tree.
+    fn voxel(&self, ijk: [i32; 3]) -> Float {
+        let key = (ijk[2] as u32 >> 12) as u64
+            | ((ijk[1] as u32 >> 12) as u64) << 21
+            | ((ijk[0] as u32 >> 12) as u64) << 42;
+        for tile in 0..self.tile_count {
+            let tile = self.root + ROOT_DATA_SIZE + tile * ROOT_TILE_SIZE;
+            if self.u64_at(tile) != key {
+                continue;
+            }
+            let child = self.i64_at(tile + 8);
+            if child == 0 {
+                return self.f32_at(tile + 20);
+            }
+            return self.internal_value((self.root as i64 + child) as usize, ijk, 5, 7);
+        }
+        self.background
+    }
+
+    /// Value of the voxel `ijk` below the internal node at `node`, of 2^`log2_dim` children per
+    /// side each `2^child_total` voxels wide.
+    fn internal_value(&self, node: usize, ijk: [i32; 3], log2_dim: u32, child_total: u32) -> Float {
+        let mask = (1 << (log2_dim + child_total)) - 1;
+        let [i, j, k] = ijk.map(|c| ((c & mask) >> child_total) as usize);
+        let n = (i << (2 * log2_dim)) | (j << log2_dim) | k;
+
+        let mask_size = 1 << (3 * log2_dim - 3);
+        let child_mask = node + 32 + mask_size;
+        let is_child = self.data[child_mask + n / 8] & (1 << (n % 8)) != 0;
+        let table = node
+            + if log2_dim == 5 {
+                UPPER_TABLE_OFFSET
+            } else {
+                LOWER_TABLE_OFFSET
+            };
+        if !is_child {
+            return self.f32_at(table + 8 * n);
+        }
+        let child = (node as i64 + self.i64_at(table + 8 * n)) as usize;
+        if log2_dim == 5 {
+            self.internal_value(child, ijk, 4, 3)
+        } else {
+            let [i, j, k] = ijk.map(|c| (c & 7) as usize);
+            self.f32_at(child + LEAF_VALUES_OFFSET + 4 * ((i << 6) | (j << 3) | k))
+        }
+    }
+
+    /// Makes sure the children of the nodes are within the buffer, so that lookups don't have
+    /// to.
+    fn check_nodes(&self) -> Result<()> {
+        for tile in 0..self.tile_count {
+            let tile = self.root + ROOT_DATA_SIZE + tile * ROOT_TILE_SIZE;
+            let child = self.i64_at(tile + 8);
+            if child != 0 {
+                self.check_internal(self.root as i64 + child, 5)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_internal(&self, node: i64, log2_dim: u32) -> Result<()> {
+        let (table, child_size) = if log2_dim == 5 {
+            (UPPER_TABLE_OFFSET, LOWER_TABLE_OFFSET + 8 * 4096)
+        } else {
+            (LOWER_TABLE_OFFSET, LEAF_VALUES_OFFSET + 4 * 512)
+        };
+        let count = 1 << (3 * log2_dim);
+        if node < 0 || node as usize + table + 8 * count > self.data.len() {
+            bail!("Invalid node offset");
+        }
+        let node = node as usize;
+        let child_mask = node + 32 + count / 8;
+        for n in 0..count {
+            if self.data[child_mask + n / 8] & (1 << (n % 8)) == 0 {
+                continue;
+            }
+            let child = node as i64 + self.i64_at(node + table + 8 * n);
+            if log2_dim == 5 {
+                self.check_internal(child, 4)?;
+            } else if child < 0 || child as usize + child_size > self.data.len() {
+                bail!("Invalid leaf offset");
+            }
+        }
+        Ok(())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.data[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn i64_at(&self, offset: usize) -> i64 {
+        self.u64_at(offset) as i64
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn f32_at(&self, offset: usize) -> Float {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.data[offset..offset + 4]);
+        f32::from_le_bytes(bytes) as Float
+    }
+}
+
+fn read_bytes<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N]> {
+    let mut value = [0; N];
+    value.copy_from_slice(
+        bytes
+            .get(offset..offset + N)
+            .context("Truncated NanoVDB data")?,
+    );
+    Ok(value)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> Result<i32> {
+    Ok(i32::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_i64(bytes: &[u8], offset: usize) -> Result<i64> {
+    Ok(i64::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> Result<f32> {
+    Ok(f32::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+fn read_f64(bytes: &[u8], offset: usize) -> Result<f64> {
+    Ok(f64::from_le_bytes(read_bytes(bytes, offset)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Grid of voxels 0.5 wide from (1, 0, 0): a leaf from (0, 0, 0) with the voxel (1, 2, 3)
+    /// of 0.5, the 128 wide tile from (0, 0, 128) of 0.25 and the 4096 wide one from
+    /// (4096, 0, 0) of 3.
+    fn grid_buffer() -> Vec<u8> {
+        let root = GRID_DATA_SIZE + 64;
+        let upper = root + ROOT_DATA_SIZE + 2 * ROOT_TILE_SIZE;
+        let lower = upper + UPPER_TABLE_OFFSET + 8 * 32768;
+        let leaf = lower + LOWER_TABLE_OFFSET + 8 * 4096;
+        let mut data = vec![0; leaf + LEAF_VALUES_OFFSET + 4 * 512];
+        let mut write = |offset: usize, bytes: &[u8]| {
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        write(0, b"NanoVDB0");
+        write(16, &(VERSION_MAJOR << 21 | 3 << 10).to_le_bytes());
+        write(636, &GRID_TYPE_FLOAT.to_le_bytes());
+        for axis in 0..3 {
+            write(384 + 32 * axis, &0.5f64.to_le_bytes());
+            write(456 + 32 * axis, &2.0f64.to_le_bytes());
+        }
+        write(528, &1.0f64.to_le_bytes());
+        write(GRID_DATA_SIZE + 24, &64i64.to_le_bytes());
+
+        for (i, bound) in [0i32, 0, 0, 4103, 7, 7].iter().enumerate() {
+            write(root + 4 * i, &bound.to_le_bytes());
+        }
+        write(root + 24, &2u32.to_le_bytes());
+        write(root + 36, &3.0f32.to_le_bytes());
+        let tile = root + ROOT_DATA_SIZE;
+        write(tile + 8, &((upper - root) as i64).to_le_bytes());
+        write(tile + ROOT_TILE_SIZE, &(1u64 << 42).to_le_bytes());
+        write(tile + ROOT_TILE_SIZE + 20, &3.0f32.to_le_bytes());
+
+        write(upper + 32 + 4096, &[1]);
+        write(
+            upper + UPPER_TABLE_OFFSET,
+            &((lower - upper) as i64).to_le_bytes(),
+        );
+        write(upper + UPPER_TABLE_OFFSET + 8, &0.25f32.to_le_bytes());
+        write(lower + 32 + 512, &[1]);
+        write(
+            lower + LOWER_TABLE_OFFSET,
+            &((leaf - lower) as i64).to_le_bytes(),
+        );
+        let voxel = (1 << 6) | (2 << 3) | 3;
+        write(leaf + LEAF_VALUES_OFFSET + 4 * voxel, &0.5f32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_voxels() {
+        let grid = NanoVdbGrid::from_buffer(grid_buffer()).unwrap();
+        assert_eq!(grid.voxel([1, 2, 3]), 0.5);
+        assert_eq!(grid.voxel([1, 2, 4]), 0.0);
+        assert_eq!(grid.voxel([0, 0, 200]), 0.25);
+        assert_eq!(grid.voxel([5000, 10, 0]), 3.0);
+        assert_eq!(grid.voxel([-1, 2, 3]), 0.0);
+        assert_eq!(grid.maximum(), 3.0);
+    }
+
+    #[test]
+    fn test_value() {
+        let grid = NanoVdbGrid::from_buffer(grid_buffer()).unwrap();
+        // The center of the voxel (1, 2, 3), and half way to the next one
+        let center = Point3::new(1.5, 1.0, 1.5);
+        assert_eq!(grid.value(&center), 0.5);
+        let between = center + Point3::new(0.0, 0.25, 0.0);
+        assert_eq!(grid.value(&between), 0.25);
+        assert_eq!(grid.value(&Point3::new(-10.0, 0.0, 0.0)), 0.0);
+
+        let bounds = grid.bounding_box();
+        assert_eq!(bounds.min(), Point3::new(0.5, -0.5, -0.5));
+        assert_eq!(bounds.max(), Point3::new(2053.0, 4.0, 4.0));
+    }
+
+    #[test]
+    fn test_read() {
+        let grid = grid_buffer();
+        let mut file = b"NanoVDB0".to_vec();
+        file.extend(&(VERSION_MAJOR << 21).to_le_bytes());
+        file.extend(&2u16.to_le_bytes());
+        file.extend(&CODEC_NONE.to_le_bytes());
+        // A grid of temperatures first, then the density
+        for name in [&b"temperature\0"[..], &b"density\0"[..]] {
+            let mut metadata = vec![0; FILE_METADATA_SIZE];
+            metadata[0..8].copy_from_slice(&(grid.len() as u64).to_le_bytes());
+            metadata[136..140].copy_from_slice(&(name.len() as u32).to_le_bytes());
+            file.extend(metadata);
+            file.extend(name);
+        }
+        // Telling them apart by their background
+        let mut temperature = grid.clone();
+        let background = GRID_DATA_SIZE + 64 + 28;
+        temperature[background..background + 4].copy_from_slice(&1.0f32.to_le_bytes());
+        file.extend(temperature);
+        file.extend(&grid);
+
+        let density = NanoVdbGrid::read(&file[..]).unwrap();
+        assert_eq!(density.voxel([-1, 0, 0]), 0.0);
+        assert!(NanoVdbGrid::read(&file[..file.len() - 1]).is_err());
+        assert!(NanoVdbGrid::read(&grid[..]).is_err());
+    }
+}
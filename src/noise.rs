@@ -0,0 +1,137 @@
+use crate::float::Float;
+use crate::vec3::Point3;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/// Perlin's improved gradient noise: smooth pseudo-random values in about [-1, 1], zero at the
+/// points of integer coordinates, varying over a unit of length.
+#[derive(Clone, Debug)]
+pub struct Perlin {
+    /// Shuffled 0..256, twice, for the lookups not to wrap around.
+    permutation: Vec<u8>,
+}
+
+impl Perlin {
+    /// Noise of the permutation shuffled from `seed`, each seed giving other values.
+    pub fn new(seed: u64) -> Perlin {
+        let mut permutation: Vec<u8> = (0..=255).collect();
+        permutation.shuffle(&mut StdRng::seed_from_u64(seed));
+        permutation.extend_from_within(..);
+        Perlin { permutation }
+    }
+
+    pub fn noise(&self, point: &Point3) -> Float {
+        let floor = [point.x().floor(), point.y().floor(), point.z().floor()];
+        let [x, y, z] = [
+            point.x() - floor[0],
+            point.y() - floor[1],
+            point.z() - floor[2],
+        ];
+        let [i, j, k] = floor.map(|c| (c as i64 & 255) as usize);
+        let p = &self.permutation;
+
+        // Hashes of the corners of the unit cube around the point
+        let a = p[i] as usize + j;
+        let (aa, ab) = (p[a] as usize + k, p[a + 1] as usize + k);
+        let b = p[i + 1] as usize + j;
+        let (ba, bb) = (p[b] as usize + k, p[b + 1] as usize + k);
+
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, gradient(p[aa], x, y, z), gradient(p[ba], x - 1.0, y, z)),
+                lerp(
+                    u,
+                    gradient(p[ab], x, y - 1.0, z),
+                    gradient(p[bb], x - 1.0, y - 1.0, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    gradient(p[aa + 1], x, y, z - 1.0),
+                    gradient(p[ba + 1], x - 1.0, y, z - 1.0),
+                ),
+                lerp(
+                    u,
+                    gradient(p[ab + 1], x, y - 1.0, z - 1.0),
+                    gradient(p[bb + 1], x - 1.0, y - 1.0, z - 1.0),
+                ),
+            ),
+        )
+    }
+
+    /// Sum of the absolute value of `octaves` layers of noise, each twice as fine and half as
+    /// strong as the previous one, normalized to [0, 1]: billows with their own smaller billows.
+    pub fn turbulence(&self, point: &Point3, octaves: u32) -> Float {
+        let mut sum = 0.0;
+        let mut total_weight = 0.0;
+        let mut weight = 1.0;
+        let mut point = *point;
+        for _ in 0..octaves {
+            sum += weight * self.noise(&point).abs();
+            total_weight += weight;
+            weight *= 0.5;
+            point = 2.0 * point;
+        }
+        (sum / total_weight).min(1.0)
+    }
+}
+
+/// Gradient among the 12 directions to the edges of a cube picked by `hash`, dotted with
+/// (x, y, z).
+fn gradient(hash: u8, x: Float, y: Float, z: Float) -> Float {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// 6t^5 - 15t^4 + 10t^3, whose first two derivatives are zero at 0 and 1.
+fn fade(t: Float) -> Float {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: Float, a: Float, b: Float) -> Float {
+    a + t * (b - a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_noise() {
+        let perlin = Perlin::new(0);
+        // Zero on the lattice
+        assert_eq!(perlin.noise(&Point3::new(3.0, -2.0, 7.0)), 0.0);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut largest: Float = 0.0;
+        for _ in 0..10_000 {
+            let point = 100.0 * Point3::random_in_unit_sphere(&mut rng);
+            let value = perlin.noise(&point);
+            largest = largest.max(value.abs());
+            // Continuous
+            let step = Point3::new(1e-3, 1e-3, 1e-3);
+            assert!((perlin.noise(&(point + step)) - value).abs() < 1e-2);
+            assert!(perlin.turbulence(&point, 4) <= 1.0);
+        }
+        assert!(largest > 0.5 && largest <= 1.1);
+
+        // Another permutation
+        let point = Point3::new(rng.gen(), rng.gen(), rng.gen());
+        assert_ne!(perlin.noise(&point), Perlin::new(1).noise(&point));
+    }
+}
@@ -1,14 +1,57 @@
-use crate::material::{Lambertian, Material};
-use crate::ray::Ray;
-use crate::vec3::{Color, Point3, Vec3};
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::material::MaterialId;
+use crate::medium::AIR_REFRACTION_INDEX;
+use crate::ray::{offset_origin, Ray};
+use crate::rng::SampleRng;
+use crate::vec3::{Point3, Vec3};
+use rand::Rng;
+use std::any::Any;
+
+/// Position of an object among the objects of the scene, the same from one run to the next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ObjectId(u32);
+
+impl ObjectId {
+    pub fn new(index: usize) -> ObjectId {
+        ObjectId(index as u32)
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct HitRecord {
     pub point: Point3,
+    /// Shading normal, facing against the ray.
     pub normal: Vec3,
-    pub material: Material,
-    pub t: f32,
+    /// Normal of the actual surface, facing against the ray, which the shading normal of smooth
+    /// shaded meshes and normal maps departs from.
+    pub geometric_normal: Vec3,
+    /// Bound of the rounding errors of the coordinates of `point`, beyond those of their own
+    /// magnitude, which the rays leaving the surface step over. Zero but for curved surfaces,
+    /// `set_face_normal` resetting it.
+    pub point_error: Float,
+    pub material: MaterialId,
+    /// Object hit, set by the collection of objects holding it. Objects nested in others
+    /// report the id of the outermost one.
+    pub object: ObjectId,
+    pub t: Float,
+    /// Surface coordinates of the hit point, in [0, 1].
+    pub u: Float,
+    pub v: Float,
+    /// Direction of increasing `u` on the surface, zero if the primitive doesn't provide one.
+    pub tangent: Vec3,
+    /// Offset of the origin of the rays leaving the surface on the side of `normal`, out of the
+    /// shadow the flat triangles of smooth shaded meshes cast on the curved surface their normals
+    /// describe. Zero for other surfaces, `set_face_normal` resetting it.
+    pub terminator_offset: Vec3,
     pub front_face: bool,
+    /// Refraction index of the medium surrounding the object, filled in by the integrator from
+    /// the media the path went through.
+    pub outside_refraction_index: Float,
 }
 
 impl HitRecord {
@@ -16,26 +59,95 @@ impl HitRecord {
         HitRecord {
             point: Point3::zero(),
             normal: Vec3::zero(),
-            material: Material::Lambertian(Lambertian::new(Color::new(0.0, 0.0, 0.0))),
+            geometric_normal: Vec3::zero(),
+            point_error: 0.0,
+            material: MaterialId::default(),
+            object: ObjectId::default(),
             t: 0.0,
+            u: 0.0,
+            v: 0.0,
+            tangent: Vec3::zero(),
+            terminator_offset: Vec3::zero(),
             front_face: false,
+            outside_refraction_index: AIR_REFRACTION_INDEX,
         }
     }
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
         // If the ray is inside the object, the ray and the outward normal are in the same direction
         self.front_face = ray.direction().dot(outward_normal) < 0.0;
         if self.front_face {
-            self.normal = outward_normal.clone()
+            self.normal = *outward_normal
         } else {
-            self.normal = -outward_normal.clone()
+            self.normal = -*outward_normal
         }
+        self.geometric_normal = self.normal;
+        self.point_error = 0.0;
+        self.terminator_offset = Vec3::zero();
+    }
+
+    /// Origin of the rays leaving the surface towards `direction`, out of reach of the surface.
+    pub fn ray_origin(&self, direction: &Vec3) -> Point3 {
+        let point = if direction.dot(&self.normal) > 0.0 {
+            self.point + self.terminator_offset
+        } else {
+            self.point
+        };
+        let normal = if direction.dot(&self.geometric_normal) > 0.0 {
+            self.geometric_normal
+        } else {
+            -self.geometric_normal
+        };
+        let error = self.point_error * (normal.x().abs() + normal.y().abs() + normal.z().abs());
+        offset_origin(&(point + error * normal), &normal)
     }
 }
 
-pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool;
+/// Bound of the rounding errors of a point computed from quantities of magnitude `size`, such
+/// as the point of a curved surface reprojected onto it from the center.
+pub fn rounding_error(size: Float) -> Float {
+    4.0 * Float::EPSILON * size.abs()
 }
 
+pub trait Hittable: Any {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool;
+
+    /// Box enclosing the object, None if it is unbounded.
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    /// Density, with respect to solid angle, of sampling `direction` from `origin` with `random`.
+    fn pdf_value(&self, _origin: &Point3, _direction: &Vec3) -> Float {
+        0.0
+    }
+
+    /// Random direction from `origin` towards the object.
+    fn random(&self, _origin: &Point3, _rng: &mut SampleRng) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    /// Random point of the surface of the object, for emitting light from it. None if the
+    /// object can't be sampled this way.
+    fn sample_surface(&self, _rng: &mut SampleRng) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Density, with respect to area, of `sample_surface` returning `point`, a point of the
+    /// surface. 0 if the object can't be sampled this way.
+    fn surface_pdf(&self, _point: &Point3) -> Float {
+        0.0
+    }
+}
+
+/// Point sampled on the surface of an object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SurfaceSample {
+    pub point: Point3,
+    /// Unit outward normal of the surface at the point.
+    pub normal: Vec3,
+    /// Density, with respect to area, of sampling the point.
+    pub pdf: Float,
+}
+
+#[derive(Default)]
 pub struct HittableList {
     objects: Vec<Box<dyn Hittable>>,
 }
@@ -54,22 +166,58 @@ impl HittableList {
     pub fn clear(&mut self) {
         self.objects.clear();
     }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Hittable> {
+        self.objects.iter().map(|object| object.as_ref())
+    }
+
+    pub fn into_objects(self) -> Vec<Box<dyn Hittable>> {
+        self.objects
+    }
 }
 
 impl Hittable for HittableList {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
         let mut tmp_hit_record = HitRecord::empty();
         let mut hit_anything = false;
         let mut closest_so_far = t_max;
 
-        for obj in &self.objects {
+        for (i, obj) in self.objects.iter().enumerate() {
             if obj.hit(ray, t_min, closest_so_far, &mut tmp_hit_record) {
                 hit_anything = true;
                 closest_so_far = tmp_hit_record.t;
                 *hit_record = tmp_hit_record;
+                hit_record.object = ObjectId::new(i);
             }
         }
 
         hit_anything
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.objects.iter().try_fold(Aabb::empty(), |aabb, obj| {
+            Some(aabb.union(&obj.bounding_box()?))
+        })
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let weight = 1.0 / self.objects.len() as Float;
+        self.objects
+            .iter()
+            .map(|obj| weight * obj.pdf_value(origin, direction))
+            .sum()
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        let index = rng.gen_range(0..self.objects.len());
+        self.objects[index].random(origin, rng)
+    }
 }
@@ -0,0 +1,79 @@
+use crate::vec3::{unit_vector, Vec3};
+
+/// Orthonormal basis, `w` being the "up" axis of the local frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    pub fn build_from_w(n: &Vec3) -> Onb {
+        let w = unit_vector(*n);
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = unit_vector(w.cross(&a));
+        let u = w.cross(&v);
+        Onb { u, v, w }
+    }
+
+    /// Basis whose `w` is the unit `normal` and `u` the unit `tangent`, orthogonal to it.
+    pub fn from_tangent_frame(tangent: &Vec3, normal: &Vec3) -> Onb {
+        Onb {
+            u: *tangent,
+            v: normal.cross(tangent),
+            w: *normal,
+        }
+    }
+
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    pub fn w(&self) -> Vec3 {
+        self.w
+    }
+
+    /// Converts a vector expressed in this basis to world space.
+    pub fn local(&self, a: &Vec3) -> Vec3 {
+        a.x() * self.u + a.y() * self.v + a.z() * self.w
+    }
+
+    /// Converts a world space vector to this basis.
+    pub fn to_local(&self, a: &Vec3) -> Vec3 {
+        Vec3::new(a.dot(&self.u), a.dot(&self.v), a.dot(&self.w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orthonormal() {
+        let onb = Onb::build_from_w(&Vec3::new(1.0, 2.0, 3.0));
+        assert!(onb.u().dot(&onb.v()).abs() < 1e-6);
+        assert!(onb.u().dot(&onb.w()).abs() < 1e-6);
+        assert!(onb.v().dot(&onb.w()).abs() < 1e-6);
+        assert!((onb.u().length() - 1.0).abs() < 1e-6);
+        assert!((onb.v().length() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_local_w() {
+        let n = Vec3::new(0.0, 0.0, 2.0);
+        let onb = Onb::build_from_w(&n);
+        assert_eq!(
+            onb.local(&Vec3::new(0.0, 0.0, 1.0)),
+            Vec3::new(0.0, 0.0, 1.0)
+        );
+    }
+}
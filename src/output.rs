@@ -0,0 +1,565 @@
+use crate::aov::heatmap;
+use crate::float::Float;
+use crate::framebuffer::Framebuffer;
+use crate::integrator::Integrator;
+use crate::object::ObjectId;
+use crate::sampler::{blue_noise_mask, hash_pixel, BLUE_NOISE_SIZE};
+use crate::settings::RenderSettings;
+use crate::util::clamp;
+use crate::vec3::Color;
+use anyhow::{bail, Context, Result};
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+
+/// Averages the samples of each pixel and converts them to displayable colors.
+pub fn post_process(settings: &RenderSettings, framebuffer: &Framebuffer) -> Vec<Color> {
+    let pixels = framebuffer.pixels();
+
+    match settings.integrator {
+        Integrator::PathTracer | Integrator::Sppm | Integrator::Bidirectional => {
+            let exposure = settings.exposure.scale(&pixels);
+            let mut pixels: Vec<Color> = pixels.iter().map(|p| exposure * *p).collect();
+            if let Some(bloom) = &settings.bloom {
+                pixels = bloom.apply(framebuffer.width(), framebuffer.height(), &pixels);
+            }
+            pixels
+                .iter()
+                .map(|pixel| {
+                    let color = settings.tone_mapper.apply(*pixel, 1.0);
+                    settings.transfer_function.encode(color)
+                })
+                .collect()
+        }
+        Integrator::DebugDepth => normalize(&pixels),
+        Integrator::DebugNodes | Integrator::DebugPrimitives => {
+            heatmap(&pixels.iter().map(|p| p.x()).collect::<Vec<_>>())
+        }
+        _ => pixels,
+    }
+}
+
+/// Scales the image so that its brightest channel is 1.
+fn normalize(pixels: &[Color]) -> Vec<Color> {
+    let max = pixels
+        .iter()
+        .fold(0.0 as Float, |max, p| max.max(p.x()).max(p.y()).max(p.z()));
+    if max <= 0.0 {
+        return pixels.to_vec();
+    }
+    pixels.iter().map(|p| *p / max).collect()
+}
+
+/// Image file format, encoding colors given in row-major order from the top row. Integer
+/// formats store displayable colors, clamped to [0, 1] and quantized with `dither`.
+pub trait ImageWriter {
+    fn write(
+        &self,
+        writer: &mut dyn Write,
+        width: usize,
+        height: usize,
+        pixels: &[Color],
+        dither: Dither,
+    ) -> Result<()>;
+}
+
+/// Writes the image to the file at `path`, through a buffer.
+pub fn save_image<P: AsRef<Path>>(
+    path: P,
+    format: &dyn ImageWriter,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+    dither: Dither,
+) -> Result<()> {
+    let path = path.as_ref();
+    if pixels.len() != width * height {
+        bail!("Image size doesn't match the pixel count");
+    }
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create output file {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    format
+        .write(&mut writer, width, height, pixels, dither)
+        .and_then(|_| Ok(writer.flush()?))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Noise added to the colors before quantizing them, turning the bands of smooth gradients
+/// into fine grain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    None,
+    /// White noise with a triangular distribution over two quantization steps, making the
+    /// quantization error independent of the color.
+    Triangular,
+    /// Tiled blue-noise mask over one quantization step, a finer grain than white noise.
+    BlueNoise,
+}
+
+impl Dither {
+    /// Offset of the colors of the pixel (`x`, `y`), in quantization steps. Zero on average,
+    /// keeping the brightness of the image.
+    pub fn offset(&self, x: usize, y: usize) -> Float {
+        match *self {
+            Dither::None => 0.0,
+            Dither::Triangular => {
+                let h = hash_pixel(x as u16, y as u16);
+                let u1 = (h as u32 >> 8) as Float / (1u32 << 24) as Float;
+                let u2 = ((h >> 32) as u32 >> 8) as Float / (1u32 << 24) as Float;
+                u1 + u2 - 1.0
+            }
+            Dither::BlueNoise => {
+                let mask = blue_noise_mask();
+                let i = (y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE;
+                mask[i] as Float - 0.5
+            }
+        }
+    }
+
+    /// Offsets of the pixels of an image `width` pixels wide, in row-major order.
+    fn offsets(self, width: usize) -> impl Iterator<Item = Float> {
+        (0..).map(move |i| self.offset(i % width, i / width))
+    }
+}
+
+impl FromStr for Dither {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Dither> {
+        match s {
+            "none" => Ok(Dither::None),
+            "triangular" => Ok(Dither::Triangular),
+            "blue-noise" => Ok(Dither::BlueNoise),
+            _ => bail!(
+                "Unknown dithering '{}', expected one of: none, triangular, blue-noise",
+                s
+            ),
+        }
+    }
+}
+
+/// Integer in [0, `levels`) for the channel value `c`, shifted by `offset` steps.
+fn quantize(c: Float, levels: Float, offset: Float) -> Float {
+    clamp((levels * c + offset).floor(), 0.0, levels - 1.0)
+}
+
+/// Quantizes a color to 8 bits per channel, shifted by `offset` steps.
+pub fn to_rgb8(color: &Color, offset: Float) -> [u8; 3] {
+    let quantize = |c: Float| quantize(c, 256.0, offset) as u8;
+    [
+        quantize(color.x()),
+        quantize(color.y()),
+        quantize(color.z()),
+    ]
+}
+
+/// Quantizes a color to 16 bits per channel, shifted by `offset` steps.
+pub fn to_rgb16(color: &Color, offset: Float) -> [u16; 3] {
+    let quantize = |c: Float| quantize(c, 65536.0, offset) as u16;
+    [
+        quantize(color.x()),
+        quantize(color.y()),
+        quantize(color.z()),
+    ]
+}
+
+/// Opaque 8-bit RGBA bytes of the image `width` pixels wide, in row-major order from the top
+/// row, the layout of the images of web canvases.
+pub fn to_rgba8_bytes(width: usize, pixels: &[Color], dither: Dither) -> Vec<u8> {
+    pixels
+        .iter()
+        .zip(dither.offsets(width))
+        .flat_map(|(pixel, offset)| {
+            let [r, g, b] = to_rgb8(pixel, offset);
+            [r, g, b, 255]
+        })
+        .collect()
+}
+
+/// Format of the rendered image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Ppm,
+    Png,
+    Png16,
+    Pfm,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Ppm => "ppm",
+            OutputFormat::Png | OutputFormat::Png16 => "png",
+            OutputFormat::Pfm => "pfm",
+        }
+    }
+
+    /// Whether the format keeps the linear radiance, before tone mapping.
+    pub fn is_hdr(&self) -> bool {
+        *self == OutputFormat::Pfm
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "ppm" => Ok(OutputFormat::Ppm),
+            "png" => Ok(OutputFormat::Png),
+            "png16" => Ok(OutputFormat::Png16),
+            "pfm" => Ok(OutputFormat::Pfm),
+            _ => bail!(
+                "Unknown output format '{}', expected one of: ppm, png, png16, pfm",
+                s
+            ),
+        }
+    }
+}
+
+/// Netpbm color image, 8 bits per channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ppm {
+    /// P3, one line of decimal values per pixel.
+    Ascii,
+    /// P6, the raw bytes of the pixels.
+    Binary,
+}
+
+impl ImageWriter for Ppm {
+    fn write(
+        &self,
+        writer: &mut dyn Write,
+        width: usize,
+        height: usize,
+        pixels: &[Color],
+        dither: Dither,
+    ) -> Result<()> {
+        match *self {
+            Ppm::Ascii => {
+                write!(writer, "P3\n{} {}\n255\n", width, height)?;
+                for (pixel, offset) in pixels.iter().zip(dither.offsets(width)) {
+                    let [r, g, b] = to_rgb8(pixel, offset);
+                    writeln!(writer, "{} {} {}", r, g, b)?;
+                }
+            }
+            Ppm::Binary => {
+                write!(writer, "P6\n{} {}\n255\n", width, height)?;
+                for (pixel, offset) in pixels.iter().zip(dither.offsets(width)) {
+                    writer.write_all(&to_rgb8(pixel, offset))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ppm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Ppm> {
+        match s {
+            "p3" => Ok(Ppm::Ascii),
+            "p6" => Ok(Ppm::Binary),
+            _ => bail!("Unknown PPM format '{}', expected one of: p3, p6", s),
+        }
+    }
+}
+
+/// PNG, 8 bits per channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Png;
+
+impl ImageWriter for Png {
+    fn write(
+        &self,
+        writer: &mut dyn Write,
+        width: usize,
+        height: usize,
+        pixels: &[Color],
+        dither: Dither,
+    ) -> Result<()> {
+        let bytes: Vec<u8> = pixels
+            .iter()
+            .zip(dither.offsets(width))
+            .flat_map(|(pixel, offset)| to_rgb8(pixel, offset))
+            .collect();
+        PngEncoder::new(writer).write_image(
+            &bytes,
+            width as u32,
+            height as u32,
+            ExtendedColorType::Rgb8,
+        )?;
+        Ok(())
+    }
+}
+
+/// Writes object ids as a 16-bit grayscale PNG, for masking objects when compositing. Pixels
+/// hold the id plus one, zero being the background.
+pub fn save_object_ids<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    ids: &[Option<ObjectId>],
+) -> Result<()> {
+    let path = path.as_ref();
+    if ids.len() != width * height {
+        bail!("Image size doesn't match the pixel count");
+    }
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create output file {}", path.display()))?;
+    write_object_ids(&mut BufWriter::new(file), width, height, ids)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn write_object_ids(
+    writer: &mut dyn Write,
+    width: usize,
+    height: usize,
+    ids: &[Option<ObjectId>],
+) -> Result<()> {
+    // The encoder takes 16-bit samples in native byte order
+    let bytes: Vec<u8> = ids
+        .iter()
+        .map(|id| id.map_or(0, |id| (id.index() + 1).min(u16::MAX as usize) as u16))
+        .flat_map(u16::to_ne_bytes)
+        .collect();
+    PngEncoder::new(writer).write_image(
+        &bytes,
+        width as u32,
+        height as u32,
+        ExtendedColorType::L16,
+    )?;
+    Ok(())
+}
+
+/// PNG, 16 bits per channel, keeping the gradients of dark areas free of banding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Png16;
+
+impl ImageWriter for Png16 {
+    fn write(
+        &self,
+        writer: &mut dyn Write,
+        width: usize,
+        height: usize,
+        pixels: &[Color],
+        dither: Dither,
+    ) -> Result<()> {
+        // The encoder takes 16-bit samples in native byte order
+        let bytes: Vec<u8> = pixels
+            .iter()
+            .zip(dither.offsets(width))
+            .flat_map(|(pixel, offset)| to_rgb16(pixel, offset))
+            .flat_map(u16::to_ne_bytes)
+            .collect();
+        PngEncoder::new(writer).write_image(
+            &bytes,
+            width as u32,
+            height as u32,
+            ExtendedColorType::Rgb16,
+        )?;
+        Ok(())
+    }
+}
+
+/// Portable float map, 32-bit floats per channel storing colors as they are, for HDR images.
+/// Being exact, it isn't dithered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pfm;
+
+impl ImageWriter for Pfm {
+    // Stored as f32, whatever the precision of `Float`
+    #[allow(clippy::unnecessary_cast)]
+    fn write(
+        &self,
+        writer: &mut dyn Write,
+        width: usize,
+        height: usize,
+        pixels: &[Color],
+        _dither: Dither,
+    ) -> Result<()> {
+        // A negative scale stands for little-endian values
+        write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+        // Rows are stored from the bottom one
+        for row in pixels.chunks(width).rev() {
+            for pixel in row {
+                for c in &[pixel.x(), pixel.y(), pixel.z()] {
+                    writer.write_all(&(*c as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Video encoded by an `ffmpeg` process found in the `PATH`, fed the frames as raw RGB over
+/// its standard input rather than through image files. The format of the video follows the
+/// extension of its path, H.264 in an mp4 file for instance.
+pub struct FfmpegPipe {
+    child: Child,
+    stdin: ChildStdin,
+    path: PathBuf,
+    width: usize,
+    height: usize,
+}
+
+impl FfmpegPipe {
+    /// Starts encoding the video at `path`, of `fps` frames of `width`x`height` pixels per
+    /// second, overwriting it.
+    pub fn spawn<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        fps: Float,
+    ) -> Result<FfmpegPipe> {
+        let path = path.as_ref();
+        let mut child = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(["-s", &format!("{}x{}", width, height)])
+            .args(["-r", &fps.to_string(), "-i", "-"])
+            // Playable by most players, which expect 4:2:0 chroma
+            .args(["-pix_fmt", "yuv420p"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("Failed to start ffmpeg, is it installed?")?;
+        let stdin = child
+            .stdin
+            .take()
+            .context("Failed to open the input of ffmpeg")?;
+        Ok(FfmpegPipe {
+            child,
+            stdin,
+            path: path.to_path_buf(),
+            width,
+            height,
+        })
+    }
+
+    /// Appends a frame to the video, its colors quantized to 8 bits as for the PNG images.
+    pub fn write_frame(&mut self, pixels: &[Color], dither: Dither) -> Result<()> {
+        if pixels.len() != self.width * self.height {
+            bail!("Frame size doesn't match the size of the video");
+        }
+        let bytes: Vec<u8> = pixels
+            .iter()
+            .zip(dither.offsets(self.width))
+            .flat_map(|(pixel, offset)| to_rgb8(pixel, offset))
+            .collect();
+        self.stdin
+            .write_all(&bytes)
+            .with_context(|| format!("Failed to write a frame to {}", self.path.display()))
+    }
+
+    /// Ends the video with the frames written so far, waiting for ffmpeg to finish encoding.
+    pub fn finish(self) -> Result<()> {
+        let FfmpegPipe {
+            mut child,
+            stdin,
+            path,
+            ..
+        } = self;
+        // Closing the input tells ffmpeg that there are no frames left
+        drop(stdin);
+        let status = child.wait().context("Failed to wait for ffmpeg")?;
+        if !status.success() {
+            bail!("ffmpeg failed to encode {}: {}", path.display(), status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ppm() {
+        let pixels = [Color::new(1.0, 0.5, 0.0), Color::new(0.0, 0.0, 2.0)];
+
+        let mut ascii = Vec::new();
+        Ppm::Ascii
+            .write(&mut ascii, 2, 1, &pixels, Dither::None)
+            .unwrap();
+        assert_eq!(ascii, b"P3\n2 1\n255\n255 128 0\n0 0 255\n");
+
+        let mut binary = Vec::new();
+        Ppm::Binary
+            .write(&mut binary, 2, 1, &pixels, Dither::None)
+            .unwrap();
+        assert_eq!(binary, b"P6\n2 1\n255\n\xff\x80\x00\x00\x00\xff");
+    }
+
+    #[test]
+    fn test_rgba8_bytes() {
+        let pixels = [Color::new(1.0, 0.5, 0.0), Color::new(0.0, 0.0, 2.0)];
+        assert_eq!(
+            to_rgba8_bytes(2, &pixels, Dither::None),
+            [255, 128, 0, 255, 0, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_dither_keeps_average() {
+        // A value between two levels comes out as a mix of both, in the right proportions
+        let width = 64;
+        let pixels = vec![Color::new(100.3 / 256.0, 0.0, 1.0); width * width];
+        for dither in &[Dither::Triangular, Dither::BlueNoise] {
+            let mut ppm = Vec::new();
+            Ppm::Binary
+                .write(&mut ppm, width, width, &pixels, *dither)
+                .unwrap();
+            let header = format!("P6\n{} {}\n255\n", width, width);
+            let values: Vec<u8> = ppm[header.len()..].iter().step_by(3).copied().collect();
+            assert_eq!(values.len(), width * width);
+            assert!(values.iter().all(|&v| (99..=101).contains(&v)));
+            let average = values.iter().map(|&v| v as Float).sum::<Float>() / values.len() as Float;
+            // Quantizing rounds down by half a step on average, as without dithering
+            assert!((average - 99.8).abs() < 0.05, "{:?}: {}", dither, average);
+        }
+    }
+
+    #[test]
+    fn test_png16() {
+        let pixels = [Color::new(1.0, 0.5, 0.0)];
+        let mut png = Vec::new();
+        Png16.write(&mut png, 1, 1, &pixels, Dither::None).unwrap();
+
+        let image = image::load_from_memory(&png).unwrap().into_rgb16();
+        assert_eq!(image.into_raw(), vec![65535, 32768, 0]);
+    }
+
+    #[test]
+    fn test_pfm() {
+        let pixels = [Color::new(2.5, 0.0, 0.0), Color::new(0.0, 0.0, 1.0)];
+        let mut pfm = Vec::new();
+        Pfm.write(&mut pfm, 1, 2, &pixels, Dither::None).unwrap();
+
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&pfm[..header.len()], header);
+        let values: Vec<f32> = pfm[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        // Bottom row first
+        assert_eq!(values, vec![0.0, 0.0, 1.0, 2.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_object_ids() {
+        let ids = [None, Some(ObjectId::new(0)), Some(ObjectId::new(299))];
+        let mut png = Vec::new();
+        write_object_ids(&mut png, 3, 1, &ids).unwrap();
+
+        let image = image::load_from_memory(&png).unwrap().into_luma16();
+        assert_eq!(image.into_raw(), vec![0, 1, 300]);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::distributed::Tile;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Nice value of a render running in the background, the lowest priority.
+#[cfg(unix)]
+const BACKGROUND_NICE: libc::c_int = 19;
+
+/// Number of threads rendering in parallel by default, one per core.
+pub fn default_thread_count() -> usize {
+    thread::available_parallelism().map_or(1, |count| count.get())
+}
+
+/// Renders `tiles` on `threads` threads, calling `on_tile` on the calling thread with each
+/// rendered tile, in the order of `tiles`. Stops early when `on_tile` returns false.
+///
+/// Tiles splat their samples to the pixels of their neighbours, so handing them over in the
+/// same order whatever the thread count keeps the sums of these pixels, and the image, the
+/// same.
+///
+/// Scenes can't be shared between threads, so each of them renders with its own renderer made
+/// by `new_renderer`, typically with its own copy of the scene. A single thread renders on the
+/// calling thread, without spawning any, as in browsers where threads can't be spawned.
+pub fn render_tiles<N, R, T, F>(threads: usize, tiles: Vec<Tile>, new_renderer: N, mut on_tile: F)
+where
+    N: Fn() -> R + Sync,
+    R: FnMut(Tile) -> T,
+    T: Send,
+    F: FnMut(Tile, &T) -> bool,
+{
+    if threads <= 1 {
+        let mut render_tile = new_renderer();
+        for tile in tiles {
+            if !on_tile(tile, &render_tile(tile)) {
+                break;
+            }
+        }
+        return;
+    }
+
+    let next_tile = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (tiles, next_tile, stop, new_renderer) = (&tiles, &next_tile, &stop, &new_renderer);
+            scope.spawn(move || {
+                let mut render_tile = new_renderer();
+                while !stop.load(Ordering::SeqCst) {
+                    let position = next_tile.fetch_add(1, Ordering::SeqCst);
+                    let tile = match tiles.get(position) {
+                        Some(tile) => *tile,
+                        None => break,
+                    };
+                    if sender.send((position, render_tile(tile))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // The channel closes once all the threads are done
+        drop(sender);
+
+        // Tiles completed ahead of the ones before them wait for their turn
+        let mut completed = HashMap::new();
+        let mut next_position = 0;
+        'receive: for (position, rendered) in receiver {
+            completed.insert(position, rendered);
+            while let Some(rendered) = completed.remove(&next_position) {
+                if !on_tile(tiles[next_position], &rendered) {
+                    // Threads finish their current tile, which is dropped
+                    stop.store(true, Ordering::SeqCst);
+                    break 'receive;
+                }
+                next_position += 1;
+            }
+        }
+    });
+}
+
+/// Gives the render the lowest scheduling priority, so that it only uses the cores left idle
+/// by interactive work. Threads started afterwards inherit it.
+#[cfg(unix)]
+pub fn lower_priority() -> Result<()> {
+    // SAFETY: setpriority has no memory safety requirements
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICE) };
+    if result != 0 {
+        bail!(
+            "Failed to lower the priority: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority() -> Result<()> {
+    bail!("Lowering the priority is only supported on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::split_into_tiles;
+    use crate::vec3::Color;
+
+    #[test]
+    fn test_all_tiles_rendered_in_order() {
+        let tiles = split_into_tiles(100, 70, 16);
+        let mut rendered = Vec::new();
+        render_tiles(
+            4,
+            tiles.clone(),
+            || |tile: Tile| vec![Color::new(tile.x as _, tile.y as _, 0.0)],
+            |tile, pixels| {
+                assert_eq!(pixels[0], Color::new(tile.x as _, tile.y as _, 0.0));
+                rendered.push(tile);
+                true
+            },
+        );
+
+        assert_eq!(rendered, tiles);
+    }
+
+    #[test]
+    fn test_stop_early() {
+        let tiles = split_into_tiles(256, 256, 16);
+        let mut count = 0;
+        render_tiles(
+            2,
+            tiles,
+            || |_| vec![Color::zero()],
+            |_, _| {
+                count += 1;
+                count < 3
+            },
+        );
+        assert_eq!(count, 3);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::float::{Float, PI};
+use crate::material::ScatterPdf;
+use crate::microfacet::{reflect_about, Ggx};
+use crate::object::Hittable;
+use crate::onb::Onb;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use rand::Rng;
+
+/// Probability density over directions, with respect to solid angle.
+pub trait Pdf {
+    fn value(&self, direction: &Vec3) -> Float;
+    fn generate(&self, rng: &mut SampleRng) -> Vec3;
+}
+
+// --------
+//  COSINE
+// --------
+
+#[derive(Clone, Copy, Debug)]
+pub struct CosinePdf {
+    uvw: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(normal: &Vec3) -> CosinePdf {
+        CosinePdf {
+            uvw: Onb::build_from_w(normal),
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let cosine = unit_vector(*direction).dot(&self.uvw.w());
+        (cosine / PI).max(0.0)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        self.uvw.local(&Vec3::random_cosine_direction(rng))
+    }
+}
+
+// -----
+//  GGX
+// -----
+
+/// Reflections of `wo` about the GGX microfacet normals visible from it.
+#[derive(Clone, Copy, Debug)]
+pub struct GgxPdf {
+    uvw: Onb,
+    /// Outgoing direction, towards the viewer, in the local frame.
+    wo: Vec3,
+    distribution: Ggx,
+}
+
+impl GgxPdf {
+    pub fn new(uvw: Onb, wo: Vec3, distribution: Ggx) -> GgxPdf {
+        GgxPdf {
+            uvw,
+            wo,
+            distribution,
+        }
+    }
+}
+
+impl Pdf for GgxPdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let wi = unit_vector(self.uvw.to_local(direction));
+        self.distribution.reflection_pdf(&self.wo, &wi)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        let h = self.distribution.sample_visible_normal(&self.wo, rng);
+        self.uvw.local(&reflect_about(&self.wo, &h))
+    }
+}
+
+// ------------
+//  PRINCIPLED
+// ------------
+
+/// Mix of the diffuse, specular and clearcoat lobes of the principled material.
+#[derive(Clone, Copy, Debug)]
+pub struct PrincipledPdf {
+    uvw: Onb,
+    /// Outgoing direction, towards the viewer, in the local frame.
+    wo: Vec3,
+    specular: Ggx,
+    clearcoat: Ggx,
+    /// Probabilities of sampling the diffuse, specular and clearcoat lobes, summing to 1.
+    lobe_weights: [Float; 3],
+}
+
+impl PrincipledPdf {
+    pub fn new(
+        uvw: Onb,
+        wo: Vec3,
+        specular: Ggx,
+        clearcoat: Ggx,
+        lobe_weights: [Float; 3],
+    ) -> PrincipledPdf {
+        let total: Float = lobe_weights.iter().sum();
+        PrincipledPdf {
+            uvw,
+            wo,
+            specular,
+            clearcoat,
+            lobe_weights: [
+                lobe_weights[0] / total,
+                lobe_weights[1] / total,
+                lobe_weights[2] / total,
+            ],
+        }
+    }
+}
+
+impl Pdf for PrincipledPdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let wi = unit_vector(self.uvw.to_local(direction));
+        let [diffuse, specular, clearcoat] = self.lobe_weights;
+        diffuse * (wi.z() / PI).max(0.0)
+            + specular * self.specular.reflection_pdf(&self.wo, &wi)
+            + clearcoat * self.clearcoat.reflection_pdf(&self.wo, &wi)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        let [diffuse, specular, _] = self.lobe_weights;
+        let choice = rng.gen::<Float>();
+        let wi = if choice < diffuse {
+            Vec3::random_cosine_direction(rng)
+        } else {
+            let distribution = if choice < diffuse + specular {
+                &self.specular
+            } else {
+                &self.clearcoat
+            };
+            let h = distribution.sample_visible_normal(&self.wo, rng);
+            reflect_about(&self.wo, &h)
+        };
+        self.uvw.local(&wi)
+    }
+}
+
+// --------
+//  COATED
+// --------
+
+/// Mix of the glossy reflection on a coat and of the PDF of the material under it.
+#[derive(Clone, Debug)]
+pub struct CoatedPdf {
+    coat: GgxPdf,
+    /// PDF of the base, None when it scatters in a specular direction instead, in which case
+    /// only the coat is sampled, the remaining probability going to the specular direction.
+    base: Option<Box<ScatterPdf>>,
+    /// Probability of sampling the coat.
+    coat_weight: Float,
+}
+
+impl CoatedPdf {
+    pub fn new(coat: GgxPdf, base: Option<ScatterPdf>, coat_weight: Float) -> CoatedPdf {
+        CoatedPdf {
+            coat,
+            base: base.map(Box::new),
+            coat_weight,
+        }
+    }
+}
+
+impl Pdf for CoatedPdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let base = self.base.as_ref().map_or(0.0, |base| base.value(direction));
+        self.coat_weight * self.coat.value(direction) + (1.0 - self.coat_weight) * base
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        match self.base {
+            Some(ref base) if rng.gen::<Float>() >= self.coat_weight => base.generate(rng),
+            _ => self.coat.generate(rng),
+        }
+    }
+}
+
+// ------
+//  HAIR
+// ------
+
+/// Tilt of the scales of the cuticle of hair fibers, in radians, shifting their highlights
+/// along them.
+const SCALE_TILT: Float = 2.0 * PI / 180.0;
+/// Smallest variance of the lobes, perfectly smooth fibers making them Diracs.
+const MIN_HAIR_VARIANCE: Float = 1e-4;
+
+/// Directions scattered by a fiber, simplified from the model of Marschner et al.: the
+/// reflection on its surface and the light going through it, each around the cone of
+/// directions mirroring the outgoing one across the fiber, and evenly all around the fiber.
+#[derive(Clone, Copy, Debug)]
+pub struct HairPdf {
+    /// Frame whose `u` is along the fiber.
+    uvw: Onb,
+    /// Sine and cosine of the angle of the outgoing direction with the plane across the fiber.
+    sin_theta_o: Float,
+    cos_theta_o: Float,
+    /// Variance of the reflection, that of the light going through being 4 times as large.
+    variance: Float,
+    /// Probability of sampling the reflection, the light going through otherwise.
+    reflectance: Float,
+}
+
+impl HairPdf {
+    /// Scattering towards `wo` of a fiber of `roughness` in [0, 1] reflecting a fraction
+    /// `reflectance` of the light.
+    pub fn new(uvw: Onb, wo: &Vec3, roughness: Float, reflectance: Float) -> HairPdf {
+        let sin_theta_o = unit_vector(uvw.to_local(wo)).x().clamp(-1.0, 1.0);
+        // Fitted by d'Eon et al. for the width of the lobes to match the roughness
+        let beta = roughness.clamp(0.0, 1.0);
+        let variance = (0.726 * beta + 0.812 * beta * beta + 3.7 * beta.powi(20)).powi(2);
+        HairPdf {
+            uvw,
+            sin_theta_o,
+            cos_theta_o: (1.0 - sin_theta_o * sin_theta_o).max(0.0).sqrt(),
+            variance: variance.max(MIN_HAIR_VARIANCE),
+            reflectance,
+        }
+    }
+
+    /// Densities of the reflection and of the light going through at `direction`, weighted by
+    /// their probabilities: they sum to the density of the PDF.
+    pub fn lobes(&self, direction: &Vec3) -> (Float, Float) {
+        let sin_theta_i = unit_vector(self.uvw.to_local(direction))
+            .x()
+            .clamp(-1.0, 1.0);
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).max(0.0).sqrt();
+        let [reflected, through] = self.lobe_parameters();
+        let density = |(sin_theta_o, cos_theta_o, variance)| {
+            longitudinal_scattering(sin_theta_i, cos_theta_i, sin_theta_o, cos_theta_o, variance)
+                / (2.0 * PI)
+        };
+        (
+            self.reflectance * density(reflected),
+            (1.0 - self.reflectance) * density(through),
+        )
+    }
+
+    /// Sine and cosine of the outgoing angle each lobe mirrors, tilted by the scales, and
+    /// variance of the lobe.
+    fn lobe_parameters(&self) -> [(Float, Float, Float); 2] {
+        let tilted = |angle: Float| {
+            let (sin, cos) = angle.sin_cos();
+            (
+                self.sin_theta_o * cos + self.cos_theta_o * sin,
+                (self.cos_theta_o * cos - self.sin_theta_o * sin).abs(),
+            )
+        };
+        let (sin_reflected, cos_reflected) = tilted(-2.0 * SCALE_TILT);
+        let (sin_through, cos_through) = tilted(4.0 * SCALE_TILT);
+        [
+            (sin_reflected, cos_reflected, self.variance),
+            (sin_through, cos_through, 4.0 * self.variance),
+        ]
+    }
+}
+
+impl Pdf for HairPdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let (reflected, through) = self.lobes(direction);
+        reflected + through
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        let [reflected, through] = self.lobe_parameters();
+        let (sin_theta_o, cos_theta_o, variance) = if rng.gen::<Float>() < self.reflectance {
+            reflected
+        } else {
+            through
+        };
+
+        // Sampling of d'Eon et al.: a direction spread around the mirrored one, of which only
+        // the angle with the plane across the fiber is kept
+        let u = rng.gen::<Float>().max(1e-5);
+        let cos_theta = 1.0 + variance * (u + (1.0 - u) * (-2.0 / variance).exp()).ln();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let cos_phi = (2.0 * PI * rng.gen::<Float>()).cos();
+        let sin_theta_i =
+            (-cos_theta * sin_theta_o + sin_theta * cos_phi * cos_theta_o).clamp(-1.0, 1.0);
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).max(0.0).sqrt();
+
+        let phi = 2.0 * PI * rng.gen::<Float>();
+        self.uvw.local(&Vec3::new(
+            sin_theta_i,
+            cos_theta_i * phi.cos(),
+            cos_theta_i * phi.sin(),
+        ))
+    }
+}
+
+/// Density of the angle of the incoming direction with the plane across the fiber, with
+/// respect to its cosine times the angle, for a lobe of `variance` around the mirror of the
+/// outgoing angle (d'Eon et al.).
+fn longitudinal_scattering(
+    sin_theta_i: Float,
+    cos_theta_i: Float,
+    sin_theta_o: Float,
+    cos_theta_o: Float,
+    variance: Float,
+) -> Float {
+    let a = cos_theta_i * cos_theta_o / variance;
+    let b = sin_theta_i * sin_theta_o / variance;
+    if variance <= 0.1 {
+        // With logarithms, the exponentials overflowing otherwise
+        (log_bessel_i0(a) - b - 1.0 / variance + (2.0 as Float).ln() + (0.5 / variance).ln()).exp()
+    } else {
+        (-b).exp() * bessel_i0(a) / ((1.0 / variance).sinh() * 2.0 * variance)
+    }
+}
+
+/// Modified Bessel function of the first kind of order 0, from the first terms of its series.
+fn bessel_i0(x: Float) -> Float {
+    let mut sum = 0.0;
+    let mut term = 1.0;
+    for i in 1..=10 {
+        sum += term;
+        term *= x * x / (4 * i * i) as Float;
+    }
+    sum
+}
+
+fn log_bessel_i0(x: Float) -> Float {
+    if x > 12.0 {
+        // Asymptotic expansion
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        bessel_i0(x).ln()
+    }
+}
+
+// ----------
+//  HITTABLE
+// ----------
+
+/// Samples directions from `origin` towards a hittable, typically the list of lights.
+pub struct HittablePdf<'a> {
+    origin: Point3,
+    hittable: &'a dyn Hittable,
+}
+
+impl<'a> HittablePdf<'a> {
+    pub fn new(hittable: &'a dyn Hittable, origin: Point3) -> HittablePdf<'a> {
+        HittablePdf { origin, hittable }
+    }
+}
+
+impl<'a> Pdf for HittablePdf<'a> {
+    fn value(&self, direction: &Vec3) -> Float {
+        self.hittable.pdf_value(&self.origin, direction)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        self.hittable.random(&self.origin, rng)
+    }
+}
+
+// ---------
+//  MIXTURE
+// ---------
+
+/// Even mix of two PDFs.
+pub struct MixturePdf<'a> {
+    p0: &'a dyn Pdf,
+    p1: &'a dyn Pdf,
+}
+
+impl<'a> MixturePdf<'a> {
+    pub fn new(p0: &'a dyn Pdf, p1: &'a dyn Pdf) -> MixturePdf<'a> {
+        MixturePdf { p0, p1 }
+    }
+}
+
+impl<'a> Pdf for MixturePdf<'a> {
+    fn value(&self, direction: &Vec3) -> Float {
+        0.5 * self.p0.value(direction) + 0.5 * self.p1.value(direction)
+    }
+
+    fn generate(&self, rng: &mut SampleRng) -> Vec3 {
+        if rng.gen::<Float>() < 0.5 {
+            self.p0.generate(rng)
+        } else {
+            self.p1.generate(rng)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cosine_generate_in_hemisphere() {
+        let mut rng = SampleRng::new(0);
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let pdf = CosinePdf::new(&normal);
+        for _ in 0..100 {
+            let direction = pdf.generate(&mut rng);
+            assert!(direction.dot(&normal) >= 0.0);
+            assert!(pdf.value(&direction) >= 0.0);
+        }
+    }
+
+    #[test]
+    fn test_mixture_value() {
+        let pdf0 = CosinePdf::new(&Vec3::new(0.0, 1.0, 0.0));
+        let pdf1 = CosinePdf::new(&Vec3::new(0.0, -1.0, 0.0));
+        let mixture = MixturePdf::new(&pdf0, &pdf1);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert!((mixture.value(&up) - 0.5 / PI).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_principled_normalized() {
+        let uvw = Onb::build_from_w(&Vec3::new(0.0, 1.0, 0.0));
+        let wo = unit_vector(Vec3::new(0.5, 0.0, 1.0));
+        let pdf = PrincipledPdf::new(
+            uvw,
+            wo,
+            Ggx::from_roughness(0.5, 0.0),
+            Ggx::from_roughness(0.3, 0.0),
+            [0.5, 1.0, 0.25],
+        );
+
+        // Integrate over the sphere with uniform samples
+        let mut rng = SampleRng::new(0);
+        let n = 200_000;
+        let sum: Float = (0..n)
+            .map(|_| pdf.value(&Vec3::random_unit_vector(&mut rng)))
+            .sum();
+        let integral = sum * 4.0 * PI / n as Float;
+        assert!((integral - 1.0).abs() < 0.05, "integral = {}", integral);
+    }
+
+    #[test]
+    fn test_hair_normalized() {
+        let uvw = Onb::build_from_w(&Vec3::new(0.0, 0.0, 1.0));
+        let mut rng = SampleRng::new(0);
+        for (roughness, wo) in [
+            (0.3, Vec3::new(0.0, 0.0, 1.0)),
+            (0.6, Vec3::new(0.8, 0.2, 0.5)),
+            (0.1, Vec3::new(-0.5, 0.3, 0.2)),
+        ] {
+            let pdf = HairPdf::new(uvw, &unit_vector(wo), roughness, 0.2);
+            let n = 200_000;
+            let sum: Float = (0..n)
+                .map(|_| pdf.value(&Vec3::random_unit_vector(&mut rng)))
+                .sum();
+            let integral = sum * 4.0 * PI / n as Float;
+            assert!((integral - 1.0).abs() < 0.05, "integral = {}", integral);
+        }
+    }
+
+    #[test]
+    fn test_hair_highlight_mirrors_along_fiber() {
+        // Fiber along x, seen from 30 degrees towards +x
+        let uvw = Onb::build_from_w(&Vec3::new(0.0, 0.0, 1.0));
+        let wo = Vec3::new(0.5, 0.0, (0.75 as Float).sqrt());
+        let pdf = HairPdf::new(uvw, &uvw.local(&wo), 0.2, 1.0);
+        let mirrored = uvw.local(&Vec3::new(-0.5, 0.0, -(0.75 as Float).sqrt()));
+        let other_side = uvw.local(&Vec3::new(-0.5, (0.75 as Float).sqrt(), 0.0));
+        let back = uvw.local(&wo);
+        // The same all around the fiber, at the mirrored angle
+        assert!((pdf.value(&mirrored) - pdf.value(&other_side)).abs() < 1e-3);
+        assert!(pdf.value(&mirrored) > 10.0 * pdf.value(&back));
+    }
+}
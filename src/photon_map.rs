@@ -0,0 +1,438 @@
+//! Caustics, light focused by glass and mirrors onto diffuse surfaces, estimated from photons
+//! traced from the lights before rendering.
+//!
+//! Paths from the camera only find the lights through specular bounces by chance, which leaves
+//! caustics noisy for a long time. Photons are traced the other way: emitted from the lights,
+//! bounced off specular surfaces, and stored where they land on the first non specular one.
+//! The radiance reflected there is then estimated from the density of the photons around.
+
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::fog::HeightFog;
+use crate::integrator::crossed_surface;
+use crate::light::sample_emission;
+use crate::light_bvh::LightBvh;
+use crate::material::{Material, MaterialList, ScatterRecord, ScatterType, Scatterable};
+use crate::medium::MediumStack;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Number of photons the radiance estimates are made from.
+const GATHER_COUNT: usize = 50;
+/// Largest distance photons are gathered from, relative to the diagonal of the box around them.
+const MAX_GATHER_RADIUS: Float = 0.01;
+
+/// Light arriving at a point of a surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Photon {
+    pub position: Point3,
+    /// Unit direction the photon was travelling in.
+    pub direction: Vec3,
+    /// Flux carried by the photon.
+    pub power: Color,
+}
+
+/// Photons stored in a balanced kd-tree, for finding the ones closest to a point.
+pub struct PhotonMap {
+    /// Photons in the order of the tree: the median of each range splits the rest of it along
+    /// the axis stored at the same position in `axes`.
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    max_radius: Float,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        let bounds = photons.iter().fold(Aabb::empty(), |bounds, photon| {
+            bounds.grow(&photon.position)
+        });
+        let max_radius = if photons.is_empty() {
+            0.0
+        } else {
+            MAX_GATHER_RADIUS * bounds.extent().length()
+        };
+        PhotonMap {
+            photons,
+            axes,
+            max_radius,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Up to `count` photons closest to `point`, within `radius` of it, along with the squared
+    /// radius of the region they were found in: the distance to the farthest one when `count`
+    /// were found, `radius` otherwise.
+    pub fn nearest(&self, point: &Point3, count: usize, radius: Float) -> (Vec<&Photon>, Float) {
+        let mut neighbors = BinaryHeap::with_capacity(count + 1);
+        let mut radius_squared = radius * radius;
+        self.search(
+            0,
+            self.photons.len(),
+            point,
+            count,
+            &mut neighbors,
+            &mut radius_squared,
+        );
+        let photons = neighbors
+            .into_iter()
+            .map(|neighbor| &self.photons[neighbor.index])
+            .collect();
+        (photons, radius_squared)
+    }
+
+    fn search(
+        &self,
+        start: usize,
+        end: usize,
+        point: &Point3,
+        count: usize,
+        neighbors: &mut BinaryHeap<Neighbor>,
+        radius_squared: &mut Float,
+    ) {
+        if start >= end {
+            return;
+        }
+        let middle = (start + end) / 2;
+        let photon = &self.photons[middle];
+        let axis = self.axes[middle] as usize;
+        let offset = point[axis] - photon.position[axis];
+        let (near, far) = if offset < 0.0 {
+            ((start, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (start, middle))
+        };
+
+        self.search(near.0, near.1, point, count, neighbors, radius_squared);
+        let distance_squared = (photon.position - *point).length_squared();
+        if distance_squared < *radius_squared {
+            neighbors.push(Neighbor {
+                distance_squared,
+                index: middle,
+            });
+            if neighbors.len() > count {
+                neighbors.pop();
+            }
+            if neighbors.len() == count {
+                *radius_squared = neighbors.peek().unwrap().distance_squared;
+            }
+        }
+        if offset * offset < *radius_squared {
+            self.search(far.0, far.1, point, count, neighbors, radius_squared);
+        }
+    }
+
+    /// Radiance reflected along `-in_ray` at `hit_record`, on a surface made of `material`, of
+    /// the photons around the hit point.
+    pub fn radiance(&self, material: &Material, in_ray: &Ray, hit_record: &HitRecord) -> Color {
+        let (photons, radius_squared) =
+            self.nearest(&hit_record.point, GATHER_COUNT, self.max_radius);
+        if photons.is_empty() {
+            return Color::zero();
+        }
+
+        let mut reflected = Color::zero();
+        for photon in photons {
+            // Photons arriving from the other side of the surface light it up from behind
+            let cosine = -photon.direction.dot(&hit_record.normal);
+            if cosine <= 0.0 {
+                continue;
+            }
+            // The BSDF includes the cosine, which the flux of the photons already accounts for
+            let incoming = Ray::new(hit_record.point, -photon.direction);
+            reflected += material.eval(in_ray, hit_record, &incoming) / cosine * photon.power;
+        }
+        reflected / (PI * radius_squared)
+    }
+}
+
+/// Photon of the map at `index`, ordered by distance to the point searched around.
+struct Neighbor {
+    distance_squared: Float,
+    index: usize,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Neighbor) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Neighbor) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Neighbor) -> Ordering {
+        self.distance_squared.total_cmp(&other.distance_squared)
+    }
+}
+
+/// Orders `photons` into a kd-tree, splitting each range at its median along its longest axis.
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+    let bounds = photons.iter().fold(Aabb::empty(), |bounds, photon| {
+        bounds.grow(&photon.position)
+    });
+    let axis = bounds.longest_axis();
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+    axes[middle] = axis as u8;
+
+    let (photons_before, photons_after) = photons.split_at_mut(middle);
+    let (axes_before, axes_after) = axes.split_at_mut(middle);
+    build(photons_before, axes_before);
+    build(&mut photons_after[1..], &mut axes_after[1..]);
+}
+
+/// Traces `photon_count` photons from the `lights` through `world`, keeping the ones reaching
+/// a non specular surface after specular bounces only: the caustics.
+///
+/// Photons are emitted from random points of the lights, which must be able to sample their
+/// surface, in cosine-weighted directions around their normal. They end absorbed or scattered
+/// away by the `fog`, without being stored.
+#[allow(clippy::too_many_arguments)]
+pub fn trace_caustics<H: Hittable + ?Sized>(
+    world: &H,
+    lights: &LightBvh,
+    materials: &MaterialList,
+    fog: Option<&HeightFog>,
+    photon_count: u32,
+    bounce_limit: u16,
+    rng: &mut SampleRng,
+) -> PhotonMap {
+    let lights: Vec<&dyn Hittable> = lights.iter().collect();
+    let mut photons = Vec::new();
+    for _ in 0..photon_count {
+        let (ray, power) = match emit_photon(&lights, materials, photon_count, rng) {
+            Some(emitted) => emitted,
+            None => continue,
+        };
+        if let Some(photon) = trace_photon(world, materials, fog, ray, power, bounce_limit, rng) {
+            photons.push(photon);
+        }
+    }
+    PhotonMap::new(photons)
+}
+
+/// Ray leaving a random point of one of the `lights`, in a cosine-weighted direction around
+/// its normal, along with the flux it carries as one of `photon_count` photons. None if the
+/// light can't sample its surface or doesn't emit in that direction.
+pub(crate) fn emit_photon(
+    lights: &[&dyn Hittable],
+    materials: &MaterialList,
+    photon_count: u32,
+    rng: &mut SampleRng,
+) -> Option<(Ray, Color)> {
+    let emission = sample_emission(lights, materials, rng)?;
+
+    // Densities of the point and the cosine-weighted direction
+    let density = emission.pdf / PI;
+    let power = emission.radiance / (density * photon_count as Float);
+    Some((emission.ray, power))
+}
+
+/// Follows a photon leaving along `ray` with `power`, returning it where it lands on a non
+/// specular surface after at least one specular bounce.
+fn trace_photon<H: Hittable + ?Sized>(
+    world: &H,
+    materials: &MaterialList,
+    fog: Option<&HeightFog>,
+    mut ray: Ray,
+    mut power: Color,
+    bounce_limit: u16,
+    rng: &mut SampleRng,
+) -> Option<Photon> {
+    let mut media = MediumStack::new();
+    let mut specular = false;
+    for _ in 0..bounce_limit {
+        let mut hit_record = HitRecord::empty();
+        if !world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            return None;
+        }
+        if let Some(fog) = fog {
+            if fog.sample_interaction(&ray, hit_record.t, rng).is_some() {
+                return None;
+            }
+        }
+
+        let material = &materials[hit_record.material];
+        hit_record.outside_refraction_index = media.outside_of(hit_record.material);
+        let mut scatter_record = ScatterRecord::empty();
+        if !material.scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            return None;
+        }
+        match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => {
+                if let Some(refraction_index) = material.refraction_index() {
+                    if crossed_surface(&hit_record, &specular_ray) {
+                        media.cross(hit_record.material, refraction_index, hit_record.front_face);
+                    }
+                }
+                power *= scatter_record.attenuation;
+                ray = specular_ray;
+                specular = true;
+            }
+            ScatterType::Pdf(_) => {
+                return specular.then(|| Photon {
+                    position: hit_record.point,
+                    direction: unit_vector(ray.direction()),
+                    power,
+                });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::SolidColor;
+    use crate::integrator::path_trace;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian};
+    use crate::object::HittableList;
+    use crate::rect::Rect;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_nearest_matches_brute_force() {
+        let mut rng = SampleRng::new(0);
+        let photons: Vec<Photon> = (0..1000)
+            .map(|_| Photon {
+                position: Vec3::random_range(&mut rng, -1.0, 1.0) * Vec3::new(4.0, 1.0, 0.2),
+                direction: Vec3::new(0.0, -1.0, 0.0),
+                power: Color::new(1.0, 1.0, 1.0),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), photons.len());
+
+        for _ in 0..20 {
+            let point = Vec3::random_range(&mut rng, -1.0, 1.0);
+            let (found, radius_squared) = map.nearest(&point, 10, 1.0);
+            let mut distances: Vec<Float> = photons
+                .iter()
+                .map(|photon| (photon.position - point).length_squared())
+                .filter(|&distance_squared| distance_squared < 1.0)
+                .collect();
+            distances.sort_by(|a, b| a.total_cmp(b));
+            distances.truncate(10);
+
+            let mut found: Vec<Float> = found
+                .iter()
+                .map(|photon| (photon.position - point).length_squared())
+                .collect();
+            found.sort_by(|a, b| a.total_cmp(b));
+            assert_eq!(found, distances);
+            assert_eq!(radius_squared, *distances.last().unwrap());
+        }
+
+        // Too far from all the photons
+        let (found, radius_squared) = map.nearest(&Point3::new(0.0, 10.0, 0.0), 10, 1.0);
+        assert!(found.is_empty());
+        assert_eq!(radius_squared, 1.0);
+    }
+
+    #[test]
+    fn test_caustic_under_glass_sphere() {
+        let mut materials = MaterialList::new();
+        let white = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+            0.5, 0.5, 0.5,
+        ))));
+        let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+        let light = materials.add(Material::DiffuseLight(DiffuseLight::new(Color::new(
+            4.0, 4.0, 4.0,
+        ))));
+
+        // A light above a glass ball, facing down onto the floor
+        let light_rect = || {
+            Rect::new(
+                Point3::new(-0.5, 4.0, -0.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                light,
+            )
+        };
+        let mut world = HittableList::new();
+        world.add(Box::new(Rect::new(
+            Point3::new(-5.0, 0.0, -5.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            white,
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 1.5, 0.0),
+            1.0,
+            glass,
+        )));
+        world.add(Box::new(light_rect()));
+        let mut lights = HittableList::new();
+        lights.add(Box::new(light_rect()));
+        let lights = LightBvh::new(lights, &materials);
+
+        let mut rng = SampleRng::new(0);
+        let map = trace_caustics(&world, &lights, &materials, None, 20_000, 10, &mut rng);
+        assert!(!map.is_empty());
+        assert!(map
+            .photons
+            .iter()
+            .all(|photon| photon.position.y().abs() < 1e-3));
+
+        // The ball focuses the light below it, away from it there is no caustic
+        let floor_record = |x: Float| {
+            let ray = Ray::new(Point3::new(x, 0.25, 0.0), Vec3::new(0.0, -1.0, 0.0));
+            let mut hit_record = HitRecord::empty();
+            assert!(world.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+            (ray, hit_record)
+        };
+        let (ray_at_focus, hit_record) = floor_record(0.0);
+        let focus = map.radiance(&materials[white], &ray_at_focus, &hit_record);
+        let (ray, hit_record) = floor_record(4.0);
+        let away = map.radiance(&materials[white], &ray, &hit_record);
+        assert_eq!(away, Color::zero());
+
+        // Below the ball, the light only arrives through it, which paths from the floor find
+        // often enough for the path tracer to converge
+        let mut path_traced = Color::zero();
+        let sample_count = 20_000;
+        for i in 0..sample_count {
+            let mut rng = SampleRng::for_sample(1, 0, i);
+            path_traced += path_trace(
+                &mut rng,
+                &ray_at_focus,
+                &world,
+                &lights,
+                &[],
+                &materials,
+                &SolidColor::new(Color::zero()),
+                None,
+                None,
+                None,
+                None,
+                false,
+                10,
+            );
+        }
+        path_traced /= sample_count as Float;
+        assert!((focus.x() - path_traced.x()).abs() < 0.15 * path_traced.x());
+    }
+}
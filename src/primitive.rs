@@ -0,0 +1,120 @@
+use crate::aabb::Aabb;
+use crate::disk::Disk;
+use crate::float::Float;
+use crate::mesh::Mesh;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::rect::Rect;
+use crate::rng::SampleRng;
+use crate::sphere::Sphere;
+use crate::vec3::{Point3, Vec3};
+use std::any::Any;
+
+/// Object in the leaves of the BVH. The most common shapes are called directly rather than
+/// through the `Hittable` trait, on the hottest path of the renderer; other objects are kept
+/// boxed.
+pub enum Primitive {
+    Sphere(Sphere),
+    Rect(Rect),
+    Disk(Disk),
+    /// Boxed, being much larger than the other shapes.
+    Mesh(Box<Mesh>),
+    Other(Box<dyn Hittable>),
+}
+
+impl Primitive {
+    /// Unboxes `object` if it is one of the known shapes.
+    pub fn new(object: Box<dyn Hittable>) -> Primitive {
+        unbox(object)
+            .map(Primitive::Sphere)
+            .or_else(|object| unbox(object).map(Primitive::Rect))
+            .or_else(|object| unbox(object).map(Primitive::Disk))
+            .or_else(|object| unbox(object).map(|mesh| Primitive::Mesh(Box::new(mesh))))
+            .unwrap_or_else(Primitive::Other)
+    }
+
+    pub fn as_hittable(&self) -> &dyn Hittable {
+        match *self {
+            Primitive::Sphere(ref sphere) => sphere,
+            Primitive::Rect(ref rect) => rect,
+            Primitive::Disk(ref disk) => disk,
+            Primitive::Mesh(ref mesh) => mesh.as_ref(),
+            Primitive::Other(ref object) => object.as_ref(),
+        }
+    }
+}
+
+/// The object of type `T` inside the box, or the box itself if it holds another type.
+fn unbox<T: Hittable>(object: Box<dyn Hittable>) -> Result<T, Box<dyn Hittable>> {
+    let any: &dyn Any = object.as_ref();
+    if !any.is::<T>() {
+        return Err(object);
+    }
+    let object: Box<dyn Any> = object;
+    Ok(*object.downcast::<T>().unwrap())
+}
+
+impl Hittable for Primitive {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        match *self {
+            Primitive::Sphere(ref sphere) => sphere.hit(ray, t_min, t_max, hit_record),
+            Primitive::Rect(ref rect) => rect.hit(ray, t_min, t_max, hit_record),
+            Primitive::Disk(ref disk) => disk.hit(ray, t_min, t_max, hit_record),
+            Primitive::Mesh(ref mesh) => mesh.hit(ray, t_min, t_max, hit_record),
+            Primitive::Other(ref object) => object.hit(ray, t_min, t_max, hit_record),
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match *self {
+            Primitive::Sphere(ref sphere) => sphere.bounding_box(),
+            Primitive::Rect(ref rect) => rect.bounding_box(),
+            Primitive::Disk(ref disk) => disk.bounding_box(),
+            Primitive::Mesh(ref mesh) => mesh.bounding_box(),
+            Primitive::Other(ref object) => object.bounding_box(),
+        }
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.as_hittable().pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        self.as_hittable().random(origin, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::Instance;
+    use crate::material::MaterialId;
+    use crate::transform::Transform;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_known_shapes_are_unboxed() {
+        let material = MaterialId::default();
+        let sphere = Sphere::new(Point3::zero(), 1.0, material);
+        let primitive = Primitive::new(Box::new(sphere.clone()));
+        assert!(matches!(primitive, Primitive::Sphere(_)));
+
+        let rect = Rect::new(
+            Point3::zero(),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            material,
+        );
+        assert!(matches!(Primitive::new(Box::new(rect)), Primitive::Rect(_)));
+
+        // Other objects keep going through the trait, and hit the same
+        let translation = Transform::translation(Vec3::new(0.0, 0.0, -3.0));
+        let instance = Instance::new(Rc::new(sphere), translation);
+        let primitive = Primitive::new(Box::new(instance));
+        assert!(matches!(primitive, Primitive::Other(_)));
+        let ray = Ray::new(Point3::zero(), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::empty();
+        assert!(primitive.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 2.0).abs() < 1e-5);
+    }
+}
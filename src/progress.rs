@@ -0,0 +1,193 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::float::Float;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::stats::STATS;
+use anyhow::{bail, Result};
+#[cfg(not(target_arch = "wasm32"))]
+use indicatif::{ProgressBar, ProgressStyle};
+use std::str::FromStr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+/// Time between two lines of JSON progress.
+#[cfg(not(target_arch = "wasm32"))]
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the progress of renders is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    /// Progress bar on the terminal.
+    Bar,
+    /// A JSON object per line on the standard output, every second and when done, for the
+    /// tools tracking renders.
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ProgressFormat> {
+        match s {
+            "bar" => Ok(ProgressFormat::Bar),
+            "json" => Ok(ProgressFormat::Json),
+            _ => bail!(
+                "Unknown progress format '{}', expected one of: bar, json",
+                s
+            ),
+        }
+    }
+}
+
+/// Parts of the work a render is counted in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressUnit {
+    Row,
+    Tile,
+    Iteration,
+    /// Sample of every pixel, for renders done in passes over the whole image.
+    Sample,
+}
+
+// Progress is only shown on terminals
+#[cfg(not(target_arch = "wasm32"))]
+impl ProgressUnit {
+    fn label(&self) -> &'static str {
+        match *self {
+            ProgressUnit::Row => "Row",
+            ProgressUnit::Tile => "Tile",
+            ProgressUnit::Iteration => "Iteration",
+            ProgressUnit::Sample => "Samples per pixel",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            ProgressUnit::Row => "row",
+            ProgressUnit::Tile => "tile",
+            ProgressUnit::Iteration => "iteration",
+            ProgressUnit::Sample => "sample",
+        }
+    }
+}
+
+/// Progress of a render of `total` units, each doing an equal share of the samples of the
+/// pixels.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Progress {
+    format: ProgressFormat,
+    bar: ProgressBar,
+    unit: ProgressUnit,
+    done: u64,
+    total: u64,
+    samples_per_pixel: u16,
+    start: Instant,
+    last_report: Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Progress {
+    pub fn new(
+        format: ProgressFormat,
+        total: u64,
+        unit: ProgressUnit,
+        samples_per_pixel: u16,
+    ) -> Progress {
+        let bar = match format {
+            ProgressFormat::Bar => {
+                let bar = ProgressBar::new(total);
+                bar.set_style(ProgressStyle::default_bar().template(&format!(
+                    "[{{elapsed_precise}}] [{{bar:40.cyan/blue}}] ({{pos}}/{{len}} {}, ETA {{eta}})",
+                    unit.label()
+                )));
+                bar
+            }
+            ProgressFormat::Json => ProgressBar::hidden(),
+        };
+        let start = Instant::now();
+        Progress {
+            format,
+            bar,
+            unit,
+            done: 0,
+            total,
+            samples_per_pixel,
+            start,
+            last_report: start,
+        }
+    }
+
+    /// Counts `count` more units done.
+    pub fn inc(&mut self, count: u64) {
+        self.done += count;
+        self.bar.inc(count);
+        if self.format == ProgressFormat::Json && self.last_report.elapsed() >= JSON_INTERVAL {
+            self.report();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.bar.finish();
+        if self.format == ProgressFormat::Json {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.last_report = Instant::now();
+        let elapsed = self.start.elapsed();
+        println!(
+            "{}",
+            self.to_json(elapsed, STATS.report(elapsed).rays_per_second())
+        );
+    }
+
+    /// Single JSON object, with the estimated seconds left as `eta`, null before the first unit
+    /// is done.
+    fn to_json(&self, elapsed: Duration, rays_per_second: f64) -> String {
+        let seconds = elapsed.as_secs_f64();
+        let fraction = if self.total > 0 {
+            self.done as f64 / self.total as f64
+        } else {
+            1.0
+        };
+        let eta = if self.done > 0 {
+            format!("{:.3}", seconds * (1.0 / fraction - 1.0))
+        } else {
+            "null".to_string()
+        };
+        format!(
+            "{{\"unit\": \"{}\", \"done\": {}, \"total\": {}, \"samples_per_pixel\": {:.2}, \
+             \"rays_per_second\": {:.0}, \"elapsed\": {:.3}, \"eta\": {}}}",
+            self.unit.name(),
+            self.done,
+            self.total,
+            self.samples_per_pixel as Float * fraction as Float,
+            rays_per_second,
+            seconds,
+            eta
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let mut progress = Progress::new(ProgressFormat::Json, 8, ProgressUnit::Tile, 100);
+        assert_eq!(
+            progress.to_json(Duration::from_millis(500), 0.0),
+            "{\"unit\": \"tile\", \"done\": 0, \"total\": 8, \"samples_per_pixel\": 0.00, \
+             \"rays_per_second\": 0, \"elapsed\": 0.500, \"eta\": null}"
+        );
+
+        progress.done = 2;
+        assert_eq!(
+            progress.to_json(Duration::from_secs(3), 1234.4),
+            "{\"unit\": \"tile\", \"done\": 2, \"total\": 8, \"samples_per_pixel\": 25.00, \
+             \"rays_per_second\": 1234, \"elapsed\": 3.000, \"eta\": 9.000}"
+        );
+        assert!("plain".parse::<ProgressFormat>().is_err());
+    }
+}
@@ -1,14 +1,33 @@
+use crate::float::{add_ulps, Float};
 use crate::vec3::{Point3, Vec3};
 
+/// Below this distance from the origin, points are offset by a fixed distance rather than a
+/// number of units in the last place, which get too small there.
+const OFFSET_ORIGIN: Float = 1.0 / 32.0;
+const OFFSET_FLOAT_SCALE: Float = 1.0 / 65536.0;
+const OFFSET_INT_SCALE: Float = 256.0;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Ray {
     origin: Point3,
     direction: Vec3,
+    /// Color channel the path of the ray is restricted to, once it went through dispersive
+    /// glass. Rays continuing the path keep it.
+    channel: Option<u8>,
 }
 
 impl Ray {
     pub fn new(origin: Point3, direction: Vec3) -> Ray {
-        Ray { origin, direction }
+        Ray {
+            origin,
+            direction,
+            channel: None,
+        }
+    }
+
+    pub fn with_channel(mut self, channel: Option<u8>) -> Ray {
+        self.channel = channel;
+        self
     }
 
     pub fn origin(&self) -> Point3 {
@@ -19,11 +38,37 @@ impl Ray {
         self.direction
     }
 
-    pub fn at(&self, t: f32) -> Point3 {
+    pub fn channel(&self) -> Option<u8> {
+        self.channel
+    }
+
+    pub fn at(&self, t: Float) -> Point3 {
         self.origin + t * self.direction
     }
 }
 
+/// Origin of the rays leaving a surface from `point`, on the side of its geometric `normal`, far
+/// enough for them not to hit the surface again whatever the rounding errors of the intersection.
+///
+/// The offset grows with the coordinates, as the rounding errors do, so that giant and tiny
+/// objects alike need no epsilon on the distances along the rays (Wächter and Binder, "A Fast
+/// and Robust Method for Avoiding Self-Intersection", Ray Tracing Gems).
+pub fn offset_origin(point: &Point3, normal: &Vec3) -> Point3 {
+    let offset = |p: Float, n: Float| {
+        if p.abs() < OFFSET_ORIGIN {
+            p + OFFSET_FLOAT_SCALE * n
+        } else {
+            let ulps = (OFFSET_INT_SCALE * n) as i32;
+            add_ulps(p, if p < 0.0 { -ulps } else { ulps })
+        }
+    };
+    Point3::new(
+        offset(point.x(), normal.x()),
+        offset(point.y(), normal.y()),
+        offset(point.z(), normal.z()),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,4 +78,17 @@ mod tests {
         let r = Ray::new(Point3::zero(), Vec3::new(1.0, 2.0, 3.0));
         assert_eq!(r.at(10.0), Point3::new(10.0, 20.0, 30.0));
     }
+
+    #[test]
+    fn test_offset_origin() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        for &y in &[-1e6, -1.0, 0.0, 1e-3, 1.0, 1e6] {
+            let point = Point3::new(y, y, y);
+            let offset = offset_origin(&point, &up);
+            assert!(offset.y() > point.y());
+            assert!(offset.y() - point.y() < 1e-3 * y.abs().max(1.0));
+            assert_eq!(offset.x(), point.x());
+            assert!(offset_origin(&point, &-up).y() < point.y());
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::material::MaterialId;
+use crate::object::{HitRecord, Hittable, SurfaceSample};
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use rand::Rng;
+
+/// Flat parallelogram with a corner at `corner` and sides `u` and `v`, a rectangle when they
+/// are orthogonal. Facing `u` x `v`.
+#[derive(Clone)]
+pub struct Rect {
+    corner: Point3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    /// Projects a vector from the corner onto `u` and `v`: n / (n . n) with n = `u` x `v`.
+    w: Vec3,
+    area: Float,
+    material: MaterialId,
+}
+
+impl Rect {
+    pub fn new(corner: Point3, u: Vec3, v: Vec3, material: MaterialId) -> Rect {
+        let n = u.cross(&v);
+        Rect {
+            corner,
+            u,
+            v,
+            normal: unit_vector(n),
+            w: n / n.dot(&n),
+            area: n.length(),
+            material,
+        }
+    }
+}
+
+impl Hittable for Rect {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let denominator = ray.direction().dot(&self.normal);
+        if denominator.abs() < 1e-8 || self.material.culls(ray, &self.normal) {
+            // Parallel to the plane, or seen from a culled back
+            return false;
+        }
+
+        let t = (self.corner - ray.origin()).dot(&self.normal) / denominator;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        // Coordinates of the hit along the sides
+        let p = ray.at(t) - self.corner;
+        let alpha = self.w.dot(&p.cross(&self.v));
+        let beta = self.w.dot(&self.u.cross(&p));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return false;
+        }
+
+        hit_record.t = t;
+        hit_record.point = ray.at(t);
+        hit_record.set_face_normal(ray, &self.normal);
+        hit_record.u = alpha;
+        hit_record.v = beta;
+        hit_record.tangent = self.u;
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let far_corner = self.corner + self.u + self.v;
+        Some(
+            Aabb::new(self.corner, far_corner)
+                .grow(&(self.corner + self.u))
+                .grow(&(self.corner + self.v)),
+        )
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let mut hit_record = HitRecord::empty();
+        if !self.hit(
+            &Ray::new(*origin, *direction),
+            0.001,
+            Float::MAX,
+            &mut hit_record,
+        ) {
+            return 0.0;
+        }
+
+        let distance_squared = hit_record.t * hit_record.t * direction.length_squared();
+        let cosine = (direction.dot(&hit_record.normal) / direction.length()).abs();
+
+        distance_squared / (cosine * self.area)
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        // Uniform on the area
+        let p = self.corner + rng.gen::<Float>() * self.u + rng.gen::<Float>() * self.v;
+        p - *origin
+    }
+
+    fn sample_surface(&self, rng: &mut SampleRng) -> Option<SurfaceSample> {
+        Some(SurfaceSample {
+            point: self.corner + rng.gen::<Float>() * self.u + rng.gen::<Float>() * self.v,
+            normal: self.normal,
+            pdf: 1.0 / self.area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point3) -> Float {
+        1.0 / self.area
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Rect {
+        Rect::new(
+            Point3::new(-1.0, 0.0, 1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -2.0),
+            MaterialId::default(),
+        )
+    }
+
+    #[test]
+    fn test_hit() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(0.5, 3.0, -0.5), Vec3::new(0.0, -1.0, 0.0));
+        assert!(square().hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 3.0).abs() < 1e-5);
+        assert!((hit_record.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+        assert!((hit_record.u - 0.75).abs() < 1e-5);
+        assert!((hit_record.v - 0.75).abs() < 1e-5);
+        assert!(hit_record.front_face);
+
+        let outside = Ray::new(Point3::new(1.5, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(!square().hit(&outside, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    #[test]
+    fn test_pdf_integrates_to_one() {
+        // Averaging 1 / pdf over the sampled directions estimates the solid angle of the
+        // square, whose exact value is known from above its center
+        let rect = square();
+        let origin = Point3::new(0.0, 1.0, 0.0);
+        let mut rng = SampleRng::new(0);
+        let n = 10000;
+        let estimate = (0..n)
+            .map(|_| 1.0 / rect.pdf_value(&origin, &rect.random(&origin, &mut rng)))
+            .sum::<Float>()
+            / n as Float;
+        // Solid angle of a 2a x 2b rectangle at distance d: 4 asin(ab / sqrt((a² + d²)(b² + d²)))
+        let exact = 4.0 * (1.0 / (2.0 as Float * 2.0).sqrt()).asin();
+        assert!((estimate - exact).abs() < 0.05 * exact);
+    }
+}
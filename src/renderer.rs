@@ -0,0 +1,415 @@
+use crate::distributed::{split_into_tiles, Tile, TILE_SIZE};
+use crate::float::Float;
+use crate::framebuffer::Framebuffer;
+use crate::parallel;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vec3::Color;
+use anyhow::{anyhow, Result};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+// The callbacks can be sent to the thread of a render run in the background
+type BuildScene<'a> = Box<dyn Fn() -> Scene + Send + Sync + 'a>;
+type OnTile<'a> = Box<dyn FnMut(Tile, &[Color]) -> bool + Send + 'a>;
+type OnPass<'a> = Box<dyn FnMut(u32, &Framebuffer) -> bool + Send + 'a>;
+
+/// Renders images in tiles on several threads for the programs embedding the ray tracer,
+/// handing them each tile and each pass over the image as soon as they are done, e.g. to
+/// stream them to their own UI or over the network. Renders can also run in the background,
+/// controlled by a `RenderHandle`.
+///
+/// ```no_run
+/// use rust_ray_tracing::renderer::Renderer;
+/// use rust_ray_tracing::settings::RenderSettings;
+///
+/// let framebuffer = Renderer::builder(RenderSettings::default())
+///     .with_passes(4)
+///     .on_tile(|tile, pixels| {
+///         println!("{} pixels from ({}, {})", pixels.len(), tile.x, tile.y);
+///         true
+///     })
+///     .build()
+///     .render();
+/// ```
+pub struct Renderer<'a> {
+    settings: RenderSettings,
+    threads: usize,
+    passes: u32,
+    build_scene: BuildScene<'a>,
+    on_tile: Option<OnTile<'a>>,
+    on_pass: Option<OnPass<'a>>,
+    control: Arc<RenderControl>,
+}
+
+impl<'a> Renderer<'a> {
+    /// Builder of a renderer of the image of the `settings`, on one thread per core and in a
+    /// single pass by default.
+    pub fn builder(settings: RenderSettings) -> RendererBuilder<'a> {
+        RendererBuilder {
+            settings,
+            threads: parallel::default_thread_count(),
+            passes: 1,
+            build_scene: None,
+            on_tile: None,
+            on_pass: None,
+        }
+    }
+
+    /// Renders the image, stopping after the current tile when a callback returns false or the
+    /// render is cancelled. The pixels not rendered yet are left without samples.
+    pub fn render(&mut self) -> Framebuffer {
+        let settings = &self.settings;
+        let (width, height) = (settings.image_width, settings.image_height);
+        let mut framebuffer = Framebuffer::new(width as usize, height as usize);
+        let tiles = split_into_tiles(width, height, TILE_SIZE);
+
+        for pass in 0..self.passes {
+            let samples = self.pass_samples(pass);
+            let (build_scene, control) = (&self.build_scene, &*self.control);
+            let new_renderer = || {
+                let scene = build_scene();
+                let samples = samples.clone();
+                move |tile| {
+                    control.wait_while_paused();
+                    scene.render_samples(settings, tile, samples.clone())
+                }
+            };
+
+            let on_tile = &mut self.on_tile;
+            let mut stopped = false;
+            parallel::render_tiles(self.threads, tiles.clone(), new_renderer, |tile, result| {
+                if control.cancelled.load(Ordering::SeqCst) {
+                    stopped = true;
+                    return false;
+                }
+                result.merge_into(&mut framebuffer);
+                control.tiles_done.fetch_add(1, Ordering::SeqCst);
+                if let Some(on_tile) = on_tile {
+                    let pixels: Vec<Color> = tile
+                        .pixel_indices(width)
+                        .map(|index| framebuffer.pixel(index))
+                        .collect();
+                    stopped = !on_tile(tile, &pixels);
+                }
+                !stopped
+            });
+
+            if stopped || control.cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Some(on_pass) = &mut self.on_pass {
+                if !on_pass(pass, &framebuffer) {
+                    break;
+                }
+            }
+        }
+        framebuffer
+    }
+
+    /// Number of tiles rendered by all the passes.
+    fn tile_count(&self) -> usize {
+        let tiles = split_into_tiles(
+            self.settings.image_width,
+            self.settings.image_height,
+            TILE_SIZE,
+        );
+        tiles.len() * self.passes as usize
+    }
+
+    /// Indices of the samples of the pixels taken by the pass `pass`, the samples per pixel
+    /// being shared out as evenly as possible.
+    fn pass_samples(&self, pass: u32) -> Range<u32> {
+        let samples_per_pixel = self.settings.samples_per_pixel as u32;
+        let first_sample = self.settings.first_sample;
+        first_sample + pass * samples_per_pixel / self.passes
+            ..first_sample + (pass + 1) * samples_per_pixel / self.passes
+    }
+}
+
+impl Renderer<'static> {
+    /// Starts rendering the image on other threads, the callbacks being called on the thread
+    /// of the render, and returns at once with the handle controlling it. Not available in
+    /// browsers, where threads can't be spawned.
+    pub fn spawn(mut self) -> RenderHandle {
+        let control = Arc::clone(&self.control);
+        let tile_count = self.tile_count();
+        RenderHandle {
+            control,
+            tile_count,
+            thread: Some(thread::spawn(move || self.render())),
+        }
+    }
+}
+
+/// State of a render shared with the handle controlling it from another thread.
+#[derive(Default)]
+struct RenderControl {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    /// Notified when the render is resumed or cancelled.
+    unpaused: Condvar,
+    tiles_done: AtomicUsize,
+}
+
+impl RenderControl {
+    /// Blocks the calling thread while the render is paused and not cancelled.
+    fn wait_while_paused(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.cancelled.load(Ordering::SeqCst) {
+            paused = self.unpaused.wait(paused).unwrap();
+        }
+    }
+
+    fn set_paused(&self, paused: bool) {
+        *self.paused.lock().unwrap() = paused;
+        self.unpaused.notify_all();
+    }
+}
+
+/// Render running in the background, started by `Renderer::spawn`, e.g. for the GUIs to stop
+/// it without stopping the whole process. Dropping the handle cancels the render.
+pub struct RenderHandle {
+    control: Arc<RenderControl>,
+    tile_count: usize,
+    thread: Option<JoinHandle<Framebuffer>>,
+}
+
+impl RenderHandle {
+    /// Stops the render after the tiles being rendered, which are dropped.
+    pub fn cancel(&self) {
+        self.control.cancelled.store(true, Ordering::SeqCst);
+        // Waking up the threads waiting for the render to be resumed
+        self.control.set_paused(false);
+    }
+
+    /// Holds the threads back from starting new tiles until `resume` is called. The tiles
+    /// being rendered still complete.
+    pub fn pause(&self) {
+        self.control.set_paused(true);
+    }
+
+    pub fn resume(&self) {
+        self.control.set_paused(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.control.paused.lock().unwrap()
+    }
+
+    /// Share of the tiles of all the passes rendered so far, in [0, 1].
+    pub fn progress(&self) -> Float {
+        if self.tile_count == 0 {
+            return 1.0;
+        }
+        self.control.tiles_done.load(Ordering::SeqCst) as Float / self.tile_count as Float
+    }
+
+    /// Whether the render is over, complete or not, `wait` then returning at once.
+    pub fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+
+    /// Waits for the end of the render, and returns the image as rendered until then.
+    pub fn wait(mut self) -> Result<Framebuffer> {
+        let thread = self.thread.take().expect("Render already waited for");
+        thread.join().map_err(|_| anyhow!("The render panicked"))
+    }
+}
+
+impl Drop for RenderHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.cancel();
+        }
+    }
+}
+
+/// Options of a `Renderer`, made by `Renderer::builder`.
+pub struct RendererBuilder<'a> {
+    settings: RenderSettings,
+    threads: usize,
+    passes: u32,
+    build_scene: Option<BuildScene<'a>>,
+    on_tile: Option<OnTile<'a>>,
+    on_pass: Option<OnPass<'a>>,
+}
+
+impl<'a> RendererBuilder<'a> {
+    pub fn with_threads(mut self, threads: usize) -> RendererBuilder<'a> {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Renders the samples per pixel in `passes` passes over the whole image, each refining
+    /// the one before, at most one per sample.
+    pub fn with_passes(mut self, passes: u32) -> RendererBuilder<'a> {
+        self.passes = passes.max(1);
+        self
+    }
+
+    /// Renders the scenes made by `build_scene`, one per thread and pass, rather than the
+    /// built-in scene of the settings.
+    pub fn with_scene(
+        mut self,
+        build_scene: impl Fn() -> Scene + Send + Sync + 'a,
+    ) -> RendererBuilder<'a> {
+        self.build_scene = Some(Box::new(build_scene));
+        self
+    }
+
+    /// Calls `on_tile` on the thread of the render with each tile once rendered, and its pixels
+    /// with all the samples of the passes so far, in row-major order. The render stops when
+    /// it returns false.
+    pub fn on_tile(
+        mut self,
+        on_tile: impl FnMut(Tile, &[Color]) -> bool + Send + 'a,
+    ) -> RendererBuilder<'a> {
+        self.on_tile = Some(Box::new(on_tile));
+        self
+    }
+
+    /// Calls `on_pass` with the index of each pass, from 0, and the image once the pass is
+    /// rendered. The render stops when it returns false.
+    pub fn on_pass(
+        mut self,
+        on_pass: impl FnMut(u32, &Framebuffer) -> bool + Send + 'a,
+    ) -> RendererBuilder<'a> {
+        self.on_pass = Some(Box::new(on_pass));
+        self
+    }
+
+    pub fn build(self) -> Renderer<'a> {
+        let build_scene = match self.build_scene {
+            Some(build_scene) => build_scene,
+            None => {
+                let settings = self.settings;
+                Box::new(move || settings.scene.build(&settings))
+            }
+        };
+        Renderer {
+            passes: self
+                .passes
+                .min((self.settings.samples_per_pixel as u32).max(1)),
+            settings: self.settings,
+            threads: self.threads,
+            build_scene,
+            on_tile: self.on_tile,
+            on_pass: self.on_pass,
+            control: Arc::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::BuiltinScene;
+
+    fn settings() -> RenderSettings {
+        RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 40,
+            image_height: 36,
+            samples_per_pixel: 6,
+            ..RenderSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_callbacks() {
+        let settings = settings();
+        let tile_count = split_into_tiles(40, 36, TILE_SIZE).len();
+        let mut tiles = Vec::new();
+        let mut passes = Vec::new();
+        let framebuffer = Renderer::builder(settings)
+            .with_threads(2)
+            .with_passes(3)
+            .on_tile(|tile, pixels| {
+                assert_eq!(pixels.len(), (tile.width * tile.height) as usize);
+                tiles.push(tile);
+                true
+            })
+            .on_pass(|pass, framebuffer| {
+                passes.push((pass, framebuffer.sample_count(0)));
+                true
+            })
+            .build()
+            .render();
+        assert_eq!(tiles.len(), 3 * tile_count);
+        assert_eq!(passes, vec![(0, 2), (1, 4), (2, 6)]);
+        assert_eq!(framebuffer.sample_count(0), 6);
+
+        // The same image in a single pass
+        let scene = settings.scene.build(&settings);
+        let single = Renderer::builder(settings).build().render();
+        let mut tiled = Framebuffer::new(40, 36);
+        for tile in split_into_tiles(40, 36, TILE_SIZE) {
+            scene.render_tile(&settings, tile).merge_into(&mut tiled);
+        }
+        for index in 0..single.len() {
+            assert!((single.pixel(index) - tiled.pixel(index)).length() < 1e-4);
+            assert!((single.pixel(index) - framebuffer.pixel(index)).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut tile_count = 0;
+        let framebuffer = Renderer::builder(settings())
+            .with_passes(2)
+            .on_tile(|_, _| {
+                tile_count += 1;
+                false
+            })
+            .build()
+            .render();
+        assert_eq!(tile_count, 1);
+        assert!((0..framebuffer.len()).any(|index| framebuffer.sample_count(index) == 0));
+
+        let mut pass_count = 0;
+        Renderer::builder(settings())
+            .with_passes(6)
+            .on_pass(|_, _| {
+                pass_count += 1;
+                pass_count < 2
+            })
+            .build()
+            .render();
+        assert_eq!(pass_count, 2);
+    }
+
+    #[test]
+    fn test_render_handle() {
+        let settings = RenderSettings {
+            image_width: 64,
+            image_height: 64,
+            samples_per_pixel: 1,
+            ..settings()
+        };
+        let handle = Renderer::builder(settings).with_threads(2).build().spawn();
+        handle.pause();
+        assert!(handle.is_paused());
+        // At most the tile each thread had started
+        thread::sleep(std::time::Duration::from_millis(500));
+        let progress = handle.progress();
+        assert!(progress <= 0.5);
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(handle.progress(), progress);
+
+        handle.resume();
+        let framebuffer = handle.wait().unwrap();
+        assert!((0..framebuffer.len()).all(|index| framebuffer.sample_count(index) == 1));
+
+        // Cancelled while paused
+        let handle = Renderer::builder(settings).with_threads(1).build().spawn();
+        handle.pause();
+        handle.cancel();
+        let framebuffer = handle.wait().unwrap();
+        assert!((0..framebuffer.len()).any(|index| framebuffer.sample_count(index) == 0));
+    }
+}
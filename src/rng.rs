@@ -0,0 +1,116 @@
+use rand::{Error, RngCore};
+
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+
+/// Random number generator of the renderer: PCG-XSH-RR, small and fast to seed.
+///
+/// Each sample of each pixel gets its own generator, seeded from the seed of the render, the
+/// pixel and the index of the sample, so that images are the same whatever the order the
+/// pixels are rendered in, and the number of threads rendering them.
+#[derive(Clone, Debug)]
+pub struct SampleRng {
+    state: u64,
+    /// Odd increment of the underlying linear congruential generator.
+    increment: u64,
+}
+
+impl SampleRng {
+    pub fn new(seed: u64) -> SampleRng {
+        let mut rng = SampleRng {
+            state: 0,
+            increment: (mix(seed ^ 0xda94_2042_e4dd_58b5) << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(mix(seed));
+        rng.step();
+        rng
+    }
+
+    /// Generator of the `sample_index`-th sample of the pixel at `pixel_index` in the image,
+    /// for the render with the given `seed`.
+    pub fn for_sample(seed: u64, pixel_index: usize, sample_index: u32) -> SampleRng {
+        SampleRng::new(mix(
+            mix(seed ^ mix(pixel_index as u64)) ^ sample_index as u64
+        ))
+    }
+
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+/// SplitMix64 finalizer, spreading the bits of consecutive seeds over the whole state.
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl RngCore for SampleRng {
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.step();
+        let xor_shifted = (((state >> 18) ^ state) >> 27) as u32;
+        let rotation = (state >> 59) as u32;
+        xor_shifted.rotate_right(rotation)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let low = self.next_u32() as u64;
+        let high = self.next_u32() as u64;
+        (high << 32) | low
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::Float;
+    use rand::Rng;
+
+    #[test]
+    fn test_same_seed_same_numbers() {
+        let mut a = SampleRng::for_sample(7, 1234, 3);
+        let mut b = SampleRng::for_sample(7, 1234, 3);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+        let mut c = SampleRng::for_sample(7, 1234, 4);
+        let mut d = SampleRng::for_sample(7, 1235, 3);
+        let first = SampleRng::for_sample(7, 1234, 3).next_u64();
+        assert_ne!(c.next_u64(), first);
+        assert_ne!(d.next_u64(), first);
+    }
+
+    #[test]
+    fn test_uniform() {
+        // Neighbouring samples of neighbouring pixels aren't correlated
+        let mut sum = 0.0;
+        let mut buckets = [0; 10];
+        let count = 100_000;
+        for i in 0..count {
+            let x: Float = SampleRng::for_sample(0, i / 8, (i % 8) as u32).gen();
+            sum += x as f64;
+            buckets[(x * 10.0) as usize] += 1;
+        }
+        assert!((sum / count as f64 - 0.5).abs() < 0.01);
+        for bucket in &buckets {
+            assert!((*bucket as f64 / count as f64 - 0.1).abs() < 0.01);
+        }
+    }
+}
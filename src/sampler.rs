@@ -0,0 +1,313 @@
+use crate::float::Float;
+use crate::rng::SampleRng;
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Side of the tiled blue-noise mask.
+pub(crate) const BLUE_NOISE_SIZE: usize = 64;
+
+/// Source of the positions of the camera rays within their pixel.
+///
+/// The low-discrepancy sequences spread the samples of a pixel evenly, converging faster than
+/// independent random samples in smooth regions. They are scrambled differently for each pixel,
+/// so that neighbouring pixels don't share the same pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampler {
+    /// Independent uniform samples.
+    Random,
+    /// Halton sequence in bases 2 and 3, randomly shifted per pixel (Cranley-Patterson).
+    Halton,
+    /// Sobol (0, 2)-sequence, with per-pixel random digit scrambling.
+    Sobol,
+    /// Additive recurrence shifted per pixel by a tiled blue-noise mask, so that the error of
+    /// neighbouring pixels differs as much as possible and low sample counts look like fine,
+    /// even grain rather than blotches.
+    BlueNoise,
+}
+
+impl Sampler {
+    /// Point of [0, 1)^2 for the `sample_index`-th sample of the pixel (`x`, `y`).
+    pub fn pixel_sample(
+        &self,
+        x: u16,
+        y: u16,
+        sample_index: u32,
+        rng: &mut SampleRng,
+    ) -> (Float, Float) {
+        match *self {
+            Sampler::Random => (rng.gen::<Float>(), rng.gen::<Float>()),
+            Sampler::Halton => {
+                let seed = hash_pixel(x, y);
+                let shift_x = to_unit_float(seed as u32);
+                let shift_y = to_unit_float((seed >> 32) as u32);
+                (
+                    wrap(radical_inverse(2, sample_index) + shift_x),
+                    wrap(radical_inverse(3, sample_index) + shift_y),
+                )
+            }
+            Sampler::Sobol => {
+                let seed = hash_pixel(x, y);
+                (
+                    to_unit_float(sample_index.reverse_bits() ^ seed as u32),
+                    to_unit_float(sobol_second_dimension(sample_index) ^ (seed >> 32) as u32),
+                )
+            }
+            Sampler::BlueNoise => {
+                // R2 sequence, based on the plastic number
+                const ALPHA_X: f64 = 0.754_877_666_246_692_8;
+                const ALPHA_Y: f64 = 0.569_840_290_998_053_2;
+                let mask = blue_noise_mask();
+                // The second dimension reads the mask half a tile away, decorrelating the two
+                let offset = BLUE_NOISE_SIZE / 2;
+                let (x, y) = (x as usize, y as usize);
+                let shift_x = mask[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE];
+                let shift_y = mask[((y + offset) % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE
+                    + (x + offset) % BLUE_NOISE_SIZE];
+                (
+                    wrap((shift_x + sample_index as f64 * ALPHA_X).fract() as Float),
+                    wrap((shift_y + sample_index as f64 * ALPHA_Y).fract() as Float),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Sampler::Random => "random",
+            Sampler::Halton => "halton",
+            Sampler::Sobol => "sobol",
+            Sampler::BlueNoise => "blue-noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Sampler {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Sampler> {
+        match s {
+            "random" => Ok(Sampler::Random),
+            "halton" => Ok(Sampler::Halton),
+            "sobol" => Ok(Sampler::Sobol),
+            "blue-noise" => Ok(Sampler::BlueNoise),
+            _ => bail!(
+                "Unknown sampler '{}', expected one of: random, halton, sobol, blue-noise",
+                s
+            ),
+        }
+    }
+}
+
+/// Mirrors the digits of `index` in `base` around the radix point.
+fn radical_inverse(base: u32, mut index: u32) -> Float {
+    let inverse_base = 1.0 / base as f64;
+    let mut inverse_base_n = 1.0;
+    let mut reversed = 0u64;
+    while index > 0 {
+        let next = index / base;
+        reversed = reversed * base as u64 + (index - next * base) as u64;
+        inverse_base_n *= inverse_base;
+        index = next;
+    }
+    (reversed as f64 * inverse_base_n) as Float
+}
+
+/// Second dimension of the Sobol sequence, as the bits of a fraction.
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut direction = 1u32 << 31;
+    let mut value = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            value ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    value
+}
+
+/// Values in [0, 1) of the blue-noise mask, in row-major order. Built on first use, always the
+/// same.
+pub(crate) fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE, 1.9, 0))
+}
+
+/// Ulichney's void-and-cluster method: ranks the pixels of a `size` x `size` tileable mask so
+/// that each new pixel goes where the previous ones are the sparsest, their density being
+/// measured with a Gaussian of deviation `sigma`.
+fn void_and_cluster(size: usize, sigma: f64, seed: u64) -> Vec<f64> {
+    let n = size * size;
+
+    // Gaussian of the toroidal offset between two pixels
+    let mut kernel = vec![0.0; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f64;
+            let wy = dy.min(size - dy) as f64;
+            kernel[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let toggle = |pattern: &mut [bool], energy: &mut [f64], p: usize| {
+        pattern[p] = !pattern[p];
+        let sign = if pattern[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // Densest set pixel and sparsest empty one
+    let tightest_cluster = |pattern: &[bool], energy: &[f64]| {
+        (0..n)
+            .filter(|&p| pattern[p])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f64]| {
+        (0..n)
+            .filter(|&p| !pattern[p])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // Random initial pattern, relaxed by moving its densest pixels to the largest voids
+    let mut rng = StdRng::seed_from_u64(seed);
+    let initial_count = n / 10;
+    let mut count = 0;
+    while count < initial_count {
+        let p = rng.gen_range(0..n);
+        if !pattern[p] {
+            toggle(&mut pattern, &mut energy, p);
+            count += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+
+    // Rank the initial pixels by removing them, densest first
+    let (initial_pattern, initial_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial_count).rev() {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        ranks[cluster] = rank;
+    }
+
+    // Then fill the mask, largest void first
+    pattern = initial_pattern;
+    energy = initial_energy;
+    for rank in initial_count..n {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks.iter().map(|&rank| rank as f64 / n as f64).collect()
+}
+
+pub(crate) fn hash_pixel(x: u16, y: u16) -> u64 {
+    // SplitMix64 finalizer
+    let mut h = ((x as u64) << 16 | y as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Fraction whose bits are `bits`, keeping the 24 most significant ones so that it can't round
+/// up to 1.
+fn to_unit_float(bits: u32) -> Float {
+    (bits >> 8) as Float / (1u32 << 24) as Float
+}
+
+fn wrap(x: Float) -> Float {
+    let x = x - x.floor();
+    if x >= 1.0 {
+        0.0
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_radical_inverse() {
+        assert_eq!(radical_inverse(2, 1), 0.5);
+        assert_eq!(radical_inverse(2, 6), 0.375);
+        assert!((radical_inverse(3, 5) - 7.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stratification() {
+        // Each block of 16 samples of a (0, 2)-sequence has one sample per 4x4 stratum, and
+        // the scrambling preserves it
+        let mut rng = SampleRng::new(0);
+        let mut strata = [false; 16];
+        for i in 16..32 {
+            let (u, v) = Sampler::Sobol.pixel_sample(3, 7, i, &mut rng);
+            let stratum = (4.0 * u) as usize * 4 + (4.0 * v) as usize;
+            assert!(!strata[stratum], "two samples in stratum {}", stratum);
+            strata[stratum] = true;
+        }
+    }
+
+    #[test]
+    fn test_blue_noise_mask() {
+        let size = 16;
+        let mask = void_and_cluster(size, 1.9, 0);
+        // Every value once
+        let mut ranks: Vec<usize> = mask.iter().map(|v| (v * 256.0).round() as usize).collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..256).collect::<Vec<_>>());
+
+        // Neighbours differ more than with white noise, where the mean difference is 1/3
+        let mut difference = 0.0;
+        for y in 0..size {
+            for x in 0..size {
+                let right = y * size + (x + 1) % size;
+                difference += (mask[y * size + x] - mask[right]).abs();
+            }
+        }
+        difference /= (size * size) as f64;
+        assert!(difference > 0.36, "mean difference = {}", difference);
+    }
+
+    #[test]
+    fn test_samples_in_unit_square() {
+        let mut rng = SampleRng::new(0);
+        for &sampler in &[
+            Sampler::Random,
+            Sampler::Halton,
+            Sampler::Sobol,
+            Sampler::BlueNoise,
+        ] {
+            for i in 0..1000 {
+                let (u, v) = sampler.pixel_sample(12, 34, i, &mut rng);
+                assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+            }
+            assert_eq!(sampler.to_string().parse::<Sampler>().unwrap(), sampler);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::accelerator::Accelerator;
+use crate::background::Background;
+use crate::camera::Camera;
+use crate::distributed::Tile;
+use crate::float::Float;
+use crate::framebuffer::TileSamples;
+use crate::guiding::SdTree;
+use crate::irradiance_cache::IrradianceCache;
+use crate::light::Light;
+use crate::light_bvh::LightBvh;
+use crate::material::{MaterialId, MaterialList};
+use crate::object::{HitRecord, ObjectId};
+use crate::photon_map::PhotonMap;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::settings::RenderSettings;
+use crate::vec3::{Color, Point3, Vec3};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Everything needed to render an image: the objects, the ones to sample as lights, the
+/// lights without an area, the materials they share, what's seen behind them and the point of
+/// view.
+pub struct Scene {
+    pub world: Box<dyn Accelerator>,
+    pub lights: LightBvh,
+    pub delta_lights: Vec<Light>,
+    pub materials: MaterialList,
+    pub background: Box<dyn Background>,
+    pub camera: Camera,
+    /// Photons focused by specular surfaces, when estimated ahead of rendering.
+    pub caustics: Option<PhotonMap>,
+    /// Light arriving at the points of the scene, learnt by the passes of the render before.
+    pub guide: Option<Arc<SdTree>>,
+    /// Indirect light of the diffuse surfaces, when interpolated from points computed ahead of
+    /// rendering.
+    pub irradiance: Option<IrradianceCache>,
+}
+
+/// Closest surface along a ray cast into the scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Intersection {
+    /// Top-level object hit, numbered in the order the objects were given to the accelerator.
+    pub object: ObjectId,
+    pub point: Point3,
+    /// Unit normal of the surface, facing the origin of the ray.
+    pub normal: Vec3,
+    pub material: MaterialId,
+    /// Distance to the point, in lengths of the direction of the ray.
+    pub t: Float,
+    /// Whether the ray hit the outside of the surface.
+    pub front_face: bool,
+}
+
+impl Scene {
+    /// Closest intersection of `ray` with the objects of the scene, for picking objects or
+    /// measuring the scene outside of rendering.
+    pub fn raycast(&self, ray: &Ray) -> Option<Intersection> {
+        let mut hit_record = HitRecord::empty();
+        if !self.world.hit(ray, 0.0, Float::MAX, &mut hit_record) {
+            return None;
+        }
+        Some(Intersection {
+            object: hit_record.object,
+            point: hit_record.point,
+            normal: hit_record.normal,
+            material: hit_record.material,
+            t: hit_record.t,
+            front_face: hit_record.front_face,
+        })
+    }
+
+    /// Camera ray of the `sample_index`-th sample of the pixel (col, row), row 0 being the
+    /// bottom of the image, along with the offset of the sample from the top left corner of
+    /// the pixel. None where the camera sees nothing, the sample then being black.
+    pub fn camera_ray(
+        &self,
+        settings: &RenderSettings,
+        col: u16,
+        row: u16,
+        sample_index: u32,
+        rng: &mut SampleRng,
+    ) -> (Option<Ray>, Float, Float) {
+        let (dx, dy) = settings.sampler.pixel_sample(col, row, sample_index, rng);
+        let u = (col as Float + dx) / (settings.image_width - 1) as Float;
+        let v = (row as Float + 1.0 - dy) / (settings.image_height - 1) as Float;
+        (self.camera.get_ray(u, v, rng), dx, dy)
+    }
+
+    pub fn ray_color(&self, settings: &RenderSettings, ray: &Ray, rng: &mut SampleRng) -> Color {
+        settings.integrator.ray_color(
+            rng,
+            ray,
+            &*self.world,
+            &self.lights,
+            &self.delta_lights,
+            &self.materials,
+            &*self.background,
+            settings.fog.as_ref(),
+            self.caustics.as_ref(),
+            self.guide.as_deref(),
+            self.irradiance.as_ref(),
+            settings.transparent_shadows,
+            settings.bounce_limit,
+        )
+    }
+
+    /// Filtered samples of the pixels of `tile`, splatted to the pixels around it as well. They
+    /// only depend on the tile and the seed of the render.
+    pub fn render_tile(&self, settings: &RenderSettings, tile: Tile) -> TileSamples {
+        let first_sample = settings.first_sample;
+        self.render_samples(
+            settings,
+            tile,
+            first_sample..first_sample + settings.samples_per_pixel as u32,
+        )
+    }
+
+    /// Filtered `samples` of the pixels of `tile`, as with `render_tile`, for rendering the
+    /// samples of the pixels in several passes.
+    pub fn render_samples(
+        &self,
+        settings: &RenderSettings,
+        tile: Tile,
+        samples: Range<u32>,
+    ) -> TileSamples {
+        let (width, height) = (settings.image_width, settings.image_height);
+        let mut tile_samples = TileSamples::new(tile, &settings.filter, width, height);
+        for y in tile.y..tile.y + tile.height {
+            let row = settings.image_height - 1 - y;
+            for col in tile.x..tile.x + tile.width {
+                let index = y as usize * settings.image_width as usize + col as usize;
+                for s in samples.clone() {
+                    let mut rng = SampleRng::for_sample(settings.seed, index, s);
+                    let (ray, dx, dy) = self.camera_ray(settings, col, row, s, &mut rng);
+                    let color = match ray {
+                        Some(ray) => self.ray_color(settings, &ray, &mut rng),
+                        None => Color::zero(),
+                    };
+                    let x = col as Float + dx;
+                    let y = y as Float + dy;
+                    tile_samples.splat(x, y, color, &settings.filter);
+                }
+            }
+        }
+        tile_samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::framebuffer::Framebuffer;
+    use crate::scenes::BuiltinScene;
+
+    #[test]
+    fn test_raycast() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            ..RenderSettings::default()
+        };
+        let scene = settings.scene.build(&settings);
+
+        // Down onto the floor from the middle of the room
+        let ray = Ray::new(Point3::new(50.0, 278.0, 500.0), Vec3::new(0.0, -2.0, 0.0));
+        let floor = scene.raycast(&ray).unwrap();
+        assert!((floor.point - Point3::new(50.0, 0.0, 500.0)).length() < 1e-3);
+        assert!((floor.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+        assert!((floor.t - 139.0).abs() < 1e-3);
+
+        // Onto one of the blocks, another object in front of the back wall
+        let ray = Ray::new(Point3::new(278.0, 50.0, -800.0), Vec3::new(0.1, 0.0, 1.0));
+        let block = scene.raycast(&ray).unwrap();
+        assert!(block.point.z() < 500.0);
+        assert_ne!(block.object, floor.object);
+        assert_eq!(block.material, floor.material);
+
+        // Away from the open side of the box
+        let ray = Ray::new(Point3::new(278.0, 278.0, -800.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(scene.raycast(&ray).is_none());
+    }
+
+    #[test]
+    fn test_passes_add_up_to_tile() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 16,
+            image_height: 8,
+            samples_per_pixel: 4,
+            filter: Filter::Gaussian,
+            ..RenderSettings::default()
+        };
+        let scene = settings.scene.build(&settings);
+        let tile = Tile {
+            x: 4,
+            y: 2,
+            width: 8,
+            height: 4,
+        };
+
+        let mut framebuffer = Framebuffer::new(16, 8);
+        for pass in 0..settings.samples_per_pixel as u32 {
+            scene
+                .render_samples(&settings, tile, pass..pass + 1)
+                .merge_into(&mut framebuffer);
+        }
+        let mut expected = Framebuffer::new(16, 8);
+        let samples = scene.render_tile(&settings, tile);
+        samples.merge_into(&mut expected);
+        // Splatted to the pixels of the tile and one pixel around it
+        assert_eq!(samples.region.width, 10);
+        for index in 0..framebuffer.len() {
+            assert!((framebuffer.pixel(index) - expected.pixel(index)).length() < 1e-4);
+            assert_eq!(
+                framebuffer.weight(index) > 0.0,
+                expected.weight(index) > 0.0
+            );
+        }
+    }
+}
@@ -0,0 +1,727 @@
+use crate::animation::{CameraPath, Keyframe, Track};
+use crate::assets::{Assets, MemoryUsage};
+use crate::background::{Background, EquirectangularHdr, Gradient, SolidColor, SunSky};
+use crate::camera::Camera;
+use crate::cutout::Cutout;
+use crate::float::Float;
+use crate::instance::Instance;
+use crate::material::{
+    BackFaces, Coated, Conductor, Dielectric, DiffuseLight, Lambertian, Material, MaterialId,
+    MaterialList, Metal, Volume,
+};
+use crate::mesh::Mesh;
+use crate::nanovdb::NanoVdbGrid;
+use crate::object::{Hittable, HittableList};
+use crate::rect::Rect;
+use crate::scene::Scene;
+use crate::scenes;
+use crate::settings::RenderSettings;
+use crate::sphere::Sphere;
+use crate::texture::Texture;
+use crate::transform::Transform;
+use crate::vec3::{Color, Point3, Vec3};
+use crate::volume::{Density, Pyroclastic};
+use crate::voxel::VoxelGrid;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::SplitWhitespace;
+use std::sync::Arc;
+
+/// Largest displacement of the surface of clouds, relative to their radius.
+const CLOUD_BILLOWS: Float = 1.0;
+
+/// Scene described in a text file, one statement per line, `#` starting comments:
+///
+/// ```text
+/// camera <look from: x y z> <look at: x y z> <vertical fov in degrees>
+/// keyframe <time in seconds> <look from: x y z> <look at: x y z> <vertical fov in degrees>
+/// material <name> lambertian <albedo: r g b>
+/// material <name> metal <albedo: r g b> <fuzz>
+/// material <name> conductor <gold, silver, copper or aluminum> <roughness>
+/// material <name> conductor <refraction index: r g b> <extinction coefficient: r g b> <roughness>
+/// material <name> dielectric <refraction index>
+/// material <name> coated <base material> <refraction index> <roughness> <tint: r g b>
+/// material <name> light <emitted: r g b>
+/// sphere <material> <center: x y z> <radius>
+/// quad <material> <corner: x y z> <side u: x y z> <side v: x y z> [cutout <opacity map>]
+/// mesh <material> <path of an OBJ, STL or PLY file> [cutout <opacity map>]
+/// voxels <path of a MagicaVoxel .vox file> <corner: x y z> <voxel size>
+/// volume <path of a NanoVDB .nvdb file> <density scale> <albedo: r g b>
+/// cloud <center: x y z> <radius> <density> <albedo: r g b>
+/// background color <r g b>
+/// background gradient <looking down: r g b> <looking up: r g b>
+/// background hdri <path of a Radiance .hdr file> <intensity>
+/// background sky <sun elevation in degrees> <sun azimuth in degrees> <turbidity>
+/// ```
+///
+/// Materials are declared before the objects using them. A material may end with how the back
+/// faces of its objects are rendered: `shaded` by default, `black` or `culled`. Quads and meshes
+/// may have holes cut out where the alpha channel of an image is below one half. Voxels are made
+/// of lambertian materials of the colors of their palette. Volumes fill the box of their grid
+/// with smoke of its density, clouds are balls of smoke with billowing surfaces, each shaped
+/// differently. Spheres and quads made of light are also sampled as lights.
+/// Without a background, rays escaping the scene see a blue sky gradient. Keyframes make the
+/// path of the camera in animations, in any order. Paths are relative to the scene file.
+///
+/// The file is read once, then each thread builds its own copy of the scene from it. Files
+/// referenced several times are loaded once, meshes then being instances of a single copy.
+#[derive(Clone)]
+pub struct SceneFile {
+    materials: MaterialList,
+    objects: Vec<(FileObject, MaterialId)>,
+    background: Option<FileBackground>,
+    look_from: Point3,
+    look_at: Point3,
+    vertical_fov_deg: Float,
+    camera_path: Option<CameraPath>,
+    memory_usage: MemoryUsage,
+}
+
+#[derive(Clone)]
+enum FileBackground {
+    Color(SolidColor),
+    Gradient(Gradient),
+    Hdri(EquirectangularHdr),
+    Sky(Box<SunSky>),
+}
+
+#[derive(Clone)]
+enum FileObject {
+    Sphere(Sphere),
+    Quad(Rect),
+    Mesh(Arc<Mesh>),
+    Voxels(VoxelGrid),
+    Cutout(Box<FileObject>, Texture),
+}
+
+impl SceneFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SceneFile> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene file {}", path.display()))?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        SceneFile::parse(&text, directory)
+            .with_context(|| format!("Failed to load scene file {}", path.display()))
+    }
+
+    /// Reads the statements of `text`, the paths of the files it references being relative to
+    /// `directory`.
+    pub fn parse(text: &str, directory: &Path) -> Result<SceneFile> {
+        let mut assets = Assets::new();
+        let mut materials = MaterialList::new();
+        let mut material_ids: HashMap<&str, MaterialId> = HashMap::new();
+        let mut objects = Vec::new();
+        let mut camera = None;
+        let mut keyframes = Vec::new();
+        let mut background = None;
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut tokens = line.split_whitespace();
+            let keyword = match tokens.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let statement = || format!("Invalid {} on line {}", keyword, line_number + 1);
+
+            match keyword {
+                "camera" => {
+                    let [x0, y0, z0, x1, y1, z1, fov] =
+                        parse_floats(&mut tokens).with_context(statement)?;
+                    camera = Some((Point3::new(x0, y0, z0), Point3::new(x1, y1, z1), fov));
+                }
+                "keyframe" => {
+                    let [time, x0, y0, z0, x1, y1, z1, fov] =
+                        parse_floats(&mut tokens).with_context(statement)?;
+                    if !time.is_finite() {
+                        bail!("{}: Keyframe time must be finite", statement());
+                    }
+                    keyframes.push((time, Point3::new(x0, y0, z0), Point3::new(x1, y1, z1), fov));
+                }
+                "material" => {
+                    let name = tokens.next().with_context(statement)?;
+                    let kind = tokens.next().unwrap_or("");
+                    let parsed = parse_material(kind, &mut tokens, &materials, &material_ids)
+                        .with_context(statement)?;
+                    let back_faces = match tokens.next() {
+                        Some(token) => token.parse().with_context(statement)?,
+                        None => BackFaces::Shaded,
+                    };
+                    let id = materials.add_with_back_faces(parsed, back_faces);
+                    if material_ids.insert(name, id).is_some() {
+                        bail!("Material '{}' redefined on line {}", name, line_number + 1);
+                    }
+                }
+                "sphere" => {
+                    let material =
+                        parse_material_id(&material_ids, &mut tokens).with_context(statement)?;
+                    let [x, y, z, radius] = parse_floats(&mut tokens).with_context(statement)?;
+                    let center = Point3::new(x, y, z);
+                    let sphere = Sphere::new(center, radius, material);
+                    objects.push((FileObject::Sphere(sphere), material));
+                }
+                "quad" => {
+                    let material =
+                        parse_material_id(&material_ids, &mut tokens).with_context(statement)?;
+                    let [x, y, z, ux, uy, uz, vx, vy, vz] =
+                        parse_floats(&mut tokens).with_context(statement)?;
+                    let quad = Rect::new(
+                        Point3::new(x, y, z),
+                        Vec3::new(ux, uy, uz),
+                        Vec3::new(vx, vy, vz),
+                        material,
+                    );
+                    let quad =
+                        parse_cutout(FileObject::Quad(quad), &mut tokens, directory, &mut assets)
+                            .with_context(statement)?;
+                    objects.push((quad, material));
+                }
+                "mesh" => {
+                    let material =
+                        parse_material_id(&material_ids, &mut tokens).with_context(statement)?;
+                    let path = tokens.next().with_context(statement)?;
+                    let mesh = assets
+                        .mesh(directory.join(path), material)
+                        .with_context(statement)?;
+                    let mesh =
+                        parse_cutout(FileObject::Mesh(mesh), &mut tokens, directory, &mut assets)
+                            .with_context(statement)?;
+                    objects.push((mesh, material));
+                }
+                "voxels" => {
+                    let path = tokens.next().with_context(statement)?;
+                    let [x, y, z, voxel_size] =
+                        parse_floats(&mut tokens).with_context(statement)?;
+                    if voxel_size <= 0.0 {
+                        bail!("{}: Voxel size must be positive", statement());
+                    }
+                    let voxels = VoxelGrid::load(directory.join(path), &mut materials)
+                        .with_context(statement)?
+                        .with_corner(Point3::new(x, y, z))
+                        .with_voxel_size(voxel_size);
+                    // Their materials come from their palette
+                    objects.push((FileObject::Voxels(voxels), MaterialId::default()));
+                }
+                "volume" => {
+                    let path = tokens.next().with_context(statement)?;
+                    let [scale, r, g, b] = parse_floats(&mut tokens).with_context(statement)?;
+                    let grid = NanoVdbGrid::load(directory.join(path)).with_context(statement)?;
+                    let density = Density::Grid(Arc::new(grid));
+                    let albedo = Color::new(r, g, b);
+                    objects.push(volume_object(density, scale, albedo, &mut materials));
+                }
+                "cloud" => {
+                    let [x, y, z, radius, scale, r, g, b] =
+                        parse_floats(&mut tokens).with_context(statement)?;
+                    // Shaped by its position in the file
+                    let seed = objects.len() as u64;
+                    let cloud = Pyroclastic::new(Point3::new(x, y, z), radius, CLOUD_BILLOWS, seed);
+                    let density = Density::Pyroclastic(cloud);
+                    let albedo = Color::new(r, g, b);
+                    objects.push(volume_object(density, scale, albedo, &mut materials));
+                }
+                "background" => {
+                    let kind = tokens.next().unwrap_or("");
+                    let parsed =
+                        parse_background(kind, &mut tokens, directory).with_context(statement)?;
+                    background = Some(parsed);
+                }
+                _ => bail!(
+                    "Unknown statement '{}' on line {}",
+                    keyword,
+                    line_number + 1
+                ),
+            }
+            if tokens.next().is_some() {
+                bail!(
+                    "Unexpected values after {} on line {}",
+                    keyword,
+                    line_number + 1
+                );
+            }
+        }
+
+        let (look_from, look_at, vertical_fov_deg) = camera.context("Missing camera")?;
+        let camera_path = (!keyframes.is_empty()).then(|| CameraPath {
+            look_from: Track::new(
+                keyframes
+                    .iter()
+                    .map(|&(time, look_from, _, _)| Keyframe {
+                        time,
+                        value: look_from,
+                    })
+                    .collect(),
+            ),
+            look_at: Track::new(
+                keyframes
+                    .iter()
+                    .map(|&(time, _, look_at, _)| Keyframe {
+                        time,
+                        value: look_at,
+                    })
+                    .collect(),
+            ),
+            vertical_fov_deg: Track::new(
+                keyframes
+                    .iter()
+                    .map(|&(time, _, _, fov)| Keyframe { time, value: fov })
+                    .collect(),
+            ),
+            v_up: Vec3::new(0.0, 1.0, 0.0),
+            aperture: 0.0,
+        });
+        Ok(SceneFile {
+            materials,
+            objects,
+            background,
+            look_from,
+            look_at,
+            vertical_fov_deg,
+            camera_path,
+            memory_usage: assets.memory_usage(),
+        })
+    }
+
+    /// Path of the camera through the keyframes of the file, None without any.
+    pub fn camera_path(&self) -> Option<CameraPath> {
+        self.camera_path.clone()
+    }
+
+    /// Memory taken by the textures and meshes the file references, each loaded once.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory_usage
+    }
+
+    pub fn build(&self, settings: &RenderSettings) -> Scene {
+        let mut world = HittableList::new();
+        let mut lights = HittableList::new();
+        let mut prototypes = HashMap::new();
+        for (object, material) in &self.objects {
+            world.add(object.to_hittable(&mut prototypes));
+            if object.can_be_sampled()
+                && matches!(self.materials[*material], Material::DiffuseLight(_))
+            {
+                lights.add(object.to_hittable(&mut prototypes));
+            }
+        }
+
+        let camera = Camera::new(
+            self.look_from,
+            self.look_at,
+            Vec3::new(0.0, 1.0, 0.0),
+            self.vertical_fov_deg,
+            settings.aspect_ratio(),
+            0.0,
+            1.0,
+        );
+        scenes::scene(
+            settings,
+            world,
+            lights,
+            self.materials.clone(),
+            match &self.background {
+                Some(background) => background.to_background(),
+                None => scenes::sky(),
+            },
+            camera,
+        )
+    }
+}
+
+impl FileObject {
+    /// Copy of the object for a scene. Meshes placed several times are instances of the copy
+    /// of `prototypes`, made the first time.
+    fn to_hittable(&self, prototypes: &mut HashMap<*const Mesh, Rc<Mesh>>) -> Box<dyn Hittable> {
+        match *self {
+            FileObject::Sphere(ref sphere) => Box::new(sphere.clone()),
+            FileObject::Quad(ref quad) => Box::new(quad.clone()),
+            FileObject::Mesh(ref mesh) if Arc::strong_count(mesh) == 1 => {
+                Box::new(Mesh::clone(mesh))
+            }
+            FileObject::Mesh(ref mesh) => {
+                let prototype = prototypes
+                    .entry(Arc::as_ptr(mesh))
+                    .or_insert_with(|| Rc::new(Mesh::clone(mesh)));
+                Box::new(Instance::new(prototype.clone(), Transform::identity()))
+            }
+            FileObject::Voxels(ref voxels) => Box::new(voxels.clone()),
+            FileObject::Cutout(ref object, ref opacity) => {
+                Box::new(Cutout::new(object.to_hittable(prototypes), opacity.clone()))
+            }
+        }
+    }
+
+    /// Whether light can be sampled over the object, which meshes and voxels can't.
+    fn can_be_sampled(&self) -> bool {
+        match *self {
+            FileObject::Sphere(_) | FileObject::Quad(_) => true,
+            FileObject::Mesh(_) | FileObject::Voxels(_) => false,
+            FileObject::Cutout(ref object, _) => object.can_be_sampled(),
+        }
+    }
+}
+
+impl FileBackground {
+    fn to_background(&self) -> Box<dyn Background> {
+        match *self {
+            FileBackground::Color(ref color) => Box::new(color.clone()),
+            FileBackground::Gradient(ref gradient) => Box::new(gradient.clone()),
+            FileBackground::Hdri(ref hdri) => Box::new(hdri.clone()),
+            FileBackground::Sky(ref sky) => Box::new(SunSky::clone(sky)),
+        }
+    }
+}
+
+/// Box filled with a volume of `density`, around the part of space where it varies.
+fn volume_object(
+    density: Density,
+    scale: Float,
+    albedo: Color,
+    materials: &mut MaterialList,
+) -> (FileObject, MaterialId) {
+    let bounds = density.bounding_box();
+    let material = materials.add(Material::Volume(Volume::new(density, scale, albedo)));
+    let boundary = scenes::cuboid(bounds.min(), bounds.max(), material);
+    (FileObject::Mesh(Arc::new(boundary)), material)
+}
+
+fn parse_material_id(
+    material_ids: &HashMap<&str, MaterialId>,
+    tokens: &mut SplitWhitespace,
+) -> Result<MaterialId> {
+    let name = tokens.next().context("Missing material")?;
+    material_ids
+        .get(name)
+        .copied()
+        .with_context(|| format!("Unknown material '{}'", name))
+}
+
+/// `object`, with the holes of the optional `cutout` suffix.
+fn parse_cutout(
+    object: FileObject,
+    tokens: &mut SplitWhitespace,
+    directory: &Path,
+    assets: &mut Assets,
+) -> Result<FileObject> {
+    match tokens.next() {
+        Some("cutout") => {
+            let path = tokens.next().context("Missing opacity map")?;
+            let opacity = assets.opacity_map(directory.join(path))?;
+            Ok(FileObject::Cutout(
+                Box::new(object),
+                Texture::Image(opacity),
+            ))
+        }
+        Some(token) => bail!("Unexpected '{}', expected cutout", token),
+        None => Ok(object),
+    }
+}
+
+fn parse_material(
+    kind: &str,
+    tokens: &mut SplitWhitespace,
+    materials: &MaterialList,
+    material_ids: &HashMap<&str, MaterialId>,
+) -> Result<Material> {
+    match kind {
+        "lambertian" => {
+            let [r, g, b] = parse_floats(tokens)?;
+            Ok(Material::Lambertian(Lambertian::new(Color::new(r, g, b))))
+        }
+        "metal" => {
+            let [r, g, b, fuzz] = parse_floats(tokens)?;
+            Ok(Material::Metal(Metal::new(Color::new(r, g, b), fuzz)))
+        }
+        "conductor" => {
+            let first = tokens.clone().next().context("Missing metal")?;
+            let conductor = if first.parse::<Float>().is_ok() {
+                let [eta_r, eta_g, eta_b, k_r, k_g, k_b] = parse_floats(tokens)?;
+                Conductor::new(Color::new(eta_r, eta_g, eta_b), Color::new(k_r, k_g, k_b))
+            } else {
+                tokens.next();
+                Conductor::from_preset(first.parse()?)
+            };
+            let [roughness] = parse_floats(tokens)?;
+            Ok(Material::Conductor(conductor.with_roughness(roughness)))
+        }
+        "dielectric" => {
+            let [refraction_index] = parse_floats(tokens)?;
+            Ok(Material::Dielectric(Dielectric::new(refraction_index)))
+        }
+        "coated" => {
+            let base = materials[parse_material_id(material_ids, tokens)?].clone();
+            let [refraction_index, roughness, r, g, b] = parse_floats(tokens)?;
+            let coated = Coated::new(base, refraction_index, roughness);
+            Ok(Material::Coated(coated.with_tint(Color::new(r, g, b))))
+        }
+        "light" => {
+            let [r, g, b] = parse_floats(tokens)?;
+            Ok(Material::DiffuseLight(DiffuseLight::new(Color::new(
+                r, g, b,
+            ))))
+        }
+        _ => bail!(
+            "Unknown material type '{}', expected one of: lambertian, metal, conductor, dielectric, coated, light",
+            kind
+        ),
+    }
+}
+
+fn parse_background(
+    kind: &str,
+    tokens: &mut SplitWhitespace,
+    directory: &Path,
+) -> Result<FileBackground> {
+    match kind {
+        "color" => {
+            let [r, g, b] = parse_floats(tokens)?;
+            Ok(FileBackground::Color(SolidColor::new(Color::new(r, g, b))))
+        }
+        "gradient" => {
+            let [r0, g0, b0, r1, g1, b1] = parse_floats(tokens)?;
+            Ok(FileBackground::Gradient(Gradient::new(
+                Color::new(r0, g0, b0),
+                Color::new(r1, g1, b1),
+            )))
+        }
+        "hdri" => {
+            let path = tokens.next().context("Missing path")?;
+            let [intensity] = parse_floats(tokens)?;
+            let hdri = EquirectangularHdr::load(directory.join(path), intensity)?;
+            Ok(FileBackground::Hdri(hdri))
+        }
+        "sky" => {
+            let [elevation_deg, azimuth_deg, turbidity] = parse_floats(tokens)?;
+            Ok(FileBackground::Sky(Box::new(SunSky::new(
+                elevation_deg,
+                azimuth_deg,
+                turbidity,
+            ))))
+        }
+        _ => bail!(
+            "Unknown background type '{}', expected one of: color, gradient, hdri, sky",
+            kind
+        ),
+    }
+}
+
+fn parse_floats<const N: usize>(tokens: &mut SplitWhitespace) -> Result<[Float; N]> {
+    let mut values = [0.0; N];
+    for value in values.iter_mut() {
+        let token = tokens.next().context("Missing value")?;
+        *value = token
+            .parse()
+            .ok()
+            .with_context(|| format!("Invalid number '{}'", token))?;
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    const SCENE: &str = "
+        # Lit sphere on a quad
+        camera 0 1 5  0 0 0  40
+        material white lambertian 0.7 0.7 0.7
+        material glow light 4 4 4
+        material gold conductor gold 0.3
+        material custom conductor 0.2 0.9 1.1  3.9 2.5 2.1  0
+        material varnished coated white 1.5 0.1  0.9 0.8 0.6
+        material one-sided lambertian 0.5 0.5 0.5 culled
+        quad white -5 0 -5  10 0 0  0 0 10
+        sphere glow 0 1 0 0.5  # above the quad
+        quad one-sided -5 3 -5  10 0 0  0 0 10  # facing down, above the sphere
+    ";
+
+    #[test]
+    fn test_parse() {
+        let scene_file = SceneFile::parse(SCENE, Path::new("")).unwrap();
+        let scene = scene_file.build(&RenderSettings::default());
+        assert_eq!(scene.world.len(), 3);
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.materials.len(), 6);
+        assert!(matches!(
+            scene.materials.iter().nth(2),
+            Some(Material::Conductor(_))
+        ));
+        assert!(matches!(
+            scene.materials.iter().nth(4),
+            Some(Material::Coated(_))
+        ));
+
+        // Through the back of the culled quad, onto the sphere
+        let ray = Ray::new(Point3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = scene.raycast(&ray).unwrap();
+        assert!((hit.point - Point3::new(0.0, 1.5, 0.0)).length() < 1e-4);
+        let ray = Ray::new(Point3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!(scene.raycast(&ray).is_some());
+    }
+
+    #[test]
+    fn test_clouds() {
+        let text = "
+            camera 0 0 5  0 0 0  40
+            cloud 0 0 0  1  2  1 1 1
+            cloud 3 0 0  1  2  1 1 1
+        ";
+        let scene_file = SceneFile::parse(text, Path::new("")).unwrap();
+        let scene = scene_file.build(&RenderSettings::default());
+        assert_eq!(scene.world.len(), 2);
+        assert!(scene.lights.is_empty());
+        assert!(matches!(
+            scene.materials.iter().next(),
+            Some(Material::Volume(_))
+        ));
+
+        // Hitting the box around the billows
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.raycast(&ray).unwrap();
+        assert!((hit.point - Point3::new(0.0, 0.0, 2.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error =
+            |text: &str| format!("{:#}", SceneFile::parse(text, Path::new("")).err().unwrap());
+        assert_eq!(error("material m lambertian 1 1 1"), "Missing camera");
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nsphere m 0 0 0 1"),
+            "Invalid sphere on line 2: Unknown material 'm'"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\n\nmaterial m metal 1 1 x 0"),
+            "Invalid material on line 3: Invalid number 'x'"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40 1"),
+            "Unexpected values after camera on line 1"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nmaterial m conductor brass 0.2"),
+            "Invalid material on line 2: Unknown metal 'brass', expected one of: gold, silver, \
+             copper, aluminum"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nmaterial m lambertian 1 1 1 hidden"),
+            "Invalid material on line 2: Unknown back faces 'hidden', expected one of: shaded, \
+             black, culled"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nmaterial m coated paint 1.5 0 1 1 1"),
+            "Invalid material on line 2: Unknown material 'paint'"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nquad m 0 0 0 1 0 0 0 1 0 cutout"),
+            "Invalid quad on line 2: Unknown material 'm'"
+        );
+        assert_eq!(
+            error("material m lambertian 1 1 1\nquad m 0 0 0 1 0 0 0 1 0 cutout"),
+            "Invalid quad on line 2: Missing opacity map"
+        );
+        assert_eq!(
+            error("material m lambertian 1 1 1\nquad m 0 0 0 1 0 0 0 1 0 holes"),
+            "Invalid quad on line 2: Unexpected 'holes', expected cutout"
+        );
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nvoxels castle.vox 0 0 0 0"),
+            "Invalid voxels on line 2: Voxel size must be positive"
+        );
+        assert!(error("camera 0 0 0 0 0 -1 40\nvoxels castle.vox 0 0 0 1")
+            .starts_with("Invalid voxels on line 2: Failed to open voxel model castle.vox"));
+        assert!(error("camera 0 0 0 0 0 -1 40\nvolume smoke.nvdb 1 1 1 1")
+            .starts_with("Invalid volume on line 2: Failed to open grid smoke.nvdb"));
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nkeyframe NaN 0 0 0 0 0 -1 40"),
+            "Invalid keyframe on line 2: Keyframe time must be finite"
+        );
+        assert_eq!(error("cube"), "Unknown statement 'cube' on line 1");
+        assert_eq!(
+            error("camera 0 0 0 0 0 -1 40\nbackground stars"),
+            "Invalid background on line 2: Unknown background type 'stars', expected one of: \
+             color, gradient, hdri, sky"
+        );
+    }
+
+    #[test]
+    fn test_keyframes() {
+        let still = SceneFile::parse("camera 0 0 5  0 0 0  40", Path::new("")).unwrap();
+        assert!(still.camera_path().is_none());
+
+        let text = "
+            camera 0 0 5  0 0 0  40
+            keyframe 2  4 0 5  0 0 0  60
+            keyframe 0  0 0 5  0 0 0  40
+        ";
+        let path = SceneFile::parse(text, Path::new(""))
+            .unwrap()
+            .camera_path()
+            .unwrap();
+        assert_eq!(path.duration(), 2.0);
+        assert_eq!(path.look_from.sample(1.0), Point3::new(2.0, 0.0, 5.0));
+        assert_eq!(path.vertical_fov_deg.sample(1.0), 50.0);
+    }
+
+    #[test]
+    fn test_background() {
+        let settings = RenderSettings::default();
+        let background_color = |text: &str, direction: Vec3| {
+            let scene_file = SceneFile::parse(text, Path::new("")).unwrap();
+            scene_file.build(&settings).background.color(direction)
+        };
+        let up = Vec3::new(0.0, 1.0, 0.0);
+
+        let night = "camera 0 0 0 0 0 -1 40\nbackground color 0 0 0.01";
+        assert_eq!(background_color(night, up), Color::new(0.0, 0.0, 0.01));
+        let studio = "camera 0 0 0 0 0 -1 40\nbackground gradient 0 0 0 0.2 0.2 0.2";
+        assert_eq!(background_color(studio, up), Color::new(0.2, 0.2, 0.2));
+        assert_eq!(background_color(studio, -up), Color::zero());
+
+        // The sun is far brighter than the sky around it
+        let sky = "camera 0 0 0 0 0 -1 40\nbackground sky 45 90 3";
+        let sun = Vec3::new(0.0, 1.0, 1.0);
+        assert!(background_color(sky, sun).length() > 100.0 * background_color(sky, up).length());
+    }
+
+    #[test]
+    fn test_shared_assets() {
+        let dir = std::env::temp_dir();
+        let opacity_path = dir.join("rust-ray-tracing-test-opacity.png");
+        image::GrayImage::new(4, 2).save(&opacity_path).unwrap();
+        let mesh_path = dir.join("rust-ray-tracing-test-scene-mesh.obj");
+        fs::write(&mesh_path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let text = format!(
+            "camera 0 0 5  0 0 0  40
+            material white lambertian 0.7 0.7 0.7
+            material red lambertian 0.7 0.1 0.1
+            quad white 0 0 0  1 0 0  0 1 0  cutout {0}
+            quad white 0 0 1  1 0 0  0 1 0  cutout {0}
+            mesh white {1}
+            mesh white {1}
+            mesh red {1}",
+            opacity_path.display(),
+            mesh_path.display()
+        );
+        let scene_file = SceneFile::parse(&text, Path::new("")).unwrap();
+        fs::remove_file(&opacity_path).unwrap();
+        fs::remove_file(&mesh_path).unwrap();
+
+        let usage = scene_file.memory_usage();
+        assert_eq!(usage.texture_count, 1);
+        assert_eq!(usage.texture_bytes, 4 * 2 * std::mem::size_of::<Color>());
+        // The same file with another material is another mesh
+        assert_eq!(usage.mesh_count, 2);
+
+        let scene = scene_file.build(&RenderSettings::default());
+        assert_eq!(scene.world.len(), 5);
+        // Through the transparent quads, onto the instanced mesh
+        let ray = Ray::new(Point3::new(0.25, 0.25, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.raycast(&ray).unwrap();
+        assert!((hit.point - Point3::new(0.25, 0.25, 0.0)).length() < 1e-4);
+    }
+}
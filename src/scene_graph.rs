@@ -0,0 +1,188 @@
+use crate::instance::Instance;
+use crate::object::Hittable;
+use crate::transform::Transform;
+use std::rc::Rc;
+
+/// Separator of the names of the nodes in a path, from the root down.
+const PATH_SEPARATOR: char = '/';
+
+/// Named group of objects and child nodes, placed relative to its parent, such as a car made
+/// of a body and four wheels, each wheel made of a tire and a rim.
+///
+/// The graph only exists while setting up the scene: `flatten` bakes the transforms of the
+/// nodes into their objects, which then go into the accelerator of the scene like any other.
+pub struct Node {
+    name: String,
+    /// Transformation from the space of the node to the space of its parent.
+    transform: Transform,
+    objects: Vec<Box<dyn Hittable>>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Node {
+        Node {
+            name: name.to_string(),
+            transform: Transform::identity(),
+            objects: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_transform(mut self, transform: Transform) -> Node {
+        self.transform = transform;
+        self
+    }
+
+    pub fn with_object(mut self, object: Box<dyn Hittable>) -> Node {
+        self.add_object(object);
+        self
+    }
+
+    pub fn with_child(mut self, child: Node) -> Node {
+        self.add_child(child);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    pub fn add_object(&mut self, object: Box<dyn Hittable>) {
+        self.objects.push(object);
+    }
+
+    pub fn add_child(&mut self, child: Node) {
+        self.children.push(child);
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &Node> {
+        self.children.iter()
+    }
+
+    /// Descendant at `path`, the names of the nodes leading to it from this one joined by
+    /// slashes, such as "car/front-left-wheel". The first child matching each name is taken.
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        path.split(PATH_SEPARATOR).try_fold(self, |node, name| {
+            node.children.iter().find(|c| c.name == name)
+        })
+    }
+
+    pub fn find_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split(PATH_SEPARATOR).try_fold(self, |node, name| {
+            node.children.iter_mut().find(|c| c.name == name)
+        })
+    }
+
+    /// Objects of the node and of its descendants, in world space, along with the path of the
+    /// node each one belongs to, from this node included.
+    ///
+    /// Objects of nodes moved from their parent, or whose parents are, are wrapped in
+    /// instances. The others are left as they are, for accelerators to recognize them.
+    pub fn flatten(self) -> Vec<(String, Box<dyn Hittable>)> {
+        let mut objects = Vec::new();
+        self.flatten_into(None, &Transform::identity(), &mut objects);
+        objects
+    }
+
+    fn flatten_into(
+        self,
+        parent_path: Option<&str>,
+        parent_to_world: &Transform,
+        objects: &mut Vec<(String, Box<dyn Hittable>)>,
+    ) {
+        let path = match parent_path {
+            Some(parent_path) => format!("{}{}{}", parent_path, PATH_SEPARATOR, self.name),
+            None => self.name,
+        };
+        let node_to_world = self.transform.then(parent_to_world);
+
+        let moved = node_to_world != Transform::identity();
+        for object in self.objects {
+            let object = if moved {
+                Box::new(Instance::new(Rc::from(object), node_to_world))
+            } else {
+                object
+            };
+            objects.push((path.clone(), object));
+        }
+        for child in self.children {
+            child.flatten_into(Some(&path), &node_to_world, objects);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::Float;
+    use crate::material::MaterialId;
+    use crate::object::HitRecord;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+    use std::any::Any;
+
+    fn sphere(center: Point3) -> Box<dyn Hittable> {
+        Box::new(Sphere::new(center, 0.5, MaterialId::default()))
+    }
+
+    fn car() -> Node {
+        let wheel = |name: &str, x: Float| {
+            Node::new(name)
+                .with_transform(Transform::translation(Vec3::new(x, 0.0, 0.0)))
+                .with_object(sphere(Point3::zero()))
+        };
+        Node::new("car")
+            .with_transform(Transform::translation(Vec3::new(0.0, 0.0, -10.0)))
+            .with_object(sphere(Point3::new(0.0, 1.0, 0.0)))
+            .with_child(wheel("front-wheel", 2.0))
+            .with_child(wheel("rear-wheel", -2.0))
+    }
+
+    #[test]
+    fn test_find() {
+        let mut world = Node::new("world").with_child(car());
+        assert_eq!(world.find("car/rear-wheel").unwrap().name(), "rear-wheel");
+        assert!(world.find("car/spare-wheel").is_none());
+        assert!(world.find("rear-wheel").is_none());
+
+        let lifted = Transform::translation(Vec3::new(0.0, 1.0, 0.0));
+        world.find_mut("car").unwrap().set_transform(lifted);
+        assert_eq!(*world.find("car").unwrap().transform(), lifted);
+    }
+
+    #[test]
+    fn test_flatten_composes_transforms() {
+        let objects = Node::new("world").with_child(car()).flatten();
+        let paths: Vec<_> = objects.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["world/car", "world/car/front-wheel", "world/car/rear-wheel"]
+        );
+
+        // The front wheel is moved by the car, then within it
+        let front_wheel = &objects[1].1;
+        let ray = Ray::new(Point3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::empty();
+        assert!(front_wheel.hit(&ray, 0.001, Float::INFINITY, &mut hit_record));
+        assert!((hit_record.point - Point3::new(2.0, 0.0, -9.5)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_objects_in_place_not_instanced() {
+        let objects = Node::new("world")
+            .with_object(sphere(Point3::zero()))
+            .flatten();
+        let object: &dyn Any = objects[0].1.as_ref();
+        assert!(object.is::<Sphere>());
+    }
+}
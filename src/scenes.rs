@@ -0,0 +1,837 @@
+use crate::animation::{CameraPath, Keyframe, Track};
+use crate::background::{Background, Gradient, SolidColor};
+use crate::bvh::{Bvh, BvhSplit};
+use crate::camera::Camera;
+use crate::float::Float;
+use crate::instance::Instance;
+use crate::irradiance_cache::IrradianceCache;
+use crate::light_bvh::LightBvh;
+use crate::material::{
+    Dielectric, DiffuseLight, Lambertian, Material, MaterialId, MaterialList, Metal, Mix,
+    Subsurface,
+};
+use crate::mesh::Mesh;
+use crate::object::{Hittable, HittableList};
+use crate::photon_map;
+use crate::rect::Rect;
+use crate::rng::SampleRng;
+use crate::scene::Scene;
+use crate::scene_graph::Node;
+use crate::sdf::{Sdf, SdfObject};
+use crate::settings::RenderSettings;
+use crate::sphere::Sphere;
+use crate::sphere_set::SphereSet;
+use crate::texture::{Checker, Ramp, Texture};
+use crate::transform::Transform;
+use crate::vec3::{Color, Point3, Vec3};
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// Random scenes must be the same from one run to the next to resume a render, and the same on
+/// every worker of a distributed render.
+const SCENE_SEED: u64 = 0;
+
+/// Scenes shipped with the renderer, each with the camera it is best seen from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuiltinScene {
+    /// Field of small random spheres around three large ones, the final scene of the first
+    /// book.
+    RandomSpheres,
+    /// Closed room with a red and a green wall, two white blocks, lit by a rectangle on the
+    /// ceiling.
+    CornellBox,
+    /// Diffuse, glass and metal spheres side by side.
+    ThreeSpheres,
+    /// Spheres standing on a checkerboard.
+    CheckeredGround,
+    /// Cornell box whose blocks are made of smoke, one light and one dark.
+    SmokeBox,
+    /// Showcase of most features, after the final scene of the second book.
+    FinalNextWeek,
+    /// Mandelbulb and Menger sponge, colored by the iterations that shaped them.
+    Fractals,
+}
+
+impl BuiltinScene {
+    pub const ALL: [BuiltinScene; 7] = [
+        BuiltinScene::RandomSpheres,
+        BuiltinScene::CornellBox,
+        BuiltinScene::ThreeSpheres,
+        BuiltinScene::CheckeredGround,
+        BuiltinScene::SmokeBox,
+        BuiltinScene::FinalNextWeek,
+        BuiltinScene::Fractals,
+    ];
+
+    pub fn build(&self, settings: &RenderSettings) -> Scene {
+        match *self {
+            BuiltinScene::RandomSpheres => random_spheres(settings),
+            BuiltinScene::CornellBox => cornell_box(settings),
+            BuiltinScene::ThreeSpheres => three_spheres(settings),
+            BuiltinScene::CheckeredGround => checkered_ground(settings),
+            BuiltinScene::SmokeBox => smoke_box(settings),
+            BuiltinScene::FinalNextWeek => final_next_week(settings),
+            BuiltinScene::Fractals => fractals(settings),
+        }
+    }
+
+    /// Keyframed camera of the animations of the scene, None for the scenes only shown still.
+    pub fn camera_path(&self) -> Option<CameraPath> {
+        match *self {
+            BuiltinScene::RandomSpheres => Some(random_spheres_camera_path()),
+            BuiltinScene::CornellBox
+            | BuiltinScene::ThreeSpheres
+            | BuiltinScene::CheckeredGround
+            | BuiltinScene::SmokeBox
+            | BuiltinScene::FinalNextWeek
+            | BuiltinScene::Fractals => None,
+        }
+    }
+}
+
+impl fmt::Display for BuiltinScene {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BuiltinScene::RandomSpheres => "random-spheres",
+            BuiltinScene::CornellBox => "cornell-box",
+            BuiltinScene::ThreeSpheres => "three-spheres",
+            BuiltinScene::CheckeredGround => "checkered-ground",
+            BuiltinScene::SmokeBox => "smoke-box",
+            BuiltinScene::FinalNextWeek => "final-next-week",
+            BuiltinScene::Fractals => "fractals",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for BuiltinScene {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BuiltinScene> {
+        match s {
+            "random-spheres" => Ok(BuiltinScene::RandomSpheres),
+            "cornell-box" => Ok(BuiltinScene::CornellBox),
+            "three-spheres" => Ok(BuiltinScene::ThreeSpheres),
+            "checkered-ground" => Ok(BuiltinScene::CheckeredGround),
+            "smoke-box" => Ok(BuiltinScene::SmokeBox),
+            "final-next-week" => Ok(BuiltinScene::FinalNextWeek),
+            "fractals" => Ok(BuiltinScene::Fractals),
+            _ => bail!(
+                "Unknown scene '{}', expected one of: random-spheres, cornell-box, three-spheres, checkered-ground, smoke-box, final-next-week, fractals",
+                s
+            ),
+        }
+    }
+}
+
+pub(crate) fn scene(
+    settings: &RenderSettings,
+    world: HittableList,
+    lights: HittableList,
+    materials: MaterialList,
+    background: Box<dyn Background>,
+    camera: Camera,
+) -> Scene {
+    let world = settings.accelerator.build(world.into_objects(), settings);
+    let lights = LightBvh::new(lights, &materials);
+    let caustics = (settings.caustic_photons > 0).then(|| {
+        // The same photons on all the threads and machines rendering the image
+        let mut rng = SampleRng::new(settings.seed);
+        photon_map::trace_caustics(
+            &*world,
+            &lights,
+            &materials,
+            settings.fog.as_ref(),
+            settings.caustic_photons,
+            settings.bounce_limit,
+            &mut rng,
+        )
+    });
+    let mut scene = Scene {
+        world,
+        lights,
+        delta_lights: Vec::new(),
+        materials,
+        background,
+        camera,
+        caustics,
+        guide: None,
+        irradiance: None,
+    };
+    if settings.irradiance_cache {
+        scene.irradiance = Some(IrradianceCache::build(&scene, settings));
+    }
+    scene
+}
+
+/// Camera without depth of field, looking from `look_from` at `look_at`, vertically upwards.
+fn pinhole_camera(
+    settings: &RenderSettings,
+    look_from: Point3,
+    look_at: Point3,
+    vertical_fov_deg: Float,
+) -> Camera {
+    Camera::new(
+        look_from,
+        look_at,
+        Vec3::new(0.0, 1.0, 0.0),
+        vertical_fov_deg,
+        settings.aspect_ratio(),
+        0.0,
+        1.0,
+    )
+}
+
+pub(crate) fn sky() -> Box<dyn Background> {
+    Box::new(Gradient::new(
+        Color::new(1.0, 1.0, 1.0),
+        Color::new(0.5, 0.7, 1.0),
+    ))
+}
+
+fn darkness() -> Box<dyn Background> {
+    Box::new(SolidColor::new(Color::zero()))
+}
+
+// ----------------
+//  RANDOM SPHERES
+// ----------------
+
+fn random_spheres(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let world = random_world(&mut StdRng::seed_from_u64(SCENE_SEED), &mut materials);
+
+    let camera = Camera::new(
+        Point3::new(13.0, 2.0, 3.0),
+        Point3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        20.0,
+        settings.aspect_ratio(),
+        0.1,
+        10.0,
+    );
+    scene(
+        settings,
+        world,
+        HittableList::new(),
+        materials,
+        sky(),
+        camera,
+    )
+}
+
+/// Sweep round the large spheres, starting from the camera of the still image.
+fn random_spheres_camera_path() -> CameraPath {
+    let keyframe = |time, value| Keyframe { time, value };
+    CameraPath {
+        look_from: Track::new(vec![
+            keyframe(0.0, Point3::new(13.0, 2.0, 3.0)),
+            keyframe(2.0, Point3::new(3.0, 2.0, 13.0)),
+            keyframe(4.0, Point3::new(-13.0, 2.0, 3.0)),
+        ]),
+        look_at: Track::constant(Point3::new(0.0, 0.0, 0.0)),
+        vertical_fov_deg: Track::constant(20.0),
+        v_up: Vec3::new(0.0, 1.0, 0.0),
+        aperture: 0.1,
+    }
+}
+
+fn random_world<R: Rng>(rng: &mut R, materials: &mut MaterialList) -> HittableList {
+    let mut world = HittableList::new();
+
+    let ground_material = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.5, 0.5, 0.5,
+    ))));
+    // All the small glass spheres share the same material
+    let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground_material,
+    )));
+
+    // Batched together, being many and small
+    let mut small_spheres = Vec::new();
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = rng.gen::<Float>();
+            let center = Point3::new(
+                a as Float + 0.9 * rng.gen::<Float>(),
+                0.2,
+                b as Float + 0.9 * rng.gen::<Float>(),
+            );
+
+            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                if choose_mat < 0.8 {
+                    let albedo = Color::random(rng) * Color::random(rng);
+                    let material = materials.add(Material::Lambertian(Lambertian::new(albedo)));
+                    small_spheres.push(Sphere::new(center, 0.2, material));
+                } else if choose_mat < 0.95 {
+                    let albedo = Color::random_range(rng, 0.5, 1.0);
+                    let fuzz = rng.gen_range(0.0..0.5) as Float;
+                    let material = materials.add(Material::Metal(Metal::new(albedo, fuzz)));
+                    small_spheres.push(Sphere::new(center, 0.2, material));
+                } else {
+                    small_spheres.push(Sphere::new(center, 0.2, glass));
+                }
+            }
+        }
+    }
+    world.add(Box::new(SphereSet::new(small_spheres)));
+
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 1.0, 0.0),
+        1.0,
+        glass,
+    )));
+
+    let material2 = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.4, 0.2, 0.1,
+    ))));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-4.0, 1.0, 0.0),
+        1.0,
+        material2,
+    )));
+
+    let material3 = materials.add(Material::Metal(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(4.0, 1.0, 0.0),
+        1.0,
+        material3,
+    )));
+
+    world
+}
+
+// -------------
+//  CORNELL BOX
+// -------------
+
+/// Side of the Cornell box, whose floor spans [0, 555] along X and Z.
+const CORNELL_SIZE: Float = 555.0;
+
+fn cornell_box(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+    let mut lights = HittableList::new();
+    let white = cornell_room(&mut world, &mut lights, &mut materials, 130.0, 105.0, 15.0);
+
+    let blocks = Node::new("blocks")
+        .with_child(
+            Node::new("tall-block")
+                .with_transform(
+                    Transform::rotation(Vec3::new(0.0, 1.0, 0.0), 15.0)
+                        .then(&Transform::translation(Vec3::new(265.0, 0.0, 295.0))),
+                )
+                .with_object(Box::new(cuboid(
+                    Point3::zero(),
+                    Point3::new(165.0, 330.0, 165.0),
+                    white,
+                ))),
+        )
+        .with_child(
+            Node::new("short-block")
+                .with_transform(
+                    Transform::rotation(Vec3::new(0.0, 1.0, 0.0), -18.0)
+                        .then(&Transform::translation(Vec3::new(130.0, 0.0, 65.0))),
+                )
+                .with_object(Box::new(cuboid(
+                    Point3::zero(),
+                    Point3::new(165.0, 165.0, 165.0),
+                    white,
+                ))),
+        );
+    for (_, block) in blocks.flatten() {
+        world.add(block);
+    }
+
+    cornell_scene(settings, world, lights, materials)
+}
+
+/// Adds the walls of the Cornell box and its ceiling light, `light_width` along X and
+/// `light_depth` along Z, returning the white material of the walls.
+fn cornell_room(
+    world: &mut HittableList,
+    lights: &mut HittableList,
+    materials: &mut MaterialList,
+    light_width: Float,
+    light_depth: Float,
+    light_intensity: Float,
+) -> MaterialId {
+    let red = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.65, 0.05, 0.05,
+    ))));
+    let white = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.73, 0.73, 0.73,
+    ))));
+    let green = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.12, 0.45, 0.15,
+    ))));
+    let light = materials.add(Material::DiffuseLight(DiffuseLight::new(Color::new(
+        light_intensity,
+        light_intensity,
+        light_intensity,
+    ))));
+
+    let s = CORNELL_SIZE;
+    let x = Vec3::new(s, 0.0, 0.0);
+    let y = Vec3::new(0.0, s, 0.0);
+    let z = Vec3::new(0.0, 0.0, s);
+    world.add(Box::new(Rect::new(Point3::new(s, 0.0, 0.0), y, z, green)));
+    world.add(Box::new(Rect::new(Point3::zero(), y, z, red)));
+    world.add(Box::new(Rect::new(Point3::zero(), x, z, white)));
+    world.add(Box::new(Rect::new(Point3::new(0.0, s, 0.0), x, z, white)));
+    world.add(Box::new(Rect::new(Point3::new(0.0, 0.0, s), x, y, white)));
+
+    // Just below the ceiling, facing down
+    let ceiling_light = || {
+        Rect::new(
+            Point3::new(0.5 * (s - light_width), s - 1.0, 0.5 * (s - light_depth)),
+            Vec3::new(light_width, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, light_depth),
+            light,
+        )
+    };
+    world.add(Box::new(ceiling_light()));
+    lights.add(Box::new(ceiling_light()));
+
+    white
+}
+
+fn cornell_scene(
+    settings: &RenderSettings,
+    world: HittableList,
+    lights: HittableList,
+    materials: MaterialList,
+) -> Scene {
+    let center = 0.5 * CORNELL_SIZE;
+    scene(
+        settings,
+        world,
+        lights,
+        materials,
+        darkness(),
+        pinhole_camera(
+            settings,
+            Point3::new(center, center, -800.0),
+            Point3::new(center, center, 0.0),
+            40.0,
+        ),
+    )
+}
+
+// ---------------
+//  THREE SPHERES
+// ---------------
+
+fn three_spheres(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+
+    let ground = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.8, 0.8, 0.0,
+    ))));
+    let diffuse = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.1, 0.2, 0.5,
+    ))));
+    let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+    let metal = materials.add(Material::Metal(Metal::new(Color::new(0.8, 0.6, 0.2), 0.0)));
+
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -100.5, -1.0),
+        100.0,
+        ground,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 0.0, -1.0),
+        0.5,
+        diffuse,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-1.0, 0.0, -1.0),
+        0.5,
+        glass,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(1.0, 0.0, -1.0),
+        0.5,
+        metal,
+    )));
+
+    scene(
+        settings,
+        world,
+        HittableList::new(),
+        materials,
+        sky(),
+        pinhole_camera(
+            settings,
+            Point3::new(-2.0, 2.0, 1.0),
+            Point3::new(0.0, 0.0, -1.0),
+            45.0,
+        ),
+    )
+}
+
+// ------------------
+//  CHECKERED GROUND
+// ------------------
+
+fn checkered_ground(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+
+    let checkerboard = Mix::new(
+        Material::Lambertian(Lambertian::new(Color::new(0.2, 0.3, 0.1))),
+        Material::Lambertian(Lambertian::new(Color::new(0.9, 0.9, 0.9))),
+        Texture::Checker(Checker::new(Color::zero(), Color::new(1.0, 1.0, 1.0), 1.0)),
+    );
+    let ground = materials.add(Material::Mix(checkerboard));
+    // The top of the ground is in the middle of a layer of cells, away from their edges
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.5, 0.0),
+        1000.0,
+        ground,
+    )));
+
+    let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+    let diffuse = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.8, 0.3, 0.1,
+    ))));
+    let metal = materials.add(Material::Metal(Metal::new(Color::new(0.7, 0.7, 0.8), 0.05)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 0.5, 0.0),
+        1.0,
+        glass,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-4.0, 0.5, 0.0),
+        1.0,
+        diffuse,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(4.0, 0.5, 0.0),
+        1.0,
+        metal,
+    )));
+
+    scene(
+        settings,
+        world,
+        HittableList::new(),
+        materials,
+        sky(),
+        pinhole_camera(
+            settings,
+            Point3::new(13.0, 2.0, 3.0),
+            Point3::new(0.0, 0.0, 0.0),
+            20.0,
+        ),
+    )
+}
+
+// -----------
+//  SMOKE BOX
+// -----------
+
+fn smoke_box(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+    let mut lights = HittableList::new();
+    cornell_room(&mut world, &mut lights, &mut materials, 330.0, 305.0, 7.0);
+
+    // Smoke is a random walk through particles, behind a surface which doesn't refract
+    let light_smoke = materials.add(Material::Subsurface(Subsurface::new(
+        Color::new(1.0, 1.0, 1.0),
+        100.0,
+        1.0,
+    )));
+    let dark_smoke = materials.add(Material::Subsurface(Subsurface::new(
+        Color::zero(),
+        100.0,
+        1.0,
+    )));
+
+    world.add(Box::new(Instance::new(
+        Rc::new(cuboid(
+            Point3::zero(),
+            Point3::new(165.0, 330.0, 165.0),
+            dark_smoke,
+        )),
+        Transform::rotation(Vec3::new(0.0, 1.0, 0.0), 15.0)
+            .then(&Transform::translation(Vec3::new(265.0, 0.0, 295.0))),
+    )));
+    world.add(Box::new(Instance::new(
+        Rc::new(cuboid(
+            Point3::zero(),
+            Point3::new(165.0, 165.0, 165.0),
+            light_smoke,
+        )),
+        Transform::rotation(Vec3::new(0.0, 1.0, 0.0), -18.0)
+            .then(&Transform::translation(Vec3::new(130.0, 0.0, 65.0))),
+    )));
+
+    cornell_scene(settings, world, lights, materials)
+}
+
+// -----------------
+//  FINAL NEXT WEEK
+// -----------------
+
+fn final_next_week(settings: &RenderSettings) -> Scene {
+    let mut rng = StdRng::seed_from_u64(SCENE_SEED);
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+    let mut lights = HittableList::new();
+
+    // Ground of blocks of random heights
+    let ground = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.48, 0.83, 0.53,
+    ))));
+    let block_size = 100.0;
+    for i in 0..20 {
+        for j in 0..20 {
+            let x = -1000.0 + i as Float * block_size;
+            let z = -1000.0 + j as Float * block_size;
+            let height = rng.gen_range(1.0..101.0) as Float;
+            world.add(Box::new(cuboid(
+                Point3::new(x, 0.0, z),
+                Point3::new(x + block_size, height, z + block_size),
+                ground,
+            )));
+        }
+    }
+
+    let light = materials.add(Material::DiffuseLight(DiffuseLight::new(Color::new(
+        7.0, 7.0, 7.0,
+    ))));
+    let ceiling_light = || {
+        Rect::new(
+            Point3::new(123.0, 554.0, 147.0),
+            Vec3::new(300.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 265.0),
+            light,
+        )
+    };
+    world.add(Box::new(ceiling_light()));
+    lights.add(Box::new(ceiling_light()));
+
+    let orange = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.7, 0.3, 0.1,
+    ))));
+    world.add(Box::new(Sphere::new(
+        Point3::new(400.0, 400.0, 200.0),
+        50.0,
+        orange,
+    )));
+    let glass = materials.add(Material::Dielectric(Dielectric::new(1.5)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(260.0, 150.0, 45.0),
+        50.0,
+        glass,
+    )));
+    let metal = materials.add(Material::Metal(Metal::new(Color::new(0.8, 0.8, 0.9), 1.0)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 150.0, 145.0),
+        50.0,
+        metal,
+    )));
+
+    // Glass filled with blue fog
+    let blue_fog = materials.add(Material::Subsurface(Subsurface::new(
+        Color::new(0.2, 0.4, 0.9),
+        5.0,
+        1.5,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(360.0, 150.0, 145.0),
+        70.0,
+        blue_fog,
+    )));
+
+    // Thin mist over everything, the camera included
+    let mist = materials.add(Material::Subsurface(Subsurface::new(
+        Color::new(1.0, 1.0, 1.0),
+        10000.0,
+        1.0,
+    )));
+    world.add(Box::new(Sphere::new(Point3::zero(), 5000.0, mist)));
+
+    let checkered = materials.add(Material::Mix(Mix::new(
+        Material::Lambertian(Lambertian::new(Color::new(0.1, 0.2, 0.5))),
+        Material::Lambertian(Lambertian::new(Color::new(0.9, 0.9, 0.9))),
+        Texture::Checker(Checker::new(Color::zero(), Color::new(1.0, 1.0, 1.0), 20.0)),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(400.0, 200.0, 400.0),
+        100.0,
+        checkered,
+    )));
+    let white = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.73, 0.73, 0.73,
+    ))));
+    world.add(Box::new(Sphere::new(
+        Point3::new(220.0, 280.0, 300.0),
+        80.0,
+        white,
+    )));
+
+    // Cluster of small spheres, moved into place as a whole
+    let spheres: Vec<Box<dyn Hittable>> = (0..1000)
+        .map(|_| {
+            let center = Vec3::random_range(&mut rng, 0.0, 165.0);
+            Box::new(Sphere::new(center, 10.0, white)) as Box<dyn Hittable>
+        })
+        .collect();
+    world.add(Box::new(Instance::new(
+        Rc::new(Bvh::new(spheres, BvhSplit::Sah)),
+        Transform::rotation(Vec3::new(0.0, 1.0, 0.0), 15.0)
+            .then(&Transform::translation(Vec3::new(-100.0, 270.0, 395.0))),
+    )));
+
+    scene(
+        settings,
+        world,
+        lights,
+        materials,
+        darkness(),
+        pinhole_camera(
+            settings,
+            Point3::new(478.0, 278.0, -600.0),
+            Point3::new(278.0, 278.0, 0.0),
+            40.0,
+        ),
+    )
+}
+
+// ----------
+//  FRACTALS
+// ----------
+
+fn fractals(settings: &RenderSettings) -> Scene {
+    let mut materials = MaterialList::new();
+    let mut world = HittableList::new();
+
+    let ground = materials.add(Material::Lambertian(Lambertian::new(Color::new(
+        0.5, 0.5, 0.5,
+    ))));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground,
+    )));
+
+    // Deep blue where the points escape first, through orange, to pale yellow inside
+    let palette = Ramp::new(vec![
+        Color::new(0.05, 0.1, 0.4),
+        Color::new(0.9, 0.4, 0.1),
+        Color::new(0.95, 0.9, 0.6),
+    ]);
+    let fractal = materials.add(Material::Lambertian(
+        Lambertian::new(Color::new(0.5, 0.5, 0.5)).with_albedo_texture(Texture::Ramp(palette)),
+    ));
+    world.add(Box::new(SdfObject::new(
+        Sdf::mandelbulb(8.0, 12).translated(Vec3::new(-1.3, 1.1, 0.0)),
+        fractal,
+    )));
+    world.add(Box::new(SdfObject::new(
+        Sdf::menger_sponge(4).translated(Vec3::new(1.3, 1.0, 0.0)),
+        fractal,
+    )));
+
+    scene(
+        settings,
+        world,
+        HittableList::new(),
+        materials,
+        sky(),
+        pinhole_camera(
+            settings,
+            Point3::new(1.0, 3.0, 7.0),
+            Point3::new(0.0, 1.0, 0.0),
+            35.0,
+        ),
+    )
+}
+
+// ------------
+//  PRIMITIVES
+// ------------
+
+/// Axis-aligned box with opposite corners `a` and `b`, facing outwards.
+pub(crate) fn cuboid(a: Point3, b: Point3, material: MaterialId) -> Mesh {
+    let (min, max) = (a.min(&b), a.max(&b));
+    // Corner i has the X coordinate of `max` if bit 0 of i is set, Y for bit 1, Z for bit 2
+    let positions = (0..8)
+        .map(|i| {
+            Point3::new(
+                if i & 1 == 0 { min.x() } else { max.x() },
+                if i & 2 == 0 { min.y() } else { max.y() },
+                if i & 4 == 0 { min.z() } else { max.z() },
+            )
+        })
+        .collect();
+    // Counterclockwise seen from the outside
+    let faces = [
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+    ];
+    let triangles = faces
+        .iter()
+        .flat_map(|f| vec![[f[0], f[1], f[2]], [f[0], f[2], f[3]]])
+        .collect();
+    Mesh::new(positions, triangles, material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::HitRecord;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_scene_from_str() {
+        for scene in &[
+            BuiltinScene::RandomSpheres,
+            BuiltinScene::CornellBox,
+            BuiltinScene::ThreeSpheres,
+            BuiltinScene::CheckeredGround,
+            BuiltinScene::SmokeBox,
+            BuiltinScene::FinalNextWeek,
+            BuiltinScene::Fractals,
+        ] {
+            assert_eq!(&scene.to_string().parse::<BuiltinScene>().unwrap(), scene);
+        }
+        assert!("teapot".parse::<BuiltinScene>().is_err());
+    }
+
+    #[test]
+    fn test_cuboid_faces_outwards() {
+        let cuboid = cuboid(
+            Point3::new(1.0, 1.0, 1.0),
+            Point3::new(-1.0, -1.0, -1.0),
+            MaterialId::default(),
+        );
+        let directions = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        for direction in directions.iter().flat_map(|&d| vec![d, -d]) {
+            let ray = Ray::new(Point3::zero() - 5.0 * direction, direction);
+            let mut hit_record = HitRecord::empty();
+            assert!(cuboid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+            assert!(hit_record.front_face);
+            assert!((hit_record.t - 4.0).abs() < 1e-5);
+        }
+    }
+}
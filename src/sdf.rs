@@ -0,0 +1,539 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::material::MaterialId;
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::vec3::{unit_vector, Point3, Vec3};
+use std::fmt;
+use std::rc::Rc;
+
+/// Distance under which a point counts as on the surface.
+const SURFACE_DISTANCE: Float = 1e-4;
+/// Steps after which a ray marching along a surface without reaching it is taken as a miss.
+const MAX_STEPS: usize = 512;
+/// Length past which the points of the Mandelbulb iteration are taken as escaping. Larger than
+/// needed for them to escape, for a closer distance estimate.
+const MANDELBULB_BAILOUT: Float = 4.0;
+
+/// Signed distance function of a shape: the distance from a point to the surface, negative
+/// inside. It may underestimate the distance, but never overestimate it, for the rays not to
+/// step over the surface.
+#[derive(Clone)]
+pub enum Sdf {
+    /// Box centered on the origin, its edges rounded with `radius` and included in
+    /// `half_extents`.
+    RoundedBox {
+        half_extents: Vec3,
+        radius: Float,
+    },
+    /// Torus around the y axis, centered on the origin.
+    Torus {
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// Union of two shapes, blended over a distance of about `smoothness` where they meet.
+    SmoothUnion {
+        first: Box<Sdf>,
+        second: Box<Sdf>,
+        smoothness: Float,
+    },
+    Translated {
+        sdf: Box<Sdf>,
+        offset: Vec3,
+    },
+    /// Mandelbulb of degree `power`, the 3D counterpart of the Mandelbrot set, centered on the
+    /// origin with its poles on the y axis.
+    Mandelbulb {
+        power: Float,
+        iterations: usize,
+    },
+    /// Menger sponge filling the cube from -1 to 1, its holes carved `iterations` times.
+    MengerSponge {
+        iterations: usize,
+    },
+    /// User-provided distance function, with the box its shape fits in.
+    Custom {
+        distance: Rc<dyn Fn(&Point3) -> Float>,
+        bounds: Aabb,
+    },
+}
+
+impl Sdf {
+    pub fn rounded_box(half_extents: Vec3, radius: Float) -> Sdf {
+        Sdf::RoundedBox {
+            half_extents,
+            radius,
+        }
+    }
+
+    pub fn torus(major_radius: Float, minor_radius: Float) -> Sdf {
+        Sdf::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn smooth_union(first: Sdf, second: Sdf, smoothness: Float) -> Sdf {
+        Sdf::SmoothUnion {
+            first: Box::new(first),
+            second: Box::new(second),
+            smoothness,
+        }
+    }
+
+    pub fn mandelbulb(power: Float, iterations: usize) -> Sdf {
+        assert!(
+            power > 1.0,
+            "The power of a Mandelbulb must be greater than 1"
+        );
+        Sdf::Mandelbulb { power, iterations }
+    }
+
+    pub fn menger_sponge(iterations: usize) -> Sdf {
+        Sdf::MengerSponge { iterations }
+    }
+
+    pub fn custom<F: Fn(&Point3) -> Float + 'static>(distance: F, bounds: Aabb) -> Sdf {
+        Sdf::Custom {
+            distance: Rc::new(distance),
+            bounds,
+        }
+    }
+
+    /// The shape moved by `offset`.
+    pub fn translated(self, offset: Vec3) -> Sdf {
+        Sdf::Translated {
+            sdf: Box::new(self),
+            offset,
+        }
+    }
+
+    pub fn distance(&self, point: &Point3) -> Float {
+        match *self {
+            Sdf::RoundedBox {
+                half_extents,
+                radius,
+            } => {
+                let inner = half_extents - Vec3::new(radius, radius, radius);
+                let q = Vec3::new(
+                    point.x().abs() - inner.x(),
+                    point.y().abs() - inner.y(),
+                    point.z().abs() - inner.z(),
+                );
+                let outside = q.max(&Vec3::zero()).length();
+                let inside = q.x().max(q.y()).max(q.z()).min(0.0);
+                outside + inside - radius
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = (point.x() * point.x() + point.z() * point.z()).sqrt() - major_radius;
+                (ring * ring + point.y() * point.y()).sqrt() - minor_radius
+            }
+            Sdf::SmoothUnion {
+                ref first,
+                ref second,
+                smoothness,
+            } => {
+                // Polynomial smooth minimum
+                let a = first.distance(point);
+                let b = second.distance(point);
+                if smoothness <= 0.0 {
+                    return a.min(b);
+                }
+                let h = (smoothness - (a - b).abs()).max(0.0) / smoothness;
+                a.min(b) - 0.25 * h * h * smoothness
+            }
+            Sdf::Translated { ref sdf, offset } => sdf.distance(&(*point - offset)),
+            Sdf::Mandelbulb { power, iterations } => mandelbulb(point, power, iterations).0,
+            Sdf::MengerSponge { iterations } => menger_sponge(point, iterations).0,
+            Sdf::Custom { ref distance, .. } => distance(point),
+        }
+    }
+
+    /// Fraction of the iterations of a fractal that shaped it at `point`, in [0, 1], to color it
+    /// by: when the point escapes the Mandelbulb, and which iteration carved the hole of the
+    /// Menger sponge whose wall it is on. 0 for the other shapes.
+    pub fn iteration_fraction(&self, point: &Point3) -> Float {
+        match *self {
+            Sdf::SmoothUnion {
+                ref first,
+                ref second,
+                ..
+            } => {
+                if first.distance(point) <= second.distance(point) {
+                    first.iteration_fraction(point)
+                } else {
+                    second.iteration_fraction(point)
+                }
+            }
+            Sdf::Translated { ref sdf, offset } => sdf.iteration_fraction(&(*point - offset)),
+            Sdf::Mandelbulb { power, iterations } => mandelbulb(point, power, iterations).1,
+            Sdf::MengerSponge { iterations } => menger_sponge(point, iterations).1,
+            Sdf::RoundedBox { .. } | Sdf::Torus { .. } | Sdf::Custom { .. } => 0.0,
+        }
+    }
+
+    /// Box the shape fits in.
+    pub fn bounds(&self) -> Aabb {
+        match *self {
+            Sdf::RoundedBox { half_extents, .. } => Aabb::new(-half_extents, half_extents),
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let radius = major_radius + minor_radius;
+                Aabb::new(
+                    Point3::new(-radius, -minor_radius, -radius),
+                    Point3::new(radius, minor_radius, radius),
+                )
+            }
+            Sdf::SmoothUnion {
+                ref first,
+                ref second,
+                smoothness,
+            } => {
+                // The blend never reaches further than a quarter of the smoothness
+                let bounds = first.bounds().union(&second.bounds());
+                bounds.padded(0.25 * smoothness.max(0.0))
+            }
+            Sdf::Translated { ref sdf, offset } => {
+                let bounds = sdf.bounds();
+                Aabb::new(bounds.min() + offset, bounds.max() + offset)
+            }
+            Sdf::Mandelbulb { power, .. } => {
+                // Past this radius, the length of the points grows at each iteration
+                Aabb::around(Point3::zero(), (2.0 as Float).powf(1.0 / (power - 1.0)))
+            }
+            Sdf::MengerSponge { .. } => Aabb::around(Point3::zero(), 1.0),
+            Sdf::Custom { bounds, .. } => bounds,
+        }
+    }
+
+    /// Outward normal at `point`, from the gradient of the distance.
+    fn normal(&self, point: &Point3) -> Vec3 {
+        let h = 0.5 * SURFACE_DISTANCE;
+        let partial =
+            |axis: Vec3| self.distance(&(*point + h * axis)) - self.distance(&(*point - h * axis));
+        unit_vector(Vec3::new(
+            partial(Vec3::new(1.0, 0.0, 0.0)),
+            partial(Vec3::new(0.0, 1.0, 0.0)),
+            partial(Vec3::new(0.0, 0.0, 1.0)),
+        ))
+    }
+}
+
+impl fmt::Debug for Sdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sdf::RoundedBox {
+                half_extents,
+                radius,
+            } => write!(f, "RoundedBox({:?}, {})", half_extents, radius),
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => write!(f, "Torus({}, {})", major_radius, minor_radius),
+            Sdf::SmoothUnion {
+                ref first,
+                ref second,
+                smoothness,
+            } => write!(f, "SmoothUnion({:?}, {:?}, {})", first, second, smoothness),
+            Sdf::Translated { ref sdf, offset } => write!(f, "Translated({:?}, {:?})", sdf, offset),
+            Sdf::Mandelbulb { power, iterations } => {
+                write!(f, "Mandelbulb({}, {})", power, iterations)
+            }
+            Sdf::MengerSponge { iterations } => write!(f, "MengerSponge({})", iterations),
+            Sdf::Custom { bounds, .. } => write!(f, "Custom({:?})", bounds),
+        }
+    }
+}
+
+/// Distance estimate of the Mandelbulb and fraction of the iterations after which `point`
+/// escapes, 1 if it doesn't. The points are raised to the power in spherical coordinates, and
+/// the distance estimated from the derivative of the iteration.
+fn mandelbulb(point: &Point3, power: Float, iterations: usize) -> (Float, Float) {
+    let mut z = *point;
+    let mut derivative = 1.0;
+    // Away from 0, for the angles and the logarithm to be defined
+    let mut r = z.length().max(Float::MIN_POSITIVE);
+    for i in 0..iterations {
+        if r > MANDELBULB_BAILOUT {
+            // Smoothed by how far past the bailout the point went, against color banding
+            let overshoot = (r.ln() / MANDELBULB_BAILOUT.ln()).ln() / power.ln();
+            let fraction = ((i as Float - overshoot) / iterations as Float).clamp(0.0, 1.0);
+            return (0.5 * r.ln() * r / derivative, fraction);
+        }
+        derivative = power * r.powf(power - 1.0) * derivative + 1.0;
+        let theta = power * (z.y() / r).clamp(-1.0, 1.0).acos();
+        let phi = power * z.z().atan2(z.x());
+        z = r.powf(power)
+            * Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            )
+            + *point;
+        r = z.length().max(Float::MIN_POSITIVE);
+    }
+    (0.5 * r.ln() * r / derivative, 1.0)
+}
+
+/// Distance to the Menger sponge and fraction of the iterations whose hole is the nearest to
+/// `point`, 0 on the faces of the cube. Each iteration carves the crosses of holes of a 3 times
+/// smaller grid of cubes.
+fn menger_sponge(point: &Point3, iterations: usize) -> (Float, Float) {
+    let q = Vec3::new(
+        point.x().abs() - 1.0,
+        point.y().abs() - 1.0,
+        point.z().abs() - 1.0,
+    );
+    let mut distance = q.max(&Vec3::zero()).length() + q.x().max(q.y()).max(q.z()).min(0.0);
+    let mut fraction = 0.0;
+    let mut scale = 1.0;
+    for i in 0..iterations {
+        // Position in the cube of the grid the point is in, from its center at -1 and 1 to
+        // its faces at 0
+        let cell = |c: Float| (c * scale).rem_euclid(2.0) - 1.0;
+        let a = Vec3::new(cell(point.x()), cell(point.y()), cell(point.z()));
+        scale *= 3.0;
+        let r = Vec3::new(
+            (1.0 - 3.0 * a.x().abs()).abs(),
+            (1.0 - 3.0 * a.y().abs()).abs(),
+            (1.0 - 3.0 * a.z().abs()).abs(),
+        );
+        // Distance to the cross of holes through the middle of the cube
+        let cross = r.x().max(r.y()).min(r.y().max(r.z())).min(r.z().max(r.x()));
+        let hole = (cross - 1.0) / scale;
+        if hole > distance {
+            distance = hole;
+            fraction = (i + 1) as Float / iterations as Float;
+        }
+    }
+    (distance, fraction)
+}
+
+/// Shape described by a signed distance function, intersected by sphere tracing: the rays step
+/// forward by the distance to the surface until they reach it. Blends and shapes without
+/// analytic intersections are then as easy to render as any other. The fractals store the
+/// fraction of their iterations in u, for a `Texture::Ramp` to color them with.
+#[derive(Clone, Debug)]
+pub struct SdfObject {
+    sdf: Sdf,
+    bounds: Aabb,
+    material: MaterialId,
+}
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, material: MaterialId) -> SdfObject {
+        // Padded for the rays to start marching off the surface
+        let bounds = sdf.bounds().padded(2.0 * SURFACE_DISTANCE);
+        SdfObject {
+            sdf,
+            bounds,
+            material,
+        }
+    }
+}
+
+impl Hittable for SdfObject {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let direction = ray.direction();
+        let inverse_direction = Vec3::new(
+            1.0 / direction.x(),
+            1.0 / direction.y(),
+            1.0 / direction.z(),
+        );
+        let (t_start, t_end) = match self.bounds.clip(ray, &inverse_direction, t_min, t_max) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        // From inside, the rays march towards the surface the same way
+        let sign = if self.sdf.distance(&ray.at(t_start)) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        let inverse_length = 1.0 / direction.length();
+        let mut t = t_start;
+        for _ in 0..MAX_STEPS {
+            if t > t_end {
+                return false;
+            }
+            let point = ray.at(t);
+            let distance = sign * self.sdf.distance(&point);
+            if distance < SURFACE_DISTANCE {
+                hit_record.t = t;
+                hit_record.point = point;
+                hit_record.set_face_normal(ray, &self.sdf.normal(&point));
+                // The rays leaving the surface start beyond where they would find it again
+                hit_record.point_error = 2.0 * SURFACE_DISTANCE;
+                hit_record.u = self.sdf.iteration_fraction(&point);
+                hit_record.v = 0.0;
+                hit_record.tangent = Vec3::zero();
+                hit_record.material = self.material;
+                return true;
+            }
+            t += distance * inverse_length;
+        }
+        false
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(object: &SdfObject, origin: Point3, direction: Vec3) -> Option<HitRecord> {
+        let mut hit_record = HitRecord::empty();
+        object
+            .hit(
+                &Ray::new(origin, direction),
+                0.0,
+                Float::MAX,
+                &mut hit_record,
+            )
+            .then_some(hit_record)
+    }
+
+    #[test]
+    fn test_rounded_box() {
+        let object = SdfObject::new(
+            Sdf::rounded_box(Vec3::new(1.0, 2.0, 1.0), 0.25),
+            MaterialId::default(),
+        );
+        let hit_record = hit(
+            &object,
+            Point3::new(0.0, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -2.0),
+        )
+        .unwrap();
+        assert!((hit_record.point.z() - 1.0).abs() < 1e-3);
+        assert!((hit_record.t - 2.0).abs() < 1e-3);
+        assert!((hit_record.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-3);
+        assert!(hit_record.front_face);
+
+        // The corners are rounded off
+        let corner = Point3::new(0.75, 1.75, 0.75);
+        let origin = Point3::new(2.0, 3.0, 2.0);
+        let hit_record = hit(&object, origin, corner - origin).unwrap();
+        assert!(((hit_record.point - corner).length() - 0.25).abs() < 1e-3);
+        assert!(hit(
+            &object,
+            Point3::new(1.2, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -1.0)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_torus_from_inside() {
+        let object = SdfObject::new(Sdf::torus(2.0, 0.5), MaterialId::default());
+        // Through the hole
+        assert!(hit(
+            &object,
+            Point3::new(0.0, 5.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0)
+        )
+        .is_none());
+
+        let hit_record = hit(
+            &object,
+            Point3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        )
+        .unwrap();
+        assert!((hit_record.t - 0.5).abs() < 1e-3);
+        assert!(!hit_record.front_face);
+        assert!((hit_record.normal - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-3);
+    }
+
+    #[test]
+    fn test_smooth_union_fills_the_gap() {
+        let ball = |x: Float| {
+            Sdf::custom(
+                |p: &Point3| p.length() - 1.0,
+                Aabb::around(Point3::zero(), 1.0),
+            )
+            .translated(Vec3::new(x, 0.0, 0.0))
+        };
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let above_gap = Point3::new(0.0, 5.0, 0.0);
+
+        let union = SdfObject::new(
+            Sdf::smooth_union(ball(-1.05), ball(1.05), 0.0),
+            MaterialId::default(),
+        );
+        assert!(hit(&union, above_gap, down).is_none());
+        let blend = SdfObject::new(
+            Sdf::smooth_union(ball(-1.05), ball(1.05), 1.0),
+            MaterialId::default(),
+        );
+        assert!(hit(&blend, above_gap, down).is_some());
+    }
+
+    #[test]
+    fn test_mandelbulb() {
+        let mandelbulb = Sdf::mandelbulb(8.0, 12);
+        // Its bounds are as tight as the escape of the points allows
+        assert!(mandelbulb.distance(&Point3::new(0.0, 1.2, 0.0)) > 0.0);
+        assert!(mandelbulb.distance(&Point3::zero()) < 0.0);
+
+        let object = SdfObject::new(mandelbulb, MaterialId::default());
+        let hit_record = hit(
+            &object,
+            Point3::new(0.0, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        )
+        .unwrap();
+        assert!(hit_record.point.z() > 0.5 && hit_record.point.z() < 1.2);
+        assert!(hit_record.normal.z() > 0.0);
+        // Escaping after a few iterations, even just off the surface
+        assert!(hit_record.u > 0.0 && hit_record.u < 1.0);
+    }
+
+    #[test]
+    fn test_menger_sponge() {
+        let sponge = Sdf::menger_sponge(3);
+        let object = SdfObject::new(sponge.clone(), MaterialId::default());
+        // Through the central hole
+        assert!(hit(
+            &object,
+            Point3::new(0.0, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -1.0)
+        )
+        .is_none());
+
+        // On the face of the cube
+        let hit_record = hit(
+            &object,
+            Point3::new(0.5, 0.5, 5.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        )
+        .unwrap();
+        assert!((hit_record.point.z() - 1.0).abs() < 1e-3);
+        assert_eq!(hit_record.u, 0.0);
+
+        // On the wall of the central hole
+        let hit_record = hit(
+            &object,
+            Point3::new(0.0, 0.0, 1.5),
+            Vec3::new(1.0, 0.0, -3.0),
+        )
+        .unwrap();
+        assert!((hit_record.point - Point3::new(1.0 / 3.0, 0.0, 0.5)).length() < 1e-3);
+        assert!((hit_record.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-3);
+        assert!((hit_record.u - 1.0 / 3.0).abs() < 1e-3);
+
+        // In the hole of the second iteration through the corner cube
+        let point = Point3::new(0.7, 2.0 / 3.0, 0.9);
+        assert!(sponge.distance(&point) > 0.0);
+        assert!((sponge.iteration_fraction(&point) - 2.0 / 3.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::accelerator::AcceleratorType;
+use crate::bloom::Bloom;
+use crate::bvh::BvhSplit;
+use crate::filter::Filter;
+use crate::float::Float;
+use crate::fog::HeightFog;
+use crate::integrator::Integrator;
+use crate::output::Dither;
+use crate::progress::ProgressFormat;
+use crate::sampler::Sampler;
+use crate::scenes::BuiltinScene;
+use crate::tonemap::{Exposure, ToneMapper, TransferFunction};
+
+pub const ASPECT_RATIO: Float = 3.0 / 2.0;
+pub const IMAGE_WIDTH: u16 = 1200;
+pub const IMAGE_HEIGHT: u16 = ((IMAGE_WIDTH as Float) / ASPECT_RATIO) as u16;
+
+pub const SAMPLES_PER_PIXEL: u16 = 500;
+pub const BOUNCE_LIMIT: u16 = 50;
+pub const PHOTONS_PER_ITERATION: u32 = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderSettings {
+    /// Built-in scene to render.
+    pub scene: BuiltinScene,
+    pub image_width: u16,
+    pub image_height: u16,
+    pub samples_per_pixel: u16,
+    pub bounce_limit: u16,
+    /// Fog filling the scene, scattering and attenuating the rays of the path tracer.
+    pub fog: Option<HeightFog>,
+    /// Photons traced from the lights before rendering to estimate the caustics, 0 to leave
+    /// them to the path tracer.
+    pub caustic_photons: u32,
+    /// Photons traced at each iteration of the SPPM integrator.
+    pub photons_per_iteration: u32,
+    /// Learn where the light comes from while rendering, and sample the path directions from it.
+    pub path_guiding: bool,
+    /// Interpolate the indirect light of the diffuse surfaces between points computed before
+    /// rendering, much faster than path tracing it but slightly biased.
+    pub irradiance_cache: bool,
+    /// Let the shadow rays of the path tracer through dielectrics, tinted by their absorption,
+    /// instead of leaving the light behind glass to the paths refracting through it.
+    pub transparent_shadows: bool,
+    pub integrator: Integrator,
+    pub sampler: Sampler,
+    pub filter: Filter,
+    pub tone_mapper: ToneMapper,
+    /// Applied to the radiance of the path tracer before tone mapping.
+    pub exposure: Exposure,
+    /// Glow around the brightest parts of the path traced image, added before tone mapping.
+    pub bloom: Option<Bloom>,
+    /// Applied to the tone mapped colors of the path tracer when writing the image.
+    pub transfer_function: TransferFunction,
+    /// Noise added to the image before quantizing it to integers, hiding the banding of smooth
+    /// gradients.
+    pub dither: Dither,
+    /// Also write the albedo, normal and depth buffers next to the image.
+    pub write_aovs: bool,
+    /// How the progress of the render is shown.
+    pub progress: ProgressFormat,
+    /// Structure speeding up the search for hits among the objects of the scene.
+    pub accelerator: AcceleratorType,
+    /// Construction of the BVH of the scene.
+    pub bvh_split: BvhSplit,
+    /// Seed of the random numbers of the samples, which the image only depends on.
+    pub seed: u64,
+    /// Index of the first sample of the pixels rendered by `Scene::render_tile`, renders
+    /// starting from other indices getting other samples, from the same seed.
+    pub first_sample: u32,
+}
+
+impl RenderSettings {
+    pub fn aspect_ratio(&self) -> Float {
+        self.image_width as Float / self.image_height as Float
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            scene: BuiltinScene::RandomSpheres,
+            image_width: IMAGE_WIDTH,
+            image_height: IMAGE_HEIGHT,
+            samples_per_pixel: SAMPLES_PER_PIXEL,
+            bounce_limit: BOUNCE_LIMIT,
+            fog: None,
+            caustic_photons: 0,
+            photons_per_iteration: PHOTONS_PER_ITERATION,
+            path_guiding: false,
+            irradiance_cache: false,
+            transparent_shadows: false,
+            integrator: Integrator::PathTracer,
+            sampler: Sampler::Random,
+            filter: Filter::Box,
+            tone_mapper: ToneMapper::Exposure,
+            exposure: Exposure::Manual(0.0),
+            bloom: None,
+            transfer_function: TransferFunction::Srgb,
+            dither: Dither::None,
+            write_aovs: false,
+            progress: ProgressFormat::Bar,
+            accelerator: AcceleratorType::Bvh,
+            bvh_split: BvhSplit::Sah,
+            seed: 0,
+            first_sample: 0,
+        }
+    }
+}
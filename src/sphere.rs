@@ -1,26 +1,58 @@
-use crate::material::Material;
-use crate::object::{HitRecord, Hittable};
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::material::{BackFaces, MaterialId};
+use crate::object::{rounding_error, HitRecord, Hittable, SurfaceSample};
+use crate::onb::Onb;
 use crate::ray::Ray;
-use crate::vec3::{unit_vector, Point3};
+use crate::rng::SampleRng;
+use crate::vec3::{unit_vector, Point3, Vec3};
 
+#[derive(Clone)]
 pub struct Sphere {
     center: Point3,
-    radius: f32,
-    material: Material,
+    radius: Float,
+    material: MaterialId,
 }
 
 impl Sphere {
-    pub fn new(center: Point3, radius: f32, material: Material) -> Sphere {
+    pub fn new(center: Point3, radius: Float, material: MaterialId) -> Sphere {
         Sphere {
             center,
             radius,
             material,
         }
     }
+
+    pub fn center(&self) -> Point3 {
+        self.center
+    }
+
+    pub fn radius(&self) -> Float {
+        self.radius
+    }
+
+    pub fn material(&self) -> MaterialId {
+        self.material
+    }
+
+    /// Fills `hit_record` for the hit of `ray` at distance `t`.
+    pub(crate) fn record_hit(&self, ray: &Ray, t: Float, hit_record: &mut HitRecord) {
+        hit_record.t = t;
+        // Reprojected onto the sphere, the root being inaccurate for large spheres
+        let outward_normal = unit_vector(ray.at(t) - self.center);
+        hit_record.point = self.center + self.radius.abs() * outward_normal;
+        hit_record.set_face_normal(ray, &outward_normal);
+        hit_record.point_error = rounding_error(self.radius);
+        let (u, v) = sphere_uv(&outward_normal);
+        hit_record.u = u;
+        hit_record.v = v;
+        hit_record.tangent = Vec3::new(outward_normal.z(), 0.0, -outward_normal.x());
+        hit_record.material = self.material;
+    }
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
         let origin_center = ray.origin() - self.center;
         let a = ray.direction().length_squared();
         let half_b = ray.direction().dot(&origin_center);
@@ -36,17 +68,70 @@ impl Hittable for Sphere {
         // Find the nearest root that lies in acceptable range
         let mut root = (-half_b - sqrt_discriminant) / a;
         if root < t_min || root > t_max {
+            // The far root is where the ray leaves the sphere, through its back
+            if self.material.back_faces() == BackFaces::Culled {
+                return false;
+            }
             root = (-half_b + sqrt_discriminant) / a;
             if root < t_min || root > t_max {
                 return false;
             }
         }
 
-        hit_record.t = root;
-        hit_record.point = ray.at(hit_record.t);
-        let outward_normal = unit_vector(hit_record.point - self.center);
-        hit_record.set_face_normal(&ray, &outward_normal);
-        hit_record.material = self.material.clone();
+        self.record_hit(ray, root, hit_record);
         true
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.center, self.radius))
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let mut hit_record = HitRecord::empty();
+        if !self.hit(
+            &Ray::new(*origin, *direction),
+            0.001,
+            Float::MAX,
+            &mut hit_record,
+        ) {
+            return 0.0;
+        }
+
+        let distance_squared = (self.center - *origin).length_squared();
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared)
+            .max(0.0)
+            .sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: &Point3, rng: &mut SampleRng) -> Vec3 {
+        let direction = self.center - *origin;
+        let distance_squared = direction.length_squared();
+        let uvw = Onb::build_from_w(&direction);
+        uvw.local(&Vec3::random_to_sphere(rng, self.radius, distance_squared))
+    }
+
+    fn sample_surface(&self, rng: &mut SampleRng) -> Option<SurfaceSample> {
+        let normal = Vec3::random_unit_vector(rng);
+        let point = self.center + self.radius * normal;
+        Some(SurfaceSample {
+            point,
+            normal,
+            pdf: self.surface_pdf(&point),
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point3) -> Float {
+        1.0 / (4.0 * PI * self.radius * self.radius)
+    }
+}
+
+/// Surface coordinates of a point of the unit sphere: u is the angle around the Y axis from
+/// X=-1, v the angle from Y=-1 to Y=+1.
+pub fn sphere_uv(p: &Point3) -> (Float, Float) {
+    let theta = (-p.y()).acos();
+    let phi = (-p.z()).atan2(p.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
 }
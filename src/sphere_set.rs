@@ -0,0 +1,218 @@
+use crate::aabb::Aabb;
+use crate::bvh::{BvhSplit, BvhTree};
+use crate::float::Float;
+use crate::material::{BackFaces, MaterialId};
+use crate::object::{HitRecord, Hittable, ObjectId};
+use crate::ray::Ray;
+use crate::sphere::Sphere;
+use crate::vec3::Point3;
+use std::convert::TryInto;
+
+/// Spheres intersected at once, filling a 256 bits SIMD register.
+#[cfg(not(feature = "f64"))]
+const LANES: usize = 8;
+#[cfg(feature = "f64")]
+const LANES: usize = 4;
+
+/// Many spheres searched with their own BVH, whose leaves are intersected `LANES` spheres at a
+/// time. Faster than the same spheres as separate objects of the scene when there are lots of
+/// them, such as in the random spheres scene.
+pub struct SphereSet {
+    tree: BvhTree,
+    /// Centers and radii in the order of the leaves, each coordinate in its own array so that
+    /// the compiler vectorizes the intersection of consecutive spheres. Padded with `LANES - 1`
+    /// zeros, so that every batch can be loaded whole.
+    center_x: Vec<Float>,
+    center_y: Vec<Float>,
+    center_z: Vec<Float>,
+    radius: Vec<Float>,
+    materials: Vec<MaterialId>,
+    /// Position of the spheres in the list given to `new`.
+    ids: Vec<ObjectId>,
+}
+
+impl SphereSet {
+    pub fn new(spheres: Vec<Sphere>) -> SphereSet {
+        let bounds: Vec<Aabb> = spheres
+            .iter()
+            .map(|sphere| sphere.bounding_box().unwrap())
+            .collect();
+        let (tree, order) = BvhTree::build_batched(&bounds, BvhSplit::Sah, LANES);
+
+        let padded = |f: &dyn Fn(&Sphere) -> Float| {
+            let mut values: Vec<Float> = order.iter().map(|&i| f(&spheres[i])).collect();
+            values.resize(order.len() + LANES - 1, 0.0);
+            values
+        };
+        SphereSet {
+            center_x: padded(&|sphere| sphere.center().x()),
+            center_y: padded(&|sphere| sphere.center().y()),
+            center_z: padded(&|sphere| sphere.center().z()),
+            radius: padded(&|sphere| sphere.radius()),
+            materials: order.iter().map(|&i| spheres[i].material()).collect(),
+            ids: order.iter().map(|&i| ObjectId::new(i)).collect(),
+            tree,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// The spheres of the set, in no particular order.
+    pub fn spheres(&self) -> impl Iterator<Item = Sphere> + '_ {
+        (0..self.len()).map(move |i| self.sphere(i))
+    }
+
+    fn sphere(&self, index: usize) -> Sphere {
+        let center = Point3::new(
+            self.center_x[index],
+            self.center_y[index],
+            self.center_z[index],
+        );
+        Sphere::new(center, self.radius[index], self.materials[index])
+    }
+
+    /// Closest hit among the `count` spheres from `first`, with the same roots as
+    /// `Sphere::hit`, as the index of the sphere and the distance.
+    fn hit_batch(
+        &self,
+        first: usize,
+        count: usize,
+        ray: &Ray,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<(usize, Float)> {
+        let lanes = first..first + LANES;
+        let center_x: &[Float; LANES] = self.center_x[lanes.clone()].try_into().unwrap();
+        let center_y: &[Float; LANES] = self.center_y[lanes.clone()].try_into().unwrap();
+        let center_z: &[Float; LANES] = self.center_z[lanes.clone()].try_into().unwrap();
+        let radius: &[Float; LANES] = self.radius[lanes].try_into().unwrap();
+        // Spheres only hit from the front, the far root being on their back
+        let mut culled = [false; LANES];
+        for (lane, material) in self.materials[first..first + count].iter().enumerate() {
+            culled[lane] = material.back_faces() == BackFaces::Culled;
+        }
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let a = direction.length_squared();
+        // Branchless, so that all the lanes are computed together
+        let mut roots = [Float::INFINITY; LANES];
+        for lane in 0..LANES {
+            let x = origin.x() - center_x[lane];
+            let y = origin.y() - center_y[lane];
+            let z = origin.z() - center_z[lane];
+            let half_b = direction.x() * x + direction.y() * y + direction.z() * z;
+            let c = x * x + y * y + z * z - radius[lane] * radius[lane];
+            let discriminant = half_b * half_b - a * c;
+            let sqrt_discriminant = discriminant.max(0.0).sqrt();
+            let near = (-half_b - sqrt_discriminant) / a;
+            let far = (-half_b + sqrt_discriminant) / a;
+            let root = if near >= t_min || culled[lane] {
+                near
+            } else {
+                far
+            };
+            let hit = lane < count && discriminant >= 0.0 && root >= t_min && root <= t_max;
+            roots[lane] = if hit { root } else { Float::INFINITY };
+        }
+
+        let (lane, root) = roots
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        if root.is_finite() {
+            Some((first + lane, *root))
+        } else {
+            None
+        }
+    }
+}
+
+impl Hittable for SphereSet {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let mut closest_hit = None;
+        self.tree
+            .traverse(ray, t_min, t_max, |leaf, mut closest_so_far| {
+                let mut closer = None;
+                // Leaves of spheres with the same center may be larger than a batch
+                for first in leaf.clone().step_by(LANES) {
+                    let count = (leaf.end - first).min(LANES);
+                    if let Some((index, t)) =
+                        self.hit_batch(first, count, ray, t_min, closest_so_far)
+                    {
+                        closest_so_far = t;
+                        closer = Some(t);
+                        closest_hit = Some((index, t));
+                    }
+                }
+                closer
+            });
+
+        match closest_hit {
+            Some((index, t)) => {
+                self.sphere(index).record_hit(ray, t, hit_record);
+                hit_record.object = self.ids[index];
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.tree.bounds())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::HittableList;
+    use crate::vec3::Vec3;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_same_hits_as_list() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut spheres: Vec<Sphere> = (0..500)
+            .map(|_| {
+                let center = Vec3::random_range(&mut rng, -10.0, 10.0);
+                let radius = rng.gen_range(0.1..1.0);
+                Sphere::new(center, radius, MaterialId::default())
+            })
+            .collect();
+        // More spheres with the same center than a batch holds
+        for i in 1..=2 * LANES {
+            let radius = 0.1 * i as Float;
+            spheres.push(Sphere::new(Point3::zero(), radius, MaterialId::default()));
+        }
+        let mut list = HittableList::new();
+        for sphere in &spheres {
+            list.add(Box::new(sphere.clone()));
+        }
+        let set = SphereSet::new(spheres);
+        assert_eq!(set.len(), list.len());
+
+        for _ in 0..1000 {
+            let ray = Ray::new(
+                Vec3::random_range(&mut rng, -15.0, 15.0),
+                Vec3::random_in_unit_sphere(&mut rng),
+            );
+            let mut expected = HitRecord::empty();
+            let hit = list.hit(&ray, 0.001, Float::MAX, &mut expected);
+            let mut hit_record = HitRecord::empty();
+            assert_eq!(set.hit(&ray, 0.001, Float::MAX, &mut hit_record), hit);
+            if hit {
+                assert_eq!(hit_record.t, expected.t);
+                assert_eq!(hit_record.object, expected.object);
+            }
+        }
+    }
+}
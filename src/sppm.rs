@@ -0,0 +1,623 @@
+//! Stochastic progressive photon mapping (Hachisuka and Jensen, 2009), for scenes where the
+//! light mostly reaches the camera by diffuse surfaces seen in mirrors or through glass, lit
+//! through glass themselves, which paths from the camera and from the lights hardly ever find.
+//!
+//! Each iteration traces a camera ray per pixel through the specular surfaces it meets, up to
+//! the first diffuse one, the visible point of the pixel, where the direct light is sampled.
+//! Photons traced from the lights then add the light arriving within a radius of the visible
+//! points. The radius of each pixel shrinks with the photons it gathers, so that the image
+//! converges over the iterations.
+
+use crate::float::{Float, PI};
+use crate::framebuffer::Framebuffer;
+use crate::integrator::{crossed_surface, power_heuristic, sample_direct_light};
+use crate::material::{MaterialList, ScatterPdf, ScatterRecord, ScatterType, Scatterable};
+use crate::medium::MediumStack;
+use crate::object::{HitRecord, Hittable};
+use crate::pdf::Pdf;
+use crate::photon_map::emit_photon;
+use crate::ray::Ray;
+use crate::rng::SampleRng;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vec3::{unit_vector, Color, Point3, Vec3};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+/// Radius photons are first gathered within, relative to the diagonal of the box around the
+/// visible points of the first iteration.
+const INITIAL_RADIUS: Float = 0.005;
+/// Fraction of the photons gathered at each iteration kept when shrinking the radius.
+const ALPHA: Float = 2.0 / 3.0;
+
+/// First diffuse surface seen from a pixel.
+#[derive(Clone, Copy)]
+struct VisiblePoint {
+    /// Ray arriving at the point, from the camera or the last specular surface.
+    ray: Ray,
+    hit_record: HitRecord,
+    /// Fraction of the light leaving the point along `-ray` reaching the camera.
+    throughput: Color,
+}
+
+/// Estimate of a pixel, refined over the iterations.
+#[derive(Clone, Copy)]
+struct PixelStats {
+    /// Radius photons are gathered within.
+    radius: Float,
+    /// Photons gathered, discounted as the radius shrinks.
+    photon_count: Float,
+    /// Flux reflected to the camera by the photons gathered, within the current radius.
+    flux: Color,
+    /// Sum of the light reaching the camera other than through the photons.
+    direct: Color,
+}
+
+/// Work handed out to the render threads, the results being sent back with `reply`.
+enum Task {
+    Camera {
+        iteration: u32,
+        reply: mpsc::Sender<Vec<(usize, Color, Option<VisiblePoint>)>>,
+    },
+    Photons {
+        iteration: u32,
+        grid: Arc<Grid>,
+        reply: mpsc::Sender<Vec<(usize, Color)>>,
+    },
+}
+
+/// Renders the image with `settings.samples_per_pixel` iterations of
+/// `settings.photons_per_iteration` photons, on `threads` threads each with its own copy of
+/// the scene, built by `build_scene`.
+///
+/// `on_iteration` is called after each iteration. When it returns false, rendering stops with
+/// the image of the iterations completed so far.
+///
+/// Photons are emitted from the lights with an area, the delta lights and the background only
+/// light the visible points directly. Fails if the scene uses features SPPM doesn't support.
+pub fn render(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    mut on_iteration: impl FnMut() -> bool,
+) -> Result<Framebuffer> {
+    if settings.fog.is_some() {
+        bail!("The SPPM integrator doesn't support fog");
+    }
+    let threads = threads.max(1);
+    let pixel_count = settings.image_width as usize * settings.image_height as usize;
+
+    thread::scope(|scope| {
+        let mut tasks = Vec::with_capacity(threads);
+        for thread_index in 0..threads {
+            let (sender, receiver) = mpsc::channel();
+            tasks.push(sender);
+            scope.spawn(move || {
+                let scene = build_scene();
+                for task in receiver {
+                    let sent = match task {
+                        Task::Camera { iteration, reply } => reply
+                            .send(camera_pass(
+                                &scene,
+                                settings,
+                                iteration,
+                                thread_index,
+                                threads,
+                            ))
+                            .is_ok(),
+                        Task::Photons {
+                            iteration,
+                            grid,
+                            reply,
+                        } => reply
+                            .send(photon_pass(
+                                &scene,
+                                settings,
+                                iteration,
+                                &grid,
+                                thread_index,
+                                threads,
+                            ))
+                            .is_ok(),
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut pixels = vec![
+            PixelStats {
+                radius: 0.0,
+                photon_count: 0.0,
+                flux: Color::zero(),
+                direct: Color::zero(),
+            };
+            pixel_count
+        ];
+        let mut iterations = 0;
+        for iteration in 0..settings.samples_per_pixel as u32 {
+            let (reply, results) = mpsc::channel();
+            for task in &tasks {
+                let reply = reply.clone();
+                // Threads only stop once the tasks are dropped
+                task.send(Task::Camera { iteration, reply }).unwrap();
+            }
+            drop(reply);
+            let mut visible_points = vec![None; pixel_count];
+            for (index, direct, visible_point) in results.into_iter().flatten() {
+                pixels[index].direct += direct;
+                visible_points[index] = visible_point;
+            }
+
+            if iteration == 0 {
+                let radius = initial_radius(&visible_points);
+                for pixel in &mut pixels {
+                    pixel.radius = radius;
+                }
+            }
+            let grid = Arc::new(Grid::new(
+                visible_points
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, point)| point.map(|p| (index, p, pixels[index].radius)))
+                    .collect(),
+            ));
+
+            let (reply, results) = mpsc::channel();
+            for task in &tasks {
+                let (grid, reply) = (grid.clone(), reply.clone());
+                task.send(Task::Photons {
+                    iteration,
+                    grid,
+                    reply,
+                })
+                .unwrap();
+            }
+            drop(reply);
+            let mut gathered = vec![(Color::zero(), 0); pixel_count];
+            for (index, flux) in results.into_iter().flatten() {
+                gathered[index].0 += flux;
+                gathered[index].1 += 1;
+            }
+            for (pixel, (flux, count)) in pixels.iter_mut().zip(gathered) {
+                pixel.gather(flux, count);
+            }
+
+            iterations += 1;
+            if !on_iteration() {
+                break;
+            }
+        }
+        drop(tasks);
+
+        let mut framebuffer = Framebuffer::new(
+            settings.image_width as usize,
+            settings.image_height as usize,
+        );
+        for (index, pixel) in pixels.iter().enumerate() {
+            framebuffer.set_pixel(index, pixel.radiance(iterations), iterations);
+        }
+        Ok(framebuffer)
+    })
+}
+
+impl PixelStats {
+    /// Adds the `flux` of `count` photons gathered in an iteration, shrinking the radius so
+    /// that only a fraction `ALPHA` of them counts as new photons from then on.
+    fn gather(&mut self, flux: Color, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let count = count as Float;
+        let photon_count = self.photon_count + ALPHA * count;
+        let radius = self.radius * (photon_count / (self.photon_count + count)).sqrt();
+        let shrink = radius * radius / (self.radius * self.radius);
+        self.flux = (self.flux + flux) * shrink;
+        self.photon_count = photon_count;
+        self.radius = radius;
+    }
+
+    /// Radiance reaching the camera, averaged over `iterations`.
+    fn radiance(&self, iterations: u32) -> Color {
+        if iterations == 0 {
+            return Color::zero();
+        }
+        let iterations = iterations as Float;
+        let photons = self.flux / (PI * self.radius * self.radius);
+        (self.direct + photons) / iterations
+    }
+}
+
+/// Fraction `INITIAL_RADIUS` of the diagonal of the box around the points, 1 when they don't
+/// spread out.
+fn initial_radius(visible_points: &[Option<VisiblePoint>]) -> Float {
+    let (min, max) = visible_points.iter().flatten().fold(
+        (
+            Point3::new(Float::MAX, Float::MAX, Float::MAX),
+            Point3::new(-Float::MAX, -Float::MAX, -Float::MAX),
+        ),
+        |(min, max), point| {
+            (
+                min.min(&point.hit_record.point),
+                max.max(&point.hit_record.point),
+            )
+        },
+    );
+    let radius = INITIAL_RADIUS * (max - min).length();
+    if radius > 0.0 && radius.is_finite() {
+        radius
+    } else {
+        1.0
+    }
+}
+
+/// Traces the camera rays of the rows of the image handled by the `thread_index`-th of
+/// `threads` threads, returning the light they found directly and the visible point of each
+/// pixel, by index.
+fn camera_pass(
+    scene: &Scene,
+    settings: &RenderSettings,
+    iteration: u32,
+    thread_index: usize,
+    threads: usize,
+) -> Vec<(usize, Color, Option<VisiblePoint>)> {
+    let mut results = Vec::new();
+    for y in (thread_index..settings.image_height as usize).step_by(threads) {
+        let row = settings.image_height - 1 - y as u16;
+        for col in 0..settings.image_width {
+            let index = y * settings.image_width as usize + col as usize;
+            let mut rng = SampleRng::for_sample(settings.seed, index, iteration);
+            let (ray, _, _) = scene.camera_ray(settings, col, row, iteration, &mut rng);
+            let (direct, visible_point) = match ray {
+                Some(ray) => trace_camera_path(scene, settings, ray, &mut rng),
+                None => (Color::zero(), None),
+            };
+            results.push((index, direct, visible_point));
+        }
+    }
+    results
+}
+
+/// Follows `ray` through the specular surfaces up to the first diffuse one, returning the
+/// light reaching the camera from the surfaces met, directly lit for the diffuse one, along
+/// with the diffuse surface.
+fn trace_camera_path(
+    scene: &Scene,
+    settings: &RenderSettings,
+    mut ray: Ray,
+    rng: &mut SampleRng,
+) -> (Color, Option<VisiblePoint>) {
+    let materials = &scene.materials;
+    let mut color = Color::zero();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    let mut media = MediumStack::new();
+
+    for _ in 0..settings.bounce_limit {
+        let mut hit_record = HitRecord::empty();
+        if !scene.world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            color += throughput * scene.background.color(ray.direction());
+            break;
+        }
+
+        let material = &materials[hit_record.material];
+        hit_record.outside_refraction_index = media.outside_of(hit_record.material);
+        color += throughput * material.emitted(&ray, &hit_record);
+
+        let mut scatter_record = ScatterRecord::empty();
+        if !material.scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            break;
+        }
+        let material_pdf = match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => {
+                if let Some(refraction_index) = material.refraction_index() {
+                    if crossed_surface(&hit_record, &specular_ray) {
+                        media.cross(hit_record.material, refraction_index, hit_record.front_face);
+                    }
+                }
+                throughput *= scatter_record.attenuation;
+                ray = specular_ray;
+                continue;
+            }
+            ScatterType::Pdf(material_pdf) => material_pdf,
+        };
+
+        color += throughput
+            * sample_direct_light(
+                rng,
+                &*scene.world,
+                &scene.lights,
+                &scene.delta_lights,
+                materials,
+                &*scene.background,
+                None,
+                &ray,
+                &hit_record,
+                &material_pdf,
+                false,
+            );
+        color += throughput * sample_bsdf_light(scene, &ray, &hit_record, &material_pdf, rng);
+        let visible_point = VisiblePoint {
+            ray,
+            hit_record,
+            throughput,
+        };
+        return (color, Some(visible_point));
+    }
+    (color, None)
+}
+
+/// Direct lighting estimate at `hit_record` from one sample of the BSDF, weighted against
+/// sampling the lights and the background. Light found through specular surfaces is left to
+/// the photons.
+fn sample_bsdf_light(
+    scene: &Scene,
+    in_ray: &Ray,
+    hit_record: &HitRecord,
+    material_pdf: &ScatterPdf,
+    rng: &mut SampleRng,
+) -> Color {
+    let direction = material_pdf.generate(rng);
+    let scattered = Ray::new(hit_record.ray_origin(&direction), direction);
+    let pdf = material_pdf.value(&scattered.direction());
+    if pdf <= 0.0 {
+        return Color::zero();
+    }
+    let bsdf = scene.materials[hit_record.material].eval(in_ray, hit_record, &scattered) / pdf;
+
+    let mut light_record = HitRecord::empty();
+    if !scene
+        .world
+        .hit(&scattered, 0.0, Float::MAX, &mut light_record)
+    {
+        let background_pdf = scene.background.pdf_value(&scattered.direction());
+        let weight = power_heuristic(pdf, background_pdf);
+        return weight * bsdf * scene.background.color(scattered.direction());
+    }
+
+    let emitted = scene.materials[light_record.material].emitted(&scattered, &light_record);
+    let weight = if scene.lights.is_empty() {
+        1.0
+    } else {
+        let light_pdf = scene
+            .lights
+            .pdf_value(&scattered.origin(), &scattered.direction());
+        power_heuristic(pdf, light_pdf)
+    };
+    weight * bsdf * emitted
+}
+
+/// Traces the photons of the iteration handled by the `thread_index`-th of `threads`
+/// threads, returning the flux each brought to the visible points of the `grid`, by pixel.
+fn photon_pass(
+    scene: &Scene,
+    settings: &RenderSettings,
+    iteration: u32,
+    grid: &Grid,
+    thread_index: usize,
+    threads: usize,
+) -> Vec<(usize, Color)> {
+    let lights: Vec<&dyn Hittable> = scene.lights.iter().collect();
+    let pixel_count = settings.image_width as usize * settings.image_height as usize;
+    let mut deposits = Vec::new();
+    for photon_index in (thread_index..settings.photons_per_iteration as usize).step_by(threads) {
+        // Random numbers of their own, apart from the ones of the pixels
+        let mut rng = SampleRng::for_sample(settings.seed, pixel_count + photon_index, iteration);
+        let emitted = emit_photon(
+            &lights,
+            &scene.materials,
+            settings.photons_per_iteration,
+            &mut rng,
+        );
+        if let Some((ray, power)) = emitted {
+            trace_photon(scene, settings, grid, ray, power, &mut rng, &mut deposits);
+        }
+    }
+    deposits
+}
+
+/// Follows a photon leaving a light along `ray` with `power`, adding its flux to the visible
+/// points around each diffuse surface it bounces off, except the first one: light coming
+/// straight from the lights is sampled at the visible points.
+fn trace_photon(
+    scene: &Scene,
+    settings: &RenderSettings,
+    grid: &Grid,
+    mut ray: Ray,
+    mut power: Color,
+    rng: &mut SampleRng,
+    deposits: &mut Vec<(usize, Color)>,
+) {
+    let materials = &scene.materials;
+    let mut media = MediumStack::new();
+    for bounce in 0..settings.bounce_limit {
+        let mut hit_record = HitRecord::empty();
+        if !scene.world.hit(&ray, 0.0, Float::MAX, &mut hit_record) {
+            return;
+        }
+
+        let material = &materials[hit_record.material];
+        hit_record.outside_refraction_index = media.outside_of(hit_record.material);
+        let mut scatter_record = ScatterRecord::empty();
+        if !material.scatter(&ray, &hit_record, &mut scatter_record, rng) {
+            return;
+        }
+        let material_pdf = match scatter_record.scatter_type {
+            ScatterType::Specular(specular_ray) => {
+                if let Some(refraction_index) = material.refraction_index() {
+                    if crossed_surface(&hit_record, &specular_ray) {
+                        media.cross(hit_record.material, refraction_index, hit_record.front_face);
+                    }
+                }
+                power *= scatter_record.attenuation;
+                ray = specular_ray;
+                continue;
+            }
+            ScatterType::Pdf(material_pdf) => material_pdf,
+        };
+
+        let direction = unit_vector(ray.direction());
+        if bounce > 0 {
+            grid.deposit(materials, &hit_record.point, &direction, power, deposits);
+        }
+
+        let scattered_direction = material_pdf.generate(rng);
+        let scattered = Ray::new(
+            hit_record.ray_origin(&scattered_direction),
+            scattered_direction,
+        )
+        .with_channel(ray.channel());
+        let pdf = material_pdf.value(&scattered.direction());
+        if pdf <= 0.0 {
+            return;
+        }
+        power *= material.eval(&ray, &hit_record, &scattered) / pdf;
+        ray = scattered;
+    }
+}
+
+/// Visible points of an iteration, with the pixel they belong to and its radius, in a
+/// uniform grid of cells twice as large as the largest radius.
+struct Grid {
+    points: Vec<(usize, VisiblePoint, Float)>,
+    /// Points whose radius overlaps each cell, by integer coordinates of the cell.
+    cells: HashMap<[i32; 3], Vec<u32>>,
+    cell_size: Float,
+}
+
+impl Grid {
+    fn new(points: Vec<(usize, VisiblePoint, Float)>) -> Grid {
+        let max_radius = points.iter().map(|p| p.2).fold(0.0, Float::max);
+        let mut grid = Grid {
+            points,
+            cells: HashMap::new(),
+            cell_size: 2.0 * max_radius,
+        };
+        for (i, (_, point, radius)) in grid.points.iter().enumerate() {
+            let offset = Vec3::new(*radius, *radius, *radius);
+            let min = grid.cell(&(point.hit_record.point - offset));
+            let max = grid.cell(&(point.hit_record.point + offset));
+            for x in min[0]..=max[0] {
+                for y in min[1]..=max[1] {
+                    for z in min[2]..=max[2] {
+                        grid.cells.entry([x, y, z]).or_default().push(i as u32);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell(&self, point: &Point3) -> [i32; 3] {
+        let coordinate = |c: Float| (c / self.cell_size).floor() as i32;
+        [
+            coordinate(point.x()),
+            coordinate(point.y()),
+            coordinate(point.z()),
+        ]
+    }
+
+    /// Adds the flux reflected to the camera of a photon with `power` arriving at `position`
+    /// along the unit `direction` to the `deposits` of the visible points around.
+    fn deposit(
+        &self,
+        materials: &MaterialList,
+        position: &Point3,
+        direction: &Vec3,
+        power: Color,
+        deposits: &mut Vec<(usize, Color)>,
+    ) {
+        let indices = match self.cells.get(&self.cell(position)) {
+            Some(indices) => indices,
+            None => return,
+        };
+        for &i in indices {
+            let (pixel, point, radius) = &self.points[i as usize];
+            let hit_record = &point.hit_record;
+            if (hit_record.point - *position).length_squared() > radius * radius {
+                continue;
+            }
+            // Photons arriving from the other side of the surface light it up from behind
+            let cosine = -direction.dot(&hit_record.normal);
+            if cosine <= 0.0 {
+                continue;
+            }
+            // The BSDF includes the cosine, which the flux of the photons already accounts for
+            let incoming = Ray::new(hit_record.point, -*direction);
+            let bsdf = materials[hit_record.material].eval(&point.ray, hit_record, &incoming);
+            deposits.push((*pixel, point.throughput * bsdf / cosine * power));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::Integrator;
+    use crate::scenes::BuiltinScene;
+
+    #[test]
+    fn test_gather_shrinks_radius() {
+        let mut pixel = PixelStats {
+            radius: 1.0,
+            photon_count: 0.0,
+            flux: Color::zero(),
+            direct: Color::zero(),
+        };
+        pixel.gather(Color::new(3.0, 3.0, 3.0), 3);
+        assert!((pixel.photon_count - 2.0).abs() < 1e-6);
+        assert!((pixel.radius * pixel.radius - 2.0 / 3.0).abs() < 1e-6);
+        // The photons left out are the ones outside of the smaller disk
+        assert!((pixel.flux.x() - 2.0).abs() < 1e-5);
+
+        // Nothing gathered, nothing changes
+        let before = (pixel.radius, pixel.photon_count);
+        pixel.gather(Color::zero(), 0);
+        assert_eq!((pixel.radius, pixel.photon_count), before);
+    }
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let settings = RenderSettings {
+            scene: BuiltinScene::CornellBox,
+            image_width: 24,
+            image_height: 16,
+            samples_per_pixel: 16,
+            photons_per_iteration: 5_000,
+            integrator: Integrator::Sppm,
+            ..RenderSettings::default()
+        };
+        let build_scene = || settings.scene.build(&settings);
+        let framebuffer = render(&settings, 2, &build_scene, || true).unwrap();
+
+        let scene = build_scene();
+        let mut path_traced = Framebuffer::new(24, 16);
+        for y in 0..16 {
+            for x in 0..24 {
+                let index = path_traced.index(x, y);
+                for s in 0..64 {
+                    let mut rng = SampleRng::for_sample(settings.seed, index, s);
+                    let row = 15 - y as u16;
+                    let (ray, _, _) = scene.camera_ray(&settings, x as u16, row, s, &mut rng);
+                    let color = scene.ray_color(&settings, &ray.unwrap(), &mut rng);
+                    path_traced.add_sample(index, color);
+                }
+            }
+        }
+
+        // Photon mapping blurs the light, only the whole image is compared
+        let mean = |framebuffer: &Framebuffer| {
+            let sum = framebuffer
+                .pixels()
+                .iter()
+                .fold(Color::zero(), |sum, pixel| sum + *pixel);
+            sum / framebuffer.len() as Float
+        };
+        let (sppm, path_traced) = (mean(&framebuffer), mean(&path_traced));
+        assert!((sppm - path_traced).length() < 0.1 * path_traced.length());
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of the work done by the renderer, incremented wherever it happens.
+pub static STATS: Stats = Stats::new();
+
+thread_local! {
+    /// Node traversals and primitive tests counted on the current thread.
+    static THREAD_WORK: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// Node traversals and primitive tests counted on the current thread so far, whose increase
+/// while tracing a ray is the work of finding what it hits.
+pub fn thread_work() -> (u64, u64) {
+    THREAD_WORK.with(Cell::get)
+}
+
+/// Counts of the rays traced, and of the work needed to find what they hit. Acceleration
+/// structures count their work locally and add it once per ray, atomics being slow in their
+/// inner loops.
+pub struct Stats {
+    primary_rays: AtomicU64,
+    secondary_rays: AtomicU64,
+    node_traversals: AtomicU64,
+    primitive_tests: AtomicU64,
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        Stats {
+            primary_rays: AtomicU64::new(0),
+            secondary_rays: AtomicU64::new(0),
+            node_traversals: AtomicU64::new(0),
+            primitive_tests: AtomicU64::new(0),
+        }
+    }
+
+    /// Ray from the camera.
+    pub fn add_primary_ray(&self) {
+        self.primary_rays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Ray scattered by a surface, or shadow ray.
+    pub fn add_secondary_ray(&self) {
+        self.secondary_rays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Nodes of an acceleration structure visited by a ray.
+    pub fn add_node_traversals(&self, count: u64) {
+        if count > 0 {
+            self.node_traversals.fetch_add(count, Ordering::Relaxed);
+            THREAD_WORK.with(|work| {
+                let (nodes, primitives) = work.get();
+                work.set((nodes + count, primitives));
+            });
+        }
+    }
+
+    /// Intersection tests of a ray against objects found by an acceleration structure.
+    pub fn add_primitive_tests(&self, count: u64) {
+        if count > 0 {
+            self.primitive_tests.fetch_add(count, Ordering::Relaxed);
+            THREAD_WORK.with(|work| {
+                let (nodes, primitives) = work.get();
+                work.set((nodes, primitives + count));
+            });
+        }
+    }
+
+    pub fn reset(&self) {
+        self.primary_rays.store(0, Ordering::Relaxed);
+        self.secondary_rays.store(0, Ordering::Relaxed);
+        self.node_traversals.store(0, Ordering::Relaxed);
+        self.primitive_tests.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts so far, for a render which took `elapsed`.
+    pub fn report(&self, elapsed: Duration) -> StatsReport {
+        StatsReport {
+            primary_rays: self.primary_rays.load(Ordering::Relaxed),
+            secondary_rays: self.secondary_rays.load(Ordering::Relaxed),
+            node_traversals: self.node_traversals.load(Ordering::Relaxed),
+            primitive_tests: self.primitive_tests.load(Ordering::Relaxed),
+            elapsed,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+/// Statistics of a finished render, displayed as a summary for the user.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatsReport {
+    pub primary_rays: u64,
+    pub secondary_rays: u64,
+    pub node_traversals: u64,
+    pub primitive_tests: u64,
+    pub elapsed: Duration,
+}
+
+impl StatsReport {
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds <= 0.0 {
+            return 0.0;
+        }
+        self.rays() as f64 / seconds
+    }
+
+    /// Single JSON object, for benchmarking scripts to compare renders.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"rays\": {}, \"primary_rays\": {}, \"secondary_rays\": {}, \
+             \"node_traversals\": {}, \"primitive_tests\": {}, \"seconds\": {:.3}, \
+             \"rays_per_second\": {:.0}}}",
+            self.rays(),
+            self.primary_rays,
+            self.secondary_rays,
+            self.node_traversals,
+            self.primitive_tests,
+            self.elapsed.as_secs_f64(),
+            self.rays_per_second()
+        )
+    }
+
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_json() + "\n")
+            .with_context(|| format!("Failed to write statistics to {}", path.display()))
+    }
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let per_ray = |count: u64| count as f64 / self.rays().max(1) as f64;
+        writeln!(
+            f,
+            "Rays traced:      {} ({} primary, {} secondary)",
+            self.rays(),
+            self.primary_rays,
+            self.secondary_rays
+        )?;
+        writeln!(
+            f,
+            "Node traversals:  {} ({:.1} per ray)",
+            self.node_traversals,
+            per_ray(self.node_traversals)
+        )?;
+        writeln!(
+            f,
+            "Primitive tests:  {} ({:.1} per ray)",
+            self.primitive_tests,
+            per_ray(self.primitive_tests)
+        )?;
+        write!(
+            f,
+            "Render time:      {:.2}s ({:.2} Mrays/s)",
+            self.elapsed.as_secs_f64(),
+            self.rays_per_second() / 1e6
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let stats = Stats::new();
+        for _ in 0..3 {
+            stats.add_primary_ray();
+        }
+        stats.add_secondary_ray();
+        stats.add_node_traversals(1);
+        stats.add_primitive_tests(1);
+
+        let report = stats.report(Duration::from_secs(2));
+        assert_eq!(report.rays(), 4);
+        assert_eq!(report.rays_per_second(), 2.0);
+        assert_eq!(
+            report.to_json(),
+            "{\"rays\": 4, \"primary_rays\": 3, \"secondary_rays\": 1, \"node_traversals\": 1, \
+             \"primitive_tests\": 1, \"seconds\": 2.000, \"rays_per_second\": 2}"
+        );
+
+        stats.reset();
+        assert_eq!(stats.report(Duration::from_secs(1)).rays(), 0);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::float::Float;
+use crate::vec3::{Color, Point3};
+use anyhow::{Context, Result};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Color varying over a surface, looked up with the surface coordinates of the hit.
+#[derive(Clone, Debug)]
+pub enum Texture {
+    Solid(Color),
+    /// Shared, so that materials using the same image don't each hold a copy of it.
+    Image(Arc<ImageTexture>),
+    Checker(Checker),
+    Ramp(Ramp),
+}
+
+impl Texture {
+    pub fn value(&self, u: Float, v: Float, point: &Point3) -> Color {
+        match *self {
+            Texture::Solid(color) => color,
+            Texture::Image(ref inner) => inner.value(u, v, point),
+            Texture::Checker(ref inner) => inner.value(point),
+            Texture::Ramp(ref inner) => inner.value(u),
+        }
+    }
+
+    /// Average of the channels, for textures driving a single parameter.
+    pub fn scalar(&self, u: Float, v: Float, point: &Point3) -> Float {
+        let value = self.value(u, v, point);
+        (value.x() + value.y() + value.z()) / 3.0
+    }
+}
+
+/// Image mapped once over the [0, 1] surface coordinates, v = 0 being the bottom row, and
+/// repeated outside of that range.
+#[derive(Debug)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> ImageTexture {
+        assert_eq!(width * height, pixels.len());
+        ImageTexture {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Loads any image format supported by the `image` crate. The values are used as stored,
+    /// without decoding the sRGB transfer function.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ImageTexture> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load texture {}", path.display()))?
+            .into_rgb32f();
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .pixels()
+            .map(|p| Color::new(p[0] as Float, p[1] as Float, p[2] as Float))
+            .collect();
+
+        Ok(ImageTexture::new(width, height, pixels))
+    }
+
+    /// Loads the opacity stored in an image, for cutouts: its alpha channel, or without one the
+    /// average of its channels, black being transparent. The opacity fills all the channels of
+    /// the texture.
+    pub fn load_opacity<P: AsRef<Path>>(path: P) -> Result<ImageTexture> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load opacity map {}", path.display()))?;
+        let has_alpha = image.color().has_alpha();
+        let image = image.into_rgba32f();
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .pixels()
+            .map(|p| {
+                let opacity = if has_alpha {
+                    p[3]
+                } else {
+                    (p[0] + p[1] + p[2]) / 3.0
+                } as Float;
+                Color::new(opacity, opacity, opacity)
+            })
+            .collect();
+
+        Ok(ImageTexture::new(width, height, pixels))
+    }
+
+    /// Bytes taken by the pixels.
+    pub fn memory_size(&self) -> usize {
+        self.pixels.len() * mem::size_of::<Color>()
+    }
+
+    pub fn value(&self, u: Float, v: Float, _point: &Point3) -> Color {
+        let u = u - u.floor();
+        let v = v - v.floor();
+        let col = ((u * self.width as Float) as usize).min(self.width - 1);
+        let row = (((1.0 - v) * self.height as Float) as usize).min(self.height - 1);
+        self.pixels[row * self.width + col]
+    }
+}
+
+/// Checkerboard filling space with cubes of alternating colors, so that it doesn't depend on
+/// the surface coordinates of the objects.
+#[derive(Clone, Copy, Debug)]
+pub struct Checker {
+    even: Color,
+    odd: Color,
+    /// Side of the cubes.
+    size: Float,
+}
+
+impl Checker {
+    pub fn new(even: Color, odd: Color, size: Float) -> Checker {
+        Checker { even, odd, size }
+    }
+
+    pub fn value(&self, point: &Point3) -> Color {
+        let cell = |c: Float| (c / self.size).floor() as i64;
+        if (cell(point.x()) + cell(point.y()) + cell(point.z())).rem_euclid(2) == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+/// Colors blended along u, for the objects storing a value to color them by there, like the
+/// iterations of the fractals. The colors are spread evenly over [0, 1], u being clamped to it.
+#[derive(Clone, Debug)]
+pub struct Ramp {
+    colors: Vec<Color>,
+}
+
+impl Ramp {
+    pub fn new(colors: Vec<Color>) -> Ramp {
+        assert!(!colors.is_empty(), "A ramp needs at least one color");
+        Ramp { colors }
+    }
+
+    pub fn value(&self, u: Float) -> Color {
+        let position = u.clamp(0.0, 1.0) * (self.colors.len() - 1) as Float;
+        let i = (position as usize).min(self.colors.len() - 1);
+        let j = (i + 1).min(self.colors.len() - 1);
+        let t = position - i as Float;
+        (1.0 - t) * self.colors[i] + t * self.colors[j]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_lookup() {
+        // Top row red, bottom row blue
+        let red = Color::new(1.0, 0.0, 0.0);
+        let blue = Color::new(0.0, 0.0, 1.0);
+        let texture = ImageTexture::new(1, 2, vec![red, blue]);
+        let p = Point3::zero();
+        assert_eq!(texture.value(0.5, 0.9, &p), red);
+        assert_eq!(texture.value(0.5, 0.1, &p), blue);
+        // Repeated outside of [0, 1]
+        assert_eq!(texture.value(1.5, 1.1, &p), blue);
+    }
+
+    #[test]
+    fn test_checker() {
+        let black = Color::zero();
+        let white = Color::new(1.0, 1.0, 1.0);
+        let checker = Checker::new(black, white, 2.0);
+        assert_eq!(checker.value(&Point3::new(0.5, 0.5, 0.5)), black);
+        assert_eq!(checker.value(&Point3::new(2.5, 0.5, 0.5)), white);
+        assert_eq!(checker.value(&Point3::new(-0.5, 0.5, 0.5)), white);
+        assert_eq!(checker.value(&Point3::new(-0.5, -0.5, 0.5)), black);
+    }
+
+    #[test]
+    fn test_ramp() {
+        let red = Color::new(1.0, 0.0, 0.0);
+        let green = Color::new(0.0, 1.0, 0.0);
+        let blue = Color::new(0.0, 0.0, 1.0);
+        let ramp = Ramp::new(vec![red, green, blue]);
+        assert_eq!(ramp.value(0.0), red);
+        assert_eq!(ramp.value(0.5), green);
+        assert_eq!(ramp.value(1.0), blue);
+        assert_eq!(ramp.value(0.25), Color::new(0.5, 0.5, 0.0));
+        assert_eq!(ramp.value(-1.0), red);
+        assert_eq!(ramp.value(2.0), blue);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::float::Float;
+use crate::vec3::Color;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Operator used to compress the linear HDR radiance into the displayable [0, 1] range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapper {
+    /// Only scale by the exposure, values above 1.0 are clipped.
+    Exposure,
+    /// Per-channel Reinhard operator: c / (1 + c).
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    AcesFilmic,
+}
+
+impl ToneMapper {
+    pub fn apply(&self, color: Color, exposure: Float) -> Color {
+        let color = exposure * color;
+        match *self {
+            ToneMapper::Exposure => color,
+            ToneMapper::Reinhard => map_channels(color, |c| c / (1.0 + c)),
+            ToneMapper::AcesFilmic => map_channels(color, aces_filmic),
+        }
+    }
+}
+
+/// Scale of the radiance before tone mapping, in stops (EV): each stop doubles it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exposure {
+    /// Fixed number of stops, 0 leaving the radiance unchanged.
+    Manual(Float),
+    /// Brings the log-average luminance of the image to middle grey, then adds the stops.
+    Auto(Float),
+}
+
+impl Exposure {
+    /// Grey reflecting 18% of the light, the luminance auto-exposure aims for.
+    const MIDDLE_GREY: Float = 0.18;
+
+    /// Factor to multiply the radiance of `pixels` by.
+    pub fn scale(&self, pixels: &[Color]) -> Float {
+        match *self {
+            Exposure::Manual(stops) => stops.exp2(),
+            Exposure::Auto(stops) => {
+                let average = log_average_luminance(pixels);
+                if average > 0.0 {
+                    Exposure::MIDDLE_GREY / average * stops.exp2()
+                } else {
+                    stops.exp2()
+                }
+            }
+        }
+    }
+}
+
+/// Relative luminance of a linear sRGB color.
+pub fn luminance(color: Color) -> Float {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
+
+/// Geometric mean of the luminance of the pixels, which unlike the arithmetic mean isn't
+/// dominated by a few very bright ones such as the light sources. Black pixels, where nothing
+/// is seen, and non-finite ones are left out. Zero if there is none left.
+pub fn log_average_luminance(pixels: &[Color]) -> Float {
+    let (sum, count) = pixels
+        .iter()
+        .map(|p| luminance(*p))
+        .filter(|l| *l > 0.0 && l.is_finite())
+        .fold((0.0 as Float, 0), |(sum, count), l| {
+            (sum + l.ln(), count + 1)
+        });
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as Float).exp()
+}
+
+/// Encoding of the linear displayable colors into the values stored in the output image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferFunction {
+    /// Piecewise sRGB curve, linear near black then a 2.4 power.
+    Srgb,
+    /// Plain power curve 1 / gamma.
+    Gamma(Float),
+}
+
+impl TransferFunction {
+    pub fn encode(&self, color: Color) -> Color {
+        match *self {
+            TransferFunction::Srgb => map_channels(color, srgb_oetf),
+            TransferFunction::Gamma(gamma) => map_channels(color, |c| c.max(0.0).powf(1.0 / gamma)),
+        }
+    }
+
+    /// Linear color of the stored `color`, inverse of `encode`.
+    pub fn decode(&self, color: Color) -> Color {
+        match *self {
+            TransferFunction::Srgb => map_channels(color, srgb_eotf),
+            TransferFunction::Gamma(gamma) => map_channels(color, |c| c.max(0.0).powf(gamma)),
+        }
+    }
+}
+
+impl FromStr for TransferFunction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TransferFunction> {
+        if s == "srgb" {
+            return Ok(TransferFunction::Srgb);
+        }
+        match s.parse::<Float>() {
+            Ok(gamma) if gamma > 0.0 => Ok(TransferFunction::Gamma(gamma)),
+            _ => bail!(
+                "Unknown transfer function '{}', expected srgb or a gamma value",
+                s
+            ),
+        }
+    }
+}
+
+impl FromStr for ToneMapper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ToneMapper> {
+        match s {
+            "exposure" => Ok(ToneMapper::Exposure),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "aces" => Ok(ToneMapper::AcesFilmic),
+            _ => bail!(
+                "Unknown tone mapper '{}', expected one of: exposure, reinhard, aces",
+                s
+            ),
+        }
+    }
+}
+
+fn srgb_oetf(c: Float) -> Float {
+    if c <= 0.003_130_8 {
+        12.92 * c.max(0.0)
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn srgb_eotf(c: Float) -> Float {
+    if c <= 0.040_45 {
+        c.max(0.0) / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn aces_filmic(x: Float) -> Float {
+    const A: Float = 2.51;
+    const B: Float = 0.03;
+    const C: Float = 2.43;
+    const D: Float = 0.59;
+    const E: Float = 0.14;
+    (x * (A * x + B)) / (x * (C * x + D) + E)
+}
+
+fn map_channels<F: Fn(Float) -> Float>(color: Color, f: F) -> Color {
+    Color::new(f(color.x()), f(color.y()), f(color.z()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposure() {
+        let c = Color::new(0.1, 0.2, 0.4);
+        assert_eq!(
+            ToneMapper::Exposure.apply(c, 2.0),
+            Color::new(0.2, 0.4, 0.8)
+        );
+    }
+
+    #[test]
+    fn test_manual_exposure() {
+        assert_eq!(Exposure::Manual(0.0).scale(&[]), 1.0);
+        assert_eq!(Exposure::Manual(2.0).scale(&[]), 4.0);
+        assert_eq!(Exposure::Manual(-1.0).scale(&[]), 0.5);
+    }
+
+    #[test]
+    fn test_auto_exposure() {
+        // Geometric mean of 0.01 and 1.0, the black and invalid pixels being left out
+        let pixels = [
+            Color::new(0.01, 0.01, 0.01),
+            Color::new(1.0, 1.0, 1.0),
+            Color::zero(),
+            Color::new(Float::NAN, 0.0, 0.0),
+        ];
+        assert!((log_average_luminance(&pixels) - 0.1).abs() < 1e-5);
+        assert!((Exposure::Auto(0.0).scale(&pixels) - 1.8).abs() < 1e-3);
+        assert!((Exposure::Auto(1.0).scale(&pixels) - 3.6).abs() < 1e-3);
+
+        // Scaling the image doesn't change the result
+        let dim: Vec<Color> = pixels.iter().map(|p| 0.01 * *p).collect();
+        let scaled = 0.01 * Exposure::Auto(0.0).scale(&dim);
+        assert!((scaled - Exposure::Auto(0.0).scale(&pixels)).abs() < 1e-3);
+
+        assert_eq!(Exposure::Auto(0.0).scale(&[]), 1.0);
+    }
+
+    #[test]
+    fn test_reinhard() {
+        let c = Color::new(1.0, 3.0, 0.0);
+        assert_eq!(
+            ToneMapper::Reinhard.apply(c, 1.0),
+            Color::new(0.5, 0.75, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_srgb() {
+        let c = TransferFunction::Srgb.encode(Color::new(0.0, 0.001, 1.0));
+        assert_eq!(c.x(), 0.0);
+        assert!((c.y() - 0.01292).abs() < 1e-6);
+        assert!((c.z() - 1.0).abs() < 1e-6);
+        // Mid grey
+        let c = TransferFunction::Srgb.encode(Color::new(0.18, 0.18, 0.18));
+        assert!((c.x() - 0.4613).abs() < 1e-3);
+        // Back to linear
+        for transfer in [TransferFunction::Srgb, TransferFunction::Gamma(2.2)] {
+            for &value in &[0.001, 0.18, 0.9] {
+                let c = transfer.decode(transfer.encode(Color::new(value, value, value)));
+                assert!((c.x() - value).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_transfer_function() {
+        assert_eq!(
+            "srgb".parse::<TransferFunction>().unwrap(),
+            TransferFunction::Srgb
+        );
+        assert_eq!(
+            "2.2".parse::<TransferFunction>().unwrap(),
+            TransferFunction::Gamma(2.2)
+        );
+        assert!("-1".parse::<TransferFunction>().is_err());
+    }
+
+    #[test]
+    fn test_parse_tone_mapper() {
+        assert_eq!(
+            "aces".parse::<ToneMapper>().unwrap(),
+            ToneMapper::AcesFilmic
+        );
+        assert_eq!(
+            "reinhard".parse::<ToneMapper>().unwrap(),
+            ToneMapper::Reinhard
+        );
+        assert!("filmic".parse::<ToneMapper>().is_err());
+    }
+
+    #[test]
+    fn test_aces_filmic_range() {
+        let c = ToneMapper::AcesFilmic.apply(Color::new(0.0, 1.0, 1000.0), 1.0);
+        assert!(c.x().abs() < 1e-6);
+        assert!(c.y() > 0.0 && c.y() < 1.0);
+        assert!(c.z() < 1.05);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::aabb::Aabb;
+use crate::float::{Float, PI};
+use crate::material::MaterialId;
+use crate::object::{rounding_error, HitRecord, Hittable};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::util::solve_quartic;
+use crate::vec3::{unit_vector, Point3, Vec3};
+
+/// Torus made by sweeping a circle of radius `minor_radius` around `axis`, at a distance
+/// `major_radius` from `center`.
+#[derive(Clone)]
+pub struct Torus {
+    center: Point3,
+    major_radius: Float,
+    minor_radius: Float,
+    /// Local frame, `w` being the axis of revolution.
+    uvw: Onb,
+    material: MaterialId,
+}
+
+impl Torus {
+    pub fn new(
+        center: Point3,
+        axis: Vec3,
+        major_radius: Float,
+        minor_radius: Float,
+        material: MaterialId,
+    ) -> Torus {
+        Torus {
+            center,
+            major_radius,
+            minor_radius,
+            uvw: Onb::build_from_w(&axis),
+            material,
+        }
+    }
+}
+
+impl Hittable for Torus {
+    // The conversions to and from f64 are no-ops with the `f64` feature
+    #[allow(clippy::unnecessary_cast)]
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let origin = self.uvw.to_local(&(ray.origin() - self.center));
+        let direction = self.uvw.to_local(&ray.direction());
+
+        // Cheap rejection against the bounding sphere
+        let bounding_radius = self.major_radius + self.minor_radius;
+        let half_b = origin.dot(&direction);
+        let a = direction.length_squared();
+        let c = origin.length_squared() - bounding_radius * bounding_radius;
+        if half_b * half_b - a * c < 0.0 {
+            return false;
+        }
+
+        // Solve with a unit direction in f64, the quartic being badly conditioned otherwise:
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (p.x^2 + p.y^2)
+        let direction_length = direction.length() as f64;
+        let (ox, oy, oz) = (origin.x() as f64, origin.y() as f64, origin.z() as f64);
+        let (dx, dy, dz) = (
+            direction.x() as f64 / direction_length,
+            direction.y() as f64 / direction_length,
+            direction.z() as f64 / direction_length,
+        );
+        let sq_major = (self.major_radius as f64).powi(2);
+        let sq_minor = (self.minor_radius as f64).powi(2);
+
+        let od = ox * dx + oy * dy + oz * dz;
+        let k = ox * ox + oy * oy + oz * oz + sq_major - sq_minor;
+        let roots = solve_quartic([
+            k * k - 4.0 * sq_major * (ox * ox + oy * oy),
+            4.0 * od * k - 8.0 * sq_major * (ox * dx + oy * dy),
+            2.0 * k + 4.0 * od * od - 4.0 * sq_major * (dx * dx + dy * dy),
+            4.0 * od,
+            1.0,
+        ]);
+
+        let t = roots
+            .iter()
+            .map(|&s| (s / direction_length) as Float)
+            .filter(|&t| t >= t_min && t <= t_max)
+            .fold(Float::INFINITY, Float::min);
+        if t == Float::INFINITY {
+            return false;
+        }
+
+        // The normal points away from the closest point of the center circle
+        let p = origin + t * direction;
+        let ring_point = self.major_radius * unit_vector(Vec3::new(p.x(), p.y(), 0.0));
+        let local_normal = unit_vector(p - ring_point);
+
+        hit_record.t = t;
+        // Reprojected onto the tube, the roots of the quartic being inaccurate
+        hit_record.point = self.center
+            + self
+                .uvw
+                .local(&(ring_point + self.minor_radius * local_normal));
+        hit_record.set_face_normal(ray, &self.uvw.local(&local_normal));
+        hit_record.point_error = rounding_error(self.major_radius + self.minor_radius);
+        hit_record.u = (p.y().atan2(p.x()) + PI) / (2.0 * PI);
+        hit_record.v = (local_normal
+            .z()
+            .atan2(ring_point.dot(&local_normal) / self.major_radius)
+            + PI)
+            / (2.0 * PI);
+        hit_record.tangent = self.uvw.local(&Vec3::new(-p.y(), p.x(), 0.0));
+        hit_record.material = self.material;
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::disk(self.center, self.uvw.w(), self.major_radius).padded(self.minor_radius))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torus() -> Torus {
+        let material = MaterialId::default();
+        Torus::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            2.0,
+            0.5,
+            material,
+        )
+    }
+
+    #[test]
+    fn test_hit_outer_side() {
+        let mut hit_record = HitRecord::empty();
+        let ray = Ray::new(Point3::new(10.0, 0.0, 0.0), Vec3::new(-2.0, 0.0, 0.0));
+        assert!(torus().hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.point - Point3::new(2.5, 0.0, 0.0)).length() < 1e-4);
+        assert!((hit_record.normal - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-4);
+        assert!(hit_record.front_face);
+    }
+
+    #[test]
+    fn test_hole() {
+        let mut hit_record = HitRecord::empty();
+        // Straight down the axis, through the hole
+        let ray = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(!torus().hit(&ray, 0.001, Float::MAX, &mut hit_record));
+
+        // Straight down onto the tube
+        let ray = Ray::new(Point3::new(0.0, 10.0, 2.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(torus().hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert!((hit_record.t - 9.5).abs() < 1e-4);
+        assert!((hit_record.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-4);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::vec3::{unit_vector, Point3, Vec3};
+
+/// Affine transformation: a linear map, stored by rows, followed by a translation. The inverse
+/// is kept alongside, normals being transformed by its transpose.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    rows: [Vec3; 3],
+    translation: Vec3,
+    inverse_rows: [Vec3; 3],
+    inverse_translation: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform::linear([
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ])
+    }
+
+    pub fn translation(offset: Vec3) -> Transform {
+        Transform {
+            translation: offset,
+            inverse_translation: -offset,
+            ..Transform::identity()
+        }
+    }
+
+    /// Scales by a different factor along each axis, none of them zero.
+    pub fn scaling(factors: Vec3) -> Transform {
+        Transform::linear([
+            Vec3::new(factors.x(), 0.0, 0.0),
+            Vec3::new(0.0, factors.y(), 0.0),
+            Vec3::new(0.0, 0.0, factors.z()),
+        ])
+    }
+
+    /// Rotation of `angle_deg` around `axis`, counterclockwise when the axis points towards the
+    /// viewer.
+    pub fn rotation(axis: Vec3, angle_deg: Float) -> Transform {
+        let a = unit_vector(axis);
+        let (sin, cos) = angle_deg.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        Transform::linear([
+            Vec3::new(
+                t * a.x() * a.x() + cos,
+                t * a.x() * a.y() - sin * a.z(),
+                t * a.x() * a.z() + sin * a.y(),
+            ),
+            Vec3::new(
+                t * a.x() * a.y() + sin * a.z(),
+                t * a.y() * a.y() + cos,
+                t * a.y() * a.z() - sin * a.x(),
+            ),
+            Vec3::new(
+                t * a.x() * a.z() - sin * a.y(),
+                t * a.y() * a.z() + sin * a.x(),
+                t * a.z() * a.z() + cos,
+            ),
+        ])
+    }
+
+    /// Linear transformation of the matrix with the given rows, which must be invertible.
+    pub fn linear(rows: [Vec3; 3]) -> Transform {
+        Transform {
+            rows,
+            translation: Vec3::zero(),
+            inverse_rows: invert(&rows),
+            inverse_translation: Vec3::zero(),
+        }
+    }
+
+    /// Applies this transformation, then `other`.
+    pub fn then(&self, other: &Transform) -> Transform {
+        let rows = multiply(&other.rows, &self.rows);
+        let inverse_rows = multiply(&self.inverse_rows, &other.inverse_rows);
+        Transform {
+            rows,
+            translation: other.transform_point(&self.translation),
+            inverse_rows,
+            inverse_translation: self.transform_inverse_point(&other.inverse_translation),
+        }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            rows: self.inverse_rows,
+            translation: self.inverse_translation,
+            inverse_rows: self.rows,
+            inverse_translation: self.translation,
+        }
+    }
+
+    pub fn transform_point(&self, p: &Point3) -> Point3 {
+        self.transform_vector(p) + self.translation
+    }
+
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.rows[0].dot(v),
+            self.rows[1].dot(v),
+            self.rows[2].dot(v),
+        )
+    }
+
+    /// Transforms a normal, by the inverse transpose so that it stays orthogonal to the
+    /// transformed surface. The result isn't normalized.
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let r = &self.inverse_rows;
+        n.x() * r[0] + n.y() * r[1] + n.z() * r[2]
+    }
+
+    /// Box enclosing the transformed corners of `aabb`.
+    pub fn transform_aabb(&self, aabb: &Aabb) -> Aabb {
+        if aabb.is_empty() {
+            return *aabb;
+        }
+        let (min, max) = (aabb.min(), aabb.max());
+        (0..8).fold(Aabb::empty(), |result, corner| {
+            let p = Point3::new(
+                if corner & 1 == 0 { min.x() } else { max.x() },
+                if corner & 2 == 0 { min.y() } else { max.y() },
+                if corner & 4 == 0 { min.z() } else { max.z() },
+            );
+            result.grow(&self.transform_point(&p))
+        })
+    }
+
+    fn transform_inverse_point(&self, p: &Point3) -> Point3 {
+        let r = &self.inverse_rows;
+        Vec3::new(r[0].dot(p), r[1].dot(p), r[2].dot(p)) + self.inverse_translation
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+/// Product of two matrices given by rows.
+fn multiply(a: &[Vec3; 3], b: &[Vec3; 3]) -> [Vec3; 3] {
+    let columns = [
+        Vec3::new(b[0].x(), b[1].x(), b[2].x()),
+        Vec3::new(b[0].y(), b[1].y(), b[2].y()),
+        Vec3::new(b[0].z(), b[1].z(), b[2].z()),
+    ];
+    let row = |r: &Vec3| Vec3::new(r.dot(&columns[0]), r.dot(&columns[1]), r.dot(&columns[2]));
+    [row(&a[0]), row(&a[1]), row(&a[2])]
+}
+
+/// Inverse of a matrix given by rows, from its adjugate.
+fn invert(m: &[Vec3; 3]) -> [Vec3; 3] {
+    // The columns of the inverse are the cross products of the rows, over the determinant
+    let c0 = m[1].cross(&m[2]);
+    let c1 = m[2].cross(&m[0]);
+    let c2 = m[0].cross(&m[1]);
+    let determinant = m[0].dot(&c0);
+    assert!(determinant != 0.0, "Transform isn't invertible");
+    let d = 1.0 / determinant;
+    [
+        d * Vec3::new(c0.x(), c1.x(), c2.x()),
+        d * Vec3::new(c0.y(), c1.y(), c2.y()),
+        d * Vec3::new(c0.z(), c1.z(), c2.z()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_compose_and_invert() {
+        let transform = Transform::scaling(Vec3::new(2.0, 1.0, 1.0))
+            .then(&Transform::rotation(Vec3::new(0.0, 0.0, 1.0), 90.0))
+            .then(&Transform::translation(Vec3::new(0.0, 0.0, 3.0)));
+        let p = Point3::new(1.0, 1.0, 0.0);
+        // Scaled to (2, 1, 0), rotated to (-1, 2, 0), moved up
+        let moved = transform.transform_point(&p);
+        assert_near(moved, Point3::new(-1.0, 2.0, 3.0));
+        assert_near(transform.inverse().transform_point(&moved), p);
+        assert_near(
+            transform.transform_vector(&Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, 2.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_normals_stay_orthogonal() {
+        let transform = Transform::scaling(Vec3::new(4.0, 1.0, 1.0))
+            .then(&Transform::rotation(Vec3::new(1.0, 1.0, 0.0), 30.0));
+        let tangent = Vec3::new(1.0, 1.0, 0.0);
+        let normal = Vec3::new(-1.0, 1.0, 0.0);
+        let dot = transform
+            .transform_vector(&tangent)
+            .dot(&transform.transform_normal(&normal));
+        assert!(dot.abs() < 1e-5);
+    }
+}
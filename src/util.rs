@@ -1,4 +1,5 @@
-pub fn clamp(x: f32, min: f32, max: f32) -> f32 {
+use crate::float::Float;
+pub fn clamp(x: Float, min: Float, max: Float) -> Float {
     if x < min {
         return min;
     }
@@ -7,3 +8,140 @@ pub fn clamp(x: f32, min: f32, max: f32) -> f32 {
     }
     x
 }
+
+const EPSILON: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EPSILON
+}
+
+/// Real roots of `c[0] + c[1] x + c[2] x^2`, `c[2]` being non-zero.
+fn solve_quadratic(c: [f64; 3]) -> Vec<f64> {
+    let p = c[1] / (2.0 * c[2]);
+    let q = c[0] / c[2];
+    let discriminant = p * p - q;
+
+    if is_zero(discriminant) {
+        vec![-p]
+    } else if discriminant < 0.0 {
+        vec![]
+    } else {
+        let sqrt_discriminant = discriminant.sqrt();
+        vec![sqrt_discriminant - p, -sqrt_discriminant - p]
+    }
+}
+
+/// Real roots of `c[0] + c[1] x + c[2] x^2 + c[3] x^3`, `c[3]` being non-zero (Cardano).
+fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let c = c[0] / c[3];
+
+    // Substitute x = y - a/3 to get y^3 + p y + q = 0
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+    let cb_p = p * p * p;
+    let discriminant = q * q + cb_p;
+
+    let mut roots = if is_zero(discriminant) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if discriminant < 0.0 {
+        // Three real roots
+        let phi = (-q / (-cb_p).sqrt()).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        vec![
+            t * phi.cos(),
+            -t * (phi + std::f64::consts::PI / 3.0).cos(),
+            -t * (phi - std::f64::consts::PI / 3.0).cos(),
+        ]
+    } else {
+        let sqrt_discriminant = discriminant.sqrt();
+        vec![(sqrt_discriminant - q).cbrt() - (sqrt_discriminant + q).cbrt()]
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 3.0;
+    }
+    roots
+}
+
+/// Real roots of `c[0] + c[1] x + c[2] x^2 + c[3] x^3 + c[4] x^4`, `c[4]` being non-zero.
+///
+/// Uses Ferrari's method, then polishes each root with a few Newton iterations since the
+/// closed form loses a lot of precision.
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    let a = c[3] / c[4];
+    let b = c[2] / c[4];
+    let c_ = c[1] / c[4];
+    let d = c[0] / c[4];
+
+    // Substitute x = y - a/4 to get y^4 + p y^2 + q y + r = 0
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c_;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c_ / 4.0 + d;
+
+    let mut roots = if is_zero(r) {
+        // y (y^3 + p y + q) = 0
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        // Any root of the resolvent cubic splits the quartic into two quadratics
+        let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        if (u < 0.0 && !is_zero(u)) || (v < 0.0 && !is_zero(v)) {
+            return vec![];
+        }
+        let u = u.max(0.0).sqrt();
+        let v = if q < 0.0 {
+            -v.max(0.0).sqrt()
+        } else {
+            v.max(0.0).sqrt()
+        };
+
+        let mut roots = solve_quadratic([z - u, v, 1.0]);
+        roots.extend(solve_quadratic([z + u, -v, 1.0]));
+        roots
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+        for _ in 0..2 {
+            let value = (((c[4] * *root + c[3]) * *root + c[2]) * *root + c[1]) * *root + c[0];
+            let derivative =
+                ((4.0 * c[4] * *root + 3.0 * c[3]) * *root + 2.0 * c[2]) * *root + c[1];
+            if derivative != 0.0 {
+                *root -= value / derivative;
+            }
+        }
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve_quartic() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        let mut roots = solve_quartic([24.0, -50.0, 35.0, -10.0, 1.0]);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(roots.len(), 4);
+        for (root, expected) in roots.iter().zip(&[1.0, 2.0, 3.0, 4.0]) {
+            assert!((root - expected).abs() < 1e-9);
+        }
+
+        // x^4 + 1 has no real root
+        assert!(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]).is_empty());
+    }
+}
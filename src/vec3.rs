@@ -1,9 +1,9 @@
-use rand::rngs::ThreadRng;
+use crate::float::{Float, PI};
 use rand::Rng;
 use std::ops;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub struct Vec3(f32, f32, f32);
+pub struct Vec3(Float, Float, Float);
 
 pub type Point3 = Vec3;
 pub type Color = Vec3;
@@ -13,31 +13,31 @@ impl Vec3 {
         Vec3(0.0, 0.0, 0.0)
     }
 
-    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+    pub fn new(x: Float, y: Float, z: Float) -> Vec3 {
         Vec3(x, y, z)
     }
 
-    pub fn x(&self) -> f32 {
+    pub fn x(&self) -> Float {
         self.0
     }
 
-    pub fn y(&self) -> f32 {
+    pub fn y(&self) -> Float {
         self.1
     }
 
-    pub fn z(&self) -> f32 {
+    pub fn z(&self) -> Float {
         self.2
     }
 
-    pub fn length_squared(&self) -> f32 {
+    pub fn length_squared(&self) -> Float {
         self.0 * self.0 + self.1 * self.1 + self.2 * self.2
     }
 
-    pub fn length(&self) -> f32 {
+    pub fn length(&self) -> Float {
         self.length_squared().sqrt()
     }
 
-    pub fn dot(&self, other: &Vec3) -> f32 {
+    pub fn dot(&self, other: &Vec3) -> Float {
         self.0 * other.0 + self.1 * other.1 + self.2 * other.2
     }
 
@@ -49,7 +49,7 @@ impl Vec3 {
         )
     }
 
-    pub fn random(rng: &mut ThreadRng) -> Vec3 {
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
         Vec3(
             rng.gen_range(0.0..1.0),
             rng.gen_range(0.0..1.0),
@@ -57,7 +57,7 @@ impl Vec3 {
         )
     }
 
-    pub fn random_range(rng: &mut ThreadRng, min: f32, max: f32) -> Vec3 {
+    pub fn random_range<R: Rng + ?Sized>(rng: &mut R, min: Float, max: Float) -> Vec3 {
         Vec3(
             rng.gen_range(min..max),
             rng.gen_range(min..max),
@@ -65,7 +65,7 @@ impl Vec3 {
         )
     }
 
-    pub fn random_in_unit_sphere(rng: &mut ThreadRng) -> Vec3 {
+    pub fn random_in_unit_sphere<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
         loop {
             let v = Self::random_range(rng, -1.0, 1.0);
             if v.length_squared() < 1.0 {
@@ -74,11 +74,11 @@ impl Vec3 {
         }
     }
 
-    pub fn random_unit_vector(rng: &mut ThreadRng) -> Vec3 {
+    pub fn random_unit_vector<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
         unit_vector(Self::random_in_unit_sphere(rng))
     }
 
-    pub fn random_in_unit_disk(rng: &mut ThreadRng) -> Vec3 {
+    pub fn random_in_unit_disk<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
         loop {
             let p = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
             if p.length_squared() >= 1.0 {
@@ -89,8 +89,57 @@ impl Vec3 {
         }
     }
 
+    /// Random direction around +z, distributed proportionally to the cosine with +z.
+    pub fn random_cosine_direction<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+        let r1 = rng.gen::<Float>();
+        let r2 = rng.gen::<Float>();
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * r2.sqrt();
+        let y = phi.sin() * r2.sqrt();
+        let z = (1.0 - r2).sqrt();
+        Vec3(x, y, z)
+    }
+
+    /// Random direction around +z, uniform over the cone subtended by a sphere of the given
+    /// radius at the given squared distance.
+    pub fn random_to_sphere<R: Rng + ?Sized>(
+        rng: &mut R,
+        radius: Float,
+        distance_squared: Float,
+    ) -> Vec3 {
+        let r1 = rng.gen::<Float>();
+        let r2 = rng.gen::<Float>();
+
+        let cos_theta_max = (1.0 - radius * radius / distance_squared).max(0.0).sqrt();
+        let z = 1.0 + r2 * (cos_theta_max - 1.0);
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * (1.0 - z * z).sqrt();
+        let y = phi.sin() * (1.0 - z * z).sqrt();
+        Vec3(x, y, z)
+    }
+
+    /// Component-wise minimum.
+    pub fn min(&self, other: &Vec3) -> Vec3 {
+        Vec3(
+            self.0.min(other.0),
+            self.1.min(other.1),
+            self.2.min(other.2),
+        )
+    }
+
+    /// Component-wise maximum.
+    pub fn max(&self, other: &Vec3) -> Vec3 {
+        Vec3(
+            self.0.max(other.0),
+            self.1.max(other.1),
+            self.2.max(other.2),
+        )
+    }
+
     pub fn is_near_zero(&self) -> bool {
-        const EPS: f32 = 1e-8;
+        const EPS: Float = 1e-8;
         (self.0 < EPS) && (self.1 < EPS) && (self.2 < EPS)
     }
 }
@@ -136,16 +185,16 @@ impl ops::Add<Vec3> for Vec3 {
 }
 
 // vecB = vecA * v
-impl ops::Mul<f32> for Vec3 {
+impl ops::Mul<Float> for Vec3 {
     type Output = Vec3;
 
-    fn mul(self, v: f32) -> Vec3 {
+    fn mul(self, v: Float) -> Vec3 {
         Vec3(self.0 * v, self.1 * v, self.2 * v)
     }
 }
 
 // vecB = v * vecA
-impl ops::Mul<Vec3> for f32 {
+impl ops::Mul<Vec3> for Float {
     type Output = Vec3;
 
     fn mul(self, v: Vec3) -> Vec3 {
@@ -154,8 +203,8 @@ impl ops::Mul<Vec3> for f32 {
 }
 
 // vecA *= v
-impl ops::MulAssign<f32> for Vec3 {
-    fn mul_assign(&mut self, v: f32) {
+impl ops::MulAssign<Float> for Vec3 {
+    fn mul_assign(&mut self, v: Float) {
         self.0 *= v;
         self.1 *= v;
         self.2 *= v;
@@ -181,23 +230,37 @@ impl ops::Mul<Vec3> for Vec3 {
 }
 
 // vecB = vecA / v
-impl ops::Div<f32> for Vec3 {
+impl ops::Div<Float> for Vec3 {
     type Output = Vec3;
 
-    fn div(self, v: f32) -> Self::Output {
+    fn div(self, v: Float) -> Self::Output {
         (1.0 / v) * self
     }
 }
 
 // vecA /= v
-impl ops::DivAssign<f32> for Vec3 {
-    fn div_assign(&mut self, v: f32) {
+impl ops::DivAssign<Float> for Vec3 {
+    fn div_assign(&mut self, v: Float) {
         self.0 /= v;
         self.1 /= v;
         self.2 /= v;
     }
 }
 
+// vecA[i]
+impl ops::Index<usize> for Vec3 {
+    type Output = Float;
+
+    fn index(&self, i: usize) -> &Float {
+        match i {
+            0 => &self.0,
+            1 => &self.1,
+            2 => &self.2,
+            _ => panic!("Vec3 index out of range: {}", i),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -231,7 +294,7 @@ mod tests {
     #[test]
     fn test_unit_vector() {
         let v = Vec3::new(4.0, 0.0, 3.0);
-        assert_eq!(unit_vector(v), Vec3(0.8, 0.0, 0.6));
+        assert!((unit_vector(v) - Vec3(0.8, 0.0, 0.6)).length() < 1e-6);
     }
 
     #[test]
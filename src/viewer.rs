@@ -0,0 +1,210 @@
+//! Window exploring the scene: dragging with the left mouse button orbits the camera around
+//! the point it's focused on and scrolling moves it closer or further. While the camera is
+//! still, the image is refined one sample per pixel at a time, up to the samples per pixel of
+//! the settings.
+//!
+//! The render threads claim the tiles of the passes from a shared queue, tagged with the
+//! camera they were rendered with. Moving the camera empties the queue and the window drops
+//! the tiles of the previous camera still in flight.
+
+use crate::distributed::{split_into_tiles, TILE_SIZE};
+use crate::float::Float;
+use crate::framebuffer::{Framebuffer, TileSamples};
+use crate::output::to_rgb8;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vec3::Color;
+use anyhow::{Context, Result};
+use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const TITLE: &str = "rust-ray-tracing";
+const FRAMES_PER_SECOND: usize = 60;
+/// Radians the camera turns by per pixel dragged.
+const ORBIT_SPEED: Float = 0.01;
+/// Factor of the distance to the focus point per step of the scroll wheel.
+const ZOOM_STEP: Float = 0.9;
+/// Time render threads wait for before checking for work again, once the image is complete.
+const IDLE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Camera of the window, as moved from the camera of the scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct View {
+    /// Incremented each time the camera moves.
+    generation: u64,
+    yaw: Float,
+    pitch: Float,
+    zoom: Float,
+}
+
+/// Tiles left to render with the current view, the `next`-th tile of all the passes being the
+/// next one.
+struct Queue {
+    view: View,
+    next: usize,
+}
+
+/// Opens a window showing the scene built by `build_scene`, made of `settings.image_width` by
+/// `settings.image_height` pixels, rendered on `threads` threads and turned into colors to
+/// display by `post_process`. Returns once the window is closed or Escape pressed.
+pub fn run(
+    settings: &RenderSettings,
+    threads: usize,
+    build_scene: &(dyn Fn() -> Scene + Sync),
+    post_process: &dyn Fn(&Framebuffer) -> Vec<Color>,
+) -> Result<()> {
+    let (width, height) = (
+        settings.image_width as usize,
+        settings.image_height as usize,
+    );
+    let mut window = Window::new(TITLE, width, height, WindowOptions::default())
+        .context("Failed to open the window")?;
+    window.set_target_fps(FRAMES_PER_SECOND);
+
+    let tiles = split_into_tiles(settings.image_width, settings.image_height, TILE_SIZE);
+    let passes = settings.samples_per_pixel as usize;
+    let mut view = View {
+        generation: 0,
+        yaw: 0.0,
+        pitch: 0.0,
+        zoom: 1.0,
+    };
+    let queue = Mutex::new(Queue { view, next: 0 });
+    let closed = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (tiles, queue, closed) = (&tiles, &queue, &closed);
+            scope.spawn(move || {
+                let mut scene = build_scene();
+                let camera = scene.camera.clone();
+                let mut generation = view.generation;
+                while !closed.load(Ordering::SeqCst) {
+                    let claimed = {
+                        let mut queue = queue.lock().unwrap();
+                        (queue.next < passes * tiles.len()).then(|| {
+                            queue.next += 1;
+                            (queue.view, queue.next - 1)
+                        })
+                    };
+                    let (view, index) = match claimed {
+                        Some(claimed) => claimed,
+                        None => {
+                            thread::sleep(IDLE_INTERVAL);
+                            continue;
+                        }
+                    };
+
+                    if view.generation != generation {
+                        scene.camera = camera.orbit(view.yaw, view.pitch, view.zoom);
+                        generation = view.generation;
+                    }
+                    let (pass, tile) = (index / tiles.len(), tiles[index % tiles.len()]);
+                    let pass = pass as u32;
+                    let samples = scene.render_samples(settings, tile, pass..pass + 1);
+                    if sender.send((view.generation, samples)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let result = show(
+            &mut window,
+            settings,
+            &queue,
+            &receiver,
+            &mut view,
+            tiles.len(),
+            post_process,
+        );
+        closed.store(true, Ordering::SeqCst);
+        result
+    })
+}
+
+/// Updates the window until it's closed, moving the camera with the mouse and showing the
+/// tiles rendered since the last update.
+fn show(
+    window: &mut Window,
+    settings: &RenderSettings,
+    queue: &Mutex<Queue>,
+    receiver: &mpsc::Receiver<(u64, TileSamples)>,
+    view: &mut View,
+    tile_count: usize,
+    post_process: &dyn Fn(&Framebuffer) -> Vec<Color>,
+) -> Result<()> {
+    let (width, height) = (
+        settings.image_width as usize,
+        settings.image_height as usize,
+    );
+    let mut framebuffer = Framebuffer::new(width, height);
+    let mut buffer = vec![0; width * height];
+    let mut rendered_tiles = 0;
+    let mut last_mouse_pos = None;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let mut moved = false;
+        let mouse_pos = window.get_mouse_pos(MouseMode::Discard);
+        if window.get_mouse_down(MouseButton::Left) {
+            if let (Some((x0, y0)), Some((x, y))) = (last_mouse_pos, mouse_pos) {
+                if (x, y) != (x0, y0) {
+                    // The scene follows the mouse, as if grabbed
+                    view.yaw -= (x - x0) as Float * ORBIT_SPEED;
+                    view.pitch += (y - y0) as Float * ORBIT_SPEED;
+                    moved = true;
+                }
+            }
+            last_mouse_pos = mouse_pos;
+        } else {
+            last_mouse_pos = None;
+        }
+        if let Some((_, scroll)) = window.get_scroll_wheel() {
+            view.zoom *= ZOOM_STEP.powf(scroll as Float);
+            moved = true;
+        }
+
+        if moved {
+            view.generation += 1;
+            *queue.lock().unwrap() = Queue {
+                view: *view,
+                next: 0,
+            };
+            framebuffer = Framebuffer::new(width, height);
+            rendered_tiles = 0;
+        }
+
+        let mut updated = false;
+        for (generation, samples) in receiver.try_iter() {
+            if generation != view.generation {
+                continue;
+            }
+            samples.merge_into(&mut framebuffer);
+            rendered_tiles += 1;
+            updated = true;
+        }
+
+        if updated {
+            for (pixel, color) in buffer.iter_mut().zip(post_process(&framebuffer)) {
+                let [r, g, b] = to_rgb8(&color, 0.0);
+                *pixel = u32::from_be_bytes([0, r, g, b]);
+            }
+            window.set_title(&format!(
+                "{} - {} samples per pixel",
+                TITLE,
+                rendered_tiles / tile_count
+            ));
+        }
+        window
+            .update_with_buffer(&buffer, width, height)
+            .context("Failed to update the window")?;
+    }
+    Ok(())
+}
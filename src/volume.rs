@@ -0,0 +1,100 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::nanovdb::NanoVdbGrid;
+use crate::noise::Perlin;
+use crate::vec3::Point3;
+use std::sync::Arc;
+
+/// Density of a heterogeneous medium, scaled into extinctions per unit of length of the scene
+/// by the material filled with it.
+#[derive(Clone, Debug)]
+pub enum Density {
+    /// Values of a grid, shared by all the copies of the scene.
+    Grid(Arc<NanoVdbGrid>),
+    Pyroclastic(Pyroclastic),
+}
+
+impl Density {
+    /// Density at `point`, in [0, `maximum`].
+    pub fn value(&self, point: &Point3) -> Float {
+        match *self {
+            Density::Grid(ref grid) => grid.value(point).max(0.0),
+            Density::Pyroclastic(ref cloud) => cloud.value(point),
+        }
+    }
+
+    /// Bound of `value` over the whole space, the majorant of delta tracking.
+    pub fn maximum(&self) -> Float {
+        match *self {
+            Density::Grid(ref grid) => grid.maximum().max(0.0),
+            Density::Pyroclastic(_) => 1.0,
+        }
+    }
+
+    /// Box around the part of space where the density varies.
+    pub fn bounding_box(&self) -> Aabb {
+        match *self {
+            Density::Grid(ref grid) => grid.bounding_box(),
+            Density::Pyroclastic(ref cloud) => Aabb::around(cloud.center, cloud.outer_radius()),
+        }
+    }
+}
+
+/// Number of layers of noise bumping the surface of pyroclastic clouds.
+const PYROCLASTIC_OCTAVES: u32 = 5;
+/// Depth over which the density of pyroclastic clouds rises from 0 to 1 below their surface,
+/// relative to their radius.
+const PYROCLASTIC_EDGE: Float = 0.1;
+
+/// Ball of density 1 whose surface is pushed out by turbulent noise, the billows of smoke
+/// plumes and cumulus clouds, fading out towards the surface.
+#[derive(Clone, Debug)]
+pub struct Pyroclastic {
+    center: Point3,
+    radius: Float,
+    /// Largest displacement of the surface, relative to `radius`.
+    amplitude: Float,
+    /// Number of billows across the radius.
+    frequency: Float,
+    noise: Perlin,
+}
+
+impl Pyroclastic {
+    /// Cloud whose billows are shaped by `seed`.
+    pub fn new(center: Point3, radius: Float, amplitude: Float, seed: u64) -> Pyroclastic {
+        Pyroclastic {
+            center,
+            radius,
+            amplitude,
+            frequency: 2.0,
+            noise: Perlin::new(seed),
+        }
+    }
+
+    pub fn with_frequency(mut self, frequency: Float) -> Pyroclastic {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn value(&self, point: &Point3) -> Float {
+        let offset = *point - self.center;
+        let distance = offset.length();
+        if distance >= self.outer_radius() {
+            return 0.0;
+        }
+        // The noise over the unit sphere displaces the surface radially
+        let direction = offset / distance.max(Float::MIN_POSITIVE);
+        // Stretched, the turbulence seldom going over one half
+        let turbulence = self
+            .noise
+            .turbulence(&(self.frequency * direction), PYROCLASTIC_OCTAVES);
+        let bumps = (2.0 * turbulence).min(1.0);
+        let surface = self.radius * (1.0 + self.amplitude * bumps);
+        ((surface - distance) / (PYROCLASTIC_EDGE * self.radius)).clamp(0.0, 1.0)
+    }
+
+    /// Radius of the ball the surface never goes out of.
+    fn outer_radius(&self) -> Float {
+        self.radius * (1.0 + self.amplitude)
+    }
+}
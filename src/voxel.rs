@@ -0,0 +1,464 @@
+use crate::aabb::Aabb;
+use crate::float::Float;
+use crate::material::{Lambertian, Material, MaterialId, MaterialList};
+use crate::object::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::tonemap::TransferFunction;
+use crate::vec3::{Color, Point3, Vec3};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Grid of cubic voxels, each empty or made of a material of `palette`, traversed voxel by voxel
+/// by the rays instead of holding a box per voxel.
+///
+/// Rays report the faces between voxels of different values: the front faces of the filled
+/// voxels they enter, and the back faces of the ones they leave for empty space, so that grids of
+/// dielectric voxels refract as a whole.
+#[derive(Clone)]
+pub struct VoxelGrid {
+    /// Number of voxels along x, y and z.
+    size: [usize; 3],
+    /// Voxels, x varying first then y. 0 for empty voxels, `i` for those made of
+    /// `palette[i - 1]`.
+    voxels: Vec<u8>,
+    palette: Vec<MaterialId>,
+    /// Corner of the voxel (0, 0, 0) with the smallest coordinates.
+    corner: Point3,
+    /// Length of the sides of the voxels.
+    voxel_size: Float,
+}
+
+impl VoxelGrid {
+    /// Grid of unit voxels from the origin, over [0, size.x] x [0, size.y] x [0, size.z].
+    pub fn new(size: [usize; 3], voxels: Vec<u8>, palette: Vec<MaterialId>) -> VoxelGrid {
+        assert_eq!(voxels.len(), size[0] * size[1] * size[2]);
+        assert!(voxels.iter().all(|&v| (v as usize) <= palette.len()));
+        VoxelGrid {
+            size,
+            voxels,
+            palette,
+            corner: Point3::zero(),
+            voxel_size: 1.0,
+        }
+    }
+
+    pub fn with_corner(mut self, corner: Point3) -> VoxelGrid {
+        self.corner = corner;
+        self
+    }
+
+    pub fn with_voxel_size(mut self, voxel_size: Float) -> VoxelGrid {
+        assert!(voxel_size > 0.0);
+        self.voxel_size = voxel_size;
+        self
+    }
+
+    /// Reads the model of a MagicaVoxel .vox file, adding a lambertian material to `materials`
+    /// per color it uses.
+    pub fn load<P: AsRef<Path>>(path: P, materials: &mut MaterialList) -> Result<VoxelGrid> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open voxel model {}", path.display()))?;
+        VoxelGrid::read_vox(BufReader::new(file), materials)
+            .with_context(|| format!("Failed to load voxel model {}", path.display()))
+    }
+
+    /// Reads a MagicaVoxel .vox file holding a single model. Its z axis pointing up becomes the y
+    /// axis, and the material settings of the MATL chunks are ignored, only the colors of the
+    /// palette being kept.
+    pub fn read_vox<R: Read>(mut reader: R, materials: &mut MaterialList) -> Result<VoxelGrid> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() < 8 || &bytes[0..4] != b"VOX " {
+            bail!("Not a MagicaVoxel file");
+        }
+        let (id, _, children) = read_chunk(&bytes[8..])?;
+        if id != b"MAIN" {
+            bail!("Missing MAIN chunk");
+        }
+
+        let mut size = None;
+        let mut model = None;
+        let mut colors = None;
+        let mut remaining = children;
+        while !remaining.is_empty() {
+            let (id, content, _) = read_chunk(remaining)?;
+            match id {
+                b"SIZE" if size.is_some() => bail!("Only single model files are supported"),
+                b"SIZE" => {
+                    size = Some([
+                        read_u32(content, 0)?,
+                        read_u32(content, 4)?,
+                        read_u32(content, 8)?,
+                    ])
+                }
+                b"XYZI" => model = Some(content),
+                b"RGBA" => colors = Some(content),
+                _ => {}
+            }
+            let chunk_length = 12 + content.len() + read_u32(remaining, 8)? as usize;
+            remaining = &remaining[chunk_length..];
+        }
+        let [sx, sy, sz] = size.context("Missing SIZE chunk")?;
+        // MagicaVoxel models are at most 256 voxels wide
+        if [sx, sy, sz].iter().any(|&s| s == 0 || s > 256) {
+            bail!("Invalid model size {}x{}x{}", sx, sy, sz);
+        }
+        let model = model.context("Missing XYZI chunk")?;
+        let colors = colors
+            .context("Missing RGBA chunk, files with the default palette are not supported")?;
+        if colors.len() < 4 * 256 {
+            bail!("Truncated RGBA chunk");
+        }
+
+        // Rotated so that z goes up to y, keeping the handedness
+        let (sx, sy, sz) = (sx as usize, sy as usize, sz as usize);
+        let size = [sx, sz, sy];
+        let mut voxels = vec![0; sx * sy * sz];
+        let mut palette = Vec::new();
+        let mut values = HashMap::new();
+        let count = read_u32(model, 0)? as usize;
+        for i in 0..count {
+            let offset = 4 + 4 * i;
+            let voxel = model
+                .get(offset..offset + 4)
+                .context("Truncated XYZI chunk")?;
+            let (x, y, z) = (voxel[0] as usize, voxel[1] as usize, voxel[2] as usize);
+            if x >= sx || y >= sy || z >= sz || voxel[3] == 0 {
+                bail!("Invalid voxel {} {} {} {}", x, y, z, voxel[3]);
+            }
+            // Color index i is the entry i - 1 of the palette
+            let color_index = voxel[3] as usize;
+            let value = *values.entry(color_index).or_insert_with(|| {
+                let rgba = &colors[4 * (color_index - 1)..4 * color_index];
+                let color = TransferFunction::Srgb.decode(Color::new(
+                    rgba[0] as Float / 255.0,
+                    rgba[1] as Float / 255.0,
+                    rgba[2] as Float / 255.0,
+                ));
+                palette.push(materials.add(Material::Lambertian(Lambertian::new(color))));
+                palette.len() as u8
+            });
+            voxels[x + sx * (z + sz * (sy - 1 - y))] = value;
+        }
+        Ok(VoxelGrid::new(size, voxels, palette))
+    }
+
+    /// Value of the voxel at `cell`, 0 outside of the grid.
+    fn voxel(&self, cell: [isize; 3]) -> u8 {
+        let inside = (0..3).all(|a| cell[a] >= 0 && (cell[a] as usize) < self.size[a]);
+        if !inside {
+            return 0;
+        }
+        let [x, y, z] = [cell[0] as usize, cell[1] as usize, cell[2] as usize];
+        self.voxels[x + self.size[0] * (y + self.size[1] * z)]
+    }
+}
+
+impl Hittable for VoxelGrid {
+    /// 3D-DDA: steps from voxel to voxel along the ray, in grid units, until the value changes.
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, hit_record: &mut HitRecord) -> bool {
+        let origin = (ray.origin() - self.corner) / self.voxel_size;
+        let direction = ray.direction() / self.voxel_size;
+
+        // Clipped to the grid, remembering the side it enters through
+        let mut t = t_min;
+        let mut t_exit = t_max;
+        let mut axis = None;
+        for a in 0..3 {
+            let inverse = 1.0 / direction[a];
+            let mut t0 = -origin[a] * inverse;
+            let mut t1 = (self.size[a] as Float - origin[a]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            if t0 > t {
+                t = t0;
+                axis = Some(a);
+            }
+            if t1 < t_exit {
+                t_exit = t1;
+            }
+            if t_exit < t {
+                return false;
+            }
+        }
+
+        let step = [0, 1, 2].map(|a| if direction[a] < 0.0 { -1 } else { 1 });
+        let entry = origin + t * direction;
+        let mut cell = [0, 1, 2].map(|a| {
+            if axis == Some(a) {
+                // Exactly on the side it enters through
+                if step[a] > 0 {
+                    0
+                } else {
+                    self.size[a] as isize - 1
+                }
+            } else {
+                (entry[a].floor() as isize).clamp(0, self.size[a] as isize - 1)
+            }
+        });
+        let mut t_next = [0, 1, 2].map(|a| {
+            if direction[a] == 0.0 {
+                Float::INFINITY
+            } else {
+                let side = cell[a] + if step[a] > 0 { 1 } else { 0 };
+                (side as Float - origin[a]) / direction[a]
+            }
+        });
+        let t_delta = [0, 1, 2].map(|a| (1.0 / direction[a]).abs());
+
+        // Value of the voxels the ray is in, empty if it starts out of the grid
+        let current = if axis.is_none() { self.voxel(cell) } else { 0 };
+        loop {
+            let value = self.voxel(cell);
+            if value != current {
+                // Crossed a side along `axis`, the ray not starting in this voxel
+                let a = axis.unwrap_or(0);
+                let (material, outward) = if value != 0 {
+                    (value, -step[a])
+                } else {
+                    (current, step[a])
+                };
+                // Exactly on the side crossed
+                let point = origin + t * direction;
+                let side = (cell[a] + if step[a] > 0 { 0 } else { 1 }) as Float;
+                let point = point + (side - point[a]) * unit_axis(a);
+                let (b, c) = ((a + 1) % 3, (a + 2) % 3);
+
+                hit_record.t = t;
+                hit_record.point = self.corner + self.voxel_size * point;
+                hit_record.set_face_normal(ray, &(outward as Float * unit_axis(a)));
+                hit_record.u = point[b] - point[b].floor();
+                hit_record.v = point[c] - point[c].floor();
+                hit_record.tangent = unit_axis(b);
+                hit_record.material = self.palette[material as usize - 1];
+                return true;
+            }
+
+            // Next voxel along the ray
+            let a = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            t = t_next[a];
+            if t > t_max || (current == 0 && t > t_exit) {
+                return false;
+            }
+            cell[a] += step[a];
+            t_next[a] += t_delta[a];
+            axis = Some(a);
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(
+            self.size[0] as Float,
+            self.size[1] as Float,
+            self.size[2] as Float,
+        );
+        Some(Aabb::new(
+            self.corner,
+            self.corner + self.voxel_size * extent,
+        ))
+    }
+}
+
+fn unit_axis(axis: usize) -> Vec3 {
+    match axis {
+        0 => Vec3::new(1.0, 0.0, 0.0),
+        1 => Vec3::new(0.0, 1.0, 0.0),
+        _ => Vec3::new(0.0, 0.0, 1.0),
+    }
+}
+
+/// Id, content and children of the chunk at the start of `bytes`.
+fn read_chunk(bytes: &[u8]) -> Result<(&[u8], &[u8], &[u8])> {
+    let content_length = read_u32(bytes, 4)? as usize;
+    let children_length = read_u32(bytes, 8)? as usize;
+    let content_end = 12 + content_length;
+    let end = content_end + children_length;
+    if bytes.len() < end {
+        bail!("Truncated chunk");
+    }
+    Ok((
+        &bytes[0..4],
+        &bytes[12..content_end],
+        &bytes[content_end..end],
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    let value = bytes.get(offset..offset + 4).context("Truncated chunk")?;
+    Ok(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Dielectric;
+
+    /// 3 x 2 x 1 grid, the voxels (0, 0, 0) and (2, 0, 0) made of the first material and (1, 0,
+    /// 0) of the second, the top row empty.
+    fn grid(materials: &mut MaterialList) -> VoxelGrid {
+        let palette = vec![
+            materials.add(Material::Lambertian(Lambertian::new(Color::new(
+                0.5, 0.5, 0.5,
+            )))),
+            materials.add(Material::Dielectric(Dielectric::new(1.5))),
+        ];
+        VoxelGrid::new([3, 2, 1], vec![1, 2, 1, 0, 0, 0], palette)
+            .with_corner(Point3::new(-3.0, 0.0, 0.0))
+            .with_voxel_size(2.0)
+    }
+
+    #[test]
+    fn test_hit_front_faces() {
+        let mut materials = MaterialList::new();
+        let grid = grid(&mut materials);
+        let mut hit_record = HitRecord::empty();
+
+        // From above, through the empty top row
+        let ray = Ray::new(Point3::new(-0.5, 10.0, 1.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert_eq!(hit_record.t, 8.0);
+        assert_eq!(hit_record.point, Point3::new(-0.5, 2.0, 1.0));
+        assert_eq!(hit_record.normal, Vec3::new(0.0, 1.0, 0.0));
+        assert!(hit_record.front_face);
+        assert_eq!(hit_record.material, grid.palette[1]);
+        assert!((hit_record.u - 0.5).abs() < 1e-6);
+        assert!((hit_record.v - 0.25).abs() < 1e-6);
+
+        // From the side, obliquely
+        let ray = Ray::new(Point3::new(-5.0, 3.0, 1.0), Vec3::new(1.0, -1.0, 0.0));
+        assert!(grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert_eq!(hit_record.point, Point3::new(-3.0, 1.0, 1.0));
+        assert_eq!(hit_record.normal, Vec3::new(-1.0, 0.0, 0.0));
+        assert_eq!(hit_record.material, grid.palette[0]);
+
+        // Beyond t_max
+        assert!(!grid.hit(&ray, 0.001, 1.0, &mut hit_record));
+    }
+
+    #[test]
+    fn test_miss_through_empty_voxels() {
+        let mut materials = MaterialList::new();
+        let grid = grid(&mut materials);
+        let mut hit_record = HitRecord::empty();
+
+        // Along the empty top row
+        let ray = Ray::new(Point3::new(-10.0, 3.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(!grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        // Away from the grid
+        let ray = Ray::new(Point3::new(-10.0, 1.0, 1.0), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(!grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        // Out of the top row, diagonally
+        let ray = Ray::new(Point3::new(-2.0, 3.0, 1.0), Vec3::new(1.0, 0.5, 0.0));
+        assert!(!grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+    }
+
+    #[test]
+    fn test_hit_from_inside() {
+        let mut materials = MaterialList::new();
+        let grid = grid(&mut materials);
+        let mut hit_record = HitRecord::empty();
+
+        // Out of the dielectric voxel, into the lambertian one next to it
+        let ray = Ray::new(Point3::new(0.0, 1.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert_eq!(hit_record.point, Point3::new(1.0, 1.0, 1.0));
+        assert_eq!(hit_record.normal, Vec3::new(-1.0, 0.0, 0.0));
+        assert!(hit_record.front_face);
+        assert_eq!(hit_record.material, grid.palette[0]);
+
+        // Out of the dielectric voxel, into the empty one above
+        let ray = Ray::new(Point3::new(0.0, 1.0, 1.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!(grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert_eq!(hit_record.t, 1.0);
+        assert_eq!(hit_record.normal, Vec3::new(0.0, -1.0, 0.0));
+        assert!(!hit_record.front_face);
+        assert_eq!(hit_record.material, grid.palette[1]);
+
+        // Out of the grid
+        let ray = Ray::new(Point3::new(0.0, 1.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(grid.hit(&ray, 0.001, Float::MAX, &mut hit_record));
+        assert_eq!(hit_record.point, Point3::new(0.0, 1.0, 0.0));
+        assert!(!hit_record.front_face);
+    }
+
+    /// .vox file of a `size` model made of `voxels`, (x, y, z, color index), with a palette of
+    /// `colors`.
+    fn vox_file(size: [u32; 3], voxels: &[[u8; 4]], colors: &[[u8; 4]]) -> Vec<u8> {
+        fn chunk(id: &[u8], content: &[u8], children: &[u8]) -> Vec<u8> {
+            let mut bytes = id.to_vec();
+            bytes.extend((content.len() as u32).to_le_bytes());
+            bytes.extend((children.len() as u32).to_le_bytes());
+            bytes.extend(content);
+            bytes.extend(children);
+            bytes
+        }
+
+        let size: Vec<u8> = size.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut model = (voxels.len() as u32).to_le_bytes().to_vec();
+        model.extend(voxels.iter().flatten());
+        let mut palette: Vec<u8> = colors.iter().flatten().copied().collect();
+        palette.resize(4 * 256, 0);
+
+        let mut children = chunk(b"SIZE", &size, &[]);
+        children.extend(chunk(b"XYZI", &model, &[]));
+        children.extend(chunk(b"MATL", &[0; 8], &[]));
+        children.extend(chunk(b"RGBA", &palette, &[]));
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend(150u32.to_le_bytes());
+        bytes.extend(chunk(b"MAIN", &[], &children));
+        bytes
+    }
+
+    #[test]
+    fn test_read_vox() {
+        let bytes = vox_file(
+            [2, 3, 4],
+            &[[0, 0, 0, 2], [1, 2, 3, 5], [1, 0, 3, 2]],
+            &[
+                [0; 4],
+                [255, 255, 255, 255],
+                [0; 4],
+                [0; 4],
+                [188, 0, 255, 255],
+            ],
+        );
+        let mut materials = MaterialList::new();
+        let grid = VoxelGrid::read_vox(&bytes[..], &mut materials).unwrap();
+        assert_eq!(grid.size, [2, 4, 3]);
+        // One material per color
+        assert_eq!(grid.palette.len(), 2);
+        assert_eq!(grid.voxel([0, 0, 2]), 1);
+        assert_eq!(grid.voxel([1, 3, 0]), 2);
+        assert_eq!(grid.voxel([1, 3, 2]), 1);
+        assert_eq!(grid.voxels.iter().filter(|&&v| v != 0).count(), 3);
+
+        let albedo = |material: MaterialId| materials[material].albedo();
+        assert_eq!(albedo(grid.palette[0]), Color::new(1.0, 1.0, 1.0));
+        // Decoded from sRGB
+        let color = albedo(grid.palette[1]);
+        assert!((color.x() - 0.5).abs() < 1e-2);
+        assert_eq!(color.y(), 0.0);
+
+        assert!(VoxelGrid::read_vox(&bytes[..40], &mut materials).is_err());
+        for size in [[0, 3, 4], [2, 3, 257], [u32::MAX; 3]] {
+            let bytes = vox_file(size, &[], &[]);
+            assert!(VoxelGrid::read_vox(&bytes[..], &mut materials).is_err());
+        }
+        assert!(VoxelGrid::read_vox(&b"VOX"[..], &mut materials).is_err());
+    }
+}
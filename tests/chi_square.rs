@@ -0,0 +1,261 @@
+//! Chi-square tests of the scattering distributions: the directions sampled by the materials
+//! must follow the densities they report, or the renders are biased in ways hard to see.
+//!
+//! The sphere of directions is split into cells of equal solid angle, the samples drawn are
+//! counted in each cell and compared with the counts expected by integrating the density over
+//! the cell.
+
+use rust_ray_tracing::float::Float;
+use rust_ray_tracing::material::{Dielectric, Lambertian, ScatterRecord, ScatterType, Scatterable};
+use rust_ray_tracing::microfacet::Ggx;
+use rust_ray_tracing::object::HitRecord;
+use rust_ray_tracing::onb::Onb;
+use rust_ray_tracing::pdf::{GgxPdf, HairPdf, Pdf};
+use rust_ray_tracing::ray::Ray;
+use rust_ray_tracing::rng::SampleRng;
+use rust_ray_tracing::vec3::{unit_vector, Point3, Vec3};
+use std::f64::consts::PI;
+
+/// Cells along the cosine of the polar angle, and along the azimuth.
+const THETA_CELLS: usize = 16;
+const PHI_CELLS: usize = 32;
+/// Points per axis of each cell the density is integrated over.
+const CELL_RESOLUTION: usize = 16;
+const SAMPLE_COUNT: usize = 200_000;
+/// Cells expecting fewer samples are pooled together, the statistic being unreliable otherwise.
+const MIN_EXPECTED: f64 = 5.0;
+/// Fraction of the samples allowed in cells where the density is zero at all the points it's
+/// integrated at, some cells straddling the edge of the support.
+const MAX_STRAY_FRACTION: f64 = 1e-4;
+/// Standard normal deviate above which the distributions are considered different, the
+/// chance of a false alarm being about 1e-5.
+const MAX_DEVIATE: f64 = 4.25;
+
+/// Direction at the cosine of the polar angle `z` in [-1, 1] and azimuth `phi` in [0, 2pi].
+#[allow(clippy::unnecessary_cast)]
+fn direction(z: f64, phi: f64) -> Vec3 {
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    Vec3::new(
+        (r * phi.cos()) as Float,
+        (r * phi.sin()) as Float,
+        z as Float,
+    )
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn cell(direction: &Vec3) -> usize {
+    let direction = unit_vector(*direction);
+    let z = (direction.z() as f64).clamp(-1.0, 1.0);
+    let phi = (direction.y() as f64)
+        .atan2(direction.x() as f64)
+        .rem_euclid(2.0 * PI);
+    let theta_cell = (((z + 1.0) / 2.0 * THETA_CELLS as f64) as usize).min(THETA_CELLS - 1);
+    let phi_cell = ((phi / (2.0 * PI) * PHI_CELLS as f64) as usize).min(PHI_CELLS - 1);
+    theta_cell * PHI_CELLS + phi_cell
+}
+
+/// Samples expected in each cell, integrating the density with the midpoint rule.
+#[allow(clippy::unnecessary_cast)]
+fn expected_counts(pdf: &dyn Pdf) -> Vec<f64> {
+    let cell_solid_angle = 4.0 * PI / (THETA_CELLS * PHI_CELLS) as f64;
+    let dz = 2.0 / (THETA_CELLS * CELL_RESOLUTION) as f64;
+    let dphi = 2.0 * PI / (PHI_CELLS * CELL_RESOLUTION) as f64;
+    let mut counts = vec![0.0; THETA_CELLS * PHI_CELLS];
+    for i in 0..THETA_CELLS * CELL_RESOLUTION {
+        for j in 0..PHI_CELLS * CELL_RESOLUTION {
+            let z = -1.0 + (i as f64 + 0.5) * dz;
+            let phi = (j as f64 + 0.5) * dphi;
+            let index = (i / CELL_RESOLUTION) * PHI_CELLS + j / CELL_RESOLUTION;
+            counts[index] += pdf.value(&direction(z, phi)) as f64;
+        }
+    }
+    let points_per_cell = (CELL_RESOLUTION * CELL_RESOLUTION) as f64;
+    counts
+        .iter()
+        .map(|c| c / points_per_cell * cell_solid_angle * SAMPLE_COUNT as f64)
+        .collect()
+}
+
+/// Pearson's statistic of the observed counts, with its degrees of freedom, pooling the cells
+/// expecting few samples.
+fn chi_square(observed: &[f64], expected: &[f64]) -> (f64, usize) {
+    let mut statistic = 0.0;
+    let mut cells = 0;
+    let mut stray = 0.0;
+    let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+    for (o, e) in observed.iter().zip(expected) {
+        if *e == 0.0 {
+            stray += o;
+        } else if *e < MIN_EXPECTED {
+            pooled_observed += o;
+            pooled_expected += e;
+        } else {
+            statistic += (o - e) * (o - e) / e;
+            cells += 1;
+        }
+    }
+    let total: f64 = observed.iter().sum();
+    assert!(
+        stray <= MAX_STRAY_FRACTION * total,
+        "{} samples where the density is zero",
+        stray
+    );
+    if pooled_expected > 0.0 {
+        let (o, e) = (pooled_observed, pooled_expected);
+        statistic += (o - e) * (o - e) / e;
+        cells += 1;
+    }
+    (statistic, cells - 1)
+}
+
+/// Standard normal deviate of a chi-square statistic (Wilson-Hilferty approximation).
+fn normal_deviate(statistic: f64, dof: usize) -> f64 {
+    let k = dof as f64;
+    let variance = 2.0 / (9.0 * k);
+    ((statistic / k).cbrt() - (1.0 - variance)) / variance.sqrt()
+}
+
+/// Checks that the directions generated by `pdf` follow its density.
+fn check_pdf(name: &str, pdf: &dyn Pdf, rng: &mut SampleRng) {
+    let expected = expected_counts(pdf);
+    let total: f64 = expected.iter().sum();
+    assert!(
+        (total / SAMPLE_COUNT as f64 - 1.0).abs() < 0.02,
+        "{}: density integrates to {}",
+        name,
+        total / SAMPLE_COUNT as f64
+    );
+
+    let mut observed = vec![0.0; expected.len()];
+    for _ in 0..SAMPLE_COUNT {
+        observed[cell(&pdf.generate(rng))] += 1.0;
+    }
+
+    let (statistic, dof) = chi_square(&observed, &expected);
+    let deviate = normal_deviate(statistic, dof);
+    assert!(
+        deviate < MAX_DEVIATE,
+        "{}: chi-square {:.1} for {} degrees of freedom",
+        name,
+        statistic,
+        dof
+    );
+}
+
+fn hit_record(normal: Vec3, front_face: bool) -> HitRecord {
+    let mut hit_record = HitRecord::empty();
+    hit_record.normal = unit_vector(normal);
+    hit_record.front_face = front_face;
+    hit_record.outside_refraction_index = 1.0;
+    hit_record
+}
+
+#[test]
+fn test_lambertian() {
+    let mut rng = SampleRng::new(0);
+    let lambertian = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+    for normal in [Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, -2.0, 0.5)] {
+        let hit_record = hit_record(normal, true);
+        let in_ray = Ray::new(Point3::new(0.0, 0.0, 1.0), -hit_record.normal);
+        let mut scatter_record = ScatterRecord::empty();
+        assert!(lambertian.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+        match scatter_record.scatter_type {
+            ScatterType::Pdf(ref pdf) => check_pdf("Lambertian", pdf, &mut rng),
+            ScatterType::Specular(_) => panic!("Lambertian scattering is not specular"),
+        }
+    }
+}
+
+#[test]
+fn test_ggx() {
+    let mut rng = SampleRng::new(0);
+    let uvw = Onb::build_from_w(&Vec3::new(0.2, 0.3, 1.0));
+    for (roughness, anisotropy, wo) in [
+        (0.5, 0.0, Vec3::new(0.0, 0.0, 1.0)),
+        (0.5, 0.0, Vec3::new(1.0, 0.0, 0.3)),
+        (0.8, 0.0, Vec3::new(0.3, -0.2, 1.0)),
+        (0.6, 0.8, Vec3::new(-0.5, 0.7, 0.4)),
+    ] {
+        let pdf = GgxPdf::new(
+            uvw,
+            unit_vector(wo),
+            Ggx::from_roughness(roughness, anisotropy),
+        );
+        let name = format!("GGX roughness {} anisotropy {}", roughness, anisotropy);
+        check_pdf(&name, &pdf, &mut rng);
+    }
+}
+
+#[test]
+fn test_hair() {
+    let mut rng = SampleRng::new(0);
+    let uvw = Onb::build_from_w(&Vec3::new(0.2, 0.3, 1.0));
+    for (roughness, reflectance, wo) in [
+        (0.3, 0.5, Vec3::new(0.0, 0.0, 1.0)),
+        (0.5, 0.05, Vec3::new(1.0, 0.0, 0.3)),
+        (0.8, 0.2, Vec3::new(0.3, -0.2, 1.0)),
+    ] {
+        let pdf = HairPdf::new(uvw, &unit_vector(wo), roughness, reflectance);
+        let name = format!("Hair roughness {} reflectance {}", roughness, reflectance);
+        check_pdf(&name, &pdf, &mut rng);
+    }
+}
+
+#[test]
+fn test_dielectric_reflection_probability() {
+    let mut rng = SampleRng::new(0);
+    let glass = Dielectric::new(1.5);
+    let normal = Vec3::new(0.0, 0.0, 1.0);
+    for front_face in [true, false] {
+        let hit_record = hit_record(normal, front_face);
+        let ratio: Float = if front_face { 1.0 / 1.5 } else { 1.5 };
+        for cos_theta in [1.0 as Float, 0.8, 0.5, 0.3, 0.1] {
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let in_ray = Ray::new(
+                Point3::new(-sin_theta, 0.0, cos_theta),
+                Vec3::new(sin_theta, 0.0, -cos_theta),
+            );
+
+            // Schlick's approximation of the Fresnel reflectance, or total internal reflection
+            let expected = if ratio * sin_theta > 1.0 {
+                1.0
+            } else {
+                let r0 = ((1.0 - ratio) / (1.0 + ratio)).powi(2);
+                (r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)) as f64
+            };
+
+            let mut reflected = 0;
+            for _ in 0..SAMPLE_COUNT {
+                let mut scatter_record = ScatterRecord::empty();
+                assert!(glass.scatter(&in_ray, &hit_record, &mut scatter_record, &mut rng));
+                match scatter_record.scatter_type {
+                    ScatterType::Specular(ray) if ray.direction().z() > 0.0 => reflected += 1,
+                    ScatterType::Specular(_) => {}
+                    ScatterType::Pdf(_) => panic!("Smooth glass scattering is specular"),
+                }
+            }
+
+            let n = SAMPLE_COUNT as f64;
+            let observed = [reflected as f64, n - reflected as f64];
+            let expected = [expected * n, (1.0 - expected) * n];
+            let name = format!(
+                "{} face at cosine {}",
+                if front_face { "front" } else { "back" },
+                cos_theta
+            );
+            if expected[1] == 0.0 {
+                assert_eq!(observed[1], 0.0, "{}: refracted", name);
+                continue;
+            }
+            // One degree of freedom: the deviate is the square root of the statistic
+            let (statistic, _) = chi_square(&observed, &expected);
+            assert!(
+                statistic.sqrt() < MAX_DEVIATE,
+                "{}: reflected {} times, expected {:.0}",
+                name,
+                reflected,
+                expected[0]
+            );
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use rust_ray_tracing::float::Float;
+use rust_ray_tracing::light_bvh::LightBvh;
+use rust_ray_tracing::rng::SampleRng;
+use rust_ray_tracing::scene::Scene;
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::RenderSettings;
+use rust_ray_tracing::vec3::Color;
+
+const SIZE: u16 = 24;
+
+fn settings() -> RenderSettings {
+    RenderSettings {
+        scene: BuiltinScene::CornellBox,
+        image_width: SIZE,
+        image_height: SIZE,
+        samples_per_pixel: 64,
+        ..RenderSettings::default()
+    }
+}
+
+/// Average radiance of each pixel, row 0 being the top of the image.
+fn render(scene: &Scene, settings: &RenderSettings) -> Vec<Color> {
+    let mut pixels = Vec::new();
+    for row in (0..settings.image_height).rev() {
+        for col in 0..settings.image_width {
+            let mut sum = Color::zero();
+            for s in 0..settings.samples_per_pixel as u32 {
+                let mut rng = SampleRng::for_sample(settings.seed, pixels.len(), s);
+                let (ray, _, _) = scene.camera_ray(settings, col, row, s, &mut rng);
+                sum += scene.ray_color(settings, &ray.unwrap(), &mut rng);
+            }
+            pixels.push(sum / settings.samples_per_pixel as Float);
+        }
+    }
+    pixels
+}
+
+fn average<'a>(pixels: impl Iterator<Item = &'a Color>) -> Color {
+    let (sum, count) = pixels.fold((Color::zero(), 0), |(sum, count), p| (sum + *p, count + 1));
+    sum / count as Float
+}
+
+/// Average of the pixels of the columns in `cols`, over the middle rows of the image.
+fn band(pixels: &[Color], cols: std::ops::Range<u16>) -> Color {
+    let size = SIZE as usize;
+    average(
+        pixels
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (size / 3..2 * size / 3).contains(&(i / size)))
+            .filter(|(i, _)| cols.contains(&((i % size) as u16)))
+            .map(|(_, p)| p),
+    )
+}
+
+#[test]
+fn test_cornell_box() {
+    let settings = settings();
+    let scene = settings.scene.build(&settings);
+    assert_eq!(scene.lights.len(), 1);
+    let pixels = render(&scene, &settings);
+
+    // The green wall is on the left of the view, the red one on the right
+    let left = band(&pixels, 0..3);
+    let right = band(&pixels, SIZE - 3..SIZE);
+    assert!(left.y() > 2.0 * left.x(), "left wall {:?}", left);
+    assert!(right.x() > 2.0 * right.y(), "right wall {:?}", right);
+
+    // The light is seen near the top, emitting more than anything reflects
+    let size = SIZE as usize;
+    let light = pixels[..size * size / 4]
+        .iter()
+        .fold(0.0 as Float, |max, p| max.max(p.x()));
+    assert!(light > 10.0, "light {}", light);
+    assert!(pixels
+        .iter()
+        .all(|p| p.x().is_finite() && p.y().is_finite()));
+}
+
+#[test]
+fn test_light_sampling_is_unbiased() {
+    // Sampling the quad light reduces the noise of the path tracer without changing the
+    // expected image
+    let settings = settings();
+    let mut scene = settings.scene.build(&settings);
+    let sampled = average(render(&scene, &settings).iter());
+    scene.lights = LightBvh::default();
+    let unsampled = average(render(&scene, &settings).iter());
+
+    let relative_difference = (sampled - unsampled).length() / sampled.length();
+    assert!(
+        relative_difference < 0.1,
+        "{:?} with light sampling, {:?} without",
+        sampled,
+        unsampled
+    );
+}
@@ -0,0 +1,62 @@
+use rust_ray_tracing::distributed::{split_into_tiles, Tile};
+use rust_ray_tracing::filter::Filter;
+use rust_ray_tracing::framebuffer::Framebuffer;
+use rust_ray_tracing::parallel::render_tiles;
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::RenderSettings;
+use rust_ray_tracing::vec3::Color;
+
+fn settings(seed: u64) -> RenderSettings {
+    RenderSettings {
+        scene: BuiltinScene::CornellBox,
+        image_width: 24,
+        image_height: 20,
+        samples_per_pixel: 8,
+        filter: Filter::Mitchell,
+        seed,
+        ..RenderSettings::default()
+    }
+}
+
+/// Pixels of the image rendered in tiles on `threads` threads, in row-major order.
+fn render(settings: &RenderSettings, threads: usize) -> Vec<Color> {
+    let (width, height) = (settings.image_width, settings.image_height);
+    let mut framebuffer = Framebuffer::new(width as usize, height as usize);
+    let tiles = split_into_tiles(width, height, 8);
+    let new_renderer = || {
+        let scene = settings.scene.build(settings);
+        move |tile| scene.render_tile(settings, tile)
+    };
+    render_tiles(threads, tiles, new_renderer, |_, samples| {
+        samples.merge_into(&mut framebuffer);
+        true
+    });
+    framebuffer.pixels()
+}
+
+#[test]
+fn test_same_image_whatever_the_thread_count() {
+    let settings = settings(1);
+    let image = render(&settings, 1);
+    assert_eq!(render(&settings, 3), image);
+    assert_eq!(render(&settings, 1), image);
+
+    // Only the seed changes the noise
+    assert_ne!(render(&self::settings(2), 3), image);
+}
+
+#[test]
+fn test_no_seams_between_tiles() {
+    let settings = settings(1);
+    let tile = Tile {
+        x: 0,
+        y: 0,
+        width: settings.image_width,
+        height: settings.image_height,
+    };
+    let scene = settings.scene.build(&settings);
+    let untiled = scene.render_tile(&settings, tile).framebuffer.pixels();
+    for (pixel, expected) in render(&settings, 1).iter().zip(untiled) {
+        assert!((*pixel - expected).length() < 1e-4 * expected.length().max(1.0));
+    }
+}
@@ -0,0 +1,146 @@
+//! Renders tiny versions of the built-in scenes and compares them to reference images, so that
+//! changes to the renderer can't alter its output unnoticed.
+//!
+//! After an intended change of the output, check the new images and update the references
+//! with `UPDATE_GOLDEN=1 cargo test --test golden`, and again with `--features f64`: the
+//! noise of both precisions differs.
+
+use rust_ray_tracing::distributed::Tile;
+use rust_ray_tracing::output::{save_image, to_rgb8, Dither, Png};
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::RenderSettings;
+use rust_ray_tracing::vec3::Color;
+use std::env;
+use std::path::PathBuf;
+
+const WIDTH: u16 = 64;
+const HEIGHT: u16 = 36;
+
+/// Mean difference of the channels allowed, out of 255, for changes of the rounding of a few
+/// computations.
+const MAX_MEAN_DIFFERENCE: f64 = 0.5;
+/// Pixels whose channels differ by more than this count as changed...
+const PIXEL_THRESHOLD: u8 = 24;
+/// ...and so few of them may, the paths of a few samples taking another turn.
+const MAX_CHANGED_FRACTION: f64 = 0.01;
+
+fn reference_path(scene: BuiltinScene) -> PathBuf {
+    let suffix = if cfg!(feature = "f64") { "-f64" } else { "" };
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}{}.png", scene, suffix))
+}
+
+/// Tone mapped and encoded image, as written by the renderer.
+fn render(scene: BuiltinScene) -> Vec<Color> {
+    let settings = RenderSettings {
+        scene,
+        image_width: WIDTH,
+        image_height: HEIGHT,
+        samples_per_pixel: 16,
+        seed: 0,
+        ..RenderSettings::default()
+    };
+    let tile = Tile {
+        x: 0,
+        y: 0,
+        width: WIDTH,
+        height: HEIGHT,
+    };
+    scene
+        .build(&settings)
+        .render_tile(&settings, tile)
+        .framebuffer
+        .pixels()
+        .iter()
+        .map(|pixel| {
+            let color = settings.tone_mapper.apply(*pixel, 1.0);
+            settings.transfer_function.encode(color)
+        })
+        .collect()
+}
+
+fn check_golden(scene: BuiltinScene) {
+    let pixels = render(scene);
+    let path = reference_path(scene);
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        save_image(
+            &path,
+            &Png,
+            WIDTH as usize,
+            HEIGHT as usize,
+            &pixels,
+            Dither::None,
+        )
+        .unwrap();
+        return;
+    }
+
+    let reference = image::open(&path)
+        .unwrap_or_else(|e| panic!("Failed to open {}: {}", path.display(), e))
+        .to_rgb8();
+    assert_eq!(
+        reference.dimensions(),
+        (WIDTH as u32, HEIGHT as u32),
+        "Wrong size of {}",
+        path.display()
+    );
+
+    let mut total_difference = 0u64;
+    let mut changed_pixels = 0;
+    for (pixel, expected) in pixels.iter().zip(reference.pixels()) {
+        let pixel = to_rgb8(pixel, 0.0);
+        let differences = (0..3).map(|c| pixel[c].abs_diff(expected[c]));
+        total_difference += differences.clone().map(u64::from).sum::<u64>();
+        if differences.max().unwrap() > PIXEL_THRESHOLD {
+            changed_pixels += 1;
+        }
+    }
+    let mean_difference = total_difference as f64 / (3 * pixels.len()) as f64;
+    let changed_fraction = changed_pixels as f64 / pixels.len() as f64;
+    assert!(
+        mean_difference <= MAX_MEAN_DIFFERENCE && changed_fraction <= MAX_CHANGED_FRACTION,
+        "{} differs from {}: mean difference {:.2}, {} changed pixels",
+        scene,
+        path.display(),
+        mean_difference,
+        changed_pixels
+    );
+}
+
+#[test]
+fn test_random_spheres() {
+    check_golden(BuiltinScene::RandomSpheres);
+}
+
+#[test]
+fn test_cornell_box() {
+    check_golden(BuiltinScene::CornellBox);
+}
+
+#[test]
+fn test_three_spheres() {
+    check_golden(BuiltinScene::ThreeSpheres);
+}
+
+#[test]
+fn test_checkered_ground() {
+    check_golden(BuiltinScene::CheckeredGround);
+}
+
+#[test]
+fn test_smoke_box() {
+    check_golden(BuiltinScene::SmokeBox);
+}
+
+#[test]
+fn test_final_next_week() {
+    check_golden(BuiltinScene::FinalNextWeek);
+}
+
+#[test]
+fn test_fractals() {
+    check_golden(BuiltinScene::Fractals);
+}
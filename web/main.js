@@ -0,0 +1,44 @@
+import init, { WebRenderer } from "./pkg/rust_ray_tracing_web.js";
+
+const canvas = document.getElementById("canvas");
+const context = canvas.getContext("2d");
+const status = document.getElementById("status");
+// Incremented by each render, for the passes of the previous one to stop
+let currentRender = 0;
+
+function render() {
+  const id = ++currentRender;
+  const scene = document.getElementById("scene").value;
+  const samples = Number(document.getElementById("samples").value);
+  let renderer;
+  try {
+    renderer = new WebRenderer(scene, canvas.width, canvas.height, samples);
+  } catch (error) {
+    status.textContent = error.message;
+    return;
+  }
+  const start = performance.now();
+
+  // One sample per pixel per frame, drawing the image refined so far
+  function pass() {
+    if (id !== currentRender) {
+      renderer.free();
+      return;
+    }
+    const more = renderer.render_pass();
+    const pixels = new Uint8ClampedArray(renderer.pixels());
+    context.putImageData(new ImageData(pixels, renderer.width(), renderer.height()), 0, 0);
+    const seconds = ((performance.now() - start) / 1000).toFixed(1);
+    status.textContent = `${renderer.samples()}/${samples} samples per pixel, ${seconds} s`;
+    if (more) {
+      requestAnimationFrame(pass);
+    } else {
+      renderer.free();
+    }
+  }
+  requestAnimationFrame(pass);
+}
+
+await init();
+document.getElementById("render").addEventListener("click", render);
+render();
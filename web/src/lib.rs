@@ -0,0 +1,115 @@
+//! Renders of the built-in scenes refined in a canvas of a web page, one sample per pixel at a
+//! time so that the page stays responsive between the passes.
+
+use rust_ray_tracing::distributed::{split_into_tiles, Tile, TILE_SIZE};
+use rust_ray_tracing::framebuffer::Framebuffer;
+use rust_ray_tracing::output::{post_process, to_rgba8_bytes};
+use rust_ray_tracing::scene::Scene;
+use rust_ray_tracing::scenes::BuiltinScene;
+use rust_ray_tracing::settings::RenderSettings;
+use wasm_bindgen::prelude::*;
+
+/// Progressive render of a built-in scene, on the thread of the page.
+#[wasm_bindgen]
+pub struct WebRenderer {
+    settings: RenderSettings,
+    scene: Scene,
+    tiles: Vec<Tile>,
+    framebuffer: Framebuffer,
+    next_sample: u32,
+}
+
+#[wasm_bindgen]
+impl WebRenderer {
+    /// Renderer of the built-in scene named `scene`, e.g. "cornell-box".
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        scene: &str,
+        width: u16,
+        height: u16,
+        samples_per_pixel: u16,
+    ) -> Result<WebRenderer, JsError> {
+        let scene: BuiltinScene = scene
+            .parse()
+            .map_err(|error| JsError::new(&format!("{:#}", error)))?;
+        if width < 2 || height < 2 || samples_per_pixel == 0 {
+            return Err(JsError::new("Empty image"));
+        }
+        let settings = RenderSettings {
+            scene,
+            image_width: width,
+            image_height: height,
+            samples_per_pixel,
+            ..RenderSettings::default()
+        };
+        Ok(WebRenderer {
+            scene: scene.build(&settings),
+            tiles: split_into_tiles(width, height, TILE_SIZE),
+            framebuffer: Framebuffer::new(width as usize, height as usize),
+            next_sample: 0,
+            settings,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.settings.image_width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.settings.image_height
+    }
+
+    /// Samples per pixel rendered so far.
+    pub fn samples(&self) -> u32 {
+        self.next_sample
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_sample >= self.settings.samples_per_pixel as u32
+    }
+
+    /// Adds a sample to every pixel, unless all of them were rendered. Returns whether samples
+    /// are left to render.
+    pub fn render_pass(&mut self) -> bool {
+        if self.is_done() {
+            return false;
+        }
+        let samples = self.next_sample..self.next_sample + 1;
+        for &tile in &self.tiles {
+            self.scene
+                .render_samples(&self.settings, tile, samples.clone())
+                .merge_into(&mut self.framebuffer);
+        }
+        self.next_sample += 1;
+        !self.is_done()
+    }
+
+    /// Image rendered so far, tone mapped, as the RGBA bytes of an `ImageData` of the canvas.
+    pub fn pixels(&self) -> Vec<u8> {
+        let colors = post_process(&self.settings, &self.framebuffer);
+        to_rgba8_bytes(
+            self.settings.image_width as usize,
+            &colors,
+            self.settings.dither,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_pass() {
+        let mut renderer = WebRenderer::new("cornell-box", 16, 12, 2).unwrap();
+        assert!(renderer.render_pass());
+        assert!(!renderer.render_pass());
+        assert!(!renderer.render_pass());
+        assert_eq!(renderer.samples(), 2);
+
+        let pixels = renderer.pixels();
+        assert_eq!(pixels.len(), 16 * 12 * 4);
+        assert!(pixels.chunks(4).all(|pixel| pixel[3] == 255));
+        assert!(pixels.iter().any(|&value| value > 0 && value < 255));
+    }
+}